@@ -0,0 +1,262 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Taking the content-coding off response bodies, for --compressed.  The
+// decompressors are only built with the "compression" feature.
+
+#[cfg(feature = "compression")]
+use brotli_decompressor::DecompressorWriter;
+#[cfg(feature = "compression")]
+use flate2::write::GzDecoder;
+
+use std::cell::RefCell;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+use std::mem;
+use std::rc::Rc;
+
+/// The value of accept-encoding that --compressed sends.
+pub const ACCEPT_ENCODING: &str = "gzip, br";
+
+#[cfg(feature = "compression")]
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+/// Where a decompressor writes.  What it writes is taken out after each call.
+#[derive(Clone, Default)]
+struct Sink(Rc<RefCell<Vec<u8>>>);
+
+impl Sink {
+    fn take(&self) -> Vec<u8> {
+        mem::replace(&mut *self.0.borrow_mut(), Vec::new())
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+trait Coding {
+    fn decode(&mut self, data: &[u8]) -> io::Result<()>;
+    /// Returns false if the compressed stream isn't complete.
+    fn finish(self: Box<Self>) -> bool;
+}
+
+#[cfg(feature = "compression")]
+impl Coding for GzDecoder<Sink> {
+    fn decode(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_all(data)
+    }
+
+    fn finish(mut self: Box<Self>) -> bool {
+        self.try_finish().is_ok()
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Coding for DecompressorWriter<Sink> {
+    fn decode(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_all(data)
+    }
+
+    fn finish(self: Box<Self>) -> bool {
+        self.into_inner().is_ok()
+    }
+}
+
+#[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+fn coding(name: &str, sink: &Sink) -> Option<Box<dyn Coding>> {
+    match name {
+        #[cfg(feature = "compression")]
+        "gzip" => Some(Box::new(GzDecoder::new(sink.clone()))),
+        #[cfg(feature = "compression")]
+        "br" => Some(Box::new(DecompressorWriter::new(
+            sink.clone(),
+            BROTLI_BUFFER_SIZE,
+        ))),
+        _ => None,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// The body ended before the compressed stream did.  This is different from
+    /// the response being cut short, which is a reset.
+    Truncated,
+    /// The body isn't valid for its content-coding.
+    Corrupt(String),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "compressed body ends early"),
+            Self::Corrupt(e) => write!(f, "compressed body is corrupt: {}", e),
+        }
+    }
+}
+
+/// Decodes one response body, counting bytes before and after.
+pub struct Decoder {
+    name: &'static str,
+    coding: Option<Box<dyn Coding>>,
+    sink: Sink,
+    wire: u64,
+    decoded: u64,
+}
+
+impl Decoder {
+    /// Make a decoder for a content-encoding header value, or `None` if there is no
+    /// content-coding.  Fails with the content-coding if it isn't supported, which
+    /// includes more than one content-coding, or with any if the feature is off.
+    pub fn new(content_encoding: Option<&str>) -> Result<Option<Self>, String> {
+        let value = match content_encoding {
+            Some(v) => v.trim().to_ascii_lowercase(),
+            None => return Ok(None),
+        };
+        let name = match value.as_str() {
+            "" | "identity" => return Ok(None),
+            "gzip" | "x-gzip" => "gzip",
+            "br" => "br",
+            _ => return Err(value),
+        };
+        let sink = Sink::default();
+        let coding = coding(name, &sink).ok_or(value)?;
+        Ok(Some(Self {
+            name,
+            coding: Some(coding),
+            sink,
+            wire: 0,
+            decoded: 0,
+        }))
+    }
+
+    /// Decode more of the body, returning what can be decoded so far.
+    pub fn decode(&mut self, data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        self.wire += data.len() as u64;
+        if let Some(coding) = &mut self.coding {
+            coding
+                .decode(data)
+                .map_err(|e| DecodeError::Corrupt(e.to_string()))?;
+        }
+        Ok(self.take())
+    }
+
+    /// Finish at the end of the body, returning the rest of what was decoded.
+    pub fn finish(&mut self) -> Result<Vec<u8>, DecodeError> {
+        if let Some(coding) = self.coding.take() {
+            if !coding.finish() {
+                return Err(DecodeError::Truncated);
+            }
+        }
+        Ok(self.take())
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        let out = self.sink.take();
+        self.decoded += out.len() as u64;
+        out
+    }
+}
+
+/// This is the summary line for the body, for when it is complete.
+impl Display for Decoder {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes received, {} bytes after {} decoding",
+            self.wire, self.decoded, self.name
+        )
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::{DecodeError, Decoder};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const BODY: &[u8] = b"hello hello hello hello hello";
+
+    /// A brotli stream with one uncompressed meta-block, holding "hello".
+    const HELLO_BR: &[u8] = &[0x40, 0x00, 0x10, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x03];
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(data).unwrap();
+        enc.finish().unwrap()
+    }
+
+    /// Decode `data` in small pieces, as it might arrive.
+    fn decode_all(coding: &str, data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        let mut dec = Decoder::new(Some(coding)).unwrap().unwrap();
+        let mut out = Vec::new();
+        for piece in data.chunks(3) {
+            out.extend(dec.decode(piece)?);
+        }
+        out.extend(dec.finish()?);
+        assert_eq!(dec.wire, data.len() as u64);
+        assert_eq!(dec.decoded, out.len() as u64);
+        Ok(out)
+    }
+
+    #[test]
+    fn gzip_body() {
+        assert_eq!(decode_all("gzip", &gzip(BODY)).unwrap(), BODY);
+        assert_eq!(decode_all(" X-GZIP ", &gzip(BODY)).unwrap(), BODY);
+    }
+
+    #[test]
+    fn gzip_truncated() {
+        let body = gzip(BODY);
+        assert_eq!(
+            decode_all("gzip", &body[..body.len() - 4]),
+            Err(DecodeError::Truncated)
+        );
+    }
+
+    #[test]
+    fn gzip_corrupt() {
+        match decode_all("gzip", b"this is not a gzip stream at all") {
+            Err(DecodeError::Corrupt(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn brotli_body() {
+        assert_eq!(decode_all("br", HELLO_BR).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn brotli_truncated() {
+        assert_eq!(
+            decode_all("br", &HELLO_BR[..HELLO_BR.len() - 1]),
+            Err(DecodeError::Truncated)
+        );
+    }
+
+    #[test]
+    fn identity_and_unsupported() {
+        assert!(Decoder::new(None).unwrap().is_none());
+        assert!(Decoder::new(Some("identity")).unwrap().is_none());
+        assert_eq!(
+            Decoder::new(Some("deflate")).err(),
+            Some(String::from("deflate"))
+        );
+        assert_eq!(
+            Decoder::new(Some("gzip, br")).err(),
+            Some(String::from("gzip, br"))
+        );
+    }
+}
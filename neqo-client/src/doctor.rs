@@ -0,0 +1,571 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// `neqo-client doctor <url>`: check, one step at a time, what is needed to reach
+// a server, and say which step is most likely to be the problem.  This is for
+// reports from environments that can't be debugged directly.
+
+use super::{
+    authentication_status, connect_socket, process_loop, qlog_new, Args, ClientSocket, Handler, Res,
+};
+use neqo_common::{matches, Decoder};
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::{Http3Client, Http3ClientEvent, Http3State};
+use neqo_qpack::QpackSettings;
+use neqo_transport::{
+    Connection, ConnectionError, Error, FixedConnectionIdManager, Output, State, Version,
+    QUIC_VERSION,
+};
+use neqo_udp as udp;
+
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// How long to wait for anything to come back from the probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long the handshake can take.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// A transport error code from here on carries a TLS alert.
+const CRYPTO_ERROR_BASE: u64 = 0x100;
+/// The TLS alert that a server sends when it doesn't accept any ALPN offered.
+const NO_APPLICATION_PROTOCOL: u8 = 120;
+
+fn report(ok: bool, what: &str, detail: impl Display) {
+    println!(
+        "[{}] {}: {}",
+        if ok { " ok " } else { "FAIL" },
+        what,
+        detail
+    );
+}
+
+/// Something that is worth knowing, but not a problem.
+fn note(what: &str, detail: impl Display) {
+    println!("[ -- ] {}: {}", what, detail);
+}
+
+/// What came back from sending a single Initial.
+#[derive(Debug, PartialEq)]
+enum Probe {
+    /// The Initial couldn't be sent because it is too large for the path.
+    TooLarge,
+    /// An ICMP error said that the Initial was refused.
+    Refused,
+    /// Nothing came back.
+    Silent,
+    VersionNegotiation(Vec<Version>),
+    Retry,
+    Initial,
+    /// Something that doesn't answer an Initial, with its first byte.
+    Other(u8),
+}
+
+impl Display for Probe {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::TooLarge => write!(f, "the Initial is too large to send"),
+            Self::Refused => write!(f, "refused (ICMP port unreachable)"),
+            Self::Silent => write!(f, "nothing came back in {:?}", PROBE_TIMEOUT),
+            Self::VersionNegotiation(versions) => {
+                write!(
+                    f,
+                    "Version Negotiation, offering {}",
+                    versions_list(versions)
+                )
+            }
+            Self::Retry => write!(f, "a Retry"),
+            Self::Initial => write!(f, "an Initial"),
+            Self::Other(first) => write!(f, "a packet that starts with {:#04x}", first),
+        }
+    }
+}
+
+fn versions_list(versions: &[Version]) -> String {
+    if versions.is_empty() {
+        return String::from("nothing");
+    }
+    versions
+        .iter()
+        .map(|v| format!("{:#010x}", v))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Work out what a datagram that answers the probe is, from its first packet.
+fn classify_response(d: &[u8]) -> Probe {
+    let mut dec = Decoder::new(d);
+    let first = match dec.decode_byte() {
+        Some(b) => b,
+        None => return Probe::Other(0),
+    };
+    if first & 0x80 == 0 {
+        return Probe::Other(first);
+    }
+    match dec.decode_uint(4) {
+        Some(0) => {
+            // What follows the connection IDs is the list of versions.
+            if dec.decode_vec(1).is_none() || dec.decode_vec(1).is_none() {
+                return Probe::Other(first);
+            }
+            let mut versions = Vec::new();
+            while let Some(v) = dec.decode_uint(4) {
+                versions.push(Version::try_from(v).unwrap());
+            }
+            Probe::VersionNegotiation(versions)
+        }
+        Some(v) if v == u64::from(QUIC_VERSION) => match (first >> 4) & 0x3 {
+            0 => Probe::Initial,
+            3 => Probe::Retry,
+            _ => Probe::Other(first),
+        },
+        _ => Probe::Other(first),
+    }
+}
+
+/// Report on a fresh socket, then send one padded Initial on it and see what
+/// comes back.
+fn probe(args: &Args, server_name: &str, remote_addr: SocketAddr) -> Res<Probe> {
+    let (socket, local_addr, remote_addr) = connect_socket(remote_addr)?;
+    report(true, "socket", socket.local_addr()?);
+    match udp::buffer_sizes(&socket) {
+        Ok((send, recv)) => report(true, "buffers", format!("send {}, receive {}", send, recv)),
+        Err(e) => note("buffers", e),
+    }
+    // Without this, a path with a small MTU could fragment the Initial and make
+    // it look like the rest of the path works.
+    match udp::set_dont_fragment(&socket) {
+        Ok(()) => report(true, "don't fragment", "set"),
+        Err(e) => note("don't fragment", e),
+    }
+
+    let mut conn = Connection::new_client(
+        server_name,
+        &args.alpn,
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+        local_addr,
+        remote_addr,
+    )
+    .map_err(neqo_http3::Error::from)?;
+    let initial = match conn.process_output(Instant::now()) {
+        Output::Datagram(d) => d,
+        _ => unreachable!("a new client always has an Initial to send"),
+    };
+    if let Err(e) = socket.send(&initial[..]) {
+        return match udp::classify(&e) {
+            udp::ErrorClass::TooLarge => Ok(Probe::TooLarge),
+            udp::ErrorClass::Unreachable => Ok(Probe::Refused),
+            _ => Err(e.into()),
+        };
+    }
+    println!(
+        "       sent a {} byte Initial to {}",
+        initial.len(),
+        remote_addr
+    );
+
+    let deadline = Instant::now() + PROBE_TIMEOUT;
+    let mut buf = [0; udp::RECV_BUF_SIZE];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(Probe::Silent);
+        }
+        udp::set_read_timeout(&socket, Some(deadline - now))?;
+        match socket.recv(&mut buf) {
+            Ok(sz) => return Ok(classify_response(&buf[..sz])),
+            Err(e) => match udp::classify(&e) {
+                udp::ErrorClass::Retry => {}
+                udp::ErrorClass::Unreachable => return Ok(Probe::Refused),
+                // Only Windows says that a datagram was truncated, but what fits is enough.
+                udp::ErrorClass::TooLarge => return Ok(classify_response(&buf)),
+                udp::ErrorClass::AddressGone | udp::ErrorClass::Fatal => return Err(e.into()),
+            },
+        }
+    }
+}
+
+/// How the handshake ended.
+#[derive(Debug)]
+enum Handshake {
+    Connected,
+    /// The certificate didn't match `--trust`.
+    CertUntrusted,
+    Closed(ConnectionError),
+    /// Nothing went wrong, but the handshake didn't finish, as summarized.
+    Stalled(String),
+}
+
+struct DoctorHandler {
+    deadline: Instant,
+    cert_status: Option<AuthenticationStatus>,
+}
+
+impl Handler for DoctorHandler {
+    fn handle(&mut self, args: &Args, client: &mut Http3Client) -> Res<bool> {
+        let authentication_needed = |e| matches!(e, Http3ClientEvent::AuthenticationNeeded);
+        if client.events().any(authentication_needed) {
+            let status = authentication_status(args, client.conn());
+            self.cert_status = Some(status);
+            client.authenticated(status, Instant::now());
+        }
+        Ok(Instant::now() < self.deadline
+            && matches!(
+                client.state(),
+                Http3State::Initializing | Http3State::ZeroRtt
+            ))
+    }
+
+    fn wake_at(&self) -> Option<Instant> {
+        Some(self.deadline)
+    }
+}
+
+/// Make an HTTP/3 connection, reporting on what was negotiated.  The connection is
+/// closed again straight away.  This also returns how many datagrams the socket
+/// dropped because its receive buffer was full.
+fn handshake(args: &Args, server_name: &str, remote_addr: SocketAddr) -> Res<(Handshake, usize)> {
+    let (socket, local_addr, remote_addr) = connect_socket(remote_addr)?;
+    let mut client = Http3Client::new(
+        server_name,
+        &args.alpn,
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+        local_addr,
+        remote_addr,
+        QpackSettings {
+            max_table_size_encoder: args.max_table_size_encoder,
+            max_table_size_decoder: args.max_table_size_decoder,
+            max_blocked_streams: args.max_blocked_streams,
+        },
+    )?;
+    client.set_qlog(qlog_new(args, server_name)?);
+    let mut sock = ClientSocket::new(args, socket, local_addr, remote_addr);
+    let mut h = DoctorHandler {
+        deadline: Instant::now() + HANDSHAKE_TIMEOUT,
+        cert_status: None,
+    };
+    process_loop(&mut sock, &mut client, &mut h, args)?;
+
+    let result = match client.conn().state() {
+        State::Connected | State::Confirmed => Handshake::Connected,
+        State::Closing { error, .. } | State::Draining { error, .. } | State::Closed(error) => {
+            if h.cert_status == Some(AuthenticationStatus::CertUntrusted) {
+                Handshake::CertUntrusted
+            } else {
+                Handshake::Closed(error.clone())
+            }
+        }
+        _ => {
+            let summary = client.conn().handshake_state_summary(Instant::now());
+            Handshake::Stalled(summary.to_string())
+        }
+    };
+    if let Handshake::Connected = result {
+        if let Some(info) = client.tls_info() {
+            report(
+                true,
+                "handshake",
+                format!(
+                    "TLS version {:#06x}, cipher {:#06x}, ALPN {}",
+                    info.version(),
+                    info.cipher_suite(),
+                    info.alpn().map_or("none", String::as_str)
+                ),
+            );
+        }
+        report(true, "handshake", &client.conn().stats().handshake);
+        client.close(Instant::now(), 0, "doctor done");
+        // This only sends the CONNECTION_CLOSE, as the handler is done.
+        process_loop(&mut sock, &mut client, &mut h, args)?;
+    } else {
+        report(false, "handshake", format!("{:?}", result));
+    }
+    let local_drops = client.conn().stats().local_drops_rx;
+    if local_drops > 0 {
+        report(
+            false,
+            "local drops",
+            format!("{} datagrams dropped by the socket", local_drops),
+        );
+    }
+    Ok((result, local_drops))
+}
+
+/// Describe a connection that closed during the handshake.
+fn closed_reason(error: &ConnectionError, alpn: &[String]) -> String {
+    match error {
+        ConnectionError::Transport(Error::PeerError(code))
+            if *code == CRYPTO_ERROR_BASE + u64::from(NO_APPLICATION_PROTOCOL) =>
+        {
+            format!("the server doesn't accept ALPN {}", alpn.join(", "))
+        }
+        ConnectionError::Transport(Error::PeerError(code))
+            if *code >= CRYPTO_ERROR_BASE && *code < CRYPTO_ERROR_BASE + 0x100 =>
+        {
+            format!(
+                "the server ended the handshake with TLS alert {}",
+                code - CRYPTO_ERROR_BASE
+            )
+        }
+        ConnectionError::Transport(Error::PeerError(code)) => {
+            format!("the server closed the connection with error {:#x}", code)
+        }
+        ConnectionError::Transport(Error::CryptoAlert(alert)) => {
+            format!("the handshake failed here with TLS alert {}", alert)
+        }
+        ConnectionError::Transport(Error::IdleTimeout) => {
+            String::from("the server stopped answering during the handshake")
+        }
+        ConnectionError::Transport(e) => format!("the handshake failed: {:?}", e),
+        ConnectionError::Application(code) => format!(
+            "the server closed the connection with application error {:#x}",
+            code
+        ),
+    }
+}
+
+/// Work out what the checks mean.  Each entry follows from the one before, and
+/// the first that isn't fine is the problem.  Datagrams dropped by the socket come
+/// last, as they can explain a handshake that stalled.
+fn diagnose(
+    probe: &Probe,
+    handshake: Option<&Handshake>,
+    alpn: &[String],
+    trusting: bool,
+    local_drops: usize,
+) -> Vec<(bool, String)> {
+    let answer = match probe {
+        Probe::TooLarge => {
+            return vec![(
+                false,
+                String::from("the path can't carry a full-size Initial; check the local MTU"),
+            )]
+        }
+        Probe::Refused => {
+            return vec![(
+                false,
+                String::from(
+                    "UDP blocked: the Initial was refused, so nothing is listening \
+                     on that port or a firewall rejects UDP",
+                ),
+            )]
+        }
+        Probe::Silent => {
+            return vec![(
+                false,
+                String::from("UDP blocked or the server is down: nothing answered the Initial"),
+            )]
+        }
+        Probe::VersionNegotiation(versions) => {
+            let problem = if versions.contains(&QUIC_VERSION) {
+                String::from("the server sent Version Negotiation, but it lists draft-27")
+            } else {
+                format!(
+                    "the server doesn't speak draft-27, which is the only version here; \
+                     it offers {}",
+                    versions_list(versions)
+                )
+            };
+            return vec![(true, String::from("UDP works")), (false, problem)];
+        }
+        Probe::Other(_) => {
+            return vec![(
+                false,
+                format!(
+                    "the server doesn't seem to speak QUIC: it answered with {}",
+                    probe
+                ),
+            )]
+        }
+        Probe::Retry => "the server wants its address validated with a Retry",
+        Probe::Initial => "the server answered the Initial",
+    };
+
+    let mut d = vec![(true, format!("UDP works: {}", answer))];
+    match handshake {
+        None => {}
+        Some(Handshake::Connected) => {
+            d.push((true, String::from("the handshake completed")));
+            if !trusting {
+                d.push((
+                    true,
+                    String::from("the certificate wasn't checked; give --trust to check it"),
+                ));
+            }
+        }
+        Some(Handshake::CertUntrusted) => d.push((
+            false,
+            String::from(
+                "certificate not trusted: the server's chain has no certificate from --trust",
+            ),
+        )),
+        Some(Handshake::Closed(error)) => d.push((false, closed_reason(error, alpn))),
+        Some(Handshake::Stalled(summary)) => {
+            d.push((false, format!("the handshake didn't finish: {}", summary)))
+        }
+    }
+    if local_drops > 0 {
+        d.push((
+            false,
+            format!(
+                "this host dropped {} datagrams because the socket receive buffer was full; \
+                 that isn't the network, so raise the buffer size (net.core.rmem_max on Linux)",
+                local_drops
+            ),
+        ));
+    }
+    d
+}
+
+/// Check the first URL in `args`, printing what is found and then the diagnosis.
+/// This returns true if no problem was found.
+pub fn run(args: &Args) -> Res<bool> {
+    let url = &args.urls[0];
+    println!("Checking {}", url);
+    // NSS was initialized before this, which doesn't return if it fails.
+    report(true, "NSS", "initialized");
+
+    let host = url.host_str().unwrap_or("");
+    let port = url.port_or_known_default().unwrap_or(443);
+    let remote_addr = match format!("{}:{}", host, port).to_socket_addrs() {
+        Ok(mut addrs) => addrs.next(),
+        Err(e) => {
+            report(false, "DNS", &e);
+            None
+        }
+    };
+    let remote_addr = if let Some(a) = remote_addr {
+        report(true, "DNS", format!("{} is {}", host, a));
+        a
+    } else {
+        print_diagnosis(&[(false, format!("{} doesn't resolve", host))]);
+        return Ok(false);
+    };
+    let server_name = args
+        .verify_hostname
+        .clone()
+        .unwrap_or_else(|| host.to_string());
+
+    let probe = probe(args, &server_name, remote_addr)?;
+    let reachable = matches!(probe, Probe::Initial | Probe::Retry);
+    report(reachable, "UDP", &probe);
+    let (handshake, local_drops) = if reachable {
+        let (h, drops) = handshake(args, &server_name, remote_addr)?;
+        (Some(h), drops)
+    } else {
+        (None, 0)
+    };
+
+    let diagnosis = diagnose(
+        &probe,
+        handshake.as_ref(),
+        &args.alpn,
+        !args.trusted_certs.is_empty(),
+        local_drops,
+    );
+    print_diagnosis(&diagnosis);
+    Ok(diagnosis.iter().all(|(ok, _)| *ok))
+}
+
+fn print_diagnosis(diagnosis: &[(bool, String)]) {
+    println!("Diagnosis:");
+    for (i, (ok, line)) in diagnosis.iter().enumerate() {
+        println!(
+            "{:indent$}{} {}",
+            "",
+            if *ok { "+" } else { "x" },
+            line,
+            indent = 2 * (i + 1)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify_response, diagnose, Handshake, Probe, CRYPTO_ERROR_BASE};
+    use neqo_common::Encoder;
+    use neqo_transport::{ConnectionError, Error, QUIC_VERSION};
+
+    #[test]
+    fn responses() {
+        // Version Negotiation, with a four byte DCID, no SCID, and two versions.
+        let vn = Encoder::from_hex("800000000004010203040000000001ff00001d");
+        assert_eq!(
+            classify_response(&vn),
+            Probe::VersionNegotiation(vec![1, 0xff00_001d])
+        );
+        let mut initial = Encoder::default();
+        initial.encode_byte(0xc0).encode_uint(4, QUIC_VERSION);
+        assert_eq!(classify_response(&initial), Probe::Initial);
+        let mut retry = Encoder::default();
+        retry.encode_byte(0xf0).encode_uint(4, QUIC_VERSION);
+        assert_eq!(classify_response(&retry), Probe::Retry);
+        let mut handshake = Encoder::default();
+        handshake.encode_byte(0xe0).encode_uint(4, QUIC_VERSION);
+        assert_eq!(classify_response(&handshake), Probe::Other(0xe0));
+        let mut other_version = Encoder::default();
+        other_version.encode_byte(0xc0).encode_uint(4, 1_u32);
+        assert_eq!(classify_response(&other_version), Probe::Other(0xc0));
+        assert_eq!(classify_response(&[0x40, 1, 2]), Probe::Other(0x40));
+        assert_eq!(classify_response(&[]), Probe::Other(0));
+    }
+
+    /// Whether the diagnosis found a problem, and the last thing it says.
+    fn conclusion(probe: &Probe, handshake: Option<&Handshake>, trusting: bool) -> (bool, String) {
+        let d = diagnose(probe, handshake, &[String::from("h3-27")], trusting, 0);
+        (d.iter().all(|(ok, _)| *ok), d.last().unwrap().1.clone())
+    }
+
+    #[test]
+    fn diagnosis() {
+        let (ok, last) = conclusion(&Probe::Refused, None, false);
+        assert!(!ok);
+        assert!(last.starts_with("UDP blocked"));
+        let (ok, last) = conclusion(&Probe::Silent, None, false);
+        assert!(!ok);
+        assert!(last.starts_with("UDP blocked"));
+
+        let (ok, last) = conclusion(&Probe::VersionNegotiation(vec![1]), None, false);
+        assert!(!ok);
+        assert!(last.contains("doesn't speak draft-27"));
+        assert!(last.ends_with("0x00000001"));
+
+        let (ok, last) = conclusion(&Probe::Initial, Some(&Handshake::Connected), true);
+        assert!(ok);
+        assert_eq!(last, "the handshake completed");
+        let (ok, last) = conclusion(&Probe::Retry, Some(&Handshake::Connected), false);
+        assert!(ok);
+        assert!(last.contains("--trust"));
+
+        let (ok, last) = conclusion(&Probe::Initial, Some(&Handshake::CertUntrusted), true);
+        assert!(!ok);
+        assert!(last.starts_with("certificate not trusted"));
+
+        let no_alpn = Handshake::Closed(ConnectionError::Transport(Error::PeerError(
+            CRYPTO_ERROR_BASE + 120,
+        )));
+        let (ok, last) = conclusion(&Probe::Initial, Some(&no_alpn), true);
+        assert!(!ok);
+        assert_eq!(last, "the server doesn't accept ALPN h3-27");
+    }
+
+    #[test]
+    fn diagnosis_local_drops() {
+        let d = diagnose(
+            &Probe::Initial,
+            Some(&Handshake::Connected),
+            &[String::from("h3-27")],
+            true,
+            12,
+        );
+        let (ok, last) = d.last().unwrap();
+        assert!(!ok);
+        assert!(last.starts_with("this host dropped 12 datagrams"));
+    }
+}
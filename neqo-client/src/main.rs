@@ -9,31 +9,53 @@
 
 use qlog::QlogStreamer;
 
-use neqo_common::{self as common, hex, matches, qlog::NeqoQlog, Datagram, Role};
+use neqo_common::{
+    self as common, hex, matches, qlog::NeqoQlog, Datagram, Decoder, Encoder, FileResumptionStore,
+    ResumptionStore, ResumptionTicket, Role, DEFAULT_TICKETS_PER_ORIGIN,
+};
 use neqo_crypto::{init, AuthenticationStatus, Cipher, TLS_CHACHA20_POLY1305_SHA256};
-use neqo_http3::{self, Header, Http3Client, Http3ClientEvent, Http3State, Output};
+use neqo_http3::{
+    self, Header, Http3Client, Http3ClientEvent, Http3Milestones, Http3Parameters, Http3State,
+    Output,
+};
 use neqo_qpack::QpackSettings;
-use neqo_transport::FixedConnectionIdManager;
+use neqo_transport::{
+    Connection, ConnectionError, DatagramDisposition, Error as TransportError,
+    FixedConnectionIdManager, PathInfo, State, ZeroRttState,
+};
+use neqo_udp::{self as udp, LocalDrops};
 
 use std::cell::RefCell;
+use std::cmp::min;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::env;
-use std::fs::{File, OpenOptions};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, ErrorKind, Write};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 
-use structopt::StructOpt;
+use structopt::{
+    clap::{self, AppSettings},
+    StructOpt,
+};
 use url::{Origin, Url};
 
+mod compression;
+mod doctor;
+
 #[derive(Debug)]
 pub enum ClientError {
     Http3Error(neqo_http3::Error),
     IoError(io::Error),
     QlogError,
+    /// The local address stopped working, for the reason given, and the connection
+    /// couldn't move to a new socket, so the requests have to be made again.
+    Rebind(String),
 }
 
 impl From<io::Error> for ClientError {
@@ -59,7 +81,8 @@ type Res<T> = Result<T, ClientError>;
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "neqo-client",
-    about = "A basic QUIC HTTP/0.9 and HTTP3 client."
+    about = "A basic QUIC HTTP/0.9 and HTTP3 client.",
+    setting = AppSettings::DisableVersion
 )]
 pub struct Args {
     #[structopt(short = "a", long, default_value = "h3-27")]
@@ -70,11 +93,22 @@ pub struct Args {
 
     urls: Vec<Url>,
 
+    #[structopt(long)]
+    /// Print details of this build and the NSS in use, then exit.
+    version: bool,
+
     #[structopt(short = "m", default_value = "GET")]
     method: String,
 
-    #[structopt(short = "h", long, number_of_values = 2)]
-    header: Vec<String>,
+    #[structopt(short = "d", long)]
+    /// Send this as the body of each request, with the method from -m.  With a leading @,
+    /// the body is read from the named file.  This is only for HTTP/3.
+    data: Option<String>,
+
+    #[structopt(short = "H", long, parse(try_from_str = parse_header))]
+    /// Add a request header, as "name: value".  Give this once for each header.
+    /// Names are sent in lower case, as HTTP/3 requires.
+    header: Vec<Header>,
 
     #[structopt(
         name = "encoder-table-size",
@@ -115,6 +149,17 @@ pub struct Args {
     /// Save contents of fetched URLs to a directory
     output_dir: Option<PathBuf>,
 
+    #[structopt(name = "continue-at", short = "C", long)]
+    /// Continue downloads into --output-dir where they stopped.  The only value
+    /// supported is "-": files that exist are completed with a range request, and
+    /// are started again if the server doesn't send exactly what is missing.
+    continue_at: Option<String>,
+
+    #[structopt(name = "compressed", long)]
+    /// Ask for a compressed response with accept-encoding, and take a gzip or br
+    /// content-coding off response bodies before they are printed or saved.
+    compressed: bool,
+
     #[structopt(name = "qns-mode", long)]
     /// Enable special behavior for use with QUIC Network Simulator
     qns_mode: bool,
@@ -123,10 +168,312 @@ pub struct Args {
     /// Client attemps to resume connections when there are multiple connections made.
     /// Use this for 0-RTT: the stack always attempts 0-RTT on resumption.
     resume: bool,
+
+    #[structopt(name = "session-file", long)]
+    /// Keep session tickets in this file, and resume with one when there is one for
+    /// the server.  This is only for HTTP/3.
+    session_file: Option<PathBuf>,
+
+    #[structopt(name = "session-ticket", long)]
+    /// Resume with the session ticket in this file, which --output-session-ticket
+    /// wrote.  The ticket is taken out of the file, as it can only be used once.
+    /// This is only for HTTP/3.
+    session_ticket: Option<PathBuf>,
+
+    #[structopt(name = "output-session-ticket", long)]
+    /// Write the session ticket from the server to this file.  This is only for HTTP/3.
+    output_session_ticket: Option<PathBuf>,
+
+    #[structopt(name = "simulate-rebind", long)]
+    /// Move to a new socket once this many bytes have arrived, as if the network had
+    /// changed.  The connection moves with it if the server allows that.  If not,
+    /// the requests that haven't finished are made again from the new socket,
+    /// continuing downloads into --output-dir where they stopped.
+    /// This is only for HTTP/3.
+    simulate_rebind: Option<u64>,
+
+    #[structopt(name = "nat-rebind", long)]
+    /// Send from a new socket this many seconds after starting, without telling the
+    /// connection, as happens when a NAT gives the client a new port.  The server
+    /// sees the client move, which it has to allow for the transfer to survive.
+    /// This is only for HTTP/3.
+    nat_rebind: Option<u64>,
+
+    #[structopt(name = "key-update", long)]
+    /// Start a key update once the first byte of a response arrives.
+    key_update: bool,
+
+    #[structopt(name = "pad-client-hello", long)]
+    /// Make the ClientHello this many bytes larger, so that it needs several packets.
+    /// This tests how a server copes with a ClientHello like one with post-quantum
+    /// key shares.
+    pad_client_hello: Option<usize>,
+
+    #[structopt(name = "limit-rate", long, parse(try_from_str = parse_bytes))]
+    /// Limit the rate of sending to this many bytes per second.
+    /// A suffix of k, m, or g multiplies the value by 1024, 1024^2, or 1024^3.
+    limit_rate: Option<u64>,
+
+    #[structopt(name = "pacing-granularity", long, default_value = "1")]
+    /// Send packets that the pacer would release within this many milliseconds
+    /// together, so that the client doesn't have to wake up for every packet.
+    pacing_granularity: u64,
+
+    #[structopt(
+        name = "read-buffer",
+        long,
+        default_value = "4000",
+        parse(try_from_str = parse_read_buffer)
+    )]
+    /// Read response bodies in pieces of up to this many bytes.  Everything that has
+    /// arrived is read each time, so this only changes how many reads that takes.
+    /// Takes the same suffixes as --limit-rate.
+    read_buffer: usize,
+
+    #[structopt(name = "max-response-bytes", long, parse(try_from_str = parse_bytes))]
+    /// Cancel a request once this much of the response body has been received,
+    /// keeping what was received.  Give this more than once to set a limit for
+    /// each URL in order; the last limit applies to any remaining URLs.
+    /// Takes the same suffixes as --limit-rate.
+    max_response_bytes: Vec<u64>,
+
+    #[structopt(name = "max-header-bytes", long, parse(try_from_str = parse_bytes))]
+    /// Limit the size of response headers, as counted for SETTINGS_MAX_HEADER_LIST_SIZE.
+    /// The server is told about the limit and larger responses are reset.
+    max_header_bytes: Option<u64>,
+
+    #[structopt(name = "max-push", long, default_value = "0")]
+    /// Let the server push this many responses, which are printed as they
+    /// arrive.  They are not saved with --output-dir, and the client doesn't
+    /// wait for pushes once the last request is done.
+    max_push: u64,
+
+    #[structopt(name = "max-data", long, parse(try_from_str = parse_bytes))]
+    /// Let the server send this much on all streams together before it has to wait
+    /// for more credit.  Takes the same suffixes as --limit-rate.
+    /// This is only for HTTP/3, as are the other transport parameters below.
+    max_data: Option<u64>,
+
+    #[structopt(name = "max-stream-data", long, parse(try_from_str = parse_bytes))]
+    /// Let the server send this much on each stream before it has to wait for more
+    /// credit.  Takes the same suffixes as --limit-rate.
+    max_stream_data: Option<u64>,
+
+    #[structopt(name = "max-streams-bidi", long)]
+    /// Let the server open this many bidirectional streams.
+    max_streams_bidi: Option<u64>,
+
+    #[structopt(name = "max-streams-uni", long)]
+    /// Let the server open this many unidirectional streams.
+    max_streams_uni: Option<u64>,
+
+    #[structopt(name = "idle-timeout", long)]
+    /// Close the connection after this many seconds without activity, unless the
+    /// server asks for less.
+    idle_timeout: Option<u64>,
+
+    #[structopt(name = "max-packet-size", long)]
+    /// Ask the server to send no UDP payload larger than this, which can't be less
+    /// than 1200.
+    max_packet_size: Option<u64>,
+
+    #[structopt(name = "trust", long)]
+    /// Only accept a server certificate that is one of the certificates in this
+    /// file (PEM or DER), and is for the server name.  This is for testing against
+    /// servers with self-signed certificates; no other validation is done.
+    trust: Option<PathBuf>,
+
+    #[structopt(name = "verify-hostname", long)]
+    /// Use this as the server name instead of the host from the URL, and only
+    /// accept a server certificate that is for this name.
+    verify_hostname: Option<String>,
+
+    #[structopt(name = "repeat", long, default_value = "1")]
+    /// Fetch the URLs this many times on the same connection, reporting how long
+    /// each round takes, to compare a new connection with a warm one.  This is only
+    /// for HTTP/3.
+    repeat: usize,
+
+    #[structopt(name = "interval", long, default_value = "0")]
+    /// With --repeat, wait this many milliseconds between rounds.  The connection
+    /// is kept open with PINGs while waiting.
+    interval: u64,
+
+    #[structopt(name = "stats", long)]
+    /// At exit, report what the transport did: packets, bytes, ACKs, loss, and RTT.
+    stats: bool,
+
+    #[structopt(name = "json", long)]
+    /// At exit, write when the connection reached each milestone to stdout as
+    /// JSON, in milliseconds from when the first packet was sent.  This is only
+    /// for HTTP/3.
+    json: bool,
+
+    #[structopt(short = "v", long, parse(from_occurrences))]
+    /// Increase verbosity.  With -v, report the size of the handshake and the number
+    /// of socket calls made at exit.
+    /// With -vv, report what happened to each received datagram.
+    verbose: u8,
+
+    #[structopt(skip)]
+    /// The DER encoding of certificates loaded from the file named by `--trust`.
+    trusted_certs: Vec<Vec<u8>>,
+
+    #[structopt(skip)]
+    /// The request body from `--data`.
+    body: Option<Vec<u8>>,
+}
+
+/// Load certificates from a file.  This takes every PEM certificate in the file,
+/// or treats the whole file as a DER-encoded certificate if there are none.
+fn load_certs(path: &Path) -> Res<Vec<Vec<u8>>> {
+    parse_certs(fs::read(path)?)
+}
+
+/// Take every PEM certificate from `contents`, or all of it as one DER certificate.
+fn parse_certs(contents: Vec<u8>) -> Res<Vec<Vec<u8>>> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+
+    let text = match std::str::from_utf8(&contents) {
+        Ok(text) if text.contains(BEGIN) => text,
+        _ => return Ok(vec![contents]),
+    };
+    let mut certs = Vec::new();
+    for block in text.split(BEGIN).skip(1) {
+        let der = block
+            .find(END)
+            .and_then(|end| {
+                let b64 = block[..end]
+                    .chars()
+                    .filter(|c| !c.is_ascii_whitespace())
+                    .collect::<String>();
+                base64::decode(b64).ok()
+            })
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "bad PEM certificate"))?;
+        certs.push(der);
+    }
+    Ok(certs)
+}
+
+/// Whether the end-entity certificate, the first in `chain`, is one of
+/// `trusted_certs`.  The rest of the chain is ignored: the server chooses what
+/// to put there, so a match on a certificate that it merely included would prove
+/// nothing.
+fn pinned<'a>(trusted_certs: &[Vec<u8>], mut chain: impl Iterator<Item = &'a [u8]>) -> bool {
+    chain
+        .next()
+        .map_or(false, |ee| trusted_certs.iter().any(|t| t[..] == *ee))
+}
+
+/// Decide whether to accept the server certificate of `conn`.  With `--trust` or
+/// `--verify-hostname`, it has to be for the server name, and with `--trust`, it
+/// has to be one of the certificates from that file.  Without either, any
+/// certificate is accepted.
+fn authentication_status(args: &Args, conn: &Connection) -> AuthenticationStatus {
+    if args.trusted_certs.is_empty() && args.verify_hostname.is_none() {
+        return AuthenticationStatus::Ok;
+    }
+    let mut cert = match conn.peer_certificate() {
+        Some(cert) => cert,
+        None => {
+            eprintln!("Server didn't send a certificate");
+            return AuthenticationStatus::CertUntrusted;
+        }
+    };
+    let name = conn.server_name().unwrap_or_default();
+    if !cert.verify_name(name) {
+        eprintln!("Server certificate isn't for {}", name);
+        return AuthenticationStatus::CertSubjectInvalid;
+    }
+    if args.trusted_certs.is_empty() {
+        eprintln!(
+            "WARNING: server certificate is for {}, but nothing else about it was checked",
+            name
+        );
+        AuthenticationStatus::Ok
+    } else if pinned(&args.trusted_certs, &mut cert) {
+        eprintln!("WARNING: accepting server certificate only because of --trust");
+        AuthenticationStatus::Ok
+    } else {
+        eprintln!("Server certificate isn't a certificate from --trust");
+        AuthenticationStatus::CertUntrusted
+    }
+}
+
+/// Parse a rate in bytes per second, like "500k".
+fn parse_bytes(s: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match s.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('g') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|v| v.checked_mul(multiplier))
+        .filter(|&v| v > 0)
+        .ok_or_else(|| format!("invalid number of bytes: {}", s))
+}
+
+/// Parse a buffer size, which takes the same suffixes as `parse_bytes`.
+fn parse_read_buffer(s: &str) -> Result<usize, String> {
+    parse_bytes(s).and_then(|v| usize::try_from(v).map_err(|_| format!("buffer too large: {}", s)))
+}
+
+/// Parse a request header, like "accept: text/html, */*".  The value is everything after
+/// the first colon, without whitespace around it, so it can hold commas and colons.
+fn parse_header(s: &str) -> Result<Header, String> {
+    const TOKEN_CHARS: &str = "!#$%&'*+-.^_`|~";
+
+    let colon = s
+        .find(':')
+        .ok_or_else(|| format!("header has no colon: {}", s))?;
+    let name = &s[..colon];
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || TOKEN_CHARS.contains(c))
+    {
+        return Err(format!("invalid header name: {:?}", name));
+    }
+    let value = s[colon + 1..].trim_matches(|c| c == ' ' || c == '\t');
+    Ok((name.to_ascii_lowercase(), value.to_string()))
 }
 
 trait Handler {
     fn handle(&mut self, args: &Args, client: &mut Http3Client) -> Res<bool>;
+
+    /// When `handle` needs to be called again, even if nothing arrives.
+    fn wake_at(&self) -> Option<Instant> {
+        None
+    }
+}
+
+/// Make a socket that is connected to `remote_addr`, returning it with the local and
+/// remote addresses for the connection to use.  A dual-stack socket can reach servers
+/// of either family.  Where that isn't available, the socket has the same family as
+/// the server.
+fn connect_socket(remote_addr: SocketAddr) -> io::Result<(UdpSocket, SocketAddr, SocketAddr)> {
+    let dual_stack = udp::bind_dual_stack().and_then(|s| {
+        s.connect(udp::dual_stack_address(remote_addr))?;
+        Ok(s)
+    });
+    if let Ok(s) = dual_stack {
+        return Ok((
+            s,
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::from([0; 16])), 0),
+            udp::dual_stack_address(remote_addr),
+        ));
+    }
+    let local_addr = match remote_addr {
+        SocketAddr::V4(..) => SocketAddr::new(IpAddr::V4(Ipv4Addr::from([0; 4])), 0),
+        SocketAddr::V6(..) => SocketAddr::new(IpAddr::V6(Ipv6Addr::from([0; 16])), 0),
+    };
+    let socket = UdpSocket::bind(local_addr)?;
+    socket.connect(&remote_addr)?;
+    Ok((socket, local_addr, remote_addr))
 }
 
 fn emit_datagram(socket: &UdpSocket, d: Option<Datagram>) -> io::Result<()> {
@@ -139,11 +486,225 @@ fn emit_datagram(socket: &UdpSocket, d: Option<Datagram>) -> io::Result<()> {
     Ok(())
 }
 
+/// Send all of `dgrams`, in as few calls as possible.  If the socket
+/// cannot take any more, or the peer is still unreachable after one more
+/// try, the rest are dropped and left to loss recovery.
+fn emit_datagrams(
+    socket: &UdpSocket,
+    dgrams: &[Datagram],
+    stats: &mut udp::BatchStats,
+) -> io::Result<()> {
+    let mut sent = 0;
+    let mut unreachable = false;
+    while sent < dgrams.len() {
+        match udp::send_batch(socket, &dgrams[sent..], stats) {
+            Ok(n) => sent += n,
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => match udp::classify(&err) {
+                udp::ErrorClass::Retry => {
+                    eprintln!(
+                        "Dropping {} of {} datagrams: socket would block",
+                        dgrams.len() - sent,
+                        dgrams.len()
+                    );
+                    break;
+                }
+                // Reporting the ICMP error clears it, so the datagram can be sent again.
+                udp::ErrorClass::Unreachable if !unreachable => {
+                    eprintln!("UDP: peer unreachable: {}", err);
+                    unreachable = true;
+                }
+                udp::ErrorClass::Unreachable => {
+                    eprintln!(
+                        "Dropping {} of {} datagrams: peer unreachable: {}",
+                        dgrams.len() - sent,
+                        dgrams.len(),
+                        err
+                    );
+                    break;
+                }
+                udp::ErrorClass::TooLarge => {
+                    eprintln!(
+                        "Dropping datagram of {} bytes: too large",
+                        dgrams[sent].len()
+                    );
+                    sent += 1;
+                }
+                udp::ErrorClass::Fatal | udp::ErrorClass::AddressGone => return Err(err),
+            },
+        }
+    }
+    Ok(())
+}
+
+/// How many times in a row the local address has to be reported gone before the
+/// socket is replaced.  One failure can be a blip as the network changes.
+const ADDRESS_GONE_LIMIT: usize = 3;
+
+/// The socket that an HTTP/3 connection uses.  If the local address stops working,
+/// as it does when a laptop moves to another network, the socket is replaced and
+/// the connection moves to the new one.  Where it can't, `ClientError::Rebind` says
+/// that the requests have to be made again.
+pub struct ClientSocket {
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    /// How many bytes to receive before moving to a new socket anyway, for
+    /// `--simulate-rebind`.
+    rebind_after: Option<u64>,
+    /// The bytes received on this socket and any before it.
+    received: u64,
+    /// How many times in a row the local address was reported gone.
+    address_gone: usize,
+    /// When to send from a new socket without telling the connection, for
+    /// `--nat-rebind`.
+    nat_rebind_at: Option<Instant>,
+    /// Datagrams that the socket dropped because its receive buffer was full.
+    local_drops: LocalDrops,
+    /// Socket calls made for the connection, on this socket and any before it.
+    stats: udp::BatchStats,
+}
+
+impl ClientSocket {
+    pub fn new(
+        args: &Args,
+        socket: UdpSocket,
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+    ) -> Self {
+        Self {
+            local_addr,
+            remote_addr,
+            rebind_after: args.simulate_rebind,
+            received: 0,
+            address_gone: 0,
+            nat_rebind_at: args
+                .nat_rebind
+                .map(|s| Instant::now() + Duration::from_secs(s)),
+            local_drops: LocalDrops::new(&socket),
+            socket,
+            stats: udp::BatchStats::default(),
+        }
+    }
+
+    /// When `maybe_rebind` next has something to do for `--nat-rebind`.
+    /// `--simulate-rebind` only depends on what arrives.
+    fn wake_at(&self) -> Option<Instant> {
+        self.nat_rebind_at
+    }
+
+    /// Move to a new socket if `--simulate-rebind` or `--nat-rebind` says that it
+    /// is time.
+    fn maybe_rebind(&mut self, client: &mut Http3Client) -> Res<()> {
+        let now = Instant::now();
+        if self.nat_rebind_at.map_or(false, |t| t <= now) {
+            self.nat_rebind_at = None;
+            // The connection keeps the old local address, as it would behind a NAT.
+            let (socket, ..) = connect_socket(self.remote_addr)?;
+            eprintln!("NAT rebinding: now sending from {}", socket.local_addr()?);
+            self.check_local_drops(client);
+            self.local_drops = LocalDrops::new(&socket);
+            self.socket = socket;
+        }
+        if self.rebind_after.map_or(false, |n| self.received >= n) {
+            self.rebind_after = None;
+            self.rebind(client, "--simulate-rebind")?;
+        }
+        Ok(())
+    }
+
+    /// The local address was reported gone by `err`.  The socket is only replaced
+    /// once that has happened `ADDRESS_GONE_LIMIT` times in a row.
+    fn address_gone(&mut self, client: &mut Http3Client, err: &io::Error) -> Res<()> {
+        self.address_gone += 1;
+        if self.address_gone < ADDRESS_GONE_LIMIT {
+            eprintln!("UDP: {}, rebinding if it keeps happening", err);
+            Ok(())
+        } else {
+            self.rebind(client, &err.to_string())
+        }
+    }
+
+    /// Replace the socket, because of `why`, with one on a new local address, and
+    /// move the connection there.  If the connection can't move, this returns
+    /// `ClientError::Rebind`.
+    fn rebind(&mut self, client: &mut Http3Client, why: &str) -> Res<()> {
+        let (socket, _, remote_addr) = connect_socket(self.remote_addr)?;
+        let local_addr = socket.local_addr()?;
+        if let Err(e) = client.conn().migrate(local_addr) {
+            eprintln!("Unable to move the connection after {}: {:?}", why, e);
+            return Err(ClientError::Rebind(String::from(why)));
+        }
+        eprintln!("Rebound after {}, moving to {}", why, local_addr);
+        self.address_gone = 0;
+        self.check_local_drops(client);
+        self.local_drops = LocalDrops::new(&socket);
+        self.socket = socket;
+        self.local_addr = local_addr;
+        self.remote_addr = remote_addr;
+        Ok(())
+    }
+
+    /// Tell the connection about datagrams that the socket has dropped since the
+    /// last check, because its receive buffer was full.
+    fn check_local_drops(&mut self, client: &mut Http3Client) {
+        let drops = self.local_drops.check(&self.socket, Instant::now());
+        if let Err(e) = client.conn().record_local_drops(drops) {
+            eprintln!("Unable to record local drops: {:?}", e);
+        }
+    }
+}
+
+/// Send `dgrams`, closing the connection if that fails.  If the local address
+/// has gone, what wasn't sent is left to loss recovery, and once that keeps
+/// happening the connection moves to a new socket.
+fn flush_datagrams(
+    sock: &mut ClientSocket,
+    client: &mut Http3Client,
+    dgrams: &[Datagram],
+) -> Res<bool> {
+    match emit_datagrams(&sock.socket, dgrams, &mut sock.stats) {
+        Ok(()) => {
+            if !dgrams.is_empty() {
+                sock.address_gone = 0;
+            }
+            Ok(true)
+        }
+        Err(e) if udp::classify(&e) == udp::ErrorClass::AddressGone => {
+            sock.address_gone(client, &e)?;
+            Ok(true)
+        }
+        Err(e) => {
+            eprintln!("UDP write error: {}", e);
+            client.close(Instant::now(), 0, e.to_string());
+            Ok(false)
+        }
+    }
+}
+
+fn print_dispositions(args: &Args, dispositions: Vec<DatagramDisposition>) {
+    if args.verbose >= 2 {
+        for d in dispositions {
+            if d.dropped.is_empty() {
+                eprintln!("Datagram: {} packets processed", d.processed);
+            } else {
+                eprintln!(
+                    "Datagram: {} packets processed, dropped {:?}",
+                    d.processed, d.dropped
+                );
+            }
+        }
+    }
+}
+
+/// Open the file to save `url` to.  With `resume`, what is in the file is kept and
+/// its size is returned, otherwise the file is truncated.
 fn get_output_file(
     url: &Url,
     output_dir: &Option<PathBuf>,
+    resume: bool,
     all_paths: &mut Vec<PathBuf>,
-) -> Option<File> {
+) -> Option<(File, u64)> {
     if let Some(ref dir) = output_dir {
         let mut out_path = dir.clone();
 
@@ -161,59 +722,85 @@ fn get_output_file(
             return None;
         }
 
-        eprintln!("Saving {} to {:?}", url.clone().into_string(), out_path);
-
-        let f = match OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&out_path)
-        {
+        let mut options = OpenOptions::new();
+        options.create(true);
+        if resume {
+            options.append(true);
+        } else {
+            options.write(true).truncate(true);
+        }
+        let f = match options.open(&out_path) {
             Err(_) => return None,
             Ok(f) => f,
         };
+        let offset = if resume {
+            match f.metadata() {
+                Err(_) => return None,
+                Ok(m) => m.len(),
+            }
+        } else {
+            0
+        };
+
+        if offset > 0 {
+            eprintln!(
+                "Continuing {} at {} bytes in {:?}",
+                url.clone().into_string(),
+                offset,
+                out_path
+            );
+        } else {
+            eprintln!("Saving {} to {:?}", url.clone().into_string(), out_path);
+        }
 
         all_paths.push(out_path);
-        Some(f)
+        Some((f, offset))
     } else {
         None
     }
 }
 
 fn process_loop(
-    local_addr: &SocketAddr,
-    remote_addr: &SocketAddr,
-    socket: &UdpSocket,
+    sock: &mut ClientSocket,
     client: &mut Http3Client,
     handler: &mut dyn Handler,
     args: &Args,
 ) -> Res<neqo_http3::Http3State> {
-    let buf = &mut [0u8; 2048];
+    let mut bufs = vec![[0u8; udp::RECV_BUF_SIZE]; udp::MAX_BATCH];
     loop {
         if let Http3State::Closed(..) = client.state() {
             return Ok(client.state());
         }
 
         let mut exiting = !handler.handle(args, client)?;
+        sock.maybe_rebind(client)?;
 
         loop {
-            let output = client.process_output(Instant::now());
+            let (dgrams, output) = client.process_output_batch(Instant::now(), udp::MAX_BATCH);
+            if !flush_datagrams(sock, client, &dgrams)? {
+                exiting = true;
+                break;
+            }
+            if dgrams.len() == udp::MAX_BATCH {
+                // There could be more to send.
+                continue;
+            }
             match output {
-                Output::Datagram(dgram) => {
-                    if let Err(e) = emit_datagram(&socket, Some(dgram)) {
-                        eprintln!("UDP write error: {}", e);
-                        client.close(Instant::now(), 0, e.to_string());
-                        exiting = true;
-                        break;
-                    }
-                }
+                Output::Datagram(_) => unreachable!("datagrams come in the batch"),
                 Output::Callback(duration) => {
-                    socket.set_read_timeout(Some(duration)).unwrap();
+                    let wake_at = match (handler.wake_at(), sock.wake_at()) {
+                        (Some(a), Some(b)) => Some(min(a, b)),
+                        (a, b) => a.or(b),
+                    };
+                    let duration = wake_at.map_or(duration, |t| {
+                        min(duration, t.saturating_duration_since(Instant::now()))
+                    });
+                    udp::set_read_timeout(&sock.socket, Some(duration))?;
                     break;
                 }
                 Output::None => {
                     // Not strictly necessary, since we're about to exit
-                    socket.set_read_timeout(None).unwrap();
+                    udp::set_read_timeout(&sock.socket, None)?;
                     exiting = true;
                     break;
                 }
@@ -224,105 +811,657 @@ fn process_loop(
             return Ok(client.state());
         }
 
-        match socket.recv(&mut buf[..]) {
-            Err(ref err)
-                if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::Interrupted => {}
-            Err(err) => {
-                eprintln!("UDP error: {}", err);
-                exit(1)
-            }
-            Ok(sz) => {
-                if sz == buf.len() {
-                    eprintln!("Received more than {} bytes", buf.len());
-                    continue;
+        match udp::recv_batch(&sock.socket, &mut bufs, &mut sock.stats) {
+            Err(err) => match udp::classify(&err) {
+                udp::ErrorClass::Retry => {}
+                // Leave it to the connection to time out if the server is really gone.
+                udp::ErrorClass::Unreachable => eprintln!("UDP: peer unreachable: {}", err),
+                udp::ErrorClass::TooLarge => {
+                    eprintln!("Received more than {} bytes", udp::RECV_BUF_SIZE)
                 }
-                if sz > 0 {
-                    let d = Datagram::new(*remote_addr, *local_addr, &buf[..sz]);
-                    client.process_input(d, Instant::now());
+                udp::ErrorClass::AddressGone => sock.address_gone(client, &err)?,
+                udp::ErrorClass::Fatal => {
+                    eprintln!("UDP error: {}", err);
+                    exit(1)
+                }
+            },
+            Ok(sizes) => {
+                for (&sz, buf) in sizes.iter().zip(&bufs) {
+                    if sz == buf.len() {
+                        eprintln!("Received more than {} bytes", buf.len());
+                        continue;
+                    }
+                    if sz > 0 {
+                        sock.received += sz as u64;
+                        let d = Datagram::new(sock.remote_addr, sock.local_addr, &buf[..sz]);
+                        client.process_input(d, Instant::now());
+                    }
                 }
+                print_dispositions(args, client.conn().take_datagram_dispositions());
+                sock.check_local_drops(client);
             }
         };
     }
 }
 
-struct PreConnectHandler {}
+/// How long to wait for the handshake before reporting on its progress.
+const HANDSHAKE_REPORT_DELAY: Duration = Duration::from_secs(5);
+
+struct PreConnectHandler {
+    report_at: Option<Instant>,
+}
+
+impl PreConnectHandler {
+    fn new() -> Self {
+        Self {
+            report_at: Some(Instant::now() + HANDSHAKE_REPORT_DELAY),
+        }
+    }
+}
+
 impl Handler for PreConnectHandler {
-    fn handle(&mut self, _args: &Args, client: &mut Http3Client) -> Res<bool> {
+    fn handle(&mut self, args: &Args, client: &mut Http3Client) -> Res<bool> {
+        let now = Instant::now();
+        if self.report_at.map_or(false, |t| now >= t) {
+            self.report_at = None;
+            eprintln!(
+                "Handshake not complete after {:?}: {}",
+                HANDSHAKE_REPORT_DELAY,
+                client.conn().handshake_state_summary(now)
+            );
+        }
         let authentication_needed = |e| matches!(e, Http3ClientEvent::AuthenticationNeeded);
         if client.events().any(authentication_needed) {
-            client.authenticated(AuthenticationStatus::Ok, Instant::now());
+            let status = authentication_status(args, client.conn());
+            client.authenticated(status, Instant::now());
         }
         Ok(Http3State::Connected != client.state())
     }
 }
 
-#[derive(Default)]
-struct PostConnectHandler {
-    streams: HashMap<u64, Option<File>>,
+/// A download that continues what is already in a file, see `--continue-at`.
+struct Resume {
+    url: Url,
+    /// The size of the file when the request was made.
+    offset: u64,
+}
+
+/// What to do with a file, given the response to a request for the rest of it.
+#[derive(Debug, PartialEq)]
+enum ResumeCheck {
+    /// The response is what is missing from the file.
+    Append,
+    /// The response is the whole resource, so the file is replaced with it.
+    Restart,
+    /// The response is a range that doesn't continue the file, so the file
+    /// is truncated and the whole resource is requested again.
+    Refetch,
+    /// The file already has everything.
+    Complete,
+    /// The request failed; the file is left as it is.
+    Fail,
+}
+
+fn header<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Parse a content-range header, like "bytes 10-19/100", into the first and last
+/// byte positions and the complete length.  Either can be unknown ("*").
+fn parse_content_range(value: &str) -> Option<(Option<(u64, u64)>, Option<u64>)> {
+    let value = value.trim();
+    if !value.starts_with("bytes ") {
+        return None;
+    }
+    let mut parts = value["bytes ".len()..].splitn(2, '/');
+    let range = match parts.next()?.trim() {
+        "*" => None,
+        r => {
+            let mut ends = r.splitn(2, '-');
+            let first = ends.next()?.parse::<u64>().ok()?;
+            let last = ends.next()?.parse::<u64>().ok()?;
+            if last < first {
+                return None;
+            }
+            Some((first, last))
+        }
+    };
+    let length = match parts.next()?.trim() {
+        "*" => None,
+        l => Some(l.parse::<u64>().ok()?),
+    };
+    match (range, length) {
+        (Some((_, last)), Some(length)) if last >= length => None,
+        (None, None) => None,
+        _ => Some((range, length)),
+    }
+}
+
+/// Decide what to do with a file that has `offset` bytes, given the response headers.
+fn check_resume(offset: u64, headers: &[Header]) -> ResumeCheck {
+    let range = header(headers, "content-range").and_then(parse_content_range);
+    match header(headers, ":status") {
+        Some("200") if offset == 0 => ResumeCheck::Append,
+        Some("200") => ResumeCheck::Restart,
+        Some("206") => match range {
+            Some((Some((first, _)), _)) if first == offset => ResumeCheck::Append,
+            _ if offset > 0 => ResumeCheck::Refetch,
+            _ => ResumeCheck::Fail,
+        },
+        Some("416") if offset > 0 => match range {
+            Some((None, Some(length))) if length == offset => ResumeCheck::Complete,
+            _ => ResumeCheck::Refetch,
+        },
+        _ => ResumeCheck::Fail,
+    }
+}
+
+/// Counts of responses that didn't finish normally, which decide the exit status.
+#[derive(Default)]
+struct Failures {
+    truncated: usize,
+    /// How many downloads were left to be continued, with `--continue-at`.
+    incomplete: usize,
+    /// How many bodies couldn't be decoded, with `--compressed`.
+    undecodable: usize,
+    /// How many requests the server reset.
+    reset: usize,
+}
+
+impl Failures {
+    fn add(&mut self, other: &Self) {
+        self.truncated += other.truncated;
+        self.incomplete += other.incomplete;
+        self.undecodable += other.undecodable;
+        self.reset += other.reset;
+    }
+}
+
+/// Take the content-coding off part of a body, and check that it ends at `fin`.
+fn decode_body(
+    decoder: &mut compression::Decoder,
+    data: &[u8],
+    fin: bool,
+) -> Result<Vec<u8>, compression::DecodeError> {
+    let mut out = decoder.decode(data)?;
+    if fin {
+        out.extend(decoder.finish()?);
+    }
+    Ok(out)
+}
+
+/// Try the key update that `--key-update` asks for, clearing `pending` once it
+/// has started.  This can't happen until the handshake is confirmed and the server
+/// has acknowledged a packet, so until then it is tried again on the next read.
+fn key_update(pending: &mut bool, conn: &mut Connection) {
+    match conn.initiate_key_update() {
+        Ok(()) => {
+            eprintln!("Started a key update");
+            *pending = false;
+        }
+        Err(TransportError::KeyUpdateBlocked) | Err(TransportError::NotConnected) => {}
+        Err(e) => {
+            eprintln!("Unable to update keys: {:?}", e);
+            *pending = false;
+        }
+    }
+}
+
+#[derive(Default)]
+struct PostConnectHandler {
+    streams: HashMap<u64, Option<File>>,
+    /// How much more of each response body can be received, for those that are limited.
+    remaining: HashMap<u64, u64>,
+    /// The downloads that are waiting for response headers, with `--continue-at`.
+    resumes: HashMap<u64, Resume>,
+    /// The bodies that have a content-coding to take off, with `--compressed`.
+    decoders: HashMap<u64, compression::Decoder>,
+    /// What is left to send of each request body, with `--data`.
+    bodies: HashMap<u64, Vec<u8>>,
+    failures: Failures,
+    /// Whether to close the connection once all streams are done.
+    close_when_done: bool,
+    /// Whether a key update is still to be started, with `--key-update`.
+    key_update: bool,
+}
+
+impl PostConnectHandler {
+    fn stream_done(&mut self, stream_id: u64, client: &mut Http3Client) -> bool {
+        self.streams.remove(&stream_id);
+        self.remaining.remove(&stream_id);
+        self.decoders.remove(&stream_id);
+        self.bodies.remove(&stream_id);
+        if self.streams.is_empty() {
+            if self.close_when_done {
+                client.close(Instant::now(), 0, "kthxbye!");
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Act on the response headers for a download that was continued.
+    /// Returns true if all streams are done.
+    fn resume_response(
+        &mut self,
+        args: &Args,
+        client: &mut Http3Client,
+        stream_id: u64,
+        resume: Resume,
+        headers: &[Header],
+    ) -> Res<bool> {
+        match check_resume(resume.offset, headers) {
+            ResumeCheck::Append => return Ok(false),
+            ResumeCheck::Restart => {
+                eprintln!("WARNING: {} was sent in full, starting again", resume.url);
+                self.truncate_file(stream_id)?;
+                return Ok(false);
+            }
+            ResumeCheck::Refetch => {
+                eprintln!(
+                    "WARNING: the range sent for {} doesn't continue the file, fetching all of it",
+                    resume.url
+                );
+                self.truncate_file(stream_id)?;
+                let new_id = client.fetch_url(&args.method, &resume.url, &args.header)?;
+                self.start_body(args, client, new_id);
+                if let Some(out_file) = self.streams.remove(&stream_id) {
+                    self.streams.insert(new_id, out_file);
+                }
+                if let Some(limit) = self.remaining.remove(&stream_id) {
+                    self.remaining.insert(new_id, limit);
+                }
+                self.resumes.insert(
+                    new_id,
+                    Resume {
+                        url: resume.url,
+                        offset: 0,
+                    },
+                );
+            }
+            ResumeCheck::Complete => println!("{} is already complete", resume.url),
+            ResumeCheck::Fail => {
+                eprintln!(
+                    "Unable to continue {}: status {}",
+                    resume.url,
+                    header(headers, ":status").unwrap_or("missing")
+                );
+                self.failures.incomplete += 1;
+            }
+        }
+        // Nothing more from this response is wanted.
+        let _ = client.cancel_fetch(stream_id);
+        Ok(self.stream_done(stream_id, client))
+    }
+
+    /// Start sending the request body from `--data`, if there is one.  Without one,
+    /// the request is complete with its headers.
+    fn start_body(&mut self, args: &Args, client: &mut Http3Client, stream_id: u64) {
+        self.bodies
+            .insert(stream_id, args.body.clone().unwrap_or_default());
+        self.send_body(client, stream_id);
+    }
+
+    /// Send as much of the request body as flow control allows, and close the
+    /// stream once all of it is sent.  An empty body closes the stream straight away.
+    fn send_body(&mut self, client: &mut Http3Client, stream_id: u64) {
+        let body = match self.bodies.get_mut(&stream_id) {
+            Some(body) => body,
+            None => return,
+        };
+        while !body.is_empty() {
+            match client.send_request_body(stream_id, body) {
+                // Either the headers haven't been sent or flow control is used up.
+                // The rest is sent when the stream is writable again.
+                Ok(0) => return,
+                Ok(sent) => {
+                    let _ = body.drain(..sent);
+                }
+                Err(e) => {
+                    println!("Unable to send request body[{}]: {:?}", stream_id, e);
+                    self.bodies.remove(&stream_id);
+                    return;
+                }
+            }
+        }
+        self.bodies.remove(&stream_id);
+        let _ = client.stream_close_send(stream_id);
+    }
+
+    /// Read the response body on `stream_id` until nothing more is available,
+    /// because there may not be another `DataReadable` until all of it has been
+    /// read.  Returns true if the stream is done.
+    fn read_response(
+        &mut self,
+        args: &Args,
+        client: &mut Http3Client,
+        stream_id: u64,
+        data: &mut [u8],
+    ) -> Res<bool> {
+        if !matches!(self.streams.get(&stream_id), Some(Some(_))) {
+            return self.read_response_to_end(args, client, stream_id);
+        }
+        loop {
+            let (mut sz, fin) = client.read_response_data(Instant::now(), stream_id, data)?;
+            if sz == 0 && !fin {
+                return Ok(false);
+            }
+            if self.key_update && sz > 0 {
+                key_update(&mut self.key_update, client.conn());
+            }
+
+            let mut truncated = false;
+            if let Some(remaining) = self.remaining.get_mut(&stream_id) {
+                if sz as u64 > *remaining {
+                    sz = *remaining as usize;
+                    truncated = true;
+                }
+                *remaining -= sz as u64;
+            }
+
+            let mut body = data[..sz].to_vec();
+            let mut undecodable = false;
+            if let Some(decoder) = self.decoders.get_mut(&stream_id) {
+                match decode_body(decoder, &body, fin && !truncated) {
+                    Ok(decoded) => body = decoded,
+                    Err(e) => {
+                        println!("UNDECODABLE[{}]: {}", stream_id, e);
+                        body.clear();
+                        undecodable = true;
+                    }
+                }
+            }
+
+            if let Some(Some(out_file)) = self.streams.get_mut(&stream_id) {
+                if !body.is_empty() {
+                    out_file.write_all(&body)?;
+                }
+            }
+
+            if undecodable || truncated {
+                if truncated {
+                    println!("TRUNCATED[{}]: --max-response-bytes reached", stream_id);
+                    self.failures.truncated += 1;
+                } else {
+                    self.failures.undecodable += 1;
+                }
+                client.cancel_fetch(stream_id)?;
+                return Ok(true);
+            }
+            if fin {
+                if let Some(decoder) = self.decoders.get(&stream_id) {
+                    println!("DECODED[{}]: {}", stream_id, decoder);
+                }
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Read a response that is printed rather than saved.  It is printed once all
+    /// of it has arrived, and --max-response-bytes limits how much of it is kept.
+    /// Returns true if the stream is done.
+    fn read_response_to_end(
+        &mut self,
+        args: &Args,
+        client: &mut Http3Client,
+        stream_id: u64,
+    ) -> Res<bool> {
+        let max_len = self
+            .remaining
+            .get(&stream_id)
+            .map_or(usize::MAX, |r| usize::try_from(*r).unwrap_or(usize::MAX));
+        let mut body = match client.read_body_to_end(Instant::now(), stream_id, max_len) {
+            Ok(None) => return Ok(false),
+            Ok(Some(body)) => body,
+            Err(neqo_http3::Error::BodyTooLarge) => {
+                // The request has been cancelled.
+                println!("TRUNCATED[{}]: --max-response-bytes reached", stream_id);
+                self.failures.truncated += 1;
+                return Ok(true);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let len = body.len();
+        if self.key_update && len > 0 {
+            key_update(&mut self.key_update, client.conn());
+        }
+        if let Some(decoder) = self.decoders.get_mut(&stream_id) {
+            match decode_body(decoder, &body, true) {
+                Ok(decoded) => body = decoded,
+                Err(e) => {
+                    println!("UNDECODABLE[{}]: {}", stream_id, e);
+                    self.failures.undecodable += 1;
+                    return Ok(true);
+                }
+            }
+        }
+
+        if !args.output_read_data {
+            println!("READ[{}]: {} bytes", stream_id, len);
+        } else if let Ok(txt) = String::from_utf8(body.clone()) {
+            println!("READ[{}]: {}", stream_id, txt);
+        } else {
+            println!("READ[{}]: 0x{}", stream_id, hex(&body));
+        }
+        if let Some(decoder) = self.decoders.get(&stream_id) {
+            println!("DECODED[{}]: {}", stream_id, decoder);
+        }
+        println!("<FIN[{}]>", stream_id);
+        Ok(true)
+    }
+
+    fn truncate_file(&self, stream_id: u64) -> Res<()> {
+        if let Some(Some(out_file)) = self.streams.get(&stream_id) {
+            out_file.set_len(0)?;
+        }
+        Ok(())
+    }
+}
+
+/// How often to send a PING while waiting between rounds of `--repeat`.  This is
+/// well inside the idle timeout that servers commonly use.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Waits for `--interval` between rounds of `--repeat`, keeping the connection open.
+struct IdleHandler {
+    until: Instant,
+    next_ping: Instant,
+}
+
+impl IdleHandler {
+    fn new(interval: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            until: now + interval,
+            next_ping: now + KEEP_ALIVE_INTERVAL,
+        }
+    }
+}
+
+impl Handler for IdleHandler {
+    fn handle(&mut self, _args: &Args, client: &mut Http3Client) -> Res<bool> {
+        // Nothing that happens while waiting concerns a request.
+        client.events().for_each(drop);
+        let now = Instant::now();
+        if now >= self.until || client.state() != Http3State::Connected {
+            return Ok(false);
+        }
+        if now >= self.next_ping {
+            client.conn().send_ping().map_err(neqo_http3::Error::from)?;
+            self.next_ping = now + KEEP_ALIVE_INTERVAL;
+        }
+        Ok(true)
+    }
+
+    fn wake_at(&self) -> Option<Instant> {
+        Some(min(self.until, self.next_ping))
+    }
+}
+
+/// Report an idle timeout distinctly, because after `--interval` it most likely
+/// means that the server dropped the connection without telling us.
+/// Returns true if the connection is closed.
+fn report_closed(client: &mut Http3Client, round: usize, waiting: bool) -> bool {
+    let when = if waiting {
+        format!("while waiting after round {}", round)
+    } else {
+        format!("during round {}", round)
+    };
+    match client.conn().state() {
+        State::Closed(ConnectionError::Transport(neqo_transport::Error::IdleTimeout)) => {
+            eprintln!(
+                "Connection idle timed out after {:?} {}; the server probably has a \
+                 shorter idle timeout and stopped responding",
+                client.idle_timeout(),
+                when
+            );
+        }
+        State::Closing { error, .. } | State::Draining { error, .. } | State::Closed(error) => {
+            eprintln!("Connection closed {}: {:?}", when, error);
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// The limit on the response body for the URL at `index` on the command line,
+/// from `--max-response-bytes`.
+fn response_limit(args: &Args, index: usize) -> Option<u64> {
+    args.max_response_bytes
+        .get(index)
+        .or_else(|| args.max_response_bytes.last())
+        .copied()
 }
 
 // This is a bit fancier than actually needed.
 impl Handler for PostConnectHandler {
     fn handle(&mut self, args: &Args, client: &mut Http3Client) -> Res<bool> {
-        let mut data = vec![0; 4000];
+        let mut data = vec![0; args.read_buffer];
         while let Some(event) = client.next_event() {
             match event {
                 Http3ClientEvent::HeaderReady {
                     stream_id,
                     headers,
                     fin,
-                } => match self.streams.get(&stream_id) {
-                    Some(out_file) => {
-                        if out_file.is_none() {
-                            println!("READ HEADERS[{}]: fin={} {:?}", stream_id, fin, headers);
+                    ..
+                } => {
+                    match self.streams.get(&stream_id) {
+                        Some(out_file) => {
+                            if out_file.is_none() {
+                                println!("READ HEADERS[{}]: fin={} {:?}", stream_id, fin, headers);
+                            }
                         }
-                    }
-                    None => {
-                        println!("Data on unexpected stream: {}", stream_id);
-                        return Ok(false);
-                    }
-                },
-                Http3ClientEvent::DataReadable { stream_id } => {
-                    let mut stream_done = false;
-                    match self.streams.get_mut(&stream_id) {
                         None => {
                             println!("Data on unexpected stream: {}", stream_id);
                             return Ok(false);
                         }
-                        Some(out_file) => {
-                            let (sz, fin) = client
-                                .read_response_data(Instant::now(), stream_id, &mut data)
-                                .expect("Read should succeed");
-
-                            if let Some(out_file) = out_file {
-                                if sz > 0 {
-                                    out_file.write_all(&data[..sz])?;
-                                }
-                            } else if !args.output_read_data {
-                                println!("READ[{}]: {} bytes", stream_id, sz);
-                            } else if let Ok(txt) = String::from_utf8(data.clone()) {
-                                println!("READ[{}]: {}", stream_id, txt);
-                            } else {
-                                println!("READ[{}]: 0x{}", stream_id, hex(&data));
-                            }
-
-                            if fin {
-                                if out_file.is_none() {
-                                    println!("<FIN[{}]>", stream_id);
-                                }
-                                stream_done = true;
+                    }
+                    if args.compressed {
+                        let coding = headers.as_ref().and_then(|h| header(h, "content-encoding"));
+                        match compression::Decoder::new(coding) {
+                            Ok(Some(decoder)) => {
+                                self.decoders.insert(stream_id, decoder);
                             }
+                            Ok(None) => {}
+                            Err(coding) => eprintln!(
+                                "WARNING: unable to decode content-encoding {} on stream {}, \
+                                 keeping the body as it is",
+                                coding, stream_id
+                            ),
                         }
                     }
-
-                    if stream_done {
-                        self.streams.remove(&stream_id);
-                        if self.streams.is_empty() {
-                            client.close(Instant::now(), 0, "kthxbye!");
+                    if let Some(resume) = self.resumes.remove(&stream_id) {
+                        let headers = headers.unwrap_or_default();
+                        if self.resume_response(args, client, stream_id, resume, &headers)? {
                             return Ok(false);
                         }
                     }
                 }
+                Http3ClientEvent::Reset {
+                    stream_id, error, ..
+                } => {
+                    if !self.streams.contains_key(&stream_id) {
+                        println!("Reset of unexpected stream: {}", stream_id);
+                        return Ok(false);
+                    }
+                    if error == neqo_http3::Error::HttpExcessiveLoad.code() {
+                        println!(
+                            "TRUNCATED[{}]: headers exceed --max-header-bytes",
+                            stream_id
+                        );
+                        self.failures.truncated += 1;
+                    } else {
+                        println!("RESET[{}]: error {}", stream_id, error);
+                        self.failures.reset += 1;
+                    }
+                    if args.continue_at.is_some()
+                        && matches!(self.streams.get(&stream_id), Some(Some(_)))
+                    {
+                        self.failures.incomplete += 1;
+                    }
+                    if self.stream_done(stream_id, client) {
+                        return Ok(false);
+                    }
+                }
+                Http3ClientEvent::DataWritable { stream_id, .. } => {
+                    self.send_body(client, stream_id)
+                }
+                Http3ClientEvent::AuthenticationNeeded => {
+                    // Only when resuming, if the server didn't accept the ticket.
+                    let status = authentication_status(args, client.conn());
+                    client.authenticated(status, Instant::now());
+                }
+                Http3ClientEvent::ZeroRttRejected => {
+                    if args.verbose > 0 {
+                        eprintln!("0-RTT rejected, sending the requests again");
+                    }
+                }
+                Http3ClientEvent::StopSending { stream_id, .. } => {
+                    // The server doesn't want the rest of the body, but it can still respond.
+                    println!("STOP_SENDING[{}]", stream_id);
+                    self.bodies.remove(&stream_id);
+                }
+                Http3ClientEvent::TrailerReady { stream_id, .. } => {
+                    if let Some(trailers) = client.get_trailers(stream_id) {
+                        if let Some(None) = self.streams.get(&stream_id) {
+                            println!("READ TRAILERS[{}]: {:?}", stream_id, trailers);
+                        }
+                    }
+                }
+                Http3ClientEvent::DataReadable { stream_id, .. } => {
+                    if !self.streams.contains_key(&stream_id) {
+                        println!("Data on unexpected stream: {}", stream_id);
+                        return Ok(false);
+                    }
+                    if self.read_response(args, client, stream_id, &mut data)?
+                        && self.stream_done(stream_id, client)
+                    {
+                        return Ok(false);
+                    }
+                }
+                Http3ClientEvent::PushPromise {
+                    push_id,
+                    request_stream_id,
+                    headers,
+                } => println!(
+                    "PUSH_PROMISE[{}] on stream {}: {:?}",
+                    push_id, request_stream_id, headers
+                ),
+                Http3ClientEvent::PushHeaderReady {
+                    push_id,
+                    headers,
+                    fin,
+                } => println!("PUSH HEADERS[{}]: fin={} {:?}", push_id, fin, headers),
+                Http3ClientEvent::PushDataReadable { push_id } => {
+                    read_push(args, client, push_id, &mut data)?
+                }
+                Http3ClientEvent::PushCanceled { push_id } => {
+                    println!("PUSH CANCELED[{}]", push_id)
+                }
                 _ => {}
             }
         }
@@ -331,84 +1470,397 @@ impl Handler for PostConnectHandler {
     }
 }
 
-fn to_headers(values: &[impl AsRef<str>]) -> Vec<Header> {
-    values
-        .iter()
-        .scan(None, |state, value| {
-            if let Some(name) = state.take() {
-                *state = None;
-                Some((name, value.as_ref().to_string())) // TODO use a real type
-            } else {
-                *state = Some(value.as_ref().to_string());
-                None
+/// Read and print what has arrived of a pushed response.
+fn read_push(args: &Args, client: &mut Http3Client, push_id: u64, data: &mut [u8]) -> Res<()> {
+    loop {
+        let (sz, fin) = client.push_read_data(Instant::now(), push_id, data)?;
+        if sz == 0 && !fin {
+            return Ok(());
+        }
+        let body = &data[..sz];
+        if !args.output_read_data {
+            println!("PUSH READ[{}]: {} bytes", push_id, sz);
+        } else if let Ok(txt) = std::str::from_utf8(body) {
+            println!("PUSH READ[{}]: {}", push_id, txt);
+        } else {
+            println!("PUSH READ[{}]: 0x{}", push_id, hex(body));
+        }
+        if fin {
+            println!("<PUSH FIN[{}]>", push_id);
+            return Ok(());
+        }
+    }
+}
+
+/// Resume with a ticket from `store`, if there is a usable one for `origin`.
+fn resume_from(
+    store: &mut dyn ResumptionStore,
+    client: &mut Http3Client,
+    args: &Args,
+    origin: &str,
+) {
+    if let Some(t) = store.get(origin, SystemTime::now()) {
+        // The path is the same whether or not the ticket can be used.  Hints have
+        // to be set before resuming starts the connection.
+        if let Some(path_info) = PathInfo::decode(&mut Decoder::from(&t.path_info[..])) {
+            if let Err(e) = client.conn().set_path_hints(&path_info, false) {
+                eprintln!("The saved path information can't be used: {:?}", e);
             }
-        })
-        .collect()
+        }
+        if !args.alpn.contains(&t.alpn) {
+            eprintln!("Not resuming: the saved session is for ALPN {}", t.alpn);
+        } else if let Err(e) = client.set_resumption_token(Instant::now(), &t.ticket) {
+            eprintln!("Not resuming: the saved session can't be used: {:?}", e);
+        } else if args.verbose > 0 {
+            eprintln!("Resuming a saved session");
+        }
+    }
+}
+
+/// Keep the ticket from `client` in `store`, if the server sent one.  The store
+/// drops it when the lifetime the server gave it runs out.  Returns false if
+/// there was no ticket to keep.
+fn save_ticket(store: &mut dyn ResumptionStore, client: &mut Http3Client, origin: &str) -> bool {
+    let alpn = client.conn().tls_info().and_then(|i| i.alpn().cloned());
+    let expires = client.conn().resumption_token_expiration().map(|t| {
+        // The store outlives this process, so it needs a wall clock time.
+        SystemTime::now() + t.saturating_duration_since(Instant::now())
+    });
+    if let (Some(ticket), Some(alpn), Some(expires)) = (client.resumption_token(), alpn, expires) {
+        let mut path_info = Encoder::default();
+        if let Some(info) = client.conn().path_info() {
+            info.encode(&mut path_info);
+        }
+        store.put(
+            origin,
+            ResumptionTicket {
+                ticket,
+                token: None,
+                transport_params: Vec::new(),
+                alpn,
+                path_info: path_info.into(),
+                expires,
+            },
+        );
+        true
+    } else {
+        false
+    }
+}
+
+/// Write `milestones` as a JSON object, with each time relative to the first
+/// packet sent, or null for milestones that weren't reached.
+fn milestones_json(milestones: &Http3Milestones) -> String {
+    let mut all = milestones.transport.reached();
+    if let Some(t) = milestones.first_response {
+        all.push(("first_response", t));
+    }
+    let start = milestones.transport.first_sent;
+    let fields = [
+        "first_sent",
+        "first_received",
+        "handshake_keys",
+        "handshake_complete",
+        "handshake_confirmed",
+        "first_stream_data",
+        "first_response",
+    ]
+    .iter()
+    .map(|name| {
+        let value = all.iter().find(|(n, _)| n == name).map_or_else(
+            || "null".to_string(),
+            |(_, t)| format!("{:.3}", (*t - start.unwrap()).as_secs_f64() * 1000.0),
+        );
+        format!("\"{}\": {}", name, value)
+    })
+    .collect::<Vec<_>>();
+    format!("{{{}}}", fields.join(", "))
+}
+
+fn zero_rtt_summary(state: &ZeroRttState) -> &'static str {
+    match state {
+        ZeroRttState::AcceptedClient | ZeroRttState::AcceptedServer => "accepted",
+        ZeroRttState::Rejected => "rejected, the requests were sent again",
+        ZeroRttState::Init | ZeroRttState::Sending => "not attempted",
+    }
+}
+
+/// The transport parameters for an HTTP/3 connection: those that `Http3Client::new`
+/// would use, unless the arguments say otherwise.
+fn http3_parameters(args: &Args) -> Http3Parameters {
+    let h3 = Http3Parameters::default();
+    let mut params = h3.get_connection_parameters().clone();
+    if let Some(max_data) = args.max_data {
+        params = params.max_data(max_data);
+    }
+    if let Some(max_stream_data) = args.max_stream_data {
+        params = params
+            .max_stream_data_bidi_local(max_stream_data)
+            .max_stream_data_bidi_remote(max_stream_data)
+            .max_stream_data_uni(max_stream_data);
+    }
+    if let Some(max_streams) = args.max_streams_bidi {
+        params = params.max_streams_bidi(max_streams);
+    }
+    if let Some(max_streams) = args.max_streams_uni {
+        params = params.max_streams_uni(max_streams);
+    }
+    if let Some(timeout) = args.idle_timeout {
+        params = params.idle_timeout(Duration::from_secs(timeout));
+    }
+    if let Some(max_packet_size) = args.max_packet_size {
+        params = params.max_packet_size(max_packet_size);
+    }
+    h3.connection_parameters(params)
 }
 
+/// Fetch the URLs in `requests`, each with its place on the command line, and
+/// return what went wrong with the responses.
 fn client(
     args: &Args,
     socket: UdpSocket,
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
     origin: &str,
-    urls: &[Url],
-) -> Res<()> {
-    let mut client = Http3Client::new(
+    requests: &[(usize, Url)],
+) -> Res<Failures> {
+    let mut client = Http3Client::new_with_params(
         origin,
         &args.alpn,
         Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
         local_addr,
         remote_addr,
+        &http3_parameters(args),
         QpackSettings {
             max_table_size_encoder: args.max_table_size_encoder,
             max_table_size_decoder: args.max_table_size_decoder,
             max_blocked_streams: args.max_blocked_streams,
         },
-    )
-    .expect("must succeed");
-    client.set_qlog(qlog_new(args, origin)?);
-    // Temporary here to help out the type inference engine
-    let mut h = PreConnectHandler {};
-    process_loop(
-        &local_addr,
-        &remote_addr,
-        &socket,
-        &mut client,
-        &mut h,
-        &args,
     )?;
+    if let Some(rate) = args.limit_rate {
+        client
+            .conn()
+            .set_max_send_rate(rate, true)
+            .expect("should set rate limit");
+    }
+    client
+        .conn()
+        .set_pacing_granularity(Duration::from_millis(args.pacing_granularity));
+    if let Some(padding) = args.pad_client_hello {
+        client
+            .conn()
+            .set_handshake_padding(padding)
+            .expect("should set padding");
+    }
+    if let Some(limit) = args.max_header_bytes {
+        client.set_max_header_list_size(limit)?;
+    }
+    client.set_max_push(args.max_push)?;
+    client.set_qlog(qlog_new(args, origin)?);
+    let mut sock = ClientSocket::new(args, socket, local_addr, remote_addr);
+    let mut store = args
+        .session_file
+        .as_ref()
+        .map(|path| FileResumptionStore::open(path, DEFAULT_TICKETS_PER_ORIGIN));
+    // Requests are made straight away when resuming, and are sent again if the server
+    // rejects 0-RTT.
+    client.set_replay_0rtt(true);
+    if let Some(store) = &mut store {
+        resume_from(store, &mut client, args, origin);
+    }
+    if let Some(path) = &args.session_ticket {
+        // This file holds one ticket, which is gone once it is used.
+        let mut ticket = FileResumptionStore::open(path, 1);
+        resume_from(&mut ticket, &mut client, args, origin);
+        ticket.save()?;
+    }
+    if client.state() != Http3State::ZeroRtt {
+        // Temporary here to help out the type inference engine
+        let mut h = PreConnectHandler::new();
+        process_loop(&mut sock, &mut client, &mut h, &args)?;
+        if args.verbose > 0 {
+            eprintln!("handshake: {}", client.conn().stats().handshake);
+            eprintln!("idle timeout: {:?}", client.idle_timeout());
+        }
+    }
 
-    let mut h2 = PostConnectHandler::default();
+    let mut failures = Failures::default();
+    for round in 1..=args.repeat {
+        if round > 1 && args.interval > 0 {
+            let mut idle = IdleHandler::new(Duration::from_millis(args.interval));
+            process_loop(&mut sock, &mut client, &mut idle, &args)?;
+            if report_closed(&mut client, round - 1, true) {
+                break;
+            }
+        }
 
-    let mut open_paths = Vec::new();
+        let start = Instant::now();
+        let (packets_tx, packets_rx) = {
+            let stats = client.conn().stats();
+            (stats.packets_tx, stats.packets_rx)
+        };
 
-    for url in urls {
-        let client_stream_id = client.fetch(
-            &args.method,
-            &url.scheme(),
-            &url.host_str().unwrap(),
-            &url.path(),
-            &to_headers(&args.header),
-        )?;
+        let mut h2 = PostConnectHandler {
+            close_when_done: round == args.repeat,
+            key_update: args.key_update && round == 1,
+            ..PostConnectHandler::default()
+        };
+        // Each round writes the same files again.
+        let mut open_paths = Vec::new();
+
+        for (index, url) in requests {
+            let resume = args.continue_at.is_some();
+            let out_file = get_output_file(url, &args.output_dir, resume, &mut open_paths);
+            let offset = out_file.as_ref().map_or(0, |(_, offset)| *offset);
 
-        let _ = client.stream_close_send(client_stream_id);
+            let mut headers = args.header.clone();
+            if offset > 0 {
+                headers.push((String::from("range"), format!("bytes={}-", offset)));
+            }
+            if args.compressed && header(&headers, "accept-encoding").is_none() {
+                headers.push((
+                    String::from("accept-encoding"),
+                    String::from(compression::ACCEPT_ENCODING),
+                ));
+            }
+            let client_stream_id = client.fetch_url(&args.method, url, &headers)?;
+
+            h2.start_body(args, &mut client, client_stream_id);
+
+            if resume && out_file.is_some() {
+                h2.resumes.insert(
+                    client_stream_id,
+                    Resume {
+                        url: url.clone(),
+                        offset,
+                    },
+                );
+            }
+            h2.streams
+                .insert(client_stream_id, out_file.map(|(out_file, _)| out_file));
+            if let Some(limit) = response_limit(args, *index) {
+                h2.remaining.insert(client_stream_id, limit);
+            }
+        }
 
-        let out_file = get_output_file(url, &args.output_dir, &mut open_paths);
+        process_loop(&mut sock, &mut client, &mut h2, &args)?;
+        failures.add(&h2.failures);
+        if args.continue_at.is_some() {
+            // Whatever is still open was cut short and can be continued.
+            failures.incomplete += h2.streams.values().filter(|f| f.is_some()).count();
+        }
 
-        h2.streams.insert(client_stream_id, out_file);
+        if args.repeat > 1 {
+            let path = client.conn().path_info();
+            let stats = client.conn().stats();
+            eprintln!(
+                "Round {}: {:?}, {} packets sent, {} received, rtt {:?}, cwnd {:?}",
+                round,
+                start.elapsed(),
+                stats.packets_tx - packets_tx,
+                stats.packets_rx - packets_rx,
+                path.map(|p| p.rtt),
+                path.map(|p| p.cwnd),
+            );
+        }
+        if round < args.repeat && report_closed(&mut client, round, false) {
+            break;
+        }
     }
 
-    process_loop(
-        &local_addr,
-        &remote_addr,
-        &socket,
-        &mut client,
-        &mut h2,
-        &args,
-    )?;
+    if let Some(store) = &mut store {
+        save_ticket(store, &mut client, origin);
+        if let Err(e) = store.save() {
+            eprintln!("Unable to save the session: {}", e);
+        }
+    }
+    if let Some(path) = &args.output_session_ticket {
+        let mut ticket = FileResumptionStore::open(path, 1);
+        if save_ticket(&mut ticket, &mut client, origin) {
+            ticket.save()?;
+        } else {
+            eprintln!("The server didn't send a session ticket");
+        }
+    }
 
-    Ok(())
+    if args.verbose > 0 {
+        eprintln!(
+            "0-RTT: {}",
+            zero_rtt_summary(client.conn().zero_rtt_state())
+        );
+        eprintln!("UDP: {}", sock.stats);
+        eprintln!("sent: {}", client.efficiency_stats());
+        eprintln!("lost: {} packets", client.conn().stats().lost);
+    }
+    let local_drops = client.conn().stats().local_drops_rx;
+    if local_drops > 0 {
+        // This is easily mistaken for loss, so it is reported even without -v.
+        eprintln!(
+            "WARNING: this host dropped {} datagrams because the socket receive buffer \
+             was full; that isn't the network, and a larger buffer (net.core.rmem_max \
+             on Linux) fixes it",
+            local_drops
+        );
+    }
+    if args.stats {
+        eprintln!("stats: {}", client.stats());
+    }
+    if args.json {
+        println!("{}", milestones_json(&client.milestones()));
+    }
+    Ok(failures)
+}
+
+/// How many times the requests are made again from a new socket, after the local
+/// address stops working, before giving up.
+const MAX_REBINDS: usize = 3;
+
+/// Like `client`, but when the local address stops working and the connection can't
+/// move, the requests are made again from a new socket, on a new connection.
+/// Downloads into `--output-dir` continue where they stopped, as they would with
+/// `--continue-at -`.
+fn rebinding_client(
+    args: &mut Args,
+    mut socket: UdpSocket,
+    mut local_addr: SocketAddr,
+    mut remote_addr: SocketAddr,
+    origin: &str,
+    requests: &[(usize, Url)],
+) -> Res<Failures> {
+    let continue_at = args.continue_at.clone();
+    let simulate_rebind = args.simulate_rebind;
+    let nat_rebind = args.nat_rebind;
+    let mut rebinds = 0;
+    let res = loop {
+        match client(args, socket, local_addr, remote_addr, origin, requests) {
+            Err(ClientError::Rebind(why)) if rebinds < MAX_REBINDS => {
+                rebinds += 1;
+                match connect_socket(remote_addr) {
+                    Ok((s, l, r)) => {
+                        eprintln!("Rebound after {}, making the requests again", why);
+                        socket = s;
+                        local_addr = l;
+                        remote_addr = r;
+                    }
+                    Err(e) => {
+                        eprintln!("Unable to rebind after {}: {}", why, e);
+                        break Err(e.into());
+                    }
+                }
+                // A decoded body can't be continued from what was written.
+                if args.output_dir.is_some() && !args.compressed {
+                    args.continue_at = Some(String::from("-"));
+                }
+                args.simulate_rebind = None;
+                args.nat_rebind = None;
+            }
+            res => break res,
+        }
+    };
+    args.continue_at = continue_at;
+    args.simulate_rebind = simulate_rebind;
+    args.nat_rebind = nat_rebind;
+    res
 }
 
 fn qlog_new(args: &Args, origin: &str) -> Res<Option<NeqoQlog>> {
@@ -438,10 +1890,64 @@ fn qlog_new(args: &Args, origin: &str) -> Res<Option<NeqoQlog>> {
     }
 }
 
+/// The features that neqo-client was built with.
+const FEATURES: &[(&str, bool)] = &[("deny-warnings", cfg!(feature = "deny-warnings"))];
+
+fn build_info() -> common::BuildInfo {
+    let mut info = neqo_http3::build_info();
+    let features = FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+    info.add("neqo-client", env!("CARGO_PKG_VERSION"))
+        .add("hq-alpn", "hq-27")
+        .add("features", features.join(","));
+    info
+}
+
+/// Parse the command line, and say whether it is for `neqo-client doctor <url>`.
+/// That takes the same options as a fetch, before or after the word "doctor".
+/// "doctor" isn't a URL, so it is the command if the command line only parses
+/// without it; this doesn't need to know which options take values.
+fn parse_args(argv: &[OsString]) -> Result<(Args, bool), clap::Error> {
+    let mut err = match Args::from_iter_safe(argv) {
+        Ok(args) => return Ok((args, false)),
+        Err(e) => e,
+    };
+    for (i, a) in argv.iter().enumerate().skip(1) {
+        if a == "doctor" {
+            let mut rest = argv.to_vec();
+            rest.remove(i);
+            match Args::from_iter_safe(rest) {
+                Ok(args) => return Ok((args, true)),
+                // This is more useful than complaining that "doctor" isn't a URL.
+                Err(e) => err = e,
+            }
+        }
+    }
+    Err(err)
+}
+
 fn main() -> Res<()> {
-    init();
+    let argv = env::args_os().collect::<Vec<_>>();
+    let (mut args, doctor) = parse_args(&argv).unwrap_or_else(|e| e.exit());
+    if args.version {
+        print!("{}", build_info());
+        return Ok(());
+    }
+
+    if doctor {
+        // Show as much as there is, unless something else is asked for.
+        if env::var_os("RUST_LOG").is_none() {
+            env::set_var("RUST_LOG", "neqo=debug");
+        }
+        if args.verbose < 2 {
+            args.verbose = 2;
+        }
+    }
 
-    let mut args = Args::from_args();
+    init();
 
     let mut resumption_test = false;
 
@@ -470,18 +1976,102 @@ fn main() -> Res<()> {
                 args.use_old_http = true;
                 ciphers = Some(&[TLS_CHACHA20_POLY1305_SHA256]);
             }
+            Ok(s) if s == "keyupdate" => {
+                args.use_old_http = true;
+                args.key_update = true;
+            }
             Ok(_) => exit(127),
             Err(_) => exit(1),
         }
     }
 
-    let mut urls_by_origin: HashMap<Origin, Vec<Url>> = HashMap::new();
-    for url in &args.urls {
+    if let Some(path) = &args.trust {
+        args.trusted_certs = load_certs(path)?;
+        eprintln!(
+            "WARNING: trusting {} certificate(s) from {} for this run only; \
+             a successful connection does not mean that the server has a valid certificate",
+            args.trusted_certs.len(),
+            path.display()
+        );
+    }
+    if args.session_file.is_some() && args.use_old_http {
+        eprintln!("--session-file needs HTTP/3");
+        exit(1);
+    }
+    if (args.session_ticket.is_some() || args.output_session_ticket.is_some()) && args.use_old_http
+    {
+        eprintln!("--session-ticket and --output-session-ticket need HTTP/3");
+        exit(1);
+    }
+    if args.session_ticket.is_some() && args.session_file.is_some() {
+        eprintln!("--session-ticket and --session-file can't both be used");
+        exit(1);
+    }
+    if (args.simulate_rebind.is_some() || args.nat_rebind.is_some()) && args.use_old_http {
+        eprintln!("--simulate-rebind and --nat-rebind need HTTP/3");
+        exit(1);
+    }
+    if let Some(data) = &args.data {
+        if args.use_old_http {
+            eprintln!("--data needs HTTP/3");
+            exit(1);
+        }
+        args.body = Some(if data.starts_with('@') {
+            fs::read(&data[1..])?
+        } else {
+            data.clone().into_bytes()
+        });
+    }
+    if args.repeat == 0 {
+        eprintln!("--repeat needs to be at least 1");
+        exit(1);
+    }
+    if let Some(at) = &args.continue_at {
+        if at != "-" {
+            eprintln!("--continue-at only supports \"-\"");
+            exit(1);
+        }
+        if args.output_dir.is_none() || args.use_old_http {
+            eprintln!("--continue-at needs --output-dir and HTTP/3");
+            exit(1);
+        }
+    }
+    if args.compressed {
+        if !cfg!(feature = "compression") {
+            eprintln!("--compressed needs a build with the compression feature");
+            exit(1);
+        }
+        if args.continue_at.is_some() || args.use_old_http {
+            eprintln!("--compressed needs HTTP/3 and can't be used with --continue-at");
+            exit(1);
+        }
+    }
+    if let Some(name) = &args.verify_hostname {
+        eprintln!(
+            "WARNING: using {} as the server name instead of the host from the URL",
+            name
+        );
+    }
+
+    if doctor {
+        if args.urls.len() != 1 || args.use_old_http {
+            eprintln!("doctor checks one URL, with HTTP/3");
+            exit(1);
+        }
+        let healthy = doctor::run(&args)?;
+        exit(if healthy { 0 } else { 1 });
+    }
+
+    let mut failures = Failures::default();
+    // Each URL is kept with its place on the command line, which options that are
+    // given once per URL use.
+    let mut urls_by_origin: HashMap<Origin, Vec<(usize, Url)>> = HashMap::new();
+    for (index, url) in args.urls.iter().enumerate() {
         let entry = urls_by_origin.entry(url.origin()).or_default();
-        entry.push(url.clone());
+        entry.push((index, url.clone()));
     }
 
-    for ((_scheme, host, port), mut urls) in urls_by_origin.into_iter().filter_map(|(k, v)| match k
+    for ((_scheme, host, port), requests) in urls_by_origin.into_iter().filter_map(|(k, v)| match k
     {
         Origin::Tuple(s, h, p) => Some(((s, h, p), v)),
         Origin::Opaque(x) => {
@@ -489,24 +2079,17 @@ fn main() -> Res<()> {
             None
         }
     }) {
+        let mut urls: Vec<Url> = requests.iter().map(|(_, url)| url.clone()).collect();
         let addrs: Vec<_> = format!("{}:{}", host, port).to_socket_addrs()?.collect();
         let remote_addr = *addrs.first().unwrap();
 
-        let local_addr = match remote_addr {
-            SocketAddr::V4(..) => SocketAddr::new(IpAddr::V4(Ipv4Addr::from([0; 4])), 0),
-            SocketAddr::V6(..) => SocketAddr::new(IpAddr::V6(Ipv6Addr::from([0; 16])), 0),
-        };
-
-        let socket = match UdpSocket::bind(local_addr) {
+        let (socket, local_addr, remote_addr) = match connect_socket(remote_addr) {
+            Ok(s) => s,
             Err(e) => {
-                eprintln!("Unable to bind UDP socket: {}", e);
+                eprintln!("Unable to set up a UDP socket: {}", e);
                 exit(1)
             }
-            Ok(s) => s,
         };
-        socket
-            .connect(&remote_addr)
-            .expect("Unable to connect UDP socket");
 
         println!(
             "{} Client connecting: {:?} -> {:?}",
@@ -515,15 +2098,21 @@ fn main() -> Res<()> {
             remote_addr
         );
 
+        let server_name = args
+            .verify_hostname
+            .clone()
+            .unwrap_or_else(|| format!("{}", host));
+
         if !args.use_old_http {
-            client(
-                &args,
+            let f = rebinding_client(
+                &mut args,
                 socket,
                 local_addr,
                 remote_addr,
-                &format!("{}", host),
-                &urls,
+                &server_name,
+                &requests,
             )?;
+            failures.add(&f);
         } else if !args.download_in_series {
             let token = if resumption_test {
                 // Download first URL using a separate connection, save the token and use it for
@@ -540,7 +2129,7 @@ fn main() -> Res<()> {
                     &socket,
                     local_addr,
                     remote_addr,
-                    &format!("{}", host),
+                    &server_name,
                     &[first_url],
                     None,
                     ciphers,
@@ -554,13 +2143,13 @@ fn main() -> Res<()> {
                 &socket,
                 local_addr,
                 remote_addr,
-                &format!("{}", host),
+                &server_name,
                 &urls,
                 token,
                 ciphers,
             )?;
         } else {
-            let mut token: Option<Vec<u8>> = None;
+            let mut token: Option<old::Session> = None;
 
             for url in urls {
                 token = old::old_client(
@@ -568,7 +2157,7 @@ fn main() -> Res<()> {
                     &socket,
                     local_addr,
                     remote_addr,
-                    &format!("{}", host),
+                    &server_name,
                     &[url],
                     token,
                     ciphers,
@@ -577,6 +2166,25 @@ fn main() -> Res<()> {
         }
     }
 
+    if failures.incomplete > 0 {
+        eprintln!(
+            "{} download(s) incomplete, run again with --continue-at - to continue",
+            failures.incomplete
+        );
+        exit(3);
+    }
+    if failures.undecodable > 0 {
+        eprintln!("{} response(s) could not be decoded", failures.undecodable);
+        exit(4);
+    }
+    if failures.truncated > 0 {
+        eprintln!("{} response(s) truncated", failures.truncated);
+        exit(2);
+    }
+    if failures.reset > 0 {
+        eprintln!("{} request(s) reset by the server", failures.reset);
+        exit(5);
+    }
     Ok(())
 }
 
@@ -584,7 +2192,7 @@ mod old {
     use std::cell::RefCell;
     use std::collections::HashMap;
     use std::fs::File;
-    use std::io::{ErrorKind, Write};
+    use std::io::Write;
     use std::net::{SocketAddr, UdpSocket};
     use std::process::exit;
     use std::rc::Rc;
@@ -592,15 +2200,23 @@ mod old {
 
     use url::Url;
 
-    use super::{qlog_new, Res};
+    use super::{qlog_new, udp, Res};
 
     use neqo_common::{matches, Datagram};
     use neqo_crypto::{AuthenticationStatus, Cipher};
     use neqo_transport::{
-        Connection, ConnectionEvent, FixedConnectionIdManager, Output, State, StreamType,
+        Connection, ConnectionEvent, FixedConnectionIdManager, Output, PathInfo, State, StreamType,
+    };
+
+    use super::{
+        authentication_status, emit_datagram, get_output_file, key_update, print_dispositions, Args,
     };
 
-    use super::{emit_datagram, get_output_file, Args};
+    /// What is remembered from one connection for use by the next.
+    pub struct Session {
+        token: Vec<u8>,
+        path_info: Option<PathInfo>,
+    }
 
     trait HandlerOld {
         fn handle(&mut self, args: &Args, client: &mut Connection) -> Res<bool>;
@@ -608,10 +2224,11 @@ mod old {
 
     struct PreConnectHandlerOld {}
     impl HandlerOld for PreConnectHandlerOld {
-        fn handle(&mut self, _args: &Args, client: &mut Connection) -> Res<bool> {
+        fn handle(&mut self, args: &Args, client: &mut Connection) -> Res<bool> {
             let authentication_needed = |e| matches!(e, ConnectionEvent::AuthenticationNeeded);
             if client.events().any(authentication_needed) {
-                client.authenticated(AuthenticationStatus::Ok, Instant::now());
+                let status = authentication_status(args, client);
+                client.authenticated(status, Instant::now());
             }
             Ok(State::Connected != *dbg!(client.state()))
         }
@@ -620,44 +2237,67 @@ mod old {
     #[derive(Default)]
     struct PostConnectHandlerOld {
         streams: HashMap<u64, Option<File>>,
+        key_update: bool,
+    }
+
+    impl PostConnectHandlerOld {
+        /// Read from `stream_id` until nothing more is available.  Returns true once
+        /// the stream has ended.
+        fn read(
+            &mut self,
+            args: &Args,
+            client: &mut Connection,
+            stream_id: u64,
+            data: &mut [u8],
+        ) -> Res<bool> {
+            loop {
+                let (sz, fin) = client
+                    .stream_recv(stream_id, data)
+                    .expect("Read should succeed");
+                if sz == 0 && !fin {
+                    return Ok(false);
+                }
+                if self.key_update && sz > 0 {
+                    key_update(&mut self.key_update, client);
+                }
+
+                let mut have_out_file = false;
+                if let Some(Some(out_file)) = self.streams.get_mut(&stream_id) {
+                    have_out_file = true;
+                    if sz > 0 {
+                        out_file.write_all(&data[..sz])?;
+                    }
+                } else if !args.output_read_data {
+                    println!("READ[{}]: {} bytes", stream_id, sz);
+                } else {
+                    println!(
+                        "READ[{}]: {}",
+                        stream_id,
+                        String::from_utf8_lossy(&data[..sz])
+                    )
+                }
+                if fin {
+                    if !have_out_file {
+                        println!("<FIN[{}]>", stream_id);
+                    }
+                    return Ok(true);
+                }
+            }
+        }
     }
 
     // This is a bit fancier than actually needed.
     impl HandlerOld for PostConnectHandlerOld {
         fn handle(&mut self, args: &Args, client: &mut Connection) -> Res<bool> {
-            let mut data = vec![0; 4000];
+            let mut data = vec![0; args.read_buffer];
             while let Some(event) = client.next_event() {
                 match event {
                     ConnectionEvent::RecvStreamReadable { stream_id } => {
-                        let out_file = self.streams.get_mut(&stream_id);
-                        if out_file.is_none() {
+                        if !self.streams.contains_key(&stream_id) {
                             println!("Data on unexpected stream: {}", stream_id);
                             return Ok(false);
                         }
-
-                        let (sz, fin) = client
-                            .stream_recv(stream_id, &mut data)
-                            .expect("Read should succeed");
-
-                        let mut have_out_file = false;
-                        if let Some(Some(out_file)) = out_file {
-                            have_out_file = true;
-                            if sz > 0 {
-                                out_file.write_all(&data[..sz])?;
-                            }
-                        } else if !args.output_read_data {
-                            println!("READ[{}]: {} bytes", stream_id, sz);
-                        } else {
-                            println!(
-                                "READ[{}]: {}",
-                                stream_id,
-                                String::from_utf8(data.clone()).unwrap()
-                            )
-                        }
-                        if fin {
-                            if !have_out_file {
-                                println!("<FIN[{}]>", stream_id);
-                            }
+                        if self.read(args, client, stream_id, &mut data)? {
                             self.streams.remove(&stream_id);
                             if self.streams.is_empty() {
                                 client.close(Instant::now(), 0, "kthxbye!");
@@ -706,12 +2346,12 @@ mod old {
                         }
                     }
                     Output::Callback(duration) => {
-                        socket.set_read_timeout(Some(duration)).unwrap();
+                        udp::set_read_timeout(socket, Some(duration))?;
                         break;
                     }
                     Output::None => {
                         // Not strictly necessary, since we're about to exit
-                        socket.set_read_timeout(None).unwrap();
+                        udp::set_read_timeout(socket, None)?;
                         exiting = true;
                         break;
                     }
@@ -723,16 +2363,18 @@ mod old {
             }
 
             let sz = match socket.recv(&mut buf[..]) {
-                Err(ref err)
-                    if err.kind() == ErrorKind::WouldBlock
-                        || err.kind() == ErrorKind::Interrupted =>
-                {
-                    0
-                }
-                Err(err) => {
-                    eprintln!("UDP error: {}", err);
-                    exit(1)
-                }
+                Err(err) => match udp::classify(&err) {
+                    udp::ErrorClass::Retry => 0,
+                    udp::ErrorClass::Unreachable => {
+                        eprintln!("UDP: peer unreachable: {}", err);
+                        0
+                    }
+                    udp::ErrorClass::TooLarge => buf.len(),
+                    udp::ErrorClass::AddressGone | udp::ErrorClass::Fatal => {
+                        eprintln!("UDP error: {}", err);
+                        exit(1)
+                    }
+                },
                 Ok(sz) => sz,
             };
             if sz == buf.len() {
@@ -742,6 +2384,7 @@ mod old {
             if sz > 0 {
                 let d = Datagram::new(*remote_addr, *local_addr, &buf[..sz]);
                 client.process_input(d, Instant::now());
+                print_dispositions(args, client.take_datagram_dispositions());
             }
         }
     }
@@ -754,9 +2397,9 @@ mod old {
         remote_addr: SocketAddr,
         origin: &str,
         urls: &[Url],
-        token: Option<Vec<u8>>,
+        session: Option<Session>,
         ciphers: Option<&[Cipher]>,
-    ) -> Res<Option<Vec<u8>>> {
+    ) -> Res<Option<Session>> {
         let mut open_paths = Vec::new();
 
         let mut client = Connection::new_client(
@@ -768,16 +2411,34 @@ mod old {
         )
         .expect("must succeed");
 
-        if let Some(tok) = token {
+        if let Some(session) = session {
             client
-                .set_resumption_token(Instant::now(), &tok)
+                .set_resumption_token(Instant::now(), &session.token)
                 .expect("should set token");
+            if let Some(path_info) = session.path_info {
+                client
+                    .set_path_hints(&path_info, false)
+                    .expect("should set path hints");
+            }
         }
 
         if let Some(cip) = ciphers {
             client.enable_ciphers(cip).expect("Cannot enable ciphers");
         }
 
+        if let Some(rate) = args.limit_rate {
+            client
+                .set_max_send_rate(rate, true)
+                .expect("should set rate limit");
+        }
+        client.set_pacing_granularity(Duration::from_millis(args.pacing_granularity));
+
+        if let Some(padding) = args.pad_client_hello {
+            client
+                .set_handshake_padding(padding)
+                .expect("should set padding");
+        }
+
         client.set_qlog(qlog_new(args, origin)?);
         // Temporary here to help out the type inference engine
         let mut h = PreConnectHandlerOld {};
@@ -789,8 +2450,14 @@ mod old {
             &mut h,
             &args,
         )?;
+        if args.verbose > 0 {
+            eprintln!("handshake: {}", client.stats().handshake);
+        }
 
-        let mut h2 = PostConnectHandlerOld::default();
+        let mut h2 = PostConnectHandlerOld {
+            key_update: args.key_update,
+            ..PostConnectHandlerOld::default()
+        };
 
         for url in urls {
             let client_stream_id = client.stream_create(StreamType::BiDi).unwrap();
@@ -799,7 +2466,8 @@ mod old {
                 .stream_send(client_stream_id, req.as_bytes())
                 .unwrap();
             let _ = client.stream_close_send(client_stream_id);
-            let out_file = get_output_file(url, &args.output_dir, &mut open_paths);
+            let out_file = get_output_file(url, &args.output_dir, false, &mut open_paths)
+                .map(|(out_file, _)| out_file);
             h2.streams.insert(client_stream_id, out_file);
         }
 
@@ -811,11 +2479,182 @@ mod old {
             &mut h2,
             &args,
         )?;
+        if args.stats {
+            eprintln!("stats: {}", client.stats());
+        }
 
         Ok(if args.resume {
-            client.resumption_token()
+            client.resumption_token().map(|token| Session {
+                token,
+                path_info: client.path_info(),
+            })
         } else {
             None
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_resume, parse_args, parse_certs, parse_content_range, parse_header, pinned,
+        response_limit, Args, ResumeCheck,
+    };
+    use std::ffi::OsString;
+    use structopt::StructOpt;
+
+    fn response(status: &str, content_range: Option<&str>) -> Vec<(String, String)> {
+        let mut headers = vec![(String::from(":status"), String::from(status))];
+        if let Some(range) = content_range {
+            headers.push((String::from("content-range"), String::from(range)));
+        }
+        headers
+    }
+
+    #[test]
+    fn content_range() {
+        assert_eq!(
+            parse_content_range("bytes 10-19/100"),
+            Some((Some((10, 19)), Some(100)))
+        );
+        assert_eq!(
+            parse_content_range("bytes 10-19/*"),
+            Some((Some((10, 19)), None))
+        );
+        assert_eq!(parse_content_range("bytes */100"), Some((None, Some(100))));
+        assert_eq!(parse_content_range("bytes */*"), None);
+        assert_eq!(parse_content_range("bytes 19-10/100"), None);
+        assert_eq!(parse_content_range("bytes 10-100/100"), None);
+        assert_eq!(parse_content_range("bytes 10-19"), None);
+        assert_eq!(parse_content_range("lines 10-19/100"), None);
+    }
+
+    #[test]
+    fn header() {
+        let header = |name: &str, value: &str| -> Result<_, String> {
+            Ok((String::from(name), String::from(value)))
+        };
+        assert_eq!(
+            parse_header("Accept: text/html, application/xhtml+xml"),
+            header("accept", "text/html, application/xhtml+xml")
+        );
+        assert_eq!(
+            parse_header("x-time:\t12:30:00 "),
+            header("x-time", "12:30:00")
+        );
+        assert_eq!(parse_header("x-note: (a, b)"), header("x-note", "(a, b)"));
+        assert_eq!(parse_header("x-empty:"), header("x-empty", ""));
+        assert_eq!(parse_header("x-empty:  "), header("x-empty", ""));
+        assert!(parse_header("no-colon").is_err());
+        assert!(parse_header(": value").is_err());
+        assert!(parse_header(":path: /").is_err());
+        assert!(parse_header("bad name: value").is_err());
+        assert!(parse_header("bad(name): value").is_err());
+    }
+
+    #[test]
+    fn resume() {
+        for (offset, status, range, check) in &[
+            (10, "206", Some("bytes 10-99/100"), ResumeCheck::Append),
+            (10, "206", Some("bytes 10-19/*"), ResumeCheck::Append),
+            (0, "200", None, ResumeCheck::Append),
+            (10, "200", None, ResumeCheck::Restart),
+            (10, "206", Some("bytes 0-99/100"), ResumeCheck::Refetch),
+            (10, "206", None, ResumeCheck::Refetch),
+            (0, "206", Some("bytes 10-99/100"), ResumeCheck::Fail),
+            (100, "416", Some("bytes */100"), ResumeCheck::Complete),
+            (120, "416", Some("bytes */100"), ResumeCheck::Refetch),
+            (10, "404", None, ResumeCheck::Fail),
+            (0, "500", None, ResumeCheck::Fail),
+        ] {
+            assert_eq!(check_resume(*offset, &response(status, *range)), *check);
+        }
+    }
+
+    #[test]
+    fn pem_certs() {
+        let pem = "junk\n-----BEGIN CERTIFICATE-----\nAQID\nBA==\n-----END CERTIFICATE-----\n\
+                   -----BEGIN CERTIFICATE-----\r\nBQY=\r\n-----END CERTIFICATE-----\r\n";
+        assert_eq!(
+            parse_certs(pem.as_bytes().to_vec()).unwrap(),
+            vec![vec![1, 2, 3, 4], vec![5, 6]]
+        );
+        // Without PEM markers, the whole file is one DER certificate.
+        assert_eq!(
+            parse_certs(vec![0x30, 0x03]).unwrap(),
+            vec![vec![0x30, 0x03]]
+        );
+        let bad = "-----BEGIN CERTIFICATE-----\n!!!!\n-----END CERTIFICATE-----\n";
+        assert!(parse_certs(bad.as_bytes().to_vec()).is_err());
+    }
+
+    #[test]
+    fn response_limits() {
+        // The same URL twice gets a limit for each place it is given.
+        let args = Args::from_iter(&[
+            "neqo-client",
+            "https://example.com/",
+            "https://example.com/",
+            "https://example.com/other",
+            "--max-response-bytes",
+            "10",
+            "--max-response-bytes",
+            "20",
+        ]);
+        assert_eq!(response_limit(&args, 0), Some(10));
+        assert_eq!(response_limit(&args, 1), Some(20));
+        assert_eq!(response_limit(&args, 2), Some(20));
+
+        let args = Args::from_iter(&["neqo-client", "https://example.com/"]);
+        assert_eq!(response_limit(&args, 0), None);
+    }
+
+    #[test]
+    fn doctor_args() {
+        let argv = |a: &[&str]| a.iter().map(OsString::from).collect::<Vec<_>>();
+        let parse = |a: &[&str]| {
+            let (args, doctor) = parse_args(&argv(a)).unwrap();
+            (args.urls.len(), args.method, doctor)
+        };
+        let url = "https://example.com/";
+        let get = String::from("GET");
+        assert_eq!(parse(&["neqo-client", url]), (1, get.clone(), false));
+        assert_eq!(
+            parse(&["neqo-client", "doctor", url]),
+            (1, get.clone(), true)
+        );
+        // Options can come before the command.
+        assert_eq!(
+            parse(&["neqo-client", "-m", "HEAD", "doctor", url]),
+            (1, String::from("HEAD"), true)
+        );
+        assert_eq!(parse(&["neqo-client", "-v", "doctor", url]), (1, get, true));
+        // An option value that happens to be "doctor" is left alone.
+        assert_eq!(
+            parse(&["neqo-client", "-m", "doctor", url]),
+            (1, String::from("doctor"), false)
+        );
+        // So is a URL with "doctor" in it.
+        assert!(!parse(&["neqo-client", "https://example.com/doctor"]).2);
+        assert!(parse_args(&argv(&["neqo-client", "doctor", "not a url"])).is_err());
+    }
+
+    #[test]
+    fn trust_accept() {
+        let trusted = vec![vec![1, 2, 3], vec![4, 5, 6]];
+        let chain: &[&[u8]] = &[&[4, 5, 6], &[7, 8, 9]];
+        assert!(pinned(&trusted, chain.iter().copied()));
+    }
+
+    #[test]
+    fn trust_reject() {
+        let trusted = vec![vec![1, 2, 3]];
+        let chain: &[&[u8]] = &[&[1, 2]];
+        assert!(!pinned(&trusted, chain.iter().copied()));
+        assert!(!pinned(&trusted, std::iter::empty()));
+        // A trusted certificate that the server only includes further up the chain
+        // doesn't vouch for the end-entity certificate.
+        let chain: &[&[u8]] = &[&[7, 8, 9], &[1, 2, 3]];
+        assert!(!pinned(&trusted, chain.iter().copied()));
+    }
+}
@@ -0,0 +1,80 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! `--trust` and `--verify-hostname`.  The certificates in `tests/certs` are
+//! copies of those in the test fixture database: `server.pem` is the one that the
+//! server uses, and `other.pem` is another.
+
+mod common;
+
+use common::{run_client, LoopbackServer, SERVER_NAME};
+use std::path::PathBuf;
+use std::process::Output;
+
+fn cert(name: &str) -> String {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/certs");
+    path.push(name);
+    path.to_str().unwrap().to_owned()
+}
+
+fn fetch(args: &[&str]) -> Output {
+    let server = LoopbackServer::start();
+    let url = server.url(100);
+    let mut args = args.to_vec();
+    args.push(&url);
+    run_client(&args)
+}
+
+fn stderr(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stderr).into_owned()
+}
+
+#[test]
+fn verify_hostname() {
+    let out = fetch(&["--verify-hostname", SERVER_NAME]);
+    assert!(out.status.success());
+    assert!(stderr(&out).contains("nothing else about it was checked"));
+}
+
+#[test]
+fn verify_hostname_mismatch() {
+    let out = fetch(&["--verify-hostname", "wrong.example"]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("Server certificate isn't for wrong.example"));
+}
+
+#[test]
+fn trust() {
+    let out = fetch(&[
+        "--trust",
+        &cert("server.pem"),
+        "--verify-hostname",
+        SERVER_NAME,
+    ]);
+    assert!(out.status.success());
+    assert!(stderr(&out).contains("only because of --trust"));
+}
+
+#[test]
+fn trust_other_certificate() {
+    let out = fetch(&[
+        "--trust",
+        &cert("other.pem"),
+        "--verify-hostname",
+        SERVER_NAME,
+    ]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("isn't a certificate from --trust"));
+}
+
+/// The certificate is one from `--trust`, but it isn't for the host in the URL.
+#[test]
+fn trust_wrong_name() {
+    let out = fetch(&["--trust", &cert("server.pem")]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("Server certificate isn't for 127.0.0.1"));
+}
@@ -0,0 +1,226 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Run the neqo-client binary against an HTTP/3 server in the test process.
+
+#![allow(dead_code)]
+
+use neqo_common::Datagram;
+use neqo_crypto::AntiReplay;
+use neqo_http3::{Http3Server, Http3ServerEvent, Output};
+use neqo_qpack::QpackSettings;
+use neqo_transport::FixedConnectionIdManager;
+use neqo_udp::{self as udp, BatchStats};
+
+use std::cell::RefCell;
+use std::cmp::{max, min};
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::process::{Command, Output as ProcessOutput};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The name that the certificate of the server is for.
+pub const SERVER_NAME: &str = "server.example";
+
+/// How often the server checks whether it has been stopped.
+const POLL: Duration = Duration::from_millis(50);
+
+/// An HTTP/3 server on a loopback socket, which runs on its own thread until it is
+/// dropped.  It answers `GET /<n>` with `n` bytes, and anything else with 404.
+/// Like neqo-http3-server, it sends what it has in batches.
+pub struct LoopbackServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    stats: Arc<Mutex<BatchStats>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LoopbackServer {
+    pub fn start() -> Self {
+        Self::start_with(|_| {})
+    }
+
+    /// Start a server, with `configure` called on it before it takes any packets.
+    /// The server is made on its own thread, so `configure` has to be `Send`.
+    pub fn start_with(configure: impl FnOnce(&mut Http3Server) + Send + 'static) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").expect("bind a loopback socket");
+        let addr = socket.local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stats = Arc::new(Mutex::new(BatchStats::default()));
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let stop_server = Arc::clone(&stop);
+        let server_stats = Arc::clone(&stats);
+        let thread = thread::spawn(move || {
+            test_fixture::fixture_init();
+            let mut server = Http3Server::new(
+                Instant::now(),
+                test_fixture::DEFAULT_KEYS,
+                &["h3-27"],
+                AntiReplay::new(Instant::now(), Duration::from_secs(10), 7, 14).unwrap(),
+                Rc::new(RefCell::new(FixedConnectionIdManager::new(5))),
+                QpackSettings {
+                    max_table_size_encoder: 100,
+                    max_table_size_decoder: 100,
+                    max_blocked_streams: 100,
+                },
+            )
+            .expect("create a server");
+            configure(&mut server);
+            ready_tx.send(()).unwrap();
+            serve(&mut server, &socket, &stop_server, &server_stats);
+        });
+        ready_rx.recv().expect("the server should start");
+        Self {
+            addr,
+            stop,
+            stats,
+            thread: Some(thread),
+        }
+    }
+
+    /// The socket calls that the server has made so far.
+    pub fn socket_stats(&self) -> BatchStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// The URL for a response of `size` bytes.
+    pub fn url(&self, size: usize) -> String {
+        format!("https://{}/{}", self.addr, size)
+    }
+}
+
+impl Drop for LoopbackServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(t) = self.thread.take() {
+            let res = t.join();
+            // Don't hide the failure that the test is unwinding from.
+            if !thread::panicking() {
+                res.expect("the server shouldn't panic");
+            }
+        }
+    }
+}
+
+fn serve(
+    server: &mut Http3Server,
+    socket: &UdpSocket,
+    stop: &AtomicBool,
+    stats: &Mutex<BatchStats>,
+) {
+    let local = socket.local_addr().unwrap();
+    let mut buf = vec![0; 65536];
+    let mut dgram = None;
+    let mut out = Vec::new();
+    while !stop.load(Ordering::Relaxed) {
+        // Send everything, then answer any new requests, until neither has more
+        // to do.
+        let wait = loop {
+            let wait = loop {
+                match server.process(dgram.take(), Instant::now()) {
+                    Output::Datagram(d) => out.push(d),
+                    Output::Callback(t) => break t,
+                    Output::None => break POLL,
+                }
+            };
+            let mut sent = 0;
+            while sent < out.len() {
+                let mut stats = stats.lock().unwrap();
+                sent += udp::send_batch_to(socket, &out[sent..], &mut stats).unwrap();
+            }
+            out.clear();
+            if !answer_requests(server) {
+                break wait;
+            }
+        };
+        let wait = max(min(wait, POLL), Duration::from_millis(1));
+        socket.set_read_timeout(Some(wait)).unwrap();
+        stats.lock().unwrap().recv_calls += 1;
+        dgram = match socket.recv_from(&mut buf) {
+            Ok((sz, remote)) => {
+                stats.lock().unwrap().received += 1;
+                Some(Datagram::new(remote, local, &buf[..sz]))
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => None,
+            Err(e) => panic!("UDP error: {}", e),
+        };
+    }
+}
+
+/// Answer the requests that have arrived, returning whether there were any.
+fn answer_requests(server: &mut Http3Server) -> bool {
+    let mut answered = false;
+    while let Some(event) = server.next_event() {
+        if let Http3ServerEvent::Headers {
+            mut request,
+            headers,
+            ..
+        } = event
+        {
+            let size = headers
+                .as_ref()
+                .and_then(|h| h.iter().find(|(k, _)| k == ":path"))
+                .and_then(|(_, path)| path.trim_start_matches('/').parse::<usize>().ok());
+            let res = match size {
+                Some(size) => request.set_response(
+                    &[(String::from(":status"), String::from("200"))],
+                    &vec![b'a'; size],
+                ),
+                None => {
+                    request.set_response(&[(String::from(":status"), String::from("404"))], &[])
+                }
+            };
+            res.unwrap();
+            answered = true;
+        }
+    }
+    answered
+}
+
+/// A directory for the files from one test, which is removed when this is dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let mut dir = env::temp_dir();
+        dir.push(format!("neqo-client-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.0
+    }
+
+    pub fn file(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Run neqo-client with `args`, and report what it printed if the test fails.
+pub fn run_client(args: &[&str]) -> ProcessOutput {
+    let out = Command::new(env!("CARGO_BIN_EXE_neqo-client"))
+        .args(args)
+        .output()
+        .expect("run neqo-client");
+    eprintln!("neqo-client {}: {}", args.join(" "), out.status);
+    eprintln!("{}", String::from_utf8_lossy(&out.stderr));
+    out
+}
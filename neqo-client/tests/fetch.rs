@@ -0,0 +1,127 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+mod common;
+
+use common::{run_client, LoopbackServer, TempDir};
+use std::fs;
+
+fn fetch(name: &str, size: usize, read_buffer: usize) {
+    let server = LoopbackServer::start();
+    let dir = TempDir::new(name);
+    let out = run_client(&[
+        "--output-dir",
+        dir.path().to_str().unwrap(),
+        "--read-buffer",
+        &read_buffer.to_string(),
+        &server.url(size),
+    ]);
+    assert!(out.status.success());
+    let saved = fs::metadata(dir.file(&size.to_string())).unwrap();
+    assert_eq!(saved.len(), size as u64);
+}
+
+#[test]
+fn fetch_small() {
+    fetch("fetch-small", 100_000, 4000);
+}
+
+/// Download 50 MB with a small read buffer.  This only finishes if the client is
+/// told about each part of the response that arrives, and reads it all.
+/// It is slow without optimization, so CI runs it with `--release --ignored`.
+#[test]
+#[ignore]
+fn fetch_large() {
+    fetch("fetch-large", 50 << 20, 1000);
+}
+
+/// A second connection resumes the session that the first one saved, through the
+/// client and a real server.
+#[test]
+fn resume_session() {
+    let server = LoopbackServer::start();
+    let dir = TempDir::new("resume-session");
+    let session = dir.file("session");
+    let run = || {
+        run_client(&[
+            "-v",
+            "--session-file",
+            session.to_str().unwrap(),
+            "--output-dir",
+            dir.path().to_str().unwrap(),
+            &server.url(1000),
+        ])
+    };
+
+    let out = run();
+    assert!(out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(!stderr.contains("Resuming a saved session"));
+    assert!(stderr.contains("0-RTT: not attempted"));
+    assert!(session.exists());
+
+    let out = run();
+    assert!(out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("Resuming a saved session"));
+    // Resuming always tries 0-RTT.  The server can still reject it, as it does until
+    // its anti-replay window has passed, in which case the request is sent again.
+    assert!(!stderr.contains("0-RTT: not attempted"));
+    let saved = fs::metadata(dir.file("1000")).unwrap();
+    assert_eq!(saved.len(), 1000);
+}
+
+/// `--simulate-rebind` moves the connection to a new socket part way through a
+/// download, and the download carries on from there to the end.
+#[test]
+fn simulate_rebind() {
+    const SIZE: usize = 1 << 20;
+    let server = LoopbackServer::start_with(|s| s.set_allow_migration(true));
+    let dir = TempDir::new("simulate-rebind");
+    let out = run_client(&[
+        "--simulate-rebind",
+        "100000",
+        "--output-dir",
+        dir.path().to_str().unwrap(),
+        &server.url(SIZE),
+    ]);
+    assert!(out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("Rebound after --simulate-rebind, moving to"));
+    // The connection moved, so nothing had to be asked for again.
+    assert!(!stderr.contains("making the requests again"));
+    let saved = fs::metadata(dir.file(&SIZE.to_string())).unwrap();
+    assert_eq!(saved.len(), SIZE as u64);
+}
+
+/// Report how many socket calls each side makes for a bulk download, which is
+/// far fewer than the number of datagrams where they can be sent in batches.
+/// Run it with `--nocapture` to see the counts.
+#[test]
+#[ignore]
+fn socket_calls() {
+    const SIZE: usize = 10 << 20;
+    let server = LoopbackServer::start();
+    let dir = TempDir::new("socket-calls");
+    let out = run_client(&[
+        "-v",
+        "--output-dir",
+        dir.path().to_str().unwrap(),
+        &server.url(SIZE),
+    ]);
+    assert!(out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let client = stderr
+        .lines()
+        .find(|l| l.starts_with("UDP: "))
+        .expect("the client reports its socket calls with -v");
+    let stats = server.socket_stats();
+    println!("client {}", &client["UDP: ".len()..]);
+    println!("server {}", stats);
+    assert!(stats.sent > 0);
+    #[cfg(target_os = "linux")]
+    assert!(stats.send_calls < stats.sent);
+}
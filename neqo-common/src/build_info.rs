@@ -0,0 +1,83 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Details of the build, for bug reports.
+
+use std::fmt::{self, Display, Formatter};
+
+/// Name and value pairs that describe a build.  A crate can only describe
+/// itself, so `build_info` has what neqo-common knows and crates that depend
+/// on it add to that; `neqo_http3::build_info` covers all of the libraries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BuildInfo {
+    entries: Vec<(&'static str, String)>,
+}
+
+impl BuildInfo {
+    pub fn add(&mut self, name: &'static str, value: impl ToString) -> &mut Self {
+        self.entries.push((name, value.to_string()));
+        self
+    }
+
+    /// All of the entries, in the order they were added.
+    #[must_use]
+    pub fn entries(&self) -> &[(&'static str, String)] {
+        &self.entries
+    }
+
+    /// The first value for `name`.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| &v[..])
+    }
+}
+
+impl Display for BuildInfo {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (name, value) in &self.entries {
+            writeln!(f, "{}: {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[must_use]
+pub fn build_info() -> BuildInfo {
+    let mut info = BuildInfo {
+        entries: Vec::new(),
+    };
+    info.add("neqo-common", env!("CARGO_PKG_VERSION"))
+        .add(
+            "target",
+            format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+        )
+        .add(
+            "profile",
+            if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            },
+        );
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::build_info;
+
+    #[test]
+    fn add() {
+        let mut info = build_info();
+        assert_eq!(info.get("neqo-common"), Some(env!("CARGO_PKG_VERSION")));
+        info.add("thing", 1).add("thing", 2);
+        assert_eq!(info.get("thing"), Some("1"));
+        assert!(info.to_string().ends_with("thing: 1\nthing: 2\n"));
+    }
+}
@@ -7,16 +7,23 @@
 #![cfg_attr(feature = "deny-warnings", deny(warnings))]
 #![warn(clippy::pedantic)]
 
+mod build_info;
 mod codec;
 mod datagram;
 mod incrdecoder;
 pub mod log;
 pub mod qlog;
+mod resumption;
 pub mod timer;
 
+pub use self::build_info::{build_info, BuildInfo};
 pub use self::codec::{Decoder, Encoder};
 pub use self::datagram::Datagram;
 pub use self::incrdecoder::{IncrementalDecoder, IncrementalDecoderResult};
+pub use self::resumption::{
+    FileResumptionStore, MemoryResumptionStore, ResumptionStore, ResumptionTicket,
+    DEFAULT_TICKETS_PER_ORIGIN,
+};
 
 #[macro_use]
 extern crate lazy_static;
@@ -4,23 +4,40 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+// Without the `qlog` feature, `NeqoQlog` can't be made, so anything that holds
+// an `Option<NeqoQlog>` always has `None`, and nothing else here is built.
+
+#[cfg(feature = "qlog")]
 use std::fmt;
+#[cfg(feature = "qlog")]
 use std::path::PathBuf;
+#[cfg(feature = "qlog")]
 use std::time::SystemTime;
 
+#[cfg(feature = "qlog")]
 use chrono::{DateTime, Utc};
+#[cfg(feature = "qlog")]
 use qlog::{
     self, CommonFields, Configuration, QlogStreamer, TimeUnits, Trace, VantagePoint,
     VantagePointType,
 };
 
+#[cfg(feature = "qlog")]
 use crate::Role;
+
+#[cfg(not(feature = "qlog"))]
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug)]
+pub enum NeqoQlog {}
+
+#[cfg(feature = "qlog")]
 #[allow(clippy::module_name_repetitions)]
 pub struct NeqoQlog {
     qlog_path: PathBuf,
     streamer: QlogStreamer,
 }
 
+#[cfg(feature = "qlog")]
 impl NeqoQlog {
     /// # Errors
     ///
@@ -39,12 +56,14 @@ impl NeqoQlog {
     }
 }
 
+#[cfg(feature = "qlog")]
 impl fmt::Debug for NeqoQlog {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "NeqoQlog writing to {}", self.qlog_path.display())
     }
 }
 
+#[cfg(feature = "qlog")]
 impl Drop for NeqoQlog {
     fn drop(&mut self) {
         if let Err(e) = self.streamer.finish_log() {
@@ -53,6 +72,7 @@ impl Drop for NeqoQlog {
     }
 }
 
+#[cfg(feature = "qlog")]
 #[must_use]
 pub fn new_trace(role: Role) -> qlog::Trace {
     Trace {
@@ -0,0 +1,412 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Keeping what a client needs to resume connections to a server.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::codec::{Decoder, Encoder};
+
+/// How many tickets are kept for each origin by default.
+pub const DEFAULT_TICKETS_PER_ORIGIN: usize = 4;
+
+/// What is kept from one connection so that another can resume.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumptionTicket {
+    /// The session ticket.  For neqo, this is the resumption token from the
+    /// connection, which already includes its transport parameters.
+    pub ticket: Vec<u8>,
+    /// An address validation token from the server, if there is one.
+    pub token: Option<Vec<u8>>,
+    /// The server's transport parameters, for those that keep them apart.
+    pub transport_params: Vec<u8>,
+    /// The ALPN that was negotiated.  A ticket can't be used with another.
+    pub alpn: String,
+    /// The path to the server, from `PathInfo::encode` in neqo-transport, or empty.
+    /// This seeds the round trip time and path MTU of the next connection.
+    pub path_info: Vec<u8>,
+    /// When the ticket can no longer be used.
+    pub expires: SystemTime,
+}
+
+/// Somewhere to keep tickets, by origin.
+///
+/// Tickets can only be used once, so that 0-RTT can't be replayed.  A store keeps
+/// several for each origin, so that parallel connections can each have one.
+pub trait ResumptionStore {
+    /// Take the newest ticket for `origin` that hasn't expired at `now`.
+    /// The ticket is removed from the store, along with any that have expired.
+    fn get(&mut self, origin: &str, now: SystemTime) -> Option<ResumptionTicket>;
+    /// Add a ticket for `origin`.  If the store is full for that origin, the oldest
+    /// ticket is dropped.
+    fn put(&mut self, origin: &str, ticket: ResumptionTicket);
+    /// Forget all of the tickets for `origin`.
+    fn remove(&mut self, origin: &str);
+}
+
+/// A store that only lasts as long as the process.
+#[derive(Debug)]
+pub struct MemoryResumptionStore {
+    limit: usize,
+    tickets: HashMap<String, VecDeque<ResumptionTicket>>,
+}
+
+impl MemoryResumptionStore {
+    /// Make a store that keeps at most `limit` tickets for each origin.
+    #[must_use]
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            tickets: HashMap::new(),
+        }
+    }
+
+    /// The number of tickets held for `origin`, including any that have expired.
+    #[must_use]
+    pub fn len(&self, origin: &str) -> usize {
+        self.tickets.get(origin).map_or(0, VecDeque::len)
+    }
+
+    fn trim(&mut self, origin: &str) {
+        if let Some(tickets) = self.tickets.get_mut(origin) {
+            while tickets.len() > self.limit {
+                tickets.pop_front();
+            }
+            if tickets.is_empty() {
+                self.tickets.remove(origin);
+            }
+        }
+    }
+}
+
+impl Default for MemoryResumptionStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_TICKETS_PER_ORIGIN)
+    }
+}
+
+impl ResumptionStore for MemoryResumptionStore {
+    fn get(&mut self, origin: &str, now: SystemTime) -> Option<ResumptionTicket> {
+        let tickets = self.tickets.get_mut(origin)?;
+        tickets.retain(|t| t.expires > now);
+        let ticket = tickets.pop_back();
+        self.trim(origin);
+        ticket
+    }
+
+    fn put(&mut self, origin: &str, ticket: ResumptionTicket) {
+        self.tickets
+            .entry(origin.to_string())
+            .or_default()
+            .push_back(ticket);
+        self.trim(origin);
+    }
+
+    fn remove(&mut self, origin: &str) {
+        self.tickets.remove(origin);
+    }
+}
+
+/// This starts the file, so that other files aren't mistaken for a store.
+const FILE_MAGIC: &[u8] = b"neqo-resumption-2\n";
+
+/// A store that is kept in a file between runs.
+///
+/// Changes are written out by `save()`, or when the store is dropped.  A file that
+/// can't be read or doesn't decode is ignored, and is replaced by the next save.
+#[derive(Debug)]
+pub struct FileResumptionStore {
+    path: PathBuf,
+    memory: MemoryResumptionStore,
+    dirty: bool,
+}
+
+impl FileResumptionStore {
+    /// Use the file at `path`, keeping at most `limit` tickets for each origin.
+    #[must_use]
+    pub fn open(path: impl Into<PathBuf>, limit: usize) -> Self {
+        let path = path.into();
+        let mut memory = MemoryResumptionStore::new(limit);
+        // Dropping anything over the limit is a change.
+        let mut dirty = false;
+        if let Some(tickets) = fs::read(&path).ok().and_then(|buf| decode_file(&buf)) {
+            memory.tickets = tickets;
+            let origins = memory.tickets.keys().cloned().collect::<Vec<_>>();
+            for origin in origins {
+                let before = memory.len(&origin);
+                memory.trim(&origin);
+                dirty |= memory.len(&origin) != before;
+            }
+        }
+        Self {
+            path,
+            memory,
+            dirty,
+        }
+    }
+
+    /// Write the store out, if it changed.  This writes a new file and renames it
+    /// into place, so that a failure leaves the old file intact.
+    ///
+    /// # Errors
+    /// When the file can't be written.
+    pub fn save(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let mut tmp = OsString::from(self.path.as_os_str());
+        tmp.push(".tmp");
+        fs::write(&tmp, encode_file(&self.memory.tickets))?;
+        fs::rename(&tmp, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl ResumptionStore for FileResumptionStore {
+    fn get(&mut self, origin: &str, now: SystemTime) -> Option<ResumptionTicket> {
+        let before = self.memory.len(origin);
+        let ticket = self.memory.get(origin, now);
+        self.dirty |= self.memory.len(origin) != before;
+        ticket
+    }
+
+    fn put(&mut self, origin: &str, ticket: ResumptionTicket) {
+        self.memory.put(origin, ticket);
+        self.dirty = true;
+    }
+
+    fn remove(&mut self, origin: &str) {
+        self.dirty |= self.memory.len(origin) > 0;
+        self.memory.remove(origin);
+    }
+}
+
+impl Drop for FileResumptionStore {
+    fn drop(&mut self) {
+        // There is nobody to tell about errors here; call save() to see them.
+        let _ = self.save();
+    }
+}
+
+fn encode_file(tickets: &HashMap<String, VecDeque<ResumptionTicket>>) -> Vec<u8> {
+    let mut enc = Encoder::default();
+    enc.encode(FILE_MAGIC);
+    enc.encode_varint(u64::try_from(tickets.len()).unwrap());
+    for (origin, tickets) in tickets {
+        enc.encode_vvec(origin.as_bytes());
+        enc.encode_varint(u64::try_from(tickets.len()).unwrap());
+        for t in tickets {
+            enc.encode_vvec(&t.ticket);
+            if let Some(token) = &t.token {
+                enc.encode_varint(1_u64).encode_vvec(token);
+            } else {
+                enc.encode_varint(0_u64);
+            }
+            enc.encode_vvec(&t.transport_params);
+            enc.encode_vvec(t.alpn.as_bytes());
+            enc.encode_vvec(&t.path_info);
+            let expires = t.expires.duration_since(UNIX_EPOCH).unwrap_or_default();
+            enc.encode_varint(expires.as_secs());
+        }
+    }
+    enc.into()
+}
+
+fn decode_string(dec: &mut Decoder) -> Option<String> {
+    String::from_utf8(dec.decode_vvec()?.to_vec()).ok()
+}
+
+fn decode_ticket(dec: &mut Decoder) -> Option<ResumptionTicket> {
+    let ticket = dec.decode_vvec()?.to_vec();
+    let token = match dec.decode_varint()? {
+        0 => None,
+        1 => Some(dec.decode_vvec()?.to_vec()),
+        _ => return None,
+    };
+    let transport_params = dec.decode_vvec()?.to_vec();
+    let alpn = decode_string(dec)?;
+    let path_info = dec.decode_vvec()?.to_vec();
+    let expires = UNIX_EPOCH.checked_add(Duration::from_secs(dec.decode_varint()?))?;
+    Some(ResumptionTicket {
+        ticket,
+        token,
+        transport_params,
+        alpn,
+        path_info,
+        expires,
+    })
+}
+
+/// Decode a whole file, or nothing if any of it is wrong.
+fn decode_file(buf: &[u8]) -> Option<HashMap<String, VecDeque<ResumptionTicket>>> {
+    let mut dec = Decoder::from(buf);
+    if dec.decode(FILE_MAGIC.len())? != FILE_MAGIC {
+        return None;
+    }
+    let mut tickets = HashMap::new();
+    for _ in 0..dec.decode_varint()? {
+        let origin = decode_string(&mut dec)?;
+        let count = dec.decode_varint()?;
+        let mut list = VecDeque::new();
+        for _ in 0..count {
+            list.push_back(decode_ticket(&mut dec)?);
+        }
+        if tickets.insert(origin, list).is_some() {
+            return None;
+        }
+    }
+    if dec.remaining() > 0 {
+        return None;
+    }
+    Some(tickets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_file, encode_file, FileResumptionStore, MemoryResumptionStore, ResumptionStore,
+        ResumptionTicket,
+    };
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    const ORIGIN: &str = "example.com";
+
+    fn now() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+    }
+
+    fn ticket(id: u8, lifetime: u64) -> ResumptionTicket {
+        ResumptionTicket {
+            ticket: vec![id; 3],
+            token: if id % 2 == 0 { Some(vec![id]) } else { None },
+            transport_params: vec![id, id],
+            alpn: String::from("h3-27"),
+            path_info: vec![id; usize::from(id % 3)],
+            expires: now() + Duration::from_secs(lifetime),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let mut path = env::temp_dir();
+        path.push(format!("neqo-resumption-{}-{}", process::id(), name));
+        path
+    }
+
+    #[test]
+    fn single_use() {
+        let mut store = MemoryResumptionStore::default();
+        store.put(ORIGIN, ticket(1, 100));
+        store.put(ORIGIN, ticket(2, 100));
+        assert_eq!(store.get(ORIGIN, now()), Some(ticket(2, 100)));
+        assert_eq!(store.get(ORIGIN, now()), Some(ticket(1, 100)));
+        assert_eq!(store.get(ORIGIN, now()), None);
+        assert_eq!(store.get("example.net", now()), None);
+    }
+
+    #[test]
+    fn limit() {
+        let mut store = MemoryResumptionStore::new(2);
+        for i in 1..=3 {
+            store.put(ORIGIN, ticket(i, 100));
+        }
+        assert_eq!(store.len(ORIGIN), 2);
+        assert_eq!(store.get(ORIGIN, now()), Some(ticket(3, 100)));
+        assert_eq!(store.get(ORIGIN, now()), Some(ticket(2, 100)));
+        assert_eq!(store.get(ORIGIN, now()), None);
+
+        let mut none = MemoryResumptionStore::new(0);
+        none.put(ORIGIN, ticket(1, 100));
+        assert_eq!(none.len(ORIGIN), 0);
+    }
+
+    #[test]
+    fn expiry() {
+        let mut store = MemoryResumptionStore::default();
+        store.put(ORIGIN, ticket(1, 100));
+        store.put(ORIGIN, ticket(2, 10));
+        // The newest has expired, so the older one is used.
+        let later = now() + Duration::from_secs(10);
+        assert_eq!(store.get(ORIGIN, later), Some(ticket(1, 100)));
+        assert_eq!(store.len(ORIGIN), 0);
+
+        store.put(ORIGIN, ticket(3, 10));
+        store.remove(ORIGIN);
+        assert_eq!(store.get(ORIGIN, now()), None);
+    }
+
+    #[test]
+    fn encoding() {
+        let mut store = MemoryResumptionStore::default();
+        store.put(ORIGIN, ticket(1, 100));
+        store.put(ORIGIN, ticket(2, 100));
+        store.put("example.net", ticket(3, 100));
+        let buf = encode_file(&store.tickets);
+        assert_eq!(decode_file(&buf), Some(store.tickets.clone()));
+
+        // Anything missing or extra means that the file is ignored.
+        for len in 0..buf.len() {
+            assert_eq!(decode_file(&buf[..len]), None);
+        }
+        let mut extra = buf.clone();
+        extra.push(0);
+        assert_eq!(decode_file(&extra), None);
+        assert_eq!(decode_file(b"garbage"), None);
+    }
+
+    #[test]
+    fn file() {
+        let path = temp_path("file");
+        let _ = fs::remove_file(&path);
+
+        let mut store = FileResumptionStore::open(&path, 2);
+        assert_eq!(store.get(ORIGIN, now()), None);
+        store.put(ORIGIN, ticket(1, 100));
+        store.put(ORIGIN, ticket(2, 100));
+        store.save().unwrap();
+
+        let mut store = FileResumptionStore::open(&path, 2);
+        assert_eq!(store.get(ORIGIN, now()), Some(ticket(2, 100)));
+        drop(store);
+
+        // A smaller limit drops the oldest tickets.
+        let mut store = FileResumptionStore::open(&path, 0);
+        assert_eq!(store.get(ORIGIN, now()), None);
+        drop(store);
+        let mut store = FileResumptionStore::open(&path, 2);
+        assert_eq!(store.get(ORIGIN, now()), None);
+        drop(store);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_file() {
+        let path = temp_path("corrupt");
+        fs::write(&path, b"not a store").unwrap();
+
+        let mut store = FileResumptionStore::open(&path, 2);
+        assert_eq!(store.get(ORIGIN, now()), None);
+        store.put(ORIGIN, ticket(1, 100));
+        store.save().unwrap();
+
+        let mut store = FileResumptionStore::open(&path, 2);
+        assert_eq!(store.get(ORIGIN, now()), Some(ticket(1, 100)));
+        drop(store);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
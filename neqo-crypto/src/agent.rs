@@ -19,7 +19,7 @@ use crate::prio;
 use crate::replay::AntiReplay;
 use crate::secrets::SecretHolder;
 use crate::ssl::{self, PRBool};
-use crate::time::TimeHolder;
+use crate::time::{Time, TimeHolder};
 
 use neqo_common::{hex, matches, qdebug, qinfo, qtrace, qwarn};
 use std::cell::RefCell;
@@ -27,7 +27,7 @@ use std::convert::TryFrom;
 use std::ffi::CString;
 use std::mem::{self, MaybeUninit};
 use std::ops::{Deref, DerefMut};
-use std::os::raw::{c_uint, c_void};
+use std::os::raw::{c_char, c_uint, c_void};
 use std::pin::Pin;
 use std::ptr::{null, null_mut, NonNull};
 use std::rc::Rc;
@@ -203,6 +203,65 @@ impl SecretAgentInfo {
     }
 }
 
+/// What a resumption token says about the session it resumes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResumptionTokenInfo {
+    alpn: Option<String>,
+    expiration_time: ssl::PRTime,
+}
+
+impl ResumptionTokenInfo {
+    /// Decode a token from `Client::resumption_token`.
+    ///
+    /// # Errors
+    /// If NSS can't decode the token.
+    pub fn new(token: &[u8]) -> Res<Self> {
+        let mut info: MaybeUninit<ssl::SSLResumptionTokenInfo> = MaybeUninit::uninit();
+        unsafe {
+            ssl::SSL_GetResumptionTokenInfo(
+                token.as_ptr(),
+                c_uint::try_from(token.len())?,
+                info.as_mut_ptr(),
+                c_uint::try_from(mem::size_of::<ssl::SSLResumptionTokenInfo>())?,
+            )
+        }?;
+        let mut info = unsafe { info.assume_init() };
+        let alpn = if info.alpnSelection.is_null() || info.alpnSelectionLen == 0 {
+            None
+        } else {
+            let v = unsafe {
+                std::slice::from_raw_parts(info.alpnSelection, info.alpnSelectionLen as usize)
+            };
+            String::from_utf8(v.to_vec()).ok()
+        };
+        let expiration_time = info.expirationTime;
+        unsafe { ssl::SSL_DestroyResumptionTokenInfo(&mut info) }?;
+        Ok(Self {
+            alpn,
+            expiration_time,
+        })
+    }
+
+    /// The ALPN that was negotiated for the session.
+    #[must_use]
+    pub fn alpn(&self) -> Option<&String> {
+        self.alpn.as_ref()
+    }
+
+    /// When the ticket expires, as the server set it.  This is `None` if the time
+    /// can't be represented, which only happens for a ticket that has expired.
+    #[must_use]
+    pub fn expiration_time(&self) -> Option<Instant> {
+        Time::try_from(self.expiration_time).ok().map(|t| *t)
+    }
+
+    /// Whether the ticket has expired at `now`.  NSS won't use a ticket that has.
+    #[must_use]
+    pub fn expired(&self, now: Instant) -> bool {
+        self.expiration_time().map_or(true, |t| t <= now)
+    }
+}
+
 /// `SecretAgent` holds the common parts of client and server.
 #[derive(Debug)]
 #[allow(clippy::module_name_repetitions)]
@@ -222,6 +281,8 @@ pub struct SecretAgent {
 
     extension_handlers: Vec<ExtensionTracker>,
     inf: Option<SecretAgentInfo>,
+    /// The ALPN values given to `set_alpn`.
+    alpn: Vec<String>,
 }
 
 impl SecretAgent {
@@ -241,6 +302,7 @@ impl SecretAgent {
 
             extension_handlers: Vec::new(),
             inf: None,
+            alpn: Vec::new(),
         })
     }
 
@@ -462,7 +524,15 @@ impl SecretAgent {
                 encoded.as_slice().as_ptr(),
                 c_uint::try_from(encoded.len())?,
             )
-        })
+        })?;
+        self.alpn = protocols.iter().map(|v| String::from(v.as_ref())).collect();
+        Ok(())
+    }
+
+    /// The ALPN values that were given to `set_alpn`, most preferred first.
+    #[must_use]
+    pub fn alpn_protocols(&self) -> &[String] {
+        &self.alpn
     }
 
     /// Install an extension handler.
@@ -526,6 +596,68 @@ impl SecretAgent {
         CertificateInfo::new(self.fd)
     }
 
+    /// Export keying material, as in RFC 5705 and Section 7.5 of RFC 8446.  This
+    /// derives `len` bytes from the secrets of the connection, the label, and the
+    /// context.  For TLS 1.3, having no context is the same as an empty context.
+    ///
+    /// # Errors
+    /// If the handshake is not complete, or if NSS can't produce the value.
+    pub fn export_keying_material(
+        &self,
+        label: &str,
+        context: Option<&[u8]>,
+        len: usize,
+    ) -> Res<Vec<u8>> {
+        let label_len = c_uint::try_from(label.len())?;
+        let ctx = context.unwrap_or(&[]);
+        let ctx_len = c_uint::try_from(ctx.len())?;
+        let mut out = vec![0; len];
+        let out_len = c_uint::try_from(out.len())?;
+        secstatus_to_res(unsafe {
+            ssl::SSL_ExportKeyingMaterial(
+                self.fd,
+                label.as_ptr() as *const c_char,
+                label_len,
+                PRBool::from(context.is_some()),
+                ctx.as_ptr(),
+                ctx_len,
+                out.as_mut_ptr(),
+                out_len,
+            )
+        })?;
+        Ok(out)
+    }
+
+    /// Export keying material using the early exporter secret.  That is only
+    /// available when 0-RTT is attempted, and values from it have none of the
+    /// protection against replay that the handshake provides.
+    ///
+    /// # Errors
+    /// If there is no early exporter secret, or if NSS can't produce the value.
+    pub fn export_early_keying_material(
+        &self,
+        label: &str,
+        context: &[u8],
+        len: usize,
+    ) -> Res<Vec<u8>> {
+        let label_len = c_uint::try_from(label.len())?;
+        let ctx_len = c_uint::try_from(context.len())?;
+        let mut out = vec![0; len];
+        let out_len = c_uint::try_from(out.len())?;
+        secstatus_to_res(unsafe {
+            ssl::SSL_ExportEarlyKeyingMaterial(
+                self.fd,
+                label.as_ptr() as *const c_char,
+                label_len,
+                context.as_ptr(),
+                ctx_len,
+                out.as_mut_ptr(),
+                out_len,
+            )
+        })?;
+        Ok(out)
+    }
+
     /// Return any fatal alert that the TLS stack might have sent.
     #[must_use]
     pub fn alert(&self) -> Option<&Alert> {
@@ -687,6 +819,8 @@ impl ::std::fmt::Display for SecretAgent {
 #[derive(Debug)]
 pub struct Client {
     agent: SecretAgent,
+    /// The name of the server, which tickets are bound to.
+    server_name: String,
 
     /// Records the last resumption token.
     resumption: Pin<Box<Option<Vec<u8>>>>,
@@ -704,6 +838,7 @@ impl Client {
         agent.ready(false)?;
         let mut client = Self {
             agent,
+            server_name: String::from(server_name),
             resumption: Box::pin(None),
         };
         client.ready()?;
@@ -736,6 +871,12 @@ impl Client {
         }
     }
 
+    /// The name of the server that this client connects to.
+    #[must_use]
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
     /// Return the resumption token.
     #[must_use]
     pub fn resumption_token(&self) -> Option<&Vec<u8>> {
@@ -6,8 +6,8 @@
 
 use crate::err::secstatus_to_res;
 use crate::p11::{
-    CERTCertList, CERTCertListNode, CERT_GetCertificateDer, CertList, PRCList, SECItem,
-    SECItemArray, SECItemType,
+    CERTCertList, CERTCertListNode, CERT_GetCertificateDer, CERT_VerifyCertName, CertList, PRCList,
+    SECItem, SECItemArray, SECItemType,
 };
 use crate::ssl::{
     PRFileDesc, SSL_PeerCertificateChain, SSL_PeerSignedCertTimestamps,
@@ -16,6 +16,7 @@ use crate::ssl::{
 use neqo_common::qerror;
 
 use std::convert::TryFrom;
+use std::ffi::CString;
 use std::ptr::{null_mut, NonNull};
 
 use std::slice;
@@ -123,4 +124,21 @@ impl CertificateInfo {
     pub fn signed_cert_timestamp(&mut self) -> &Option<Vec<u8>> {
         &self.signed_cert_timestamp
     }
+
+    /// Whether the end-entity certificate is for `name`.  This uses the subject
+    /// alternative names, or the common name if there are none.  Nothing else
+    /// about the certificate is checked.
+    #[must_use]
+    pub fn verify_name(&self, name: &str) -> bool {
+        let head = Self::head(&self.certs);
+        let first = unsafe { *head }.links.next as *const CERTCertListNode;
+        if first == head {
+            return false;
+        }
+        let name = match CString::new(name) {
+            Ok(n) => n,
+            Err(_) => return false,
+        };
+        secstatus_to_res(unsafe { CERT_VerifyCertName((*first).cert, name.as_ptr()) }).is_ok()
+    }
 }
@@ -36,8 +36,8 @@ mod ssl;
 mod time;
 
 pub use self::agent::{
-    Agent, Client, HandshakeState, Record, RecordList, SecretAgent, SecretAgentInfo,
-    SecretAgentPreInfo, Server, ZeroRttCheckResult, ZeroRttChecker,
+    Agent, Client, HandshakeState, Record, RecordList, ResumptionTokenInfo, SecretAgent,
+    SecretAgentInfo, SecretAgentPreInfo, Server, ZeroRttCheckResult, ZeroRttChecker,
 };
 pub use self::auth::AuthenticationStatus;
 pub use self::constants::*;
@@ -50,7 +50,7 @@ pub use self::ssl::Opt;
 
 use self::once::OnceResult;
 
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
 use std::path::{Path, PathBuf};
 use std::ptr::null;
@@ -60,6 +60,18 @@ mod nss {
     include!(concat!(env!("OUT_DIR"), "/nss_init.rs"));
 }
 
+/// The version of neqo-crypto.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The version of the NSS library that is in use.  This can be newer than the
+/// version that neqo-crypto was built against.
+#[must_use]
+pub fn nss_version() -> &'static str {
+    // This returns a static string, and NSS doesn't need to be initialized.
+    let version = unsafe { CStr::from_ptr(nss::NSS_GetVersion()) };
+    version.to_str().unwrap_or("unknown")
+}
+
 // Need to map the types through.
 fn secstatus_to_res(code: nss::SECStatus) -> Res<()> {
     crate::err::secstatus_to_res(code as crate::ssl::SECStatus)
@@ -2,12 +2,13 @@
 #![warn(clippy::pedantic)]
 
 use neqo_crypto::{
-    AuthenticationStatus, Client, HandshakeState, SecretAgentPreInfo, Server, ZeroRttCheckResult,
-    ZeroRttChecker, TLS_AES_128_GCM_SHA256, TLS_CHACHA20_POLY1305_SHA256, TLS_GRP_EC_SECP256R1,
-    TLS_VERSION_1_3,
+    AuthenticationStatus, Client, HandshakeState, ResumptionTokenInfo, SecretAgentPreInfo, Server,
+    ZeroRttCheckResult, ZeroRttChecker, TLS_AES_128_GCM_SHA256, TLS_CHACHA20_POLY1305_SHA256,
+    TLS_GRP_EC_SECP256R1, TLS_VERSION_1_3,
 };
 
 use std::boxed::Box;
+use std::time::Duration;
 
 mod handshake;
 use crate::handshake::{
@@ -130,6 +131,9 @@ fn raw() {
     let mut certs = client.peer_certificate().unwrap();
     let cert_vec: Vec<&[u8]> = certs.collect();
     assert_eq!(1, cert_vec.len());
+    assert!(certs.verify_name("server.example"));
+    assert!(!certs.verify_name("example.com"));
+    assert!(!certs.verify_name("127.0.0.1"));
 
     // The server shouldn't have a client certificate.
     assert!(server.peer_certificate().is_none());
@@ -279,6 +283,23 @@ fn resume() {
     assert!(server.info().unwrap().resumed());
 }
 
+#[test]
+fn resumption_token_info() {
+    let (_, token) = resumption_setup(Resumption::WithoutZeroRtt);
+
+    let info = ResumptionTokenInfo::new(&token).expect("should decode token");
+    assert_eq!(info.alpn(), None);
+    assert!(!info.expired(now()));
+    // NSS servers issue tickets that last for two days.
+    assert!(info.expired(now() + Duration::from_secs(3 * 24 * 60 * 60)));
+    let expiration = info.expiration_time().expect("should expire");
+    assert!(expiration > now() + Duration::from_secs(24 * 60 * 60));
+    assert!(!info.expired(expiration - Duration::from_secs(1)));
+    assert!(info.expired(expiration));
+
+    assert!(ResumptionTokenInfo::new(&token[..token.len() / 2]).is_err());
+}
+
 #[test]
 fn zero_rtt() {
     let (anti_replay, token) = resumption_setup(Resumption::WithZeroRtt);
@@ -385,3 +406,81 @@ fn close_client_twice() {
     client.close();
     client.close(); // Should be a noop.
 }
+
+#[test]
+fn export_keying_material() {
+    const CONTEXT: Option<&[u8]> = Some(b"context");
+
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    assert!(client
+        .export_keying_material("EXPORTER-test", CONTEXT, 32)
+        .is_err());
+    connect(&mut client, &mut server);
+
+    let export = |agent: &Client, label: &str, context: Option<&[u8]>| {
+        agent
+            .export_keying_material(label, context, 32)
+            .expect("should export")
+    };
+    let value = export(&client, "EXPORTER-test", CONTEXT);
+    assert_eq!(value.len(), 32);
+    assert_eq!(
+        value,
+        server
+            .export_keying_material("EXPORTER-test", CONTEXT, 32)
+            .unwrap()
+    );
+    assert_ne!(value, export(&client, "EXPORTER-other", CONTEXT));
+    assert_ne!(value, export(&client, "EXPORTER-test", Some(b"other")));
+    assert_ne!(value, export(&client, "EXPORTER-test", None));
+    // The length is part of the derivation, so a shorter value isn't a prefix.
+    let short = client
+        .export_keying_material("EXPORTER-test", CONTEXT, 16)
+        .unwrap();
+    assert_ne!(&value[..16], &short[..]);
+
+    // Without 0-RTT, there is no early exporter.
+    assert!(client
+        .export_early_keying_material("EXPORTER-test", b"context", 32)
+        .is_err());
+}
+
+#[test]
+fn export_early_keying_material() {
+    let (anti_replay, token) = resumption_setup(Resumption::WithZeroRtt);
+
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    client
+        .set_resumption_token(&token[..])
+        .expect("should accept token");
+    client.enable_0rtt().expect("should enable 0-RTT");
+    server
+        .enable_0rtt(
+            anti_replay.as_ref().unwrap(),
+            0xffff_ffff,
+            Box::new(PermissiveZeroRttChecker::default()),
+        )
+        .expect("should enable 0-RTT");
+
+    // The early exporter is available as soon as the ClientHello is sent and received.
+    let records = client.handshake_raw(now(), None).unwrap();
+    let value = client
+        .export_early_keying_material("EXPORTER-early", b"context", 32)
+        .expect("client should export");
+    let _ = forward_records(now(), &mut server, records).unwrap();
+    assert_eq!(
+        value,
+        server
+            .export_early_keying_material("EXPORTER-early", b"context", 32)
+            .expect("server should export")
+    );
+    assert_ne!(
+        value,
+        client
+            .export_early_keying_material("EXPORTER-early", b"other", 32)
+            .unwrap()
+    );
+}
@@ -0,0 +1,998 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// An offline decoder for QUIC and HTTP/3 captures.  It reads datagrams from one
+// connection, one on each line in hex or base64, and prints the packets in them.
+//
+// Initial packets are decrypted with keys from the connection ID in the first
+// client Initial.  Other packets need a key log, in the format that NSS writes
+// for SSLKEYLOGFILE; the ClientHello picks the connection in the log.  Which side
+// sent a packet is worked out from which keys decrypt it.  What each side sends
+// on a stream is put back together, so that HTTP/3 frames and header blocks can
+// be decoded even when they are split between packets.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![warn(clippy::use_self)]
+
+use neqo_common::{hex, Decoder, Role};
+use neqo_crypto::{
+    hkdf, Cipher, SymKey, TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384, TLS_VERSION_1_3,
+};
+use neqo_http3::{HFrame, HSettingType};
+use neqo_qpack::decoder::QPackDecoder;
+use neqo_qpack::QpackSettings;
+use neqo_transport::{
+    ConnectionId, ConnectionIdDecoder, ConnectionIdRef, CryptoDxDirection, CryptoStates,
+    DecryptedPacket, Frame, PacketType, PublicPacket, StreamId,
+};
+
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::fs;
+use std::io::{self, Read};
+use std::mem;
+use std::path::PathBuf;
+use std::time::Instant;
+use structopt::StructOpt;
+
+const CLIENT_EARLY: &str = "CLIENT_EARLY_TRAFFIC_SECRET";
+const CLIENT_HANDSHAKE: &str = "CLIENT_HANDSHAKE_TRAFFIC_SECRET";
+const SERVER_HANDSHAKE: &str = "SERVER_HANDSHAKE_TRAFFIC_SECRET";
+const CLIENT_APPLICATION: &str = "CLIENT_TRAFFIC_SECRET_0";
+const SERVER_APPLICATION: &str = "SERVER_TRAFFIC_SECRET_0";
+
+// Writing to a `String` can't fail.
+macro_rules! out {
+    ($out:expr, $($arg:tt)*) => {
+        writeln!($out, $($arg)*).unwrap()
+    };
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    IoError(io::Error),
+    /// The input or the key log is malformed.
+    FormatError(String),
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(err: io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+type Res<T> = Result<T, DecodeError>;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "neqo-decode",
+    about = "Prints the QUIC packets and HTTP/3 frames in captured datagrams."
+)]
+struct Args {
+    #[structopt(parse(from_os_str))]
+    /// A file with a datagram on each line.  Standard input is read if there is no file.
+    input: Option<PathBuf>,
+    #[structopt(short = "k", long, parse(from_os_str))]
+    /// A key log with the TLS secrets for the connection.
+    keylog: Option<PathBuf>,
+    #[structopt(short = "c", long)]
+    /// The length of connection IDs, if the capture has no long header packets.
+    cid_len: Option<usize>,
+    #[structopt(short = "b", long)]
+    /// Read datagrams as base64, even those that look like hex.
+    base64: bool,
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// This takes both the standard and the URL-safe alphabets, with or without padding.
+fn from_base64(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    base64::decode_config(s, base64::STANDARD_NO_PAD)
+        .or_else(|_| base64::decode_config(s, base64::URL_SAFE_NO_PAD))
+        .ok()
+}
+
+/// A datagram is in hex, which can have spaces and a leading "0x", or in base64.
+fn parse_datagram(line: &str, base64: bool) -> Res<Vec<u8>> {
+    let s: String = line.split_whitespace().collect();
+    let d = if base64 {
+        from_base64(&s)
+    } else if s.starts_with("0x") {
+        from_hex(&s[2..])
+    } else {
+        from_hex(&s).or_else(|| from_base64(&s))
+    };
+    d.ok_or_else(|| DecodeError::FormatError(format!("not hex or base64: {}", line)))
+}
+
+/// The secrets for a connection, by label.
+type Secrets = HashMap<String, Vec<u8>>;
+
+/// Read a key log, which has a line for each secret with a label, the client
+/// random, and the secret.  This returns the secrets for each client random.
+fn parse_keylog(text: &str) -> Res<HashMap<Vec<u8>, Secrets>> {
+    let mut connections: HashMap<_, Secrets> = HashMap::new();
+    for line in text.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        match (
+            parts.next(),
+            parts.next().and_then(from_hex),
+            parts.next().and_then(from_hex),
+            parts.next(),
+        ) {
+            (Some(label), Some(random), Some(secret), None) => {
+                connections
+                    .entry(random)
+                    .or_default()
+                    .insert(label.to_owned(), secret);
+            }
+            _ => {
+                return Err(DecodeError::FormatError(format!(
+                    "bad key log line: {}",
+                    line
+                )))
+            }
+        }
+    }
+    Ok(connections)
+}
+
+/// The cipher suite that goes with a secret, for when there is no ServerHello.
+/// This can't tell ChaCha20 from AES-128, so it picks AES.
+fn guess_cipher(secret: &[u8]) -> Cipher {
+    if secret.len() == 48 {
+        TLS_AES_256_GCM_SHA384
+    } else {
+        TLS_AES_128_GCM_SHA256
+    }
+}
+
+/// The client random from the start of a ClientHello.
+fn client_random(hello: &[u8]) -> Option<&[u8]> {
+    if hello.first() == Some(&1) {
+        hello.get(6..38)
+    } else {
+        None
+    }
+}
+
+/// The cipher suite from the start of a ServerHello.
+fn server_cipher(hello: &[u8]) -> Option<Cipher> {
+    let mut dec = Decoder::from(hello);
+    if dec.decode_byte()? != 2 {
+        return None;
+    }
+    // The length, version and random.
+    dec.decode(3 + 2 + 32)?;
+    // The session ID.
+    dec.decode_vec(1)?;
+    Cipher::try_from(dec.decode_uint(2)?).ok()
+}
+
+/// Connection IDs in short headers don't say how long they are, so that has to
+/// come from the long header packets, or from the command line.
+struct CidLength(usize);
+
+impl ConnectionIdDecoder for CidLength {
+    fn decode_cid<'a>(&self, dec: &mut Decoder<'a>) -> Option<ConnectionIdRef<'a>> {
+        dec.decode(self.0).map(ConnectionIdRef::from)
+    }
+}
+
+/// Puts the pieces of a stream back in order.
+#[derive(Debug, Default)]
+struct Reassembly {
+    /// Where the data that has been returned ends.
+    offset: u64,
+    /// Pieces that can't be returned yet, by offset.
+    pending: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Reassembly {
+    /// Add data at `offset` and return anything that is now in order.
+    fn add(&mut self, offset: u64, data: &[u8]) -> Vec<u8> {
+        let piece = self.pending.entry(offset).or_default();
+        if data.len() > piece.len() {
+            *piece = data.to_vec();
+        }
+
+        let mut out = Vec::new();
+        while let Some(start) = self.pending.keys().next().copied() {
+            if start > self.offset {
+                break;
+            }
+            let piece = self.pending.remove(&start).unwrap();
+            let end = start + u64::try_from(piece.len()).unwrap();
+            if end > self.offset {
+                out.extend_from_slice(&piece[usize::try_from(self.offset - start).unwrap()..]);
+                self.offset = end;
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StreamKind {
+    /// A unidirectional stream with a type that hasn't been read yet.
+    Untyped,
+    /// A push stream with the push ID still to read.
+    Push,
+    /// HTTP/3 frames: requests, responses, pushes and the control stream.
+    Frames,
+    QpackEncoder,
+    /// The QPACK decoder stream, or anything else that isn't decoded.
+    Opaque,
+}
+
+#[derive(Debug)]
+struct Stream {
+    kind: StreamKind,
+    data: Reassembly,
+    /// Data that is in order, but hasn't been decoded.
+    buf: Vec<u8>,
+    /// How much of the payload of a DATA frame is still to come.
+    data_left: u64,
+}
+
+impl Stream {
+    fn new(id: StreamId) -> Self {
+        Self {
+            kind: if id.is_uni() {
+                StreamKind::Untyped
+            } else {
+                StreamKind::Frames
+            },
+            data: Reassembly::default(),
+            buf: Vec::new(),
+            data_left: 0,
+        }
+    }
+}
+
+/// What is known about one side of the connection and what it sends.
+#[derive(Debug, Default)]
+struct Sender {
+    /// The keys for packets from this side, set up as the other side has them.
+    keys: CryptoStates,
+    /// 0-RTT keys are kept apart, so that they can be used after 1-RTT keys are in place.
+    early_keys: Option<CryptoStates>,
+    /// The connection ID that this side chose.
+    cid: Option<ConnectionId>,
+    /// The CRYPTO stream in Initial packets, which starts with the ClientHello or ServerHello.
+    hello: Reassembly,
+    hello_buf: Vec<u8>,
+    streams: BTreeMap<u64, Stream>,
+    /// The QPACK decoder for header blocks from this side.
+    qpack: Option<QPackDecoder>,
+    /// Header blocks that need more of the encoder stream, by stream ID.
+    blocked: BTreeMap<u64, Vec<u8>>,
+    /// The QPACK table capacity from the SETTINGS that this side sent.
+    qpack_capacity: Option<u64>,
+}
+
+impl Sender {
+    /// The QPACK decoder is made when it is first needed.  How big the table can be
+    /// is set by the other side, so `peer_capacity` comes from what it sent.
+    fn qpack(&mut self, peer_capacity: Option<u64>) -> &mut QPackDecoder {
+        let max_table_size_decoder = peer_capacity.unwrap_or(0);
+        self.qpack.get_or_insert_with(|| {
+            QPackDecoder::new(QpackSettings {
+                max_table_size_decoder,
+                max_table_size_encoder: 0,
+                max_blocked_streams: u16::max_value(),
+            })
+        })
+    }
+
+    fn header_block(
+        &mut self,
+        out: &mut String,
+        peer_capacity: Option<u64>,
+        id: u64,
+        block: &[u8],
+    ) {
+        match self.qpack(peer_capacity).decode_header_block(block, id) {
+            Ok(Some(headers)) => {
+                for (name, value) in headers {
+                    out!(out, "        {}: {}", name, value);
+                }
+            }
+            Ok(None) => {
+                out!(out, "        (waiting for the encoder stream)");
+                self.blocked.insert(id, block.to_vec());
+            }
+            Err(e) => out!(out, "        bad header block: {:?}", e),
+        }
+    }
+
+    fn h3_frame(
+        &mut self,
+        out: &mut String,
+        peer_capacity: Option<u64>,
+        id: u64,
+        stream: &mut Stream,
+        f: HFrame,
+    ) {
+        match f {
+            HFrame::Data { len } => {
+                out!(out, "      stream {}: DATA, {} bytes", id, len);
+                stream.data_left = len;
+            }
+            HFrame::Headers { header_block } => {
+                out!(out, "      stream {}: HEADERS", id);
+                self.header_block(out, peer_capacity, id, &header_block);
+            }
+            HFrame::PushPromise {
+                push_id,
+                header_block,
+            } => {
+                out!(out, "      stream {}: PUSH_PROMISE {}", id, push_id);
+                self.header_block(out, peer_capacity, id, &header_block);
+            }
+            HFrame::Settings { settings } => {
+                out!(out, "      stream {}: SETTINGS", id);
+                for s in settings.iter() {
+                    out!(out, "        {:?}: {}", s.setting_type, s.value);
+                }
+                self.qpack_capacity = Some(settings.get(HSettingType::MaxTableCapacity));
+            }
+            f => out!(out, "      stream {}: {:?}", id, f),
+        }
+    }
+
+    fn read_stream(
+        &mut self,
+        out: &mut String,
+        peer_capacity: Option<u64>,
+        id: u64,
+        stream: &mut Stream,
+    ) {
+        loop {
+            match stream.kind {
+                StreamKind::Untyped | StreamKind::Push => {
+                    let mut dec = Decoder::from(&stream.buf[..]);
+                    let v = if let Some(v) = dec.decode_varint() {
+                        v
+                    } else {
+                        break;
+                    };
+                    let used = dec.offset();
+                    stream.buf.drain(..used);
+                    stream.kind = if stream.kind == StreamKind::Push {
+                        out!(out, "      stream {}: push ID {}", id, v);
+                        StreamKind::Frames
+                    } else {
+                        let (name, kind) = match v {
+                            0x0 => ("control", StreamKind::Frames),
+                            0x1 => ("push", StreamKind::Push),
+                            0x2 => ("QPACK encoder", StreamKind::QpackEncoder),
+                            0x3 => ("QPACK decoder", StreamKind::Opaque),
+                            _ => ("unknown type", StreamKind::Opaque),
+                        };
+                        out!(out, "      stream {}: {} ({:#x})", id, name, v);
+                        kind
+                    };
+                }
+                StreamKind::Frames if stream.data_left > 0 => {
+                    let n = min(stream.data_left, u64::try_from(stream.buf.len()).unwrap());
+                    if n == 0 {
+                        break;
+                    }
+                    stream.buf.drain(..usize::try_from(n).unwrap());
+                    stream.data_left -= n;
+                }
+                StreamKind::Frames => match HFrame::decode(&stream.buf) {
+                    Ok(Some((f, used))) => {
+                        stream.buf.drain(..used);
+                        if let Some(f) = f {
+                            self.h3_frame(out, peer_capacity, id, stream, f);
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        out!(out, "      stream {}: bad frame: {:?}", id, e);
+                        stream.kind = StreamKind::Opaque;
+                    }
+                },
+                StreamKind::QpackEncoder => {
+                    if stream.buf.is_empty() {
+                        break;
+                    }
+                    let buf = mem::replace(&mut stream.buf, Vec::new());
+                    out!(out, "      stream {}: {} bytes", id, buf.len());
+                    match self.qpack(peer_capacity).consume_encoder_stream(&buf) {
+                        Ok(unblocked) => {
+                            for s in unblocked {
+                                if let Some(block) = self.blocked.remove(&s) {
+                                    out!(out, "      stream {}: HEADERS, now unblocked", s);
+                                    self.header_block(out, peer_capacity, s, &block);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            out!(out, "      stream {}: bad instructions: {:?}", id, e);
+                            stream.kind = StreamKind::Opaque;
+                        }
+                    }
+                }
+                StreamKind::Opaque => {
+                    stream.buf.clear();
+                    break;
+                }
+            }
+        }
+    }
+
+    fn stream_frame(
+        &mut self,
+        out: &mut String,
+        peer_capacity: Option<u64>,
+        stream_id: StreamId,
+        offset: u64,
+        data: &[u8],
+    ) {
+        let id = stream_id.as_u64();
+        let mut stream = self
+            .streams
+            .remove(&id)
+            .unwrap_or_else(|| Stream::new(stream_id));
+        let data = stream.data.add(offset, data);
+        stream.buf.extend_from_slice(&data);
+        self.read_stream(out, peer_capacity, id, &mut stream);
+        self.streams.insert(id, stream);
+    }
+
+    /// Only the start of the Initial CRYPTO stream is needed.
+    fn hello(&mut self, offset: u64, data: &[u8]) {
+        const HELLO_LIMIT: usize = 4096;
+        let data = self.hello.add(offset, data);
+        if self.hello_buf.len() < HELLO_LIMIT {
+            self.hello_buf.extend_from_slice(&data);
+        }
+    }
+}
+
+/// Decodes the datagrams from one connection.
+struct Capture {
+    client: Sender,
+    server: Sender,
+    secrets: HashMap<Vec<u8>, Secrets>,
+    /// The destination connection ID from the first client Initial.
+    odcid: Option<ConnectionId>,
+    /// The length of connection IDs, from the command line.
+    cid_len: Option<usize>,
+    client_random: Option<Vec<u8>>,
+    cipher: Option<Cipher>,
+    /// Whether there are Handshake and 1-RTT keys.
+    keys_installed: bool,
+    /// There is no clock, so key updates are used as soon as they are seen.
+    now: Instant,
+    datagrams: usize,
+    out: String,
+}
+
+impl Capture {
+    fn new(secrets: HashMap<Vec<u8>, Secrets>, cid_len: Option<usize>) -> Self {
+        Self {
+            client: Sender::default(),
+            server: Sender::default(),
+            secrets,
+            odcid: None,
+            cid_len,
+            client_random: None,
+            cipher: None,
+            keys_installed: false,
+            now: Instant::now(),
+            datagrams: 0,
+            out: String::new(),
+        }
+    }
+
+    fn take_output(&mut self) -> String {
+        mem::replace(&mut self.out, String::new())
+    }
+
+    fn sender(&mut self, role: Role) -> &mut Sender {
+        match role {
+            Role::Client => &mut self.client,
+            Role::Server => &mut self.server,
+        }
+    }
+
+    /// The secrets for this connection: those for the client random, or the only ones there are.
+    fn connection_secrets(&self) -> Option<&Secrets> {
+        match &self.client_random {
+            Some(r) => self.secrets.get(r),
+            None if self.secrets.len() == 1 => self.secrets.values().next(),
+            None => None,
+        }
+    }
+
+    /// Set up keys from the key log.  Handshake and 1-RTT keys need the cipher suite
+    /// from the ServerHello.  Without that, `guess` picks one from the secrets.
+    fn install_keys(&mut self, guess: bool) {
+        let secrets = if let Some(s) = self.connection_secrets() {
+            s.clone()
+        } else {
+            return;
+        };
+
+        if self.client.early_keys.is_none() {
+            if let Some(secret) = secrets.get(CLIENT_EARLY) {
+                // 0-RTT uses the cipher suite from an earlier connection.
+                let cipher = guess_cipher(secret);
+                if let Ok(key) = hkdf::import_key(TLS_VERSION_1_3, cipher, secret) {
+                    let mut keys = CryptoStates::default();
+                    keys.set_0rtt_keys(CryptoDxDirection::Read, &key, cipher);
+                    self.client.early_keys = Some(keys);
+                }
+            }
+        }
+
+        if self.keys_installed {
+            return;
+        }
+        let cipher = match (self.cipher, secrets.get(CLIENT_HANDSHAKE)) {
+            (Some(c), _) => c,
+            (None, Some(secret)) if guess => guess_cipher(secret),
+            _ => return,
+        };
+        let get = |label: &str| -> Option<SymKey> {
+            hkdf::import_key(TLS_VERSION_1_3, cipher, secrets.get(label)?).ok()
+        };
+        let (client_hs, server_hs) = match (get(CLIENT_HANDSHAKE), get(SERVER_HANDSHAKE)) {
+            (Some(c), Some(s)) => (c, s),
+            _ => return,
+        };
+        self.keys_installed = true;
+        self.client
+            .keys
+            .set_handshake_keys(&server_hs, &client_hs, cipher);
+        self.server
+            .keys
+            .set_handshake_keys(&client_hs, &server_hs, cipher);
+
+        let now = self.now;
+        let app = |keys: &mut CryptoStates, write: &str, read: &str| -> Option<()> {
+            keys.set_application_write_key(get(write)?).ok()?;
+            keys.set_application_read_key(get(read)?, now).ok()
+        };
+        let client_app = app(
+            &mut self.client.keys,
+            SERVER_APPLICATION,
+            CLIENT_APPLICATION,
+        );
+        let server_app = app(
+            &mut self.server.keys,
+            CLIENT_APPLICATION,
+            SERVER_APPLICATION,
+        );
+        if client_app.is_none() || server_app.is_none() {
+            out!(self.out, "  (no 1-RTT keys)");
+        }
+    }
+
+    fn describe(&mut self, packet: &PublicPacket) {
+        let pt = packet.packet_type();
+        if pt == PacketType::Short {
+            out!(self.out, "  Short dcid={}", packet.dcid());
+        } else {
+            out!(
+                self.out,
+                "  {:?} dcid={} scid={}",
+                pt,
+                packet.dcid(),
+                packet.scid()
+            );
+        }
+        if !packet.token().is_empty() {
+            out!(self.out, "    token={}", hex(packet.token()));
+        }
+    }
+
+    /// The first client Initial sets the connection ID that Initial keys come from.
+    fn first_initial(&mut self, packet: &PublicPacket) {
+        let odcid = ConnectionId::from(packet.dcid());
+        self.client.keys.init(Role::Server, &odcid);
+        self.server.keys.init(Role::Client, &odcid);
+        self.client.cid = Some(ConnectionId::from(packet.scid()));
+        self.odcid = Some(odcid);
+    }
+
+    fn retry(&mut self, packet: &PublicPacket) {
+        let valid = self
+            .odcid
+            .as_ref()
+            .map_or(false, |odcid| packet.is_valid_retry(odcid));
+        if !valid {
+            out!(self.out, "    (integrity tag doesn't match)");
+            return;
+        }
+        // Initial keys now come from the connection ID that the server chose.
+        self.client.keys.init(Role::Server, packet.scid());
+        self.server.keys.init(Role::Client, packet.scid());
+        self.server.cid = Some(ConnectionId::from(packet.scid()));
+    }
+
+    fn try_decrypt(&mut self, role: Role, packet: &PublicPacket) -> Option<DecryptedPacket> {
+        let now = self.now;
+        let sender = self.sender(role);
+        let keys = if packet.packet_type() == PacketType::ZeroRtt {
+            sender.early_keys.as_mut()?
+        } else {
+            &mut sender.keys
+        };
+        let _ = keys.check_key_update(now);
+        packet.decrypt(keys, now).ok()
+    }
+
+    fn hello(&mut self, role: Role, offset: u64, data: &[u8]) {
+        self.sender(role).hello(offset, data);
+        match role {
+            Role::Client if self.client_random.is_none() => {
+                if let Some(r) = client_random(&self.client.hello_buf) {
+                    out!(self.out, "      ClientHello, random={}", hex(r));
+                    self.client_random = Some(r.to_vec());
+                    self.install_keys(false);
+                }
+            }
+            Role::Server if self.cipher.is_none() => {
+                if let Some(c) = server_cipher(&self.server.hello_buf) {
+                    out!(self.out, "      ServerHello, cipher={:#06x}", c);
+                    self.cipher = Some(c);
+                    self.install_keys(false);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn frame(&mut self, role: Role, pt: PacketType, f: Frame) {
+        if let Some(s) = f.dump() {
+            out!(self.out, "      {}", s);
+        }
+        match f {
+            Frame::Crypto { offset, data } if pt == PacketType::Initial => {
+                self.hello(role, offset, &data);
+            }
+            Frame::Stream {
+                stream_id,
+                offset,
+                data,
+                ..
+            } => {
+                let (sender, peer) = match role {
+                    Role::Client => (&mut self.client, &self.server),
+                    Role::Server => (&mut self.server, &self.client),
+                };
+                sender.stream_frame(&mut self.out, peer.qpack_capacity, stream_id, offset, &data);
+            }
+            _ => {}
+        }
+    }
+
+    fn decrypted(&mut self, role: Role, packet: &PublicPacket, d: &DecryptedPacket) {
+        out!(self.out, "    from {:?}, pn={}", role, d.pn());
+        if role == Role::Server
+            && packet.packet_type() != PacketType::Short
+            && self.server.cid.is_none()
+        {
+            self.server.cid = Some(ConnectionId::from(packet.scid()));
+        }
+
+        let mut dec = Decoder::from(&d[..]);
+        let mut padding = 0;
+        while dec.remaining() > 0 {
+            match Frame::decode(&mut dec) {
+                Ok(Frame::Padding) => padding += 1,
+                Ok(f) => self.frame(role, d.packet_type(), f),
+                Err(e) => {
+                    out!(self.out, "      bad frame: {:?}", e);
+                    break;
+                }
+            }
+        }
+        if padding > 0 {
+            out!(self.out, "      Padding, {} bytes", padding);
+        }
+    }
+
+    fn long_packet<'a>(&mut self, data: &'a [u8]) -> Option<&'a [u8]> {
+        let (packet, rest) = match PublicPacket::decode(data, &CidLength(0)) {
+            Ok(p) => p,
+            Err(e) => {
+                out!(self.out, "  bad packet: {:?}", e);
+                return None;
+            }
+        };
+        self.describe(&packet);
+        let pt = packet.packet_type();
+        match pt {
+            PacketType::VersionNegotiation => match packet.supported_versions() {
+                Ok(v) => out!(self.out, "    versions={:x?}", v),
+                Err(e) => out!(self.out, "    bad versions: {:?}", e),
+            },
+            PacketType::Retry => self.retry(&packet),
+            PacketType::Initial | PacketType::Handshake | PacketType::ZeroRtt => {
+                if pt == PacketType::Initial && self.odcid.is_none() {
+                    self.first_initial(&packet);
+                } else if pt != PacketType::Initial {
+                    self.install_keys(pt == PacketType::Handshake);
+                }
+                let senders: &[Role] = if pt == PacketType::ZeroRtt {
+                    &[Role::Client]
+                } else {
+                    &[Role::Client, Role::Server]
+                };
+                let decrypted = senders
+                    .iter()
+                    .find_map(|&role| self.try_decrypt(role, &packet).map(|d| (role, d)));
+                if let Some((role, d)) = decrypted {
+                    self.decrypted(role, &packet, &d);
+                } else {
+                    out!(self.out, "    (not decrypted)");
+                }
+            }
+            _ => {}
+        }
+        Some(rest)
+    }
+
+    fn short_packet(&mut self, data: &[u8]) {
+        self.install_keys(true);
+        // The client sends to the connection ID that the server chose, and the
+        // other way around.
+        let cid_len = |s: &Sender| s.cid.as_ref().map(|c| c.len());
+        let guesses = [
+            (Role::Client, cid_len(&self.server).or(self.cid_len)),
+            (Role::Server, cid_len(&self.client).or(self.cid_len)),
+        ];
+        let mut fallback = None;
+        let mut error = None;
+        for &(role, len) in &guesses {
+            let len = if let Some(len) = len { len } else { continue };
+            match PublicPacket::decode(data, &CidLength(len)) {
+                Ok((packet, _)) => {
+                    if let Some(d) = self.try_decrypt(role, &packet) {
+                        self.describe(&packet);
+                        self.decrypted(role, &packet, &d);
+                        return;
+                    }
+                    if fallback.is_none() {
+                        fallback = Some(packet);
+                    }
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        if let Some(packet) = fallback {
+            self.describe(&packet);
+            out!(self.out, "    (not decrypted)");
+        } else if let Some(e) = error {
+            out!(self.out, "  bad packet: {:?}", e);
+        } else {
+            out!(self.out, "  Short, with a connection ID of unknown length");
+        }
+    }
+
+    fn datagram(&mut self, d: &[u8]) {
+        self.datagrams += 1;
+        out!(self.out, "datagram {}: {} bytes", self.datagrams, d.len());
+        let mut rest = d;
+        while !rest.is_empty() {
+            if rest[0] & 0x40 == 0 {
+                // Without the fixed bit, this isn't a packet.
+                out!(self.out, "  {} bytes that aren't a packet", rest.len());
+                break;
+            }
+            if rest[0] & 0x80 == 0 {
+                self.short_packet(rest);
+                break;
+            }
+            match self.long_packet(rest) {
+                Some(r) => rest = r,
+                None => break,
+            }
+        }
+    }
+}
+
+fn main() -> Res<()> {
+    let args = Args::from_args();
+    neqo_crypto::init();
+
+    let secrets = if let Some(keylog) = &args.keylog {
+        parse_keylog(&fs::read_to_string(keylog)?)?
+    } else {
+        HashMap::new()
+    };
+    let input = if let Some(input) = &args.input {
+        fs::read_to_string(input)?
+    } else {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        input
+    };
+
+    let mut capture = Capture::new(secrets, args.cid_len);
+    for line in input.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        capture.datagram(&parse_datagram(line, args.base64)?);
+        print!("{}", capture.take_output());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_datagram, parse_keylog, Capture, Reassembly, Sender};
+    use neqo_common::Encoder;
+    use neqo_http3::{HFrame, HSetting, HSettingType, HSettings};
+    use neqo_qpack::encoder::QPackEncoder;
+    use neqo_qpack::QpackSettings;
+    use neqo_transport::StreamId;
+    use std::collections::HashMap;
+    use test_fixture::{default_client, default_server, fixture_init, now};
+
+    #[test]
+    fn datagram_formats() {
+        let d = vec![0x01, 0xab, 0xff];
+        assert_eq!(parse_datagram("01abff", false).unwrap(), d);
+        assert_eq!(parse_datagram("0x01 AB ff", false).unwrap(), d);
+        assert_eq!(parse_datagram("Aav/", false).unwrap(), d);
+        assert_eq!(parse_datagram("Aav_", false).unwrap(), d);
+        assert_eq!(parse_datagram("AQ==", false).unwrap(), vec![0x01]);
+        // This looks like hex, but it can be read as base64.
+        assert_eq!(
+            parse_datagram("abcd", true).unwrap(),
+            vec![0x69, 0xb7, 0x1d]
+        );
+        assert!(parse_datagram("not base64!", false).is_err());
+    }
+
+    #[test]
+    fn keylog() {
+        let log = "# a comment\n\
+                   CLIENT_HANDSHAKE_TRAFFIC_SECRET 0102 aabb\n\
+                   SERVER_HANDSHAKE_TRAFFIC_SECRET 0102 ccdd\n\
+                   CLIENT_TRAFFIC_SECRET_0 0304 eeff\n";
+        let secrets = parse_keylog(log).unwrap();
+        assert_eq!(secrets.len(), 2);
+        assert_eq!(
+            secrets[&vec![1_u8, 2]]["SERVER_HANDSHAKE_TRAFFIC_SECRET"],
+            vec![0xcc, 0xdd]
+        );
+        assert!(parse_keylog("CLIENT_TRAFFIC_SECRET_0 0304\n").is_err());
+    }
+
+    #[test]
+    fn reassembly() {
+        let mut r = Reassembly::default();
+        assert!(r.add(3, &[3, 4]).is_empty());
+        assert_eq!(r.add(0, &[0, 1, 2]), vec![0, 1, 2, 3, 4]);
+        // Data that was already returned isn't returned again.
+        assert_eq!(r.add(1, &[1, 2, 3, 4, 5]), vec![5]);
+    }
+
+    #[test]
+    fn handshake_without_keys() {
+        fixture_init();
+        let mut client = default_client();
+        let mut server = default_server();
+        let mut capture = Capture::new(HashMap::new(), None);
+
+        let initial = client.process(None, now()).dgram().unwrap();
+        capture.datagram(&initial);
+        let out = capture.take_output();
+        assert!(out.contains("Initial"));
+        assert!(out.contains("from Client"));
+        assert!(out.contains("ClientHello"));
+
+        let reply = server.process(Some(initial), now()).dgram().unwrap();
+        capture.datagram(&reply);
+        let out = capture.take_output();
+        assert!(out.contains("from Server"));
+        assert!(out.contains("ServerHello"));
+        // There is no key log, so the Handshake packet can't be read.
+        assert!(out.contains("Handshake"));
+        assert!(out.contains("(not decrypted)"));
+    }
+
+    #[test]
+    fn short_packet_undecodable() {
+        fixture_init();
+        // A connection ID longer than the packet.
+        let mut capture = Capture::new(HashMap::new(), Some(20));
+        capture.datagram(&[0x40, 1, 2, 3]);
+        let out = capture.take_output();
+        assert!(out.contains("bad packet"));
+
+        // The next datagram is still read.
+        capture.datagram(&[0x01]);
+        assert!(capture.take_output().contains("datagram 2"));
+    }
+
+    fn stream(sender: &mut Sender, out: &mut String, id: u64, offset: u64, data: &[u8]) {
+        sender.stream_frame(out, Some(4096), StreamId::from(id), offset, data);
+    }
+
+    #[test]
+    fn http3_streams() {
+        let settings = QpackSettings {
+            max_table_size_encoder: 4096,
+            max_table_size_decoder: 4096,
+            max_blocked_streams: 10,
+        };
+        let mut encoder = QPackEncoder::new(settings, true);
+        encoder.add_send_stream_without_type(6);
+        encoder.set_max_blocked_streams(10).unwrap();
+        encoder.set_max_capacity(4096).unwrap();
+        let headers = vec![
+            (String::from(":method"), String::from("GET")),
+            (String::from("x-neqo"), String::from("decode")),
+        ];
+        let mut instructions = Vec::new();
+        encoder.send(&mut instructions).unwrap();
+        let block = encoder
+            .encode_header_block(&mut instructions, &headers, 0)
+            .unwrap();
+
+        let mut control = Encoder::default();
+        control.encode_varint(0_u64);
+        HFrame::Settings {
+            settings: HSettings::new(&[HSetting::new(HSettingType::MaxTableCapacity, 4096)]),
+        }
+        .encode(&mut control);
+        let mut request = Encoder::default();
+        HFrame::Headers {
+            header_block: block.to_vec(),
+        }
+        .encode(&mut request);
+        HFrame::Data { len: 3 }.encode(&mut request);
+        request.encode(&[1, 2, 3]);
+        HFrame::Goaway { stream_id: 4 }.encode(&mut request);
+        let mut encoder_stream = Encoder::default();
+        encoder_stream.encode_varint(2_u64).encode(&instructions);
+
+        let mut sender = Sender::default();
+        let mut out = String::new();
+        // The control stream arrives in two pieces, out of order.
+        stream(&mut sender, &mut out, 2, 3, &control[3..]);
+        assert!(out.is_empty());
+        stream(&mut sender, &mut out, 2, 0, &control[..3]);
+        assert!(out.contains("control"));
+        assert!(out.contains("MaxTableCapacity: 4096"));
+        assert_eq!(sender.qpack_capacity, Some(4096));
+
+        // The request comes before the encoder stream that it might need, and the
+        // DATA frame is split.
+        stream(&mut sender, &mut out, 0, 0, &request[..request.len() - 4]);
+        stream(&mut sender, &mut out, 0, 0, &request);
+        stream(&mut sender, &mut out, 6, 0, &encoder_stream);
+        assert!(out.contains("DATA, 3 bytes"));
+        assert!(out.contains("Goaway { stream_id: 4 }"));
+        assert!(out.contains(":method: GET"));
+        assert!(out.contains("x-neqo: decode"));
+    }
+}
@@ -8,6 +8,7 @@
 #![warn(clippy::use_self)]
 
 use std::cell::RefCell;
+use std::cmp::min;
 use std::collections::HashMap;
 use std::io;
 use std::net::{SocketAddr, ToSocketAddrs};
@@ -23,11 +24,16 @@ use structopt::StructOpt;
 
 use neqo_common::{qdebug, qinfo, Datagram};
 use neqo_crypto::{init_db, AntiReplay};
-use neqo_http3::{Error, Http3Server, Http3ServerEvent};
+use neqo_http3::{Error, Header, Http3Server, Http3ServerEvent};
 use neqo_qpack::QpackSettings;
+use neqo_transport::server::ValidateAddress;
 use neqo_transport::{FixedConnectionIdManager, Output};
+use neqo_udp as udp;
 
 const TIMER_TOKEN: Token = Token(0xffff_ffff);
+/// With --verbose, how long the server has to be idle before the counts of socket
+/// calls are reported.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "neqo-http3-server", about = "A basic HTTP3 server.")]
@@ -77,6 +83,20 @@ struct Args {
     #[structopt(name = "qlog-dir", long)]
     /// Enable QLOG logging and QLOG traces to this directory
     qlog_dir: Option<PathBuf>,
+
+    #[structopt(short = "r", long)]
+    /// Send a Retry to validate the address of each client.
+    retry: bool,
+
+    #[structopt(name = "disable-migration", long)]
+    /// Don't let clients move connections to a new address, and tell them so with
+    /// the disable_active_migration transport parameter.
+    disable_migration: bool,
+
+    #[structopt(short = "v", long)]
+    /// Report how many datagrams each socket sent and received, and in how many
+    /// calls, whenever the server has been idle for a second.
+    verbose: bool,
 }
 
 impl Args {
@@ -89,6 +109,61 @@ impl Args {
     }
 }
 
+/// Parse a request for a single range, like "bytes=10-" or "bytes=10-19", and
+/// return where it starts and where it ends, if it does.  Other forms of range
+/// are not supported, so the whole resource is served for those.
+fn parse_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let value = value.trim();
+    if !value.starts_with("bytes=") || value.contains(',') {
+        return None;
+    }
+    let mut parts = value["bytes=".len()..].splitn(2, '-');
+    let start = parts.next()?.trim().parse::<u64>().ok()?;
+    let end = match parts.next()?.trim() {
+        "" => None,
+        e => Some(e.parse::<u64>().ok().filter(|&e| e >= start)?),
+    };
+    Some((start, end))
+}
+
+/// Build the response to a request with the given range header.  This is a 206
+/// with the requested part of `body`, a 416 if the range starts past the end,
+/// or a 200 with all of `body` if there is no range that can be served.
+fn range_response(body: &[u8], range: Option<&str>) -> (Vec<Header>, Vec<u8>) {
+    let len = body.len() as u64;
+    match range.and_then(parse_range) {
+        Some((start, _)) if start >= len => (
+            vec![
+                (String::from(":status"), String::from("416")),
+                (String::from("content-range"), format!("bytes */{}", len)),
+            ],
+            Vec::new(),
+        ),
+        Some((start, end)) => {
+            let end = end.map_or(len - 1, |e| min(e, len - 1));
+            let part = body[start as usize..=end as usize].to_vec();
+            (
+                vec![
+                    (String::from(":status"), String::from("206")),
+                    (
+                        String::from("content-range"),
+                        format!("bytes {}-{}/{}", start, end, len),
+                    ),
+                    (String::from("content-length"), part.len().to_string()),
+                ],
+                part,
+            )
+        }
+        None => (
+            vec![
+                (String::from(":status"), String::from("200")),
+                (String::from("content-length"), body.len().to_string()),
+            ],
+            body.to_vec(),
+        ),
+    }
+}
+
 fn process_events(server: &mut Http3Server) {
     while let Some(event) = server.next_event() {
         eprintln!("Event: {:?}", event);
@@ -100,13 +175,11 @@ fn process_events(server: &mut Http3Server) {
             } => {
                 println!("Headers (request={} fin={}): {:?}", request, fin, headers);
 
-                let default_ret = b"Hello World".to_vec();
-
-                let response = headers.and_then(|h| {
-                    h.iter().find(|&(k, _)| k == ":path").and_then(|(_, path)| {
+                let response = headers.as_ref().and_then(|h| {
+                    h.iter().find(|&(k, _)| k == ":path").map(|(_, path)| {
                         match path.trim_matches(|p| p == '/').parse::<usize>() {
-                            Ok(v) => Some(vec![b'a'; v]),
-                            Err(_) => Some(default_ret),
+                            Ok(v) => vec![b'a'; v],
+                            Err(_) => b"Hello World".to_vec(),
                         }
                     })
                 });
@@ -116,17 +189,12 @@ fn process_events(server: &mut Http3Server) {
                     continue;
                 }
 
-                let response = response.unwrap();
-
-                request
-                    .set_response(
-                        &[
-                            (String::from(":status"), String::from("200")),
-                            (String::from("content-length"), response.len().to_string()),
-                        ],
-                        &response,
-                    )
-                    .unwrap();
+                let range = headers
+                    .as_ref()
+                    .and_then(|h| h.iter().find(|&(k, _)| k == "range"))
+                    .map(|(_, v)| v.as_str());
+                let (response_headers, body) = range_response(&response.unwrap(), range);
+                request.set_response(&response_headers, &body).unwrap();
             }
             Http3ServerEvent::Data { request, data, fin } => {
                 println!("Data (request={} fin={}): {:?}", request, fin, data);
@@ -136,19 +204,76 @@ fn process_events(server: &mut Http3Server) {
     }
 }
 
-fn emit_packets(sockets: &mut Vec<UdpSocket>, out_dgrams: &HashMap<SocketAddr, Vec<Datagram>>) {
-    for s in sockets {
-        if let Some(dgrams) = out_dgrams.get(&s.local_addr().unwrap()) {
-            for d in dgrams {
-                let sent = s
-                    .send_to(d, &d.destination())
-                    .expect("Error sending datagram");
-                if sent != d.len() {
-                    eprintln!("Unable to send all {} bytes of datagram", d.len());
-                }
+struct ServerSocket {
+    /// The socket that is polled and read from.
+    socket: UdpSocket,
+    /// The same socket, for sending in batches, which mio can't do.
+    sender: std::net::UdpSocket,
+    stats: udp::BatchStats,
+    /// The counts when they were last reported, with --verbose.
+    reported: udp::BatchStats,
+}
+
+impl ServerSocket {
+    fn bind(host: &SocketAddr) -> io::Result<Self> {
+        let sender = std::net::UdpSocket::bind(host)?;
+        // This makes both non-blocking, as they share the one socket.
+        let socket = UdpSocket::from_socket(sender.try_clone()?)?;
+        Ok(Self {
+            socket,
+            sender,
+            stats: udp::BatchStats::default(),
+            reported: udp::BatchStats::default(),
+        })
+    }
+
+    /// Send all of `dgrams`, in as few calls as possible.  If the socket can't
+    /// take any more, the rest are dropped and left to loss recovery.
+    fn send(&mut self, dgrams: &[Datagram]) {
+        let mut sent = 0;
+        while sent < dgrams.len() {
+            match udp::send_batch_to(&self.sender, &dgrams[sent..], &mut self.stats) {
+                Ok(n) => sent += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => match udp::classify(&err) {
+                    udp::ErrorClass::Retry => {
+                        eprintln!(
+                            "Dropping {} of {} datagrams: socket would block",
+                            dgrams.len() - sent,
+                            dgrams.len()
+                        );
+                        break;
+                    }
+                    udp::ErrorClass::Unreachable | udp::ErrorClass::TooLarge => {
+                        eprintln!(
+                            "Dropping datagram to {}: {}",
+                            dgrams[sent].destination(),
+                            err
+                        );
+                        sent += 1;
+                    }
+                    udp::ErrorClass::AddressGone | udp::ErrorClass::Fatal => {
+                        panic!("Error sending datagram: {}", err)
+                    }
+                },
             }
         }
     }
+
+    fn report_stats(&mut self) {
+        if self.stats != self.reported {
+            eprintln!("UDP {}: {}", self.socket.local_addr().unwrap(), self.stats);
+            self.reported = self.stats;
+        }
+    }
+}
+
+fn emit_packets(sockets: &mut [ServerSocket], out_dgrams: &HashMap<SocketAddr, Vec<Datagram>>) {
+    for s in sockets {
+        if let Some(dgrams) = out_dgrams.get(&s.socket.local_addr().unwrap()) {
+            s.send(dgrams);
+        }
+    }
 }
 
 fn process(
@@ -200,7 +325,7 @@ fn main() -> Result<(), io::Error> {
     poll.register(&timer, TIMER_TOKEN, Ready::readable(), PollOpt::edge())?;
 
     for (i, host) in hosts.iter().enumerate() {
-        let socket = match UdpSocket::bind(&host) {
+        let socket = match ServerSocket::bind(host) {
             Err(err) => {
                 eprintln!("Unable to bind UDP socket: {}", err);
                 exit(1)
@@ -208,7 +333,7 @@ fn main() -> Result<(), io::Error> {
             Ok(s) => s,
         };
 
-        let local_addr = match socket.local_addr() {
+        let local_addr = match socket.socket.local_addr() {
             Err(err) => {
                 eprintln!("Socket local address not bound: {}", err);
                 exit(1)
@@ -216,7 +341,7 @@ fn main() -> Result<(), io::Error> {
             Ok(s) => s,
         };
 
-        let res = socket.only_v6();
+        let res = socket.socket.only_v6();
         let also_v4 = if res.is_ok() && !res.unwrap() {
             " as well as V4"
         } else {
@@ -228,7 +353,7 @@ fn main() -> Result<(), io::Error> {
         );
 
         poll.register(
-            &socket,
+            &socket.socket,
             Token(i),
             Ready::readable() | Ready::writable(),
             PollOpt::edge(),
@@ -254,6 +379,10 @@ fn main() -> Result<(), io::Error> {
                     )
                     .expect("We cannot make a server!");
                     svr.set_qlog_dir(args.qlog_dir.clone());
+                    if args.retry {
+                        svr.set_validation(ValidateAddress::Always);
+                    }
+                    svr.set_allow_migration(!args.disable_migration);
                     svr
                 },
                 None,
@@ -265,13 +394,24 @@ fn main() -> Result<(), io::Error> {
 
     let mut events = Events::with_capacity(1024);
 
+    let poll_timeout = if args.verbose {
+        Some(STATS_INTERVAL)
+    } else {
+        None
+    };
     loop {
-        poll.poll(&mut events, None)?;
+        poll.poll(&mut events, poll_timeout)?;
+        if events.is_empty() {
+            for s in &mut sockets {
+                s.report_stats();
+            }
+            continue;
+        }
         let mut out_dgrams = HashMap::new();
         for event in &events {
             if event.token() == TIMER_TOKEN {
                 while let Some(inx) = timer.poll() {
-                    if let Some(socket) = sockets.get(inx) {
+                    if let Some(ServerSocket { socket, .. }) = sockets.get(inx) {
                         qinfo!("Timer expired for {:?}", socket);
                         if let Some((server, svr_timeout)) =
                             servers.get_mut(&socket.local_addr().unwrap())
@@ -289,7 +429,9 @@ fn main() -> Result<(), io::Error> {
                         }
                     }
                 }
-            } else if let Some(socket) = sockets.get(event.token().0) {
+            } else if let Some(ServerSocket { socket, stats, .. }) =
+                sockets.get_mut(event.token().0)
+            {
                 let local_addr = hosts[event.token().0];
 
                 if !event.readiness().is_readable() {
@@ -297,6 +439,7 @@ fn main() -> Result<(), io::Error> {
                 }
 
                 loop {
+                    stats.recv_calls += 1;
                     let (sz, remote_addr) = match socket.recv_from(&mut buf[..]) {
                         Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
                         Err(err) => {
@@ -305,6 +448,7 @@ fn main() -> Result<(), io::Error> {
                         }
                         Ok(res) => res,
                     };
+                    stats.received += 1;
 
                     if sz == buf.len() {
                         eprintln!("Might have received more than {} bytes", buf.len());
@@ -336,3 +480,59 @@ fn main() -> Result<(), io::Error> {
         emit_packets(&mut sockets, &out_dgrams);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_range, range_response};
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=10-"), Some((10, None)));
+        assert_eq!(parse_range("bytes=10-19"), Some((10, Some(19))));
+        assert_eq!(parse_range("bytes=19-10"), None);
+        assert_eq!(parse_range("bytes=-10"), None);
+        assert_eq!(parse_range("bytes=0-1,5-6"), None);
+        assert_eq!(parse_range("items=0-1"), None);
+    }
+
+    fn status(headers: &[(String, String)]) -> &str {
+        &headers.iter().find(|(k, _)| k == ":status").unwrap().1
+    }
+
+    fn content_range(headers: &[(String, String)]) -> Option<&str> {
+        headers
+            .iter()
+            .find(|(k, _)| k == "content-range")
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn range_responses() {
+        let body = b"0123456789";
+
+        let (headers, part) = range_response(body, Some("bytes=4-"));
+        assert_eq!(status(&headers), "206");
+        assert_eq!(content_range(&headers), Some("bytes 4-9/10"));
+        assert_eq!(part, b"456789");
+
+        let (headers, part) = range_response(body, Some("bytes=2-3"));
+        assert_eq!(content_range(&headers), Some("bytes 2-3/10"));
+        assert_eq!(part, b"23");
+
+        // The end is limited to what there is.
+        let (_, part) = range_response(body, Some("bytes=8-100"));
+        assert_eq!(part, b"89");
+
+        let (headers, part) = range_response(body, Some("bytes=10-"));
+        assert_eq!(status(&headers), "416");
+        assert_eq!(content_range(&headers), Some("bytes */10"));
+        assert!(part.is_empty());
+
+        for range in &[None, Some("bytes=0-1,4-5"), Some("lines=1-")] {
+            let (headers, all) = range_response(body, *range);
+            assert_eq!(status(&headers), "200");
+            assert_eq!(content_range(&headers), None);
+            assert_eq!(all, body);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The capsule protocol, which carries messages in the body of an extended CONNECT
+// once it has been accepted.  Capsules are a type, a length, and a payload.  They
+// don't line up with DATA frames, so they are collected here until each is whole.
+
+use crate::{Error, Res};
+use neqo_common::{Decoder, Encoder};
+use std::convert::TryFrom;
+
+/// The type of the DATAGRAM capsule.
+pub const CAPSULE_DATAGRAM: u64 = 0x00;
+
+/// The largest capsule that is accepted.  Capsules are buffered until they are
+/// complete, so this is the most that one can use.
+pub(crate) const MAX_CAPSULE_SIZE: u64 = 1 << 16;
+
+#[derive(Debug, Default)]
+pub(crate) struct CapsuleDecoder {
+    buf: Vec<u8>,
+}
+
+impl CapsuleDecoder {
+    /// Add more of the body.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Take the next capsule as `(type, payload)`, if all of it has arrived.
+    /// # Errors
+    /// `CapsuleTooLarge` if the capsule is longer than `MAX_CAPSULE_SIZE`.
+    pub fn next_capsule(&mut self) -> Res<Option<(u64, Vec<u8>)>> {
+        let mut dec = Decoder::from(&self.buf[..]);
+        let capsule_type = match dec.decode_varint() {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        let len = match dec.decode_varint() {
+            Some(l) if l > MAX_CAPSULE_SIZE => return Err(Error::CapsuleTooLarge),
+            Some(l) => usize::try_from(l).or(Err(Error::CapsuleTooLarge))?,
+            None => return Ok(None),
+        };
+        let payload = match dec.decode(len) {
+            Some(p) => p.to_vec(),
+            None => return Ok(None),
+        };
+        let used = self.buf.len() - dec.remaining();
+        self.buf.drain(..used);
+        Ok(Some((capsule_type, payload)))
+    }
+
+    /// Check that the body ended between capsules.
+    /// # Errors
+    /// `InvalidCapsule` if part of a capsule is left over.
+    pub fn finish(&self) -> Res<()> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidCapsule)
+        }
+    }
+}
+
+/// Encode a capsule.
+pub(crate) fn encode_capsule(enc: &mut Encoder, capsule_type: u64, payload: &[u8]) {
+    enc.encode_varint(capsule_type);
+    enc.encode_vvec(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_capsule, CapsuleDecoder, CAPSULE_DATAGRAM, MAX_CAPSULE_SIZE};
+    use crate::Error;
+    use neqo_common::Encoder;
+
+    #[test]
+    fn split_anywhere() {
+        let mut enc = Encoder::default();
+        encode_capsule(&mut enc, CAPSULE_DATAGRAM, &[1, 2, 3]);
+        encode_capsule(&mut enc, 0x1234, &[]);
+        encode_capsule(&mut enc, 0x3f_ffff, &[4; 100]);
+
+        for split in 0..enc.len() {
+            let mut d = CapsuleDecoder::default();
+            let mut capsules = Vec::new();
+            d.push(&enc[..split]);
+            while let Some(c) = d.next_capsule().unwrap() {
+                capsules.push(c);
+            }
+            d.push(&enc[split..]);
+            while let Some(c) = d.next_capsule().unwrap() {
+                capsules.push(c);
+            }
+            assert_eq!(
+                capsules,
+                vec![
+                    (CAPSULE_DATAGRAM, vec![1, 2, 3]),
+                    (0x1234, Vec::new()),
+                    (0x3f_ffff, vec![4; 100]),
+                ]
+            );
+            assert_eq!(d.finish(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let mut enc = Encoder::default();
+        encode_capsule(&mut enc, CAPSULE_DATAGRAM, &[1, 2, 3]);
+        for len in 1..enc.len() {
+            let mut d = CapsuleDecoder::default();
+            d.push(&enc[..len]);
+            assert_eq!(d.next_capsule(), Ok(None));
+            assert_eq!(d.finish(), Err(Error::InvalidCapsule));
+        }
+    }
+
+    #[test]
+    fn too_large() {
+        let mut enc = Encoder::default();
+        enc.encode_varint(CAPSULE_DATAGRAM);
+        enc.encode_varint(MAX_CAPSULE_SIZE + 1);
+        let mut d = CapsuleDecoder::default();
+        d.push(&enc);
+        assert_eq!(d.next_capsule(), Err(Error::CapsuleTooLarge));
+    }
+}
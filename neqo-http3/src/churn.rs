@@ -0,0 +1,127 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Accounting for the streams that a peer opens, so that a peer that opens and
+// resets streams as fast as it can is noticed and can be stopped.
+
+use crate::{Error, Res};
+use std::time::{Duration, Instant};
+
+/// The period that the stream rate limit applies to.
+const RATE_PERIOD: Duration = Duration::from_secs(1);
+
+/// The streams that a peer has opened on a connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChurnStats {
+    /// Every stream that the peer opened.
+    pub opened: u64,
+    /// The streams that the peer reset before their headers arrived, or before
+    /// the type of a unidirectional stream did.  These were no use to anyone.
+    pub churned: u64,
+}
+
+impl ChurnStats {
+    pub(crate) fn add(&mut self, other: &Self) {
+        self.opened += other.opened;
+        self.churned += other.churned;
+    }
+}
+
+/// Counts the streams that a peer opens and limits how quickly it can open them.
+#[derive(Debug, Default)]
+pub(crate) struct StreamChurn {
+    stats: ChurnStats,
+    rate_limit: Option<u64>,
+    /// When the current period started and how many streams were opened since.
+    period: Option<(Instant, u64)>,
+}
+
+impl StreamChurn {
+    /// Allow the peer to open at most `limit` streams each second, or any number
+    /// if `limit` is `None`.
+    pub fn set_rate_limit(&mut self, limit: Option<u64>) {
+        self.rate_limit = limit;
+    }
+
+    /// Count a stream that the peer opened.
+    /// # Errors
+    /// `HttpExcessiveLoad` if the peer has opened more streams this second than
+    /// the rate limit allows.
+    pub fn opened(&mut self, now: Instant) -> Res<()> {
+        self.stats.opened += 1;
+        let limit = match self.rate_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let count = match &mut self.period {
+            Some((start, count)) if now < *start + RATE_PERIOD => {
+                *count += 1;
+                *count
+            }
+            _ => {
+                self.period = Some((now, 1));
+                1
+            }
+        };
+        if count > limit {
+            Err(Error::HttpExcessiveLoad)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Count a stream that the peer reset before it could be used.
+    pub fn churned(&mut self) {
+        self.stats.churned += 1;
+    }
+
+    pub fn stats(&self) -> ChurnStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChurnStats, StreamChurn, RATE_PERIOD};
+    use crate::Error;
+    use std::time::Duration;
+    use test_fixture::now;
+
+    #[test]
+    fn no_limit() {
+        let mut churn = StreamChurn::default();
+        for _ in 0..1000 {
+            assert_eq!(churn.opened(now()), Ok(()));
+        }
+        churn.churned();
+        assert_eq!(
+            churn.stats(),
+            ChurnStats {
+                opened: 1000,
+                churned: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut churn = StreamChurn::default();
+        churn.set_rate_limit(Some(3));
+        for _ in 0..3 {
+            assert_eq!(churn.opened(now()), Ok(()));
+        }
+        let later = now() + RATE_PERIOD - Duration::from_millis(1);
+        assert_eq!(churn.opened(later), Err(Error::HttpExcessiveLoad));
+
+        // The count starts again in the next period.
+        let next = now() + RATE_PERIOD;
+        for _ in 0..3 {
+            assert_eq!(churn.opened(next), Ok(()));
+        }
+        assert_eq!(churn.opened(next), Err(Error::HttpExcessiveLoad));
+        assert_eq!(churn.stats().opened, 8);
+    }
+}
@@ -6,17 +6,91 @@
 
 #![allow(clippy::module_name_repetitions)]
 
+use crate::capsule::CAPSULE_DATAGRAM;
 use crate::connection::Http3State;
 use crate::recv_message::RecvMessageEvents;
 use crate::send_message::SendMessageEvents;
-use crate::Header;
+use crate::{Error, Header, HeaderViolations};
 use neqo_common::matches;
-use neqo_transport::{AppError, StreamType};
+use neqo_transport::{
+    AppError, CloseError, CongestionState, EventMask, FlowControlStall, StreamType,
+};
 
-use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
 use std::rc::Rc;
 
+/// How a request ended, see `Http3ClientEvent::RequestComplete`.
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Clone, Copy)]
+pub enum RequestOutcome {
+    /// The whole response has been read.
+    Completed,
+    /// The request failed with an HTTP/3 error code.  If the connection was closed
+    /// with a transport error, this is `H3_REQUEST_INCOMPLETE` and the transport
+    /// error is in `Http3State::Closing`.
+    Failed { error: AppError },
+    /// The server did not process the request, so it is safe to send it again on
+    /// a new connection.
+    Retryable,
+    /// The application reset the request, stopped reading the response or closed
+    /// the connection.
+    Cancelled,
+}
+
+/// Why a request ended before its response was read to the end.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum RequestEnd {
+    /// The server sent RESET_STREAM, or STOP_SENDING with an error other than `H3_NO_ERROR`.
+    PeerReset(AppError),
+    /// The request is at or above the stream ID in a GOAWAY frame.
+    Goaway,
+    /// 0-RTT was rejected, so the server discarded the request.
+    ZeroRttRejected,
+    /// The peer closed the connection, or it failed with a transport error.
+    ConnectionClosed(CloseError),
+    /// The application reset the request or closed the connection.
+    Local,
+    /// The request was reset because the server stopped increasing flow control
+    /// limits, see `Http3Client::set_reset_stalled_requests`.
+    FlowControlStalled,
+}
+
+impl RequestOutcome {
+    /// Map the end of a request onto an outcome.  `response_started` is true if
+    /// response headers were received.  The server has processed such a request, so
+    /// it is not retryable even if the server claims to have rejected it.
+    pub(crate) fn from_end(end: RequestEnd, response_started: bool) -> Self {
+        let rejected = match end {
+            RequestEnd::PeerReset(error) => error == Error::HttpRequestRejected.code(),
+            RequestEnd::Goaway | RequestEnd::ZeroRttRejected | RequestEnd::FlowControlStalled => {
+                true
+            }
+            RequestEnd::ConnectionClosed(_) | RequestEnd::Local => false,
+        };
+        if rejected && !response_started {
+            return Self::Retryable;
+        }
+        match end {
+            RequestEnd::PeerReset(error)
+            | RequestEnd::ConnectionClosed(CloseError::Application(error)) => {
+                Self::Failed { error }
+            }
+            RequestEnd::Goaway | RequestEnd::ZeroRttRejected => Self::Failed {
+                error: Error::HttpRequestRejected.code(),
+            },
+            RequestEnd::ConnectionClosed(CloseError::Transport(_)) => Self::Failed {
+                error: Error::HttpRequestIncomplete.code(),
+            },
+            RequestEnd::FlowControlStalled => Self::Failed {
+                error: Error::HttpRequestCancelled.code(),
+            },
+            RequestEnd::Local => Self::Cancelled,
+        }
+    }
+}
+
+/// The events for a request carry the `user_data` that was given to it with
+/// `RequestOptions::user_data`, or 0.
 #[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Clone)]
 pub enum Http3ClientEvent {
     /// Space available in the buffer for an application write to succeed.
@@ -24,32 +98,141 @@ pub enum Http3ClientEvent {
         stream_id: u64,
         headers: Option<Vec<Header>>,
         fin: bool,
+        user_data: u64,
+    },
+    /// An informational (1xx) response arrived, such as 100 (Continue).  The final
+    /// response follows in a `HeaderReady` event.
+    InterimHeaders {
+        stream_id: u64,
+        headers: Vec<Header>,
+        user_data: u64,
     },
+    /// The response headers broke the rules for HTTP/3 fields and were repaired before
+    /// being delivered, see `HeaderValidation::Lenient`.  This comes just before the
+    /// `HeaderReady` event for the stream.
+    HeaderViolations {
+        stream_id: u64,
+        violations: HeaderViolations,
+        user_data: u64,
+    },
+    /// The trailers of a response have arrived, and can be taken with
+    /// `Http3Client::get_trailers`.  Only the end of the response can follow them.
+    TrailerReady { stream_id: u64, user_data: u64 },
     /// A stream can accept new data.
-    DataWritable { stream_id: u64 },
+    DataWritable { stream_id: u64, user_data: u64 },
     /// New bytes available for reading.
-    DataReadable { stream_id: u64 },
+    DataReadable { stream_id: u64, user_data: u64 },
+    /// A DATAGRAM capsule arrived on an extended CONNECT stream, see `Http3Client::connect`.
+    Datagram {
+        stream_id: u64,
+        payload: Vec<u8>,
+        user_data: u64,
+    },
+    /// A capsule of a type that isn't handled here arrived on an extended CONNECT
+    /// stream.
+    Capsule {
+        stream_id: u64,
+        capsule_type: u64,
+        payload: Vec<u8>,
+        user_data: u64,
+    },
     /// Peer reset the stream.
-    Reset { stream_id: u64, error: AppError },
+    Reset {
+        stream_id: u64,
+        error: AppError,
+        user_data: u64,
+    },
     /// Peer has send STOP_SENDING with error code EarlyResponse, other error will post a reset event.
-    StopSending { stream_id: u64, error: AppError },
+    StopSending {
+        stream_id: u64,
+        error: AppError,
+        user_data: u64,
+    },
     ///A new push stream
     NewPushStream { stream_id: u64 },
+    /// The server promised a push on a request stream, see `Http3Client::set_max_push`.
+    /// The same push can be promised on more than one request, and this is reported
+    /// for each of them, but its response is only delivered once.
+    PushPromise {
+        push_id: u64,
+        request_stream_id: u64,
+        headers: Vec<Header>,
+    },
+    /// The response headers of a push have arrived.
+    PushHeaderReady {
+        push_id: u64,
+        headers: Option<Vec<Header>>,
+        fin: bool,
+    },
+    /// More of the body of a push can be read with `Http3Client::push_read_data`.
+    PushDataReadable { push_id: u64 },
+    /// The server canceled a push or reset its stream.
+    PushCanceled { push_id: u64 },
     /// New stream can be created
     RequestsCreatable,
     /// Cert authentication needed
     AuthenticationNeeded,
-    /// Zero Rtt has been rejected.
+    /// Zero Rtt has been rejected.  The requests that were made are reported as
+    /// `Retryable`, unless `Http3Client::set_replay_0rtt` has them sent again.
     ZeroRttRejected,
     /// Client has received a GOAWAY frame
     GoawayReceived,
+    /// The server's SETTINGS frame has arrived, see `Http3Client::peer_settings`.
+    SettingsReceived,
+    /// The connection has run out of stream IDs, so no more requests can be made on
+    /// it, see `neqo_transport::Connection::set_stream_allowance`.  Requests that
+    /// were already made carry on, but new requests need a new connection.
+    RequestsExhausted,
+    /// A request body is much larger than the peer's connection flow control limit,
+    /// so sending it will be slow.  This is reported once for each stream.  `body_len`
+    /// is how much of the body had been sent plus what was being sent when the limit
+    /// was passed.
+    BodyExceedsFlowControl {
+        stream_id: u64,
+        body_len: u64,
+        max_data: u64,
+        user_data: u64,
+    },
+    /// The congestion controller entered or left a recovery period.  This is
+    /// advisory, see `neqo_transport::ConnectionEvent::CongestionStateChanged`.
+    CongestionStateChanged { state: CongestionState },
+    /// Sending has been blocked on a flow control limit from the server for too
+    /// long, see `neqo_transport::ConnectionEvent::FlowControlStalled`.
+    FlowControlStalled { stall: FlowControlStall },
+    /// A request has ended.  This is reported once for each request, after any
+    /// `Reset` or `StateChange` event that ended it.
+    RequestComplete {
+        stream_id: u64,
+        outcome: RequestOutcome,
+        user_data: u64,
+    },
     /// Connection state change.
     StateChange(Http3State),
 }
 
+impl Http3ClientEvent {
+    /// The kind of event, for filtering.  Events that can't be filtered return `None`.
+    fn kind(&self) -> Option<EventMask> {
+        match self {
+            Self::DataWritable { .. } => Some(EventMask::SEND_STREAM_WRITABLE),
+            Self::DataReadable { .. } | Self::PushDataReadable { .. } => {
+                Some(EventMask::RECV_STREAM_READABLE)
+            }
+            Self::NewPushStream { .. } => Some(EventMask::NEW_STREAM),
+            Self::RequestsCreatable => Some(EventMask::SEND_STREAM_CREATABLE),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct Http3ClientEvents {
     events: Rc<RefCell<VecDeque<Http3ClientEvent>>>,
+    filter: Rc<Cell<EventMask>>,
+    // The `user_data` of requests that haven't been reported as complete.
+    user_data: Rc<RefCell<HashMap<u64, u64>>>,
+    // Trailers that have been reported with `TrailerReady` but not taken yet.
+    trailers: Rc<RefCell<HashMap<u64, Vec<Header>>>>,
 }
 
 impl RecvMessageEvents for Http3ClientEvents {
@@ -59,19 +242,103 @@ impl RecvMessageEvents for Http3ClientEvents {
             stream_id,
             headers,
             fin,
+            user_data: self.user_data(stream_id),
+        });
+    }
+
+    /// Add a new `InterimHeaders` event.
+    fn interim_headers(&self, stream_id: u64, headers: Vec<Header>) {
+        self.insert(Http3ClientEvent::InterimHeaders {
+            stream_id,
+            headers,
+            user_data: self.user_data(stream_id),
+        });
+    }
+
+    /// Add a new `HeaderViolations` event.
+    fn header_violations(&self, stream_id: u64, violations: HeaderViolations) {
+        self.insert(Http3ClientEvent::HeaderViolations {
+            stream_id,
+            violations,
+            user_data: self.user_data(stream_id),
+        });
+    }
+
+    /// Add a new `TrailerReady` event, keeping the trailers until they are taken.
+    fn trailer_ready(&self, stream_id: u64, trailers: Vec<Header>) {
+        self.trailers.borrow_mut().insert(stream_id, trailers);
+        self.insert(Http3ClientEvent::TrailerReady {
+            stream_id,
+            user_data: self.user_data(stream_id),
         });
     }
 
     /// Add a new `DataReadable` event
     fn data_readable(&self, stream_id: u64) {
-        self.insert(Http3ClientEvent::DataReadable { stream_id });
+        self.insert(Http3ClientEvent::DataReadable {
+            stream_id,
+            user_data: self.user_data(stream_id),
+        });
+    }
+
+    /// Add a new `Reset` event.
+    fn reset(&self, stream_id: u64, error: AppError) {
+        self.remove_events_for_stream_id(stream_id);
+        self.insert(Http3ClientEvent::Reset {
+            stream_id,
+            error,
+            user_data: self.user_data(stream_id),
+        });
+    }
+
+    /// Add `Reset` and `RequestComplete` events for a response that broke a local limit.
+    fn local_reset(&self, stream_id: u64, error: AppError) {
+        self.reset(stream_id, error);
+        self.request_complete(stream_id, RequestOutcome::Failed { error });
+    }
+
+    /// Add a `RequestComplete` event for a response that has been read to the end.
+    fn complete(&self, stream_id: u64) {
+        self.request_complete(stream_id, RequestOutcome::Completed);
+    }
+
+    /// Add a `Datagram` or `Capsule` event.
+    fn capsule(&self, stream_id: u64, capsule_type: u64, payload: Vec<u8>) {
+        let user_data = self.user_data(stream_id);
+        if capsule_type == CAPSULE_DATAGRAM {
+            self.insert(Http3ClientEvent::Datagram {
+                stream_id,
+                payload,
+                user_data,
+            });
+        } else {
+            self.insert(Http3ClientEvent::Capsule {
+                stream_id,
+                capsule_type,
+                payload,
+                user_data,
+            });
+        }
     }
 }
 
 impl SendMessageEvents for Http3ClientEvents {
     /// Add a new `DataWritable` event.
     fn data_writable(&self, stream_id: u64) {
-        self.insert(Http3ClientEvent::DataWritable { stream_id });
+        self.insert(Http3ClientEvent::DataWritable {
+            stream_id,
+            user_data: self.user_data(stream_id),
+        });
+    }
+
+    /// Add a new `BodyExceedsFlowControl` event.
+    fn body_exceeds_flow_control(&self, stream_id: u64, body_len: u64, max_data: u64) {
+        self.insert(Http3ClientEvent::BodyExceedsFlowControl {
+            stream_id,
+            body_len,
+            max_data,
+            user_data: self.user_data(stream_id),
+        });
     }
 }
 
@@ -81,15 +348,52 @@ impl Http3ClientEvents {
         // Remove DataWritable event if any.
         self.remove(|evt| {
             matches!(evt, Http3ClientEvent::DataWritable {
-                    stream_id: x } if *x == stream_id)
+                    stream_id: x, .. } if *x == stream_id)
+        });
+        self.insert(Http3ClientEvent::StopSending {
+            stream_id,
+            error,
+            user_data: self.user_data(stream_id),
+        });
+    }
+
+    /// Add a new `PushPromise` event.
+    pub(crate) fn push_promise(&self, push_id: u64, request_stream_id: u64, headers: Vec<Header>) {
+        self.insert(Http3ClientEvent::PushPromise {
+            push_id,
+            request_stream_id,
+            headers,
+        });
+    }
+
+    /// Add a new `PushHeaderReady` event.
+    pub(crate) fn push_header_ready(&self, push_id: u64, headers: Option<Vec<Header>>, fin: bool) {
+        self.insert(Http3ClientEvent::PushHeaderReady {
+            push_id,
+            headers,
+            fin,
         });
-        self.insert(Http3ClientEvent::StopSending { stream_id, error });
     }
 
-    // TODO: implement push.
-    // pub fn new_push_stream(&self, stream_id: u64) {
-    //     self.insert(Http3ClientEvent::NewPushStream { stream_id });
-    // }
+    /// Add a new `PushDataReadable` event.
+    pub(crate) fn push_data_readable(&self, push_id: u64) {
+        self.insert(Http3ClientEvent::PushDataReadable { push_id });
+    }
+
+    /// Add a new `PushCanceled` event.
+    pub(crate) fn push_canceled(&self, push_id: u64) {
+        self.remove_events_for_push_id(push_id);
+        self.insert(Http3ClientEvent::PushCanceled { push_id });
+    }
+
+    /// Remove the events for the response of a push.
+    pub(crate) fn remove_events_for_push_id(&self, push_id: u64) {
+        self.remove(|evt| {
+            matches!(evt,
+                Http3ClientEvent::PushHeaderReady { push_id: x, .. }
+                | Http3ClientEvent::PushDataReadable { push_id: x } if *x == push_id)
+        });
+    }
 
     /// Add a new `RequestCreatable` event
     pub(crate) fn new_requests_creatable(&self, stream_type: StreamType) {
@@ -108,12 +412,63 @@ impl Http3ClientEvents {
         self.insert(Http3ClientEvent::ZeroRttRejected);
     }
 
+    /// Add a new `SettingsReceived` event.
+    pub(crate) fn settings_received(&self) {
+        self.insert(Http3ClientEvent::SettingsReceived);
+    }
+
     /// Add a new `GoawayReceived` event.
     pub(crate) fn goaway_received(&self) {
         self.remove(|evt| matches!(evt, Http3ClientEvent::RequestsCreatable));
         self.insert(Http3ClientEvent::GoawayReceived);
     }
 
+    /// Add a new `RequestsExhausted` event.
+    pub(crate) fn requests_exhausted(&self) {
+        self.remove(|evt| matches!(evt, Http3ClientEvent::RequestsCreatable));
+        self.insert(Http3ClientEvent::RequestsExhausted);
+    }
+
+    /// Add a new `CongestionStateChanged` event.
+    pub(crate) fn congestion_state_changed(&self, state: CongestionState) {
+        self.insert(Http3ClientEvent::CongestionStateChanged { state });
+    }
+
+    /// Add a new `FlowControlStalled` event.
+    pub(crate) fn flow_control_stalled(&self, stall: FlowControlStall) {
+        self.insert(Http3ClientEvent::FlowControlStalled { stall });
+    }
+
+    /// Add a new `RequestComplete` event.
+    pub(crate) fn request_complete(&self, stream_id: u64, outcome: RequestOutcome) {
+        let user_data = self.user_data.borrow_mut().remove(&stream_id).unwrap_or(0);
+        self.insert(Http3ClientEvent::RequestComplete {
+            stream_id,
+            outcome,
+            user_data,
+        });
+    }
+
+    /// Give the events for the request on `stream_id` this `user_data`.
+    pub(crate) fn set_user_data(&self, stream_id: u64, user_data: u64) {
+        if user_data != 0 {
+            self.user_data.borrow_mut().insert(stream_id, user_data);
+        }
+    }
+
+    /// Take the trailers that a `TrailerReady` event reported.
+    pub(crate) fn take_trailers(&self, stream_id: u64) -> Option<Vec<Header>> {
+        self.trailers.borrow_mut().remove(&stream_id)
+    }
+
+    fn user_data(&self, stream_id: u64) -> u64 {
+        self.user_data
+            .borrow()
+            .get(&stream_id)
+            .copied()
+            .unwrap_or(0)
+    }
+
     /// Take all events currently in the queue.
     pub(crate) fn events(&self) -> impl Iterator<Item = Http3ClientEvent> {
         self.events.replace(VecDeque::new()).into_iter()
@@ -129,7 +484,19 @@ impl Http3ClientEvents {
         self.events.borrow_mut().pop_front()
     }
 
+    /// Only queue events of the kinds in `filter`.  Queued events that
+    /// no longer pass are dropped.
+    pub(crate) fn set_filter(&self, filter: EventMask) {
+        self.filter.set(filter);
+        self.remove(|evt| evt.kind().map_or(false, |k| !filter.contains(k)));
+    }
+
     fn insert(&self, event: Http3ClientEvent) {
+        if let Some(kind) = event.kind() {
+            if !self.filter.get().contains(kind) {
+                return;
+            }
+        }
         self.events.borrow_mut().push_back(event);
     }
 
@@ -140,12 +507,6 @@ impl Http3ClientEvents {
         self.events.borrow_mut().retain(|evt| !f(evt))
     }
 
-    /// Add a new `Reset` event.
-    pub(crate) fn reset(&self, stream_id: u64, error: AppError) {
-        self.remove_events_for_stream_id(stream_id);
-        self.insert(Http3ClientEvent::Reset { stream_id, error });
-    }
-
     /// Add a new `StateChange` event.
     pub(crate) fn connection_state_change(&self, state: Http3State) {
         // If closing, existing events no longer relevant.
@@ -156,16 +517,40 @@ impl Http3ClientEvents {
         self.insert(Http3ClientEvent::StateChange(state));
     }
 
+    /// Remove the events for reading a response on a stream.
+    pub(crate) fn remove_recv_events_for_stream_id(&self, stream_id: u64) {
+        self.trailers.borrow_mut().remove(&stream_id);
+        self.remove(|evt| {
+            matches!(evt,
+                Http3ClientEvent::HeaderReady { stream_id: x, .. }
+                | Http3ClientEvent::InterimHeaders { stream_id: x, .. }
+                | Http3ClientEvent::HeaderViolations { stream_id: x, .. }
+                | Http3ClientEvent::TrailerReady { stream_id: x, .. }
+                | Http3ClientEvent::DataReadable { stream_id: x, .. }
+                | Http3ClientEvent::Datagram { stream_id: x, .. }
+                | Http3ClientEvent::Capsule { stream_id: x, .. }
+                | Http3ClientEvent::BodyExceedsFlowControl { stream_id: x, .. } if *x == stream_id)
+        });
+    }
+
     /// Remove all events for a stream
     pub(crate) fn remove_events_for_stream_id(&self, stream_id: u64) {
+        self.trailers.borrow_mut().remove(&stream_id);
         self.remove(|evt| {
             matches!(evt,
                 Http3ClientEvent::HeaderReady { stream_id: x, .. }
-                | Http3ClientEvent::DataWritable { stream_id: x }
-                | Http3ClientEvent::DataReadable { stream_id: x }
+                | Http3ClientEvent::InterimHeaders { stream_id: x, .. }
+                | Http3ClientEvent::HeaderViolations { stream_id: x, .. }
+                | Http3ClientEvent::TrailerReady { stream_id: x, .. }
+                | Http3ClientEvent::DataWritable { stream_id: x, .. }
+                | Http3ClientEvent::DataReadable { stream_id: x, .. }
+                | Http3ClientEvent::Datagram { stream_id: x, .. }
+                | Http3ClientEvent::Capsule { stream_id: x, .. }
                 | Http3ClientEvent::NewPushStream { stream_id: x }
                 | Http3ClientEvent::Reset { stream_id: x, .. }
-                | Http3ClientEvent::StopSending { stream_id: x, .. } if *x == stream_id)
+                | Http3ClientEvent::StopSending { stream_id: x, .. }
+                | Http3ClientEvent::BodyExceedsFlowControl { stream_id: x, .. }
+                | Http3ClientEvent::RequestComplete { stream_id: x, .. } if *x == stream_id)
         });
     }
 }
@@ -6,26 +6,114 @@
 
 #![allow(clippy::module_name_repetitions)]
 
+use crate::churn::{ChurnStats, StreamChurn};
 use crate::control_stream_local::{ControlStreamLocal, HTTP3_UNI_STREAM_TYPE_CONTROL};
 use crate::control_stream_remote::ControlStreamRemote;
+use crate::efficiency::{EfficiencyStats, QpackWriter, StreamBytes};
 use crate::hframe::HFrame;
-use crate::hsettings_frame::{HSetting, HSettingType, HSettings};
+use crate::hsettings_frame::{check_extra_setting, HSetting, HSettingType, HSettings, Settings};
+#[cfg(feature = "qlog")]
+use crate::qlog;
 use crate::recv_message::RecvMessage;
 use crate::send_message::SendMessage;
 use crate::stream_type_reader::NewStreamTypeReader;
-use neqo_common::{matches, qdebug, qerror, qinfo, qtrace, qwarn};
+use crate::{Header, HeaderValidation, Priority, PriorityTable};
+use neqo_common::{matches, qdebug, qerror, qinfo, qtrace, qwarn, Role};
 use neqo_qpack::decoder::{QPackDecoder, QPACK_UNI_STREAM_TYPE_DECODER};
 use neqo_qpack::encoder::{QPackEncoder, QPACK_UNI_STREAM_TYPE_ENCODER};
 use neqo_qpack::QpackSettings;
-use neqo_transport::{AppError, CloseError, Connection, State, StreamType};
-use std::collections::{BTreeSet, HashMap};
+use neqo_transport::tparams::{self, TransportParameter, TransportParameterId};
+use neqo_transport::{
+    AppError, CloseError, Connection, ConnectionParameters, State, StreamId, StreamType,
+    TransmissionPriority,
+};
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::fmt::Debug;
 use std::mem;
+use std::time::{Duration, Instant};
 
 use crate::{Error, Res};
 
-const HTTP3_UNI_STREAM_TYPE_PUSH: u64 = 0x1;
+pub(crate) const HTTP3_UNI_STREAM_TYPE_PUSH: u64 = 0x1;
 const QPACK_TABLE_SIZE_LIMIT: u64 = 1 << 30;
+/// The dynamic table is only used if the peer lets the encoder stream carry at
+/// least this much at the start.  With less, most inserts would block on flow
+/// control and the headers that use them would be delayed.
+const QPACK_MIN_ENCODER_STREAM_DATA: u64 = 1024;
+/// Connection flow control credit that requests and responses leave for the
+/// control and QPACK streams, so that frames like GOAWAY and PRIORITY_UPDATE
+/// aren't stuck behind large bodies.
+const CRITICAL_STREAM_RESERVE: u64 = 512;
+/// How many streams that ended are remembered, so that reading one again can
+/// say why it is gone.
+const MAX_ENDED_STREAMS: usize = 32;
+
+/// The transport parameters that an HTTP/3 endpoint sends.  These start from
+/// `ConnectionParameters::default()`, but with different receive windows: responses
+/// and requests are large, while the control, QPACK and push streams that arrive on
+/// unidirectional streams need far less.
+///
+/// ```
+/// # use neqo_http3::Http3Parameters;
+/// let params = Http3Parameters::default();
+/// let conn_params = params.get_connection_parameters().clone().max_streams_bidi(10);
+/// let params = params.connection_parameters(conn_params);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Http3Parameters {
+    conn_params: ConnectionParameters,
+}
+
+impl Default for Http3Parameters {
+    fn default() -> Self {
+        Self {
+            conn_params: ConnectionParameters::default()
+                .max_stream_data_bidi_local(1 << 20)
+                .max_stream_data_bidi_remote(1 << 20)
+                .max_stream_data_uni(1 << 16)
+                .max_data(10 << 20),
+        }
+    }
+}
+
+impl Http3Parameters {
+    /// Replace the transport parameters.  Start from `get_connection_parameters`
+    /// to keep the HTTP/3 receive windows.
+    #[must_use]
+    pub fn connection_parameters(mut self, conn_params: ConnectionParameters) -> Self {
+        self.conn_params = conn_params;
+        self
+    }
+
+    #[must_use]
+    pub fn get_connection_parameters(&self) -> &ConnectionParameters {
+        &self.conn_params
+    }
+
+    /// The transport parameters that carry the receive windows, for a connection
+    /// that was made without these parameters.
+    pub(crate) fn transport_parameters(&self) -> [(TransportParameterId, TransportParameter); 4] {
+        let p = &self.conn_params;
+        [
+            (
+                tparams::INITIAL_MAX_STREAM_DATA_BIDI_LOCAL,
+                TransportParameter::Integer(p.get_max_stream_data_bidi_local()),
+            ),
+            (
+                tparams::INITIAL_MAX_STREAM_DATA_BIDI_REMOTE,
+                TransportParameter::Integer(p.get_max_stream_data_bidi_remote()),
+            ),
+            (
+                tparams::INITIAL_MAX_STREAM_DATA_UNI,
+                TransportParameter::Integer(p.get_max_stream_data_uni()),
+            ),
+            (
+                tparams::INITIAL_MAX_DATA,
+                TransportParameter::Integer(p.get_max_data()),
+            ),
+        ]
+    }
+}
 
 pub(crate) enum HandleReadableOutput {
     NoOutput,
@@ -57,10 +145,62 @@ impl Http3State {
     }
 }
 
+/// The size of a field section as SETTINGS_MAX_HEADER_LIST_SIZE counts it: the length of
+/// each name and value plus 32 for each field.
+pub(crate) fn header_list_size(headers: &[Header]) -> u64 {
+    headers
+        .iter()
+        .map(|(name, value)| (name.len() + value.len() + 32) as u64)
+        .sum()
+}
+
+/// The code that a stream is reset with for an error in a message that only
+/// affects that stream.  `RecvMessage` has already reported the stream as reset.
+/// Other errors close the connection, so this returns `None` for them.
+pub(crate) fn stream_error_code(error: &Error) -> Option<AppError> {
+    match error {
+        Error::HeaderListTooLarge | Error::CapsuleTooLarge => Some(Error::HttpExcessiveLoad.code()),
+        Error::InvalidHeader => Some(Error::HttpMessageError.code()),
+        Error::InvalidCapsule => Some(Error::HttpGeneralProtocol.code()),
+        Error::FrameAfterTrailers => Some(Error::HttpFrameUnexpected.code()),
+        _ => None,
+    }
+}
+
+/// What a stream that the peer opened is for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum NewStreamKind {
+    /// A request stream, which only a client can open.
+    Request,
+    /// A unidirectional stream, which says what it is in its first bytes.
+    Unidirectional,
+}
+
+/// Sort out a stream that the transport reports as new.  Only clients can open
+/// bidirectional streams in HTTP/3, so one from a server is an error, as is a
+/// stream that should have been opened by this endpoint.
+pub(crate) fn classify_new_stream(role: Role, stream_id: u64) -> Res<NewStreamKind> {
+    let id = StreamId::from(stream_id);
+    if id.is_self_initiated(role) {
+        Err(Error::HttpStreamCreation)
+    } else if id.is_uni() {
+        Ok(NewStreamKind::Unidirectional)
+    } else if id.is_client_initiated() {
+        Ok(NewStreamKind::Request)
+    } else {
+        Err(Error::HttpStreamCreation)
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct Http3Connection {
     pub state: Http3State,
     local_qpack_settings: QpackSettings,
+    local_max_header_list_size: Option<u64>,
+    local_max_push_id: Option<u64>,
+    /// Settings that the application added, which are sent after ours.
+    extra_settings: Vec<(u64, u64)>,
+    header_validation: Option<HeaderValidation>,
     control_stream_local: ControlStreamLocal,
     control_stream_remote: ControlStreamRemote,
     new_streams: HashMap<u64, NewStreamTypeReader>,
@@ -70,6 +210,12 @@ pub(crate) struct Http3Connection {
     streams_have_data_to_send: BTreeSet<u64>,
     pub send_streams: HashMap<u64, SendMessage>,
     pub recv_streams: HashMap<u64, RecvMessage>,
+    pub priorities: PriorityTable,
+    /// The stream data that has been given to QUIC.
+    pub stream_bytes: StreamBytes,
+    churn: StreamChurn,
+    /// Streams that ended recently, with the error code if the peer reset them.
+    ended_streams: VecDeque<(u64, Option<AppError>)>,
 }
 
 impl ::std::fmt::Display for Http3Connection {
@@ -89,6 +235,10 @@ impl Http3Connection {
         Self {
             state: Http3State::Initializing,
             local_qpack_settings,
+            local_max_header_list_size: None,
+            local_max_push_id: None,
+            extra_settings: Vec::new(),
+            header_validation: None,
             control_stream_local: ControlStreamLocal::default(),
             control_stream_remote: ControlStreamRemote::new(),
             new_streams: HashMap::new(),
@@ -98,40 +248,161 @@ impl Http3Connection {
             streams_have_data_to_send: BTreeSet::new(),
             send_streams: HashMap::new(),
             recv_streams: HashMap::new(),
+            priorities: PriorityTable::default(),
+            stream_bytes: StreamBytes::default(),
+            churn: StreamChurn::default(),
+            ended_streams: VecDeque::new(),
         }
     }
 
     fn initialize_http3_connection(&mut self, conn: &mut Connection) -> Res<()> {
         qinfo!([self], "Initialize the http3 connection.");
+        conn.set_critical_reserve(CRITICAL_STREAM_RESERVE);
         self.control_stream_local.create(conn)?;
 
-        self.send_settings();
+        self.send_settings(conn)?;
+        if let Some(push_id) = self.local_max_push_id {
+            self.control_stream_local
+                .queue_frame(conn, &HFrame::MaxPushId { push_id })?;
+        }
         self.create_qpack_streams(conn)?;
         Ok(())
     }
 
-    fn send_settings(&mut self) {
+    fn send_settings(&mut self, conn: &mut Connection) -> Res<()> {
         qdebug!([self], "Send settings.");
-        self.control_stream_local.queue_frame(&HFrame::Settings {
-            settings: HSettings::new(&[
-                HSetting {
-                    setting_type: HSettingType::MaxTableCapacity,
-                    value: self.qpack_decoder.get_max_table_size(),
-                },
-                HSetting {
-                    setting_type: HSettingType::BlockedStreams,
-                    value: self.qpack_decoder.get_blocked_streams().into(),
-                },
-            ]),
-        });
+        let mut settings = vec![
+            HSetting {
+                setting_type: HSettingType::MaxTableCapacity,
+                value: self.qpack_decoder.get_max_table_size(),
+            },
+            HSetting {
+                setting_type: HSettingType::BlockedStreams,
+                value: self.qpack_decoder.get_blocked_streams().into(),
+            },
+        ];
+        if let Some(limit) = self.local_max_header_list_size {
+            settings.push(HSetting {
+                setting_type: HSettingType::MaxHeaderListSize,
+                value: limit,
+            });
+        }
+        settings.extend(
+            self.extra_settings
+                .iter()
+                .map(|(id, value)| HSetting::new(HSettingType::Other(*id), *value)),
+        );
+        self.control_stream_local.queue_frame(
+            conn,
+            &HFrame::Settings {
+                settings: HSettings::new(&settings),
+            },
+        )
+    }
+
+    /// Set the largest field section that the peer can send, which is sent in
+    /// SETTINGS_MAX_HEADER_LIST_SIZE.  A stream with larger headers is reset with
+    /// `HttpExcessiveLoad`.  This has to be set before settings are sent.
+    pub fn set_max_header_list_size(&mut self, limit: u64) -> Res<()> {
+        if self.state != Http3State::Initializing {
+            return Err(Error::AlreadyInitialized);
+        }
+        self.local_max_header_list_size = Some(limit);
+        Ok(())
+    }
+
+    /// Set the largest push ID that the peer can use, which is sent in MAX_PUSH_ID
+    /// after SETTINGS.  This has to be set before settings are sent.
+    pub fn set_max_push_id(&mut self, push_id: u64) -> Res<()> {
+        if self.state != Http3State::Initializing {
+            return Err(Error::AlreadyInitialized);
+        }
+        self.local_max_push_id = Some(push_id);
+        Ok(())
+    }
+
+    /// Add a setting with identifier `id` to those that are sent to the peer.  This
+    /// can be any setting that isn't used here, including a GREASE setting, and has
+    /// to be added before settings are sent.
+    pub fn add_setting(&mut self, id: u64, value: u64) -> Res<()> {
+        if self.state != Http3State::Initializing {
+            return Err(Error::AlreadyInitialized);
+        }
+        check_extra_setting(&self.extra_settings, id)?;
+        self.extra_settings.push((id, value));
+        Ok(())
+    }
+
+    /// Limit the number of streams that the peer can open each second, or remove the
+    /// limit with `None`.  Opening more closes the connection with `HttpExcessiveLoad`.
+    pub fn set_stream_rate_limit(&mut self, limit: Option<u64>) {
+        self.churn.set_rate_limit(limit);
+    }
+
+    /// This is called when the peer opens a stream.
+    /// # Errors
+    /// `HttpExcessiveLoad` if the peer opens streams faster than the rate limit.
+    pub fn stream_opened(&mut self, now: Instant) -> Res<()> {
+        self.churn.opened(now)
+    }
+
+    /// The streams that the peer opened and the ones that it reset before they
+    /// could be used.
+    pub fn churn_stats(&self) -> ChurnStats {
+        self.churn.stats()
+    }
+
+    /// The streams that are being kept track of, other than the critical ones.
+    #[cfg(test)]
+    pub fn stream_count(&self) -> usize {
+        self.new_streams.len() + self.recv_streams.len() + self.send_streams.len()
+    }
+
+    /// Check the headers of messages on streams that are added to this connection
+    /// from now on.
+    pub fn set_header_validation(&mut self, policy: HeaderValidation) {
+        self.header_validation = Some(policy);
+    }
+
+    /// Set the receive windows of `conn` from `params`.  This has to be done before
+    /// the connection starts.  The other transport parameters are fixed when the
+    /// connection is made.
+    pub fn set_parameters(&self, conn: &Connection, params: &Http3Parameters) -> Res<()> {
+        if self.state != Http3State::Initializing || *conn.state() != State::Init {
+            return Err(Error::AlreadyInitialized);
+        }
+        for (tp, value) in &params.transport_parameters() {
+            conn.set_local_tparam(*tp, value.clone())?;
+        }
+        Ok(())
     }
 
     fn create_qpack_streams(&mut self, conn: &mut Connection) -> Res<()> {
         qdebug!([self], "create_qpack_streams.");
-        self.qpack_encoder
-            .add_send_stream(conn.stream_create(StreamType::UniDi)?);
-        self.qpack_decoder
-            .add_send_stream(conn.stream_create(StreamType::UniDi)?);
+        // Like the control stream, these don't depend on the peer's stream limit, so
+        // they are always the first three unidirectional streams.
+        // A blocked encoder stream can block every request, so these and the control
+        // stream are sent ahead of everything else.
+        let encoder_stream_id = conn.stream_create_pending(StreamType::UniDi)?.as_u64();
+        conn.stream_priority(encoder_stream_id, TransmissionPriority::Critical)?;
+        self.qpack_encoder.add_send_stream(encoder_stream_id);
+        #[cfg(feature = "qlog")]
+        qlog::h3_stream_type_set(
+            conn.qlog_mut(),
+            encoder_stream_id,
+            true,
+            QPACK_UNI_STREAM_TYPE_ENCODER,
+        )?;
+        let decoder_stream_id = conn.stream_create_pending(StreamType::UniDi)?.as_u64();
+        conn.stream_priority(decoder_stream_id, TransmissionPriority::Critical)?;
+        self.qpack_decoder.add_send_stream(decoder_stream_id);
+        #[cfg(feature = "qlog")]
+        qlog::h3_stream_type_set(
+            conn.qlog_mut(),
+            decoder_stream_id,
+            true,
+            QPACK_UNI_STREAM_TYPE_DECODER,
+        )?;
         Ok(())
     }
 
@@ -146,15 +417,15 @@ impl Http3Connection {
     }
 
     /// Call `send` for all streams that need to send data.
-    pub fn process_sending(&mut self, conn: &mut Connection) -> Res<()> {
+    pub fn process_sending(&mut self, conn: &mut Connection, now: Instant) -> Res<()> {
         // check if control stream has data to send.
-        self.control_stream_local.send(conn)?;
+        self.stream_bytes.h3_framing += self.control_stream_local.send(conn, now)? as u64;
 
         let to_send = mem::replace(&mut self.streams_have_data_to_send, BTreeSet::new());
         for stream_id in to_send {
             let mut remove = false;
             if let Some(s) = &mut self.send_streams.get_mut(&stream_id) {
-                s.send(conn, &mut self.qpack_encoder)?;
+                s.send(conn, &mut self.qpack_encoder, &mut self.stream_bytes)?;
                 if s.has_data_to_send() {
                     self.streams_have_data_to_send.insert(stream_id);
                 }
@@ -164,16 +435,23 @@ impl Http3Connection {
                 self.send_streams.remove(&stream_id);
             }
         }
-        self.qpack_decoder.send(conn)?;
-        self.qpack_encoder.send(conn)?;
+        self.qpack_decoder
+            .send(&mut QpackWriter::new(conn, &mut self.stream_bytes.qpack))?;
+        self.qpack_encoder
+            .send(&mut QpackWriter::new(conn, &mut self.stream_bytes.qpack))?;
         Ok(())
     }
 
+    /// What the bytes that the connection sent were used for.
+    pub fn efficiency_stats(&self, conn: &Connection) -> EfficiencyStats {
+        EfficiencyStats::new(&self.stream_bytes, &conn.stats().sent_bytes)
+    }
+
     /// We have a resumption token which remembers previous settings. Update the setting.
     pub fn set_0rtt_settings(&mut self, conn: &mut Connection, settings: HSettings) -> Res<()> {
         self.state = Http3State::ZeroRtt;
         self.initialize_http3_connection(conn)?;
-        self.set_qpack_settings(&settings)?;
+        self.set_qpack_settings(conn, &settings)?;
         self.settings_state = Http3RemoteSettingsState::ZeroRtt(settings);
         Ok(())
     }
@@ -187,6 +465,39 @@ impl Http3Connection {
         }
     }
 
+    /// The settings that the peer sent.  This is `None` until its SETTINGS frame
+    /// arrives, even if settings were remembered for 0-RTT.
+    pub fn peer_settings(&self) -> Option<Settings> {
+        if let Http3RemoteSettingsState::Received(settings) = &self.settings_state {
+            Some(Settings::from(settings))
+        } else {
+            None
+        }
+    }
+
+    /// The largest field section the peer will accept, from SETTINGS_MAX_HEADER_LIST_SIZE.
+    /// Until settings arrive (or are remembered for 0-RTT) there is no limit.
+    pub fn peer_max_header_list_size(&self) -> u64 {
+        match &self.settings_state {
+            Http3RemoteSettingsState::ZeroRtt(settings)
+            | Http3RemoteSettingsState::Received(settings) => {
+                settings.get(HSettingType::MaxHeaderListSize)
+            }
+            Http3RemoteSettingsState::NotReceived => u64::max_value(),
+        }
+    }
+
+    /// Whether the peer accepts extended CONNECT, from SETTINGS_ENABLE_CONNECT_PROTOCOL.
+    pub fn peer_enable_connect_protocol(&self) -> bool {
+        match &self.settings_state {
+            Http3RemoteSettingsState::ZeroRtt(settings)
+            | Http3RemoteSettingsState::Received(settings) => {
+                settings.get(HSettingType::EnableConnectProtocol) == 1
+            }
+            Http3RemoteSettingsState::NotReceived => false,
+        }
+    }
+
     /// This function adds a new unidi stream and try to read its type. `Http3Connection` can handle
     /// a Http3 Control stream, Qpack streams and an unknown stream, but it cannot handle a Push stream.
     /// If a Push stream has been discovered, return true and let the `Http3Client`/`Server` handle it.
@@ -246,7 +557,7 @@ impl Http3Connection {
             while self.control_stream_remote.frame_reader_done()
                 || self.control_stream_remote.recvd_fin()
             {
-                if let Some(f) = self.handle_control_frame()? {
+                if let Some(f) = self.handle_control_frame(conn)? {
                     control_frames.push(f);
                 }
                 self.control_stream_remote
@@ -329,9 +640,18 @@ impl Http3Connection {
             app_err
         );
 
+        let untyped = self.new_streams.remove(&stream_id).is_some();
+        let recv_stream = self.remove_recv_stream(stream_id);
+        if recv_stream.is_some() {
+            self.stream_ended(stream_id, Some(app_err));
+        }
+        // A stream that the peer reset before there was anything to use on it.
+        let unused = untyped || matches!(&recv_stream, Some(s) if !s.headers_received());
+        if unused && StreamId::from(stream_id).is_remote_initiated(conn.role()) {
+            self.churn.churned();
+        }
         // We want to execute both statements, therefore we use | instead of ||.
-        let found = self.recv_streams.remove(&stream_id).is_some()
-            | self.send_streams.remove(&stream_id).is_some();
+        let found = recv_stream.is_some() | self.send_streams.remove(&stream_id).is_some();
 
         // close sending side of the transport stream as well. The server may have done
         // it as well, but just to be sure.
@@ -346,6 +666,35 @@ impl Http3Connection {
         }
     }
 
+    /// Remember that a stream ended, because it was read to the end or because
+    /// the peer reset it with `reset`.
+    pub fn stream_ended(&mut self, stream_id: u64, reset: Option<AppError>) {
+        if self.ended_streams.len() >= MAX_ENDED_STREAMS {
+            self.ended_streams.pop_front();
+        }
+        self.ended_streams.push_back((stream_id, reset));
+    }
+
+    /// The error for reading from a stream that isn't in `recv_streams`.
+    pub fn missing_stream_error(&self, stream_id: u64) -> Error {
+        match self.ended_streams.iter().find(|(id, _)| *id == stream_id) {
+            Some((_, None)) => Error::AlreadyRead,
+            Some((_, Some(code))) => Error::StreamReset(*code),
+            None => Error::UnknownStream,
+        }
+    }
+
+    /// Remove the receiving side of a stream.  QPACK only needs to hear about it if
+    /// a header block is still waiting for the encoder; a stream that never got
+    /// as far as a header block costs nothing there.
+    fn remove_recv_stream(&mut self, stream_id: u64) -> Option<RecvMessage> {
+        let recv_stream = self.recv_streams.remove(&stream_id)?;
+        if recv_stream.header_block_blocked() {
+            self.qpack_decoder.cancel_stream(stream_id);
+        }
+        Some(recv_stream)
+    }
+
     /// This is called when `neqo_transport::Connection` state has been change to take proper actions in
     /// the HTTP3 layer.
     pub fn handle_state_change(&mut self, conn: &mut Connection, state: &State) -> Res<bool> {
@@ -367,6 +716,8 @@ impl Http3Connection {
                     Ok(false)
                 } else {
                     self.state = Http3State::Closing(error.clone().into());
+                    #[cfg(feature = "qlog")]
+                    self.log_efficiency(conn)?;
                     Ok(true)
                 }
             }
@@ -374,6 +725,12 @@ impl Http3Connection {
                 if matches!(self.state, Http3State::Closed(_)) {
                     Ok(false)
                 } else {
+                    #[cfg(feature = "qlog")]
+                    {
+                        if !matches!(self.state, Http3State::Closing(_)) {
+                            self.log_efficiency(conn)?;
+                        }
+                    }
                     self.state = Http3State::Closed(error.clone().into());
                     Ok(true)
                 }
@@ -382,6 +739,14 @@ impl Http3Connection {
         }
     }
 
+    /// Summarize what was sent once the connection closes.  Anything sent after
+    /// that is a CONNECTION_CLOSE, which `efficiency_stats` still counts.
+    #[cfg(feature = "qlog")]
+    fn log_efficiency(&self, conn: &mut Connection) -> Res<()> {
+        let stats = self.efficiency_stats(conn);
+        qlog::efficiency_summary(conn.qlog_mut(), &stats)
+    }
+
     /// This is called when 0RTT has been reseted to clear `send_streams`, `recv_streams` and settings.
     pub fn handle_zero_rtt_rejected(&mut self) -> Res<()> {
         if self.state == Http3State::ZeroRtt {
@@ -406,21 +771,25 @@ impl Http3Connection {
     fn handle_read_stream(&mut self, conn: &mut Connection, stream_id: u64) -> Res<bool> {
         let label = ::neqo_common::log_subject!(::log::Level::Info, self);
 
-        let r = self.recv_streams.get_mut(&stream_id);
-
-        if r.is_none() {
-            return Ok(false);
-        }
+        let recv_stream = match self.recv_streams.get_mut(&stream_id) {
+            Some(recv_stream) => recv_stream,
+            None => return Ok(false),
+        };
 
-        let recv_stream = r.unwrap();
         qinfo!(
             [label],
             "Request/response stream {} is readable.",
             stream_id
         );
-        recv_stream.receive(conn, &mut self.qpack_decoder)?;
+        if let Err(e) = recv_stream.receive(conn, &mut self.qpack_decoder) {
+            let code = stream_error_code(&e).ok_or(e)?;
+            // This only affects the stream, which has been reported as reset.
+            let _ = self.stream_reset(conn, stream_id, code);
+            return Ok(true);
+        }
         if recv_stream.done() {
             self.recv_streams.remove(&stream_id);
+            self.stream_ended(stream_id, None);
         }
         Ok(true)
     }
@@ -432,6 +801,8 @@ impl Http3Connection {
         stream_type: u64,
         stream_id: u64,
     ) -> Res<bool> {
+        #[cfg(feature = "qlog")]
+        qlog::h3_stream_type_set(conn.qlog_mut(), stream_id, false, stream_type)?;
         match stream_type {
             HTTP3_UNI_STREAM_TYPE_CONTROL => {
                 self.control_stream_remote.add_remote_stream(stream_id)?;
@@ -485,7 +856,7 @@ impl Http3Connection {
 
         // We want to execute both statements, therefore we use | instead of ||.
         let found = self.send_streams.remove(&stream_id).is_some()
-            | self.recv_streams.remove(&stream_id).is_some();
+            | self.remove_recv_stream(stream_id).is_some();
 
         // Stream maybe already be closed and we may get an error here, but we do not care.
         let _ = conn.stream_reset_send(stream_id, error);
@@ -494,18 +865,21 @@ impl Http3Connection {
         if found {
             Ok(())
         } else {
-            Err(Error::InvalidStreamId)
+            Err(Error::UnknownStream)
         }
     }
 
     /// This is called when an application wants to close the sending side of a stream.
     pub fn stream_close_send(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
         qinfo!([self], "Close the sending side for stream {}.", stream_id);
-        debug_assert!(self.state.active());
         let send_stream = self
             .send_streams
             .get_mut(&stream_id)
-            .ok_or(Error::InvalidStreamId)?;
+            .ok_or(Error::UnknownStream)?;
+        // Streams are not removed when the peer closes the connection.
+        if !self.state.active() {
+            return Err(Error::AlreadyClosed);
+        }
         send_stream.close(conn)?;
         if send_stream.done() {
             self.send_streams.remove(&stream_id);
@@ -513,15 +887,44 @@ impl Http3Connection {
         Ok(())
     }
 
-    // If the control stream has received frames MaxPushId or Goaway which handling is specific to
+    /// This is called when an application no longer wants to read from a stream, but
+    /// still has to finish sending on it.  The peer is sent STOP_SENDING with `error` and
+    /// the sending side is left alone.
+    pub fn stream_stop_receiving(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        error: AppError,
+    ) -> Res<()> {
+        qinfo!([self], "Stop receiving {} error={}.", stream_id, error);
+        if !self.recv_streams.contains_key(&stream_id) {
+            return Err(Error::UnknownStream);
+        }
+        if !self.state.active() {
+            return Err(Error::AlreadyClosed);
+        }
+        self.remove_recv_stream(stream_id);
+        // The peer may already have sent everything, in which case this does nothing.
+        let _ = conn.stream_stop_sending(stream_id, error);
+        Ok(())
+    }
+
+    // If the control stream has received frames MaxPushId, CancelPush or Goaway which handling is specific to
     // the client and server, we must give them to the specific client/server handler..
-    fn handle_control_frame(&mut self) -> Res<Option<HFrame>> {
+    // SETTINGS is handled here and then given to them as well, so that they can report it.
+    fn handle_control_frame(&mut self, conn: &mut Connection) -> Res<Option<HFrame>> {
         if self.control_stream_remote.recvd_fin() {
             return Err(Error::HttpClosedCriticalStream);
         }
         if self.control_stream_remote.frame_reader_done() {
             let f = self.control_stream_remote.get_frame()?;
             qinfo!([self], "Handle a control frame {:?}", f);
+            #[cfg(feature = "qlog")]
+            {
+                if let Some(stream_id) = self.control_stream_remote.stream_id() {
+                    qlog::h3_frame_parsed(conn.qlog_mut(), stream_id, &f)?;
+                }
+            }
             if !matches!(f, HFrame::Settings { .. })
                 && !matches!(self.settings_state, Http3RemoteSettingsState::Received{..})
             {
@@ -529,21 +932,36 @@ impl Http3Connection {
             }
             return match f {
                 HFrame::Settings { settings } => {
-                    self.handle_settings(settings)?;
-                    Ok(None)
+                    self.handle_settings(conn, settings.clone())?;
+                    Ok(Some(HFrame::Settings { settings }))
                 }
-                HFrame::Goaway { .. } | HFrame::MaxPushId { .. } => Ok(Some(f)),
+                HFrame::Goaway { .. }
+                | HFrame::MaxPushId { .. }
+                | HFrame::CancelPush { .. }
+                | HFrame::PriorityUpdateRequest { .. }
+                | HFrame::PriorityUpdatePush { .. } => Ok(Some(f)),
                 _ => Err(Error::HttpFrameUnexpected),
             };
         }
         Ok(None)
     }
 
-    fn set_qpack_settings(&mut self, settings: &[HSetting]) -> Res<()> {
+    fn set_qpack_settings(&mut self, conn: &Connection, settings: &[HSetting]) -> Res<()> {
+        let encoder_stream_data = conn.remote_stream_limits().initial_max_stream_data_uni;
         for s in settings {
             qinfo!([self], " {:?} = {:?}", s.setting_type, s.value);
             match s.setting_type {
-                HSettingType::MaxTableCapacity => self.qpack_encoder.set_max_capacity(s.value)?,
+                HSettingType::MaxTableCapacity => {
+                    if s.value > 0 && encoder_stream_data < QPACK_MIN_ENCODER_STREAM_DATA {
+                        qinfo!(
+                            [self],
+                            "Not using the dynamic table, encoder stream limit is {}",
+                            encoder_stream_data
+                        );
+                    } else {
+                        self.qpack_encoder.set_max_capacity(s.value)?;
+                    }
+                }
                 HSettingType::BlockedStreams => {
                     self.qpack_encoder.set_max_blocked_streams(s.value)?
                 }
@@ -553,11 +971,11 @@ impl Http3Connection {
         Ok(())
     }
 
-    fn handle_settings(&mut self, new_settings: HSettings) -> Res<()> {
+    fn handle_settings(&mut self, conn: &Connection, new_settings: HSettings) -> Res<()> {
         qinfo!([self], "Handle SETTINGS frame.");
         match &self.settings_state {
             Http3RemoteSettingsState::NotReceived => {
-                self.set_qpack_settings(&new_settings)?;
+                self.set_qpack_settings(conn, &new_settings)?;
                 self.settings_state = Http3RemoteSettingsState::Received(new_settings);
                 Ok(())
             }
@@ -567,6 +985,7 @@ impl Http3Connection {
                     HSettingType::MaxHeaderListSize,
                     HSettingType::MaxTableCapacity,
                     HSettingType::BlockedStreams,
+                    HSettingType::EnableConnectProtocol,
                 ] {
                     let zero_rtt_value = settings.get(*st);
                     let new_value = new_settings.get(*st);
@@ -597,13 +1016,73 @@ impl Http3Connection {
                 }
                 if qpack_changed {
                     qdebug!([self], "Settings after zero rtt differ.");
-                    self.set_qpack_settings(&(new_settings))?;
+                    self.set_qpack_settings(conn, &(new_settings))?;
                 }
                 self.settings_state = Http3RemoteSettingsState::Received(new_settings);
                 Ok(())
             }
-            Http3RemoteSettingsState::Received { .. } => Err(Error::HttpFrameUnexpected),
+            Http3RemoteSettingsState::Received { .. } => Err(Error::HttpSettings),
+        }
+    }
+
+    /// Set the priority of the request on `stream_id`.  The stream doesn't have to
+    /// exist yet, a PRIORITY_UPDATE frame can arrive before the request does.
+    pub fn set_request_priority(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        priority: Priority,
+    ) {
+        self.priorities.set_request(stream_id, priority);
+        // This fails if the stream is not open, or it has finished sending.
+        let _ = conn.stream_priority(stream_id, priority.transmission_priority());
+    }
+
+    /// Give a new stream the priority that was set for it, if any.
+    pub fn apply_request_priority(&self, conn: &mut Connection, stream_id: u64) {
+        if let Some(priority) = self.priorities.request(stream_id) {
+            let _ = conn.stream_priority(stream_id, priority.transmission_priority());
+        }
+    }
+
+    /// Queue a PRIORITY_UPDATE frame on the control stream.
+    pub fn queue_priority_update(&mut self, conn: &mut Connection, frame: &HFrame) -> Res<()> {
+        debug_assert!(matches!(
+            frame,
+            HFrame::PriorityUpdateRequest { .. } | HFrame::PriorityUpdatePush { .. }
+        ));
+        self.control_stream_local.queue_frame(conn, frame)
+    }
+
+    /// Send GOAWAY, which tells the peer not to make requests on `stream_id` or
+    /// above, and move to `GoingAway`.  Requests below `stream_id` carry on; it is
+    /// up to the caller to close the connection once they are done.
+    pub fn close_gracefully(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
+        match self.state {
+            Http3State::Connected => {}
+            Http3State::GoingAway(_) => return Ok(()),
+            Http3State::Initializing | Http3State::ZeroRtt => return Err(Error::Unavailable),
+            Http3State::Closing(_) | Http3State::Closed(_) => return Err(Error::AlreadyClosed),
         }
+        qinfo!([self], "Send GOAWAY with stream ID {}.", stream_id);
+        self.control_stream_local
+            .queue_frame(conn, &HFrame::Goaway { stream_id })?;
+        self.state = Http3State::GoingAway(stream_id);
+        Ok(())
+    }
+
+    /// Send CANCEL_PUSH, which tells the peer that a push is not wanted or will not
+    /// be sent.
+    pub fn cancel_push(&mut self, conn: &mut Connection, push_id: u64) -> Res<()> {
+        qinfo!([self], "Send CANCEL_PUSH for push {}.", push_id);
+        self.control_stream_local
+            .queue_frame(conn, &HFrame::CancelPush { push_id })
+    }
+
+    /// The longest that frames have waited for the transport to take them on the
+    /// local control stream.
+    pub fn control_stream_delay(&self) -> Duration {
+        self.control_stream_local.max_delay()
     }
 
     /// Return the current state on `Http3Connection`.
@@ -616,12 +1095,84 @@ impl Http3Connection {
         &mut self,
         stream_id: u64,
         send_stream: SendMessage,
-        recv_stream: RecvMessage,
+        mut recv_stream: RecvMessage,
     ) {
+        if let Some(limit) = self.local_max_header_list_size {
+            recv_stream.set_max_header_list_size(limit);
+        }
+        if let Some(policy) = self.header_validation {
+            recv_stream.set_header_validation(policy);
+        }
         if send_stream.has_data_to_send() {
             self.streams_have_data_to_send.insert(stream_id);
         }
         self.send_streams.insert(stream_id, send_stream);
         self.recv_streams.insert(stream_id, recv_stream);
     }
+
+    /// Adds a stream that is only read, such as a push stream.
+    pub fn add_recv_stream(&mut self, stream_id: u64, mut recv_stream: RecvMessage) {
+        if let Some(limit) = self.local_max_header_list_size {
+            recv_stream.set_max_header_list_size(limit);
+        }
+        if let Some(policy) = self.header_validation {
+            recv_stream.set_header_validation(policy);
+        }
+        self.recv_streams.insert(stream_id, recv_stream);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify_new_stream, NewStreamKind};
+    use crate::Error;
+    use neqo_common::Role;
+
+    // The two low bits of a stream ID say who opened it and whether it is
+    // unidirectional: 0 client bidi, 1 server bidi, 2 client uni, 3 server uni.
+
+    #[test]
+    fn classify_client() {
+        assert_eq!(
+            classify_new_stream(Role::Client, 0),
+            Err(Error::HttpStreamCreation)
+        );
+        assert_eq!(
+            classify_new_stream(Role::Client, 1),
+            Err(Error::HttpStreamCreation)
+        );
+        assert_eq!(
+            classify_new_stream(Role::Client, 2),
+            Err(Error::HttpStreamCreation)
+        );
+        assert_eq!(
+            classify_new_stream(Role::Client, 3),
+            Ok(NewStreamKind::Unidirectional)
+        );
+    }
+
+    #[test]
+    fn classify_server() {
+        assert_eq!(
+            classify_new_stream(Role::Server, 0),
+            Ok(NewStreamKind::Request)
+        );
+        assert_eq!(
+            classify_new_stream(Role::Server, 4),
+            Ok(NewStreamKind::Request)
+        );
+        // Streams that only the server itself could have opened are an error.
+        assert_eq!(
+            classify_new_stream(Role::Server, 1),
+            Err(Error::HttpStreamCreation)
+        );
+        assert_eq!(
+            classify_new_stream(Role::Server, 2),
+            Ok(NewStreamKind::Unidirectional)
+        );
+        assert_eq!(
+            classify_new_stream(Role::Server, 3),
+            Err(Error::HttpStreamCreation)
+        );
+    }
 }
@@ -4,15 +4,22 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use crate::connection::{HandleReadableOutput, Http3Connection, Http3State};
+use crate::churn::ChurnStats;
+use crate::connection::{
+    classify_new_stream, stream_error_code, HandleReadableOutput, Http3Connection, Http3State,
+    NewStreamKind,
+};
+use crate::efficiency::EfficiencyStats;
+use crate::headers::MessageType;
 use crate::hframe::HFrame;
 use crate::recv_message::RecvMessage;
 use crate::send_message::SendMessage;
 use crate::server_connection_events::{Http3ServerConnEvent, Http3ServerConnEvents};
-use crate::{Error, Header, Res};
+use crate::{Error, Header, Priority, RequestValidation, Res, Settings};
 use neqo_common::{matches, qdebug, qinfo, qtrace};
 use neqo_qpack::QpackSettings;
-use neqo_transport::{AppError, Connection, ConnectionEvent, StreamType};
+use neqo_transport::{AppError, Connection, ConnectionEvent, StreamId, StreamType};
+use std::cmp::max;
 use std::time::Instant;
 
 #[derive(Debug)]
@@ -20,6 +27,15 @@ pub struct Http3ServerHandler {
     base_handler: Http3Connection,
     events: Http3ServerConnEvents,
     needs_processing: bool,
+    request_validation: RequestValidation,
+    /// The lowest stream ID that the client hasn't made a request on, which is
+    /// what GOAWAY carries.
+    next_request_stream_id: u64,
+    /// The requests that a graceful close waits for: those that the response
+    /// hasn't been acknowledged for in full, or that haven't been reset.
+    unfinished_requests: Vec<u64>,
+    /// The largest push ID that the client sent in MAX_PUSH_ID.
+    max_push_id: Option<u64>,
 }
 
 impl ::std::fmt::Display for Http3ServerHandler {
@@ -29,14 +45,56 @@ impl ::std::fmt::Display for Http3ServerHandler {
 }
 
 impl Http3ServerHandler {
-    pub(crate) fn new(qpack_settings: QpackSettings) -> Self {
+    pub(crate) fn new(
+        qpack_settings: QpackSettings,
+        request_validation: RequestValidation,
+        extra_settings: &[(u64, u64)],
+        stream_rate_limit: Option<u64>,
+    ) -> Self {
+        let mut base_handler = Http3Connection::new(qpack_settings);
+        base_handler.set_header_validation(request_validation.header_validation());
+        base_handler.set_stream_rate_limit(stream_rate_limit);
+        for (id, value) in extra_settings {
+            // The server checked these when they were added.
+            let _ = base_handler.add_setting(*id, *value);
+        }
         Self {
-            base_handler: Http3Connection::new(qpack_settings),
+            base_handler,
             events: Http3ServerConnEvents::default(),
             needs_processing: false,
+            request_validation,
+            next_request_stream_id: 0,
+            unfinished_requests: Vec::new(),
+            max_push_id: None,
         }
     }
 
+    /// The settings that the client sent, if they have arrived.
+    pub(crate) fn peer_settings(&self) -> Option<Settings> {
+        self.base_handler.peer_settings()
+    }
+
+    /// The streams that the client opened and the ones that it reset before they
+    /// could be used.
+    pub(crate) fn churn_stats(&self) -> ChurnStats {
+        self.base_handler.churn_stats()
+    }
+
+    /// What the bytes that the connection sent were used for.
+    pub(crate) fn efficiency_stats(&self, conn: &Connection) -> EfficiencyStats {
+        self.base_handler.efficiency_stats(conn)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn stream_count(&self) -> usize {
+        self.base_handler.stream_count()
+    }
+
+    /// What happens to requests that break the rules for HTTP/3 messages.
+    pub(crate) fn request_validation(&self) -> RequestValidation {
+        self.request_validation
+    }
+
     /// Supply a response for a request.
     pub(crate) fn set_response(
         &mut self,
@@ -47,7 +105,7 @@ impl Http3ServerHandler {
         self.base_handler
             .send_streams
             .get_mut(&stream_id)
-            .ok_or(Error::InvalidStreamId)?
+            .ok_or(Error::UnknownStream)?
             .set_message(headers, Some(data))?;
         self.base_handler
             .insert_streams_have_data_to_send(stream_id);
@@ -67,6 +125,49 @@ impl Http3ServerHandler {
         Ok(())
     }
 
+    /// Answer a request that breaks the rules for HTTP/3 messages with a 400 and
+    /// stop reading it.  If a response has already been given, the request is
+    /// reset instead.
+    pub(crate) fn reject_request(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
+        qinfo!([self], "Reject request {}.", stream_id);
+        self.events.remove_events_for_stream_id(stream_id);
+        let headers = vec![
+            (String::from(":status"), String::from("400")),
+            (String::from("content-length"), String::from("0")),
+        ];
+        match self.set_response(stream_id, &headers, &[]) {
+            Err(Error::AlreadyInitialized) => {
+                return self.stream_reset(conn, stream_id, Error::HttpMessageError.code());
+            }
+            res => res?,
+        }
+        self.base_handler.recv_streams.remove(&stream_id);
+        // The request may have been received in full; that is not a problem.
+        let _ = conn.stream_stop_sending(stream_id, Error::HttpNoError.code());
+        self.needs_processing = true;
+        Ok(())
+    }
+
+    /// Send GOAWAY, so that the client makes no more requests, and close the
+    /// connection once the requests that were already made are done.  Requests
+    /// that the client makes before the GOAWAY arrives are rejected.
+    pub(crate) fn close_gracefully(&mut self, conn: &mut Connection) -> Res<()> {
+        if matches!(self.base_handler.state(), Http3State::GoingAway(_)) {
+            return Ok(());
+        }
+        self.base_handler
+            .close_gracefully(conn, self.next_request_stream_id)?;
+        // A stream that the transport no longer has is finished.
+        self.unfinished_requests = (0..self.next_request_stream_id)
+            .step_by(4)
+            .filter(|id| conn.stream_avail_send_space(*id).is_ok())
+            .collect();
+        self.events
+            .connection_state_change(self.base_handler.state());
+        self.needs_processing = true;
+        Ok(())
+    }
+
     /// Process HTTTP3 layer.
     pub fn process_http3(&mut self, conn: &mut Connection, now: Instant) {
         qtrace!([self], "Process http3 internal.");
@@ -74,10 +175,24 @@ impl Http3ServerHandler {
             return;
         }
 
-        let res = self.check_connection_events(conn);
+        let res = self.check_connection_events(conn, now);
         if !self.check_result(conn, now, &res) && self.base_handler.state().active() {
-            let res = self.base_handler.process_sending(conn);
-            self.check_result(conn, now, &res);
+            let res = self.base_handler.process_sending(conn, now);
+            if !self.check_result(conn, now, &res) {
+                self.close_if_requests_done(conn, now);
+            }
+        }
+    }
+
+    /// Once a graceful close has no more requests to wait for, close the connection.
+    fn close_if_requests_done(&mut self, conn: &mut Connection, now: Instant) {
+        if matches!(self.base_handler.state(), Http3State::GoingAway(_)) {
+            self.unfinished_requests
+                .retain(|id| conn.stream_avail_send_space(*id).is_ok());
+            if self.unfinished_requests.is_empty() {
+                qinfo!([self], "All requests are done after GOAWAY.");
+                self.close(conn, now, &Error::HttpNoError);
+            }
         }
     }
 
@@ -116,26 +231,49 @@ impl Http3ServerHandler {
     }
 
     // If this return an error the connection must be closed.
-    fn check_connection_events(&mut self, conn: &mut Connection) -> Res<()> {
+    fn check_connection_events(&mut self, conn: &mut Connection, now: Instant) -> Res<()> {
         qtrace!([self], "Check connection events.");
         while let Some(e) = conn.next_event() {
             qdebug!([self], "check_connection_events - event {:?}.", e);
             match e {
-                ConnectionEvent::NewStream {
-                    stream_id,
-                    stream_type,
-                } => match stream_type {
-                    StreamType::BiDi => self.base_handler.add_streams(
-                        stream_id,
-                        SendMessage::new(stream_id, Box::new(self.events.clone())),
-                        RecvMessage::new(stream_id, Box::new(self.events.clone()), None),
-                    ),
-                    StreamType::UniDi => {
-                        if self.base_handler.handle_new_unidi_stream(conn, stream_id)? {
-                            return Err(Error::HttpStreamCreation);
+                ConnectionEvent::NewStream { stream_id, .. } => {
+                    self.base_handler.stream_opened(now)?;
+                    match classify_new_stream(conn.role(), stream_id)? {
+                        NewStreamKind::Request => {
+                            if let Http3State::GoingAway(goaway_stream_id) =
+                                self.base_handler.state()
+                            {
+                                if stream_id >= goaway_stream_id {
+                                    qinfo!([self], "Reject request {} after GOAWAY.", stream_id);
+                                    let error = Error::HttpRequestRejected.code();
+                                    let _ = conn.stream_stop_sending(stream_id, error);
+                                    let _ = conn.stream_reset_send(stream_id, error);
+                                    continue;
+                                }
+                            }
+                            self.next_request_stream_id =
+                                max(self.next_request_stream_id, stream_id + 4);
+                            self.base_handler.add_streams(
+                                stream_id,
+                                SendMessage::new(stream_id, Box::new(self.events.clone())),
+                                RecvMessage::new(
+                                    MessageType::Request,
+                                    stream_id,
+                                    Box::new(self.events.clone()),
+                                    None,
+                                ),
+                            );
+                            if let Some(p) = self.base_handler.priorities.take_request(stream_id) {
+                                let _ = conn.stream_priority(stream_id, p.transmission_priority());
+                            }
+                        }
+                        NewStreamKind::Unidirectional => {
+                            if self.base_handler.handle_new_unidi_stream(conn, stream_id)? {
+                                return Err(Error::HttpStreamCreation);
+                            }
                         }
                     }
-                },
+                }
                 ConnectionEvent::RecvStreamReadable { stream_id } => {
                     self.handle_stream_readable(conn, stream_id)?
                 }
@@ -143,9 +281,22 @@ impl Http3ServerHandler {
                     stream_id,
                     app_error,
                 } => {
-                    let _ = self
+                    // A request that is reset before its headers arrive was never
+                    // reported, so it doesn't need to be reported now either.
+                    let headers_received = self
                         .base_handler
-                        .handle_stream_reset(conn, stream_id, app_error)?;
+                        .recv_streams
+                        .get(&stream_id)
+                        .map_or(false, RecvMessage::headers_received);
+                    if self
+                        .base_handler
+                        .handle_stream_reset(conn, stream_id, app_error)?
+                        && headers_received
+                    {
+                        self.events.peer_reset(stream_id, app_error);
+                    } else {
+                        self.events.remove_events_for_stream_id(stream_id);
+                    }
                 }
                 ConnectionEvent::SendStreamStopSending {
                     stream_id,
@@ -160,9 +311,13 @@ impl Http3ServerHandler {
                 ConnectionEvent::AuthenticationNeeded | ConnectionEvent::ZeroRttRejected => {
                     return Err(Error::HttpInternal)
                 }
+                ConnectionEvent::CongestionStateChanged { state } => {
+                    self.events.congestion_state_changed(state)
+                }
                 ConnectionEvent::SendStreamWritable { .. }
                 | ConnectionEvent::SendStreamComplete { .. }
-                | ConnectionEvent::SendStreamCreatable { .. } => {}
+                | ConnectionEvent::SendStreamCreatable { .. }
+                | ConnectionEvent::FlowControlStalled { .. } => {}
             }
         }
         Ok(())
@@ -174,13 +329,40 @@ impl Http3ServerHandler {
             HandleReadableOutput::ControlFrames(control_frames) => {
                 for f in control_frames {
                     match f {
-                        HFrame::MaxPushId { .. } => {
+                        HFrame::MaxPushId { push_id } => {
+                            // The limit can't go down.
+                            if self.max_push_id.map_or(false, |max| push_id < max) {
+                                Err(Error::HttpId)
+                            } else {
+                                self.max_push_id = Some(push_id);
+                                Ok(())
+                            }
+                        }
+                        HFrame::CancelPush { .. } => {
                             // TODO implement push
                             Ok(())
                         }
+                        HFrame::Settings { .. } => {
+                            self.events.settings_received();
+                            Ok(())
+                        }
                         HFrame::Goaway { .. } => Err(Error::HttpFrameUnexpected),
+                        HFrame::PriorityUpdateRequest {
+                            element_id,
+                            priority,
+                        } => self.priority_update_request(conn, element_id, priority),
+                        HFrame::PriorityUpdatePush { element_id, .. } => {
+                            // This server doesn't push, so there is nothing to
+                            // reprioritize, but the push ID still has to be one
+                            // that the client allowed.
+                            if self.max_push_id.map_or(true, |max| element_id > max) {
+                                Err(Error::HttpId)
+                            } else {
+                                Ok(())
+                            }
+                        }
                         _ => unreachable!(
-                            "we should only put MaxPushId and Goaway into control_frames."
+                            "we should only put Settings, MaxPushId, CancelPush, Goaway and PriorityUpdate into control_frames."
                         ),
                     }?;
                 }
@@ -190,6 +372,34 @@ impl Http3ServerHandler {
         }
     }
 
+    /// Reprioritize a request.  A request that has started is reprioritized
+    /// straight away, and one that has finished is ignored.  The priority for one
+    /// that hasn't started is kept until it does, so the client can't make the
+    /// server keep more priorities than it is allowed streams.
+    fn priority_update_request(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        priority: Priority,
+    ) -> Res<()> {
+        let id = StreamId::from(stream_id);
+        if !id.is_bidi()
+            || !id.is_client_initiated()
+            || stream_id / 4 >= conn.local_stream_limit(StreamType::BiDi)
+        {
+            return Err(Error::HttpId);
+        }
+        if stream_id >= self.next_request_stream_id {
+            self.base_handler
+                .priorities
+                .set_request(stream_id, priority);
+        } else if self.base_handler.send_streams.contains_key(&stream_id) {
+            // This fails if the response has been sent in full.
+            let _ = conn.stream_priority(stream_id, priority.transmission_priority());
+        }
+        Ok(())
+    }
+
     fn handle_stream_stop_sending(
         &mut self,
         conn: &mut Connection,
@@ -216,7 +426,8 @@ impl Http3ServerHandler {
     /// data.
     /// # Errors
     /// It returns an error if a stream does not exist or an error happen while reading a stream, e.g.
-    /// early close, protocol error, etc.
+    /// early close, protocol error, etc.  A stream that is gone gives `AlreadyRead`, `StreamReset`
+    /// or `UnknownStream`, as for `Http3Client::read_response_data`.
     pub fn read_request_data(
         &mut self,
         conn: &mut Connection,
@@ -226,20 +437,25 @@ impl Http3ServerHandler {
     ) -> Res<(usize, bool)> {
         qinfo!([self], "read_data from stream {}.", stream_id);
         match self.base_handler.recv_streams.get_mut(&stream_id) {
-            None => {
-                self.close(conn, now, &Error::Internal);
-                Err(Error::Internal)
-            }
+            // A stale stream ID is not a reason to close the connection.
+            None => Err(self.base_handler.missing_stream_error(stream_id)),
             Some(recv_stream) => {
                 match recv_stream.read_data(conn, &mut self.base_handler.qpack_decoder, buf) {
                     Ok((amount, fin)) => {
                         if recv_stream.done() {
                             self.base_handler.recv_streams.remove(&stream_id);
+                            self.base_handler.stream_ended(stream_id, None);
                         }
                         Ok((amount, fin))
                     }
+                    Err(Error::HeadersNotReady) => Err(Error::HeadersNotReady),
                     Err(e) => {
-                        self.close(conn, now, &e);
+                        // A request that isn't valid only affects its stream.
+                        if let Some(code) = stream_error_code(&e) {
+                            let _ = self.base_handler.stream_reset(conn, stream_id, code);
+                        } else {
+                            self.close(conn, now, &e);
+                        }
                         Err(e)
                     }
                 }
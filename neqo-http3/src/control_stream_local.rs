@@ -5,9 +5,13 @@
 // except according to those terms.
 
 use crate::hframe::HFrame;
+#[cfg(feature = "qlog")]
+use crate::qlog;
 use crate::Res;
 use neqo_common::{qtrace, Encoder};
-use neqo_transport::{Connection, StreamType};
+use neqo_transport::{Connection, StreamType, TransmissionPriority};
+use std::cmp::max;
+use std::time::{Duration, Instant};
 
 pub const HTTP3_UNI_STREAM_TYPE_CONTROL: u64 = 0x0;
 
@@ -16,6 +20,9 @@ pub const HTTP3_UNI_STREAM_TYPE_CONTROL: u64 = 0x0;
 pub(crate) struct ControlStreamLocal {
     stream_id: Option<u64>,
     buf: Vec<u8>,
+    /// When `send` first found data in `buf` that the transport hasn't taken.
+    waiting_since: Option<Instant>,
+    max_delay: Duration,
 }
 
 impl ::std::fmt::Display for ControlStreamLocal {
@@ -26,39 +33,66 @@ impl ::std::fmt::Display for ControlStreamLocal {
 
 impl ControlStreamLocal {
     /// Add a new frame that needs to be send.
-    pub fn queue_frame(&mut self, f: &HFrame) {
+    #[cfg_attr(not(feature = "qlog"), allow(unused_variables))]
+    pub fn queue_frame(&mut self, conn: &mut Connection, f: &HFrame) -> Res<()> {
         let mut enc = Encoder::default();
         f.encode(&mut enc);
         self.buf.append(&mut enc.into());
+        #[cfg(feature = "qlog")]
+        {
+            if let Some(stream_id) = self.stream_id {
+                qlog::h3_frame_created(conn.qlog_mut(), stream_id, f)?;
+            }
+        }
+        Ok(())
     }
 
-    /// Send control data if available.
-    pub fn send(&mut self, conn: &mut Connection) -> Res<()> {
+    /// Send control data if available.  This returns the number of bytes sent.
+    pub fn send(&mut self, conn: &mut Connection, now: Instant) -> Res<usize> {
         if let Some(stream_id) = self.stream_id {
             if !self.buf.is_empty() {
                 qtrace!([self], "sending data.");
+                let since = *self.waiting_since.get_or_insert(now);
                 let sent = conn.stream_send(stream_id, &self.buf[..])?;
                 if sent == self.buf.len() {
                     self.buf.clear();
+                    self.max_delay = max(self.max_delay, now.duration_since(since));
+                    self.waiting_since = None;
                 } else {
                     let b = self.buf.split_off(sent);
                     self.buf = b;
                 }
+                return Ok(sent);
             }
         }
-        Ok(())
+        Ok(0)
     }
 
-    /// Create a control stream.
+    /// Create a control stream.  It is opened as soon as the peer's stream limit allows.
     pub fn create(&mut self, conn: &mut Connection) -> Res<()> {
         qtrace!([self], "Create a control stream.");
-        self.stream_id = Some(conn.stream_create(StreamType::UniDi)?);
+        let stream_id = conn.stream_create_pending(StreamType::UniDi)?.as_u64();
+        conn.stream_priority(stream_id, TransmissionPriority::Critical)?;
+        self.stream_id = Some(stream_id);
         let mut enc = Encoder::default();
         enc.encode_varint(HTTP3_UNI_STREAM_TYPE_CONTROL);
         self.buf.append(&mut enc.into());
+        #[cfg(feature = "qlog")]
+        qlog::h3_stream_type_set(
+            conn.qlog_mut(),
+            stream_id,
+            true,
+            HTTP3_UNI_STREAM_TYPE_CONTROL,
+        )?;
         Ok(())
     }
 
+    /// The longest that data has waited before the transport took all of it.
+    #[must_use]
+    pub fn max_delay(&self) -> Duration {
+        self.max_delay
+    }
+
     #[must_use]
     pub fn stream_id(&self) -> Option<u64> {
         self.stream_id
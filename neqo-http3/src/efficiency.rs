@@ -0,0 +1,156 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Accounting for what the bytes that an HTTP/3 connection sends are used for.
+
+use neqo_common::qlog::NeqoQlog;
+use neqo_qpack::writer::StreamWriter;
+use neqo_qpack::Error as QpackError;
+use neqo_transport::{Connection, SentBytes};
+use std::fmt::{self, Display, Formatter};
+
+/// The stream data that HTTP/3 has given to QUIC, by what it is for.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct StreamBytes {
+    pub app_payload: u64,
+    pub h3_framing: u64,
+    pub qpack: u64,
+}
+
+impl StreamBytes {
+    pub fn add(&mut self, other: &Self) {
+        self.app_payload += other.app_payload;
+        self.h3_framing += other.h3_framing;
+        self.qpack += other.qpack;
+    }
+}
+
+/// A `StreamWriter` that counts what QPACK writes to its streams.
+pub(crate) struct QpackWriter<'a> {
+    conn: &'a mut Connection,
+    sent: &'a mut u64,
+}
+
+impl<'a> QpackWriter<'a> {
+    pub fn new(conn: &'a mut Connection, sent: &'a mut u64) -> Self {
+        Self { conn, sent }
+    }
+}
+
+impl StreamWriter for QpackWriter<'_> {
+    fn send(&mut self, stream_id: u64, buf: &[u8]) -> Result<usize, QpackError> {
+        let sent = self.conn.stream_send(stream_id, buf)?;
+        *self.sent += sent as u64;
+        Ok(sent)
+    }
+
+    fn send_atomic(&mut self, stream_id: u64, buf: &[u8]) -> Result<bool, QpackError> {
+        let sent = self.conn.stream_send_atomic(stream_id, buf)?;
+        if sent {
+            *self.sent += buf.len() as u64;
+        }
+        Ok(sent)
+    }
+
+    fn qlog(&mut self) -> Option<&mut NeqoQlog> {
+        self.conn.qlog_mut().as_mut()
+    }
+}
+
+/// What the bytes that a connection sent were used for, see
+/// `Http3Client::efficiency_stats` and `Http3Server::efficiency_stats`.  HTTP/3 counts stream data when it gives it
+/// to QUIC, so the fields only add up to the size of every datagram that was sent,
+/// which is `total()`, once QUIC has sent all of it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EfficiencyStats {
+    /// The content of requests and responses, which is the payload of DATA frames.
+    pub app_payload: u64,
+    /// The type and length of each HTTP/3 frame and everything on the control
+    /// stream.
+    pub h3_framing: u64,
+    /// Header blocks and everything on the QPACK encoder and decoder streams.
+    pub qpack: u64,
+    /// Stream data that QUIC sent again because it was lost.
+    pub retransmitted: u64,
+    /// QUIC frames other than the stream data that they carry.
+    pub quic_frames: u64,
+    /// QUIC packet headers.
+    pub packet_headers: u64,
+    /// The authentication tag on each packet.
+    pub aead: u64,
+    /// Bytes that were added to make a datagram larger.
+    pub padding: u64,
+}
+
+impl EfficiencyStats {
+    pub(crate) fn new(stream: &StreamBytes, sent: &SentBytes) -> Self {
+        Self {
+            app_payload: stream.app_payload,
+            h3_framing: stream.h3_framing,
+            qpack: stream.qpack,
+            retransmitted: sent.stream_retransmitted,
+            quic_frames: sent.frames,
+            packet_headers: sent.packet_headers,
+            aead: sent.aead,
+            padding: sent.padding,
+        }
+    }
+
+    pub(crate) fn add(&mut self, other: &Self) {
+        self.app_payload += other.app_payload;
+        self.h3_framing += other.h3_framing;
+        self.qpack += other.qpack;
+        self.retransmitted += other.retransmitted;
+        self.quic_frames += other.quic_frames;
+        self.packet_headers += other.packet_headers;
+        self.aead += other.aead;
+        self.padding += other.padding;
+    }
+
+    /// All of the bytes that were sent.
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        self.app_payload
+            + self.h3_framing
+            + self.qpack
+            + self.retransmitted
+            + self.quic_frames
+            + self.packet_headers
+            + self.aead
+            + self.padding
+    }
+
+    /// The bytes that were sent for anything other than the content of requests
+    /// and responses, as a percentage of everything that was sent.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn overhead_percent(&self) -> f64 {
+        let total = self.total();
+        if total == 0 {
+            return 0.0;
+        }
+        (total - self.app_payload) as f64 * 100.0 / total as f64
+    }
+}
+
+impl Display for EfficiencyStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes sent, {:.1}% overhead: payload {}, h3 {}, qpack {}, retransmitted {}, quic frames {}, headers {}, aead {}, padding {}",
+            self.total(),
+            self.overhead_percent(),
+            self.app_payload,
+            self.h3_framing,
+            self.qpack,
+            self.retransmitted,
+            self.quic_frames,
+            self.packet_headers,
+            self.aead,
+            self.padding
+        )
+    }
+}
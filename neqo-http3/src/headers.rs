@@ -0,0 +1,791 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Checking received header fields against the rules in Section 4.1.1 of the
+// HTTP/3 draft and RFC 7230, and the pseudo-header fields of requests and
+// responses against Section 8.1.2 of RFC 7540.
+
+use crate::{Error, Header, Res};
+use neqo_common::{matches, qdebug};
+
+use std::ops::{BitOr, BitOrAssign};
+
+/// What to do with received headers that break the rules for HTTP/3 fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderValidation {
+    /// Reset the stream with `H3_MESSAGE_ERROR`, as HTTP/3 requires.  This is the
+    /// default.
+    Strict,
+    /// Repair what can be repaired, deliver the result, and report what was wrong
+    /// in a `HeaderViolations` event.  Headers that can't be parsed are treated
+    /// as they are in strict mode.  Problems with pseudo-header fields and
+    /// `content-length` are reported, but not repaired.  This is only for talking
+    /// to peers that are known to be broken, so it has to be asked for.
+    Lenient,
+}
+
+impl Default for HeaderValidation {
+    fn default() -> Self {
+        Self::Strict
+    }
+}
+
+/// What `Http3Server` does with a request that breaks the rules for HTTP/3 messages.
+/// Requests with fields that can't be parsed at all are always reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestValidation {
+    /// Respond with a 400 (Bad Request) and stop reading the request.  The
+    /// application doesn't see these requests, unless the problem is only found
+    /// in the body; if a response was given by then, the request is reset instead.
+    Reject,
+    /// Reset the stream with `H3_MESSAGE_ERROR`.
+    Reset,
+    /// Repair what can be repaired and deliver the request, after an event that
+    /// says what was wrong with it, so that the application decides.
+    Deliver,
+}
+
+impl Default for RequestValidation {
+    fn default() -> Self {
+        Self::Deliver
+    }
+}
+
+impl RequestValidation {
+    /// How the headers of requests are checked.
+    pub(crate) fn header_validation(self) -> HeaderValidation {
+        match self {
+            Self::Reset => HeaderValidation::Strict,
+            Self::Reject | Self::Deliver => HeaderValidation::Lenient,
+        }
+    }
+}
+
+/// Whether a header block starts a request or a response.  That determines which
+/// pseudo-header fields it has to have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MessageType {
+    Request,
+    Response,
+}
+
+/// A set of problems found in a header block, see `HeaderValidation`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct HeaderViolations(u16);
+
+impl HeaderViolations {
+    pub const NONE: Self = Self(0);
+    /// A field name has uppercase characters.  These are lowercased.
+    pub const UPPERCASE_NAME: Self = Self(1);
+    /// A field value starts or ends with whitespace.  This is removed.
+    pub const VALUE_WHITESPACE: Self = Self(1 << 1);
+    /// A field value is folded over several lines.  Each fold is replaced with a space.
+    pub const OBS_FOLD: Self = Self(1 << 2);
+    /// There is a connection-specific field, such as `connection` or `transfer-encoding`.
+    /// These are removed.
+    pub const CONNECTION_SPECIFIC: Self = Self(1 << 3);
+    /// A pseudo-header follows a regular field.  Pseudo-headers are moved to the front.
+    pub const PSEUDO_HEADER_ORDER: Self = Self(1 << 4);
+    /// A pseudo-header isn't one that is defined for the message, or isn't allowed
+    /// with the request method, like `:path` in a CONNECT request.
+    pub const INVALID_PSEUDO_HEADER: Self = Self(1 << 5);
+    /// A pseudo-header appears more than once.
+    pub const DUPLICATE_PSEUDO_HEADER: Self = Self(1 << 6);
+    /// A pseudo-header that the message needs is missing or empty.
+    pub const MISSING_PSEUDO_HEADER: Self = Self(1 << 7);
+    /// `content-length` isn't a number, has several different values, or doesn't
+    /// match the length of the body of a request.
+    pub const CONTENT_LENGTH: Self = Self(1 << 8);
+
+    /// The set with the given bits, as returned by `bits`.
+    #[must_use]
+    pub fn from_bits(bits: u16) -> Self {
+        Self(bits)
+    }
+
+    #[must_use]
+    pub fn bits(self) -> u16 {
+        self.0
+    }
+
+    #[must_use]
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether all of the problems in `other` are in this set.
+    #[must_use]
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for HeaderViolations {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for HeaderViolations {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+const CONNECTION_SPECIFIC_FIELDS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+fn is_connection_specific(name: &str, value: &str) -> bool {
+    CONNECTION_SPECIFIC_FIELDS.contains(&name) || (name == "te" && value != "trailers")
+}
+
+/// Check a name, lowercasing it if needed.  This returns `None` if the name isn't
+/// a token, optionally preceded by a colon.
+fn normalize_name(name: String, violations: &mut HeaderViolations) -> Option<String> {
+    let token = if name.starts_with(':') {
+        &name[1..]
+    } else {
+        &name[..]
+    };
+    if token.is_empty() || !token.chars().all(is_tchar) {
+        return None;
+    }
+    if name.chars().any(|c| c.is_ascii_uppercase()) {
+        *violations |= HeaderViolations::UPPERCASE_NAME;
+        Some(name.to_ascii_lowercase())
+    } else {
+        Some(name)
+    }
+}
+
+/// Unfold and trim a value.  This returns `None` if the value has a NUL, or a CR or
+/// LF that isn't part of a fold.
+fn normalize_value(value: String, violations: &mut HeaderViolations) -> Option<String> {
+    if value.contains('\0') {
+        return None;
+    }
+    let mut unfolded = value;
+    if unfolded.contains(|c| c == '\r' || c == '\n') {
+        let mut out = String::with_capacity(unfolded.len());
+        let mut chars = unfolded.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '\r' && c != '\n' {
+                out.push(c);
+                continue;
+            }
+            if c == '\r' && chars.next() != Some('\n') {
+                return None;
+            }
+            if !matches!(chars.peek(), Some(' ') | Some('\t')) {
+                return None;
+            }
+            while matches!(chars.peek(), Some(' ') | Some('\t')) {
+                let _ = chars.next();
+            }
+            out.truncate(out.trim_end_matches(|c| c == ' ' || c == '\t').len());
+            out.push(' ');
+        }
+        *violations |= HeaderViolations::OBS_FOLD;
+        unfolded = out;
+    }
+    let trimmed = unfolded.trim_matches(|c| c == ' ' || c == '\t');
+    if trimmed.len() == unfolded.len() {
+        Some(unfolded)
+    } else {
+        *violations |= HeaderViolations::VALUE_WHITESPACE;
+        Some(String::from(trimmed))
+    }
+}
+
+const REQUEST_PSEUDO_HEADERS: &[&str] = &[":method", ":scheme", ":authority", ":path", ":protocol"];
+const RESPONSE_PSEUDO_HEADERS: &[&str] = &[":status"];
+
+/// Check the pseudo-header fields of a message, which have to come first.
+fn check_pseudo_headers(headers: &[Header], message_type: MessageType) -> HeaderViolations {
+    let mut violations = HeaderViolations::NONE;
+    let defined = match message_type {
+        MessageType::Request => REQUEST_PSEUDO_HEADERS,
+        MessageType::Response => RESPONSE_PSEUDO_HEADERS,
+    };
+    let mut seen: Vec<&str> = Vec::new();
+    for (name, _) in headers.iter().take_while(|(n, _)| n.starts_with(':')) {
+        if !defined.contains(&name.as_str()) {
+            violations |= HeaderViolations::INVALID_PSEUDO_HEADER;
+        } else if seen.contains(&name.as_str()) {
+            violations |= HeaderViolations::DUPLICATE_PSEUDO_HEADER;
+        } else {
+            seen.push(name);
+        }
+    }
+    let value = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    };
+    let needs = |name: &str| value(name).map_or(true, str::is_empty);
+
+    match message_type {
+        MessageType::Response => {
+            let status = value(":status").unwrap_or("");
+            if status.len() != 3 || !status.chars().all(|c| c.is_ascii_digit()) {
+                violations |= HeaderViolations::MISSING_PSEUDO_HEADER;
+            }
+        }
+        MessageType::Request => {
+            let connect = value(":method") == Some("CONNECT");
+            if needs(":method") {
+                violations |= HeaderViolations::MISSING_PSEUDO_HEADER;
+            } else if connect && value(":protocol").is_none() {
+                // A plain CONNECT only names the authority to connect to.
+                if value(":scheme").is_some() || value(":path").is_some() {
+                    violations |= HeaderViolations::INVALID_PSEUDO_HEADER;
+                }
+                if needs(":authority") {
+                    violations |= HeaderViolations::MISSING_PSEUDO_HEADER;
+                }
+            } else {
+                if value(":protocol").is_some() && !connect {
+                    violations |= HeaderViolations::INVALID_PSEUDO_HEADER;
+                }
+                if needs(":scheme") || needs(":path") {
+                    violations |= HeaderViolations::MISSING_PSEUDO_HEADER;
+                }
+                // An extended CONNECT needs an authority too, as does any request
+                // for an http or https URI that doesn't have a host field.
+                let web = value(":scheme").map_or(false, |s| s == "http" || s == "https");
+                if needs(":authority") && (connect || (web && value("host").is_none())) {
+                    violations |= HeaderViolations::MISSING_PSEUDO_HEADER;
+                }
+            }
+        }
+    }
+    violations
+}
+
+/// The value of `content-length`, if there is one.  A field that appears several
+/// times has to have the same value each time.
+/// # Errors
+/// `InvalidHeader` if the values are not numbers or are different.
+pub(crate) fn content_length(headers: &[Header]) -> Res<Option<u64>> {
+    let mut length = None;
+    for (_, value) in headers.iter().filter(|(n, _)| n == "content-length") {
+        // A list of values is also allowed, as long as they are all the same.
+        for v in value.split(',') {
+            let v = v.trim();
+            if v.is_empty() || !v.chars().all(|c| c.is_ascii_digit()) {
+                return Err(Error::InvalidHeader);
+            }
+            let v = v.parse::<u64>().or(Err(Error::InvalidHeader))?;
+            if length.map_or(false, |l| l != v) {
+                return Err(Error::InvalidHeader);
+            }
+            length = Some(v);
+        }
+    }
+    Ok(length)
+}
+
+/// Check received headers.  This returns the headers to deliver and what was wrong
+/// with them, which is always nothing in strict mode.
+/// # Errors
+/// `InvalidHeader` if a problem can't be repaired, or if there is any problem in strict mode.
+pub(crate) fn validate_headers(
+    headers: Vec<Header>,
+    message_type: MessageType,
+    policy: HeaderValidation,
+) -> Res<(Vec<Header>, HeaderViolations)> {
+    let mut violations = HeaderViolations::NONE;
+    let mut pseudo = Vec::new();
+    let mut regular = Vec::new();
+    for (name, value) in headers {
+        let name = normalize_name(name, &mut violations).ok_or(Error::InvalidHeader)?;
+        let value = normalize_value(value, &mut violations).ok_or(Error::InvalidHeader)?;
+        if name.starts_with(':') {
+            if !regular.is_empty() {
+                violations |= HeaderViolations::PSEUDO_HEADER_ORDER;
+            }
+            pseudo.push((name, value));
+        } else if is_connection_specific(&name, &value) {
+            violations |= HeaderViolations::CONNECTION_SPECIFIC;
+        } else {
+            regular.push((name, value));
+        }
+    }
+    pseudo.append(&mut regular);
+    violations |= check_pseudo_headers(&pseudo, message_type);
+    if content_length(&pseudo).is_err() {
+        violations |= HeaderViolations::CONTENT_LENGTH;
+    }
+    if !violations.is_empty() {
+        qdebug!("Received headers with problems: {:?}", violations);
+        if policy == HeaderValidation::Strict {
+            return Err(Error::InvalidHeader);
+        }
+    }
+    Ok((pseudo, violations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        content_length, validate_headers, HeaderValidation, HeaderViolations, MessageType,
+    };
+    use crate::{Error, Header};
+
+    fn h(name: &str, value: &str) -> Header {
+        (String::from(name), String::from(value))
+    }
+
+    /// Header blocks that have been seen from real servers, the headers that lenient
+    /// mode delivers for them, and what is wrong with them.
+    fn repairable() -> Vec<(Vec<Header>, Vec<Header>, HeaderViolations)> {
+        vec![
+            (
+                vec![h(":status", "200"), h("Content-Type", "text/html")],
+                vec![h(":status", "200"), h("content-type", "text/html")],
+                HeaderViolations::UPPERCASE_NAME,
+            ),
+            (
+                vec![h(":status", "200"), h("server", " nginx\t")],
+                vec![h(":status", "200"), h("server", "nginx")],
+                HeaderViolations::VALUE_WHITESPACE,
+            ),
+            (
+                vec![h(":status", "200"), h("x-long", "one,\r\n two,\n\tthree")],
+                vec![h(":status", "200"), h("x-long", "one, two, three")],
+                HeaderViolations::OBS_FOLD,
+            ),
+            (
+                vec![
+                    h(":status", "200"),
+                    h("connection", "keep-alive"),
+                    h("keep-alive", "timeout=5"),
+                    h("transfer-encoding", "chunked"),
+                    h("content-length", "3"),
+                ],
+                vec![h(":status", "200"), h("content-length", "3")],
+                HeaderViolations::CONNECTION_SPECIFIC,
+            ),
+            (
+                vec![h("date", "today"), h(":status", "204")],
+                vec![h(":status", "204"), h("date", "today")],
+                HeaderViolations::PSEUDO_HEADER_ORDER,
+            ),
+            (
+                vec![
+                    h(":status", "200"),
+                    h("Connection", "close"),
+                    h("Set-Cookie", "a=b; \r\n path=/ "),
+                ],
+                vec![h(":status", "200"), h("set-cookie", "a=b; path=/")],
+                HeaderViolations::UPPERCASE_NAME
+                    | HeaderViolations::VALUE_WHITESPACE
+                    | HeaderViolations::OBS_FOLD
+                    | HeaderViolations::CONNECTION_SPECIFIC,
+            ),
+        ]
+    }
+
+    /// Header blocks that neither mode accepts.
+    fn irreparable() -> Vec<Vec<Header>> {
+        vec![
+            vec![h(":status", "200"), h("", "empty")],
+            vec![h(":status", "200"), h(":", "colon")],
+            vec![h(":status", "200"), h("x header", "space")],
+            vec![h(":status", "200"), h("x:y", "colon")],
+            vec![h(":status", "200"), h("x-nul", "a\0b")],
+            vec![h(":status", "200"), h("x-cr", "a\rb")],
+            vec![h(":status", "200"), h("x-lf", "a\nb")],
+            vec![h(":status", "200"), h("x-crlf", "a\r\nb")],
+            vec![h(":status", "200"), h("x-end", "a\r\n")],
+        ]
+    }
+
+    #[test]
+    fn valid() {
+        let headers = vec![
+            h(":status", "200"),
+            h("content-length", "3"),
+            h("te", "trailers"),
+            h("x-empty", ""),
+            h("x-inner", "a \t b"),
+        ];
+        for policy in &[HeaderValidation::Strict, HeaderValidation::Lenient] {
+            assert_eq!(
+                validate_headers(headers.clone(), MessageType::Response, *policy),
+                Ok((headers.clone(), HeaderViolations::NONE))
+            );
+        }
+    }
+
+    #[test]
+    fn lenient() {
+        for (headers, expected, violations) in repairable() {
+            assert_eq!(
+                validate_headers(headers, MessageType::Response, HeaderValidation::Lenient),
+                Ok((expected, violations))
+            );
+        }
+    }
+
+    #[test]
+    fn strict() {
+        for (headers, _, _) in repairable() {
+            assert_eq!(
+                validate_headers(headers, MessageType::Response, HeaderValidation::Strict),
+                Err(Error::InvalidHeader)
+            );
+        }
+    }
+
+    #[test]
+    fn irreparable_in_both_modes() {
+        for headers in irreparable() {
+            for policy in &[HeaderValidation::Strict, HeaderValidation::Lenient] {
+                assert_eq!(
+                    validate_headers(headers.clone(), MessageType::Response, *policy),
+                    Err(Error::InvalidHeader)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn te_other_than_trailers() {
+        assert_eq!(
+            validate_headers(
+                vec![h(":status", "200"), h("te", "gzip")],
+                MessageType::Response,
+                HeaderValidation::Lenient
+            ),
+            Ok((
+                vec![h(":status", "200")],
+                HeaderViolations::CONNECTION_SPECIFIC
+            ))
+        );
+    }
+
+    fn request(pseudo: &[(&str, &str)]) -> Vec<Header> {
+        pseudo.iter().map(|(n, v)| h(n, v)).collect()
+    }
+
+    /// Requests that are valid, in both modes.
+    fn valid_requests() -> Vec<Vec<Header>> {
+        vec![
+            request(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":authority", "example.com"),
+                (":path", "/"),
+            ]),
+            // An authority can be given in a host field instead.
+            request(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/"),
+                ("host", "example.com"),
+            ]),
+            request(&[
+                (":method", "POST"),
+                (":scheme", "https"),
+                (":authority", "example.com"),
+                (":path", "/upload"),
+                ("content-length", "12, 12"),
+            ]),
+            request(&[(":method", "CONNECT"), (":authority", "example.com:443")]),
+            request(&[
+                (":method", "CONNECT"),
+                (":protocol", "websocket"),
+                (":scheme", "https"),
+                (":authority", "example.com"),
+                (":path", "/chat"),
+            ]),
+        ]
+    }
+
+    /// Requests that break the rules for pseudo-headers or `content-length`, and
+    /// what is wrong with them.  Nothing is repaired, so lenient mode delivers
+    /// them as they are.
+    fn invalid_requests() -> Vec<(Vec<Header>, HeaderViolations)> {
+        vec![
+            (
+                request(&[(":scheme", "https"), (":authority", "a"), (":path", "/")]),
+                HeaderViolations::MISSING_PSEUDO_HEADER,
+            ),
+            (
+                request(&[(":method", "GET"), (":authority", "a"), (":path", "/")]),
+                HeaderViolations::MISSING_PSEUDO_HEADER,
+            ),
+            (
+                request(&[
+                    (":method", "GET"),
+                    (":scheme", "https"),
+                    (":authority", "a"),
+                ]),
+                HeaderViolations::MISSING_PSEUDO_HEADER,
+            ),
+            (
+                request(&[
+                    (":method", "GET"),
+                    (":scheme", "https"),
+                    (":authority", "a"),
+                    (":path", ""),
+                ]),
+                HeaderViolations::MISSING_PSEUDO_HEADER,
+            ),
+            (
+                request(&[(":method", "GET"), (":scheme", "https"), (":path", "/")]),
+                HeaderViolations::MISSING_PSEUDO_HEADER,
+            ),
+            (
+                request(&[
+                    (":method", "GET"),
+                    (":scheme", "https"),
+                    (":authority", "a"),
+                    (":path", "/"),
+                    (":status", "200"),
+                ]),
+                HeaderViolations::INVALID_PSEUDO_HEADER,
+            ),
+            (
+                request(&[
+                    (":method", "GET"),
+                    (":scheme", "https"),
+                    (":authority", "a"),
+                    (":path", "/"),
+                    (":host", "a"),
+                ]),
+                HeaderViolations::INVALID_PSEUDO_HEADER,
+            ),
+            (
+                request(&[
+                    (":method", "GET"),
+                    (":method", "POST"),
+                    (":scheme", "https"),
+                    (":authority", "a"),
+                    (":path", "/"),
+                ]),
+                HeaderViolations::DUPLICATE_PSEUDO_HEADER,
+            ),
+            (
+                request(&[
+                    (":method", "GET"),
+                    (":scheme", "https"),
+                    (":authority", "a"),
+                    (":path", "/"),
+                    (":path", "/"),
+                ]),
+                HeaderViolations::DUPLICATE_PSEUDO_HEADER,
+            ),
+            (
+                request(&[(":method", "CONNECT")]),
+                HeaderViolations::MISSING_PSEUDO_HEADER,
+            ),
+            (
+                request(&[
+                    (":method", "CONNECT"),
+                    (":authority", "a:443"),
+                    (":path", "/"),
+                ]),
+                HeaderViolations::INVALID_PSEUDO_HEADER,
+            ),
+            (
+                request(&[
+                    (":method", "CONNECT"),
+                    (":scheme", "https"),
+                    (":authority", "a:443"),
+                ]),
+                HeaderViolations::INVALID_PSEUDO_HEADER,
+            ),
+            (
+                request(&[
+                    (":method", "CONNECT"),
+                    (":protocol", "websocket"),
+                    (":authority", "a"),
+                ]),
+                HeaderViolations::MISSING_PSEUDO_HEADER,
+            ),
+            (
+                request(&[
+                    (":method", "CONNECT"),
+                    (":protocol", "websocket"),
+                    (":scheme", "https"),
+                    (":path", "/chat"),
+                ]),
+                HeaderViolations::MISSING_PSEUDO_HEADER,
+            ),
+            (
+                request(&[
+                    (":method", "GET"),
+                    (":protocol", "websocket"),
+                    (":scheme", "https"),
+                    (":authority", "a"),
+                    (":path", "/"),
+                ]),
+                HeaderViolations::INVALID_PSEUDO_HEADER,
+            ),
+            (
+                request(&[
+                    (":method", "POST"),
+                    (":scheme", "https"),
+                    (":authority", "a"),
+                    (":path", "/"),
+                    ("content-length", "abc"),
+                ]),
+                HeaderViolations::CONTENT_LENGTH,
+            ),
+            (
+                request(&[
+                    (":method", "POST"),
+                    (":scheme", "https"),
+                    (":authority", "a"),
+                    (":path", "/"),
+                    ("content-length", "3"),
+                    ("content-length", "4"),
+                ]),
+                HeaderViolations::CONTENT_LENGTH,
+            ),
+        ]
+    }
+
+    /// Responses that break the rules for pseudo-headers.
+    fn invalid_responses() -> Vec<(Vec<Header>, HeaderViolations)> {
+        vec![
+            (
+                vec![h("content-type", "text/plain")],
+                HeaderViolations::MISSING_PSEUDO_HEADER,
+            ),
+            (
+                vec![h(":status", "2000")],
+                HeaderViolations::MISSING_PSEUDO_HEADER,
+            ),
+            (
+                vec![h(":status", "ok")],
+                HeaderViolations::MISSING_PSEUDO_HEADER,
+            ),
+            (
+                vec![h(":status", "200"), h(":status", "204")],
+                HeaderViolations::DUPLICATE_PSEUDO_HEADER,
+            ),
+            (
+                vec![h(":status", "200"), h(":path", "/")],
+                HeaderViolations::INVALID_PSEUDO_HEADER,
+            ),
+        ]
+    }
+
+    #[test]
+    fn valid_request() {
+        for headers in valid_requests() {
+            for policy in &[HeaderValidation::Strict, HeaderValidation::Lenient] {
+                assert_eq!(
+                    validate_headers(headers.clone(), MessageType::Request, *policy),
+                    Ok((headers.clone(), HeaderViolations::NONE))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_request() {
+        for (headers, violations) in invalid_requests() {
+            assert_eq!(
+                validate_headers(
+                    headers.clone(),
+                    MessageType::Request,
+                    HeaderValidation::Lenient
+                ),
+                Ok((headers.clone(), violations))
+            );
+            assert_eq!(
+                validate_headers(headers, MessageType::Request, HeaderValidation::Strict),
+                Err(Error::InvalidHeader)
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_response() {
+        for (headers, violations) in invalid_responses() {
+            assert_eq!(
+                validate_headers(
+                    headers.clone(),
+                    MessageType::Response,
+                    HeaderValidation::Lenient
+                ),
+                Ok((headers.clone(), violations))
+            );
+            assert_eq!(
+                validate_headers(headers, MessageType::Response, HeaderValidation::Strict),
+                Err(Error::InvalidHeader)
+            );
+        }
+    }
+
+    /// A valid request is not a valid response, nor the other way around.
+    #[test]
+    fn message_type_matters() {
+        let (headers, violations) = validate_headers(
+            vec![h(":status", "200")],
+            MessageType::Request,
+            HeaderValidation::Lenient,
+        )
+        .unwrap();
+        assert_eq!(headers, vec![h(":status", "200")]);
+        assert!(violations.contains(HeaderViolations::INVALID_PSEUDO_HEADER));
+        assert!(violations.contains(HeaderViolations::MISSING_PSEUDO_HEADER));
+        assert_eq!(
+            validate_headers(
+                valid_requests().remove(0),
+                MessageType::Response,
+                HeaderValidation::Strict
+            ),
+            Err(Error::InvalidHeader)
+        );
+    }
+
+    #[test]
+    fn content_length_values() {
+        assert_eq!(content_length(&[h(":status", "200")]), Ok(None));
+        assert_eq!(content_length(&[h("content-length", "0")]), Ok(Some(0)));
+        assert_eq!(
+            content_length(&[h("content-length", "7"), h("content-length", "7, 7")]),
+            Ok(Some(7))
+        );
+        assert_eq!(
+            content_length(&[h("content-length", "-1")]),
+            Err(Error::InvalidHeader)
+        );
+        assert_eq!(
+            content_length(&[h("content-length", "")]),
+            Err(Error::InvalidHeader)
+        );
+        assert_eq!(
+            content_length(&[h("content-length", "99999999999999999999999")]),
+            Err(Error::InvalidHeader)
+        );
+    }
+
+    #[test]
+    fn violations() {
+        let v = HeaderViolations::UPPERCASE_NAME | HeaderViolations::OBS_FOLD;
+        assert!(v.contains(HeaderViolations::OBS_FOLD));
+        assert!(!v.contains(HeaderViolations::VALUE_WHITESPACE));
+        assert_eq!(HeaderViolations::from_bits(v.bits()), v);
+        assert!(HeaderViolations::default().is_empty());
+    }
+}
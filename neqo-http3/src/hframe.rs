@@ -5,6 +5,7 @@
 // except according to those terms.
 
 use crate::hsettings_frame::HSettings;
+use crate::Priority;
 use neqo_common::{
     hex_with_len, qdebug, qtrace, Decoder, Encoder, IncrementalDecoder, IncrementalDecoderResult,
 };
@@ -23,10 +24,12 @@ const H3_FRAME_TYPE_SETTINGS: HFrameType = 0x4;
 const H3_FRAME_TYPE_PUSH_PROMISE: HFrameType = 0x5;
 const H3_FRAME_TYPE_GOAWAY: HFrameType = 0x7;
 const H3_FRAME_TYPE_MAX_PUSH_ID: HFrameType = 0xd;
+const H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST: HFrameType = 0xf0700;
+const H3_FRAME_TYPE_PRIORITY_UPDATE_PUSH: HFrameType = 0xf0701;
 
 // data for DATA frame is not read into HFrame::Data.
 #[derive(PartialEq, Debug)]
-pub(crate) enum HFrame {
+pub enum HFrame {
     Data {
         len: u64, // length of the data
     },
@@ -49,6 +52,14 @@ pub(crate) enum HFrame {
     MaxPushId {
         push_id: u64,
     },
+    PriorityUpdateRequest {
+        element_id: u64,
+        priority: Priority,
+    },
+    PriorityUpdatePush {
+        element_id: u64,
+        priority: Priority,
+    },
 }
 
 impl HFrame {
@@ -61,6 +72,8 @@ impl HFrame {
             Self::PushPromise { .. } => H3_FRAME_TYPE_PUSH_PROMISE,
             Self::Goaway { .. } => H3_FRAME_TYPE_GOAWAY,
             Self::MaxPushId { .. } => H3_FRAME_TYPE_MAX_PUSH_ID,
+            Self::PriorityUpdateRequest { .. } => H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST,
+            Self::PriorityUpdatePush { .. } => H3_FRAME_TYPE_PRIORITY_UPDATE_PUSH,
         }
     }
 
@@ -101,8 +114,111 @@ impl HFrame {
                     enc_inner.encode_varint(*push_id);
                 });
             }
+            Self::PriorityUpdateRequest {
+                element_id,
+                priority,
+            }
+            | Self::PriorityUpdatePush {
+                element_id,
+                priority,
+            } => {
+                enc.encode_vvec_with(|enc_inner| {
+                    enc_inner.encode_varint(*element_id);
+                    enc_inner.encode(priority.to_string().as_bytes());
+                });
+            }
         }
     }
+
+    /// Decode a frame from the start of `buf`, for looking at HTTP/3 without a connection.
+    /// This returns the frame and the number of bytes it took, or `None` if `buf` doesn't
+    /// hold all of the frame.  Only the type and length of DATA are read; the data that
+    /// follows is left for the caller.  Frames of unknown types are skipped and returned
+    /// as `None`.
+    /// # Errors
+    /// `NotEnoughData` or `HttpFrame` if the frame is malformed.
+    pub fn decode(buf: &[u8]) -> Res<Option<(Option<Self>, usize)>> {
+        let mut dec = Decoder::from(buf);
+        let (hframe_type, len) = match (dec.decode_varint(), dec.decode_varint()) {
+            (Some(t), Some(l)) => (t, l),
+            _ => return Ok(None),
+        };
+        if hframe_type == H3_FRAME_TYPE_DATA {
+            return Ok(Some((Some(Self::Data { len }), dec.offset())));
+        }
+        let payload = match dec.decode(usize::try_from(len).or(Err(Error::HttpFrame))?) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        let f = Self::decode_payload(hframe_type, len, payload)?;
+        Ok(Some((f, dec.offset())))
+    }
+
+    /// Decode the payload of a frame.  This is `None` for unknown frame types.
+    fn decode_payload(hframe_type: HFrameType, len: u64, payload: &[u8]) -> Res<Option<Self>> {
+        let mut dec = Decoder::from(payload);
+        let f = match hframe_type {
+            H3_FRAME_TYPE_DATA => Self::Data { len },
+            H3_FRAME_TYPE_HEADERS => Self::Headers {
+                header_block: dec.decode_remainder().to_vec(),
+            },
+            H3_FRAME_TYPE_CANCEL_PUSH => Self::CancelPush {
+                push_id: match dec.decode_varint() {
+                    Some(v) => v,
+                    _ => return Err(Error::NotEnoughData),
+                },
+            },
+            H3_FRAME_TYPE_SETTINGS => {
+                let mut settings = HSettings::default();
+                settings.decode_frame_contents(&mut dec)?;
+                Self::Settings { settings }
+            }
+            H3_FRAME_TYPE_PUSH_PROMISE => {
+                let push_id = match dec.decode_varint() {
+                    Some(v) => v,
+                    _ => return Err(Error::NotEnoughData),
+                };
+                Self::PushPromise {
+                    push_id,
+                    header_block: dec.decode_remainder().to_vec(),
+                }
+            }
+            H3_FRAME_TYPE_GOAWAY => Self::Goaway {
+                stream_id: match dec.decode_varint() {
+                    Some(v) => v,
+                    _ => return Err(Error::NotEnoughData),
+                },
+            },
+            H3_FRAME_TYPE_MAX_PUSH_ID => Self::MaxPushId {
+                push_id: match dec.decode_varint() {
+                    Some(v) => v,
+                    _ => return Err(Error::NotEnoughData),
+                },
+            },
+            H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST | H3_FRAME_TYPE_PRIORITY_UPDATE_PUSH => {
+                let element_id = match dec.decode_varint() {
+                    Some(v) => v,
+                    _ => return Err(Error::NotEnoughData),
+                };
+                let value =
+                    std::str::from_utf8(dec.decode_remainder()).or(Err(Error::HttpFrame))?;
+                let priority = Priority::from_field_value(value);
+                if hframe_type == H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST {
+                    Self::PriorityUpdateRequest {
+                        element_id,
+                        priority,
+                    }
+                } else {
+                    Self::PriorityUpdatePush {
+                        element_id,
+                        priority,
+                    }
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(f))
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -220,6 +336,8 @@ impl HFrameReader {
                                 | H3_FRAME_TYPE_SETTINGS
                                 | H3_FRAME_TYPE_GOAWAY
                                 | H3_FRAME_TYPE_MAX_PUSH_ID
+                                | H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST
+                                | H3_FRAME_TYPE_PRIORITY_UPDATE_PUSH
                                 | H3_FRAME_TYPE_PUSH_PROMISE
                                 | H3_FRAME_TYPE_HEADERS => {
                                     if len == 0 {
@@ -305,48 +423,9 @@ impl HFrameReader {
         }
 
         let payload = mem::replace(&mut self.payload, Vec::new());
-        let mut dec = Decoder::from(&payload[..]);
-        let f = match self.hframe_type {
-            H3_FRAME_TYPE_DATA => HFrame::Data {
-                len: self.hframe_len,
-            },
-            H3_FRAME_TYPE_HEADERS => HFrame::Headers {
-                header_block: dec.decode_remainder().to_vec(),
-            },
-            H3_FRAME_TYPE_CANCEL_PUSH => HFrame::CancelPush {
-                push_id: match dec.decode_varint() {
-                    Some(v) => v,
-                    _ => return Err(Error::NotEnoughData),
-                },
-            },
-            H3_FRAME_TYPE_SETTINGS => {
-                let mut settings = HSettings::default();
-                settings.decode_frame_contents(&mut dec)?;
-                HFrame::Settings { settings }
-            }
-            H3_FRAME_TYPE_PUSH_PROMISE => {
-                let push_id = match dec.decode_varint() {
-                    Some(v) => v,
-                    _ => return Err(Error::NotEnoughData),
-                };
-                HFrame::PushPromise {
-                    push_id,
-                    header_block: dec.decode_remainder().to_vec(),
-                }
-            }
-            H3_FRAME_TYPE_GOAWAY => HFrame::Goaway {
-                stream_id: match dec.decode_varint() {
-                    Some(v) => v,
-                    _ => return Err(Error::NotEnoughData),
-                },
-            },
-            H3_FRAME_TYPE_MAX_PUSH_ID => HFrame::MaxPushId {
-                push_id: match dec.decode_varint() {
-                    Some(v) => v,
-                    _ => return Err(Error::NotEnoughData),
-                },
-            },
-            _ => panic!("We should not be in state Done with unknown frame type!"),
+        let f = match HFrame::decode_payload(self.hframe_type, self.hframe_len, &payload)? {
+            Some(f) => f,
+            None => panic!("We should not be in state Done with unknown frame type!"),
         };
         self.reset();
         Ok(f)
@@ -357,6 +436,7 @@ impl HFrameReader {
 mod tests {
     use super::{Encoder, Error, HFrame, HFrameReader, HSettings};
     use crate::hsettings_frame::{HSetting, HSettingType};
+    use crate::Priority;
     use neqo_crypto::AuthenticationStatus;
     use neqo_transport::StreamType;
     use num_traits::Num;
@@ -444,6 +524,23 @@ mod tests {
         enc_dec(&f, "04020604", 0);
     }
 
+    #[test]
+    fn test_settings_frame_grease() {
+        let f = HFrame::Settings {
+            settings: HSettings::new(&[HSetting::new(HSettingType::Other(0x21), 1)]),
+        };
+        enc_dec(&f, "04022101", 0);
+    }
+
+    #[test]
+    fn test_settings_frame_h2_reserved() {
+        // SETTINGS_ENABLE_PUSH from HTTP/2.
+        assert_eq!(
+            HFrame::decode(&[0x4, 0x2, 0x2, 0x0]),
+            Err(Error::HttpSettings)
+        );
+    }
+
     #[test]
     fn test_push_promise_frame4() {
         let f = HFrame::PushPromise {
@@ -465,6 +562,20 @@ mod tests {
         enc_dec(&f, "0d0105", 0);
     }
 
+    #[test]
+    fn test_priority_update_frames() {
+        let f = HFrame::PriorityUpdateRequest {
+            element_id: 4,
+            priority: Priority::new(5, false),
+        };
+        enc_dec(&f, "800f07000404753d35", 0);
+        let f = HFrame::PriorityUpdatePush {
+            element_id: 2,
+            priority: Priority::new(1, true),
+        };
+        enc_dec(&f, "800f07010702753d312c2069", 0);
+    }
+
     // We have 2 code paths in frame_reader:
     // 1) All frames except DATA (here we test SETTING and SETTINGS with larger varints and PUSH_PROMISE)
     // 1) DATA
@@ -981,4 +1092,34 @@ mod tests {
         conn_s.process(out.dgram(), now());
         assert_eq!(Ok(true), fr.receive(&mut conn_s, stream_id));
     }
+
+    #[test]
+    fn decode_from_buffer() {
+        let frames = [
+            HFrame::Headers {
+                header_block: vec![0x01, 0x02, 0x03],
+            },
+            HFrame::Goaway { stream_id: 5 },
+            HFrame::Data { len: 2 },
+        ];
+        let mut enc = Encoder::default();
+        for f in &frames {
+            f.encode(&mut enc);
+        }
+        // The DATA, then an unknown frame.
+        enc.encode(&[0xaa, 0xbb]);
+        enc.encode_varint(0x21_u64).encode_vvec(&[0; 3]);
+        let buf: Vec<_> = enc.into();
+
+        let mut offset = 0;
+        for f in &frames {
+            let (decoded, used) = HFrame::decode(&buf[offset..]).unwrap().unwrap();
+            assert_eq!(decoded.as_ref(), Some(f));
+            // Nothing is decoded from a partial frame.
+            assert_eq!(HFrame::decode(&buf[offset..offset + used - 1]), Ok(None));
+            offset += used;
+        }
+        offset += 2;
+        assert_eq!(HFrame::decode(&buf[offset..]), Ok(Some((None, 5))));
+    }
 }
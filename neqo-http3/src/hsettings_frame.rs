@@ -5,7 +5,7 @@
 // except according to those terms.
 
 use crate::{Error, Res};
-use neqo_common::{Decoder, Encoder};
+use neqo_common::{matches, Decoder, Encoder};
 use std::ops::Deref;
 
 type SettingsType = u64;
@@ -13,28 +13,65 @@ type SettingsType = u64;
 const SETTINGS_MAX_HEADER_LIST_SIZE: SettingsType = 0x6;
 const SETTINGS_QPACK_MAX_TABLE_CAPACITY: SettingsType = 0x1;
 const SETTINGS_QPACK_BLOCKED_STREAMS: SettingsType = 0x7;
+const SETTINGS_ENABLE_CONNECT_PROTOCOL: SettingsType = 0x8;
+
+/// HTTP/2 settings that have no HTTP/3 equivalent; receiving one is an error.
+const SETTINGS_H2_RESERVED: &[SettingsType] = &[0x2, 0x3, 0x4, 0x5];
+
+/// Whether `id` is a setting that can't be sent in HTTP/3.
+fn is_reserved_setting(id: u64) -> bool {
+    SETTINGS_H2_RESERVED.contains(&id)
+}
+
+/// Check that an application can add a setting with identifier `id` to `extra`,
+/// the identifier and value pairs that it has already added.
+/// # Errors
+/// `InvalidState` if the setting is one that is sent here, is reserved for HTTP/2,
+/// or has already been added.
+pub(crate) fn check_extra_setting(extra: &[(u64, u64)], id: u64) -> Res<()> {
+    let known = matches!(
+        id,
+        SETTINGS_MAX_HEADER_LIST_SIZE
+            | SETTINGS_QPACK_MAX_TABLE_CAPACITY
+            | SETTINGS_QPACK_BLOCKED_STREAMS
+            | SETTINGS_ENABLE_CONNECT_PROTOCOL
+    );
+    if known || is_reserved_setting(id) || extra.iter().any(|(i, _)| *i == id) {
+        Err(Error::InvalidState)
+    } else {
+        Ok(())
+    }
+}
 
 #[derive(Clone, PartialEq, Debug, Copy)]
-pub(crate) enum HSettingType {
+pub enum HSettingType {
     MaxHeaderListSize,
     MaxTableCapacity,
     BlockedStreams,
+    /// Whether extended CONNECT can be used; 1 if it can.
+    EnableConnectProtocol,
+    /// A setting that isn't used here, such as GREASE, with its identifier.
+    Other(u64),
 }
 
 fn hsetting_default(setting_type: HSettingType) -> u64 {
     match setting_type {
         HSettingType::MaxHeaderListSize => 1 << 62,
-        HSettingType::MaxTableCapacity | HSettingType::BlockedStreams => 0,
+        HSettingType::MaxTableCapacity
+        | HSettingType::BlockedStreams
+        | HSettingType::EnableConnectProtocol
+        | HSettingType::Other(_) => 0,
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) struct HSetting {
+pub struct HSetting {
     pub setting_type: HSettingType,
     pub value: u64,
 }
 
 impl HSetting {
+    #[must_use]
     pub fn new(setting_type: HSettingType, value: u64) -> Self {
         Self {
             setting_type,
@@ -44,17 +81,20 @@ impl HSetting {
 }
 
 #[derive(Clone, Debug, Default, PartialEq)]
-pub(crate) struct HSettings {
+pub struct HSettings {
     settings: Vec<HSetting>,
 }
 
 impl HSettings {
+    #[must_use]
     pub fn new(settings: &[HSetting]) -> Self {
         Self {
             settings: settings.to_vec(),
         }
     }
 
+    /// The value of `setting`, or its default if it wasn't sent.
+    #[must_use]
     pub fn get(&self, setting: HSettingType) -> u64 {
         match self.settings.iter().find(|s| s.setting_type == setting) {
             Some(v) => v.value,
@@ -78,11 +118,22 @@ impl HSettings {
                         enc_inner.encode_varint(SETTINGS_QPACK_BLOCKED_STREAMS as u64);
                         enc_inner.encode_varint(iter.value);
                     }
+                    HSettingType::EnableConnectProtocol => {
+                        enc_inner.encode_varint(SETTINGS_ENABLE_CONNECT_PROTOCOL as u64);
+                        enc_inner.encode_varint(iter.value);
+                    }
+                    HSettingType::Other(id) => {
+                        enc_inner.encode_varint(id);
+                        enc_inner.encode_varint(iter.value);
+                    }
                 }
             }
         });
     }
 
+    /// # Errors
+    /// `NotEnoughData` if a setting is truncated and `HttpSettings` if a setting
+    /// is reserved for HTTP/2.
     pub fn decode_frame_contents(&mut self, dec: &mut Decoder) -> Res<()> {
         while dec.remaining() > 0 {
             let t = dec.decode_varint();
@@ -98,8 +149,14 @@ impl HSettings {
                 (Some(SETTINGS_QPACK_BLOCKED_STREAMS), Some(value)) => self
                     .settings
                     .push(HSetting::new(HSettingType::BlockedStreams, value)),
-                // other supported settings here
-                (Some(_), Some(_)) => {} // ignore unknown setting, it is fine.
+                (Some(SETTINGS_ENABLE_CONNECT_PROTOCOL), Some(value)) => self
+                    .settings
+                    .push(HSetting::new(HSettingType::EnableConnectProtocol, value)),
+                (Some(t), Some(_)) if is_reserved_setting(t) => return Err(Error::HttpSettings),
+                // Unknown settings are kept so that the application can see them.
+                (Some(t), Some(value)) => self
+                    .settings
+                    .push(HSetting::new(HSettingType::Other(t), value)),
                 _ => return Err(Error::NotEnoughData),
             };
         }
@@ -107,6 +164,42 @@ impl HSettings {
     }
 }
 
+/// The settings that a peer sent, with defaults for those it left out.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Settings {
+    /// SETTINGS_QPACK_MAX_TABLE_CAPACITY.
+    pub max_table_capacity: u64,
+    /// SETTINGS_QPACK_BLOCKED_STREAMS.
+    pub blocked_streams: u64,
+    /// SETTINGS_MAX_HEADER_LIST_SIZE, if the peer set a limit.
+    pub max_header_list_size: Option<u64>,
+    /// SETTINGS_ENABLE_CONNECT_PROTOCOL.
+    pub enable_connect_protocol: bool,
+    /// Every other setting, as identifier and value, in the order they were sent.
+    pub other: Vec<(u64, u64)>,
+}
+
+impl From<&HSettings> for Settings {
+    fn from(settings: &HSettings) -> Self {
+        Self {
+            max_table_capacity: settings.get(HSettingType::MaxTableCapacity),
+            blocked_streams: settings.get(HSettingType::BlockedStreams),
+            max_header_list_size: settings
+                .iter()
+                .find(|s| s.setting_type == HSettingType::MaxHeaderListSize)
+                .map(|s| s.value),
+            enable_connect_protocol: settings.get(HSettingType::EnableConnectProtocol) == 1,
+            other: settings
+                .iter()
+                .filter_map(|s| match s.setting_type {
+                    HSettingType::Other(id) => Some((id, s.value)),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+}
+
 impl Deref for HSettings {
     type Target = [HSetting];
     fn deref(&self) -> &Self::Target {
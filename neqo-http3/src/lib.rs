@@ -7,36 +7,78 @@
 #![cfg_attr(feature = "deny-warnings", deny(warnings))]
 #![warn(clippy::pedantic)]
 #![allow(clippy::pub_enum_variant_names)]
+#![cfg_attr(not(test), warn(clippy::unwrap_used))]
 
+mod capsule;
+mod churn;
 mod client_events;
 mod connection;
 pub mod connection_client;
 mod connection_server;
 mod control_stream_local;
 mod control_stream_remote;
+mod efficiency;
+mod headers;
 pub mod hframe;
 mod hsettings_frame;
+mod priority;
 mod push_controller;
+#[cfg(feature = "qlog")]
+mod qlog;
+#[cfg(feature = "client-helpers")]
+mod recording;
 mod recv_message;
+mod request_options;
 mod send_message;
 pub mod server;
 mod server_connection_events;
 mod server_events;
 mod stream_type_reader;
 
+use neqo_common::BuildInfo;
 use neqo_qpack::Error as QpackError;
-pub use neqo_transport::Output;
 use neqo_transport::{AppError, Error as TransportError};
 
-pub use client_events::Http3ClientEvent;
-pub use connection::Http3State;
-pub use connection_client::Http3Client;
+pub use capsule::CAPSULE_DATAGRAM;
+pub use churn::ChurnStats;
+pub use client_events::{Http3ClientEvent, RequestOutcome};
+pub use connection::{Http3Parameters, Http3State};
+pub use connection_client::{Http3Client, Http3Milestones};
+pub use efficiency::EfficiencyStats;
+pub use headers::{HeaderValidation, HeaderViolations, RequestValidation};
+pub use hframe::HFrame;
+pub use hsettings_frame::{HSetting, HSettingType, HSettings, Settings};
 pub use neqo_qpack::Header;
+pub use neqo_transport::{EventMask, Output};
+pub use priority::{Priority, PriorityTable};
+#[cfg(feature = "client-helpers")]
+pub use recording::{RecordedItem, RecordedServerEvent, Recording};
+pub use request_options::RequestOptions;
 pub use server::Http3Server;
 pub use server_events::Http3ServerEvent;
 
 type Res<T> = Result<T, Error>;
 
+/// The version of neqo-http3.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The ALPN token for the draft of HTTP/3 that this implements.
+pub const ALPN: &str = "h3-27";
+
+/// Details of the build of all of the neqo libraries and the NSS that is in use.
+#[must_use]
+pub fn build_info() -> BuildInfo {
+    let quic_version = format!("{:#x}", neqo_transport::QUIC_VERSION);
+    let mut info = neqo_common::build_info();
+    info.add("neqo-crypto", neqo_crypto::VERSION)
+        .add("nss", neqo_crypto::nss_version())
+        .add("neqo-transport", neqo_transport::VERSION)
+        .add("quic-version", quic_version)
+        .add("neqo-qpack", neqo_qpack::VERSION)
+        .add("neqo-http3", VERSION)
+        .add("h3-alpn", ALPN);
+    info
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Error {
     HttpNoError,
@@ -53,6 +95,7 @@ pub enum Error {
     HttpRequestRejected,
     HttpRequestCancelled,
     HttpRequestIncomplete,
+    HttpMessageError,
     HttpConnect,
     HttpVersionFallback,
     QpackError(neqo_qpack::Error),
@@ -60,17 +103,35 @@ pub enum Error {
     // Internal errors from here.
     AlreadyClosed,
     AlreadyInitialized,
+    /// All of a stream has been read, so the stream is gone.
+    AlreadyRead,
+    BodyTooLarge,
+    CapsuleTooLarge,
     DecodingFrame,
+    FrameAfterTrailers,
+    /// The connection is going away, after a GOAWAY or because it has run out of
+    /// stream IDs, so new requests have to be made on another connection.
+    GoingAway,
+    HeaderListTooLarge,
+    /// Data can't be read before the headers have been.
+    HeadersNotReady,
     HttpGoaway,
     Internal,
+    InvalidCapsule,
+    InvalidHeader,
+    InvalidRecording,
     InvalidResumptionToken,
-    InvalidStreamId,
     InvalidState,
+    InvalidUrl,
     NoMoreData,
     NotEnoughData,
+    /// The peer reset the stream with this error code.
+    StreamReset(AppError),
     TransportError(TransportError),
     Unavailable,
     Unexpected,
+    /// There is no such stream, or it ended without being read to the end.
+    UnknownStream,
 }
 
 impl Error {
@@ -91,6 +152,7 @@ impl Error {
             Self::HttpRequestRejected => 0x10b,
             Self::HttpRequestCancelled => 0x10c,
             Self::HttpRequestIncomplete => 0x10d,
+            Self::HttpMessageError => 0x10e,
             Self::HttpConnect => 0x10f,
             Self::HttpVersionFallback => 0x110,
             Self::QpackError(e) => e.code(),
@@ -112,6 +174,13 @@ impl From<QpackError> for Error {
     }
 }
 
+#[cfg(feature = "qlog")]
+impl From<::qlog::Error> for Error {
+    fn from(_err: ::qlog::Error) -> Self {
+        Self::TransportError(TransportError::QlogError)
+    }
+}
+
 impl From<AppError> for Error {
     fn from(error: AppError) -> Self {
         match error {
@@ -128,6 +197,7 @@ impl From<AppError> for Error {
             0x10b => Self::HttpRequestRejected,
             0x10c => Self::HttpRequestCancelled,
             0x10d => Self::HttpRequestIncomplete,
+            0x10e => Self::HttpMessageError,
             0x10f => Self::HttpConnect,
             0x110 => Self::HttpVersionFallback,
             0x200 => Self::QpackError(QpackError::DecompressionFailed),
@@ -0,0 +1,212 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Priorities of requests and pushes, as in the extensible priority scheme
+// (draft-ietf-httpbis-priority).
+
+use neqo_transport::TransmissionPriority;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+/// The priority of a request or push: an urgency from 0 (most urgent) to 7, and
+/// whether the response can be used as it arrives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Priority {
+    urgency: u8,
+    incremental: bool,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self {
+            urgency: 3,
+            incremental: false,
+        }
+    }
+}
+
+impl Priority {
+    /// # Panics
+    /// If `urgency` is more than 7.
+    #[must_use]
+    pub fn new(urgency: u8, incremental: bool) -> Self {
+        assert!(urgency < 8);
+        Self {
+            urgency,
+            incremental,
+        }
+    }
+
+    #[must_use]
+    pub fn urgency(self) -> u8 {
+        self.urgency
+    }
+
+    #[must_use]
+    pub fn incremental(self) -> bool {
+        self.incremental
+    }
+
+    /// Read a Priority Field Value, like "u=2, i".  Parameters that aren't understood
+    /// are ignored, as are invalid values.
+    #[must_use]
+    pub fn from_field_value(value: &str) -> Self {
+        let mut priority = Self::default();
+        for param in value.split(',') {
+            let mut kv = param.trim().splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("u"), Some(u)) => {
+                    if let Ok(u) = u.parse::<u8>() {
+                        if u < 8 {
+                            priority.urgency = u;
+                        }
+                    }
+                }
+                (Some("i"), None) | (Some("i"), Some("?1")) => priority.incremental = true,
+                (Some("i"), Some("?0")) => priority.incremental = false,
+                _ => {}
+            }
+        }
+        priority
+    }
+
+    /// The band that the transport sends a stream with this priority in.
+    pub(crate) fn transmission_priority(self) -> TransmissionPriority {
+        match self.urgency {
+            0..=2 => TransmissionPriority::High,
+            3 => TransmissionPriority::Normal,
+            _ => TransmissionPriority::Low,
+        }
+    }
+}
+
+/// This is the Priority Field Value, with the defaults left out.
+impl Display for Priority {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let default = Self::default();
+        let mut sep = "";
+        if self.urgency != default.urgency {
+            write!(f, "u={}", self.urgency)?;
+            sep = ", ";
+        }
+        if self.incremental {
+            write!(f, "{}i", sep)?;
+        }
+        Ok(())
+    }
+}
+
+/// The priorities that have been set with PRIORITY_UPDATE.  A push has the
+/// priority of the request that promised it.  A client keeps priorities for the
+/// life of the connection, so that pushes can still inherit them once the request
+/// is complete.  A server only keeps them for requests that haven't started, which
+/// the stream limit bounds.  The server doesn't push, so it doesn't keep any for
+/// pushes.
+#[derive(Debug, Default)]
+pub struct PriorityTable {
+    requests: HashMap<u64, Priority>,
+}
+
+impl PriorityTable {
+    pub(crate) fn set_request(&mut self, stream_id: u64, priority: Priority) {
+        self.requests.insert(stream_id, priority);
+    }
+
+    /// Take the priority for a request that has just started on `stream_id`.  Any
+    /// for earlier streams are dropped: those requests have started already, or
+    /// never will.
+    pub(crate) fn take_request(&mut self, stream_id: u64) -> Option<Priority> {
+        let priority = self.requests.remove(&stream_id);
+        self.requests.retain(|id, _| *id > stream_id);
+        priority
+    }
+
+    /// The priority of the request on `stream_id`, if it was set.
+    #[must_use]
+    pub fn request(&self, stream_id: u64) -> Option<Priority> {
+        self.requests.get(&stream_id).copied()
+    }
+
+    /// The priority of a push that was promised on the request on `stream_id`.
+    #[must_use]
+    pub fn push(&self, stream_id: u64) -> Priority {
+        self.request(stream_id).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Priority, PriorityTable};
+    use neqo_transport::TransmissionPriority;
+
+    #[test]
+    fn field_value() {
+        for (value, priority) in &[
+            ("", Priority::default()),
+            ("u=3", Priority::default()),
+            ("u=0", Priority::new(0, false)),
+            ("u=7, i", Priority::new(7, true)),
+            ("i=?1,u=1", Priority::new(1, true)),
+            ("u=5, i=?0", Priority::new(5, false)),
+            ("u=8, x=1", Priority::default()),
+            ("u=-1, i=3", Priority::default()),
+        ] {
+            assert_eq!(Priority::from_field_value(value), *priority);
+        }
+        for priority in &[
+            Priority::default(),
+            Priority::new(0, true),
+            Priority::new(6, false),
+        ] {
+            assert_eq!(Priority::from_field_value(&priority.to_string()), *priority);
+        }
+        assert_eq!(Priority::new(1, true).to_string(), "u=1, i");
+        assert_eq!(Priority::default().to_string(), "");
+    }
+
+    #[test]
+    fn transmission_priority() {
+        assert_eq!(
+            Priority::new(0, false).transmission_priority(),
+            TransmissionPriority::High
+        );
+        assert_eq!(
+            Priority::default().transmission_priority(),
+            TransmissionPriority::Normal
+        );
+        assert_eq!(
+            Priority::new(7, true).transmission_priority(),
+            TransmissionPriority::Low
+        );
+    }
+
+    #[test]
+    fn push_inherits() {
+        let mut table = PriorityTable::default();
+        assert_eq!(table.push(0), Priority::default());
+
+        table.set_request(0, Priority::new(1, false));
+        assert_eq!(table.push(0), Priority::new(1, false));
+        table.set_request(0, Priority::new(2, true));
+        assert_eq!(table.push(0), Priority::new(2, true));
+        assert_eq!(table.push(4), Priority::default());
+    }
+
+    #[test]
+    fn take_request() {
+        let mut table = PriorityTable::default();
+        for id in &[0, 4, 8, 12] {
+            table.set_request(*id, Priority::new(1, false));
+        }
+        assert_eq!(table.take_request(8), Some(Priority::new(1, false)));
+        // The priorities for 0 and 4 are gone too.
+        assert_eq!(table.take_request(4), None);
+        assert_eq!(table.request(0), None);
+        assert_eq!(table.request(12), Some(Priority::new(1, false)));
+        assert_eq!(table.take_request(16), None);
+        assert_eq!(table.request(12), None);
+    }
+}
@@ -4,36 +4,344 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use crate::{Error, Res};
-use neqo_common::qtrace;
-use std::fmt::Debug;
+// The client side of server push: which pushes have been promised, and which
+// push streams carry them.
+
+#![allow(clippy::module_name_repetitions)]
+
+use crate::client_events::Http3ClientEvents;
+#[cfg(feature = "qlog")]
+use crate::qlog;
+use crate::recv_message::RecvMessageEvents;
+use crate::stream_type_reader::NewStreamTypeReader;
+use crate::{Error, Header, HeaderViolations, Res};
+use neqo_common::{qinfo, qtrace};
+use neqo_transport::{AppError, Connection};
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::mem;
 
+/// Where a push is up to.  A push is promised with PUSH_PROMISE on one or more
+/// request streams and its response arrives on a push stream, in either order.
 #[derive(Debug)]
-pub(crate) struct PushController {}
+enum PushState {
+    /// The push has been promised, but its stream hasn't arrived.
+    Promised { headers: Vec<Header> },
+    /// The push stream arrived before any promise, so it isn't read yet.
+    StreamOnly { stream_id: u64 },
+    /// The push has been promised and its stream is being read.
+    Active {
+        stream_id: u64,
+        headers: Vec<Header>,
+    },
+    /// The push was canceled or read to the end.  Later promises are ignored.
+    Closed,
+}
 
-impl PushController {
-    pub fn new() -> Self {
-        PushController {}
-    }
+#[derive(Debug)]
+pub(crate) struct PushController {
+    /// How many pushes the server can make, which is 0 unless `set_max_push` is called.
+    max_push: u64,
+    pushes: HashMap<u64, PushState>,
+    /// Push streams whose push ID hasn't been read yet.
+    new_streams: HashMap<u64, NewStreamTypeReader>,
+    /// Push streams that can be read now that their push has been promised, with
+    /// their push IDs.
+    ready: Vec<(u64, u64)>,
+    events: Http3ClientEvents,
 }
 
 impl Display for PushController {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(f, "Push controler")
+        write!(f, "Push controller")
     }
 }
 
 impl PushController {
-    #[allow(clippy::needless_pass_by_value)]
-    pub fn new_push_promise(&self, push_id: u64, header_block: Vec<u8>) -> Res<()> {
+    pub fn new(events: Http3ClientEvents) -> Self {
+        Self {
+            max_push: 0,
+            pushes: HashMap::new(),
+            new_streams: HashMap::new(),
+            ready: Vec::new(),
+            events,
+        }
+    }
+
+    /// Allow push IDs below `max_push`.
+    pub fn set_max_push(&mut self, max_push: u64) {
+        self.max_push = max_push;
+    }
+
+    fn check_push_id(&self, push_id: u64) -> Res<()> {
+        if push_id < self.max_push {
+            Ok(())
+        } else {
+            qinfo!(
+                [self],
+                "Push ID {} is over the limit of {}.",
+                push_id,
+                self.max_push
+            );
+            Err(Error::HttpId)
+        }
+    }
+
+    /// A PUSH_PROMISE frame on `request_stream_id`.  The same push can be promised
+    /// on more than one request, but each promise has to have the same headers.
+    #[cfg_attr(not(feature = "qlog"), allow(unused_variables))]
+    pub fn new_push_promise(
+        &mut self,
+        conn: &mut Connection,
+        push_id: u64,
+        request_stream_id: u64,
+        headers: Vec<Header>,
+    ) -> Res<()> {
         qtrace!(
             [self],
-            "New push promise push_id={} header_block={:?}",
+            "New push promise push_id={} request_stream_id={} headers={:?}",
             push_id,
-            header_block
+            request_stream_id,
+            headers
         );
-        qtrace!("A new push promise {} {:?}", push_id, header_block);
-        Err(Error::HttpId)
+        self.check_push_id(push_id)?;
+        match self.pushes.get_mut(&push_id) {
+            None => {
+                self.pushes.insert(
+                    push_id,
+                    PushState::Promised {
+                        headers: headers.clone(),
+                    },
+                );
+            }
+            Some(PushState::Promised { headers: promised })
+            | Some(PushState::Active {
+                headers: promised, ..
+            }) => {
+                if *promised != headers {
+                    return Err(Error::HttpGeneralProtocol);
+                }
+            }
+            Some(state) => {
+                if let PushState::StreamOnly { stream_id } = *state {
+                    self.ready.push((push_id, stream_id));
+                    *state = PushState::Active {
+                        stream_id,
+                        headers: headers.clone(),
+                    };
+                } else {
+                    return Ok(());
+                }
+            }
+        }
+        #[cfg(feature = "qlog")]
+        qlog::h3_push_promised(conn.qlog_mut(), push_id, request_stream_id)?;
+        self.events
+            .push_promise(push_id, request_stream_id, headers);
+        Ok(())
+    }
+
+    /// A new push stream, once its type has been read.  Its push ID comes next.
+    #[cfg_attr(not(feature = "qlog"), allow(unused_variables))]
+    pub fn new_stream(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
+        if self.max_push == 0 {
+            qinfo!(
+                [self],
+                "Push stream {} when pushes are not allowed.",
+                stream_id
+            );
+            return Err(Error::HttpId);
+        }
+        self.new_streams
+            .insert(stream_id, NewStreamTypeReader::new());
+        #[cfg(feature = "qlog")]
+        qlog::h3_push_stream(conn.qlog_mut(), stream_id)?;
+        Ok(())
+    }
+
+    /// Whether `stream_id` is a push stream whose push ID hasn't been read.
+    pub fn is_new_stream(&self, stream_id: u64) -> bool {
+        self.new_streams.contains_key(&stream_id)
+    }
+
+    /// Read the push ID from a new push stream.  The stream can be read once its
+    /// push has been promised, see `take_ready`.
+    pub fn read_push_id(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
+        let reader = match self.new_streams.get_mut(&stream_id) {
+            Some(reader) => reader,
+            None => return Ok(()),
+        };
+        let push_id = reader.get_type(conn, stream_id);
+        if reader.fin() {
+            self.new_streams.remove(&stream_id);
+            return Ok(());
+        }
+        let push_id = match push_id {
+            Some(push_id) => push_id,
+            None => return Ok(()),
+        };
+        self.new_streams.remove(&stream_id);
+        self.check_push_id(push_id)?;
+        qinfo!([self], "Push {} is on stream {}.", push_id, stream_id);
+        match self.pushes.get_mut(&push_id) {
+            None => {
+                self.pushes
+                    .insert(push_id, PushState::StreamOnly { stream_id });
+            }
+            Some(state) => match state {
+                PushState::Promised { headers } => {
+                    let headers = mem::take(headers);
+                    *state = PushState::Active { stream_id, headers };
+                    self.ready.push((push_id, stream_id));
+                }
+                PushState::StreamOnly { .. } | PushState::Active { .. } => {
+                    return Err(Error::HttpId);
+                }
+                PushState::Closed => {
+                    let _ = conn.stream_stop_sending(stream_id, Error::HttpRequestCancelled.code());
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Take the push streams that can be read now, with their push IDs.
+    pub fn take_ready(&mut self) -> Vec<(u64, u64)> {
+        mem::take(&mut self.ready)
+    }
+
+    /// The stream that carries a push that has been promised.
+    pub fn stream_id(&self, push_id: u64) -> Option<u64> {
+        match self.pushes.get(&push_id) {
+            Some(PushState::Active { stream_id, .. }) => Some(*stream_id),
+            _ => None,
+        }
+    }
+
+    /// The push on `stream_id`, if that is a push stream.
+    fn push_id(&self, stream_id: u64) -> Option<u64> {
+        self.pushes.iter().find_map(|(push_id, state)| match state {
+            PushState::StreamOnly { stream_id: id } | PushState::Active { stream_id: id, .. }
+                if *id == stream_id =>
+            {
+                Some(*push_id)
+            }
+            _ => None,
+        })
+    }
+
+    /// A push has been read to the end.
+    #[cfg_attr(not(feature = "qlog"), allow(unused_variables))]
+    pub fn close(&mut self, conn: &mut Connection, push_id: u64) -> Res<()> {
+        if let Some(state) = self.pushes.get_mut(&push_id) {
+            if let PushState::Active { stream_id, .. } = mem::replace(state, PushState::Closed) {
+                #[cfg(feature = "qlog")]
+                qlog::h3_push_resolved(conn.qlog_mut(), push_id, Some(stream_id), true)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The application cancels a push.  This returns the push stream if it has
+    /// arrived.
+    #[cfg_attr(not(feature = "qlog"), allow(unused_variables))]
+    pub fn cancel(&mut self, conn: &mut Connection, push_id: u64) -> Res<Option<u64>> {
+        qinfo!([self], "Cancel push {}.", push_id);
+        let state = self.pushes.get_mut(&push_id).ok_or(Error::UnknownStream)?;
+        let stream_id = match state {
+            PushState::Promised { .. } => None,
+            PushState::StreamOnly { stream_id } | PushState::Active { stream_id, .. } => {
+                Some(*stream_id)
+            }
+            PushState::Closed => return Err(Error::UnknownStream),
+        };
+        *state = PushState::Closed;
+        #[cfg(feature = "qlog")]
+        qlog::h3_push_resolved(conn.qlog_mut(), push_id, stream_id, false)?;
+        Ok(stream_id)
+    }
+
+    /// The server canceled a push with CANCEL_PUSH.  This returns the push stream
+    /// if it has arrived.
+    pub fn canceled_by_server(&mut self, conn: &mut Connection, push_id: u64) -> Res<Option<u64>> {
+        self.check_push_id(push_id)?;
+        self.cancel_internal(conn, push_id)
+    }
+
+    /// The server reset `stream_id`.  This returns true if it was a push stream.
+    pub fn stream_reset(&mut self, conn: &mut Connection, stream_id: u64) -> Res<bool> {
+        if self.new_streams.remove(&stream_id).is_some() {
+            return Ok(true);
+        }
+        if let Some(push_id) = self.push_id(stream_id) {
+            let _ = self.cancel_internal(conn, push_id)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    #[cfg_attr(not(feature = "qlog"), allow(unused_variables))]
+    fn cancel_internal(&mut self, conn: &mut Connection, push_id: u64) -> Res<Option<u64>> {
+        let stream_id = match self.pushes.insert(push_id, PushState::Closed) {
+            Some(PushState::Promised { .. }) => {
+                self.events.push_canceled(push_id);
+                None
+            }
+            Some(PushState::Active { stream_id, .. }) => {
+                self.events.push_canceled(push_id);
+                Some(stream_id)
+            }
+            Some(PushState::StreamOnly { stream_id }) => Some(stream_id),
+            None | Some(PushState::Closed) => return Ok(None),
+        };
+        #[cfg(feature = "qlog")]
+        qlog::h3_push_resolved(conn.qlog_mut(), push_id, stream_id, false)?;
+        Ok(stream_id)
     }
 }
+
+/// The events for the response on a push stream, which are reported by push ID.
+#[derive(Debug)]
+pub(crate) struct PushStreamEvents {
+    push_id: u64,
+    events: Http3ClientEvents,
+}
+
+impl PushStreamEvents {
+    pub fn new(push_id: u64, events: Http3ClientEvents) -> Self {
+        Self { push_id, events }
+    }
+}
+
+impl RecvMessageEvents for PushStreamEvents {
+    fn header_ready(&self, _stream_id: u64, headers: Option<Vec<Header>>, fin: bool) {
+        self.events.push_header_ready(self.push_id, headers, fin);
+    }
+
+    // A pushed response is only delivered once the final headers arrive.
+    fn interim_headers(&self, _stream_id: u64, _headers: Vec<Header>) {}
+
+    // Repaired headers are delivered without saying what was wrong with them.
+    fn header_violations(&self, _stream_id: u64, _violations: HeaderViolations) {}
+
+    // The trailers of a push are not delivered.
+    fn trailer_ready(&self, _stream_id: u64, _trailers: Vec<Header>) {}
+
+    fn data_readable(&self, _stream_id: u64) {
+        self.events.push_data_readable(self.push_id);
+    }
+
+    fn reset(&self, _stream_id: u64, _error: AppError) {
+        self.events.push_canceled(self.push_id);
+    }
+
+    fn local_reset(&self, _stream_id: u64, _error: AppError) {
+        self.events.push_canceled(self.push_id);
+    }
+
+    fn complete(&self, _stream_id: u64) {}
+
+    // Capsules are only read on extended CONNECT streams.
+    fn capsule(&self, _stream_id: u64, _capsule_type: u64, _payload: Vec<u8>) {}
+}
@@ -0,0 +1,210 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Functions that handle capturing QLOG traces.
+
+use crate::connection::HTTP3_UNI_STREAM_TYPE_PUSH;
+use crate::control_stream_local::HTTP3_UNI_STREAM_TYPE_CONTROL;
+use crate::efficiency::EfficiencyStats;
+use crate::hframe::HFrame;
+use crate::hsettings_frame::HSettingType;
+use crate::Res;
+use neqo_common::qlog::NeqoQlog;
+use neqo_qpack::decoder::QPACK_UNI_STREAM_TYPE_DECODER;
+use neqo_qpack::encoder::QPACK_UNI_STREAM_TYPE_ENCODER;
+use qlog::{
+    event::Event, EventCategory, EventData, EventType, GenericEventType, H3Owner, H3PushDecision,
+    H3StreamType, Http3EventType, Http3Frame, Setting,
+};
+
+/// A unidirectional stream that was opened, or one whose type has been read.
+/// Streams of unknown types aren't recorded.
+pub fn h3_stream_type_set(
+    qlog: &mut Option<NeqoQlog>,
+    stream_id: u64,
+    local: bool,
+    stream_type: u64,
+) -> Res<()> {
+    if let Some(qlog) = qlog {
+        let new = match stream_type {
+            HTTP3_UNI_STREAM_TYPE_CONTROL => H3StreamType::Control,
+            HTTP3_UNI_STREAM_TYPE_PUSH => H3StreamType::Push,
+            QPACK_UNI_STREAM_TYPE_ENCODER => H3StreamType::QpackEncode,
+            QPACK_UNI_STREAM_TYPE_DECODER => H3StreamType::QpackDecode,
+            _ => return Ok(()),
+        };
+        qlog.stream().add_event(Event::h3_stream_type_set(
+            stream_id.to_string(),
+            Some(if local {
+                H3Owner::Local
+            } else {
+                H3Owner::Remote
+            }),
+            None,
+            new,
+        ))?;
+    }
+    Ok(())
+}
+
+pub fn h3_frame_created(qlog: &mut Option<NeqoQlog>, stream_id: u64, frame: &HFrame) -> Res<()> {
+    if let Some(qlog) = qlog {
+        qlog.stream().add_event(Event::h3_frame_created(
+            stream_id.to_string(),
+            frame_to_qlogframe(frame),
+            data_length(frame),
+            None,
+        ))?;
+    }
+    Ok(())
+}
+
+pub fn h3_frame_parsed(qlog: &mut Option<NeqoQlog>, stream_id: u64, frame: &HFrame) -> Res<()> {
+    if let Some(qlog) = qlog {
+        qlog.stream().add_event(Event::h3_frame_parsed(
+            stream_id.to_string(),
+            frame_to_qlogframe(frame),
+            data_length(frame),
+            None,
+        ))?;
+    }
+    Ok(())
+}
+
+/// There is no qlog event for a promise, so it is a message with the IDs.
+pub fn h3_push_promised(
+    qlog: &mut Option<NeqoQlog>,
+    push_id: u64,
+    request_stream_id: u64,
+) -> Res<()> {
+    if let Some(qlog) = qlog {
+        qlog.stream().add_event(Event {
+            category: EventCategory::Http,
+            ty: EventType::GenericEventType(GenericEventType::Message),
+            data: EventData::Message {
+                message: format!("push {} promised on stream {}", push_id, request_stream_id),
+            },
+        })?;
+    }
+    Ok(())
+}
+
+/// There is no qlog event for a push stream before its push ID is read either.
+pub fn h3_push_stream(qlog: &mut Option<NeqoQlog>, stream_id: u64) -> Res<()> {
+    if let Some(qlog) = qlog {
+        qlog.stream().add_event(Event {
+            category: EventCategory::Http,
+            ty: EventType::GenericEventType(GenericEventType::Message),
+            data: EventData::Message {
+                message: format!("push stream {}", stream_id),
+            },
+        })?;
+    }
+    Ok(())
+}
+
+/// A push that was read to the end is claimed; one that was canceled, by either
+/// side, is abandoned.
+pub fn h3_push_resolved(
+    qlog: &mut Option<NeqoQlog>,
+    push_id: u64,
+    stream_id: Option<u64>,
+    claimed: bool,
+) -> Res<()> {
+    if let Some(qlog) = qlog {
+        qlog.stream().add_event(Event {
+            category: EventCategory::Http,
+            ty: EventType::Http3EventType(Http3EventType::PushResolved),
+            data: EventData::H3PushResolved {
+                push_id: Some(push_id.to_string()),
+                stream_id: stream_id.map(|id| id.to_string()),
+                decision: Some(if claimed {
+                    H3PushDecision::Claimed
+                } else {
+                    H3PushDecision::Abandoned
+                }),
+            },
+        })?;
+    }
+    Ok(())
+}
+
+/// There is no qlog event for this, so it is a message with the numbers.
+pub fn efficiency_summary(qlog: &mut Option<NeqoQlog>, stats: &EfficiencyStats) -> Res<()> {
+    if let Some(qlog) = qlog {
+        qlog.stream().add_event(Event {
+            category: EventCategory::Info,
+            ty: EventType::GenericEventType(GenericEventType::Message),
+            data: EventData::Message {
+                message: format!("efficiency: {}", stats),
+            },
+        })?;
+    }
+    Ok(())
+}
+
+/// There is no qlog event for reaching a milestone, so it is a message with the name.
+pub fn milestone(qlog: &mut Option<NeqoQlog>, name: &str) -> Res<()> {
+    if let Some(qlog) = qlog {
+        qlog.stream().add_event(Event {
+            category: EventCategory::Info,
+            ty: EventType::GenericEventType(GenericEventType::Message),
+            data: EventData::Message {
+                message: format!("milestone {}", name),
+            },
+        })?;
+    }
+    Ok(())
+}
+
+// Helper functions
+
+/// Header blocks are recorded without their fields, which QPACK has yet to encode
+/// or decode when a frame is created or parsed.  PRIORITY_UPDATE has no qlog frame.
+fn frame_to_qlogframe(frame: &HFrame) -> Http3Frame {
+    match frame {
+        HFrame::Data { .. } => Http3Frame::data(None),
+        HFrame::Headers { .. } => Http3Frame::headers(Vec::new()),
+        HFrame::CancelPush { push_id } => Http3Frame::cancel_push(push_id.to_string()),
+        HFrame::Settings { settings } => Http3Frame::settings(
+            settings
+                .iter()
+                .map(|s| Setting {
+                    name: setting_name(s.setting_type),
+                    value: s.value.to_string(),
+                })
+                .collect(),
+        ),
+        HFrame::PushPromise { push_id, .. } => {
+            Http3Frame::push_promise(push_id.to_string(), Vec::new())
+        }
+        HFrame::Goaway { stream_id } => Http3Frame::goaway(stream_id.to_string()),
+        HFrame::MaxPushId { push_id } => Http3Frame::max_push_id(push_id.to_string()),
+        HFrame::PriorityUpdateRequest { .. } | HFrame::PriorityUpdatePush { .. } => {
+            Http3Frame::unknown()
+        }
+    }
+}
+
+/// The length of the data that follows a DATA frame header, which isn't in the frame.
+fn data_length(frame: &HFrame) -> Option<String> {
+    if let HFrame::Data { len } = frame {
+        Some(len.to_string())
+    } else {
+        None
+    }
+}
+
+/// Settings that have no name are recorded by their identifier.
+fn setting_name(setting_type: HSettingType) -> String {
+    match setting_type {
+        HSettingType::MaxHeaderListSize => "SETTINGS_MAX_HEADER_LIST_SIZE".to_string(),
+        HSettingType::MaxTableCapacity => "SETTINGS_QPACK_MAX_TABLE_CAPACITY".to_string(),
+        HSettingType::BlockedStreams => "SETTINGS_QPACK_BLOCKED_STREAMS".to_string(),
+        HSettingType::EnableConnectProtocol => "SETTINGS_ENABLE_CONNECT_PROTOCOL".to_string(),
+        HSettingType::Other(id) => format!("{:#x}", id),
+    }
+}
@@ -0,0 +1,1203 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Recording what an `Http3Client` or `Http3Server` hands to the application, so
+// that application code can be tested against an exact sequence without running
+// a connection.
+
+use crate::client_events::{Http3ClientEvent, RequestOutcome};
+use crate::connection::Http3State;
+use crate::hsettings_frame::Settings;
+use crate::server_events::Http3ServerEvent;
+use crate::{Error, Header, HeaderViolations, Res};
+use neqo_common::hex;
+use neqo_transport::{AppError, CloseError, CongestionState, FlowControlStall};
+
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter, Write};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// One thing that the application saw.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordedItem {
+    /// An event, as returned by `Http3Client::next_event` or `Http3Client::events`.
+    Event(Http3ClientEvent),
+    /// A successful call to `Http3Client::read_response_data`.
+    Read {
+        stream_id: u64,
+        data: Vec<u8>,
+        fin: bool,
+    },
+    /// An event, as returned by `Http3Server::next_event` or `Http3Server::events`.
+    Server(RecordedServerEvent),
+}
+
+/// An `Http3ServerEvent` without the request or connection that it is about,
+/// which can't be recorded.  Requests are known by their stream ID alone, so a
+/// recording of a server with more than one client can be ambiguous.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordedServerEvent {
+    Headers {
+        stream_id: u64,
+        headers: Option<Vec<Header>>,
+        fin: bool,
+    },
+    HeaderViolations {
+        stream_id: u64,
+        violations: HeaderViolations,
+    },
+    Trailers {
+        stream_id: u64,
+        trailers: Vec<Header>,
+    },
+    Data {
+        stream_id: u64,
+        data: Vec<u8>,
+        fin: bool,
+    },
+    Reset {
+        stream_id: u64,
+        error: AppError,
+    },
+    SettingsReceived(Settings),
+    StateChange(Http3State),
+    CongestionStateChanged(CongestionState),
+}
+
+impl From<&Http3ServerEvent> for RecordedServerEvent {
+    fn from(event: &Http3ServerEvent) -> Self {
+        match event {
+            Http3ServerEvent::Headers {
+                request,
+                headers,
+                fin,
+            } => Self::Headers {
+                stream_id: request.stream_id(),
+                headers: headers.clone(),
+                fin: *fin,
+            },
+            Http3ServerEvent::HeaderViolations {
+                request,
+                violations,
+            } => Self::HeaderViolations {
+                stream_id: request.stream_id(),
+                violations: *violations,
+            },
+            Http3ServerEvent::Trailers { request, trailers } => Self::Trailers {
+                stream_id: request.stream_id(),
+                trailers: trailers.clone(),
+            },
+            Http3ServerEvent::Data { request, data, fin } => Self::Data {
+                stream_id: request.stream_id(),
+                data: data.clone(),
+                fin: *fin,
+            },
+            Http3ServerEvent::Reset { request, error } => Self::Reset {
+                stream_id: request.stream_id(),
+                error: *error,
+            },
+            Http3ServerEvent::SettingsReceived { settings, .. } => {
+                Self::SettingsReceived(settings.clone())
+            }
+            Http3ServerEvent::StateChange { state, .. } => Self::StateChange(state.clone()),
+            Http3ServerEvent::CongestionStateChanged { state, .. } => {
+                Self::CongestionStateChanged(*state)
+            }
+        }
+    }
+}
+
+/// Events and reads in the order that the application saw them.
+///
+/// The text form has one item per line; lines that are empty or start with `#`
+/// are ignored.  Stream IDs and error codes are decimal, flags are `0` or `1`,
+/// data is hex (or `-` if empty), and header fields are `name=value` with
+/// spaces, `%`, `=` in names, and anything that isn't printable ASCII
+/// percent-encoded.  A header list of `-` is `None`, as is a stream ID of `-`
+/// where one is optional.  Durations are in microseconds.  The stream ID of an
+/// event is followed by `/` and the `user_data` of the request, if that isn't 0.
+/// Server events start with `server`.
+///
+/// ```text
+/// headers 0 0 :status=200 content-length=3
+/// readable 0
+/// read 0 1 616263
+/// server headers 0 1 :method=GET :scheme=https :authority=example.com :path=/
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    items: Vec<RecordedItem>,
+}
+
+impl Recording {
+    #[must_use]
+    pub fn items(&self) -> &[RecordedItem] {
+        &self.items
+    }
+
+    pub(crate) fn event(&mut self, event: &Http3ClientEvent) {
+        self.items.push(RecordedItem::Event(event.clone()));
+    }
+
+    pub(crate) fn read(&mut self, stream_id: u64, data: &[u8], fin: bool) {
+        self.items.push(RecordedItem::Read {
+            stream_id,
+            data: data.to_vec(),
+            fin,
+        });
+    }
+
+    pub(crate) fn server_event(&mut self, event: &Http3ServerEvent) {
+        self.items.push(RecordedItem::Server(event.into()));
+    }
+}
+
+impl Display for Recording {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for item in &self.items {
+            writeln!(f, "{}", item)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Recording {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Res<Self> {
+        let items = s
+            .lines()
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(str::parse)
+            .collect::<Res<_>>()?;
+        Ok(Self { items })
+    }
+}
+
+fn escape(f: &mut Formatter, s: &str, reserved: &[u8]) -> fmt::Result {
+    for &b in s.as_bytes() {
+        if b.is_ascii_graphic() && b != b'%' && !reserved.contains(&b) {
+            f.write_char(char::from(b))?;
+        } else {
+            write!(f, "%{:02x}", b)?;
+        }
+    }
+    Ok(())
+}
+
+/// Header fields as they are written in a recording, each after a space.
+fn write_headers(f: &mut Formatter, headers: &[Header]) -> fmt::Result {
+    for (name, value) in headers {
+        f.write_char(' ')?;
+        escape(f, name, b"=")?;
+        f.write_char('=')?;
+        escape(f, value, &[])?;
+    }
+    Ok(())
+}
+
+/// Header fields that might be `None`.
+fn write_optional_headers(f: &mut Formatter, headers: &Option<Vec<Header>>) -> fmt::Result {
+    match headers {
+        None => write!(f, " -"),
+        Some(headers) => write_headers(f, headers),
+    }
+}
+
+/// Data as it is written in a recording.
+fn data_word(data: &[u8]) -> String {
+    if data.is_empty() {
+        String::from("-")
+    } else {
+        hex(data)
+    }
+}
+
+/// A stream ID in an event, with the `user_data` of the request.
+struct StreamWord(u64, u64);
+
+impl Display for StreamWord {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.1 == 0 {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "{}/{}", self.0, self.1)
+        }
+    }
+}
+
+fn write_close_error(f: &mut Formatter, err: CloseError) -> fmt::Result {
+    match err {
+        CloseError::Transport(c) => write!(f, "transport {}", c),
+        CloseError::Application(c) => write!(f, "application {}", c),
+    }
+}
+
+fn write_congestion_state(f: &mut Formatter, state: CongestionState) -> fmt::Result {
+    match state {
+        CongestionState::NoCongestion => write!(f, "none"),
+        CongestionState::Recovery => write!(f, "recovery"),
+        CongestionState::PersistentCongestion => write!(f, "persistent"),
+    }
+}
+
+fn write_state(f: &mut Formatter, state: &Http3State) -> fmt::Result {
+    match state {
+        Http3State::Initializing => write!(f, "initializing"),
+        Http3State::ZeroRtt => write!(f, "zero-rtt"),
+        Http3State::Connected => write!(f, "connected"),
+        Http3State::GoingAway(id) => write!(f, "going-away {}", id),
+        Http3State::Closing(err) => {
+            write!(f, "closing ")?;
+            write_close_error(f, *err)
+        }
+        Http3State::Closed(err) => {
+            write!(f, "closed ")?;
+            write_close_error(f, *err)
+        }
+    }
+}
+
+/// Settings are written as the QPACK table capacity, the number of blocked
+/// streams, the header list limit (or `-`), whether extended CONNECT is enabled,
+/// and then any other settings as `identifier=value`.
+fn write_settings(f: &mut Formatter, settings: &Settings) -> fmt::Result {
+    write!(
+        f,
+        "{} {} ",
+        settings.max_table_capacity, settings.blocked_streams
+    )?;
+    match settings.max_header_list_size {
+        Some(limit) => write!(f, "{}", limit)?,
+        None => write!(f, "-")?,
+    }
+    write!(f, " {}", u8::from(settings.enable_connect_protocol))?;
+    for (id, value) in &settings.other {
+        write!(f, " {}={}", id, value)?;
+    }
+    Ok(())
+}
+
+impl Display for RecordedServerEvent {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Headers {
+                stream_id,
+                headers,
+                fin,
+            } => {
+                write!(f, "headers {} {}", stream_id, u8::from(*fin))?;
+                write_optional_headers(f, headers)
+            }
+            Self::HeaderViolations {
+                stream_id,
+                violations,
+            } => write!(f, "violations {} {}", stream_id, violations.bits()),
+            Self::Trailers {
+                stream_id,
+                trailers,
+            } => {
+                write!(f, "trailers {}", stream_id)?;
+                write_headers(f, trailers)
+            }
+            Self::Data {
+                stream_id,
+                data,
+                fin,
+            } => write!(
+                f,
+                "data {} {} {}",
+                stream_id,
+                u8::from(*fin),
+                data_word(data)
+            ),
+            Self::Reset { stream_id, error } => write!(f, "reset {} {}", stream_id, error),
+            Self::SettingsReceived(settings) => {
+                write!(f, "settings ")?;
+                write_settings(f, settings)
+            }
+            Self::StateChange(state) => {
+                write!(f, "state ")?;
+                write_state(f, state)
+            }
+            Self::CongestionStateChanged(state) => {
+                write!(f, "congestion ")?;
+                write_congestion_state(f, *state)
+            }
+        }
+    }
+}
+
+impl Display for RecordedItem {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let event = match self {
+            Self::Read {
+                stream_id,
+                data,
+                fin,
+            } => {
+                return write!(
+                    f,
+                    "read {} {} {}",
+                    stream_id,
+                    u8::from(*fin),
+                    data_word(data)
+                );
+            }
+            Self::Event(e) => e,
+            Self::Server(e) => return write!(f, "server {}", e),
+        };
+        match event {
+            Http3ClientEvent::HeaderReady {
+                stream_id,
+                headers,
+                fin,
+                user_data,
+            } => {
+                write!(
+                    f,
+                    "headers {} {}",
+                    StreamWord(*stream_id, *user_data),
+                    u8::from(*fin)
+                )?;
+                write_optional_headers(f, headers)
+            }
+            Http3ClientEvent::InterimHeaders {
+                stream_id,
+                headers,
+                user_data,
+            } => {
+                write!(f, "interim {}", StreamWord(*stream_id, *user_data))?;
+                write_headers(f, headers)
+            }
+            Http3ClientEvent::HeaderViolations {
+                stream_id,
+                violations,
+                user_data,
+            } => write!(
+                f,
+                "violations {} {}",
+                StreamWord(*stream_id, *user_data),
+                violations.bits()
+            ),
+            Http3ClientEvent::TrailerReady {
+                stream_id,
+                user_data,
+            } => write!(f, "trailers {}", StreamWord(*stream_id, *user_data)),
+            Http3ClientEvent::DataWritable {
+                stream_id,
+                user_data,
+            } => write!(f, "writable {}", StreamWord(*stream_id, *user_data)),
+            Http3ClientEvent::DataReadable {
+                stream_id,
+                user_data,
+            } => write!(f, "readable {}", StreamWord(*stream_id, *user_data)),
+            Http3ClientEvent::Datagram {
+                stream_id,
+                payload,
+                user_data,
+            } => write!(
+                f,
+                "datagram {} {}",
+                StreamWord(*stream_id, *user_data),
+                data_word(payload)
+            ),
+            Http3ClientEvent::Capsule {
+                stream_id,
+                capsule_type,
+                payload,
+                user_data,
+            } => write!(
+                f,
+                "capsule {} {} {}",
+                StreamWord(*stream_id, *user_data),
+                capsule_type,
+                data_word(payload)
+            ),
+            Http3ClientEvent::Reset {
+                stream_id,
+                error,
+                user_data,
+            } => write!(f, "reset {} {}", StreamWord(*stream_id, *user_data), error),
+            Http3ClientEvent::StopSending {
+                stream_id,
+                error,
+                user_data,
+            } => write!(
+                f,
+                "stop-sending {} {}",
+                StreamWord(*stream_id, *user_data),
+                error
+            ),
+            Http3ClientEvent::NewPushStream { stream_id } => write!(f, "push {}", stream_id),
+            Http3ClientEvent::PushPromise {
+                push_id,
+                request_stream_id,
+                headers,
+            } => {
+                write!(f, "push-promise {} {}", push_id, request_stream_id)?;
+                write_headers(f, headers)
+            }
+            Http3ClientEvent::PushHeaderReady {
+                push_id,
+                headers,
+                fin,
+            } => {
+                write!(f, "push-headers {} {}", push_id, u8::from(*fin))?;
+                write_optional_headers(f, headers)
+            }
+            Http3ClientEvent::PushDataReadable { push_id } => {
+                write!(f, "push-readable {}", push_id)
+            }
+            Http3ClientEvent::PushCanceled { push_id } => write!(f, "push-canceled {}", push_id),
+            Http3ClientEvent::RequestsCreatable => write!(f, "requests-creatable"),
+            Http3ClientEvent::AuthenticationNeeded => write!(f, "authentication-needed"),
+            Http3ClientEvent::ZeroRttRejected => write!(f, "zero-rtt-rejected"),
+            Http3ClientEvent::GoawayReceived => write!(f, "goaway"),
+            Http3ClientEvent::SettingsReceived => write!(f, "settings"),
+            Http3ClientEvent::RequestsExhausted => write!(f, "requests-exhausted"),
+            Http3ClientEvent::BodyExceedsFlowControl {
+                stream_id,
+                body_len,
+                max_data,
+                user_data,
+            } => write!(
+                f,
+                "body-exceeds-flow-control {} {} {}",
+                StreamWord(*stream_id, *user_data),
+                body_len,
+                max_data
+            ),
+            Http3ClientEvent::CongestionStateChanged { state } => {
+                write!(f, "congestion ")?;
+                write_congestion_state(f, *state)
+            }
+            Http3ClientEvent::FlowControlStalled { stall } => {
+                write!(f, "flow-control-stalled ")?;
+                match stall.stream_id {
+                    Some(id) => write!(f, "{}", id)?,
+                    None => write!(f, "-")?,
+                }
+                write!(f, " {} {}", stall.limit, stall.duration.as_micros())
+            }
+            Http3ClientEvent::RequestComplete {
+                stream_id,
+                outcome,
+                user_data,
+            } => {
+                write!(f, "complete {} ", StreamWord(*stream_id, *user_data))?;
+                match outcome {
+                    RequestOutcome::Completed => write!(f, "completed"),
+                    RequestOutcome::Failed { error } => write!(f, "failed {}", error),
+                    RequestOutcome::Retryable => write!(f, "retryable"),
+                    RequestOutcome::Cancelled => write!(f, "cancelled"),
+                }
+            }
+            Http3ClientEvent::StateChange(state) => {
+                write!(f, "state ")?;
+                write_state(f, state)
+            }
+        }
+    }
+}
+
+fn hex_byte(digits: &[u8]) -> Res<u8> {
+    let s = std::str::from_utf8(digits).map_err(|_| Error::InvalidRecording)?;
+    u8::from_str_radix(s, 16).map_err(|_| Error::InvalidRecording)
+}
+
+fn unescape(s: &str) -> Res<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, r)) = rest.split_first() {
+        if b == b'%' {
+            if r.len() < 2 {
+                return Err(Error::InvalidRecording);
+            }
+            out.push(hex_byte(&r[..2])?);
+            rest = &r[2..];
+        } else {
+            out.push(b);
+            rest = r;
+        }
+    }
+    String::from_utf8(out).map_err(|_| Error::InvalidRecording)
+}
+
+fn parse_number(word: Option<&str>) -> Res<u64> {
+    word.and_then(|w| w.parse().ok())
+        .ok_or(Error::InvalidRecording)
+}
+
+fn parse_stream(word: Option<&str>) -> Res<(u64, u64)> {
+    let word = word.ok_or(Error::InvalidRecording)?;
+    let mut parts = word.splitn(2, '/');
+    let stream_id = parse_number(parts.next())?;
+    let user_data = match parts.next() {
+        Some(u) => parse_number(Some(u))?,
+        None => 0,
+    };
+    Ok((stream_id, user_data))
+}
+
+fn parse_flag(word: Option<&str>) -> Res<bool> {
+    match word {
+        Some("0") => Ok(false),
+        Some("1") => Ok(true),
+        _ => Err(Error::InvalidRecording),
+    }
+}
+
+fn parse_data(word: Option<&str>) -> Res<Vec<u8>> {
+    match word {
+        Some("-") => Ok(Vec::new()),
+        Some(w) if w.len() % 2 == 0 => w.as_bytes().chunks(2).map(hex_byte).collect(),
+        _ => Err(Error::InvalidRecording),
+    }
+}
+
+fn parse_header(word: &str) -> Res<Header> {
+    let eq = word.find('=').ok_or(Error::InvalidRecording)?;
+    Ok((unescape(&word[..eq])?, unescape(&word[eq + 1..])?))
+}
+
+fn parse_headers<'a>(words: &mut impl Iterator<Item = &'a str>) -> Res<Vec<Header>> {
+    words.map(parse_header).collect()
+}
+
+fn parse_optional_headers<'a>(
+    words: &mut impl Iterator<Item = &'a str>,
+) -> Res<Option<Vec<Header>>> {
+    let fields = words.collect::<Vec<_>>();
+    if fields == ["-"] {
+        Ok(None)
+    } else {
+        let headers = fields.into_iter().map(parse_header).collect::<Res<_>>()?;
+        Ok(Some(headers))
+    }
+}
+
+fn parse_close_error<'a>(words: &mut impl Iterator<Item = &'a str>) -> Res<CloseError> {
+    match words.next() {
+        Some("transport") => Ok(CloseError::Transport(parse_number(words.next())?)),
+        Some("application") => Ok(CloseError::Application(parse_number(words.next())?)),
+        _ => Err(Error::InvalidRecording),
+    }
+}
+
+fn parse_congestion_state(word: Option<&str>) -> Res<CongestionState> {
+    match word {
+        Some("none") => Ok(CongestionState::NoCongestion),
+        Some("recovery") => Ok(CongestionState::Recovery),
+        Some("persistent") => Ok(CongestionState::PersistentCongestion),
+        _ => Err(Error::InvalidRecording),
+    }
+}
+
+fn parse_state<'a>(words: &mut impl Iterator<Item = &'a str>) -> Res<Http3State> {
+    match words.next() {
+        Some("initializing") => Ok(Http3State::Initializing),
+        Some("zero-rtt") => Ok(Http3State::ZeroRtt),
+        Some("connected") => Ok(Http3State::Connected),
+        Some("going-away") => Ok(Http3State::GoingAway(parse_number(words.next())?)),
+        Some("closing") => Ok(Http3State::Closing(parse_close_error(words)?)),
+        Some("closed") => Ok(Http3State::Closed(parse_close_error(words)?)),
+        _ => Err(Error::InvalidRecording),
+    }
+}
+
+fn parse_violations(word: Option<&str>) -> Res<HeaderViolations> {
+    let bits = u16::try_from(parse_number(word)?).or(Err(Error::InvalidRecording))?;
+    Ok(HeaderViolations::from_bits(bits))
+}
+
+fn parse_settings<'a>(words: &mut impl Iterator<Item = &'a str>) -> Res<Settings> {
+    let max_table_capacity = parse_number(words.next())?;
+    let blocked_streams = parse_number(words.next())?;
+    let max_header_list_size = match words.next() {
+        Some("-") => None,
+        w => Some(parse_number(w)?),
+    };
+    let enable_connect_protocol = parse_flag(words.next())?;
+    let other = words
+        .map(|w| -> Res<(u64, u64)> {
+            let eq = w.find('=').ok_or(Error::InvalidRecording)?;
+            let id = parse_number(Some(&w[..eq]))?;
+            Ok((id, parse_number(Some(&w[eq + 1..]))?))
+        })
+        .collect::<Res<_>>()?;
+    Ok(Settings {
+        max_table_capacity,
+        blocked_streams,
+        max_header_list_size,
+        enable_connect_protocol,
+        other,
+    })
+}
+
+fn parse_server_event<'a>(words: &mut impl Iterator<Item = &'a str>) -> Res<RecordedServerEvent> {
+    let event = match words.next() {
+        Some("headers") => {
+            let stream_id = parse_number(words.next())?;
+            let fin = parse_flag(words.next())?;
+            RecordedServerEvent::Headers {
+                stream_id,
+                headers: parse_optional_headers(words)?,
+                fin,
+            }
+        }
+        Some("violations") => RecordedServerEvent::HeaderViolations {
+            stream_id: parse_number(words.next())?,
+            violations: parse_violations(words.next())?,
+        },
+        Some("trailers") => RecordedServerEvent::Trailers {
+            stream_id: parse_number(words.next())?,
+            trailers: parse_headers(words)?,
+        },
+        Some("data") => RecordedServerEvent::Data {
+            stream_id: parse_number(words.next())?,
+            fin: parse_flag(words.next())?,
+            data: parse_data(words.next())?,
+        },
+        Some("reset") => RecordedServerEvent::Reset {
+            stream_id: parse_number(words.next())?,
+            error: parse_number(words.next())?,
+        },
+        Some("settings") => RecordedServerEvent::SettingsReceived(parse_settings(words)?),
+        Some("state") => RecordedServerEvent::StateChange(parse_state(words)?),
+        Some("congestion") => {
+            RecordedServerEvent::CongestionStateChanged(parse_congestion_state(words.next())?)
+        }
+        _ => return Err(Error::InvalidRecording),
+    };
+    Ok(event)
+}
+
+impl FromStr for RecordedItem {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Res<Self> {
+        let mut words = line.split(' ');
+        let event = match words.next() {
+            Some("read") => {
+                let stream_id = parse_number(words.next())?;
+                let fin = parse_flag(words.next())?;
+                let data = parse_data(words.next())?;
+                Self::Read {
+                    stream_id,
+                    data,
+                    fin,
+                }
+            }
+            Some("headers") => {
+                let (stream_id, user_data) = parse_stream(words.next())?;
+                let fin = parse_flag(words.next())?;
+                let headers = parse_optional_headers(&mut words)?;
+                Self::Event(Http3ClientEvent::HeaderReady {
+                    stream_id,
+                    headers,
+                    fin,
+                    user_data,
+                })
+            }
+            Some("interim") => {
+                let (stream_id, user_data) = parse_stream(words.next())?;
+                Self::Event(Http3ClientEvent::InterimHeaders {
+                    stream_id,
+                    headers: parse_headers(&mut words)?,
+                    user_data,
+                })
+            }
+            Some("violations") => {
+                let (stream_id, user_data) = parse_stream(words.next())?;
+                Self::Event(Http3ClientEvent::HeaderViolations {
+                    stream_id,
+                    violations: parse_violations(words.next())?,
+                    user_data,
+                })
+            }
+            Some("trailers") => {
+                let (stream_id, user_data) = parse_stream(words.next())?;
+                Self::Event(Http3ClientEvent::TrailerReady {
+                    stream_id,
+                    user_data,
+                })
+            }
+            Some("writable") => {
+                let (stream_id, user_data) = parse_stream(words.next())?;
+                Self::Event(Http3ClientEvent::DataWritable {
+                    stream_id,
+                    user_data,
+                })
+            }
+            Some("readable") => {
+                let (stream_id, user_data) = parse_stream(words.next())?;
+                Self::Event(Http3ClientEvent::DataReadable {
+                    stream_id,
+                    user_data,
+                })
+            }
+            Some("datagram") => {
+                let (stream_id, user_data) = parse_stream(words.next())?;
+                Self::Event(Http3ClientEvent::Datagram {
+                    stream_id,
+                    payload: parse_data(words.next())?,
+                    user_data,
+                })
+            }
+            Some("capsule") => {
+                let (stream_id, user_data) = parse_stream(words.next())?;
+                Self::Event(Http3ClientEvent::Capsule {
+                    stream_id,
+                    capsule_type: parse_number(words.next())?,
+                    payload: parse_data(words.next())?,
+                    user_data,
+                })
+            }
+            Some("reset") => {
+                let (stream_id, user_data) = parse_stream(words.next())?;
+                Self::Event(Http3ClientEvent::Reset {
+                    stream_id,
+                    error: parse_number(words.next())?,
+                    user_data,
+                })
+            }
+            Some("stop-sending") => {
+                let (stream_id, user_data) = parse_stream(words.next())?;
+                Self::Event(Http3ClientEvent::StopSending {
+                    stream_id,
+                    error: parse_number(words.next())?,
+                    user_data,
+                })
+            }
+            Some("push") => Self::Event(Http3ClientEvent::NewPushStream {
+                stream_id: parse_number(words.next())?,
+            }),
+            Some("push-promise") => {
+                let push_id = parse_number(words.next())?;
+                let request_stream_id = parse_number(words.next())?;
+                Self::Event(Http3ClientEvent::PushPromise {
+                    push_id,
+                    request_stream_id,
+                    headers: parse_headers(&mut words)?,
+                })
+            }
+            Some("push-headers") => {
+                let push_id = parse_number(words.next())?;
+                let fin = parse_flag(words.next())?;
+                Self::Event(Http3ClientEvent::PushHeaderReady {
+                    push_id,
+                    headers: parse_optional_headers(&mut words)?,
+                    fin,
+                })
+            }
+            Some("push-readable") => Self::Event(Http3ClientEvent::PushDataReadable {
+                push_id: parse_number(words.next())?,
+            }),
+            Some("push-canceled") => Self::Event(Http3ClientEvent::PushCanceled {
+                push_id: parse_number(words.next())?,
+            }),
+            Some("requests-creatable") => Self::Event(Http3ClientEvent::RequestsCreatable),
+            Some("authentication-needed") => Self::Event(Http3ClientEvent::AuthenticationNeeded),
+            Some("zero-rtt-rejected") => Self::Event(Http3ClientEvent::ZeroRttRejected),
+            Some("goaway") => Self::Event(Http3ClientEvent::GoawayReceived),
+            Some("settings") => Self::Event(Http3ClientEvent::SettingsReceived),
+            Some("requests-exhausted") => Self::Event(Http3ClientEvent::RequestsExhausted),
+            Some("body-exceeds-flow-control") => {
+                let (stream_id, user_data) = parse_stream(words.next())?;
+                Self::Event(Http3ClientEvent::BodyExceedsFlowControl {
+                    stream_id,
+                    body_len: parse_number(words.next())?,
+                    max_data: parse_number(words.next())?,
+                    user_data,
+                })
+            }
+            Some("congestion") => Self::Event(Http3ClientEvent::CongestionStateChanged {
+                state: parse_congestion_state(words.next())?,
+            }),
+            Some("flow-control-stalled") => {
+                let stream_id = match words.next() {
+                    Some("-") => None,
+                    w => Some(parse_number(w)?),
+                };
+                Self::Event(Http3ClientEvent::FlowControlStalled {
+                    stall: FlowControlStall {
+                        stream_id,
+                        limit: parse_number(words.next())?,
+                        duration: Duration::from_micros(parse_number(words.next())?),
+                    },
+                })
+            }
+            Some("complete") => {
+                let (stream_id, user_data) = parse_stream(words.next())?;
+                let outcome = match words.next() {
+                    Some("completed") => RequestOutcome::Completed,
+                    Some("failed") => RequestOutcome::Failed {
+                        error: parse_number(words.next())?,
+                    },
+                    Some("retryable") => RequestOutcome::Retryable,
+                    Some("cancelled") => RequestOutcome::Cancelled,
+                    _ => return Err(Error::InvalidRecording),
+                };
+                Self::Event(Http3ClientEvent::RequestComplete {
+                    stream_id,
+                    outcome,
+                    user_data,
+                })
+            }
+            Some("state") => Self::Event(Http3ClientEvent::StateChange(parse_state(&mut words)?)),
+            Some("server") => Self::Server(parse_server_event(&mut words)?),
+            _ => return Err(Error::InvalidRecording),
+        };
+        if words.next().is_some() {
+            return Err(Error::InvalidRecording);
+        }
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RecordedItem, RecordedServerEvent, Recording};
+    use crate::hsettings_frame::Settings;
+    use crate::{HeaderViolations, Http3ClientEvent, Http3State, RequestOutcome};
+    use neqo_transport::{CloseError, CongestionState, FlowControlStall};
+    use std::time::Duration;
+
+    fn round_trip(line: &str, item: RecordedItem) {
+        assert_eq!(line.parse::<RecordedItem>().unwrap(), item);
+        assert_eq!(item.to_string(), line);
+    }
+
+    #[test]
+    fn headers() {
+        round_trip(
+            "headers 4 1 :status=200 x%3dy=a%20b=c%25",
+            RecordedItem::Event(Http3ClientEvent::HeaderReady {
+                stream_id: 4,
+                headers: Some(vec![
+                    (String::from(":status"), String::from("200")),
+                    (String::from("x=y"), String::from("a b=c%")),
+                ]),
+                fin: true,
+                user_data: 0,
+            }),
+        );
+        round_trip(
+            "headers 0 0 -",
+            RecordedItem::Event(Http3ClientEvent::HeaderReady {
+                stream_id: 0,
+                headers: None,
+                fin: false,
+                user_data: 0,
+            }),
+        );
+    }
+
+    #[test]
+    fn trailers() {
+        round_trip(
+            "trailers 4/7",
+            RecordedItem::Event(Http3ClientEvent::TrailerReady {
+                stream_id: 4,
+                user_data: 7,
+            }),
+        );
+    }
+
+    #[test]
+    fn pushes() {
+        round_trip(
+            "push-promise 2 4 :path=/style.css",
+            RecordedItem::Event(Http3ClientEvent::PushPromise {
+                push_id: 2,
+                request_stream_id: 4,
+                headers: vec![(String::from(":path"), String::from("/style.css"))],
+            }),
+        );
+        round_trip(
+            "push-headers 2 0 -",
+            RecordedItem::Event(Http3ClientEvent::PushHeaderReady {
+                push_id: 2,
+                headers: None,
+                fin: false,
+            }),
+        );
+        round_trip(
+            "push-readable 2",
+            RecordedItem::Event(Http3ClientEvent::PushDataReadable { push_id: 2 }),
+        );
+        round_trip(
+            "push-canceled 2",
+            RecordedItem::Event(Http3ClientEvent::PushCanceled { push_id: 2 }),
+        );
+    }
+
+    #[test]
+    fn interim_headers() {
+        round_trip(
+            "interim 4/9 :status=100",
+            RecordedItem::Event(Http3ClientEvent::InterimHeaders {
+                stream_id: 4,
+                headers: vec![(String::from(":status"), String::from("100"))],
+                user_data: 9,
+            }),
+        );
+    }
+
+    #[test]
+    fn header_violations() {
+        round_trip(
+            "violations 4 5",
+            RecordedItem::Event(Http3ClientEvent::HeaderViolations {
+                stream_id: 4,
+                violations: HeaderViolations::UPPERCASE_NAME | HeaderViolations::OBS_FOLD,
+                user_data: 0,
+            }),
+        );
+    }
+
+    #[test]
+    fn body_exceeds_flow_control() {
+        round_trip(
+            "body-exceeds-flow-control 4 1000000 1000",
+            RecordedItem::Event(Http3ClientEvent::BodyExceedsFlowControl {
+                stream_id: 4,
+                body_len: 1_000_000,
+                max_data: 1000,
+                user_data: 0,
+            }),
+        );
+    }
+
+    #[test]
+    fn capsules() {
+        round_trip(
+            "datagram 4 0102",
+            RecordedItem::Event(Http3ClientEvent::Datagram {
+                stream_id: 4,
+                payload: vec![1, 2],
+                user_data: 0,
+            }),
+        );
+        round_trip(
+            "capsule 4 4660 -",
+            RecordedItem::Event(Http3ClientEvent::Capsule {
+                stream_id: 4,
+                capsule_type: 0x1234,
+                payload: Vec::new(),
+                user_data: 0,
+            }),
+        );
+    }
+
+    #[test]
+    fn congestion() {
+        round_trip(
+            "congestion recovery",
+            RecordedItem::Event(Http3ClientEvent::CongestionStateChanged {
+                state: CongestionState::Recovery,
+            }),
+        );
+    }
+
+    #[test]
+    fn flow_control_stalled() {
+        round_trip(
+            "flow-control-stalled 4 1000 30000000",
+            RecordedItem::Event(Http3ClientEvent::FlowControlStalled {
+                stall: FlowControlStall {
+                    stream_id: Some(4),
+                    limit: 1000,
+                    duration: Duration::from_secs(30),
+                },
+            }),
+        );
+        round_trip(
+            "flow-control-stalled - 5000 1500",
+            RecordedItem::Event(Http3ClientEvent::FlowControlStalled {
+                stall: FlowControlStall {
+                    stream_id: None,
+                    limit: 5000,
+                    duration: Duration::from_micros(1500),
+                },
+            }),
+        );
+    }
+
+    #[test]
+    fn request_complete() {
+        round_trip(
+            "complete 4 failed 268",
+            RecordedItem::Event(Http3ClientEvent::RequestComplete {
+                stream_id: 4,
+                outcome: RequestOutcome::Failed { error: 268 },
+                user_data: 0,
+            }),
+        );
+        round_trip(
+            "complete 8 retryable",
+            RecordedItem::Event(Http3ClientEvent::RequestComplete {
+                stream_id: 8,
+                outcome: RequestOutcome::Retryable,
+                user_data: 0,
+            }),
+        );
+        round_trip(
+            "complete 8/17 retryable",
+            RecordedItem::Event(Http3ClientEvent::RequestComplete {
+                stream_id: 8,
+                outcome: RequestOutcome::Retryable,
+                user_data: 17,
+            }),
+        );
+    }
+
+    #[test]
+    fn reads() {
+        round_trip(
+            "read 8 0 00ff",
+            RecordedItem::Read {
+                stream_id: 8,
+                data: vec![0, 0xff],
+                fin: false,
+            },
+        );
+        round_trip(
+            "read 8 1 -",
+            RecordedItem::Read {
+                stream_id: 8,
+                data: Vec::new(),
+                fin: true,
+            },
+        );
+    }
+
+    #[test]
+    fn states() {
+        round_trip(
+            "state closing application 268",
+            RecordedItem::Event(Http3ClientEvent::StateChange(Http3State::Closing(
+                CloseError::Application(0x10c),
+            ))),
+        );
+        round_trip(
+            "state going-away 4",
+            RecordedItem::Event(Http3ClientEvent::StateChange(Http3State::GoingAway(4))),
+        );
+    }
+
+    #[test]
+    fn server_events() {
+        round_trip(
+            "server headers 0 1 :method=GET :path=/",
+            RecordedItem::Server(RecordedServerEvent::Headers {
+                stream_id: 0,
+                headers: Some(vec![
+                    (String::from(":method"), String::from("GET")),
+                    (String::from(":path"), String::from("/")),
+                ]),
+                fin: true,
+            }),
+        );
+        round_trip(
+            "server violations 4 2",
+            RecordedItem::Server(RecordedServerEvent::HeaderViolations {
+                stream_id: 4,
+                violations: HeaderViolations::from_bits(2),
+            }),
+        );
+        round_trip(
+            "server trailers 4 x=y",
+            RecordedItem::Server(RecordedServerEvent::Trailers {
+                stream_id: 4,
+                trailers: vec![(String::from("x"), String::from("y"))],
+            }),
+        );
+        round_trip(
+            "server data 4 0 6162",
+            RecordedItem::Server(RecordedServerEvent::Data {
+                stream_id: 4,
+                data: vec![0x61, 0x62],
+                fin: false,
+            }),
+        );
+        round_trip(
+            "server reset 8 268",
+            RecordedItem::Server(RecordedServerEvent::Reset {
+                stream_id: 8,
+                error: 0x10c,
+            }),
+        );
+        round_trip(
+            "server settings 100 100 - 0",
+            RecordedItem::Server(RecordedServerEvent::SettingsReceived(Settings {
+                max_table_capacity: 100,
+                blocked_streams: 100,
+                ..Settings::default()
+            })),
+        );
+        round_trip(
+            "server settings 0 0 4096 1 33=1",
+            RecordedItem::Server(RecordedServerEvent::SettingsReceived(Settings {
+                max_header_list_size: Some(4096),
+                enable_connect_protocol: true,
+                other: vec![(33, 1)],
+                ..Settings::default()
+            })),
+        );
+        round_trip(
+            "server state closed transport 0",
+            RecordedItem::Server(RecordedServerEvent::StateChange(Http3State::Closed(
+                CloseError::Transport(0),
+            ))),
+        );
+        round_trip(
+            "server congestion persistent",
+            RecordedItem::Server(RecordedServerEvent::CongestionStateChanged(
+                CongestionState::PersistentCongestion,
+            )),
+        );
+    }
+
+    #[test]
+    fn skip_comments() {
+        let recording = "# comment\n\nreadable 0\n".parse::<Recording>().unwrap();
+        assert_eq!(
+            recording.items(),
+            &[RecordedItem::Event(Http3ClientEvent::DataReadable {
+                stream_id: 0,
+                user_data: 0,
+            })]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        for line in &[
+            "unknown",
+            "readable",
+            "readable x",
+            "readable 0 0",
+            "read 0 2 -",
+            "read 0 0 abc",
+            "headers 0 0 novalue",
+            "headers 0 0 bad=%2",
+            "state closed peer 1",
+            "congestion bad",
+            "complete 0 failed",
+            "complete 0 done",
+            "server",
+            "server readable 0",
+            "server headers 0/1 0 -",
+            "server settings 0 0 - 0 33",
+            "server state connected 1",
+        ] {
+            assert!(line.parse::<RecordedItem>().is_err(), "{}", line);
+        }
+    }
+}
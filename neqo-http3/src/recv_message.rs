@@ -4,12 +4,19 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use crate::capsule::CapsuleDecoder;
+use crate::connection::header_list_size;
+use crate::headers::{
+    content_length, validate_headers, HeaderValidation, HeaderViolations, MessageType,
+};
 use crate::hframe::{HFrame, HFrameReader};
 use crate::push_controller::PushController;
+#[cfg(feature = "qlog")]
+use crate::qlog;
 use crate::{Error, Header, Res};
 use neqo_common::{matches, qdebug, qinfo, qtrace};
 use neqo_qpack::decoder::QPackDecoder;
-use neqo_transport::Connection;
+use neqo_transport::{AppError, Connection};
 use std::cell::RefCell;
 use std::cmp::min;
 use std::convert::TryFrom;
@@ -18,7 +25,36 @@ use std::rc::Rc;
 
 pub(crate) trait RecvMessageEvents: Debug {
     fn header_ready(&self, stream_id: u64, headers: Option<Vec<Header>>, fin: bool);
+    fn interim_headers(&self, stream_id: u64, headers: Vec<Header>);
+    fn header_violations(&self, stream_id: u64, violations: HeaderViolations);
+    fn trailer_ready(&self, stream_id: u64, trailers: Vec<Header>);
     fn data_readable(&self, stream_id: u64);
+    fn reset(&self, stream_id: u64, error: AppError);
+    fn local_reset(&self, stream_id: u64, error: AppError);
+    fn complete(&self, stream_id: u64);
+    fn capsule(&self, stream_id: u64, capsule_type: u64, payload: Vec<u8>);
+}
+
+/// How much of a DATA frame is read at a time when it holds capsules.
+const CAPSULE_READ_SIZE: usize = 4096;
+
+/// Whether the body is read as capsules.  That is only asked for with an
+/// extended CONNECT, and only happens if the response is a 2xx.
+#[derive(Debug)]
+enum Capsules {
+    No,
+    Requested,
+    Active(CapsuleDecoder),
+}
+
+/// The status code of an informational (1xx) response, which is followed by
+/// more headers on the same stream.
+fn interim_status(headers: &[Header]) -> Option<&str> {
+    headers
+        .iter()
+        .find(|(n, _)| n == ":status")
+        .map(|(_, v)| v.as_str())
+        .filter(|v| v.len() == 3 && v.starts_with('1'))
 }
 
 /*
@@ -27,9 +63,16 @@ pub(crate) trait RecvMessageEvents: Debug {
  *                                also get a PUSH_PROMISE frame.
  *    DecodingHeaders : In this step the headers will be decoded. The stream
  *                      may be blocked in this state on encoder instructions.
+ *                      Headers of an interim (1xx) response are delivered and
+ *                      the stream goes back to WaitingForResponseHeaders.
+ *    DecodingPushPromise : The headers of a PUSH_PROMISE frame are decoded.
+ *                          This can also be blocked, and afterwards the stream
+ *                          goes back to the state it was in.
  *    WaitingForData : we got HEADERS, we are waiting for one or more data
  *                     frames. In this state we can receive one or more
  *                     PUSH_PROMIS frames or a HEADERS frame carrying trailers.
+ *    DecodingTrailers : The trailers are decoded. This can be blocked like
+ *                       DecodingHeaders. Afterwards only the FIN can follow.
  *    ReadingData : we got a DATA frame, now we letting the app read payload.
  *                  From here we will go back to WaitingForData state to wait
  *                  for more data frames or to CLosed state
@@ -37,12 +80,24 @@ pub(crate) trait RecvMessageEvents: Debug {
  * the TransactionClient.
  *    Closed
  */
+/// A PUSH_PROMISE frame whose headers are being decoded.  Afterwards, the stream
+/// waits for headers or data again, depending on `headers_received`.
+#[derive(PartialEq, Debug)]
+struct PendingPushPromise {
+    push_id: u64,
+    header_block: Vec<u8>,
+    headers_received: bool,
+    fin: bool,
+}
+
 #[derive(PartialEq, Debug)]
 enum RecvMessageState {
     WaitingForResponseHeaders,
     DecodingHeaders { header_block: Vec<u8>, fin: bool },
+    DecodingPushPromise(PendingPushPromise),
     WaitingForData,
     ReadingData { remaining_data_len: usize },
+    DecodingTrailers { header_block: Vec<u8>, fin: bool },
     WaitingForFinAfterTrailers,
     ClosePending, // Close must first be read by application
     Closed,
@@ -55,6 +110,13 @@ pub(crate) struct RecvMessage {
     conn_events: Box<dyn RecvMessageEvents>,
     push_handler: Option<Rc<RefCell<PushController>>>,
     stream_id: u64,
+    max_header_list_size: u64,
+    header_validation: Option<HeaderValidation>,
+    message_type: MessageType,
+    /// The `content-length` of a request that the body is checked against.
+    content_length: Option<u64>,
+    body_len: u64,
+    capsules: Capsules,
 }
 
 impl ::std::fmt::Display for RecvMessage {
@@ -65,6 +127,7 @@ impl ::std::fmt::Display for RecvMessage {
 
 impl RecvMessage {
     pub fn new(
+        message_type: MessageType,
         stream_id: u64,
         conn_events: Box<dyn RecvMessageEvents>,
         push_handler: Option<Rc<RefCell<PushController>>>,
@@ -75,9 +138,37 @@ impl RecvMessage {
             conn_events,
             push_handler,
             stream_id,
+            max_header_list_size: u64::max_value(),
+            header_validation: None,
+            message_type,
+            content_length: None,
+            body_len: 0,
+            capsules: Capsules::No,
         }
     }
 
+    /// Refuse headers that are larger than `limit`, as SETTINGS_MAX_HEADER_LIST_SIZE counts them.
+    pub fn set_max_header_list_size(&mut self, limit: u64) {
+        self.max_header_list_size = limit;
+    }
+
+    /// Check headers against the rules for HTTP/3 fields.  By default they are
+    /// delivered as they are received.  The body of a request is also checked
+    /// against its `content-length`.
+    pub fn set_header_validation(&mut self, policy: HeaderValidation) {
+        self.header_validation = Some(policy);
+    }
+
+    /// Read the body as capsules if the response is a 2xx.
+    pub fn enable_capsules(&mut self) {
+        self.capsules = Capsules::Requested;
+    }
+
+    /// Whether the body is being read as capsules.
+    pub fn capsules_active(&self) -> bool {
+        matches!(self.capsules, Capsules::Active(..))
+    }
+
     fn handle_headers_frame(&mut self, header_block: Vec<u8>, fin: bool) -> Res<()> {
         match self.state {
             RecvMessageState::WaitingForResponseHeaders => {
@@ -88,17 +179,39 @@ impl RecvMessage {
                 }
              }
             RecvMessageState::WaitingForData => {
-                // TODO implement trailers, for now just ignore them.
-                self.state = RecvMessageState::WaitingForFinAfterTrailers;
+                self.state = RecvMessageState::DecodingTrailers { header_block, fin };
             }
             RecvMessageState::WaitingForFinAfterTrailers => {
-                return Err(Error::HttpFrameUnexpected);
+                // Only the end of the stream can follow trailers.
+                qinfo!([self], "HEADERS frame after trailers.");
+                self.conn_events
+                    .local_reset(self.stream_id, Error::HttpFrameUnexpected.code());
+                self.state = RecvMessageState::Closed;
+                return Err(Error::FrameAfterTrailers);
             }
             _ => unreachable!("This functions is only called in WaitingForResponseHeaders | WaitingForData | WaitingForFinAfterTrailers state.")
          }
         Ok(())
     }
 
+    fn handle_push_promise(&mut self, push_id: u64, header_block: Vec<u8>, fin: bool) -> Res<()> {
+        if self.push_handler.is_none() {
+            return Err(Error::HttpId);
+        }
+        let headers_received = match self.state {
+            RecvMessageState::WaitingForResponseHeaders => false,
+            RecvMessageState::WaitingForData => true,
+            _ => return Err(Error::HttpFrameUnexpected),
+        };
+        self.state = RecvMessageState::DecodingPushPromise(PendingPushPromise {
+            push_id,
+            header_block,
+            headers_received,
+            fin,
+        });
+        Ok(())
+    }
+
     fn handle_data_frame(&mut self, len: u64, fin: bool) -> Res<()> {
         match self.state {
             RecvMessageState::WaitingForResponseHeaders | RecvMessageState::WaitingForFinAfterTrailers => {
@@ -120,9 +233,20 @@ impl RecvMessage {
     }
 
     fn add_headers(&mut self, headers: Option<Vec<Header>>, fin: bool) {
+        if matches!(self.capsules, Capsules::Requested) {
+            let accepted = headers
+                .as_ref()
+                .and_then(|h| h.iter().find(|(n, _)| n == ":status"))
+                .map_or(false, |(_, v)| v.len() == 3 && v.starts_with('2'));
+            self.capsules = if accepted && !fin {
+                Capsules::Active(CapsuleDecoder::default())
+            } else {
+                Capsules::No
+            };
+        }
         if fin {
             self.conn_events.header_ready(self.stream_id, headers, true);
-            self.state = RecvMessageState::Closed;
+            self.set_closed();
         } else {
             self.conn_events
                 .header_ready(self.stream_id, headers, false);
@@ -130,6 +254,32 @@ impl RecvMessage {
         }
     }
 
+    /// Deliver the trailers and wait for the end of the stream.  Trailers can't
+    /// carry pseudo-headers, and they count towards the header list size limit.
+    fn add_trailers(&mut self, trailers: Vec<Header>, fin: bool) -> Res<()> {
+        if header_list_size(&trailers) > self.max_header_list_size {
+            qinfo!([self], "trailers are larger than the limit.");
+            self.conn_events
+                .local_reset(self.stream_id, Error::HttpExcessiveLoad.code());
+            self.state = RecvMessageState::Closed;
+            return Err(Error::HeaderListTooLarge);
+        }
+        if trailers.iter().any(|(name, _)| name.starts_with(':')) {
+            qinfo!([self], "trailers contain a pseudo-header.");
+            self.conn_events
+                .local_reset(self.stream_id, Error::HttpMessageError.code());
+            self.state = RecvMessageState::Closed;
+            return Err(Error::InvalidHeader);
+        }
+        self.check_content_length(true)?;
+        self.conn_events.trailer_ready(self.stream_id, trailers);
+        self.state = RecvMessageState::WaitingForFinAfterTrailers;
+        if fin {
+            self.set_state_to_close_pending();
+        }
+        Ok(())
+    }
+
     fn set_state_to_close_pending(&mut self) {
         // Stream has received fin. Depending on headers state set header_ready
         // or data_readable event so that app can pick up the fin.
@@ -142,7 +292,7 @@ impl RecvMessage {
         match self.state {
             RecvMessageState::WaitingForResponseHeaders => {
                 self.conn_events.header_ready(self.stream_id, None, true);
-                self.state = RecvMessageState::Closed;
+                self.set_closed();
             }
             RecvMessageState::ReadingData { .. } => {}
             RecvMessageState::WaitingForData | RecvMessageState::WaitingForFinAfterTrailers => {
@@ -155,12 +305,98 @@ impl RecvMessage {
         }
     }
 
+    // The message has been delivered in full.
+    fn set_closed(&mut self) {
+        self.state = RecvMessageState::Closed;
+        self.conn_events.complete(self.stream_id);
+    }
+
+    /// Check the length of the body of a request against its `content-length`,
+    /// where `fin` says whether all of the body has been received.  A mismatch
+    /// is treated like any other problem with the headers.
+    fn check_content_length(&mut self, fin: bool) -> Res<()> {
+        if let Some(len) = self.content_length {
+            if self.body_len > len || (fin && self.body_len < len) {
+                qinfo!([self], "body doesn't match content-length {}.", len);
+                self.content_length = None;
+                if self.header_validation == Some(HeaderValidation::Strict) {
+                    self.conn_events
+                        .local_reset(self.stream_id, Error::HttpMessageError.code());
+                    self.state = RecvMessageState::Closed;
+                    return Err(Error::InvalidHeader);
+                }
+                self.conn_events
+                    .header_violations(self.stream_id, HeaderViolations::CONTENT_LENGTH);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop reading capsules because of `error`, resetting the stream.
+    fn capsule_error(&mut self, error: Error) -> Error {
+        qinfo!([self], "capsule error {:?}.", error);
+        let code = if error == Error::CapsuleTooLarge {
+            Error::HttpExcessiveLoad
+        } else {
+            Error::HttpGeneralProtocol
+        };
+        self.conn_events.local_reset(self.stream_id, code.code());
+        self.state = RecvMessageState::Closed;
+        error
+    }
+
+    /// At the end of the body, there can't be part of a capsule left.
+    fn capsules_finished(&mut self) -> Res<()> {
+        if let Capsules::Active(decoder) = &self.capsules {
+            if let Err(e) = decoder.finish() {
+                return Err(self.capsule_error(e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read up to `remaining` bytes of a DATA frame as capsules, reporting each
+    /// capsule once it is complete.  This returns how much of the frame is left
+    /// and whether the stream ended.
+    fn read_capsules(&mut self, conn: &mut Connection, remaining: usize) -> Res<(usize, bool)> {
+        let decoder = match &mut self.capsules {
+            Capsules::Active(decoder) => decoder,
+            _ => unreachable!("only called when reading capsules"),
+        };
+        let mut buf = [0; CAPSULE_READ_SIZE];
+        let mut left = remaining;
+        let mut fin = false;
+        while left > 0 && !fin {
+            let (amount, f) = conn.stream_recv(self.stream_id, &mut buf[..min(left, buf.len())])?;
+            if amount == 0 && !f {
+                break;
+            }
+            decoder.push(&buf[..amount]);
+            left -= amount;
+            fin = f;
+        }
+        loop {
+            match decoder.next_capsule() {
+                Ok(Some((capsule_type, payload))) => {
+                    self.conn_events
+                        .capsule(self.stream_id, capsule_type, payload)
+                }
+                Ok(None) => break,
+                Err(e) => return Err(self.capsule_error(e)),
+            }
+        }
+        Ok((left, fin))
+    }
+
     fn recv_frame(&mut self, conn: &mut Connection) -> Res<(Option<HFrame>, bool)> {
         qtrace!([self], "receiving frame header");
         let fin = self.frame_reader.receive(conn, self.stream_id)?;
         if self.frame_reader.done() {
             qdebug!([self], "A new frame has been received.");
-            Ok((Some(self.frame_reader.get_frame()?), fin))
+            let f = self.frame_reader.get_frame()?;
+            #[cfg(feature = "qlog")]
+            qlog::h3_frame_parsed(conn.qlog_mut(), self.stream_id, &f)?;
+            Ok((Some(f), fin))
         } else {
             Ok((None, fin))
         }
@@ -175,6 +411,10 @@ impl RecvMessage {
         let mut written = 0;
         loop {
             match self.state {
+                // Capsules are read as they arrive, not by the application.
+                RecvMessageState::ReadingData { .. } if self.capsules_active() => {
+                    break Ok((written, false))
+                }
                 RecvMessageState::ReadingData {
                     ref mut remaining_data_len,
                 } => {
@@ -183,15 +423,18 @@ impl RecvMessage {
                         conn.stream_recv(self.stream_id, &mut buf[written..written + to_read])?;
                     debug_assert!(amount <= to_read);
                     *remaining_data_len -= amount;
+                    let remaining = *remaining_data_len;
                     written += amount;
+                    self.body_len += u64::try_from(amount).map_err(|_| Error::Internal)?;
+                    self.check_content_length(fin)?;
 
                     if fin {
-                        if *remaining_data_len > 0 {
+                        if remaining > 0 {
                             return Err(Error::HttpFrame);
                         }
-                        self.state = RecvMessageState::Closed;
+                        self.set_closed();
                         break Ok((written, fin));
-                    } else if *remaining_data_len == 0 {
+                    } else if remaining == 0 {
                         self.state = RecvMessageState::WaitingForData;
                         self.receive_internal(conn, decoder, false)?;
                     } else {
@@ -199,9 +442,16 @@ impl RecvMessage {
                     }
                 }
                 RecvMessageState::ClosePending => {
-                    self.state = RecvMessageState::Closed;
+                    self.check_content_length(true)?;
+                    self.set_closed();
                     break Ok((written, true));
                 }
+                RecvMessageState::WaitingForResponseHeaders
+                | RecvMessageState::DecodingHeaders { .. }
+                    if written == 0 =>
+                {
+                    break Err(Error::HeadersNotReady)
+                }
                 _ => break Ok((written, false)),
             }
         }
@@ -223,6 +473,7 @@ impl RecvMessage {
                 | RecvMessageState::WaitingForFinAfterTrailers => {
                     match self.recv_frame(conn)? {
                         (None, true) => {
+                            self.capsules_finished()?;
                             self.set_state_to_close_pending();
                             break Ok(());
                         }
@@ -242,18 +493,21 @@ impl RecvMessage {
                                 HFrame::PushPromise {
                                     push_id,
                                     header_block,
-                                } => self
-                                    .push_handler
-                                    .as_ref()
-                                    .ok_or(Error::HttpId)?
-                                    .borrow()
-                                    .new_push_promise(push_id, header_block)?,
+                                } => self.handle_push_promise(push_id, header_block, fin)?,
                                 _ => break Err(Error::HttpFrameUnexpected),
                             }
                             if matches!(self.state, RecvMessageState::Closed) {
                                 break Ok(());
                             }
-                            if fin && !matches!(self.state, RecvMessageState::DecodingHeaders{..}) {
+                            if fin
+                                && !matches!(
+                                    self.state,
+                                    RecvMessageState::DecodingHeaders { .. }
+                                        | RecvMessageState::DecodingTrailers { .. }
+                                        | RecvMessageState::DecodingPushPromise(..)
+                                )
+                            {
+                                self.capsules_finished()?;
                                 self.set_state_to_close_pending();
                                 break Ok(());
                             }
@@ -267,6 +521,64 @@ impl RecvMessage {
                     if let Some(headers) =
                         decoder.decode_header_block(header_block, self.stream_id)?
                     {
+                        if header_list_size(&headers) > self.max_header_list_size {
+                            qinfo!([self], "headers are larger than the limit.");
+                            self.conn_events
+                                .local_reset(self.stream_id, Error::HttpExcessiveLoad.code());
+                            self.state = RecvMessageState::Closed;
+                            break Err(Error::HeaderListTooLarge);
+                        }
+                        let headers = if let Some(policy) = self.header_validation {
+                            match validate_headers(headers, self.message_type, policy) {
+                                Ok((headers, violations)) => {
+                                    if !violations.is_empty() {
+                                        self.conn_events
+                                            .header_violations(self.stream_id, violations);
+                                    }
+                                    headers
+                                }
+                                Err(e) => {
+                                    qinfo!([self], "headers are not valid.");
+                                    self.conn_events.local_reset(
+                                        self.stream_id,
+                                        Error::HttpMessageError.code(),
+                                    );
+                                    self.state = RecvMessageState::Closed;
+                                    break Err(e);
+                                }
+                            }
+                        } else {
+                            headers
+                        };
+                        if self.header_validation.is_some()
+                            && self.message_type == MessageType::Request
+                        {
+                            // A value that isn't valid has already been reported.
+                            self.content_length = content_length(&headers).unwrap_or(None);
+                            if fin {
+                                if let Err(e) = self.check_content_length(true) {
+                                    break Err(e);
+                                }
+                            }
+                        }
+                        if self.message_type == MessageType::Response {
+                            if let Some(status) = interim_status(&headers) {
+                                // 101 (Switching Protocols) can't be used in HTTP/3, and
+                                // an interim response can't end the stream.
+                                if fin || status == "101" {
+                                    qinfo!([self], "interim response {} is not valid.", status);
+                                    self.conn_events.local_reset(
+                                        self.stream_id,
+                                        Error::HttpMessageError.code(),
+                                    );
+                                    self.state = RecvMessageState::Closed;
+                                    break Err(Error::InvalidHeader);
+                                }
+                                self.conn_events.interim_headers(self.stream_id, headers);
+                                self.state = RecvMessageState::WaitingForResponseHeaders;
+                                continue;
+                            }
+                        }
                         self.add_headers(Some(headers), fin);
                         if fin {
                             break Ok(());
@@ -276,6 +588,72 @@ impl RecvMessage {
                         break Ok(());
                     }
                 }
+                RecvMessageState::DecodingTrailers {
+                    ref header_block,
+                    fin,
+                } => {
+                    if let Some(trailers) =
+                        decoder.decode_header_block(header_block, self.stream_id)?
+                    {
+                        if let Err(e) = self.add_trailers(trailers, fin) {
+                            break Err(e);
+                        }
+                        if fin {
+                            break Ok(());
+                        }
+                    } else {
+                        qinfo!([self], "decoding trailers is blocked.");
+                        break Ok(());
+                    }
+                }
+                RecvMessageState::DecodingPushPromise(ref promise) => {
+                    let push_id = promise.push_id;
+                    let headers_received = promise.headers_received;
+                    let fin = promise.fin;
+                    if let Some(headers) =
+                        decoder.decode_header_block(&promise.header_block, self.stream_id)?
+                    {
+                        self.push_handler
+                            .as_ref()
+                            .ok_or(Error::HttpId)?
+                            .borrow_mut()
+                            .new_push_promise(conn, push_id, self.stream_id, headers)?;
+                        self.state = if headers_received {
+                            RecvMessageState::WaitingForData
+                        } else {
+                            RecvMessageState::WaitingForResponseHeaders
+                        };
+                        if fin {
+                            self.capsules_finished()?;
+                            self.set_state_to_close_pending();
+                            break Ok(());
+                        }
+                    } else {
+                        qinfo!([self], "decoding a push promise is blocked.");
+                        break Ok(());
+                    }
+                }
+                RecvMessageState::ReadingData { remaining_data_len }
+                    if self.capsules_active() =>
+                {
+                    let (left, fin) = self.read_capsules(conn, remaining_data_len)?;
+                    if fin {
+                        if left > 0 {
+                            break Err(Error::HttpFrame);
+                        }
+                        self.capsules_finished()?;
+                        self.state = RecvMessageState::WaitingForData;
+                        self.set_state_to_close_pending();
+                        break Ok(());
+                    } else if left == 0 {
+                        self.state = RecvMessageState::WaitingForData;
+                    } else {
+                        self.state = RecvMessageState::ReadingData {
+                            remaining_data_len: left,
+                        };
+                        break Ok(());
+                    }
+                }
                 RecvMessageState::ReadingData { .. } => {
                     if post_readable_event {
                         self.conn_events.data_readable(self.stream_id);
@@ -296,4 +674,37 @@ impl RecvMessage {
     pub fn done(&self) -> bool {
         self.state == RecvMessageState::Closed
     }
+
+    /// Whether the headers have been received.
+    pub fn headers_received(&self) -> bool {
+        !matches!(
+            self.state,
+            RecvMessageState::WaitingForResponseHeaders
+                | RecvMessageState::DecodingHeaders { .. }
+                | RecvMessageState::DecodingPushPromise(PendingPushPromise {
+                    headers_received: false,
+                    ..
+                })
+        )
+    }
+
+    /// Whether a header block is waiting for QPACK, which has to be cancelled if the
+    /// stream is abandoned.
+    pub fn header_block_blocked(&self) -> bool {
+        matches!(
+            self.state,
+            RecvMessageState::DecodingHeaders { .. }
+                | RecvMessageState::DecodingTrailers { .. }
+                | RecvMessageState::DecodingPushPromise(..)
+        )
+    }
+
+    /// Whether the end of the message has been received, even if the application
+    /// has not read it yet.
+    pub fn fin_received(&self) -> bool {
+        matches!(
+            self.state,
+            RecvMessageState::ClosePending | RecvMessageState::Closed
+        )
+    }
 }
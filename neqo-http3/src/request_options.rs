@@ -0,0 +1,84 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The things about a request that `Http3Client::fetch_with` can change, beyond
+// the method, target and header fields that `fetch` takes.
+
+use crate::Priority;
+
+/// Options for `Http3Client::fetch_with`.  The defaults are what `fetch` uses.
+///
+/// ```
+/// # use neqo_http3::{Priority, RequestOptions};
+/// let options = RequestOptions::default()
+///     .priority(Priority::new(1, true))
+///     .allow_0rtt(false)
+///     .user_data(17);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestOptions {
+    pub(crate) priority: Priority,
+    pub(crate) allow_0rtt: bool,
+    pub(crate) authority: Option<String>,
+    pub(crate) expect_100_continue: bool,
+    pub(crate) user_data: u64,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            priority: Priority::default(),
+            allow_0rtt: true,
+            authority: None,
+            expect_100_continue: false,
+            user_data: 0,
+        }
+    }
+}
+
+impl RequestOptions {
+    /// The priority of the request.  If this isn't the default, it is sent to the
+    /// server in a `priority` header field, and it is used to schedule the request
+    /// body.  It can be changed later with `Http3Client::priority_update`.
+    #[must_use]
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Whether the request can be sent in 0-RTT.  Requests that aren't safe to
+    /// replay should not be; `fetch_with` fails with `Unavailable` for them until
+    /// the handshake is done.  This is allowed by default.
+    #[must_use]
+    pub fn allow_0rtt(mut self, allow: bool) -> Self {
+        self.allow_0rtt = allow;
+        self
+    }
+
+    /// Send this as `:authority` instead of the host that is given to `fetch_with`.
+    #[must_use]
+    pub fn authority(mut self, authority: &str) -> Self {
+        self.authority = Some(authority.to_owned());
+        self
+    }
+
+    /// Add `expect: 100-continue` to the request.  The application should wait
+    /// for an `Http3ClientEvent::InterimHeaders` with a 100 status, or for the final
+    /// response, before it sends the body.
+    #[must_use]
+    pub fn expect_100_continue(mut self, expect: bool) -> Self {
+        self.expect_100_continue = expect;
+        self
+    }
+
+    /// A value that the events for the request carry, so that they can be matched
+    /// to it without looking up the stream ID.
+    #[must_use]
+    pub fn user_data(mut self, user_data: u64) -> Self {
+        self.user_data = user_data;
+        self
+    }
+}
@@ -4,7 +4,11 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use crate::capsule::encode_capsule;
+use crate::efficiency::{QpackWriter, StreamBytes};
 use crate::hframe::HFrame;
+#[cfg(feature = "qlog")]
+use crate::qlog;
 use crate::Header;
 use crate::{Error, Res};
 use neqo_common::{matches, qdebug, qinfo, qtrace, Encoder};
@@ -20,9 +24,12 @@ const MAX_DATA_HEADER_SIZE_3: usize = (1 << 14) - 1; // Maximal amount of data w
 const MAX_DATA_HEADER_SIZE_3_LIMIT: usize = MAX_DATA_HEADER_SIZE_3 + 5; // 16383 + 5 (size of the next buffer data frame header)
 const MAX_DATA_HEADER_SIZE_5: usize = (1 << 30) - 1; // Maximal amount of data with DATA frame header size 3
 const MAX_DATA_HEADER_SIZE_5_LIMIT: usize = MAX_DATA_HEADER_SIZE_5 + 9; // 1073741823 + 9 (size of the next buffer data frame header)
+/// A body this many times larger than the connection flow control limit gets a warning.
+const BODY_FLOW_CONTROL_WARNING_FACTOR: u64 = 16;
 
 pub(crate) trait SendMessageEvents: Debug {
     fn data_writable(&self, stream_id: u64);
+    fn body_exceeds_flow_control(&self, stream_id: u64, body_len: u64, max_data: u64);
 }
 
 /*
@@ -36,6 +43,8 @@ pub(crate) trait SendMessageEvents: Debug {
  *                     SendingData or Closed (if the app does not want to send data and
  *                     has already closed the send stream).
  *    SendingData : We are sending request data until the app closes the stream.
+ *    TrailersPending : The app has supplied trailers, which are encoded and then sent
+ *                      like the initial message, followed by the FIN.
  *    Closed
  */
 
@@ -50,8 +59,13 @@ enum SendMessageState {
     SendingInitialMessage {
         buf: Vec<u8>,
         fin: bool,
+        /// What `buf` holds, which is counted once all of it is sent.
+        bytes: StreamBytes,
     },
     SendingData,
+    TrailersPending {
+        trailers: Vec<Header>,
+    },
     Closed,
 }
 
@@ -78,6 +92,9 @@ pub(crate) struct SendMessage {
     state: SendMessageState,
     stream_id: u64,
     conn_events: Box<dyn SendMessageEvents>,
+    /// How much of the body has been sent so far.
+    body_sent: u64,
+    flow_control_warned: bool,
 }
 
 impl SendMessage {
@@ -87,6 +104,8 @@ impl SendMessage {
             state: SendMessageState::Uninitialized,
             stream_id,
             conn_events,
+            body_sent: 0,
+            flow_control_warned: false,
         }
     }
 
@@ -104,6 +123,8 @@ impl SendMessage {
             },
             stream_id,
             conn_events,
+            body_sent: 0,
+            flow_control_warned: false,
         }
     }
 
@@ -124,13 +145,35 @@ impl SendMessage {
         Ok(())
     }
 
-    pub fn send_body(&mut self, conn: &mut Connection, buf: &[u8]) -> Res<usize> {
+    /// Send `trailers` after the body, which ends the message.  That is only
+    /// possible once the headers have been sent and while the body can be added to.
+    pub fn set_trailers(&mut self, trailers: &[Header]) -> Res<()> {
+        match self.state {
+            SendMessageState::SendingData => {
+                self.state = SendMessageState::TrailersPending {
+                    trailers: trailers.to_vec(),
+                };
+                Ok(())
+            }
+            SendMessageState::Initialized { fin: false, .. }
+            | SendMessageState::SendingInitialMessage { fin: false, .. } => Err(Error::Unavailable),
+            _ => Err(Error::AlreadyClosed),
+        }
+    }
+
+    pub fn send_body(
+        &mut self,
+        conn: &mut Connection,
+        buf: &[u8],
+        stats: &mut StreamBytes,
+    ) -> Res<usize> {
         qinfo!(
             [self],
             "send_request_body: state={:?} len={}",
             self.state,
             buf.len()
         );
+        self.check_flow_control(conn, buf.len());
         match self.state {
             SendMessageState::Uninitialized
             | SendMessageState::Initialized { .. }
@@ -167,18 +210,86 @@ impl SendMessage {
                 };
                 let mut enc = Encoder::default();
                 data_frame.encode(&mut enc);
-                match conn.stream_send(self.stream_id, &enc) {
-                    Ok(sent) => {
-                        debug_assert_eq!(sent, enc.len());
+                enc.encode(&buf[..to_send]);
+                // The frame header and the data are sent together or not at all, so that a
+                // header is never left on the stream without the data it announces.
+                match conn.stream_send_atomic(self.stream_id, &enc) {
+                    Ok(true) => {
+                        stats.h3_framing += (enc.len() - to_send) as u64;
+                        stats.app_payload += to_send as u64;
+                        self.body_sent += to_send as u64;
+                        #[cfg(feature = "qlog")]
+                        qlog::h3_frame_created(conn.qlog_mut(), self.stream_id, &data_frame)?;
+                        Ok(to_send)
                     }
-                    Err(e) => return Err(Error::TransportError(e)),
-                }
-                match conn.stream_send(self.stream_id, &buf[..to_send]) {
-                    Ok(sent) => Ok(sent),
+                    Ok(false) => Ok(0),
                     Err(e) => Err(Error::TransportError(e)),
                 }
             }
-            SendMessageState::Closed => Err(Error::AlreadyClosed),
+            SendMessageState::TrailersPending { .. } | SendMessageState::Closed => {
+                Err(Error::AlreadyClosed)
+            }
+        }
+    }
+
+    /// Send a capsule in a DATA frame of its own.  All of it is sent or none of it,
+    /// and nothing is sent until the headers are.
+    pub fn send_capsule(
+        &mut self,
+        conn: &mut Connection,
+        capsule_type: u64,
+        payload: &[u8],
+        stats: &mut StreamBytes,
+    ) -> Res<bool> {
+        match self.state {
+            SendMessageState::SendingData => {
+                let mut capsule = Encoder::default();
+                encode_capsule(&mut capsule, capsule_type, payload);
+                let mut enc = Encoder::default();
+                let data_frame = HFrame::Data {
+                    len: capsule.len() as u64,
+                };
+                data_frame.encode(&mut enc);
+                enc.encode(&capsule);
+                let sent = conn.stream_send_atomic(self.stream_id, &enc)?;
+                if sent {
+                    // The capsule is the payload of the DATA frame.
+                    stats.h3_framing += (enc.len() - capsule.len()) as u64;
+                    stats.app_payload += capsule.len() as u64;
+                    #[cfg(feature = "qlog")]
+                    qlog::h3_frame_created(conn.qlog_mut(), self.stream_id, &data_frame)?;
+                }
+                Ok(sent)
+            }
+            SendMessageState::TrailersPending { .. } | SendMessageState::Closed => {
+                Err(Error::AlreadyClosed)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Warn, once, if the body is so much larger than the connection flow control
+    /// limit that sending it will spend most of its time blocked.  The body is what
+    /// was sent already and the `len` bytes that are being sent now, so a body that
+    /// is sent a piece at a time is caught as well as one that is sent all at once.
+    fn check_flow_control(&mut self, conn: &Connection, len: usize) {
+        if self.flow_control_warned {
+            return;
+        }
+        let max_data = conn.remote_stream_limits().max_data;
+        let body_len = self
+            .body_sent
+            .saturating_add(u64::try_from(len).unwrap_or(u64::max_value()));
+        if body_len > max_data.saturating_mul(BODY_FLOW_CONTROL_WARNING_FACTOR) {
+            qinfo!(
+                [self],
+                "Body of {} exceeds connection flow control of {}",
+                body_len,
+                max_data
+            );
+            self.flow_control_warned = true;
+            self.conn_events
+                .body_exceeds_flow_control(self.stream_id, body_len, max_data);
         }
     }
 
@@ -194,15 +305,55 @@ impl SendMessage {
         self.state.is_state_sending_data()
     }
 
-    fn ensure_encoded(&mut self, conn: &mut Connection, encoder: &mut QPackEncoder) -> Res<()> {
+    fn ensure_encoded(
+        &mut self,
+        conn: &mut Connection,
+        encoder: &mut QPackEncoder,
+        stats: &mut StreamBytes,
+    ) -> Res<()> {
+        if let SendMessageState::TrailersPending { trailers } = &self.state {
+            qdebug!([self], "Encoding trailers");
+            let header_block = encoder.encode_header_block(
+                &mut QpackWriter::new(conn, &mut stats.qpack),
+                &trailers,
+                self.stream_id,
+            )?;
+            let hframe = HFrame::Headers {
+                header_block: header_block.to_vec(),
+            };
+            let mut d = Encoder::default();
+            hframe.encode(&mut d);
+            #[cfg(feature = "qlog")]
+            qlog::h3_frame_created(conn.qlog_mut(), self.stream_id, &hframe)?;
+            let bytes = StreamBytes {
+                qpack: header_block.len() as u64,
+                h3_framing: (d.len() - header_block.len()) as u64,
+                ..StreamBytes::default()
+            };
+            self.state = SendMessageState::SendingInitialMessage {
+                buf: d.into(),
+                fin: true,
+                bytes,
+            };
+        }
         if let SendMessageState::Initialized { headers, data, fin } = &self.state {
             qdebug!([self], "Encoding headers");
-            let header_block = encoder.encode_header_block(conn, &headers, self.stream_id)?;
+            let header_block = encoder.encode_header_block(
+                &mut QpackWriter::new(conn, &mut stats.qpack),
+                &headers,
+                self.stream_id,
+            )?;
+            let mut bytes = StreamBytes {
+                qpack: header_block.len() as u64,
+                ..StreamBytes::default()
+            };
             let hframe = HFrame::Headers {
                 header_block: header_block.to_vec(),
             };
             let mut d = Encoder::default();
             hframe.encode(&mut d);
+            #[cfg(feature = "qlog")]
+            qlog::h3_frame_created(conn.qlog_mut(), self.stream_id, &hframe)?;
             if let Some(buf) = data {
                 qdebug!([self], "Encoding data");
                 let d_frame = HFrame::Data {
@@ -210,18 +361,28 @@ impl SendMessage {
                 };
                 d_frame.encode(&mut d);
                 d.encode(&buf);
+                bytes.app_payload = buf.len() as u64;
+                #[cfg(feature = "qlog")]
+                qlog::h3_frame_created(conn.qlog_mut(), self.stream_id, &d_frame)?;
             }
+            bytes.h3_framing = d.len() as u64 - bytes.qpack - bytes.app_payload;
 
             self.state = SendMessageState::SendingInitialMessage {
                 buf: d.into(),
                 fin: *fin,
+                bytes,
             };
         }
         Ok(())
     }
 
-    pub fn send(&mut self, conn: &mut Connection, encoder: &mut QPackEncoder) -> Res<()> {
-        self.ensure_encoded(conn, encoder)?;
+    pub fn send(
+        &mut self,
+        conn: &mut Connection,
+        encoder: &mut QPackEncoder,
+        stats: &mut StreamBytes,
+    ) -> Res<()> {
+        self.ensure_encoded(conn, encoder, stats)?;
 
         let label = if ::log::log_enabled!(::log::Level::Debug) {
             format!("{}", self)
@@ -229,11 +390,17 @@ impl SendMessage {
             String::new()
         };
 
-        if let SendMessageState::SendingInitialMessage { ref mut buf, fin } = self.state {
+        if let SendMessageState::SendingInitialMessage {
+            ref mut buf,
+            fin,
+            bytes,
+        } = self.state
+        {
             let sent = conn.stream_send(self.stream_id, &buf)?;
             qtrace!([label], "{} bytes sent", sent);
 
             if sent == buf.len() {
+                stats.add(&bytes);
                 if fin {
                     conn.stream_close_send(self.stream_id)?;
                     self.state = SendMessageState::Closed;
@@ -255,7 +422,12 @@ impl SendMessage {
     // This method returns if they're still being sent. Request body (if any) is sent by
     // http client afterwards using `send_request_body` after receiving DataWritable event.
     pub fn has_data_to_send(&self) -> bool {
-        matches!(self.state, SendMessageState::Initialized {..} | SendMessageState::SendingInitialMessage { .. } )
+        matches!(
+            self.state,
+            SendMessageState::Initialized { .. }
+                | SendMessageState::SendingInitialMessage { .. }
+                | SendMessageState::TrailersPending { .. }
+        )
     }
 
     pub fn close(&mut self, conn: &mut Connection) -> Res<()> {
@@ -264,6 +436,8 @@ impl SendMessage {
             | SendMessageState::Initialized { ref mut fin, .. } => {
                 *fin = true;
             }
+            // The FIN follows the trailers.
+            SendMessageState::TrailersPending { .. } => {}
             _ => {
                 self.state = SendMessageState::Closed;
                 conn.stream_close_send(self.stream_id)?;
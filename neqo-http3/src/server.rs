@@ -6,15 +6,20 @@
 
 #![allow(clippy::module_name_repetitions)]
 
-use crate::connection::Http3State;
+use crate::churn::ChurnStats;
+use crate::connection::{Http3Parameters, Http3State};
 use crate::connection_server::Http3ServerHandler;
+use crate::efficiency::EfficiencyStats;
+use crate::hsettings_frame::check_extra_setting;
+#[cfg(feature = "client-helpers")]
+use crate::recording::Recording;
 use crate::server_connection_events::Http3ServerConnEvent;
 use crate::server_events::{ClientRequestStream, Http3ServerEvent, Http3ServerEvents};
-use crate::Res;
+use crate::{RequestValidation, Res};
 use neqo_common::{qtrace, Datagram};
 use neqo_crypto::AntiReplay;
 use neqo_qpack::QpackSettings;
-use neqo_transport::server::{ActiveConnectionRef, Server};
+use neqo_transport::server::{ActiveConnectionRef, Server, ValidateAddress};
 use neqo_transport::{ConnectionIdManager, Output};
 use std::cell::RefCell;
 use std::cell::RefMut;
@@ -30,8 +35,17 @@ const MAX_EVENT_DATA_SIZE: usize = 1024;
 pub struct Http3Server {
     server: Server,
     qpack_settings: QpackSettings,
+    request_validation: RequestValidation,
+    extra_settings: Vec<(u64, u64)>,
+    stream_rate_limit: Option<u64>,
     http3_handlers: HashMap<ActiveConnectionRef, HandlerRef>,
+    /// The streams of connections that are gone.
+    closed_churn: ChurnStats,
+    /// What connections that are gone sent.
+    closed_efficiency: EfficiencyStats,
     events: Http3ServerEvents,
+    #[cfg(feature = "client-helpers")]
+    recording: Option<Recording>,
 }
 
 impl ::std::fmt::Display for Http3Server {
@@ -52,18 +66,114 @@ impl Http3Server {
         cid_manager: Rc<RefCell<dyn ConnectionIdManager>>,
         qpack_settings: QpackSettings,
     ) -> Res<Self> {
-        Ok(Self {
+        let mut server = Self {
             server: Server::new(now, certs, protocols, anti_replay, cid_manager)?,
             qpack_settings,
+            request_validation: RequestValidation::default(),
+            extra_settings: Vec::new(),
+            stream_rate_limit: None,
             http3_handlers: HashMap::new(),
+            closed_churn: ChurnStats::default(),
+            closed_efficiency: EfficiencyStats::default(),
             events: Http3ServerEvents::default(),
-        })
+            #[cfg(feature = "client-helpers")]
+            recording: None,
+        };
+        server.set_parameters(&Http3Parameters::default())?;
+        Ok(server)
+    }
+
+    /// Set the transport parameters that are sent to clients.  This only affects
+    /// connections that are accepted afterwards.
+    /// # Errors
+    /// `InvalidParameters` if one of `params` can't be sent.
+    pub fn set_parameters(&mut self, params: &Http3Parameters) -> Res<()> {
+        self.server
+            .set_connection_parameters(params.get_connection_parameters().clone())?;
+        Ok(())
     }
 
     pub fn set_qlog_dir(&mut self, dir: Option<PathBuf>) {
         self.server.set_qlog_dir(dir)
     }
 
+    /// Set whether clients have to show that they can receive packets at their
+    /// address before a connection is accepted, see `Server::set_validation`.
+    pub fn set_validation(&mut self, validate: ValidateAddress) {
+        self.server.set_validation(validate);
+    }
+
+    /// Set whether clients can move connections to another address, see
+    /// `Server::set_allow_migration`.  This only affects connections that are
+    /// accepted afterwards.
+    pub fn set_allow_migration(&mut self, allow: bool) {
+        self.server.set_allow_migration(allow);
+    }
+
+    /// Set what happens to requests that break the rules for HTTP/3 messages.
+    /// This only affects connections that are accepted afterwards.  The default
+    /// is `RequestValidation::Deliver`.
+    pub fn set_request_validation(&mut self, validation: RequestValidation) {
+        self.request_validation = validation;
+    }
+
+    /// Add a setting with identifier `id` and `value` to the SETTINGS frame that is
+    /// sent to clients.  This is for settings that aren't used here, such as GREASE
+    /// settings.  This only affects connections that are accepted afterwards.
+    /// # Errors
+    /// `InvalidState` if `id` is a setting that is sent here, is reserved for HTTP/2,
+    /// or was already added.
+    pub fn add_setting(&mut self, id: u64, value: u64) -> Res<()> {
+        check_extra_setting(&self.extra_settings, id)?;
+        self.extra_settings.push((id, value));
+        Ok(())
+    }
+
+    /// Limit the number of streams that each client can open each second, or remove
+    /// the limit with `None`, which is the default.  A client that opens more, for
+    /// instance by opening and resetting requests as fast as it can, is closed with
+    /// `HttpExcessiveLoad`.  This only affects connections that are accepted afterwards.
+    pub fn set_stream_rate_limit(&mut self, limit: Option<u64>) {
+        self.stream_rate_limit = limit;
+    }
+
+    /// How many streams clients have opened and how many of them they reset before
+    /// they were any use, over all connections so far.
+    #[must_use]
+    pub fn churn_stats(&self) -> ChurnStats {
+        let mut stats = self.closed_churn;
+        for handler in self.http3_handlers.values() {
+            stats.add(&handler.borrow().churn_stats());
+        }
+        stats
+    }
+
+    /// What the bytes that the server has sent were used for, over all connections
+    /// so far.  Datagrams that are not sent on a connection, such as Retry and
+    /// Version Negotiation, are not counted.
+    #[must_use]
+    pub fn efficiency_stats(&self) -> EfficiencyStats {
+        let mut stats = self.closed_efficiency;
+        for (conn, handler) in &self.http3_handlers {
+            stats.add(&handler.borrow().efficiency_stats(&conn.borrow()));
+        }
+        stats
+    }
+
+    /// Start closing every connection gracefully.  Each client is sent GOAWAY, so
+    /// that it makes no more requests, and its connection is closed with `H3_NO_ERROR`
+    /// once the requests that it has already made have been answered or reset.
+    /// Connections that have not finished their handshake are not affected.
+    pub fn close_gracefully(&mut self) {
+        for (conn, handler) in &self.http3_handlers {
+            let mut conn = conn.clone();
+            // Only connections that can't send GOAWAY yet fail.
+            let _ = handler
+                .borrow_mut()
+                .close_gracefully(&mut conn.borrow_mut());
+        }
+    }
+
     pub fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
         qtrace!([self], "Process.");
         let out = self.server.process(dgram, now);
@@ -104,11 +214,18 @@ impl Http3Server {
             .iter()
             .for_each(|conn| self.server.add_to_waiting(conn.clone()));
         let qpack_settings = self.qpack_settings;
+        let request_validation = self.request_validation;
+        let extra_settings = &self.extra_settings;
+        let stream_rate_limit = self.stream_rate_limit;
         for mut conn in active_conns {
-            let handler = self
-                .http3_handlers
-                .entry(conn.clone())
-                .or_insert_with(|| Rc::new(RefCell::new(Http3ServerHandler::new(qpack_settings))));
+            let handler = self.http3_handlers.entry(conn.clone()).or_insert_with(|| {
+                Rc::new(RefCell::new(Http3ServerHandler::new(
+                    qpack_settings,
+                    request_validation,
+                    extra_settings,
+                    stream_rate_limit,
+                )))
+            });
 
             handler
                 .borrow_mut()
@@ -127,6 +244,32 @@ impl Http3Server {
                             headers,
                             fin,
                         ),
+                        Http3ServerConnEvent::HeaderViolations {
+                            stream_id,
+                            violations,
+                        } => {
+                            if handler_borrowed.request_validation() == RequestValidation::Reject {
+                                // Only the stream is affected, which is now gone.
+                                let _ = handler_borrowed
+                                    .reject_request(&mut conn.borrow_mut(), stream_id);
+                            } else {
+                                self.events.header_violations(
+                                    ClientRequestStream::new(
+                                        conn.clone(),
+                                        handler.clone(),
+                                        stream_id,
+                                    ),
+                                    violations,
+                                );
+                            }
+                        }
+                        Http3ServerConnEvent::Trailers {
+                            stream_id,
+                            trailers,
+                        } => self.events.trailers(
+                            ClientRequestStream::new(conn.clone(), handler.clone(), stream_id),
+                            trailers,
+                        ),
                         Http3ServerConnEvent::DataReadable { stream_id } => {
                             prepare_data(
                                 stream_id,
@@ -137,6 +280,15 @@ impl Http3Server {
                                 &mut self.events,
                             );
                         }
+                        Http3ServerConnEvent::Reset { stream_id, error } => self.events.reset(
+                            ClientRequestStream::new(conn.clone(), handler.clone(), stream_id),
+                            error,
+                        ),
+                        Http3ServerConnEvent::SettingsReceived => {
+                            if let Some(settings) = handler_borrowed.peer_settings() {
+                                self.events.settings_received(conn.clone(), settings);
+                            }
+                        }
                         Http3ServerConnEvent::StateChange(state) => {
                             self.events
                                 .connection_state_change(conn.clone(), state.clone());
@@ -144,11 +296,18 @@ impl Http3Server {
                                 remove = true;
                             }
                         }
+                        Http3ServerConnEvent::CongestionStateChanged(state) => {
+                            self.events.congestion_state_changed(conn.clone(), state);
+                        }
                     }
                 }
             }
             if remove {
-                self.http3_handlers.remove(&conn.clone());
+                if let Some(handler) = self.http3_handlers.remove(&conn.clone()) {
+                    self.closed_churn.add(&handler.borrow().churn_stats());
+                    self.closed_efficiency
+                        .add(&handler.borrow().efficiency_stats(&conn.borrow()));
+                }
             }
         }
     }
@@ -156,7 +315,14 @@ impl Http3Server {
     /// Get all current events. Best used just in debug/testing code, use
     /// `next_event` instead.
     pub fn events(&mut self) -> impl Iterator<Item = Http3ServerEvent> {
-        self.events.events()
+        let events = self.events.events().collect::<Vec<_>>();
+        #[cfg(feature = "client-helpers")]
+        if let Some(r) = &mut self.recording {
+            for e in &events {
+                r.server_event(e);
+            }
+        }
+        events.into_iter()
     }
 
     /// Return true if there are outstanding events.
@@ -169,7 +335,25 @@ impl Http3Server {
     /// correctly handles cases where handling one event can obsolete
     /// previously-queued events, or cause new events to be generated.
     pub fn next_event(&mut self) -> Option<Http3ServerEvent> {
-        self.events.next_event()
+        let event = self.events.next_event();
+        #[cfg(feature = "client-helpers")]
+        if let (Some(r), Some(e)) = (&mut self.recording, &event) {
+            r.server_event(e);
+        }
+        event
+    }
+
+    /// Start recording the events that are taken from this, discarding any
+    /// earlier recording.  Requests are recorded by stream ID only.
+    #[cfg(feature = "client-helpers")]
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Recording::default());
+    }
+
+    /// Stop recording and return what was recorded, if anything.
+    #[cfg(feature = "client-helpers")]
+    pub fn take_recording(&mut self) -> Option<Recording> {
+        self.recording.take()
     }
 }
 fn prepare_data(
@@ -199,8 +383,8 @@ fn prepare_data(
                 break;
             }
         } else {
-            // Any error will closed the handler, just ignore this event, the next event must
-            // be a state change event.
+            // Any other error will closed the handler, just ignore this event, the next event
+            // must be a state change event.  A stream that is gone has nothing to read.
             break;
         }
     }
@@ -208,15 +392,18 @@ fn prepare_data(
 
 #[cfg(test)]
 mod tests {
-    use super::{Http3Server, Http3ServerEvent, Http3State, Rc, RefCell};
-    use crate::{Error, Header};
-    use neqo_common::matches;
+    use super::{Http3Parameters, Http3Server, Http3ServerEvent, Http3State, Rc, RefCell};
+    use crate::hframe::HFrame;
+    use crate::{Error, Header, HeaderViolations, RequestValidation, Settings};
+    use neqo_common::{matches, Encoder};
     use neqo_crypto::AuthenticationStatus;
     use neqo_qpack::encoder::QPackEncoder;
     use neqo_qpack::QpackSettings;
+    use neqo_transport::tparams::{self, TransportParameter};
     use neqo_transport::{
         CloseError, Connection, ConnectionEvent, FixedConnectionIdManager, State, StreamType,
     };
+    use std::time::{Duration, Instant};
     use test_fixture::{
         anti_replay, default_client, fixture_init, now, DEFAULT_ALPN, DEFAULT_KEYS,
     };
@@ -272,9 +459,20 @@ mod tests {
     const SERVER_SIDE_DECODER_STREAM_ID: u64 = 11;
 
     // Start a client/server and check setting frame.
-    #[allow(clippy::cognitive_complexity)]
     fn connect_and_receive_settings() -> (Http3Server, Connection) {
-        // Create a server and connect it to a client.
+        connect_and_receive_settings_to(default_http3_server())
+    }
+
+    fn connect_and_receive_settings_to(hconn: Http3Server) -> (Http3Server, Connection) {
+        connect_peer_and_receive_settings(hconn, default_client())
+    }
+
+    #[allow(clippy::cognitive_complexity)]
+    fn connect_peer_and_receive_settings(
+        mut hconn: Http3Server,
+        mut neqo_trans_conn: Connection,
+    ) -> (Http3Server, Connection) {
+        // Connect a server to a client.
         // We will have a http3 server on one side and a neqo_transport
         // connection on the other side so that we can check what the http3
         // side sends and also to simulate an incorrectly behaving http3
@@ -282,10 +480,6 @@ mod tests {
 
         const CONTROL_STREAM_DATA: &[u8] = &[0x0, 0x4, 0x6, 0x1, 0x40, 0x64, 0x7, 0x40, 0x64];
 
-        fixture_init();
-        let mut hconn = default_http3_server();
-        let mut neqo_trans_conn = default_client();
-
         let out = neqo_trans_conn.process(None, now());
         let out = hconn.process(out.dgram(), now());
         let out = neqo_trans_conn.process(out.dgram(), now());
@@ -368,6 +562,24 @@ mod tests {
         let _ = connect_and_receive_settings();
     }
 
+    // The client sees the receive windows from the default `Http3Parameters`.
+    #[test]
+    fn test_server_default_parameters() {
+        let (_hconn, neqo_trans_conn) = connect_and_receive_settings();
+        let params = Http3Parameters::default();
+        let params = params.get_connection_parameters();
+        let limits = neqo_trans_conn.remote_stream_limits();
+        assert_eq!(
+            limits.initial_max_stream_data_bidi_remote,
+            params.get_max_stream_data_bidi_remote()
+        );
+        assert_eq!(
+            limits.initial_max_stream_data_uni,
+            params.get_max_stream_data_uni()
+        );
+        assert_eq!(limits.max_data, params.get_max_data());
+    }
+
     struct PeerConnection {
         conn: Connection,
         control_stream_id: u64,
@@ -375,7 +587,27 @@ mod tests {
 
     // Connect transport, send and receive settings.
     fn connect() -> (Http3Server, PeerConnection) {
-        let (mut hconn, mut neqo_trans_conn) = connect_and_receive_settings();
+        connect_with(RequestValidation::default())
+    }
+
+    fn connect_with(request_validation: RequestValidation) -> (Http3Server, PeerConnection) {
+        let mut hconn = default_http3_server();
+        hconn.set_request_validation(request_validation);
+        connect_to(hconn)
+    }
+
+    // Connect a client to `hconn`, which has been configured already.
+    fn connect_to(hconn: Http3Server) -> (Http3Server, PeerConnection) {
+        connect_peer_to(hconn, default_client())
+    }
+
+    // Connect `neqo_trans_conn` to `hconn`, both of which have been configured already.
+    fn connect_peer_to(
+        hconn: Http3Server,
+        neqo_trans_conn: Connection,
+    ) -> (Http3Server, PeerConnection) {
+        let (mut hconn, mut neqo_trans_conn) =
+            connect_peer_and_receive_settings(hconn, neqo_trans_conn);
         let control_stream = neqo_trans_conn.stream_create(StreamType::UniDi).unwrap();
         let mut sent = neqo_trans_conn.stream_send(
             control_stream,
@@ -446,7 +678,7 @@ mod tests {
     }
 
     // Server: receiving SETTINGS frame twice causes connection close
-    // with error HTTP_UNEXPECTED_FRAME.
+    // with error HTTP_SETTINGS_ERROR.
     #[test]
     fn test_server_receive_settings_twice() {
         let (mut hconn, mut peer_conn) = connect();
@@ -458,7 +690,50 @@ mod tests {
         assert_eq!(sent, Ok(8));
         let out = peer_conn.conn.process(None, now());
         hconn.process(out.dgram(), now());
-        assert_closed(&mut hconn, &Error::HttpFrameUnexpected);
+        assert_closed(&mut hconn, &Error::HttpSettings);
+    }
+
+    // Server: the client's settings are reported once they arrive, including
+    // those that aren't used here.
+    #[test]
+    fn test_server_settings_received() {
+        let (mut hconn, mut neqo_trans_conn) = connect_and_receive_settings();
+        let control_stream = neqo_trans_conn.stream_create(StreamType::UniDi).unwrap();
+        // SETTINGS with a GREASE setting after the QPACK settings.
+        let sent = neqo_trans_conn.stream_send(
+            control_stream,
+            &[0x0, 0x4, 0x8, 0x1, 0x40, 0x64, 0x7, 0x40, 0x64, 0x21, 0x3],
+        );
+        assert_eq!(sent, Ok(11));
+        let out = neqo_trans_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+
+        let settings = hconn.events().find_map(|e| {
+            if let Http3ServerEvent::SettingsReceived { settings, .. } = e {
+                Some(settings)
+            } else {
+                None
+            }
+        });
+        assert_eq!(
+            settings,
+            Some(Settings {
+                max_table_capacity: 100,
+                blocked_streams: 100,
+                max_header_list_size: None,
+                enable_connect_protocol: false,
+                other: vec![(0x21, 3)],
+            })
+        );
+    }
+
+    #[test]
+    fn test_server_add_setting() {
+        let mut hconn = default_http3_server();
+        hconn.add_setting(0x21, 7).unwrap();
+        assert_eq!(hconn.add_setting(0x21, 8), Err(Error::InvalidState));
+        assert_eq!(hconn.add_setting(0x7, 8), Err(Error::InvalidState));
+        assert_eq!(hconn.add_setting(0x5, 0), Err(Error::InvalidState));
     }
 
     fn test_wrong_frame_on_control_stream(v: &[u8]) {
@@ -490,6 +765,88 @@ mod tests {
         test_wrong_frame_on_control_stream(&[0x5, 0x2, 0x1, 0x2]);
     }
 
+    // Server: PRIORITY_UPDATE for a request stream is accepted.
+    #[test]
+    fn test_server_priority_update() {
+        let (mut hconn, mut peer_conn) = connect();
+        let _ = peer_conn.conn.stream_send(
+            peer_conn.control_stream_id,
+            &[0x80, 0x0f, 0x07, 0x00, 0x04, 0x04, 0x75, 0x3d, 0x35],
+        );
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        assert_not_closed(&mut hconn);
+    }
+
+    // Server: PRIORITY_UPDATE for a stream that can't carry a request causes HttpId.
+    #[test]
+    fn test_server_priority_update_wrong_stream() {
+        let (mut hconn, mut peer_conn) = connect();
+        let _ = peer_conn.conn.stream_send(
+            peer_conn.control_stream_id,
+            &[0x80, 0x0f, 0x07, 0x00, 0x04, 0x02, 0x75, 0x3d, 0x35],
+        );
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        assert_closed(&mut hconn, &Error::HttpId);
+    }
+
+    // Server: PRIORITY_UPDATE for a request stream that the client isn't allowed
+    // to open yet causes HttpId.  The client has 16 streams, so stream 64 is beyond it.
+    #[test]
+    fn test_server_priority_update_beyond_stream_limit() {
+        let (mut hconn, mut peer_conn) = connect();
+        let _ = peer_conn.conn.stream_send(
+            peer_conn.control_stream_id,
+            &[0x80, 0x0f, 0x07, 0x00, 0x05, 0x40, 0x40, 0x75, 0x3d, 0x35],
+        );
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        assert_closed(&mut hconn, &Error::HttpId);
+    }
+
+    fn send_control_frames(v: &[u8]) -> Http3Server {
+        let (mut hconn, mut peer_conn) = connect();
+        let _ = peer_conn.conn.stream_send(peer_conn.control_stream_id, v);
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        hconn
+    }
+
+    // Server: PRIORITY_UPDATE for a push ID that MAX_PUSH_ID allows is accepted.
+    #[test]
+    fn test_server_priority_update_push() {
+        // MAX_PUSH_ID 3, then PRIORITY_UPDATE for push 3.
+        let mut hconn = send_control_frames(&[
+            0x0d, 0x01, 0x03, 0x80, 0x0f, 0x07, 0x01, 0x04, 0x03, 0x75, 0x3d, 0x35,
+        ]);
+        assert_not_closed(&mut hconn);
+    }
+
+    // Server: PRIORITY_UPDATE for a push ID above MAX_PUSH_ID causes HttpId.
+    #[test]
+    fn test_server_priority_update_push_above_max() {
+        let mut hconn = send_control_frames(&[
+            0x0d, 0x01, 0x03, 0x80, 0x0f, 0x07, 0x01, 0x04, 0x04, 0x75, 0x3d, 0x35,
+        ]);
+        assert_closed(&mut hconn, &Error::HttpId);
+    }
+
+    // Server: PRIORITY_UPDATE for a push before any MAX_PUSH_ID causes HttpId.
+    #[test]
+    fn test_server_priority_update_push_no_max() {
+        let mut hconn =
+            send_control_frames(&[0x80, 0x0f, 0x07, 0x01, 0x04, 0x00, 0x75, 0x3d, 0x35]);
+        assert_closed(&mut hconn, &Error::HttpId);
+    }
+
+    // Server: MAX_PUSH_ID that goes down causes HttpId.
+    #[test]
+    fn test_server_max_push_id_reduced() {
+        let mut hconn = send_control_frames(&[0x0d, 0x01, 0x03, 0x0d, 0x01, 0x02]);
+        assert_closed(&mut hconn, &Error::HttpId);
+    }
+
     // Server: receive unkonwn stream type
     // also test getting stream id that does not fit into a single byte.
     #[test]
@@ -945,4 +1302,595 @@ mod tests {
         hconn.process(out.dgram(), now());
         assert_closed(&mut hconn, &Error::HttpClosedCriticalStream);
     }
+    fn h(name: &str, value: &str) -> Header {
+        (String::from(name), String::from(value))
+    }
+
+    /// A request that is missing `:path`.
+    fn request_without_path() -> Vec<Header> {
+        vec![
+            h(":method", "GET"),
+            h(":scheme", "https"),
+            h(":authority", "something.com"),
+        ]
+    }
+
+    // Send a request with `headers` and `body`, closing the stream if `fin` is set.
+    fn send_request(
+        hconn: &mut Http3Server,
+        peer_conn: &mut PeerConnection,
+        headers: &[Header],
+        body: &[u8],
+        fin: bool,
+    ) -> u64 {
+        let stream_id = peer_conn.conn.stream_create(StreamType::BiDi).unwrap();
+        // Without a dynamic table, this doesn't need an encoder stream.
+        let mut encoder = QPackEncoder::new(
+            QpackSettings {
+                max_table_size_encoder: 0,
+                max_table_size_decoder: 0,
+                max_blocked_streams: 0,
+            },
+            true,
+        );
+        let header_block = encoder
+            .encode_header_block(&mut peer_conn.conn, headers, stream_id)
+            .unwrap();
+        let mut d = Encoder::default();
+        HFrame::Headers {
+            header_block: header_block.to_vec(),
+        }
+        .encode(&mut d);
+        if !body.is_empty() {
+            HFrame::Data {
+                len: body.len() as u64,
+            }
+            .encode(&mut d);
+            d.encode(body);
+        }
+        peer_conn.conn.stream_send(stream_id, &d).unwrap();
+        if fin {
+            peer_conn.conn.stream_close_send(stream_id).unwrap();
+        }
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        stream_id
+    }
+
+    // Deliver what the server sends to the peer.
+    fn server_to_peer(hconn: &mut Http3Server, peer_conn: &mut PeerConnection) {
+        let out = hconn.process(None, now());
+        peer_conn.conn.process(out.dgram(), now());
+    }
+
+    fn assert_peer_reset(peer_conn: &mut PeerConnection, request_stream_id: u64) {
+        assert!(peer_conn.conn.events().any(|e| matches!(e,
+            ConnectionEvent::RecvStreamReset { stream_id, app_error }
+                if stream_id == request_stream_id && app_error == Error::HttpMessageError.code())));
+    }
+
+    #[test]
+    fn test_server_request_validation_reject() {
+        let (mut hconn, mut peer_conn) = connect_with(RequestValidation::Reject);
+        let request_stream_id = send_request(
+            &mut hconn,
+            &mut peer_conn,
+            &request_without_path(),
+            &[],
+            false,
+        );
+
+        // The application doesn't see the request.
+        assert!(!hconn.events().any(|e| matches!(
+            e,
+            Http3ServerEvent::Headers { .. } | Http3ServerEvent::HeaderViolations { .. }
+        )));
+
+        server_to_peer(&mut hconn, &mut peer_conn);
+        let mut stop_sending = false;
+        let mut readable = false;
+        while let Some(e) = peer_conn.conn.next_event() {
+            match e {
+                ConnectionEvent::SendStreamStopSending {
+                    stream_id,
+                    app_error,
+                } => {
+                    assert_eq!(stream_id, request_stream_id);
+                    assert_eq!(app_error, Error::HttpNoError.code());
+                    stop_sending = true;
+                }
+                ConnectionEvent::RecvStreamReadable { stream_id } => {
+                    assert_eq!(stream_id, request_stream_id);
+                    readable = true;
+                }
+                _ => {}
+            }
+        }
+        assert!(stop_sending);
+        assert!(readable);
+
+        // The response is a HEADERS frame.
+        let mut buf = [0_u8; 100];
+        let (amount, fin) = peer_conn
+            .conn
+            .stream_recv(request_stream_id, &mut buf)
+            .unwrap();
+        assert!(amount > 0);
+        assert_eq!(buf[0], 0x01);
+        assert!(fin);
+        assert_not_closed(&mut hconn);
+    }
+
+    #[test]
+    fn test_server_request_validation_reset() {
+        let (mut hconn, mut peer_conn) = connect_with(RequestValidation::Reset);
+        let request_stream_id = send_request(
+            &mut hconn,
+            &mut peer_conn,
+            &request_without_path(),
+            &[],
+            true,
+        );
+        assert!(!hconn.events().any(|e| matches!(
+            e,
+            Http3ServerEvent::Headers { .. } | Http3ServerEvent::HeaderViolations { .. }
+        )));
+
+        server_to_peer(&mut hconn, &mut peer_conn);
+        assert_peer_reset(&mut peer_conn, request_stream_id);
+        assert_not_closed(&mut hconn);
+    }
+
+    #[test]
+    fn test_server_request_validation_deliver() {
+        let (mut hconn, mut peer_conn) = connect_with(RequestValidation::Deliver);
+        let headers = vec![
+            h(":method", "GET"),
+            h(":scheme", "https"),
+            h(":authority", "something.com"),
+            h(":path", "/"),
+            h(":path", "/"),
+        ];
+        send_request(&mut hconn, &mut peer_conn, &headers, &[], true);
+
+        let events = hconn.events().collect::<Vec<_>>();
+        let violations = events
+            .iter()
+            .position(|e| {
+                matches!(e,
+                Http3ServerEvent::HeaderViolations { violations, .. }
+                    if *violations == HeaderViolations::DUPLICATE_PSEUDO_HEADER)
+            })
+            .unwrap();
+        let headers_event = events
+            .iter()
+            .position(|e| {
+                matches!(e,
+                Http3ServerEvent::Headers { headers: Some(h), fin: true, .. } if *h == headers)
+            })
+            .unwrap();
+        assert!(violations < headers_event);
+    }
+
+    // A valid request is delivered without a `HeaderViolations` event in any mode.
+    #[test]
+    fn test_server_request_validation_valid() {
+        for validation in &[
+            RequestValidation::Reject,
+            RequestValidation::Reset,
+            RequestValidation::Deliver,
+        ] {
+            let (mut hconn, mut peer_conn) = connect_with(*validation);
+            let headers = vec![
+                h(":method", "POST"),
+                h(":scheme", "https"),
+                h(":authority", "something.com"),
+                h(":path", "/"),
+                h("content-length", "6"),
+            ];
+            send_request(&mut hconn, &mut peer_conn, &headers, REQUEST_BODY, true);
+            let events = hconn.events().collect::<Vec<_>>();
+            assert!(!events
+                .iter()
+                .any(|e| matches!(e, Http3ServerEvent::HeaderViolations { .. })));
+            assert!(events.iter().any(|e| matches!(e,
+                Http3ServerEvent::Data { data, fin: true, .. } if data == REQUEST_BODY)));
+        }
+    }
+
+    fn request_with_content_length(len: &str) -> Vec<Header> {
+        vec![
+            h(":method", "POST"),
+            h(":scheme", "https"),
+            h(":authority", "something.com"),
+            h(":path", "/"),
+            h("content-length", len),
+        ]
+    }
+
+    #[test]
+    fn test_server_content_length_mismatch_deliver() {
+        let (mut hconn, mut peer_conn) = connect_with(RequestValidation::Deliver);
+        send_request(
+            &mut hconn,
+            &mut peer_conn,
+            &request_with_content_length("10"),
+            REQUEST_BODY,
+            true,
+        );
+        let events = hconn.events().collect::<Vec<_>>();
+        let data = events
+            .iter()
+            .position(|e| matches!(e, Http3ServerEvent::Data { .. }))
+            .unwrap();
+        let violations = events
+            .iter()
+            .position(|e| {
+                matches!(e,
+                Http3ServerEvent::HeaderViolations { violations, .. }
+                    if *violations == HeaderViolations::CONTENT_LENGTH)
+            })
+            .unwrap();
+        assert!(data < violations);
+    }
+
+    #[test]
+    fn test_server_content_length_mismatch_reset() {
+        let (mut hconn, mut peer_conn) = connect_with(RequestValidation::Reset);
+        let request_stream_id = send_request(
+            &mut hconn,
+            &mut peer_conn,
+            &request_with_content_length("3"),
+            REQUEST_BODY,
+            true,
+        );
+        let events = hconn.events().collect::<Vec<_>>();
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, Http3ServerEvent::Headers { .. })));
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, Http3ServerEvent::Data { .. })));
+
+        server_to_peer(&mut hconn, &mut peer_conn);
+        assert_peer_reset(&mut peer_conn, request_stream_id);
+        assert_not_closed(&mut hconn);
+    }
+
+    // A request that ends with its headers can't have a body.
+    #[test]
+    fn test_server_content_length_without_body() {
+        let (mut hconn, mut peer_conn) = connect_with(RequestValidation::Reset);
+        let request_stream_id = send_request(
+            &mut hconn,
+            &mut peer_conn,
+            &request_with_content_length("3"),
+            &[],
+            true,
+        );
+        assert!(!hconn
+            .events()
+            .any(|e| matches!(e, Http3ServerEvent::Headers { .. })));
+
+        server_to_peer(&mut hconn, &mut peer_conn);
+        assert_peer_reset(&mut peer_conn, request_stream_id);
+    }
+
+    // Trailers with "age: 0", which is in the static table.
+    const TRAILER_FRAME: &[u8] = &[0x01, 0x03, 0x00, 0x00, 0xc2];
+    // Trailers with ":status: 200", which isn't allowed.
+    const TRAILER_FRAME_PSEUDO: &[u8] = &[0x01, 0x03, 0x00, 0x00, 0xd9];
+
+    // Send a request with a body followed by `trailers`.
+    fn send_request_with_trailers(
+        hconn: &mut Http3Server,
+        peer_conn: &mut PeerConnection,
+        trailers: &[u8],
+    ) -> u64 {
+        let request_stream_id = send_request(
+            hconn,
+            peer_conn,
+            &request_with_content_length("6"),
+            REQUEST_BODY,
+            false,
+        );
+        peer_conn
+            .conn
+            .stream_send(request_stream_id, trailers)
+            .unwrap();
+        peer_conn.conn.stream_close_send(request_stream_id).unwrap();
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        request_stream_id
+    }
+
+    #[test]
+    fn test_server_request_trailers() {
+        let (mut hconn, mut peer_conn) = connect();
+        send_request_with_trailers(&mut hconn, &mut peer_conn, TRAILER_FRAME);
+        let events = hconn.events().collect::<Vec<_>>();
+        assert!(events.iter().any(|e| matches!(e,
+            Http3ServerEvent::Data { data, .. } if data == REQUEST_BODY)));
+        assert!(events.iter().any(|e| matches!(e,
+            Http3ServerEvent::Trailers { trailers, .. } if *trailers == vec![h("age", "0")])));
+        assert_not_closed(&mut hconn);
+    }
+
+    #[test]
+    fn test_server_request_trailers_with_pseudo_header() {
+        let (mut hconn, mut peer_conn) = connect();
+        let request_stream_id =
+            send_request_with_trailers(&mut hconn, &mut peer_conn, TRAILER_FRAME_PSEUDO);
+        assert!(!hconn
+            .events()
+            .any(|e| matches!(e, Http3ServerEvent::Trailers { .. })));
+
+        server_to_peer(&mut hconn, &mut peer_conn);
+        assert_peer_reset(&mut peer_conn, request_stream_id);
+        assert_not_closed(&mut hconn);
+    }
+
+    // After GOAWAY, the request that was made is answered, a later one is
+    // rejected, and the connection closes once the response is acknowledged.
+    #[test]
+    fn test_server_close_gracefully() {
+        let (mut hconn, mut peer_conn) = connect();
+        let headers = vec![
+            h(":method", "GET"),
+            h(":scheme", "https"),
+            h(":authority", "something.com"),
+            h(":path", "/"),
+        ];
+        send_request(&mut hconn, &mut peer_conn, &headers, &[], true);
+        let mut request = hconn
+            .events()
+            .find_map(|e| match e {
+                Http3ServerEvent::Headers { request, .. } => Some(request),
+                _ => None,
+            })
+            .unwrap();
+
+        hconn.close_gracefully();
+        server_to_peer(&mut hconn, &mut peer_conn);
+        assert!(hconn.events().any(|e| matches!(
+            e,
+            Http3ServerEvent::StateChange {
+                state: Http3State::GoingAway(4),
+                ..
+            }
+        )));
+        let mut buf = [0_u8; 10];
+        let (amount, fin) = peer_conn
+            .conn
+            .stream_recv(SERVER_SIDE_CONTROL_STREAM_ID, &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..amount], &[0x7, 0x1, 0x4]);
+        assert_eq!(fin, false);
+
+        // A request that crosses the GOAWAY is rejected.
+        let rejected = send_request(&mut hconn, &mut peer_conn, &headers, &[], true);
+        let events = hconn.events().collect::<Vec<_>>();
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, Http3ServerEvent::Headers { .. })));
+        server_to_peer(&mut hconn, &mut peer_conn);
+        assert!(peer_conn.conn.events().any(|e| matches!(e,
+            ConnectionEvent::RecvStreamReset { stream_id, app_error }
+                if stream_id == rejected && app_error == Error::HttpRequestRejected.code())));
+
+        request
+            .set_response(&[h(":status", "200"), h("content-length", "0")], &[])
+            .unwrap();
+        let out = hconn.process(None, now());
+        let out = peer_conn.conn.process(out.dgram(), now());
+        assert_not_closed(&mut hconn);
+
+        let later = now() + Duration::from_millis(50);
+        let ack = out
+            .dgram()
+            .or_else(|| peer_conn.conn.process(None, later).dgram());
+        hconn.process(ack, later);
+        assert_closed(&mut hconn, &Error::HttpNoError);
+    }
+
+    // A response that has used all of the connection flow control credit that it
+    // can doesn't hold up GOAWAY: it goes out straight away, without waiting for
+    // the client to send MAX_DATA.
+    #[test]
+    fn test_server_close_gracefully_flow_control_reserve() {
+        let mut client = default_client();
+        client
+            .set_local_tparam(tparams::INITIAL_MAX_DATA, TransportParameter::Integer(4000))
+            .unwrap();
+        let (mut hconn, mut peer_conn) = connect_peer_to(default_http3_server(), client);
+        send_request(&mut hconn, &mut peer_conn, &get_request(), &[], true);
+        let mut request = hconn
+            .events()
+            .find_map(|e| match e {
+                Http3ServerEvent::Headers { request, .. } => Some(request),
+                _ => None,
+            })
+            .unwrap();
+        request
+            .set_response(&[h(":status", "200")], &[0; 10_000])
+            .unwrap();
+        while let Some(d) = hconn.process(None, now()).dgram() {
+            peer_conn.conn.process_input(d, now());
+        }
+
+        hconn.close_gracefully();
+        let dgram = hconn.process(None, now()).dgram();
+        assert!(dgram.is_some());
+        peer_conn.conn.process_input(dgram.unwrap(), now());
+        let mut buf = [0_u8; 10];
+        let (amount, _) = peer_conn
+            .conn
+            .stream_recv(SERVER_SIDE_CONTROL_STREAM_ID, &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..amount], &[0x7, 0x1, 0x4]);
+    }
+
+    // Server: the bytes that were sent on every connection are counted.
+    #[test]
+    fn test_server_efficiency_stats() {
+        const BODY_LEN: usize = 100;
+        let (mut hconn, mut peer_conn) = connect();
+        let before = hconn.efficiency_stats();
+        assert_eq!(before.app_payload, 0);
+        assert!(before.total() > 0);
+
+        send_request(&mut hconn, &mut peer_conn, &get_request(), &[], true);
+        let mut request = hconn
+            .events()
+            .find_map(|e| match e {
+                Http3ServerEvent::Headers { request, .. } => Some(request),
+                _ => None,
+            })
+            .unwrap();
+        request
+            .set_response(&[h(":status", "200")], &[0; BODY_LEN])
+            .unwrap();
+        server_to_peer(&mut hconn, &mut peer_conn);
+
+        let stats = hconn.efficiency_stats();
+        assert_eq!(stats.app_payload, BODY_LEN as u64);
+        assert!(stats.h3_framing > before.h3_framing);
+        assert!(stats.qpack > before.qpack);
+        assert!(stats.total() > before.total() + BODY_LEN as u64);
+    }
+
+    fn get_request() -> Vec<Header> {
+        vec![
+            h(":method", "GET"),
+            h(":scheme", "https"),
+            h(":authority", "something.com"),
+            h(":path", "/"),
+        ]
+    }
+
+    // Open a request, send the first byte of a HEADERS frame on it and reset it,
+    // all at `now`.  Returns the number of datagrams that the peer sent.
+    fn open_and_reset(
+        hconn: &mut Http3Server,
+        peer_conn: &mut PeerConnection,
+        now: Instant,
+    ) -> usize {
+        let stream_id = peer_conn.conn.stream_create(StreamType::BiDi).unwrap();
+        peer_conn.conn.stream_send(stream_id, &[0x1]).unwrap();
+        let mut sent = exchange(hconn, peer_conn, now);
+        peer_conn
+            .conn
+            .stream_reset_send(stream_id, Error::HttpRequestCancelled.code())
+            .unwrap();
+        sent += exchange(hconn, peer_conn, now);
+        // The peer hears that the server reset its side too.
+        while peer_conn.conn.next_event().is_some() {}
+        sent
+    }
+
+    // Deliver datagrams between the peer and the server until the peer has nothing
+    // more to send.  Returns the number of datagrams that the peer sent.
+    fn exchange(hconn: &mut Http3Server, peer_conn: &mut PeerConnection, now: Instant) -> usize {
+        let mut sent = 0;
+        let mut dgram = peer_conn.conn.process(None, now).dgram();
+        while dgram.is_some() {
+            sent += 1;
+            let out = hconn.process(dgram, now);
+            dgram = peer_conn.conn.process(out.dgram(), now).dgram();
+        }
+        sent
+    }
+
+    // Server: a client that opens and resets requests over and over costs a
+    // bounded amount of work per request and leaves nothing behind.
+    #[test]
+    fn test_server_open_reset_churn() {
+        const CYCLES: u64 = 10_000;
+        let (mut hconn, mut peer_conn) = connect();
+        let mut t = now();
+        for _ in 0..CYCLES {
+            t += Duration::from_millis(1);
+            assert!(open_and_reset(&mut hconn, &mut peer_conn, t) <= 6);
+            // Nothing about these requests reaches the application.
+            assert!(!hconn.events().any(|e| matches!(
+                e,
+                Http3ServerEvent::Headers { .. }
+                    | Http3ServerEvent::Data { .. }
+                    | Http3ServerEvent::Reset { .. }
+            )));
+        }
+        assert_not_closed(&mut hconn);
+        assert_eq!(hconn.http3_handlers.len(), 1);
+        for handler in hconn.http3_handlers.values() {
+            assert_eq!(handler.borrow().stream_count(), 0);
+        }
+        let stats = hconn.churn_stats();
+        assert_eq!(stats.churned, CYCLES);
+        // The control and QPACK streams are counted as opened as well.
+        assert_eq!(stats.opened, CYCLES + 3);
+    }
+
+    // Server: a client that opens streams faster than the rate limit is closed.
+    #[test]
+    fn test_server_stream_rate_limit() {
+        let mut hconn = default_http3_server();
+        hconn.set_stream_rate_limit(Some(10));
+        let (mut hconn, mut peer_conn) = connect_to(hconn);
+        // The control and QPACK streams use 3 of the 10.
+        for _ in 0..7 {
+            open_and_reset(&mut hconn, &mut peer_conn, now());
+        }
+        assert_not_closed(&mut hconn);
+        open_and_reset(&mut hconn, &mut peer_conn, now());
+        assert_closed(&mut hconn, &Error::HttpExcessiveLoad);
+    }
+
+    // Server: a request that is reset before the application takes its events
+    // is reported with a single `Reset`.
+    #[test]
+    fn test_server_reset_replaces_request_events() {
+        let (mut hconn, mut peer_conn) = connect();
+        let stream_id = send_request(&mut hconn, &mut peer_conn, &get_request(), &[], false);
+        peer_conn
+            .conn
+            .stream_reset_send(stream_id, Error::HttpRequestCancelled.code())
+            .unwrap();
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+
+        let events = hconn.events().collect::<Vec<_>>();
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, Http3ServerEvent::Headers { .. })));
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(e, Http3ServerEvent::Reset { error, .. }
+                    if *error == Error::HttpRequestCancelled.code()))
+                .count(),
+            1
+        );
+        assert_eq!(hconn.churn_stats().churned, 0);
+    }
+
+    // Server: a request that the application has seen is reported with `Reset`
+    // when the client resets it.
+    #[test]
+    fn test_server_reset_after_headers() {
+        let (mut hconn, mut peer_conn) = connect();
+        let stream_id = send_request(&mut hconn, &mut peer_conn, &get_request(), &[], false);
+        assert!(hconn
+            .events()
+            .any(|e| matches!(e, Http3ServerEvent::Headers { .. })));
+        peer_conn
+            .conn
+            .stream_reset_send(stream_id, Error::HttpRequestCancelled.code())
+            .unwrap();
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        assert!(hconn
+            .events()
+            .any(|e| matches!(e, Http3ServerEvent::Reset { error, .. }
+            if error == Error::HttpRequestCancelled.code())));
+    }
 }
@@ -7,8 +7,9 @@
 use crate::connection::Http3State;
 use crate::recv_message::RecvMessageEvents;
 use crate::send_message::SendMessageEvents;
-use crate::Header;
+use crate::{Header, HeaderViolations};
 use neqo_common::matches;
+use neqo_transport::{AppError, CongestionState};
 
 use std::cell::RefCell;
 use std::collections::VecDeque;
@@ -22,13 +23,26 @@ pub(crate) enum Http3ServerConnEvent {
         headers: Option<Vec<Header>>,
         fin: bool,
     },
+    /// A request breaks the rules for HTTP/3 messages.
+    HeaderViolations {
+        stream_id: u64,
+        violations: HeaderViolations,
+    },
+    /// Request trailers have been received.
+    Trailers {
+        stream_id: u64,
+        trailers: Vec<Header>,
+    },
     /// Request data is ready.
     DataReadable { stream_id: u64 },
-    //TODO: This is never used. Do we need it?
-    // Peer reset the stream.
-    //Reset { stream_id: u64, error: AppError },
+    /// Peer reset a request after its headers arrived.
+    Reset { stream_id: u64, error: AppError },
+    /// The client's SETTINGS frame has arrived.
+    SettingsReceived,
     /// Connection state change.
     StateChange(Http3State),
+    /// The congestion controller entered or left a recovery period.
+    CongestionStateChanged(CongestionState),
 }
 
 #[derive(Debug, Default, Clone)]
@@ -46,16 +60,56 @@ impl RecvMessageEvents for Http3ServerConnEvents {
         });
     }
 
+    // Requests don't have interim headers.
+    fn interim_headers(&self, _stream_id: u64, _headers: Vec<Header>) {}
+
+    /// Add a new `HeaderViolations` event.
+    fn header_violations(&self, stream_id: u64, violations: HeaderViolations) {
+        self.insert(Http3ServerConnEvent::HeaderViolations {
+            stream_id,
+            violations,
+        });
+    }
+
+    /// Add a new `Trailers` event.
+    fn trailer_ready(&self, stream_id: u64, trailers: Vec<Header>) {
+        self.insert(Http3ServerConnEvent::Trailers {
+            stream_id,
+            trailers,
+        });
+    }
+
     /// Add a new `DataReadable` event
     fn data_readable(&self, stream_id: u64) {
         self.insert(Http3ServerConnEvent::DataReadable { stream_id });
     }
+
+    fn reset(&self, stream_id: u64, _error: AppError) {
+        // A reset by the peer is reported with `peer_reset`.
+        self.remove_events_for_stream_id(stream_id);
+    }
+
+    fn local_reset(&self, stream_id: u64, error: AppError) {
+        self.reset(stream_id, error);
+    }
+
+    fn complete(&self, _stream_id: u64) {
+        // The end of a request is reported with `fin` on `Headers` or when reading data.
+    }
+
+    fn capsule(&self, _stream_id: u64, _capsule_type: u64, _payload: Vec<u8>) {
+        // Capsules are only read on the client.
+    }
 }
 
 impl SendMessageEvents for Http3ServerConnEvents {
     fn data_writable(&self, _stream_id: u64) {
         // Curently not used on the server side.
     }
+
+    fn body_exceeds_flow_control(&self, _stream_id: u64, _body_len: u64, _max_data: u64) {
+        // The server doesn't use `send_body`.
+    }
 }
 
 impl Http3ServerConnEvents {
@@ -78,14 +132,32 @@ impl Http3ServerConnEvents {
         self.events.borrow_mut().pop_front()
     }
 
+    /// The peer reset a request; this replaces any events that are waiting for it.
+    pub fn peer_reset(&self, stream_id: u64, error: AppError) {
+        self.remove_events_for_stream_id(stream_id);
+        self.insert(Http3ServerConnEvent::Reset { stream_id, error });
+    }
+
+    pub fn settings_received(&self) {
+        self.insert(Http3ServerConnEvent::SettingsReceived);
+    }
+
     pub fn connection_state_change(&self, state: Http3State) {
         self.insert(Http3ServerConnEvent::StateChange(state));
     }
 
+    pub fn congestion_state_changed(&self, state: CongestionState) {
+        self.insert(Http3ServerConnEvent::CongestionStateChanged(state));
+    }
+
     pub fn remove_events_for_stream_id(&self, stream_id: u64) {
         self.remove(|evt| {
             matches!(evt,
-                Http3ServerConnEvent::Headers { stream_id: x, .. } | Http3ServerConnEvent::DataReadable { stream_id: x, .. } if *x == stream_id)
+                Http3ServerConnEvent::Headers { stream_id: x, .. }
+                | Http3ServerConnEvent::HeaderViolations { stream_id: x, .. }
+                | Http3ServerConnEvent::Trailers { stream_id: x, .. }
+                | Http3ServerConnEvent::DataReadable { stream_id: x, .. }
+                | Http3ServerConnEvent::Reset { stream_id: x, .. } if *x == stream_id)
         });
     }
 }
@@ -8,10 +8,10 @@
 
 use crate::connection::Http3State;
 use crate::connection_server::Http3ServerHandler;
-use crate::{Header, Res};
+use crate::{Header, HeaderViolations, Res, Settings};
 use neqo_common::{qdebug, qinfo};
 use neqo_transport::server::ActiveConnectionRef;
-use neqo_transport::{AppError, Connection};
+use neqo_transport::{AppError, CongestionState, Connection};
 
 use std::cell::RefCell;
 use std::collections::VecDeque;
@@ -48,6 +48,11 @@ impl ClientRequestStream {
         }
     }
 
+    #[must_use]
+    pub fn stream_id(&self) -> u64 {
+        self.stream_id
+    }
+
     /// Supply a response to a request.
     pub fn set_response(&mut self, headers: &[Header], data: &[u8]) -> Res<()> {
         qinfo!([self], "Set new response.");
@@ -89,17 +94,49 @@ pub enum Http3ServerEvent {
         headers: Option<Vec<Header>>,
         fin: bool,
     },
+    /// A request breaks the rules for HTTP/3 messages, but is being delivered, see
+    /// `RequestValidation::Deliver`.  Problems with the headers are reported just
+    /// before the `Headers` event; a body that doesn't match `content-length` is
+    /// reported when that is found.
+    HeaderViolations {
+        request: ClientRequestStream,
+        violations: HeaderViolations,
+    },
+    /// The trailers of a request have arrived.  Nothing but the end of the request
+    /// can follow them.
+    Trailers {
+        request: ClientRequestStream,
+        trailers: Vec<Header>,
+    },
     /// Request data is ready.
     Data {
         request: ClientRequestStream,
         data: Vec<u8>,
         fin: bool,
     },
+    /// The client reset a request after its headers arrived.  Any events for the
+    /// request that had not been taken yet are dropped, so a request that is
+    /// opened and reset before the application sees it is only reported with this.
+    Reset {
+        request: ClientRequestStream,
+        error: AppError,
+    },
+    /// The SETTINGS frame of a client has arrived.
+    SettingsReceived {
+        conn: ActiveConnectionRef,
+        settings: Settings,
+    },
     /// When individual connection change state. It is only used for tests.
     StateChange {
         conn: ActiveConnectionRef,
         state: Http3State,
     },
+    /// The congestion controller of a connection entered or left a recovery period.
+    /// This is advisory, see `neqo_transport::ConnectionEvent::CongestionStateChanged`.
+    CongestionStateChanged {
+        conn: ActiveConnectionRef,
+        state: CongestionState,
+    },
 }
 
 #[derive(Debug, Default, Clone)]
@@ -141,11 +178,56 @@ impl Http3ServerEvents {
         });
     }
 
+    /// Insert a `HeaderViolations` event.
+    pub(crate) fn header_violations(
+        &self,
+        request: ClientRequestStream,
+        violations: HeaderViolations,
+    ) {
+        self.insert(Http3ServerEvent::HeaderViolations {
+            request,
+            violations,
+        });
+    }
+
+    /// Insert a `Trailers` event.
+    pub(crate) fn trailers(&self, request: ClientRequestStream, trailers: Vec<Header>) {
+        self.insert(Http3ServerEvent::Trailers { request, trailers });
+    }
+
+    /// Insert a `Reset` event in place of any that are waiting for `request`.
+    pub(crate) fn reset(&self, request: ClientRequestStream, error: AppError) {
+        self.events.borrow_mut().retain(|evt| match evt {
+            Http3ServerEvent::Headers { request: r, .. }
+            | Http3ServerEvent::HeaderViolations { request: r, .. }
+            | Http3ServerEvent::Trailers { request: r, .. }
+            | Http3ServerEvent::Data { request: r, .. } => {
+                r.stream_id != request.stream_id || r.conn != request.conn
+            }
+            _ => true,
+        });
+        self.insert(Http3ServerEvent::Reset { request, error });
+    }
+
+    /// Insert a `SettingsReceived` event.
+    pub(crate) fn settings_received(&self, conn: ActiveConnectionRef, settings: Settings) {
+        self.insert(Http3ServerEvent::SettingsReceived { conn, settings });
+    }
+
     /// Insert a `StateChange` event.
     pub(crate) fn connection_state_change(&self, conn: ActiveConnectionRef, state: Http3State) {
         self.insert(Http3ServerEvent::StateChange { conn, state });
     }
 
+    /// Insert a `CongestionStateChanged` event.
+    pub(crate) fn congestion_state_changed(
+        &self,
+        conn: ActiveConnectionRef,
+        state: CongestionState,
+    ) {
+        self.insert(Http3ServerEvent::CongestionStateChanged { conn, state });
+    }
+
     /// Insert a `Data` event.
     pub(crate) fn data(&self, request: ClientRequestStream, data: Vec<u8>, fin: bool) {
         self.insert(Http3ServerEvent::Data { request, data, fin });
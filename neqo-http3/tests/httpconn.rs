@@ -8,7 +8,13 @@
 
 use neqo_common::{matches, Datagram};
 use neqo_crypto::AuthenticationStatus;
-use neqo_http3::{Http3Client, Http3ClientEvent, Http3Server, Http3ServerEvent, Http3State};
+use neqo_http3::{
+    Http3Client, Http3ClientEvent, Http3Server, Http3ServerEvent, Http3State, Output, Recording,
+};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
 use test_fixture::*;
 
 const RESPONSE_DATA: &[u8] = &[0x61, 0x62, 0x63];
@@ -63,12 +69,9 @@ fn process_client_events(conn: &mut Http3Client) {
                 assert_eq!(fin, false);
                 response_header_found = true;
             }
-            Http3ClientEvent::DataReadable { stream_id } => {
-                let mut buf = [0u8; 100];
-                let (amount, fin) = conn.read_response_data(now(), stream_id, &mut buf).unwrap();
-                assert_eq!(fin, true);
-                assert_eq!(amount, RESPONSE_DATA.len());
-                assert_eq!(&buf[..RESPONSE_DATA.len()], RESPONSE_DATA);
+            Http3ClientEvent::DataReadable { stream_id, .. } => {
+                let body = conn.read_body_to_end(now(), stream_id, 100).unwrap();
+                assert_eq!(body, Some(RESPONSE_DATA.to_vec()));
                 response_data_found = true;
             }
             _ => {}
@@ -79,7 +82,10 @@ fn process_client_events(conn: &mut Http3Client) {
 }
 
 fn connect() -> (Http3Client, Http3Server, Option<Datagram>) {
-    let mut hconn_c = default_http3_client();
+    connect_client(default_http3_client())
+}
+
+fn connect_client(mut hconn_c: Http3Client) -> (Http3Client, Http3Server, Option<Datagram>) {
     let mut hconn_s = default_http3_server();
 
     assert_eq!(hconn_c.state(), Http3State::Initializing);
@@ -107,10 +113,7 @@ fn test_connect() {
     let (_hconn_c, _hconn_s, _d) = connect();
 }
 
-#[test]
-fn test_fetch() {
-    let (mut hconn_c, mut hconn_s, dgram) = connect();
-
+fn fetch(hconn_c: &mut Http3Client, hconn_s: &mut Http3Server, dgram: Option<Datagram>) {
     eprintln!("-----client");
     let req = hconn_c
         .fetch("GET", "https", "something.com", "/", &[])
@@ -121,12 +124,190 @@ fn test_fetch() {
     eprintln!("-----server");
     let out = hconn_s.process(out.dgram(), now());
     let _ = hconn_c.process(out.dgram(), now());
-    process_server_events(&mut hconn_s);
+    process_server_events(hconn_s);
     let out = hconn_s.process(None, now());
 
     eprintln!("-----client");
     let _ = hconn_c.process(out.dgram(), now());
     let out = hconn_s.process(None, now());
     let _ = hconn_c.process(out.dgram(), now());
-    process_client_events(&mut hconn_c);
+    process_client_events(hconn_c);
+}
+
+#[test]
+fn test_fetch() {
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+    fetch(&mut hconn_c, &mut hconn_s, dgram);
+}
+
+/// Download a large response, reading after each `DataReadable` until nothing is
+/// left, as neqo-client does.  This only finishes if there is a new event every
+/// time more of the response arrives.  neqo-client's own tests do the same with
+/// 50 MB, through the binary.
+#[test]
+fn fetch_large_drained() {
+    const RESPONSE_SIZE: usize = 2 << 20;
+
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+    let req = hconn_c
+        .fetch("GET", "https", "something.com", "/", &[])
+        .unwrap();
+    hconn_c.stream_close_send(req).unwrap();
+
+    let mut now = now();
+    let mut dgram = hconn_c.process(dgram, now).dgram();
+    let mut buf = vec![0; 4000];
+    let mut received = 0;
+    let mut fin = false;
+    while !fin {
+        let out = hconn_s.process(dgram.take(), now);
+        while let Some(event) = hconn_s.next_event() {
+            if let Http3ServerEvent::Headers { mut request, .. } = event {
+                request
+                    .set_response(
+                        &[(String::from(":status"), String::from("200"))],
+                        &vec![0x58; RESPONSE_SIZE],
+                    )
+                    .unwrap();
+            }
+        }
+        let (server_sent, server_wait) = match &out {
+            Output::Datagram(_) => (true, None),
+            Output::Callback(t) => (false, Some(*t)),
+            Output::None => (false, None),
+        };
+        let client_wait = match hconn_c.process(out.dgram(), now) {
+            Output::Datagram(d) => {
+                dgram = Some(d);
+                None
+            }
+            Output::Callback(t) => Some(t),
+            Output::None => None,
+        };
+
+        while let Some(event) = hconn_c.next_event() {
+            if let Http3ClientEvent::DataReadable { stream_id, .. } = event {
+                loop {
+                    let (amount, f) = hconn_c
+                        .read_response_data(now, stream_id, &mut buf)
+                        .unwrap();
+                    received += amount;
+                    fin = f;
+                    if fin || amount == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        assert_eq!(hconn_c.state(), Http3State::Connected);
+
+        // Only move time on when neither side has anything to send.
+        if !server_sent && dgram.is_none() {
+            let wait = match (server_wait, client_wait) {
+                (Some(s), Some(c)) => s.min(c),
+                (s, c) => s.or(c).expect("the transfer stalled"),
+            };
+            now += wait.max(Duration::from_millis(1));
+        }
+    }
+    assert_eq!(received, RESPONSE_SIZE);
+}
+
+/// Replay a recorded GET.  This reads in small pieces, so that the reads don't match
+/// the recorded ones.
+fn check_replay(recording: &Recording) {
+    let mut mock = MockHttp3::new(recording);
+    let mut headers = None;
+    let mut data = Vec::new();
+    let mut fin = false;
+    while let Some(event) = mock.next_event() {
+        match event {
+            Http3ClientEvent::HeaderReady {
+                stream_id,
+                headers: h,
+                ..
+            } => {
+                assert_eq!(stream_id, 0);
+                headers = h;
+            }
+            Http3ClientEvent::DataReadable { stream_id, .. } => {
+                while !fin {
+                    let mut buf = [0; 2];
+                    let (amount, f) = mock.read_response_data(now(), stream_id, &mut buf).unwrap();
+                    data.extend_from_slice(&buf[..amount]);
+                    fin = f;
+                }
+                assert!(mock
+                    .read_response_data(now(), stream_id, &mut [0; 2])
+                    .is_err());
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(
+        headers,
+        Some(vec![
+            (String::from(":status"), String::from("200")),
+            (String::from("content-length"), String::from("3")),
+        ])
+    );
+    assert!(fin);
+    assert_eq!(&data[..], RESPONSE_DATA);
+}
+
+/// Check a recording against a file in `tests/recordings`, which is rewritten from
+/// the recording instead if `NEQO_UPDATE_RECORDINGS` is set.  Comments at the top
+/// of the file are kept.
+fn check_golden(recording: &Recording, name: &str) {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "recordings", name]
+        .iter()
+        .collect();
+    let golden = fs::read_to_string(&path).unwrap_or_default();
+    if env::var_os("NEQO_UPDATE_RECORDINGS").is_some() {
+        let comments = golden
+            .lines()
+            .take_while(|l| l.starts_with('#'))
+            .map(|l| format!("{}\n", l))
+            .collect::<String>();
+        fs::write(&path, comments + &recording.to_string()).unwrap();
+    } else {
+        assert_eq!(
+            golden.parse::<Recording>().as_ref(),
+            Ok(recording),
+            "{} doesn't match this run; set NEQO_UPDATE_RECORDINGS to rewrite it",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_fetch_recording() {
+    let mut hconn_c = default_http3_client();
+    hconn_c.start_recording();
+    let (mut hconn_c, mut hconn_s, dgram) = connect_client(hconn_c);
+    hconn_s.start_recording();
+    fetch(&mut hconn_c, &mut hconn_s, dgram);
+
+    let recording = hconn_c.take_recording().unwrap();
+    assert_eq!(
+        recording.to_string().parse::<Recording>(),
+        Ok(recording.clone())
+    );
+    check_replay(&recording);
+    check_golden(&recording, "get.txt");
+
+    let recording = hconn_s.take_recording().unwrap();
+    assert_eq!(
+        recording.to_string().parse::<Recording>(),
+        Ok(recording.clone())
+    );
+    check_golden(&recording, "get-server.txt");
+}
+
+#[test]
+fn test_replay_golden() {
+    let recording = include_str!("recordings/get.txt")
+        .parse::<Recording>()
+        .unwrap();
+    check_replay(&recording);
 }
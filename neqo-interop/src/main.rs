@@ -9,7 +9,7 @@
 
 use neqo_common::{hex, matches, Datagram};
 use neqo_crypto::{init, AuthenticationStatus};
-use neqo_http3::{Header, Http3Client, Http3ClientEvent};
+use neqo_http3::{Http3Client, Http3ClientEvent};
 use neqo_qpack::QpackSettings;
 use neqo_transport::{
     Connection, ConnectionError, ConnectionEvent, Error, FixedConnectionIdManager, Output, State,
@@ -22,8 +22,6 @@ use std::collections::HashSet;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
 use std::rc::Rc;
 // use std::path::PathBuf;
-use std::str::FromStr;
-use std::string::ParseError;
 use std::sync::Mutex;
 use std::thread;
 use std::time::{Duration, Instant};
@@ -215,41 +213,6 @@ impl Handler for H9Handler {
 }
 
 // HTTP/3 IMPLEMENTATION
-#[derive(Debug)]
-struct Headers {
-    pub h: Vec<Header>,
-}
-
-// dragana: this is a very stupid parser.
-// headers should be in form "[(something1, something2), (something3, something4)]"
-impl FromStr for Headers {
-    type Err = ParseError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut res = Self { h: Vec::new() };
-        let h1: Vec<&str> = s
-            .trim_matches(|p| p == '[' || p == ']')
-            .split(')')
-            .collect();
-
-        for h in h1 {
-            let h2: Vec<&str> = h
-                .trim_matches(|p| p == ',')
-                .trim()
-                .trim_matches(|p| p == '(' || p == ')')
-                .split(',')
-                .collect();
-
-            if h2.len() == 2 {
-                res.h
-                    .push((h2[0].trim().to_string(), h2[1].trim().to_string()));
-            }
-        }
-
-        Ok(res)
-    }
-}
-
 struct H3Handler {
     streams: HashSet<u64>,
     h3: Http3Client,
@@ -316,6 +279,7 @@ impl H3Handler {
                     stream_id,
                     headers,
                     fin,
+                    ..
                 } => {
                     if !self.streams.contains(&stream_id) {
                         eprintln!("Data on unexpected stream: {}", stream_id);
@@ -324,7 +288,7 @@ impl H3Handler {
 
                     eprintln!("READ HEADERS[{}]: fin={} {:?}", stream_id, fin, headers);
                 }
-                Http3ClientEvent::DataReadable { stream_id } => {
+                Http3ClientEvent::DataReadable { stream_id, .. } => {
                     if !self.streams.contains(&stream_id) {
                         eprintln!("Data on unexpected stream: {}", stream_id);
                         return false;
@@ -445,14 +409,22 @@ fn test_connect(nctx: &NetworkCtx, test: &Test, peer: &Peer) -> Result<Connectio
     let st = match res {
         Ok(st) => st,
         Err(e) => {
-            return Err(format!("ERROR: {}", e));
+            return Err(format!(
+                "ERROR: {} [{}]",
+                e,
+                client.handshake_state_summary(Instant::now())
+            ));
         }
     };
 
     if st.connected() {
         Ok(client)
     } else {
-        Err(format!("{:?}", st))
+        Err(format!(
+            "{:?} [{}]",
+            st,
+            client.handshake_state_summary(Instant::now())
+        ))
     }
 }
 
@@ -697,6 +669,7 @@ fn main() {
 
     let args = Args::from_args();
     init();
+    eprint!("{}", neqo_http3::build_info());
     Timer::set_timeout(Duration::from_secs(args.timeout));
 
     let mut children = Vec::new();
@@ -0,0 +1,440 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// An offline QPACK tool for the qpack-interop corpus.  It encodes .qif files
+// into the interop format and decodes files in the interop format back to .qif.
+//
+// A .qif file has one header per line, as the name and value separated by a
+// tab.  Header lists are separated by empty lines and lines starting with '#'
+// are comments.  An encoded file is a sequence of blocks, each with a 64-bit
+// stream ID and a 32-bit length, both in network byte order, followed by the
+// data.  Stream 0 is the encoder stream and the header list at position N in
+// the .qif file (counting from 1) is the header block on stream N.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![warn(clippy::use_self)]
+
+use neqo_common::{Decoder, Encoder};
+use neqo_qpack::decoder::QPackDecoder;
+use neqo_qpack::encoder::QPackEncoder;
+use neqo_qpack::{Header, QpackSettings};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+const ENCODER_STREAM_ID: u64 = 0;
+
+#[derive(Debug)]
+pub enum InteropError {
+    QpackError(neqo_qpack::Error),
+    IoError(io::Error),
+    /// The input file is malformed.
+    FormatError(&'static str),
+}
+
+impl From<io::Error> for InteropError {
+    fn from(err: io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+impl From<neqo_qpack::Error> for InteropError {
+    fn from(err: neqo_qpack::Error) -> Self {
+        Self::QpackError(err)
+    }
+}
+
+type Res<T> = Result<T, InteropError>;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "neqo-qpack-interop",
+    about = "Converts between .qif files and the QPACK offline interop format."
+)]
+enum Args {
+    /// Encode a .qif file.
+    Encode {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+        #[structopt(short = "t", long, default_value = "4096")]
+        /// The size of the dynamic table.
+        table_size: u64,
+        #[structopt(short = "b", long, default_value = "16")]
+        /// The number of streams that can be blocked.
+        blocked_streams: u16,
+        #[structopt(short = "a", long)]
+        /// Treat every header block as acknowledged as soon as it is encoded.
+        ack: bool,
+    },
+    /// Decode an encoded file to .qif.
+    Decode {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+        #[structopt(short = "t", long, default_value = "4096")]
+        /// The size of the dynamic table.
+        table_size: u64,
+        #[structopt(short = "b", long, default_value = "16")]
+        /// The number of streams that can be blocked.
+        blocked_streams: u16,
+    },
+}
+
+fn parse_qif(text: &str) -> Vec<Vec<Header>> {
+    let mut lists = Vec::new();
+    let mut headers = Vec::new();
+    for line in text.lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        if line.is_empty() {
+            if !headers.is_empty() {
+                lists.push(headers);
+                headers = Vec::new();
+            }
+            continue;
+        }
+        let mut parts = line.splitn(2, '\t');
+        let name = parts.next().unwrap_or_default();
+        let value = parts.next().unwrap_or_default();
+        headers.push((String::from(name), String::from(value)));
+    }
+    if !headers.is_empty() {
+        lists.push(headers);
+    }
+    lists
+}
+
+fn write_qif(lists: &[Vec<Header>]) -> String {
+    let mut text = String::new();
+    for headers in lists {
+        for (name, value) in headers {
+            text.push_str(name);
+            text.push('\t');
+            text.push_str(value);
+            text.push('\n');
+        }
+        text.push('\n');
+    }
+    text
+}
+
+fn settings(table_size: u64, blocked_streams: u16) -> QpackSettings {
+    QpackSettings {
+        max_table_size_encoder: table_size,
+        max_table_size_decoder: table_size,
+        max_blocked_streams: blocked_streams,
+    }
+}
+
+fn encode(lists: &[Vec<Header>], table_size: u64, blocked_streams: u16, ack: bool) -> Res<Vec<u8>> {
+    let mut encoder = QPackEncoder::new(settings(table_size, blocked_streams), true);
+    encoder.add_send_stream_without_type(ENCODER_STREAM_ID);
+    encoder.set_max_blocked_streams(u64::from(blocked_streams))?;
+    encoder.set_max_capacity(table_size)?;
+
+    // Acknowledgments come from a decoder that reads everything as soon as it
+    // is written.
+    let mut decoder = if ack {
+        let mut decoder = QPackDecoder::new(settings(table_size, blocked_streams));
+        decoder.add_send_stream_without_type(ENCODER_STREAM_ID);
+        Some(decoder)
+    } else {
+        None
+    };
+
+    let mut out = Encoder::new();
+    for (stream_id, headers) in (1..).zip(lists) {
+        let mut instructions = Vec::new();
+        encoder.send(&mut instructions)?;
+        let block = encoder.encode_header_block(&mut instructions, headers, stream_id)?;
+        if !instructions.is_empty() {
+            out.encode_uint(8, ENCODER_STREAM_ID)
+                .encode_vec(4, &instructions);
+        }
+        out.encode_uint(8, stream_id).encode_vec(4, &block);
+
+        if let Some(decoder) = &mut decoder {
+            decoder.consume_encoder_stream(&instructions)?;
+            if decoder.decode_header_block(&block, stream_id)?.is_none() {
+                return Err(InteropError::FormatError("header block was blocked"));
+            }
+            let mut acks = Vec::new();
+            decoder.send(&mut acks)?;
+            encoder.consume_decoder_stream(&acks)?;
+        }
+    }
+    Ok(out.into())
+}
+
+fn decode(data: &[u8], table_size: u64, blocked_streams: u16) -> Res<Vec<Vec<Header>>> {
+    let mut decoder = QPackDecoder::new(settings(table_size, blocked_streams));
+    let mut blocked = HashMap::new();
+    let mut lists = BTreeMap::new();
+
+    let mut dec = Decoder::from(data);
+    while dec.remaining() > 0 {
+        let stream_id = dec
+            .decode_uint(8)
+            .ok_or(InteropError::FormatError("truncated stream ID"))?;
+        let buf = dec
+            .decode_vec(4)
+            .ok_or(InteropError::FormatError("truncated block"))?;
+        if stream_id == ENCODER_STREAM_ID {
+            for id in decoder.consume_encoder_stream(buf)? {
+                let block: Vec<u8> = blocked
+                    .remove(&id)
+                    .ok_or(InteropError::FormatError("unknown stream unblocked"))?;
+                let headers = decoder
+                    .decode_header_block(&block, id)?
+                    .ok_or(InteropError::FormatError("header block still blocked"))?;
+                lists.insert(id, headers);
+            }
+        } else if let Some(headers) = decoder.decode_header_block(buf, stream_id)? {
+            lists.insert(stream_id, headers);
+        } else {
+            blocked.insert(stream_id, buf.to_vec());
+        }
+    }
+
+    if !blocked.is_empty() {
+        return Err(InteropError::FormatError("header blocks remain blocked"));
+    }
+    Ok(lists.values().cloned().collect())
+}
+
+fn main() -> Res<()> {
+    match Args::from_args() {
+        Args::Encode {
+            input,
+            output,
+            table_size,
+            blocked_streams,
+            ack,
+        } => {
+            let lists = parse_qif(&fs::read_to_string(input)?);
+            fs::write(output, encode(&lists, table_size, blocked_streams, ack)?)?;
+        }
+        Args::Decode {
+            input,
+            output,
+            table_size,
+            blocked_streams,
+        } => {
+            let lists = decode(&fs::read(input)?, table_size, blocked_streams)?;
+            fs::write(output, write_qif(&lists))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, parse_qif, write_qif, InteropError, ENCODER_STREAM_ID};
+    use neqo_common::{Decoder, Encoder};
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    const FIXTURES: &[&str] = &["requests.qif", "responses.qif", "repeated.qif"];
+
+    fn fixture(name: &str) -> PathBuf {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("tests");
+        path.push("fixtures");
+        path.push(name);
+        path
+    }
+
+    fn read_qif(name: &str) -> String {
+        fs::read_to_string(fixture(name)).unwrap()
+    }
+
+    fn same_headers(name: &str, table_size: u64, blocked_streams: u16, ack: bool) {
+        let lists = parse_qif(&read_qif(name));
+        assert!(!lists.is_empty());
+        let encoded = encode(&lists, table_size, blocked_streams, ack).unwrap();
+        assert_eq!(
+            decode(&encoded, table_size, blocked_streams).unwrap(),
+            lists
+        );
+    }
+
+    #[test]
+    fn qif() {
+        let text = "# a comment\n:method\tGET\n:path\t/\n\n\n\
+                    :status\t200\nempty\t\n";
+        let lists = parse_qif(text);
+        assert_eq!(
+            lists,
+            vec![
+                vec![
+                    (String::from(":method"), String::from("GET")),
+                    (String::from(":path"), String::from("/")),
+                ],
+                vec![
+                    (String::from(":status"), String::from("200")),
+                    (String::from("empty"), String::new()),
+                ],
+            ]
+        );
+        assert_eq!(parse_qif(&write_qif(&lists)), lists);
+    }
+
+    #[test]
+    fn static_only() {
+        for name in FIXTURES {
+            same_headers(name, 0, 0, false);
+        }
+    }
+
+    #[test]
+    fn no_ack() {
+        for name in FIXTURES {
+            same_headers(name, 256, 0, false);
+            same_headers(name, 4096, 100, false);
+        }
+    }
+
+    #[test]
+    fn immediate_ack() {
+        for name in FIXTURES {
+            same_headers(name, 256, 1, true);
+            same_headers(name, 4096, 100, true);
+        }
+    }
+
+    fn from_hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// The examples in Appendix B.1 and B.2 of RFC 9204, which weren't made by this
+    /// encoder.  The request stream of B.1 is stream 1 here, because stream 0 is the
+    /// encoder stream in this format.
+    #[test]
+    fn rfc_examples() {
+        let mut enc = Encoder::default();
+        let literal = from_hex("0000 510b 2f69 6e64 6578 2e68 746d 6c");
+        enc.encode_uint(8, 1_u64).encode_vec(4, &literal);
+        let inserts = from_hex(
+            "3fbd01 c00f 7777 772e 6578 616d 706c 652e 636f 6d \
+             c10c 2f73 616d 706c 652f 7061 7468",
+        );
+        enc.encode_uint(8, ENCODER_STREAM_ID)
+            .encode_vec(4, &inserts);
+        let indexed = from_hex("0381 10 11");
+        enc.encode_uint(8, 4_u64).encode_vec(4, &indexed);
+
+        let header = |n: &str, v: &str| (String::from(n), String::from(v));
+        assert_eq!(
+            decode(&enc, 220, 0).unwrap(),
+            vec![
+                vec![header(":path", "/index.html")],
+                vec![
+                    header(":authority", "www.example.com"),
+                    header(":path", "/sample/path"),
+                ],
+            ]
+        );
+    }
+
+    /// Each encoded file in a checkout of the QPACK interop corpus
+    /// (https://github.com/qpackers/qifs), which other implementations produced,
+    /// decodes to the header lists in the .qif file that it was made from.  Set
+    /// `QIFS_DIR` to the checkout to run this; otherwise it does nothing, except
+    /// in CI (where `CI` is set), where it fails so that the corpus isn't skipped
+    /// without anyone noticing.
+    #[test]
+    fn qifs_corpus() {
+        let root = if let Some(root) = env::var_os("QIFS_DIR") {
+            PathBuf::from(root)
+        } else {
+            assert!(
+                env::var_os("CI").is_none(),
+                "QIFS_DIR must name a checkout of the QPACK interop corpus in CI"
+            );
+            return;
+        };
+        let mut checked = 0;
+        for draft in fs::read_dir(root.join("encoded")).unwrap() {
+            for implementation in fs::read_dir(draft.unwrap().path()).unwrap() {
+                for file in fs::read_dir(implementation.unwrap().path()).unwrap() {
+                    let path = file.unwrap().path();
+                    if check_corpus_file(&root, &path) {
+                        checked += 1;
+                    }
+                }
+            }
+        }
+        assert!(checked > 0, "no encoded files in {}", root.display());
+    }
+
+    /// Decode a file named `<name>.out.<table size>.<blocked streams>.<ack>` and
+    /// compare it with `qifs/<name>.qif`.  Other files are skipped.
+    fn check_corpus_file(root: &Path, path: &Path) -> bool {
+        let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let parts = file_name.rsplitn(5, '.').collect::<Vec<_>>();
+        if parts.len() != 5 || parts[3] != "out" {
+            return false;
+        }
+        let table_size = parts[2].parse().unwrap();
+        let blocked_streams = parts[1].parse().unwrap();
+        let qif = root.join("qifs").join(format!("{}.qif", parts[4]));
+        let expected = parse_qif(&fs::read_to_string(qif).unwrap());
+        let encoded = fs::read(path).unwrap();
+        match decode(&encoded, table_size, blocked_streams) {
+            Ok(lists) => assert!(lists == expected, "{} decoded wrongly", path.display()),
+            Err(e) => panic!("{} doesn't decode: {:?}", path.display(), e),
+        }
+        true
+    }
+
+    #[test]
+    fn truncated() {
+        let lists = parse_qif(&read_qif("responses.qif"));
+        let encoded = encode(&lists, 4096, 100, false).unwrap();
+        for len in &[3, encoded.len() - 1] {
+            match decode(&encoded[..*len], 4096, 100) {
+                Err(InteropError::FormatError(_)) => {}
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+    }
+
+    /// Header blocks that refer to entries that are never inserted.
+    #[test]
+    fn never_unblocked() {
+        let lists = parse_qif(&read_qif("requests.qif"));
+        let encoded = encode(&lists, 4096, 100, false).unwrap();
+
+        // Drop the encoder stream.
+        let mut dec = Decoder::from(&encoded[..]);
+        let mut header_blocks = Encoder::new();
+        while dec.remaining() > 0 {
+            let stream_id = dec.decode_uint(8).unwrap();
+            let buf = dec.decode_vec(4).unwrap();
+            if stream_id != ENCODER_STREAM_ID {
+                header_blocks.encode_uint(8, stream_id).encode_vec(4, buf);
+            }
+        }
+        assert!(header_blocks.len() < encoded.len());
+
+        match decode(&header_blocks, 4096, 100) {
+            Err(InteropError::FormatError(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
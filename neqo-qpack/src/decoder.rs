@@ -7,13 +7,16 @@
 use crate::decoder_instructions::DecoderInstruction;
 use crate::encoder_instructions::{DecodedEncoderInstruction, EncoderInstructionReader};
 use crate::header_block::{HeaderDecoder, HeaderDecoderResult};
+use crate::qlog;
 use crate::qpack_send_buf::QPData;
-use crate::reader::ReceiverConnWrapper;
+use crate::reader::{ReadByte, Reader, ReceiverConnWrapper, ReceiverSliceWrapper};
 use crate::table::HeaderTable;
+use crate::writer::StreamWriter;
 use crate::{Error, Header, QpackSettings, Res};
-use neqo_common::qdebug;
+use neqo_common::{qdebug, qlog::NeqoQlog, qtrace};
 use neqo_transport::Connection;
 use std::convert::TryInto;
+use std::mem;
 
 pub const QPACK_UNI_STREAM_TYPE_DECODER: u64 = 0x3;
 
@@ -29,6 +32,8 @@ pub struct QPackDecoder {
     max_table_size: u64,
     max_blocked_streams: usize,
     blocked_streams: Vec<(u64, u64)>, //stream_id and requested inserts count.
+    /// The instructions in `send_buf`, which are recorded in the qlog once they are written.
+    qlog_instructions: Vec<DecoderInstruction>,
 }
 
 impl QPackDecoder {
@@ -46,6 +51,7 @@ impl QPackDecoder {
             max_table_size: qpack_settings.max_table_size_decoder,
             max_blocked_streams: qpack_settings.max_blocked_streams.try_into().unwrap(),
             blocked_streams: Vec::new(),
+            qlog_instructions: Vec::new(),
         }
     }
 
@@ -72,9 +78,25 @@ impl QPackDecoder {
         let base_old = self.table.base();
         self.read_instructions(conn, stream_id)
             .map_err(|e| map_error(&e))?;
+        Ok(self.unblocked_streams(base_old))
+    }
+
+    /// Reads encoder instructions from a buffer, for using the decoder without a transport.
+    /// The buffer holds the next part of the encoder stream; an instruction may be split between
+    /// calls. Returns a list of unblocked streams.
+    /// # Errors
+    /// May return `EncoderStream` if the instructions are malformed or cannot be applied.
+    pub fn consume_encoder_stream(&mut self, buf: &[u8]) -> Res<Vec<u64>> {
+        let base_old = self.table.base();
+        self.read_instructions_from(&mut ReceiverSliceWrapper::new(buf))
+            .map_err(|e| map_error(&e))?;
+        Ok(self.unblocked_streams(base_old))
+    }
+
+    fn unblocked_streams(&mut self, base_old: u64) -> Vec<u64> {
         let base_new = self.table.base();
         if base_old == base_new {
-            return Ok(Vec::new());
+            return Vec::new();
         }
 
         let r = self
@@ -83,21 +105,39 @@ impl QPackDecoder {
             .filter_map(|(id, req)| if *req <= base_new { Some(*id) } else { None })
             .collect::<Vec<_>>();
         self.blocked_streams.retain(|(_, req)| *req > base_new);
-        Ok(r)
+        r
     }
 
     fn read_instructions(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
-        let mut recv = ReceiverConnWrapper::new(conn, stream_id);
         loop {
+            let mut recv = ReceiverConnWrapper::new(conn, stream_id);
             match self.instruction_reader.read_instructions(&mut recv) {
-                Ok(instruction) => self.execute_instruction(instruction)?,
+                Ok(instruction) => {
+                    self.execute_instruction(instruction, conn.qlog_mut().as_mut())?
+                }
                 Err(Error::NeedMoreData) => break Ok(()),
                 Err(e) => break Err(e),
             }
         }
     }
 
-    fn execute_instruction(&mut self, instruction: DecodedEncoderInstruction) -> Res<()> {
+    fn read_instructions_from<R: ReadByte + Reader>(&mut self, recv: &mut R) -> Res<()> {
+        loop {
+            match self.instruction_reader.read_instructions(recv) {
+                Ok(instruction) => self.execute_instruction(instruction, None)?,
+                Err(Error::NeedMoreData) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        }
+    }
+
+    fn execute_instruction(
+        &mut self,
+        instruction: DecodedEncoderInstruction,
+        qlog: Option<&mut NeqoQlog>,
+    ) -> Res<()> {
+        qtrace!([self], "execute instruction {:?}", instruction);
+        qlog::qpack_encoder_instruction_received(qlog, &instruction)?;
         match instruction {
             DecodedEncoderInstruction::Capacity { value } => self.set_capacity(value)?,
             DecodedEncoderInstruction::InsertWithNameRefStatic { index, value } => {
@@ -137,31 +177,46 @@ impl QPackDecoder {
     }
 
     fn header_ack(&mut self, stream_id: u64, required_inserts: u64) {
-        DecoderInstruction::HeaderAck { stream_id }.marshal(&mut self.send_buf);
+        self.queue_instruction(DecoderInstruction::HeaderAck { stream_id });
         if required_inserts > self.acked_inserts {
             self.acked_inserts = required_inserts;
         }
     }
 
+    /// Abandon the header block of `stream_id`, which has to be blocked.  The stream no
+    /// longer counts against the blocked stream limit and the encoder is told, so
+    /// that it can release the entries that the header block referenced.
     pub fn cancel_stream(&mut self, stream_id: u64) {
-        DecoderInstruction::StreamCancellation { stream_id }.marshal(&mut self.send_buf);
+        self.blocked_streams.retain(|(id, _)| *id != stream_id);
+        self.queue_instruction(DecoderInstruction::StreamCancellation { stream_id });
+    }
+
+    fn queue_instruction(&mut self, instruction: DecoderInstruction) {
+        instruction.marshal(&mut self.send_buf);
+        self.qlog_instructions.push(instruction);
     }
 
     /// # Errors
     ///     May return an error in case of any transport error. TODO: define transport errors.
-    pub fn send(&mut self, conn: &mut Connection) -> Res<()> {
+    pub fn send<W: StreamWriter>(&mut self, writer: &mut W) -> Res<()> {
         // Encode increment instruction if needed.
         let increment = self.table.base() - self.acked_inserts;
         if increment > 0 {
-            DecoderInstruction::InsertCountIncrement { increment }.marshal(&mut self.send_buf);
+            self.queue_instruction(DecoderInstruction::InsertCountIncrement { increment });
             self.acked_inserts = self.table.base();
         }
         if self.send_buf.len() != 0 && self.local_stream_id.is_some() {
-            let r = conn
-                .stream_send(self.local_stream_id.unwrap(), &self.send_buf[..])
+            let r = writer
+                .send(self.local_stream_id.unwrap(), &self.send_buf[..])
                 .map_err(|_| Error::DecoderStream)?;
             qdebug!([self], "{} bytes sent.", r);
             self.send_buf.read(r as usize);
+            if self.send_buf.len() == 0 {
+                let sent = mem::replace(&mut self.qlog_instructions, Vec::new());
+                for instruction in &sent {
+                    qlog::qpack_decoder_instruction_sent(writer.qlog(), instruction)?;
+                }
+            }
         }
         Ok(())
     }
@@ -202,11 +257,17 @@ impl QPackDecoder {
     }
 
     pub fn add_send_stream(&mut self, stream_id: u64) {
+        self.add_send_stream_without_type(stream_id);
+        self.send_buf.encode_varint(QPACK_UNI_STREAM_TYPE_DECODER);
+    }
+
+    /// Add the decoder stream, but do not write the stream type. This is used when the decoder
+    /// runs without a transport and instructions are written to a buffer.
+    pub fn add_send_stream_without_type(&mut self, stream_id: u64) {
         if self.local_stream_id.is_some() {
             panic!("Adding multiple local streams");
         }
         self.local_stream_id = Some(stream_id);
-        self.send_buf.encode_varint(QPACK_UNI_STREAM_TYPE_DECODER);
     }
 
     /// # Errors
@@ -773,4 +834,47 @@ mod tests {
 
         decode_headers(&mut decoder, HEADER_BLOCK, &headers, 0);
     }
+
+    #[test]
+    fn standalone() {
+        let headers = vec![
+            (String::from("my-headera"), String::from("my-valuea")),
+            (String::from("my-headerb"), String::from("my-valueb")),
+        ];
+        let header_block = &[0x03, 0x81, 0x10, 0x11];
+        let encoder_inst = &[
+            0x4a, 0x6d, 0x79, 0x2d, 0x68, 0x65, 0x61, 0x64, 0x65, 0x72, 0x61, 0x09, 0x6d, 0x79,
+            0x2d, 0x76, 0x61, 0x6c, 0x75, 0x65, 0x61, 0x4a, 0x6d, 0x79, 0x2d, 0x68, 0x65, 0x61,
+            0x64, 0x65, 0x72, 0x62, 0x09, 0x6d, 0x79, 0x2d, 0x76, 0x61, 0x6c, 0x75, 0x65, 0x62,
+        ];
+
+        let mut decoder = QPackDecoder::new(QpackSettings {
+            max_table_size_encoder: 0,
+            max_table_size_decoder: 300,
+            max_blocked_streams: 100,
+        });
+        decoder.add_send_stream_without_type(0);
+        assert!(decoder.set_capacity(200).is_ok());
+
+        // The header block is blocked until both entries are inserted.
+        assert_eq!(decoder.decode_header_block(header_block, 4), Ok(None));
+
+        // The second instruction is split between two buffers.
+        assert_eq!(
+            decoder.consume_encoder_stream(&encoder_inst[..30]),
+            Ok(Vec::new())
+        );
+        assert_eq!(
+            decoder.consume_encoder_stream(&encoder_inst[30..]),
+            Ok(vec![4])
+        );
+
+        let decoded_headers = decoder.decode_header_block(header_block, 4).unwrap();
+        assert_eq!(decoded_headers.unwrap(), headers);
+
+        // Only a header ack is written, without a stream type.
+        let mut buf = Vec::new();
+        decoder.send(&mut buf).unwrap();
+        assert_eq!(buf, vec![0x84]);
+    }
 }
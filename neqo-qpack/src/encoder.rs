@@ -5,18 +5,20 @@
 // except according to those terms.
 
 use crate::decoder_instructions::{DecoderInstruction, DecoderInstructionReader};
-use crate::encoder_instructions::EncoderInstruction;
+use crate::encoder_instructions::{DecodedEncoderInstruction, EncoderInstruction};
 use crate::header_block::HeaderEncoder;
 use crate::qlog;
 use crate::qpack_send_buf::QPData;
-use crate::reader::ReceiverConnWrapper;
+use crate::reader::{ReceiverConnWrapper, ReceiverSliceWrapper};
 use crate::table::{HeaderTable, LookupResult, ADDITIONAL_TABLE_ENTRY_SIZE};
+use crate::writer::StreamWriter;
 use crate::Header;
 use crate::{Error, QpackSettings, Res};
 use neqo_common::{qdebug, qlog::NeqoQlog, qtrace};
 use neqo_transport::Connection;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
+use std::mem;
 
 pub const QPACK_UNI_STREAM_TYPE_ENCODER: u64 = 0x2;
 
@@ -37,6 +39,8 @@ pub struct QPackEncoder {
     unacked_header_blocks: HashMap<u64, VecDeque<HashSet<u64>>>,
     blocked_stream_cnt: u16,
     use_huffman: bool,
+    /// The instructions in `send_buf`, which are recorded in the qlog once they are written.
+    qlog_instructions: Vec<DecodedEncoderInstruction>,
 }
 
 impl QPackEncoder {
@@ -54,6 +58,7 @@ impl QPackEncoder {
             unacked_header_blocks: HashMap::new(),
             blocked_stream_cnt: 0,
             use_huffman,
+            qlog_instructions: Vec::new(),
         }
     }
 
@@ -117,13 +122,31 @@ impl QPackEncoder {
         loop {
             let mut recv = ReceiverConnWrapper::new(conn, stream_id);
             match self.instruction_reader.read_instructions(&mut recv) {
-                Ok(instruction) => self.call_instruction(instruction, conn.qlog_mut())?,
+                Ok(instruction) => self.call_instruction(instruction, conn.qlog_mut().as_mut())?,
                 Err(Error::NeedMoreData) => break Ok(()),
                 Err(e) => break Err(e),
             }
         }
     }
 
+    /// Reads decoder instructions from a buffer, for using the encoder without a transport.
+    /// The buffer holds the next part of the decoder stream; an instruction may be split between
+    /// calls.
+    /// # Errors
+    /// May return `DecoderStream` if the instructions are malformed.
+    pub fn consume_decoder_stream(&mut self, buf: &[u8]) -> Res<()> {
+        let mut recv = ReceiverSliceWrapper::new(buf);
+        loop {
+            match self.instruction_reader.read_instructions(&mut recv) {
+                Ok(instruction) => self
+                    .call_instruction(instruction, None)
+                    .map_err(|e| map_error(&e))?,
+                Err(Error::NeedMoreData) => break Ok(()),
+                Err(e) => break Err(map_error(&e)),
+            }
+        }
+    }
+
     fn recalculate_blocked_streams(&mut self) {
         let acked_inserts_cnt = self.table.get_acked_inserts_cnt();
         self.blocked_stream_cnt = 0;
@@ -188,17 +211,12 @@ impl QPackEncoder {
     fn call_instruction(
         &mut self,
         instruction: DecoderInstruction,
-        qlog: &mut Option<NeqoQlog>,
+        qlog: Option<&mut NeqoQlog>,
     ) -> Res<()> {
         qdebug!([self], "call intruction {:?}", instruction);
+        qlog::qpack_decoder_instruction_received(qlog, &instruction)?;
         match instruction {
             DecoderInstruction::InsertCountIncrement { increment } => {
-                qlog::qpack_read_insert_count_increment_instruction(
-                    qlog,
-                    increment,
-                    &increment.to_be_bytes(),
-                )?;
-
                 self.insert_count_instruction(increment)
             }
             DecoderInstruction::HeaderAck { stream_id } => self.header_ack(stream_id),
@@ -216,14 +234,14 @@ impl QPackEncoder {
     /// `EncoderStreamBlocked` if the encoder stream is blocked by the flow control.
     /// `DynamicTableFull` if the dynamic table does not have enough space for the entry.
     /// The function can return transport errors: `InvalidStreamId`, `InvalidInput` and `FinalSizeError`.
-    pub fn send_and_insert(
+    pub fn send_and_insert<W: StreamWriter>(
         &mut self,
-        conn: &mut Connection,
+        writer: &mut W,
         name: &[u8],
         value: &[u8],
     ) -> Res<u64> {
         qdebug!([self], "insert {:?} {:?}.", name, value);
-        self.send(conn)?;
+        self.send(writer)?;
         if self.send_buf.len() != 0 {
             return Err(Error::EncoderStreamBlocked);
         }
@@ -235,18 +253,24 @@ impl QPackEncoder {
         }
 
         let mut buf = QPData::default();
-        EncoderInstruction::InsertWithNameLiteral {
+        let instruction = EncoderInstruction::InsertWithNameLiteral {
             name: &name,
             value: &value,
-        }
-        .marshal(&mut buf, self.use_huffman);
+        };
+        instruction.marshal(&mut buf, self.use_huffman);
 
         let stream_id = self.local_stream_id.ok_or(Error::Internal)?;
 
-        let sent = conn.stream_send_atomic(stream_id, &buf)?;
+        let sent = writer.send_atomic(stream_id, &buf)?;
         if !sent {
             return Err(Error::EncoderStreamBlocked);
         }
+        qtrace!([self], "sent instruction {:x?}", &buf[..]);
+        qlog::qpack_encoder_instruction_sent(
+            writer.qlog(),
+            &(&instruction).into(),
+            self.use_huffman,
+        )?;
 
         match self.table.insert(name, value) {
             Ok(inx) => Ok(inx),
@@ -260,22 +284,34 @@ impl QPackEncoder {
     fn change_capacity(&mut self, value: u64) -> Res<()> {
         qdebug!([self], "change capacity: {}", value);
         self.table.set_capacity(value)?;
-        EncoderInstruction::Capacity { value }.marshal(&mut self.send_buf, self.use_huffman);
+        let instruction = EncoderInstruction::Capacity { value };
+        instruction.marshal(&mut self.send_buf, self.use_huffman);
+        self.qlog_instructions.push((&instruction).into());
         Ok(())
     }
 
     /// Sends any qpack encoder instructions.
     /// # Errors
     ///   returns `EncoderStream` in case of an error.
-    pub fn send(&mut self, conn: &mut Connection) -> Res<()> {
+    pub fn send<W: StreamWriter>(&mut self, writer: &mut W) -> Res<()> {
         if self.send_buf.is_empty() {
             Ok(())
         } else if let Some(stream_id) = self.local_stream_id {
-            let r = conn
-                .stream_send(stream_id, &self.send_buf[..])
+            let r = writer
+                .send(stream_id, &self.send_buf[..])
                 .map_err(|_| Error::EncoderStream)?;
             qdebug!([self], "{} bytes sent.", r);
             self.send_buf.read(r as usize);
+            if self.send_buf.is_empty() {
+                let sent = mem::replace(&mut self.qlog_instructions, Vec::new());
+                for instruction in &sent {
+                    qlog::qpack_encoder_instruction_sent(
+                        writer.qlog(),
+                        instruction,
+                        self.use_huffman,
+                    )?;
+                }
+            }
             Ok(())
         } else {
             Ok(())
@@ -294,13 +330,14 @@ impl QPackEncoder {
         }
     }
 
-    /// Encodes headers
+    /// Encodes headers. New table entries are written to `writer`, which is usually the
+    /// `Connection`, or a buffer if the encoder is used without a transport.
     /// ### Errors
     /// This function may return a transport error when a new entry is added to the table and while sending its
     /// instruction an error occurs.
-    pub fn encode_header_block(
+    pub fn encode_header_block<W: StreamWriter>(
         &mut self,
-        conn: &mut Connection,
+        writer: &mut W,
         h: &[Header],
         stream_id: u64,
     ) -> Res<HeaderEncoder> {
@@ -347,7 +384,7 @@ impl QPackEncoder {
             } else if can_block & !encoder_blocked {
                 // Insert using an InsertWithNameLiteral instruction. This entry name does not match any name in the
                 // tables therefore we cannot use any other instruction.
-                match self.send_and_insert(conn, &name, &value) {
+                match self.send_and_insert(writer, &name, &value) {
                     Ok(index) => {
                         encoded_h.encode_indexed_dynamic(index);
                         ref_entries.insert(index);
@@ -393,11 +430,17 @@ impl QPackEncoder {
 
     /// Encoder stream has been created. Add the stream id.
     pub fn add_send_stream(&mut self, stream_id: u64) {
+        self.add_send_stream_without_type(stream_id);
+        self.send_buf.encode_varint(QPACK_UNI_STREAM_TYPE_ENCODER);
+    }
+
+    /// Add the encoder stream, but do not write the stream type. This is used when the encoder
+    /// runs without a transport and instructions are written to a buffer.
+    pub fn add_send_stream_without_type(&mut self, stream_id: u64) {
         if self.local_stream_id.is_some() {
             panic!("Adding multiple local streams");
         }
         self.local_stream_id = Some(stream_id);
-        self.send_buf.encode_varint(QPACK_UNI_STREAM_TYPE_ENCODER);
     }
 
     /// We have received a remote decoder stream. Remember its stream id.
@@ -445,6 +488,7 @@ fn map_error(err: &Error) -> Error {
 #[cfg(test)]
 mod tests {
     use super::{Connection, Error, Header, QPackEncoder};
+    use crate::decoder::QPackDecoder;
     use crate::QpackSettings;
     use neqo_transport::tparams::{self, TransportParameter};
     use neqo_transport::StreamType;
@@ -606,6 +650,45 @@ mod tests {
         send_instructions(&mut encoder, CAP_INSTRUCTION_200);
     }
 
+    // Until the peer's SETTINGS arrive the table has no capacity, so header blocks only
+    // use literals and the static table.  Setting a capacity later, and growing it
+    // again, doesn't change what was encoded before and keeps the entries.
+    #[test]
+    fn capacity_from_zero_and_growing() {
+        const MY_HEADER_INSERT: &[u8] = &[
+            0x49, 0x6d, 0x79, 0x2d, 0x68, 0x65, 0x61, 0x64, 0x65, 0x72, 0x08, 0x6d, 0x79, 0x2d,
+            0x76, 0x61, 0x6c, 0x75, 0x65,
+        ];
+        let headers = vec![(String::from("my-header"), String::from("my-value"))];
+        let mut encoder = connect(false);
+        encoder.encoder.set_max_blocked_streams(100).unwrap();
+
+        let buf = encoder
+            .encoder
+            .encode_header_block(&mut encoder.conn, &headers, 1)
+            .unwrap();
+        assert_eq!(&buf[..2], &[0x00, 0x00]);
+        assert_is_literal_value_literal_name(&buf);
+        send_instructions(&mut encoder, &[0x02]);
+
+        encoder.encoder.set_max_capacity(200).unwrap();
+        send_instructions(&mut encoder, CAP_INSTRUCTION_200);
+        let buf = encoder
+            .encoder
+            .encode_header_block(&mut encoder.conn, &headers, 1)
+            .unwrap();
+        assert_eq!(&buf[..], &[0x02, 0x80, 0x10]);
+        send_instructions(&mut encoder, MY_HEADER_INSERT);
+
+        encoder.encoder.set_max_capacity(1000).unwrap();
+        send_instructions(&mut encoder, CAP_INSTRUCTION_1000);
+        let buf = encoder
+            .encoder
+            .encode_header_block(&mut encoder.conn, &headers, 1)
+            .unwrap();
+        assert_eq!(&buf[..], ENCODE_INDEXED_REF_DYNAMIC);
+    }
+
     struct TestElement {
         pub headers: Vec<Header>,
         pub header_block: &'static [u8],
@@ -1594,4 +1677,49 @@ mod tests {
             )
             .is_ok());
     }
+
+    #[test]
+    fn standalone() {
+        let headers = vec![
+            (String::from(":method"), String::from("GET")),
+            (String::from("my-header"), String::from("my-value")),
+        ];
+        let mut encoder = QPackEncoder::new(
+            QpackSettings {
+                max_table_size_encoder: 1500,
+                max_table_size_decoder: 0,
+                max_blocked_streams: 0,
+            },
+            false,
+        );
+        encoder.add_send_stream_without_type(0);
+        encoder.set_max_blocked_streams(1).unwrap();
+        assert!(encoder.set_max_capacity(200).is_ok());
+
+        let mut instructions = Vec::new();
+        let header_block = encoder
+            .encode_header_block(&mut instructions, &headers, 4)
+            .unwrap();
+        assert_eq!(encoder.blocked_stream_cnt(), 1);
+
+        let mut decoder = QPackDecoder::new(QpackSettings {
+            max_table_size_encoder: 0,
+            max_table_size_decoder: 200,
+            max_blocked_streams: 1,
+        });
+        decoder.add_send_stream_without_type(0);
+        assert_eq!(
+            decoder.consume_encoder_stream(&instructions),
+            Ok(Vec::new())
+        );
+        let decoded_headers = decoder.decode_header_block(&header_block, 4).unwrap();
+        assert_eq!(decoded_headers.unwrap(), headers);
+
+        let mut decoder_instructions = Vec::new();
+        decoder.send(&mut decoder_instructions).unwrap();
+        assert!(encoder
+            .consume_decoder_stream(&decoder_instructions)
+            .is_ok());
+        assert_eq!(encoder.blocked_stream_cnt(), 0);
+    }
 }
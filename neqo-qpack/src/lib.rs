@@ -25,8 +25,13 @@ mod qpack_send_buf;
 pub mod reader;
 mod static_table;
 mod table;
+pub mod writer;
 
 pub type Header = (String, String);
+
+/// The version of neqo-qpack.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 type Res<T> = Result<T, Error>;
 
 #[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Clone, Copy)]
@@ -6,27 +6,141 @@
 
 // Functions that handle capturing QLOG traces.
 
+use crate::decoder_instructions::DecoderInstruction;
+use crate::encoder_instructions::DecodedEncoderInstruction;
 use crate::Res;
-use neqo_common::hex;
 use neqo_common::qlog::NeqoQlog;
-use qlog::{event::Event, QPackInstruction, QpackInstructionTypeName};
+use qlog::{event::Event, QPackInstruction, QpackInstructionTypeName, QpackTableType};
 
-pub fn qpack_read_insert_count_increment_instruction(
-    qlog: &mut Option<NeqoQlog>,
-    increment: u64,
-    data: &[u8],
+/// An encoder instruction that was written to the encoder stream.  The lengths
+/// are those of the name and value before any Huffman encoding.
+pub fn qpack_encoder_instruction_sent(
+    qlog: Option<&mut NeqoQlog>,
+    instruction: &DecodedEncoderInstruction,
+    huffman: bool,
 ) -> Res<()> {
     if let Some(qlog) = qlog {
-        let event = Event::qpack_instruction_received(
-            QPackInstruction::InsertCountIncrementInstruction {
-                instruction_type: QpackInstructionTypeName::InsertCountIncrementInstruction,
-                increment,
-            },
-            Some(8.to_string()),
-            Some(hex(data)),
-        );
+        if let Some(i) = encoder_instruction_to_qlog(instruction, huffman) {
+            qlog.stream()
+                .add_event(Event::qpack_instruction_sent_min(i))?;
+        }
+    }
+    Ok(())
+}
+
+/// An encoder instruction that was read from the peer's encoder stream.  The reader
+/// doesn't keep whether literals were Huffman encoded, so they are recorded as not.
+pub fn qpack_encoder_instruction_received(
+    qlog: Option<&mut NeqoQlog>,
+    instruction: &DecodedEncoderInstruction,
+) -> Res<()> {
+    if let Some(qlog) = qlog {
+        if let Some(i) = encoder_instruction_to_qlog(instruction, false) {
+            qlog.stream()
+                .add_event(Event::qpack_instruction_received_min(i))?;
+        }
+    }
+    Ok(())
+}
+
+/// A decoder instruction that was written to the decoder stream.
+pub fn qpack_decoder_instruction_sent(
+    qlog: Option<&mut NeqoQlog>,
+    instruction: &DecoderInstruction,
+) -> Res<()> {
+    if let Some(qlog) = qlog {
+        if let Some(i) = decoder_instruction_to_qlog(instruction) {
+            qlog.stream()
+                .add_event(Event::qpack_instruction_sent_min(i))?;
+        }
+    }
+    Ok(())
+}
 
-        qlog.stream().add_event(event)?;
+/// A decoder instruction that was read from the peer's decoder stream.
+pub fn qpack_decoder_instruction_received(
+    qlog: Option<&mut NeqoQlog>,
+    instruction: &DecoderInstruction,
+) -> Res<()> {
+    if let Some(qlog) = qlog {
+        if let Some(i) = decoder_instruction_to_qlog(instruction) {
+            qlog.stream()
+                .add_event(Event::qpack_instruction_received_min(i))?;
+        }
     }
     Ok(())
 }
+
+// Helper functions
+
+fn encoder_instruction_to_qlog(
+    instruction: &DecodedEncoderInstruction,
+    huffman: bool,
+) -> Option<QPackInstruction> {
+    let insert_with_name_ref = |table_type, index: u64, value: &[u8]| {
+        QPackInstruction::InsertWithNameReferenceInstruction {
+            instruction_type: QpackInstructionTypeName::InsertWithNameReferenceInstruction,
+            table_type,
+            name_index: index,
+            huffman_encoded_value: huffman,
+            value_length: value.len() as u64,
+            value: String::from_utf8_lossy(value).to_string(),
+        }
+    };
+    match instruction {
+        DecodedEncoderInstruction::Capacity { value } => {
+            Some(QPackInstruction::SetDynamicTableCapacityInstruction {
+                instruction_type: QpackInstructionTypeName::SetDynamicTableCapacityInstruction,
+                capacity: *value,
+            })
+        }
+        DecodedEncoderInstruction::InsertWithNameRefStatic { index, value } => {
+            Some(insert_with_name_ref(QpackTableType::Static, *index, value))
+        }
+        DecodedEncoderInstruction::InsertWithNameRefDynamic { index, value } => {
+            Some(insert_with_name_ref(QpackTableType::Dynamic, *index, value))
+        }
+        DecodedEncoderInstruction::InsertWithNameLiteral { name, value } => {
+            Some(QPackInstruction::InsertWithoutNameReferenceInstruction {
+                instruction_type: QpackInstructionTypeName::InsertWithoutNameReferenceInstruction,
+                huffman_encoded_name: huffman,
+                name_length: name.len() as u64,
+                name: String::from_utf8_lossy(name).to_string(),
+                huffman_encoded_value: huffman,
+                value_length: value.len() as u64,
+                value: String::from_utf8_lossy(value).to_string(),
+            })
+        }
+        DecodedEncoderInstruction::Duplicate { index } => {
+            Some(QPackInstruction::DuplicateInstruction {
+                instruction_type: QpackInstructionTypeName::DuplicateInstruction,
+                index: *index,
+            })
+        }
+        DecodedEncoderInstruction::NoInstruction => None,
+    }
+}
+
+fn decoder_instruction_to_qlog(instruction: &DecoderInstruction) -> Option<QPackInstruction> {
+    match instruction {
+        DecoderInstruction::InsertCountIncrement { increment } => {
+            Some(QPackInstruction::InsertCountIncrementInstruction {
+                instruction_type: QpackInstructionTypeName::InsertCountIncrementInstruction,
+                increment: *increment,
+            })
+        }
+        DecoderInstruction::HeaderAck { stream_id } => {
+            Some(QPackInstruction::HeaderAcknowledgementInstruction {
+                instruction_type: QpackInstructionTypeName::HeaderAcknowledgementInstruction,
+                stream_id: stream_id.to_string(),
+            })
+        }
+        DecoderInstruction::StreamCancellation { stream_id } => {
+            Some(QPackInstruction::StreamCancellationInstruction {
+                instruction_type: QpackInstructionTypeName::StreamCancellationInstruction,
+                stream_id: stream_id.to_string(),
+            })
+        }
+        DecoderInstruction::NoInstruction => None,
+    }
+}
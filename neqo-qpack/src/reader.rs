@@ -58,6 +58,41 @@ impl<'a> ReceiverConnWrapper<'a> {
     }
 }
 
+/// This reads instructions from a buffer that holds the next part of a stream, e.g. when QPACK
+/// is used without a transport. Reaching the end of the buffer is not an error, it returns
+/// `NeedMoreData` and an instruction reader will continue when more data is supplied.
+pub(crate) struct ReceiverSliceWrapper<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ReadByte for ReceiverSliceWrapper<'a> {
+    fn read_byte(&mut self) -> Res<u8> {
+        if self.offset == self.buf.len() {
+            Err(Error::NeedMoreData)
+        } else {
+            let b = self.buf[self.offset];
+            self.offset += 1;
+            Ok(b)
+        }
+    }
+}
+
+impl<'a> Reader for ReceiverSliceWrapper<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Res<usize> {
+        let amount = std::cmp::min(buf.len(), self.buf.len() - self.offset);
+        buf[..amount].copy_from_slice(&self.buf[self.offset..self.offset + amount]);
+        self.offset += amount;
+        Ok(amount)
+    }
+}
+
+impl<'a> ReceiverSliceWrapper<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, offset: 0 }
+    }
+}
+
 /// This is only used by header decoder therefore all errors are `DecompressionFailed`.
 /// A header block is read entirely before decoding it, therefore if there is not enough
 /// data in the buffer an error `DecompressionFailed` will be return.
@@ -0,0 +1,72 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::Res;
+use neqo_common::qlog::NeqoQlog;
+use neqo_transport::Connection;
+
+/// This is where the encoder and the decoder write their instructions. Usually this is a
+/// `Connection`, but a buffer can be used to run QPACK without a transport, e.g. for testing
+/// against other implementations.
+pub trait StreamWriter {
+    /// Writes as much of `buf` as possible and returns the amount written.
+    /// # Errors
+    ///    Return error occurred while writing to a stream.
+    ///    The exact error depends on trait implementation.
+    fn send(&mut self, stream_id: u64, buf: &[u8]) -> Res<usize>;
+
+    /// Writes either all of `buf` or nothing and returns whether it was written.
+    /// # Errors
+    ///    Return error occurred while writing to a stream.
+    ///    The exact error depends on trait implementation.
+    fn send_atomic(&mut self, stream_id: u64, buf: &[u8]) -> Res<bool>;
+
+    /// The qlog that the instructions that are written are recorded in, if any.
+    fn qlog(&mut self) -> Option<&mut NeqoQlog> {
+        None
+    }
+}
+
+impl StreamWriter for Connection {
+    fn send(&mut self, stream_id: u64, buf: &[u8]) -> Res<usize> {
+        Ok(self.stream_send(stream_id, buf)?)
+    }
+
+    fn send_atomic(&mut self, stream_id: u64, buf: &[u8]) -> Res<bool> {
+        Ok(self.stream_send_atomic(stream_id, buf)?)
+    }
+
+    fn qlog(&mut self) -> Option<&mut NeqoQlog> {
+        self.qlog_mut().as_mut()
+    }
+}
+
+/// A buffer holds the data of a single stream, so the stream ID is ignored.
+/// Writing to a buffer never blocks.
+impl StreamWriter for Vec<u8> {
+    fn send(&mut self, _stream_id: u64, buf: &[u8]) -> Res<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn send_atomic(&mut self, _stream_id: u64, buf: &[u8]) -> Res<bool> {
+        self.extend_from_slice(buf);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StreamWriter;
+
+    #[test]
+    fn buffer() {
+        let mut buf = Vec::new();
+        assert_eq!(buf.send(2, &[1, 2]), Ok(2));
+        assert_eq!(buf.send_atomic(6, &[3]), Ok(true));
+        assert_eq!(buf, vec![1, 2, 3]);
+    }
+}
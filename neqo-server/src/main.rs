@@ -10,6 +10,7 @@
 use neqo_common::Datagram;
 use neqo_crypto::{init_db, AntiReplay};
 use neqo_transport::{Connection, ConnectionEvent, FixedConnectionIdManager, State};
+use neqo_udp::{self as udp, LocalDrops};
 use regex::Regex;
 
 use std::cell::RefCell;
@@ -121,12 +122,10 @@ fn http_serve(server: &mut Connection, stream: u64) {
     server.stream_close_send(stream).expect("Stream closed");
 }
 
-fn emit_datagram(socket: &UdpSocket, d: Datagram) {
-    let sent = socket
-        .send_to(&d[..], d.destination())
-        .expect("Error sending datagram");
-    if sent != d.len() {
-        eprintln!("Unable to send all {} bytes of datagram", d.len());
+fn emit_datagrams(socket: &UdpSocket, dgrams: &[Datagram], stats: &mut udp::BatchStats) {
+    let mut sent = 0;
+    while sent < dgrams.len() {
+        sent += udp::send_batch_to(socket, &dgrams[sent..], stats).expect("Error sending datagram");
     }
 }
 
@@ -145,6 +144,9 @@ fn main() {
 
     println!("Server waiting for connection on: {:?}", local_addr);
 
+    let mut local_drops = LocalDrops::new(&socket);
+    let mut total_drops = 0;
+    let mut stats = udp::BatchStats::default();
     let buf = &mut [0u8; 2048];
     let mut connections: HashMap<SocketAddr, Connection> = HashMap::new();
     loop {
@@ -170,6 +172,16 @@ fn main() {
             let dgram = Datagram::new(remote_addr, local_addr, &buf[..sz]);
             server.process_input(dgram, Instant::now());
         }
+        // Every connection shares the socket, so which of them lost the datagrams
+        // isn't known.  They are reported for the socket instead.
+        let drops = local_drops.check(&socket, Instant::now());
+        if drops > 0 {
+            total_drops += drops;
+            eprintln!(
+                "The socket dropped {} datagrams, {} in all: its receive buffer was full",
+                drops, total_drops
+            );
+        }
         if let State::Closed(e) = server.state() {
             eprintln!("Closed connection from {:?}: {:?}", remote_addr, e);
             connections.remove(&remote_addr);
@@ -191,9 +203,13 @@ fn main() {
             http_serve(&mut server, stream_id);
         }
 
-        let out = server.process_output(Instant::now());
-        if let Some(dgram) = out.dgram() {
-            emit_datagram(&socket, dgram);
+        // A full batch means that there could be more to send.
+        loop {
+            let (dgrams, _) = server.process_output_batch(Instant::now(), udp::MAX_BATCH);
+            emit_datagrams(&socket, &dgrams, &mut stats);
+            if dgrams.len() < udp::MAX_BATCH {
+                break;
+            }
         }
     }
 }
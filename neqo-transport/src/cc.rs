@@ -6,11 +6,11 @@
 
 // Congestion control
 
-use std::cmp::max;
-use std::fmt::{self, Display};
+use std::cmp::{max, min};
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Display};
 use std::time::{Duration, Instant};
 
-use crate::pace::Pacer;
 use crate::path::PATH_MTU_V6;
 use crate::tracking::SentPacket;
 use neqo_common::{const_max, const_min, qdebug, qinfo, qtrace};
@@ -22,67 +22,193 @@ const INITIAL_WINDOW: usize = const_min(
     const_max(2 * MAX_DATAGRAM_SIZE, 14720),
 );
 pub const MIN_CONG_WINDOW: usize = MAX_DATAGRAM_SIZE * 2;
-/// The number of packets we allow to burst from the pacer.
-pub(crate) const PACING_BURST_SIZE: usize = 2;
 const PERSISTENT_CONG_THRESH: u32 = 3;
+/// The largest congestion window that a connection can be jump-started with.
+const MAX_JUMP_START_WINDOW: usize = 4 * INITIAL_WINDOW;
 
+/// A coarse view of the congestion controller, see `ConnectionEvent::CongestionStateChanged`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CongestionState {
+    /// No packets have been lost since the last recovery period ended.
+    NoCongestion,
+    /// Packets were lost and the congestion window was reduced.  This ends when
+    /// a packet that was sent after the reduction is acknowledged.
+    Recovery,
+    /// Losses went on for long enough that the congestion window was reset to
+    /// the minimum.  This ends in the same way as `Recovery`.
+    PersistentCongestion,
+}
+
+/// A congestion control algorithm.  This decides how much can be in flight;
+/// the `PacketSender` that holds it adds pacing and any send rate limit.
+pub trait CongestionControl: Display + Debug {
+    #[must_use]
+    fn cwnd(&self) -> usize;
+
+    /// The slow start threshold, which is `usize::MAX` until something is lost.
+    #[must_use]
+    fn ssthresh(&self) -> usize;
+
+    #[must_use]
+    fn bytes_in_flight(&self) -> usize;
+
+    #[must_use]
+    fn cwnd_avail(&self) -> usize;
+
+    #[must_use]
+    fn state(&self) -> CongestionState;
+
+    /// Start with a congestion window based on one that a previous connection
+    /// on the same path reached.
+    fn jump_start(&mut self, cwnd: usize);
+
+    /// Start again on a new path.  What is in flight is still counted, as it is
+    /// acknowledged or lost as usual.
+    fn restart(&mut self);
+
+    /// Enable or disable the reduction of the congestion window after an idle period.
+    fn set_idle_restart(&mut self, idle_restart: bool);
+
+    /// Before sending after an idle period, reduce the congestion window so that
+    /// a window that was built up before the idle period doesn't all go at once.
+    /// Returns `true` if the window was reduced.
+    fn restart_after_idle(&mut self, now: Instant, pto: Duration) -> bool;
+
+    fn on_packet_sent(&mut self, pkt: &SentPacket);
+
+    fn on_packets_acked(&mut self, acked_pkts: &[SentPacket]);
+
+    fn on_packets_lost(
+        &mut self,
+        now: Instant,
+        prev_largest_acked_sent: Option<Instant>,
+        pto: Duration,
+        lost_packets: &[SentPacket],
+    );
+
+    /// Stop counting a packet that will neither be acknowledged nor declared lost.
+    fn discard(&mut self, pkt: &SentPacket);
+
+    /// Move any timing `by` later, to match the packets that were sent before
+    /// the host was suspended.
+    fn suspended(&mut self, by: Duration);
+}
+
+/// NewReno (RFC 6582), as described in the QUIC recovery draft: slow start
+/// until the first loss, then congestion avoidance, with the window halved
+/// once per recovery period.
 #[derive(Debug)]
-pub struct CongestionControl {
+pub struct NewReno {
     congestion_window: usize, // = kInitialWindow
     bytes_in_flight: usize,
     congestion_recovery_start_time: Option<Instant>,
     ssthresh: usize,
-    pacer: Option<Pacer>,
+    state: CongestionState,
+    /// Whether the congestion window is reduced after an idle period.
+    idle_restart: bool,
+    /// When the last packet that counts toward `bytes_in_flight` was sent.
+    last_sent: Option<Instant>,
 }
 
-impl Default for CongestionControl {
+impl Default for NewReno {
     fn default() -> Self {
         Self {
             congestion_window: INITIAL_WINDOW,
             bytes_in_flight: 0,
             congestion_recovery_start_time: None,
             ssthresh: std::usize::MAX,
-            pacer: None,
+            state: CongestionState::NoCongestion,
+            idle_restart: true,
+            last_sent: None,
         }
     }
 }
 
-impl Display for CongestionControl {
+impl Display for NewReno {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "CongCtrl {}/{} ssthresh {}",
+            "NewReno {}/{} ssthresh {}",
             self.bytes_in_flight, self.congestion_window, self.ssthresh,
-        )?;
-        if let Some(p) = &self.pacer {
-            write!(f, " {}", p)?;
-        }
-        Ok(())
+        )
     }
 }
 
-impl CongestionControl {
-    #[cfg(test)]
-    #[must_use]
-    pub fn cwnd(&self) -> usize {
+impl CongestionControl for NewReno {
+    fn cwnd(&self) -> usize {
         self.congestion_window
     }
 
-    #[cfg(test)]
-    #[must_use]
-    pub fn ssthresh(&self) -> usize {
+    fn ssthresh(&self) -> usize {
         self.ssthresh
     }
 
-    #[must_use]
-    pub fn cwnd_avail(&self) -> usize {
+    fn bytes_in_flight(&self) -> usize {
+        self.bytes_in_flight
+    }
+
+    fn state(&self) -> CongestionState {
+        self.state
+    }
+
+    fn cwnd_avail(&self) -> usize {
         // BIF can be higher than cwnd due to PTO packets, which are sent even
         // if avail is 0, but still count towards BIF.
         self.congestion_window.saturating_sub(self.bytes_in_flight)
     }
 
+    /// Only half of the window is used, and the result is bounded so that
+    /// a stale hint can't produce a large burst.
+    fn jump_start(&mut self, cwnd: usize) {
+        debug_assert_eq!(self.bytes_in_flight, 0);
+        self.congestion_window = max(INITIAL_WINDOW, min(cwnd / 2, MAX_JUMP_START_WINDOW));
+        qinfo!([self], "Jump start");
+    }
+
+    fn restart(&mut self) {
+        self.congestion_window = INITIAL_WINDOW;
+        self.ssthresh = std::usize::MAX;
+        self.congestion_recovery_start_time = None;
+        self.state = CongestionState::NoCongestion;
+        qinfo!([self], "Restart");
+    }
+
+    fn set_idle_restart(&mut self, idle_restart: bool) {
+        self.idle_restart = idle_restart;
+    }
+
+    /// This follows congestion window validation (RFC 2861): the window is halved
+    /// for each `pto` that nothing was in flight, down to the initial window.
+    /// `ssthresh` keeps 3/4 of the old window, so that slow start gets back to it quickly.
+    fn restart_after_idle(&mut self, now: Instant, pto: Duration) -> bool {
+        if !self.idle_restart || self.bytes_in_flight > 0 {
+            return false;
+        }
+        let idle = match self.last_sent {
+            Some(t) => now.saturating_duration_since(t),
+            None => return false,
+        };
+        let restart_window = min(INITIAL_WINDOW, self.congestion_window);
+        if idle <= pto || self.congestion_window <= restart_window {
+            return false;
+        }
+        let reduced = idle
+            .as_nanos()
+            .checked_div(pto.as_nanos())
+            .and_then(|p| u32::try_from(p).ok())
+            .and_then(|p| self.congestion_window.checked_shr(p))
+            .unwrap_or(0);
+        self.ssthresh = max(self.ssthresh, self.congestion_window * 3 / 4);
+        self.congestion_window = max(restart_window, reduced);
+        // This can be called again before anything is sent, so the idle time
+        // that was just used is not counted again.
+        self.last_sent = Some(now);
+        qinfo!([self], "Restart after idle for {:?}", idle);
+        true
+    }
+
     // Multi-packet version of OnPacketAckedCC
-    pub fn on_packets_acked(&mut self, acked_pkts: &[SentPacket]) {
+    fn on_packets_acked(&mut self, acked_pkts: &[SentPacket]) {
         for pkt in acked_pkts.iter().filter(|pkt| pkt.cc_outstanding()) {
             assert!(self.bytes_in_flight >= pkt.size);
             self.bytes_in_flight -= pkt.size;
@@ -91,6 +217,10 @@ impl CongestionControl {
                 // Do not increase congestion window in recovery period.
                 continue;
             }
+            if self.state != CongestionState::NoCongestion {
+                qinfo!([self], "recovery period ends");
+                self.state = CongestionState::NoCongestion;
+            }
             if self.app_limited() {
                 // Do not increase congestion_window if application limited.
                 continue;
@@ -106,7 +236,7 @@ impl CongestionControl {
         }
     }
 
-    pub fn on_packets_lost(
+    fn on_packets_lost(
         &mut self,
         now: Instant,
         prev_largest_acked_sent: Option<Instant>,
@@ -134,14 +264,19 @@ impl CongestionControl {
             .iter()
             .find(|p| Some(p.time_sent) > prev_largest_acked_sent)
         {
-            if last_lost_pkt.time_sent.duration_since(first.time_sent) > congestion_period {
+            if last_lost_pkt
+                .time_sent
+                .saturating_duration_since(first.time_sent)
+                > congestion_period
+            {
                 self.congestion_window = MIN_CONG_WINDOW;
+                self.state = CongestionState::PersistentCongestion;
                 qinfo!([self], "persistent congestion");
             }
         }
     }
 
-    pub fn discard(&mut self, pkt: &SentPacket) {
+    fn discard(&mut self, pkt: &SentPacket) {
         if pkt.cc_outstanding() {
             assert!(self.bytes_in_flight >= pkt.size);
             self.bytes_in_flight -= pkt.size;
@@ -149,16 +284,12 @@ impl CongestionControl {
         }
     }
 
-    pub fn on_packet_sent(&mut self, pkt: &SentPacket, rtt: Duration) {
-        self.pacer
-            .as_mut()
-            .unwrap()
-            .spend(pkt.time_sent, rtt, self.congestion_window, pkt.size);
-
+    fn on_packet_sent(&mut self, pkt: &SentPacket) {
         if !pkt.cc_in_flight() {
             return;
         }
 
+        self.last_sent = Some(pkt.time_sent);
         self.bytes_in_flight += pkt.size;
         qdebug!(
             [self],
@@ -170,8 +301,16 @@ impl CongestionControl {
         debug_assert!(self.bytes_in_flight <= self.congestion_window);
     }
 
-    #[must_use]
-    pub fn in_congestion_recovery(&self, sent_time: Instant) -> bool {
+    /// The start of the recovery period moves.
+    fn suspended(&mut self, by: Duration) {
+        if let Some(t) = &mut self.congestion_recovery_start_time {
+            *t += by;
+        }
+    }
+}
+
+impl NewReno {
+    fn in_congestion_recovery(&self, sent_time: Instant) -> bool {
         self.congestion_recovery_start_time
             .map(|start| sent_time <= start)
             .unwrap_or(false)
@@ -185,6 +324,7 @@ impl CongestionControl {
             self.congestion_window /= 2; // kLossReductionFactor = 0.5
             self.congestion_window = max(self.congestion_window, MIN_CONG_WINDOW);
             self.ssthresh = self.congestion_window;
+            self.state = CongestionState::Recovery;
             qinfo!(
                 [self],
                 "Cong event -> recovery; cwnd {}, ssthresh {}",
@@ -200,27 +340,4 @@ impl CongestionControl {
         //TODO(agrover): how do we get this info??
         false
     }
-
-    pub fn start_pacer(&mut self, now: Instant) {
-        // Start the pacer with a small burst size.
-        self.pacer = Some(Pacer::new(
-            now,
-            MAX_DATAGRAM_SIZE * PACING_BURST_SIZE,
-            MAX_DATAGRAM_SIZE,
-        ));
-    }
-
-    pub fn next_paced(&self, rtt: Duration) -> Option<Instant> {
-        // Only pace if there are bytes in flight.
-        if self.bytes_in_flight > 0 {
-            Some(
-                self.pacer
-                    .as_ref()
-                    .unwrap()
-                    .next(rtt, self.congestion_window),
-            )
-        } else {
-            None
-        }
-    }
 }
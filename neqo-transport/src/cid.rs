@@ -6,13 +6,28 @@
 
 // Encoding and decoding packets off the wire.
 
-use neqo_common::{hex, hex_with_len, matches, Decoder};
-use neqo_crypto::random;
+use crate::frame::Frame;
+use crate::recovery::RecoveryToken;
+use crate::rng::random;
+use crate::stateless_reset::{StatelessResetToken, STATELESS_RESET_TOKEN_LEN};
+use crate::tracking::PNSpace;
+use crate::{Error, Res};
+
+use neqo_common::{hex, hex_with_len, matches, qinfo, Decoder, Encoder};
 
 use std::borrow::Borrow;
-use std::cmp::max;
+use std::cmp::{max, min};
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
 
 pub const MAX_CONNECTION_ID_LEN: usize = 20;
+/// The most RETIRE_CONNECTION_ID frames that can be waiting to be sent.  A peer
+/// that retires connection IDs faster than that is closed.
+const MAX_PENDING_RETIRE: usize = 32;
+/// How many retired connection IDs can be replaced at once.
+pub const CID_ISSUE_BURST: usize = 8;
+/// How often another retired connection ID can be replaced, once the burst is used.
+pub const CID_ISSUE_INTERVAL: Duration = Duration::from_millis(250);
 
 #[derive(Clone, Default, Eq, Hash, PartialEq)]
 pub struct ConnectionId {
@@ -130,6 +145,347 @@ pub trait ConnectionIdDecoder {
 pub trait ConnectionIdManager: ConnectionIdDecoder {
     fn generate_cid(&mut self) -> ConnectionId;
     fn as_decoder(&self) -> &dyn ConnectionIdDecoder;
+    /// The stateless reset token for a connection ID that this generated.
+    /// A server sends this to the client, so that it can tell the client about
+    /// connections that it has lost the state for.  By default there is none.
+    fn stateless_reset_token(&self, _cid: &[u8]) -> Option<[u8; 16]> {
+        None
+    }
+    /// Called when the peer retires a connection ID that this generated, so that
+    /// packets sent to it are no longer routed to the connection.
+    fn retire_cid(&mut self, _cid: &[u8]) {}
+}
+
+/// A connection ID with the sequence number and stateless reset token that
+/// are sent with it in NEW_CONNECTION_ID.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionIdEntry {
+    seqno: u64,
+    cid: ConnectionId,
+    srt: StatelessResetToken,
+}
+
+impl ConnectionIdEntry {
+    /// We never ask the peer to retire connection IDs, so Retire Prior To is 0.
+    fn frame(&self) -> Frame {
+        Frame::NewConnectionId {
+            sequence_number: self.seqno,
+            retire_prior: 0,
+            connection_id: self.cid.to_vec(),
+            stateless_reset_token: self.srt,
+        }
+    }
+}
+
+/// Whether `frame` fits in `remaining` bytes.
+fn frame_that_fits(frame: &Frame, remaining: usize) -> bool {
+    let mut enc = Encoder::default();
+    frame.marshal(&mut enc);
+    enc.len() <= remaining
+}
+
+/// A token bucket for replacing connection IDs that the peer retires.  A peer
+/// that retires every connection ID as soon as it gets it would otherwise have
+/// us generate and send new ones without end.
+#[derive(Debug)]
+pub struct IssueLimiter {
+    tokens: usize,
+    /// When the last token was added, or when the bucket was last full.
+    updated: Option<Instant>,
+}
+
+impl Default for IssueLimiter {
+    fn default() -> Self {
+        Self {
+            tokens: CID_ISSUE_BURST,
+            updated: None,
+        }
+    }
+}
+
+impl IssueLimiter {
+    fn refill(&mut self, now: Instant) {
+        let updated = *self.updated.get_or_insert(now);
+        let added =
+            now.saturating_duration_since(updated).as_nanos() / CID_ISSUE_INTERVAL.as_nanos();
+        let added = usize::try_from(added).unwrap_or(usize::max_value());
+        self.tokens = min(CID_ISSUE_BURST, self.tokens.saturating_add(added));
+        if self.tokens == CID_ISSUE_BURST {
+            self.updated = Some(now);
+        } else if added > 0 {
+            // Keep the remainder, so that tokens arrive at a steady rate.
+            self.updated = Some(updated + CID_ISSUE_INTERVAL * u32::try_from(added).unwrap());
+        }
+    }
+
+    /// Take a token, if there is one.
+    pub fn take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens == 0 {
+            false
+        } else {
+            self.tokens -= 1;
+            true
+        }
+    }
+
+    /// When the next token arrives, if there are none now.
+    pub fn next_token(&self) -> Option<Instant> {
+        if self.tokens == 0 {
+            self.updated.map(|u| u + CID_ISSUE_INTERVAL)
+        } else {
+            None
+        }
+    }
+}
+
+/// The connection IDs that the peer can send to.  The one from the handshake has
+/// sequence number 0; the rest are sent to the peer in NEW_CONNECTION_ID.
+#[derive(Debug, Default)]
+pub struct LocalConnectionIds {
+    /// Those that haven't been retired, in order of sequence number.
+    active: Vec<ConnectionIdEntry>,
+    /// The sequence numbers of those that NEW_CONNECTION_ID needs to be sent for.
+    pending: Vec<u64>,
+    next_seqno: u64,
+}
+
+impl LocalConnectionIds {
+    /// Add a connection ID.  All but the first are sent to the peer.
+    pub fn add(&mut self, cid: ConnectionId, srt: Option<StatelessResetToken>) {
+        let srt = srt.unwrap_or_else(|| {
+            let mut token = [0; STATELESS_RESET_TOKEN_LEN];
+            // The peer can end the connection with this, so it can't be predictable.
+            token.copy_from_slice(&neqo_crypto::random(STATELESS_RESET_TOKEN_LEN));
+            token
+        });
+        if self.next_seqno > 0 {
+            self.pending.push(self.next_seqno);
+        }
+        self.active.push(ConnectionIdEntry {
+            seqno: self.next_seqno,
+            cid,
+            srt,
+        });
+        self.next_seqno += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.len()
+    }
+
+    pub fn contains(&self, cid: &ConnectionIdRef) -> bool {
+        self.active.iter().any(|e| e.cid == *cid)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ConnectionId> {
+        self.active.iter().map(|e| &e.cid)
+    }
+
+    /// The peer has retired `seqno`, which can't be one that wasn't issued.
+    /// This returns the connection ID, unless it was already retired.
+    pub fn retire(&mut self, seqno: u64) -> Res<Option<ConnectionId>> {
+        if seqno >= self.next_seqno {
+            return Err(Error::ProtocolViolation);
+        }
+        self.pending.retain(|s| *s != seqno);
+        let i = self.active.iter().position(|e| e.seqno == seqno);
+        Ok(i.map(|i| self.active.remove(i).cid))
+    }
+
+    /// The sequence number of `cid`, if it is active.
+    pub fn seqno(&self, cid: &[u8]) -> Option<u64> {
+        self.active
+            .iter()
+            .find(|e| &e.cid[..] == cid)
+            .map(|e| e.seqno)
+    }
+
+    pub fn get_frame(
+        &mut self,
+        space: PNSpace,
+        remaining: usize,
+    ) -> Option<(Frame, Option<RecoveryToken>)> {
+        if space != PNSpace::ApplicationData {
+            return None;
+        }
+        let seqno = *self.pending.first()?;
+        let entry = self.active.iter().find(|e| e.seqno == seqno).unwrap();
+        let frame = entry.frame();
+        if !frame_that_fits(&frame, remaining) {
+            return None;
+        }
+        let token = RecoveryToken::NewConnectionId(entry.clone());
+        self.pending.remove(0);
+        Some((frame, Some(token)))
+    }
+
+    /// NEW_CONNECTION_ID was lost, so send it again if the peer hasn't retired
+    /// the connection ID since.
+    pub fn lost(&mut self, entry: &ConnectionIdEntry) {
+        if self.active.contains(entry) && !self.pending.contains(&entry.seqno) {
+            self.pending.push(entry.seqno);
+        }
+    }
+}
+
+/// The connection IDs that the peer has given us to send to.  The one from the
+/// handshake has sequence number 0, and is used until the peer retires it or
+/// the connection moves; it isn't recorded here, as it doesn't have a sequence
+/// number until then.
+#[derive(Debug)]
+pub struct RemoteConnectionIds {
+    /// Those from NEW_CONNECTION_ID that haven't been retired.
+    entries: Vec<ConnectionIdEntry>,
+    /// Whether the connection ID from the handshake hasn't been retired.
+    handshake_active: bool,
+    /// The largest Retire Prior To value that the peer has sent.
+    retire_prior: u64,
+    /// The sequence numbers that RETIRE_CONNECTION_ID needs to be sent for.
+    pending_retire: Vec<u64>,
+}
+
+impl Default for RemoteConnectionIds {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            handshake_active: true,
+            retire_prior: 0,
+            pending_retire: Vec::new(),
+        }
+    }
+}
+
+impl RemoteConnectionIds {
+    /// Add a connection ID from NEW_CONNECTION_ID, and retire any that the frame
+    /// says to.  No more than `limit` can be active afterwards.
+    pub fn add(
+        &mut self,
+        seqno: u64,
+        retire_prior: u64,
+        cid: &[u8],
+        srt: StatelessResetToken,
+        limit: usize,
+    ) -> Res<()> {
+        if retire_prior > seqno {
+            return Err(Error::FrameEncodingError);
+        }
+        if let Some(e) = self.entries.iter().find(|e| e.seqno == seqno) {
+            // A repeated frame is fine, but the value can't change.
+            return if &e.cid[..] == cid && e.srt == srt {
+                Ok(())
+            } else {
+                Err(Error::ProtocolViolation)
+            };
+        }
+        if seqno < self.retire_prior {
+            // This was already retired, by a frame that arrived first.
+            self.retire_seqno(seqno);
+            return self.check_pending_retire();
+        }
+        let retire_prior = max(retire_prior, self.retire_prior);
+        let keep = self
+            .entries
+            .iter()
+            .filter(|e| e.seqno >= retire_prior)
+            .count();
+        let handshake = self.handshake_active && retire_prior == 0;
+        if keep + 1 + usize::from(handshake) > limit {
+            return Err(Error::ConnectionIdLimitExceeded);
+        }
+
+        if self.handshake_active && !handshake {
+            self.handshake_active = false;
+            self.retire_seqno(0);
+        }
+        let (keep, retire): (Vec<_>, Vec<_>) = self
+            .entries
+            .drain(..)
+            .partition(|e| e.seqno >= retire_prior);
+        self.entries = keep;
+        for e in retire {
+            self.retire_seqno(e.seqno);
+        }
+        self.entries.push(ConnectionIdEntry {
+            seqno,
+            cid: ConnectionId::from(cid),
+            srt,
+        });
+        self.retire_prior = retire_prior;
+        self.check_pending_retire()
+    }
+
+    /// The peer decides how many connection IDs are retired, so it has to wait
+    /// for RETIRE_CONNECTION_ID before it retires too many more.
+    fn check_pending_retire(&self) -> Res<()> {
+        if self.pending_retire.len() > MAX_PENDING_RETIRE {
+            Err(Error::ConnectionIdLimitExceeded)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn retire_seqno(&mut self, seqno: u64) {
+        qinfo!("Retiring remote connection ID {}", seqno);
+        if !self.pending_retire.contains(&seqno) {
+            self.pending_retire.push(seqno);
+        }
+    }
+
+    /// Whether `cid` can still be sent to.  Anything that this doesn't know
+    /// about is taken to be the connection ID from the handshake.
+    pub fn is_active(&self, cid: &[u8]) -> bool {
+        self.entries.iter().any(|e| &e.cid[..] == cid) || self.handshake_active
+    }
+
+    /// Stop using `cid`, which the connection has moved away from.
+    pub fn retire(&mut self, cid: &[u8]) {
+        if let Some(i) = self.entries.iter().position(|e| &e.cid[..] == cid) {
+            let e = self.entries.remove(i);
+            self.retire_seqno(e.seqno);
+        } else if self.handshake_active {
+            self.handshake_active = false;
+            self.retire_seqno(0);
+        }
+    }
+
+    /// A connection ID that isn't one of `in_use`, with the lowest sequence number.
+    pub fn next_unused(&self, in_use: &[&[u8]]) -> Option<ConnectionId> {
+        self.entries
+            .iter()
+            .filter(|e| !in_use.contains(&&e.cid[..]))
+            .min_by_key(|e| e.seqno)
+            .map(|e| e.cid.clone())
+    }
+
+    /// The stateless reset tokens for the connection IDs from NEW_CONNECTION_ID.
+    pub fn reset_tokens(&self) -> impl Iterator<Item = &StatelessResetToken> {
+        self.entries.iter().map(|e| &e.srt)
+    }
+
+    pub fn get_frame(
+        &mut self,
+        space: PNSpace,
+        remaining: usize,
+    ) -> Option<(Frame, Option<RecoveryToken>)> {
+        if space != PNSpace::ApplicationData {
+            return None;
+        }
+        let sequence_number = *self.pending_retire.first()?;
+        let frame = Frame::RetireConnectionId { sequence_number };
+        if !frame_that_fits(&frame, remaining) {
+            return None;
+        }
+        self.pending_retire.remove(0);
+        Some((
+            frame,
+            Some(RecoveryToken::RetireConnectionId(sequence_number)),
+        ))
+    }
+
+    pub fn lost(&mut self, seqno: u64) {
+        self.retire_seqno(seqno);
+    }
 }
 
 #[cfg(test)]
@@ -138,6 +494,33 @@ mod tests {
     use neqo_common::matches;
     use test_fixture::fixture_init;
 
+    #[test]
+    fn issue_limiter() {
+        let start = test_fixture::now();
+        let mut limiter = IssueLimiter::default();
+        for _ in 0..CID_ISSUE_BURST {
+            assert!(limiter.take(start));
+        }
+        assert!(!limiter.take(start));
+        assert_eq!(limiter.next_token(), Some(start + CID_ISSUE_INTERVAL));
+        assert!(!limiter.take(start + CID_ISSUE_INTERVAL / 2));
+        assert!(limiter.take(start + CID_ISSUE_INTERVAL));
+        assert!(!limiter.take(start + CID_ISSUE_INTERVAL));
+
+        // Time that passes between tokens isn't lost.
+        let later = start + CID_ISSUE_INTERVAL * 5 / 2;
+        assert!(limiter.take(later));
+        assert!(!limiter.take(later));
+        assert!(limiter.take(start + CID_ISSUE_INTERVAL * 3));
+
+        // Nothing builds up beyond the burst.
+        let much_later = start + CID_ISSUE_INTERVAL * 100;
+        for _ in 0..CID_ISSUE_BURST {
+            assert!(limiter.take(much_later));
+        }
+        assert!(!limiter.take(much_later));
+    }
+
     #[test]
     fn generate_initial_cid() {
         fixture_init();
@@ -148,4 +531,40 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn remote_retire_prior() {
+        let mut remote = RemoteConnectionIds::default();
+        remote.add(1, 0, &[1], [1; 16], 2).unwrap();
+        assert!(remote.is_active(&[9]));
+        assert_eq!(
+            remote.add(2, 0, &[2], [2; 16], 2),
+            Err(Error::ConnectionIdLimitExceeded)
+        );
+        // Retiring everything before 2 also retires the one from the handshake.
+        remote.add(2, 2, &[2], [2; 16], 2).unwrap();
+        assert!(!remote.is_active(&[9]));
+        assert!(!remote.is_active(&[1]));
+        assert_eq!(remote.next_unused(&[]), Some(ConnectionId::from(&[2][..])));
+        // Something that was already retired is retired again straight away.
+        remote.add(1, 0, &[1], [1; 16], 2).unwrap();
+        assert_eq!(remote.pending_retire, vec![0, 1]);
+    }
+
+    #[test]
+    fn remote_pending_retire_limit() {
+        let mut remote = RemoteConnectionIds::default();
+        // Each of these retires the one before it, which is only retired once
+        // RETIRE_CONNECTION_ID is sent.
+        for seqno in 1..=MAX_PENDING_RETIRE as u64 {
+            let cid = seqno.to_be_bytes();
+            remote.add(seqno, seqno, &cid, [0; 16], 2).unwrap();
+        }
+        assert_eq!(remote.pending_retire.len(), MAX_PENDING_RETIRE);
+        let seqno = MAX_PENDING_RETIRE as u64 + 1;
+        assert_eq!(
+            remote.add(seqno, seqno, &seqno.to_be_bytes(), [0; 16], 2),
+            Err(Error::ConnectionIdLimitExceeded)
+        );
+    }
 }
@@ -8,7 +8,7 @@
 
 use std::cell::RefCell;
 use std::cmp::{max, min, Ordering};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::convert::TryFrom;
 use std::fmt::{self, Debug};
 use std::mem;
@@ -24,32 +24,45 @@ use neqo_common::{
 };
 use neqo_crypto::agent::CertificateInfo;
 use neqo_crypto::{
-    Agent, AntiReplay, AuthenticationStatus, Cipher, Client, HandshakeState, SecretAgentInfo,
-    Server,
+    Agent, AntiReplay, AuthenticationStatus, Cipher, Client, Group, HandshakeState,
+    ResumptionTokenInfo, SecretAgentInfo, Server, TLS_VERSION_1_3,
 };
 
-use crate::cid::{ConnectionId, ConnectionIdDecoder, ConnectionIdManager, ConnectionIdRef};
-use crate::crypto::{Crypto, CryptoDxState};
+use crate::cc::CongestionState;
+use crate::cid::{
+    ConnectionId, ConnectionIdDecoder, ConnectionIdManager, ConnectionIdRef, IssueLimiter,
+    LocalConnectionIds, RemoteConnectionIds,
+};
+use crate::crypto::{Crypto, CryptoDxState, CryptoStates};
 use crate::dump::*;
-use crate::events::{ConnectionEvent, ConnectionEvents};
+use crate::events::{ConnectionEvent, ConnectionEvents, EventMask};
 use crate::flow_mgr::FlowMgr;
 use crate::frame::{
-    AckRange, CloseError, Frame, FrameType, StreamType, FRAME_TYPE_CONNECTION_CLOSE_APPLICATION,
-    FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT,
+    printable_reason_phrase, AckRange, CloseError, Frame, FrameType, StreamType,
+    FRAME_TYPE_CONNECTION_CLOSE_APPLICATION, FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT,
+};
+use crate::packet::{
+    DecryptedPacket, PacketBuilder, PacketNumber, PacketType, PublicPacket, MIN_INITIAL_PACKET_SIZE,
 };
-use crate::packet::{DecryptedPacket, PacketBuilder, PacketNumber, PacketType, PublicPacket};
-use crate::path::Path;
+use crate::params::ConnectionParameters;
+use crate::path::{Path, PathInfo, PathStats, RecoveryStats, PATH_MTU_V6, PATH_PROBE_FRAME_SIZE};
+#[cfg(feature = "qlog")]
 use crate::qlog;
-use crate::recovery::{LossRecovery, RecoveryToken, SendProfile, GRANULARITY};
-use crate::recv_stream::{RecvStream, RecvStreams, RX_STREAM_DATA_WINDOW};
-use crate::send_stream::{SendStream, SendStreams};
-use crate::stats::Stats;
-use crate::stream_id::{StreamId, StreamIndex, StreamIndexes};
+use crate::recovery::{LossRecovery, RecoveryToken, SendProfile, ACK_ONLY_SIZE_LIMIT, GRANULARITY};
+use crate::recv_stream::{RecvStream, RecvStreams, STREAM_CREDIT_DIVISOR};
+use crate::send_stream::{PendingSendStream, SendStream, SendStreams, TransmissionPriority};
+use crate::stall::StallDetector;
+use crate::stateless_reset;
+use crate::stats::{
+    CongestionStats, DatagramDisposition, DropReason, Milestones, Stats, AMPLIFICATION_FACTOR,
+};
+use crate::stream_id::{StreamId, StreamIndex, StreamIndexes, StreamLimits, MAX_STREAMS};
+use crate::summary::{HandshakeSummary, SpaceSummary, TimerKind};
 use crate::tparams::{
     self, TransportParameter, TransportParameterId, TransportParameters, TransportParametersHandler,
 };
-use crate::tracking::{AckTracker, PNSpace, SentPacket};
-use crate::{AppError, ConnectionError, Error, Res, LOCAL_IDLE_TIMEOUT};
+use crate::tracking::{AckTracker, PNSpace, SentPacket, ACK_DELAY};
+use crate::{AppError, ConnectionError, Error, Res, LOCAL_IDLE_TIMEOUT, QUIC_VERSION};
 
 #[derive(Debug, Default)]
 struct Packet(Vec<u8>);
@@ -57,7 +70,29 @@ struct Packet(Vec<u8>);
 pub const LOCAL_STREAM_LIMIT_BIDI: u64 = 16;
 pub const LOCAL_STREAM_LIMIT_UNI: u64 = 16;
 
-const LOCAL_MAX_DATA: u64 = 0x3FFF_FFFF_FFFF_FFFF; // 2^62-1
+pub(crate) const LOCAL_MAX_DATA: u64 = 0x3FFF_FFFF_FFFF_FFFF; // 2^62-1
+/// The number of connection IDs from the peer that we are willing to store.
+/// This is the default value of the active_connection_id_limit transport parameter,
+/// which we don't send.  It includes the connection ID from the handshake.
+const LOCAL_ACTIVE_CID_LIMIT: usize = 2;
+/// The most connection IDs that are given to the peer, however many it can hold.
+const MAX_ISSUED_CIDS: u64 = 4;
+
+/// The number of datagram dispositions that are retained if the application
+/// doesn't collect them with `take_datagram_dispositions`.
+const MAX_DATAGRAM_DISPOSITIONS: usize = 64;
+
+/// The most streams of each type that can wait in `stream_create_pending` for the
+/// connection state or the peer's stream limit to allow them to be opened.
+const MAX_PENDING_STREAMS: usize = 16;
+
+/// The default amount of stream data that a client accepts for sending in 0-RTT,
+/// before it knows whether the server will accept 0-RTT.
+pub const DEFAULT_ZERO_RTT_SEND_LIMIT: usize = 3 * PATH_MTU_V6;
+
+/// The default time that the application has to call `authenticated` after an
+/// `AuthenticationNeeded` event.
+pub const DEFAULT_AUTHENTICATION_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Clone, Debug, PartialEq, Ord, Eq)]
 /// The state of the Connection.
@@ -248,6 +283,10 @@ impl Default for IdleTimeout {
 }
 
 impl IdleTimeout {
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
     pub fn set_peer_timeout(&mut self, peer_timeout: Duration) {
         self.timeout = min(self.timeout, peer_timeout);
     }
@@ -292,6 +331,7 @@ impl IdleTimeout {
 /// * Idle/HandshakeDone -> Closing/Draining: when closing or draining
 /// * Closing/Draining -> CloseSent: after sending CONNECTION_CLOSE
 /// * CloseSent -> Closing: any time a new CONNECTION_CLOSE is needed
+/// * Closing/CloseSent -> Draining/CloseSent: when the peer closes while we are closing
 #[derive(Debug, Clone, PartialEq)]
 enum StateSignaling {
     Idle,
@@ -379,6 +419,19 @@ impl StateSignaling {
             *self = Self::Closing(frame);
         }
     }
+
+    /// The peer closed while we were closing.  A close that is waiting is still sent,
+    /// but only once, and one that was already sent is never sent again.
+    pub fn drain_closing(&mut self) {
+        match self {
+            Self::Closing(frame) => {
+                let frame = mem::replace(frame, Frame::Padding);
+                *self = Self::Draining(frame);
+            }
+            Self::CloseSent(frame) => *frame = None,
+            _ => {}
+        }
+    }
 }
 
 /// A QUIC Connection
@@ -402,28 +455,84 @@ pub struct Connection {
     tps: Rc<RefCell<TransportParametersHandler>>,
     /// What we are doing with 0-RTT.
     zero_rtt_state: ZeroRttState,
+    /// The amount of stream data that can be sent while 0-RTT is unresolved.
+    zero_rtt_limit: usize,
+    /// The amount of stream data that has been accepted while 0-RTT is unresolved.
+    zero_rtt_buffered: usize,
+    /// Whether to send stream data again in 1-RTT if 0-RTT is rejected,
+    /// see `set_zero_rtt_requeue`.
+    zero_rtt_requeue: bool,
+    /// Whether the early exporter can be used, see `enable_early_exporter`.
+    early_exporter: bool,
+    /// The first Initial packet, which a server checked before it created this
+    /// connection, so that it isn't decrypted twice.
+    first_initial: Option<DecryptedPacket>,
     /// This object will generate connection IDs for the connection.
     cid_manager: CidMgr,
-    /// Network paths.  Right now, this tracks at most one path, so it uses `Option`.
+    /// The network path that is in use.
     path: Option<Path>,
+    /// A path that is being validated.  The connection moves to it once the peer
+    /// answers the PATH_CHALLENGE that was sent on it.
+    probe: Option<Path>,
+    /// The path that the connection last moved away from, with what loss recovery
+    /// measured on it.  `paths` reports it until the connection moves again.
+    retired_path: Option<(Path, RecoveryStats)>,
+    /// Whether the packet that is being processed arrived on `probe`.
+    rx_probe: bool,
+    /// The sequence number of the connection ID that the packet that is being
+    /// processed was sent to.
+    rx_cid_seqno: Option<u64>,
+    /// Whether the client came back with a Retry token, which validates its address.
+    token_validated: bool,
+    /// Whether so little can be sent to an address that isn't validated that
+    /// probing would be pointless.
+    amplification_blocked: bool,
     /// The connection IDs that we will accept.
     /// This includes any we advertise in NEW_CONNECTION_ID that haven't been bound to a path yet.
     /// During the handshake at the server, it also includes the randomized DCID pick by the client.
     valid_cids: Vec<ConnectionId>,
+    /// The connection IDs that we have given the peer, which it can send to.
+    local_cids: LocalConnectionIds,
+    /// Limits how fast connection IDs that the peer retires are replaced.
+    cid_issue: IssueLimiter,
+    /// The connection IDs that the peer has given us.
+    remote_cids: RemoteConnectionIds,
     retry_info: Option<RetryInfo>,
     pub(crate) crypto: Crypto,
     pub(crate) acks: AckTracker,
     idle_timeout: IdleTimeout,
+    authentication_timeout: Option<Duration>,
+    /// When the connection closes if `authenticated` hasn't been called.
+    authentication_deadline: Option<Instant>,
+    stall_detector: StallDetector,
+    /// Whether `send_ping` was called and the PING hasn't been sent.
+    ping_pending: bool,
+    /// See `set_stream_credit_divisor`.
+    stream_credit_divisor: u64,
+    /// When MAX_STREAM_DATA frames that are waiting for another packet to go in
+    /// get a packet of their own.
+    stream_credit_deadline: Option<Instant>,
+    /// The congestion state that was last reported, and when.
+    congestion_state: CongestionState,
+    congestion_state_time: Option<Instant>,
+    /// When a packet was last received successfully.
+    last_received: Option<Instant>,
     pub(crate) indexes: StreamIndexes,
-    connection_ids: HashMap<u64, (Vec<u8>, [u8; 16])>, // (sequence number, (connection id, reset token))
     pub(crate) send_streams: SendStreams,
     pub(crate) recv_streams: RecvStreams,
+    /// Streams from `stream_create_pending` that are not open or still have data.
+    pending_streams: BTreeMap<StreamId, PendingSendStream>,
     pub(crate) flow_mgr: Rc<RefCell<FlowMgr>>,
     state_signaling: StateSignaling,
     loss_recovery: LossRecovery,
     events: ConnectionEvents,
     token: Option<Vec<u8>>,
     stats: Stats,
+    milestones: Milestones,
+    /// When the first handshake datagram was sent or received.
+    handshake_start: Option<Instant>,
+    /// What happened to recently received datagrams, oldest first.
+    dispositions: VecDeque<DatagramDisposition>,
     qlog: Option<NeqoQlog>,
 }
 
@@ -446,6 +555,29 @@ impl Connection {
         local_addr: SocketAddr,
         remote_addr: SocketAddr,
     ) -> Res<Self> {
+        Self::new_client_with_params(
+            server_name,
+            protocols,
+            cid_manager,
+            local_addr,
+            remote_addr,
+            ConnectionParameters::default(),
+        )
+    }
+
+    /// Create a new QUIC connection with Client role that sends the transport
+    /// parameters in `params`.
+    /// # Errors
+    /// `InvalidParameters` if one of `params` can't be sent.
+    pub fn new_client_with_params(
+        server_name: &str,
+        protocols: &[impl AsRef<str>],
+        cid_manager: CidMgr,
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+        params: ConnectionParameters,
+    ) -> Res<Self> {
+        params.validate()?;
         let dcid = ConnectionId::generate_initial();
         let scid = cid_manager.borrow_mut().generate_cid();
         let mut c = Self::new(
@@ -454,8 +586,16 @@ impl Connection {
             cid_manager,
             None,
             protocols,
-            Some(Path::new(local_addr, remote_addr, scid, dcid.clone())),
+            Some(Path::new(
+                0,
+                local_addr,
+                remote_addr,
+                scid.clone(),
+                dcid.clone(),
+            )),
+            &params,
         );
+        c.local_cids.add(scid, None);
         c.crypto.states.init(Role::Client, &dcid);
         c.retry_info = Some(RetryInfo::new(dcid));
         Ok(c)
@@ -468,6 +608,27 @@ impl Connection {
         anti_replay: &AntiReplay,
         cid_manager: CidMgr,
     ) -> Res<Self> {
+        Self::new_server_with_params(
+            certs,
+            protocols,
+            anti_replay,
+            cid_manager,
+            ConnectionParameters::default(),
+        )
+    }
+
+    /// Create a new QUIC connection with Server role that sends the transport
+    /// parameters in `params`.
+    /// # Errors
+    /// `InvalidParameters` if one of `params` can't be sent.
+    pub fn new_server_with_params(
+        certs: &[impl AsRef<str>],
+        protocols: &[impl AsRef<str>],
+        anti_replay: &AntiReplay,
+        cid_manager: CidMgr,
+        params: ConnectionParameters,
+    ) -> Res<Self> {
+        params.validate()?;
         Ok(Self::new(
             Role::Server,
             Server::new(certs)?.into(),
@@ -475,27 +636,26 @@ impl Connection {
             Some(anti_replay),
             protocols,
             None,
+            &params,
         ))
     }
 
-    fn set_tp_defaults(tps: &mut TransportParameters) {
-        tps.set_integer(
-            tparams::INITIAL_MAX_STREAM_DATA_BIDI_LOCAL,
-            RX_STREAM_DATA_WINDOW,
-        );
-        tps.set_integer(
-            tparams::INITIAL_MAX_STREAM_DATA_BIDI_REMOTE,
-            RX_STREAM_DATA_WINDOW,
-        );
-        tps.set_integer(tparams::INITIAL_MAX_STREAM_DATA_UNI, RX_STREAM_DATA_WINDOW);
-        tps.set_integer(tparams::INITIAL_MAX_STREAMS_BIDI, LOCAL_STREAM_LIMIT_BIDI);
-        tps.set_integer(tparams::INITIAL_MAX_STREAMS_UNI, LOCAL_STREAM_LIMIT_UNI);
-        tps.set_integer(tparams::INITIAL_MAX_DATA, LOCAL_MAX_DATA);
-        tps.set_integer(
-            tparams::IDLE_TIMEOUT,
-            u64::try_from(LOCAL_IDLE_TIMEOUT.as_millis()).unwrap(),
-        );
-        tps.set_empty(tparams::DISABLE_MIGRATION);
+    /// Create a new server connection that uses the Initial keys that the server
+    /// derived when it checked the first Initial packet, and that packet as it
+    /// was decrypted then.  The datagram that it came in has to be the first input.
+    pub(crate) fn new_server_with_initial(
+        certs: &[impl AsRef<str>],
+        protocols: &[impl AsRef<str>],
+        anti_replay: &AntiReplay,
+        cid_manager: CidMgr,
+        initial: (CryptoStates, DecryptedPacket),
+        params: ConnectionParameters,
+    ) -> Res<Self> {
+        let mut c =
+            Self::new_server_with_params(certs, protocols, anti_replay, cid_manager, params)?;
+        c.crypto.states = initial.0;
+        c.first_initial = Some(initial.1);
+        Ok(c)
     }
 
     fn new(
@@ -505,34 +665,69 @@ impl Connection {
         anti_replay: Option<&AntiReplay>,
         protocols: &[impl AsRef<str>],
         path: Option<Path>,
+        params: &ConnectionParameters,
     ) -> Self {
         let tphandler = Rc::new(RefCell::new(TransportParametersHandler::default()));
-        Self::set_tp_defaults(&mut tphandler.borrow_mut().local);
+        params.set_transport_parameters(&mut tphandler.borrow_mut().local);
         let crypto = Crypto::new(agent, protocols, tphandler.clone(), anti_replay)
             .expect("TLS should be configured successfully");
+        let mut flow_mgr = FlowMgr::default();
+        flow_mgr.set_rx_window(params.get_max_data());
 
         Self {
             role,
             state: State::Init,
             cid_manager,
             path,
+            probe: None,
+            retired_path: None,
+            rx_probe: false,
+            rx_cid_seqno: None,
             valid_cids: Vec::new(),
+            local_cids: LocalConnectionIds::default(),
+            cid_issue: IssueLimiter::default(),
+            remote_cids: RemoteConnectionIds::default(),
             tps: tphandler,
             zero_rtt_state: ZeroRttState::Init,
+            zero_rtt_limit: DEFAULT_ZERO_RTT_SEND_LIMIT,
+            zero_rtt_buffered: 0,
+            zero_rtt_requeue: false,
+            early_exporter: false,
+            first_initial: None,
+            token_validated: false,
+            amplification_blocked: false,
             retry_info: None,
             crypto,
             acks: AckTracker::default(),
-            idle_timeout: IdleTimeout::default(),
-            indexes: StreamIndexes::new(),
-            connection_ids: HashMap::new(),
+            idle_timeout: IdleTimeout {
+                timeout: params.get_idle_timeout(),
+                ..IdleTimeout::default()
+            },
+            authentication_timeout: Some(DEFAULT_AUTHENTICATION_TIMEOUT),
+            authentication_deadline: None,
+            stall_detector: StallDetector::default(),
+            ping_pending: false,
+            stream_credit_divisor: STREAM_CREDIT_DIVISOR,
+            stream_credit_deadline: None,
+            congestion_state: CongestionState::NoCongestion,
+            congestion_state_time: None,
+            last_received: None,
+            indexes: StreamIndexes::new(
+                params.get_max_streams_bidi(),
+                params.get_max_streams_uni(),
+            ),
             send_streams: SendStreams::default(),
             recv_streams: RecvStreams::default(),
-            flow_mgr: Rc::new(RefCell::new(FlowMgr::default())),
+            pending_streams: BTreeMap::new(),
+            flow_mgr: Rc::new(RefCell::new(flow_mgr)),
             state_signaling: StateSignaling::Idle,
             loss_recovery: LossRecovery::new(),
             events: ConnectionEvents::default(),
             token: None,
             stats: Stats::default(),
+            milestones: Milestones::default(),
+            handshake_start: None,
+            dispositions: VecDeque::new(),
             qlog: None,
         }
     }
@@ -542,7 +737,8 @@ impl Connection {
         self.path.as_ref()
     }
 
-    /// Set or clear the qlog for this connection.
+    /// Set or clear the qlog for this connection.  Without the `qlog` feature,
+    /// nothing is logged.
     pub fn set_qlog(&mut self, qlog: Option<NeqoQlog>) {
         self.qlog = qlog;
     }
@@ -555,6 +751,9 @@ impl Connection {
     /// Set a local transport parameter, possibly overriding a default value.
     pub fn set_local_tparam(&self, tp: TransportParameterId, value: TransportParameter) -> Res<()> {
         if *self.state() == State::Init {
+            if let (tparams::INITIAL_MAX_DATA, TransportParameter::Integer(window)) = (tp, &value) {
+                self.flow_mgr.borrow_mut().set_rx_window(*window);
+            }
             self.tps.borrow_mut().local.set(tp, value);
             Ok(())
         } else {
@@ -564,9 +763,12 @@ impl Connection {
         }
     }
 
-    /// Set the connection ID that was originally chosen by the client.
+    /// Set the connection ID that was originally chosen by the client.  This is
+    /// only known if the client returned a valid Retry token, and that also means
+    /// that the client's address doesn't need to be validated.
     pub(crate) fn original_connection_id(&mut self, odcid: &ConnectionId) {
         assert_eq!(self.role, Role::Server);
+        self.token_validated = true;
         self.tps
             .borrow_mut()
             .local
@@ -590,6 +792,18 @@ impl Connection {
         Ok(())
     }
 
+    /// Set the key exchange groups, in order of preference.  A client only sends a
+    /// key share for the first, so a server that doesn't accept that group sends a
+    /// HelloRetryRequest, which costs a round trip.
+    pub fn set_groups(&mut self, groups: &[Group]) -> Res<()> {
+        if self.state != State::Init {
+            qerror!([self], "Cannot set groups in state {:?}", self.state);
+            return Err(Error::ConnectionState);
+        }
+        self.crypto.tls.set_groups(groups)?;
+        Ok(())
+    }
+
     /// Access the latest resumption token on the connection.
     pub fn resumption_token(&self) -> Option<Vec<u8>> {
         if self.state < State::Connected {
@@ -600,6 +814,7 @@ impl Connection {
                 Some(ref t) => {
                     qtrace!("TLS token {}", hex(&t));
                     let mut enc = Encoder::default();
+                    enc.encode_vvec(c.server_name().as_bytes());
                     let rtt = self.loss_recovery.rtt();
                     let rtt = u64::try_from(rtt.as_millis()).unwrap_or(0);
                     enc.encode_varint(rtt);
@@ -621,10 +836,29 @@ impl Connection {
         }
     }
 
+    /// When the latest resumption token expires.  The server picks this, so a
+    /// token shouldn't be kept beyond it.
+    pub fn resumption_token_expiration(&self) -> Option<Instant> {
+        if self.state < State::Connected {
+            return None;
+        }
+        match self.crypto.tls {
+            Agent::Client(ref c) => c
+                .resumption_token()
+                .and_then(|t| ResumptionTokenInfo::new(t).ok())
+                .and_then(|info| info.expiration_time()),
+            Agent::Server(_) => None,
+        }
+    }
+
     /// Enable resumption, using a token previously provided.
     /// This can only be called once and only on the client.
     /// After calling the function, it should be possible to attempt 0-RTT
     /// if the token supports that.
+    /// A token for a different server name, or for an ALPN that this client
+    /// doesn't offer, is rejected with `InvalidResumptionToken`, which leaves the
+    /// connection as it was.  If the ticket in the token has expired, the
+    /// connection starts with a full handshake instead.
     pub fn set_resumption_token(&mut self, now: Instant, token: &[u8]) -> Res<()> {
         if self.state != State::Init {
             qerror!([self], "set token in state {:?}", self.state);
@@ -633,6 +867,10 @@ impl Connection {
         qinfo!([self], "resumption token {}", hex(token));
         let mut dec = Decoder::from(token);
 
+        let server_name = match dec.decode_vvec() {
+            Some(v) => v,
+            _ => return Err(Error::InvalidResumptionToken),
+        };
         let smoothed_rtt = match dec.decode_varint() {
             Some(v) => Duration::from_millis(v),
             _ => return Err(Error::InvalidResumptionToken),
@@ -648,10 +886,33 @@ impl Connection {
 
         let tok = dec.decode_remainder();
         qtrace!([self], "  TLS token {}", hex(&tok));
+        let info = ResumptionTokenInfo::new(tok).or(Err(Error::InvalidResumptionToken))?;
         match self.crypto.tls {
-            Agent::Client(ref mut c) => c.set_resumption_token(&tok)?,
+            Agent::Client(ref c) => {
+                if server_name != c.server_name().as_bytes() {
+                    qwarn!([self], "Resumption token is for a different server");
+                    return Err(Error::InvalidResumptionToken);
+                }
+                if let Some(alpn) = info.alpn() {
+                    if !c.alpn_protocols().contains(alpn) {
+                        qwarn!(
+                            [self],
+                            "Resumption token is for ALPN {}, which isn't offered",
+                            alpn
+                        );
+                        return Err(Error::InvalidResumptionToken);
+                    }
+                }
+            }
             Agent::Server(_) => return Err(Error::WrongRole),
         }
+        if info.expired(now) {
+            qinfo!([self], "Resumption token has expired; not resuming");
+            return self.client_start(now);
+        }
+        if let Agent::Client(ref mut c) = self.crypto.tls {
+            c.set_resumption_token(&tok)?;
+        }
 
         self.tps.borrow_mut().remote_0rtt = Some(tp);
 
@@ -664,6 +925,154 @@ impl Connection {
         self.client_start(now)
     }
 
+    /// Get information about the current path, which can be used to seed a
+    /// later connection to the same peer using `set_path_hints`.
+    pub fn path_info(&self) -> Option<PathInfo> {
+        self.path.as_ref().map(|p| PathInfo {
+            rtt: self.loss_recovery.rtt(),
+            mtu: p.mtu(),
+            cwnd: self.loss_recovery.cwnd(),
+        })
+    }
+
+    /// Statistics for each path: the one the connection is on, then the one it
+    /// last moved away from, if any.  That one is kept, with what loss recovery
+    /// measured on it when the connection moved, until the connection moves again.
+    /// A path that is being validated isn't included until the connection moves to it.
+    pub fn paths(&self) -> impl Iterator<Item = PathStats> + '_ {
+        let current = self
+            .path
+            .iter()
+            .map(move |p| Self::path_stats(p, true, self.path_recovery(p)));
+        let retired = self
+            .retired_path
+            .iter()
+            .map(|(p, recovery)| Self::path_stats(p, false, *recovery));
+        current.chain(retired)
+    }
+
+    fn path_stats(p: &Path, current: bool, recovery: RecoveryStats) -> PathStats {
+        PathStats {
+            id: p.id(),
+            local: *p.local_address(),
+            remote: *p.remote_address(),
+            mtu: p.mtu(),
+            bytes_sent: p.bytes_sent(),
+            bytes_received: p.bytes_received(),
+            current,
+            recovery,
+        }
+    }
+
+    /// What loss recovery has measured since the connection moved to `path`.
+    fn path_recovery(&self, path: &Path) -> RecoveryStats {
+        let (lost, pto_count) = path.recovery_counts(
+            self.loss_recovery.packets_lost(),
+            self.loss_recovery.pto_count(),
+        );
+        RecoveryStats {
+            rtt: self.loss_recovery.rtt(),
+            min_rtt: self.loss_recovery.min_rtt().unwrap_or_default(),
+            cwnd: self.loss_recovery.cwnd(),
+            lost,
+            pto_count,
+        }
+    }
+
+    /// Seed a new connection with information from a previous connection to the
+    /// same peer.  This uses the round trip time as the initial estimate, and the
+    /// MTU as the path MTU, within the limits of `Path::set_mtu_hint`.
+    /// If `jump_start` is set, the congestion window starts at a value derived
+    /// from the previous window rather than the default.
+    /// This needs to be called before the connection starts.
+    pub fn set_path_hints(&mut self, hints: &PathInfo, jump_start: bool) -> Res<()> {
+        if self.state != State::Init {
+            qerror!([self], "set path hints in state {:?}", self.state);
+            return Err(Error::ConnectionState);
+        }
+        qinfo!([self], "path hints {:?} jump_start={}", hints, jump_start);
+        if hints.rtt > GRANULARITY {
+            self.loss_recovery.set_initial_rtt(hints.rtt);
+        }
+        if let Some(path) = &mut self.path {
+            path.set_mtu_hint(hints.mtu);
+        }
+        if jump_start {
+            self.loss_recovery.jump_start(hints.cwnd);
+        }
+        Ok(())
+    }
+
+    /// Limit the rate at which this connection sends to `rate` bytes per second,
+    /// regardless of the congestion window.  This is intended for testing how
+    /// applications behave on slow paths.  Probes are not limited, so the rate
+    /// can be exceeded briefly when the probe timer fires.
+    /// If `include_handshake` is false, the limit applies once the handshake completes.
+    /// This needs to be called before the connection starts.
+    pub fn set_max_send_rate(&mut self, rate: u64, include_handshake: bool) -> Res<()> {
+        if self.state != State::Init {
+            qerror!([self], "set max send rate in state {:?}", self.state);
+            return Err(Error::ConnectionState);
+        }
+        if rate == 0 {
+            return Err(Error::InvalidInput);
+        }
+        qinfo!(
+            [self],
+            "max send rate {} include_handshake={}",
+            rate,
+            include_handshake
+        );
+        let rate = usize::try_from(rate).unwrap_or(usize::max_value());
+        self.loss_recovery
+            .set_max_send_rate(rate, include_handshake);
+        Ok(())
+    }
+
+    /// Make the handshake larger by `len` bytes, using a transport parameter that
+    /// peers ignore.  For a client, this makes a ClientHello that needs several
+    /// Initial packets, like one with post-quantum key shares; for a server, it
+    /// makes the server's first flight larger.  The TLS stack decides how large
+    /// the rest of the handshake is, so this can only be used to reach a target size
+    /// by picking `len` accordingly.  This needs to be called before the connection starts.
+    pub fn set_handshake_padding(&self, len: usize) -> Res<()> {
+        self.set_local_tparam(
+            tparams::QUANTUM_READINESS,
+            TransportParameter::Bytes(vec![0; len]),
+        )
+    }
+
+    /// Choose whether the congestion window is reduced when the connection has
+    /// been idle: if nothing was sent for longer than the probe timeout, the window
+    /// is halved for each probe timeout that passed, down to the initial window.
+    /// This stops a window from before the idle period being used all at once.
+    /// This is on by default.  Restarts are counted in `Stats::idle_restarts`.
+    pub fn set_idle_restart(&mut self, idle_restart: bool) {
+        self.loss_recovery.set_idle_restart(idle_restart);
+    }
+
+    /// Let the pacer release packets up to `granularity` early.  By default, packets
+    /// are paced as finely as the pacer can manage, which means that `process_output`
+    /// asks to be called again after intervals that can be very short; a caller that
+    /// can't wake up that often can set this to send what is due in small bursts
+    /// instead.  Handshake packets and ACK frames are never paced.
+    pub fn set_pacing_granularity(&mut self, granularity: Duration) {
+        self.loss_recovery.set_pacing_granularity(granularity);
+    }
+
+    /// Send more credit for a stream once the application has read 1/`divisor`
+    /// of its receive window, rather than a quarter.  This applies to streams
+    /// that are opened after it is called.
+    /// # Errors
+    /// `InvalidInput` if `divisor` is zero.
+    pub fn set_stream_credit_divisor(&mut self, divisor: u64) -> Res<()> {
+        if divisor == 0 {
+            return Err(Error::InvalidInput);
+        }
+        self.stream_credit_divisor = divisor;
+        Ok(())
+    }
+
     /// Send a TLS session ticket.
     pub fn send_ticket(&mut self, now: Instant, extra: &[u8]) -> Res<()> {
         let tps = &self.tps;
@@ -692,8 +1101,115 @@ impl Connection {
         self.crypto.tls.peer_certificate()
     }
 
+    /// The name of the server that a client connects to, which is what the
+    /// server certificate has to be for.
+    pub fn server_name(&self) -> Option<&str> {
+        match self.crypto.tls {
+            Agent::Client(ref c) => Some(c.server_name()),
+            Agent::Server(_) => None,
+        }
+    }
+
+    /// Export keying material, as in RFC 5705.  Values are only available once the
+    /// handshake is confirmed, so that both peers are certain to have them.
+    /// # Errors
+    /// `NotConnected` before the handshake is confirmed, or the reason that the
+    /// crypto stack couldn't produce the value.
+    pub fn export_keying_material(
+        &self,
+        label: &str,
+        context: Option<&[u8]>,
+        len: usize,
+    ) -> Res<Vec<u8>> {
+        if self.state != State::Confirmed {
+            return Err(Error::NotConnected);
+        }
+        Ok(self
+            .crypto
+            .tls
+            .export_keying_material(label, context, len)?)
+    }
+
+    /// Allow `export_early_keying_material` to be used.  Values from the early
+    /// exporter can be replayed by an attacker, just like 0-RTT data, so an
+    /// application needs to decide that this is acceptable.
+    pub fn enable_early_exporter(&mut self) {
+        self.early_exporter = true;
+    }
+
+    /// Export keying material using the early exporter, which is available when
+    /// 0-RTT is attempted.  This has to be enabled with `enable_early_exporter`.
+    /// # Errors
+    /// `ConnectionState` if the early exporter isn't enabled or there was no
+    /// attempt at 0-RTT, or the reason that the crypto stack couldn't produce the value.
+    pub fn export_early_keying_material(
+        &self,
+        label: &str,
+        context: &[u8],
+        len: usize,
+    ) -> Res<Vec<u8>> {
+        let attempted = matches!(
+            self.zero_rtt_state,
+            ZeroRttState::Sending | ZeroRttState::AcceptedClient | ZeroRttState::AcceptedServer
+        );
+        if !self.early_exporter || !attempted {
+            return Err(Error::ConnectionState);
+        }
+        Ok(self
+            .crypto
+            .tls
+            .export_early_keying_material(label, context, len)?)
+    }
+
+    /// Set how long the application has to call `authenticated` after an
+    /// `AuthenticationNeeded` event, or `None` to wait as long as it takes.
+    /// When this time passes, the connection is closed with
+    /// `Error::AuthenticationTimeout`.  Otherwise, an application that never
+    /// calls `authenticated` would leave the connection open for as long as
+    /// the peer keeps sending.  The default is `DEFAULT_AUTHENTICATION_TIMEOUT`.
+    pub fn set_authentication_timeout(&mut self, timeout: Option<Duration>) {
+        self.authentication_timeout = timeout;
+    }
+
+    /// Set the idle timeout that is advertised to the peer.  The connection uses
+    /// the smaller of this and the value the peer advertises.  This can only be
+    /// set before the connection starts, and it can't be zero.  The default is
+    /// 30 seconds.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) -> Res<()> {
+        if self.state != State::Init {
+            qerror!([self], "Cannot set idle timeout in state {:?}", self.state);
+            return Err(Error::ConnectionState);
+        }
+        let ms = u64::try_from(timeout.as_millis()).or(Err(Error::InvalidInput))?;
+        if ms == 0 {
+            return Err(Error::InvalidInput);
+        }
+        self.tps
+            .borrow_mut()
+            .local
+            .set_integer(tparams::IDLE_TIMEOUT, ms);
+        self.idle_timeout.timeout = Duration::from_millis(ms);
+        Ok(())
+    }
+
+    /// The idle timeout in effect.  Until the peer's transport parameters
+    /// arrive, this is the local value.  A connection that receives nothing for
+    /// this long (or three times the PTO, if that is longer) is closed with
+    /// `Error::IdleTimeout`.
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout.timeout()
+    }
+
+    /// Set how long sending can be blocked on one flow control limit from the peer
+    /// before a `ConnectionEvent::FlowControlStalled` is raised, or `None` to never
+    /// raise it.  The default is `DEFAULT_FLOW_CONTROL_STALL_TIMEOUT`.
+    pub fn set_flow_control_stall_timeout(&mut self, timeout: Option<Duration>) {
+        self.stall_detector.set_timeout(timeout);
+    }
+
     /// Call by application when the peer cert has been verified
     pub fn authenticated(&mut self, status: AuthenticationStatus, now: Instant) {
+        self.authentication_deadline = None;
         self.crypto.tls.authenticated(status);
         let res = self.handshake(now, PNSpace::Handshake, None);
         self.absorb_error(now, res);
@@ -714,11 +1230,339 @@ impl Connection {
         &self.zero_rtt_state
     }
 
+    /// Send a PING, which the peer has to acknowledge.  Because the acknowledgment
+    /// counts as activity, calling this more often than the idle timeout keeps an
+    /// otherwise idle connection open.  A PING that is lost isn't sent again.
+    pub fn send_ping(&mut self) -> Res<()> {
+        if !self.state.connected() {
+            return Err(Error::ConnectionState);
+        }
+        self.ping_pending = true;
+        Ok(())
+    }
+
+    /// Move the connection to a new local address, as when the network that a
+    /// client is on changes.  A PATH_CHALLENGE is sent from `local` straight away,
+    /// and the connection moves once the server answers it.  Until then, everything
+    /// else is sent on the current path.  Congestion control and the RTT estimate
+    /// start again on the move, because nothing is known about the new path.
+    /// If the server doesn't answer, the connection stays where it is.
+    ///
+    /// Only a client can do this, once the handshake is confirmed, and not if
+    /// the server sent the `disable_active_migration` transport parameter.
+    pub fn migrate(&mut self, local: SocketAddr) -> Res<()> {
+        if self.role != Role::Client {
+            return Err(Error::WrongRole);
+        }
+        if self.state != State::Confirmed {
+            return Err(Error::ConnectionState);
+        }
+        let disabled = self.tps.borrow().remote.as_ref().map_or(false, |tps| {
+            tps.get_empty(tparams::DISABLE_MIGRATION).is_some()
+        });
+        if disabled {
+            return Err(Error::InvalidMigration);
+        }
+        let path = self.path.as_ref().ok_or(Error::NotConnected)?;
+        if *path.local_address() == local {
+            // Stay where we are.
+            self.abandon_probe();
+            return Ok(());
+        }
+        let mut probe = path.probe_from_local(self.next_path_id(), local);
+        // Packets on the new path have to use a connection ID that hasn't been
+        // used on the old one, so that they can't be linked.  Only a server that
+        // uses zero-length connection IDs can be moved to without one.
+        if !path.remote_cid().is_empty() {
+            let cid = self
+                .remote_cids
+                .next_unused(&[&path.remote_cid()[..]])
+                .ok_or(Error::InvalidMigration)?;
+            probe.set_remote_cid(&cid.as_ref());
+        }
+        probe.challenge();
+        qinfo!([self], "Validating path from {}", local);
+        self.abandon_probe();
+        self.probe = Some(probe);
+        Ok(())
+    }
+
+    /// Set whether the peer can move the connection to a new address.  By default,
+    /// this isn't allowed, and the `disable_active_migration` transport parameter
+    /// is sent.  This can only be changed before the connection starts.
+    pub fn set_allow_migration(&mut self, allow: bool) -> Res<()> {
+        if *self.state() != State::Init {
+            return Err(Error::ConnectionState);
+        }
+        let mut tps = self.tps.borrow_mut();
+        if allow {
+            tps.local.remove(tparams::DISABLE_MIGRATION);
+        } else {
+            tps.local.set_empty(tparams::DISABLE_MIGRATION);
+        }
+        Ok(())
+    }
+
+    fn next_path_id(&self) -> u64 {
+        self.path
+            .iter()
+            .chain(self.probe.iter())
+            .chain(self.retired_path.iter().map(|(p, _)| p))
+            .map(Path::id)
+            .max()
+            .map_or(0, |id| id + 1)
+    }
+
+    /// Whether the peer can move to the new path that `packet` came on.  Only a
+    /// client moves, and only once the handshake is confirmed.
+    fn can_probe(&self, packet: &PublicPacket) -> bool {
+        self.role == Role::Server
+            && self.state == State::Confirmed
+            && packet.packet_type() == PacketType::Short
+            && self
+                .tps
+                .borrow()
+                .local
+                .get_empty(tparams::DISABLE_MIGRATION)
+                .is_none()
+    }
+
+    /// The peer answered the challenge on the path that was being validated, so
+    /// move the connection there.
+    fn move_to_probe(&mut self) -> Res<()> {
+        let mut probe = self.probe.take().expect("should have a path to move to");
+        probe.set_valid();
+        if let Some(old) = self.path.as_ref() {
+            if old.remote_cid() != probe.remote_cid() {
+                self.remote_cids.retire(old.remote_cid());
+            }
+        }
+        qinfo!(
+            [self],
+            "Path validated, moving to {} -> {}",
+            probe.local_address(),
+            probe.remote_address()
+        );
+        probe.set_recovery_base(
+            self.loss_recovery.packets_lost(),
+            self.loss_recovery.pto_count(),
+        );
+        if let Some(old) = self.path.take() {
+            let recovery = self.path_recovery(&old);
+            self.retired_path = Some((old, recovery));
+        }
+        self.path = Some(probe);
+        self.loss_recovery.migrate();
+        #[cfg(feature = "qlog")]
+        {
+            if let Some((old, recovery)) = &self.retired_path {
+                qlog::path_metrics(&mut self.qlog, old.id(), recovery)?;
+            }
+            qlog::path_moved(&mut self.qlog, self.path.as_ref().unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Stop validating a path.  A connection ID that was only used on that path
+    /// isn't used again.
+    fn abandon_probe(&mut self) {
+        if let Some(probe) = self.probe.take() {
+            if self
+                .path
+                .as_ref()
+                .map_or(true, |p| p.remote_cid() != probe.remote_cid())
+            {
+                self.remote_cids.retire(probe.remote_cid());
+            }
+        }
+    }
+
+    /// An estimate of the rate at which this connection can send, in bytes per
+    /// second: the congestion window divided by the round trip time.  Like
+    /// `ConnectionEvent::CongestionStateChanged`, this is only a rough guide.
+    pub fn current_bandwidth_estimate(&self) -> u64 {
+        let rtt = max(self.loss_recovery.rtt(), GRANULARITY);
+        let rate = self.loss_recovery.cwnd() as u128 * 1_000_000 / rtt.as_micros();
+        u64::try_from(rate).unwrap_or(u64::MAX)
+    }
+
+    /// When a change in the congestion state that hasn't been reported yet can be,
+    /// which is a round trip after the last report.
+    fn congestion_state_deadline(&self) -> Option<Instant> {
+        if self.loss_recovery.congestion_state() == self.congestion_state {
+            return None;
+        }
+        self.congestion_state_time
+            .map(|t| t + max(self.loss_recovery.rtt(), GRANULARITY))
+    }
+
+    /// Report a change in the congestion state, but no more than once per round trip.
+    /// A change during that time is reported afterwards, if it persists; the
+    /// `CongestionState` timer makes sure that happens.
+    fn check_congestion_state(&mut self, now: Instant) {
+        let state = self.loss_recovery.congestion_state();
+        if state == self.congestion_state {
+            return;
+        }
+        if self.congestion_state_deadline().map_or(false, |t| now < t) {
+            return;
+        }
+        qinfo!([self], "Congestion state {:?}", state);
+        self.congestion_state = state;
+        self.congestion_state_time = Some(now);
+        self.events.congestion_state_changed(state);
+    }
+
+    /// Look for sending that has been blocked on the same flow control limit for
+    /// too long.  The connection limit only counts while a stream has data that it
+    /// can't send, which is true when a stream is blocked on it.
+    fn check_flow_control_stalls(&mut self, now: Instant) -> Res<()> {
+        let flow_mgr = &self.flow_mgr;
+        let send_streams = &self.send_streams;
+        let stalls = self.stall_detector.check(now, |id, limit| {
+            let current = if let Some(id) = id {
+                send_streams
+                    .get(id)
+                    .ok()
+                    .and_then(SendStream::flow_control_blocked)
+            } else {
+                flow_mgr.borrow().conn_blocked()
+            };
+            current == Some(limit)
+        });
+        for stall in stalls {
+            qwarn!([self], "Flow control stalled: {}", stall);
+            #[cfg(feature = "qlog")]
+            qlog::flow_control_stalled(&mut self.qlog, &stall)?;
+            self.stats.flow_control_stalls += 1;
+            self.events.flow_control_stalled(stall);
+        }
+        Ok(())
+    }
+
     /// Get collected statistics.
     pub fn stats(&self) -> &Stats {
         &self.stats
     }
 
+    /// Record datagrams for this connection that the socket dropped because its
+    /// receive buffer was full.  Only the application can see these, by asking the
+    /// operating system, and they are easily mistaken for loss on the network.
+    /// This is for sockets that only this connection uses: the drops on a socket
+    /// that several connections share can't be attributed to any one of them.
+    /// # Errors
+    /// Only if writing qlog fails.
+    pub fn record_local_drops(&mut self, count: usize) -> Res<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        self.stats.local_drops_rx += count;
+        qwarn!(
+            [self],
+            "Socket dropped {} datagrams, {} in total",
+            count,
+            self.stats.local_drops_rx
+        );
+        #[cfg(feature = "qlog")]
+        qlog::local_drops(&mut self.qlog, count, self.stats.local_drops_rx)?;
+        Ok(())
+    }
+
+    /// Get the current state of the congestion controller.
+    pub fn congestion_stats(&self) -> CongestionStats {
+        self.loss_recovery.congestion_stats()
+    }
+
+    /// Get the times at which the connection reached each milestone so far.
+    pub fn milestones(&self) -> Milestones {
+        self.milestones
+    }
+
+    /// Record a milestone the first time it is reached.
+    fn reach_milestone(
+        &mut self,
+        milestone: fn(&mut Milestones) -> &mut Option<Instant>,
+        name: &str,
+        now: Instant,
+    ) -> Res<()> {
+        if Milestones::reach(milestone(&mut self.milestones), now) {
+            qinfo!([self], "Reached milestone {}", name);
+            #[cfg(feature = "qlog")]
+            qlog::milestone(&mut self.qlog, name)?;
+        }
+        Ok(())
+    }
+
+    /// Take the record of what happened to each datagram received since the last call.
+    /// Only the most recent datagrams are retained.
+    pub fn take_datagram_dispositions(&mut self) -> Vec<DatagramDisposition> {
+        self.dispositions.drain(..).collect()
+    }
+
+    /// Get a snapshot of the progress of the handshake: keys, crypto data, and
+    /// what the connection is waiting for.  This is cheap enough to call often.
+    pub fn handshake_state_summary(&mut self, now: Instant) -> HandshakeSummary {
+        let summarize = |c: &Self, space| {
+            let (sent, send_buffered, received, recv_buffered) =
+                c.crypto.streams.progress(space)?;
+            let (read_keys, write_keys) = c.crypto.states.has_keys(space);
+            Some(SpaceSummary {
+                read_keys,
+                write_keys,
+                crypto_sent: sent,
+                crypto_received: received,
+                crypto_send_buffered: send_buffered,
+                crypto_recv_buffered: recv_buffered,
+                unacked_packets: c.loss_recovery.unacked_packets(space).unwrap_or(0),
+            })
+        };
+        let next_timer = match self.state {
+            State::Closing { timeout, .. } | State::Draining { timeout, .. } => {
+                Some((timeout, TimerKind::Closing))
+            }
+            _ => self.timers(now).into_iter().min_by_key(|&(t, _)| t),
+        };
+        HandshakeSummary {
+            state: self.state.clone(),
+            initial: summarize(self, PNSpace::Initial),
+            handshake: summarize(self, PNSpace::Handshake),
+            application: summarize(self, PNSpace::ApplicationData),
+            congestion_blocked: self.loss_recovery.cwnd_avail() == 0,
+            flow_control_blocked: self.flow_mgr.borrow().conn_credit_avail() == 0,
+            since_last_received: self.last_received.map(|t| now.saturating_duration_since(t)),
+            next_timer: next_timer.map(|(t, kind)| (kind, t.saturating_duration_since(now))),
+        }
+    }
+
+    fn packet_processed(&mut self) {
+        if let Some(d) = self.dispositions.back_mut() {
+            d.processed += 1;
+        }
+    }
+
+    fn packet_dropped(&mut self, reason: DropReason) {
+        self.stats.packet_dropped(reason);
+        self.record_drop(reason);
+    }
+
+    /// A packet that arrived while closing, which is answered with CONNECTION_CLOSE
+    /// rather than processed.  This was never counted in `dropped_rx`, so only the
+    /// reason is recorded.
+    fn packet_ignored_while_closing(&mut self) {
+        *self
+            .stats
+            .dropped_by_reason
+            .entry(DropReason::Closed)
+            .or_insert(0) += 1;
+        self.record_drop(DropReason::Closed);
+    }
+
+    fn record_drop(&mut self, reason: DropReason) {
+        if let Some(d) = self.dispositions.back_mut() {
+            d.dropped.push(reason);
+        }
+    }
+
     // This function wraps a call to another function and sets the connection state
     // properly if that call fails.
     fn capture_error<T>(&mut self, now: Instant, frame_type: FrameType, res: Res<T>) -> Res<T> {
@@ -782,10 +1626,29 @@ impl Connection {
             return;
         }
 
-        let res = self.crypto.states.check_key_update(now);
-        self.absorb_error(now, res);
+        if self.authentication_deadline.map_or(false, |t| t <= now) {
+            qinfo!([self], "authentication timeout expired");
+            self.authentication_deadline = None;
+            self.absorb_error::<()>(now, Err(Error::AuthenticationTimeout));
+            return;
+        }
+
+        let res = self.crypto.states.check_key_update(now);
+        self.absorb_error(now, res);
+
+        if let Some(probe) = self
+            .probe
+            .as_mut()
+            .filter(|p| p.challenge_timeout().map_or(false, |t| t <= now))
+        {
+            if !probe.challenge_expired() {
+                qinfo!("Path validation failed for {}", probe.local_address());
+                self.abandon_probe();
+            }
+        }
 
         let lost = self.loss_recovery.timeout(now);
+        self.update_recovery_stats();
         self.handle_lost_packets(&lost);
     }
 
@@ -806,36 +1669,81 @@ impl Connection {
         frames
     }
 
-    /// Get the time that we next need to be called back, relative to `now`.
-    fn next_delay(&mut self, now: Instant, paced: bool) -> Duration {
-        qtrace!([self], "Get callback delay {:?}", now);
-
-        // Only one timer matters when closing...
-        if let State::Closing { timeout, .. } | State::Draining { timeout, .. } = self.state {
-            return timeout.duration_since(now);
-        }
-
-        let mut delays = SmallVec::<[_; 5]>::new();
+    /// All the timers that are running, other than the pacer.
+    fn timers(&mut self, now: Instant) -> SmallVec<[(Instant, TimerKind); 10]> {
+        let mut timers = SmallVec::new();
         if let Some(ack_time) = self.acks.ack_time(now) {
             qtrace!([self], "Delayed ACK timer {:?}", ack_time);
-            delays.push(ack_time);
+            timers.push((ack_time, TimerKind::Ack));
         }
 
         if let Some(idle_time) = self.idle_timeout.expiry(self.loss_recovery.raw_pto()) {
             qtrace!([self], "Idle timer {:?}", idle_time);
-            delays.push(idle_time);
+            timers.push((idle_time, TimerKind::Idle));
         }
 
-        if let Some(lr_time) = self.loss_recovery.next_timeout() {
-            qtrace!([self], "Loss recovery timer {:?}", lr_time);
-            delays.push(lr_time);
+        // Nothing can be sent until the client sends more, so don't probe.
+        if !self.amplification_blocked {
+            if let Some(lr_time) = self.loss_recovery.next_timeout() {
+                qtrace!([self], "Loss recovery timer {:?}", lr_time);
+                timers.push((lr_time, TimerKind::LossRecovery));
+            }
         }
 
         if let Some(key_update_time) = self.crypto.states.update_time() {
             qtrace!([self], "Key update timer {:?}", key_update_time);
-            delays.push(key_update_time);
+            timers.push((key_update_time, TimerKind::KeyUpdate));
+        }
+
+        if let Some(auth_time) = self.authentication_deadline {
+            qtrace!([self], "Authentication timer {:?}", auth_time);
+            timers.push((auth_time, TimerKind::Authentication));
+        }
+
+        if let Some(stall_time) = self.stall_detector.next_timeout() {
+            qtrace!([self], "Flow control stall timer {:?}", stall_time);
+            timers.push((stall_time, TimerKind::FlowControlStall));
+        }
+
+        if let Some(challenge_time) = self.probe.as_ref().and_then(Path::challenge_timeout) {
+            qtrace!([self], "Path validation timer {:?}", challenge_time);
+            timers.push((challenge_time, TimerKind::PathValidation));
+        }
+
+        if let Some(credit_time) = self.stream_credit_deadline {
+            qtrace!([self], "Stream credit timer {:?}", credit_time);
+            timers.push((credit_time, TimerKind::StreamCredit));
+        }
+
+        if self.state.connected() {
+            if let Some(congestion_time) = self.congestion_state_deadline() {
+                qtrace!([self], "Congestion state timer {:?}", congestion_time);
+                timers.push((congestion_time, TimerKind::CongestionState));
+            }
+            if self.local_cids.len() < self.local_cid_limit() {
+                if let Some(cid_time) = self.cid_issue.next_token() {
+                    qtrace!([self], "Connection ID timer {:?}", cid_time);
+                    timers.push((cid_time, TimerKind::ConnectionIds));
+                }
+            }
+        }
+        timers
+    }
+
+    /// Get the time that we next need to be called back, relative to `now`.
+    fn next_delay(&mut self, now: Instant, paced: bool) -> Duration {
+        qtrace!([self], "Get callback delay {:?}", now);
+
+        // Only one timer matters when closing...
+        if let State::Closing { timeout, .. } | State::Draining { timeout, .. } = self.state {
+            return timeout.saturating_duration_since(now);
         }
 
+        let mut delays = self
+            .timers(now)
+            .into_iter()
+            .map(|(t, _)| t)
+            .collect::<SmallVec<[_; 11]>>();
         if paced {
             if let Some(pace_time) = self.loss_recovery.next_paced() {
                 qtrace!([self], "Pacing timer {:?}", pace_time);
@@ -847,15 +1755,11 @@ impl Connection {
         assert!(!delays.is_empty());
         let earliest = delays.into_iter().min().unwrap();
 
-        // TODO(agrover, mt) - need to analyze and fix #47
-        // rather than just clamping to zero here.
-        qdebug!(
-            [self],
-            "delay duration {:?}",
-            max(now, earliest).duration_since(now)
-        );
-        debug_assert!(earliest > now);
-        max(now, earliest).duration_since(now)
+        // A timer can be in the past if the caller passes the same time twice
+        // or a time that is older than one it used before, so this saturates.
+        let delay = earliest.saturating_duration_since(now);
+        qdebug!([self], "delay duration {:?}", delay);
+        delay
     }
 
     /// Get output packets, as a result of receiving packets, or actions taken
@@ -864,7 +1768,31 @@ impl Connection {
     /// even if no incoming packets.
     pub fn process_output(&mut self, now: Instant) -> Output {
         qtrace!([self], "process_output {:?} {:?}", self.state, now);
+        self.prepare_output(now);
+        self.next_output(now)
+    }
+
+    /// Get up to `max` datagrams to send together, as `process_output` would
+    /// return them one at a time, so that they can be given to the socket in one
+    /// call.  The batch ends where `process_output` would stop returning datagrams,
+    /// because of the congestion window or pacing, and the `Output` that stopped it
+    /// is returned with the batch.  If the batch is full, that is a `Callback` of
+    /// zero, as there could be more to send.
+    pub fn process_output_batch(&mut self, now: Instant, max: usize) -> (Vec<Datagram>, Output) {
+        qtrace!([self], "process_output_batch {:?} {:?}", self.state, now);
+        self.prepare_output(now);
+        let mut dgrams = Vec::new();
+        while dgrams.len() < max {
+            match self.next_output(now) {
+                Output::Datagram(dgram) => dgrams.push(dgram),
+                out => return (dgrams, out),
+            }
+        }
+        (dgrams, Output::Callback(Duration::new(0, 0)))
+    }
 
+    /// Start the handshake or run timers ahead of sending.
+    fn prepare_output(&mut self, now: Instant) {
         if self.state == State::Init {
             if self.role == Role::Client {
                 let res = self.client_start(now);
@@ -873,13 +1801,30 @@ impl Connection {
         } else {
             self.process_timer(now);
         }
+        if self.state.connected() {
+            self.check_congestion_state(now);
+            self.replace_cids(now);
+        }
+    }
 
-        match self.output(now) {
+    fn next_output(&mut self, now: Instant) -> Output {
+        let output = self.output(now);
+        if let SendOption::Yes(dgram) = &output {
+            self.stats.bytes_tx += dgram.len();
+            let res = self.reach_milestone(|m| &mut m.first_sent, "first_sent", now);
+            self.absorb_error(now, res);
+        }
+        // Sending can leave streams blocked, so this comes after.
+        if self.state.connected() {
+            let res = self.check_flow_control_stalls(now);
+            self.absorb_error(now, res);
+        }
+        match output {
             SendOption::Yes(dgram) => Output::Datagram(dgram),
             SendOption::No(paced) => match self.state {
                 State::Closed(_) => Output::None,
                 State::Closing { timeout, .. } | State::Draining { timeout, .. } => {
-                    Output::Callback(timeout.duration_since(now))
+                    Output::Callback(timeout.saturating_duration_since(now))
                 }
                 _ => Output::Callback(self.next_delay(now, paced)),
             },
@@ -895,7 +1840,68 @@ impl Connection {
     }
 
     fn is_valid_cid(&self, cid: &ConnectionIdRef) -> bool {
-        self.valid_cids.iter().any(|c| c == cid) || self.path.iter().any(|p| p.valid_local_cid(cid))
+        self.valid_cids.iter().any(|c| c == cid) || self.local_cids.contains(cid)
+    }
+
+    /// The connection IDs that the peer can send to, including the one from the
+    /// handshake unless the peer has retired it.  The `ConnectionIdManager` for the
+    /// connection is told as these are made and retired, so a server can use that
+    /// to route packets instead.
+    pub fn local_cids(&self) -> impl Iterator<Item = &ConnectionId> {
+        self.local_cids.iter()
+    }
+
+    /// The connection ID that the peer uses is fixed when the handshake starts.
+    /// For a client, that happens when the first Initial from the server is processed;
+    /// until then (in `WaitInitial`), any source connection ID is acceptable.
+    /// For a server, the client's first Initial sets the value.
+    /// After that, long header packets with any other source connection ID are
+    /// dropped, so that they can't change the connection ID we use again.
+    fn is_valid_remote_cid(&self, packet: &PublicPacket) -> bool {
+        if packet.packet_type() == PacketType::Short {
+            return true;
+        }
+        self.path
+            .as_ref()
+            .map_or(false, |p| p.remote_cid() == packet.scid())
+    }
+
+    fn handle_version_negotiation(&mut self, packet: &PublicPacket) -> Res<()> {
+        qdebug!([self], "received Version Negotiation");
+        let retry_info = self.retry_info.as_ref().unwrap();
+        if !retry_info.token.is_empty() {
+            // The server has already shown that it supports this version.
+            qinfo!([self], "Dropping Version Negotiation after Retry");
+            self.packet_dropped(DropReason::UnexpectedPacket);
+            return Ok(());
+        }
+        let path = self.path.as_ref().ok_or(Error::InternalError)?;
+        if packet.dcid()[..] != path.local_cid()[..] || packet.scid()[..] != retry_info.odcid[..] {
+            qinfo!([self], "Dropping Version Negotiation with wrong CIDs");
+            self.packet_dropped(DropReason::UnknownConnectionId);
+            return Ok(());
+        }
+        let versions = if let Ok(versions) = packet.supported_versions() {
+            versions
+        } else {
+            qinfo!([self], "Dropping Version Negotiation with bad versions");
+            self.packet_dropped(DropReason::MalformedHeader);
+            return Ok(());
+        };
+        if versions.contains(&QUIC_VERSION) {
+            // A server that supports this version would have used it, so this
+            // is either an attempt to downgrade or a mistake.
+            qinfo!([self], "Dropping Version Negotiation with our version");
+            self.packet_dropped(DropReason::UnexpectedPacket);
+            return Ok(());
+        }
+
+        // There is only one version to choose from, so it isn't possible to try again.
+        qinfo!([self], "No version in common with {:x?}", versions);
+        self.set_state(State::Closed(ConnectionError::Transport(
+            Error::VersionNegotiation,
+        )));
+        Err(Error::VersionNegotiation)
     }
 
     fn handle_retry(&mut self, packet: PublicPacket) -> Res<()> {
@@ -903,17 +1909,23 @@ impl Connection {
         debug_assert!(self.retry_info.is_some());
         if !self.retry_info.as_ref().unwrap().token.is_empty() {
             qinfo!([self], "Dropping extra Retry");
-            self.stats.dropped_rx += 1;
+            self.packet_dropped(DropReason::UnexpectedPacket);
             return Ok(());
         }
         if packet.token().is_empty() {
             qinfo!([self], "Dropping Retry without a token");
-            self.stats.dropped_rx += 1;
+            self.packet_dropped(DropReason::MalformedHeader);
+            return Ok(());
+        }
+        if self.retry_info.as_ref().unwrap().odcid == *packet.scid() {
+            // A server has to choose a new connection ID when it sends Retry.
+            qinfo!([self], "Dropping Retry that uses the original CID");
+            self.packet_dropped(DropReason::UnexpectedPacket);
             return Ok(());
         }
         if !packet.is_valid_retry(&self.retry_info.as_ref().unwrap().odcid) {
             qinfo!([self], "Dropping Retry with bad integrity tag");
-            self.stats.dropped_rx += 1;
+            self.packet_dropped(DropReason::DecryptionFailure);
             return Ok(());
         }
         if let Some(p) = &mut self.path {
@@ -951,35 +1963,56 @@ impl Connection {
         let mut frames = Vec::new();
 
         qtrace!([self], "input {}", hex(&**d));
+        self.stats.bytes_rx += d.len();
+        // The datagram counts toward the handshake flights once a packet in it is processed.
+        let mut counted = false;
+        if let Some(p) = self.path.as_mut().filter(|p| p.received_on(&d)) {
+            p.received(d.len());
+        } else if let Some(p) = self.probe.as_mut().filter(|p| p.received_on(&d)) {
+            p.received(d.len());
+        }
+        if self.dispositions.len() >= MAX_DATAGRAM_DISPOSITIONS {
+            self.dispositions.pop_front();
+        }
+        self.dispositions.push_back(DatagramDisposition::default());
 
         // Handle each packet in the datagram
         while !slc.is_empty() {
-            let (packet, remainder) =
-                match PublicPacket::decode(slc, self.cid_manager.borrow().as_decoder()) {
-                    Ok((packet, remainder)) => (packet, remainder),
-                    Err(e) => {
-                        qdebug!([self], "Garbage packet: {} {}", e, hex(slc));
-                        self.stats.dropped_rx += 1;
-                        return Ok(frames);
-                    }
-                }; // TODO(mt) use in place of res, and allow errors
+            let res = PublicPacket::decode(slc, self.cid_manager.borrow().as_decoder());
+            let (packet, remainder) = match res {
+                Ok((packet, remainder)) => (packet, remainder),
+                Err(e) => {
+                    qdebug!([self], "Garbage packet: {} {}", e, hex(slc));
+                    self.packet_dropped(DropReason::MalformedHeader);
+                    return Ok(frames);
+                }
+            }; // TODO(mt) use in place of res, and allow errors
             self.stats.packets_rx += 1;
             match (packet.packet_type(), &self.state, &self.role) {
                 (PacketType::Initial, State::Init, Role::Server) => {
                     if !packet.is_valid_initial() {
-                        self.stats.dropped_rx += 1;
+                        self.packet_dropped(DropReason::MalformedHeader);
+                        return Ok(frames);
+                    }
+                    if d.len() < MIN_INITIAL_PACKET_SIZE {
+                        qinfo!([self], "Initial datagram too small to answer");
+                        self.packet_dropped(DropReason::AmplificationLimit);
                         return Ok(frames);
                     }
                     qinfo!([self], "Received valid Initial packet");
                     self.set_state(State::WaitInitial);
+                    // Install a path now, so that the connection ID for it can go in
+                    // the transport parameters along with its stateless reset token.
+                    self.initialize_path(&packet, &d);
                     self.loss_recovery.start_pacer(now);
-                    self.crypto.states.init(self.role, &packet.dcid());
+                    // The server might have already created keys for us.
+                    if self.crypto.states.rx_hp(PNSpace::Initial).is_none() {
+                        self.crypto.states.init(self.role, &packet.dcid());
+                    }
                 }
                 (PacketType::VersionNegotiation, State::WaitInitial, Role::Client) => {
-                    self.set_state(State::Closed(ConnectionError::Transport(
-                        Error::VersionNegotiation,
-                    )));
-                    return Err(Error::VersionNegotiation);
+                    self.handle_version_negotiation(&packet)?;
+                    return Ok(frames);
                 }
                 (PacketType::Retry, State::WaitInitial, Role::Client) => {
                     self.handle_retry(packet)?;
@@ -989,7 +2022,7 @@ impl Connection {
                 | (PacketType::Retry, ..)
                 | (PacketType::OtherVersion, ..) => {
                     qwarn!("dropping {:?}", packet.packet_type());
-                    self.stats.dropped_rx += 1;
+                    self.packet_dropped(DropReason::UnexpectedPacket);
                     return Ok(frames);
                 }
                 _ => {}
@@ -998,14 +2031,23 @@ impl Connection {
             match self.state {
                 State::Init => {
                     qinfo!([self], "Received message while in Init state");
-                    self.stats.dropped_rx += 1;
+                    self.packet_dropped(DropReason::UnexpectedPacket);
                     return Ok(frames);
                 }
                 State::WaitInitial => {}
                 State::Handshaking | State::Connected | State::Confirmed => {
                     if !self.is_valid_cid(packet.dcid()) {
+                        if self.is_stateless_reset(&packet, &d) {
+                            self.stateless_reset();
+                            return Ok(frames);
+                        }
                         qinfo!([self], "Ignoring packet with CID {:?}", packet.dcid());
-                        self.stats.dropped_rx += 1;
+                        self.packet_dropped(DropReason::UnknownConnectionId);
+                        return Ok(frames);
+                    }
+                    if !self.is_valid_remote_cid(&packet) {
+                        qinfo!([self], "Ignoring packet with SCID {:?}", packet.scid());
+                        self.packet_dropped(DropReason::SourceConnectionIdChanged);
                         return Ok(frames);
                     }
                     if self.role == Role::Server && packet.packet_type() == PacketType::Handshake {
@@ -1014,29 +2056,72 @@ impl Connection {
                     }
                 }
                 State::Closing { .. } => {
-                    // Don't bother processing the packet. Instead ask to get a
-                    // new close frame.
-                    self.state_signaling.send_close();
-                    return Ok(frames);
+                    // The packet is only checked for CONNECTION_CLOSE, below.
                 }
                 State::Draining { .. } | State::Closed(..) => {
                     // Do nothing.
-                    self.stats.dropped_rx += 1;
+                    self.packet_dropped(DropReason::Closed);
                     return Ok(frames);
                 }
             }
 
+            let new_path = self.path.as_ref().map_or(false, |p| !p.received_on(&d));
+            let on_probe = new_path && self.probe.as_ref().map_or(false, |p| p.received_on(&d));
+            if new_path && !on_probe && !self.can_probe(&packet) {
+                // Nothing that arrives on a path that the peer can't move to is
+                // acted on: not a PATH_CHALLENGE, which would be answered on the
+                // existing path, and not a PATH_RESPONSE.  Dropping the packet
+                // before it is decrypted also means that it isn't recorded as
+                // received, so the same packet sent on the right path still counts.
+                qinfo!([self], "Ignoring packet from {}", d.source());
+                self.packet_dropped(DropReason::UnknownPath);
+                return Ok(frames);
+            }
+
             qtrace!([self], "Received unverified packet {:?}", packet);
 
             let pto = self.loss_recovery.pto();
-            let payload = packet.decrypt(&mut self.crypto.states, now + pto);
+            let have_keys = self
+                .crypto
+                .states
+                .rx_hp(PNSpace::from(packet.packet_type()))
+                .is_some();
+            let first = self.first_initial.take();
+            let payload = match first {
+                Some(first) if packet.packet_type() == PacketType::Initial => Ok(first),
+                _ => packet.decrypt(&mut self.crypto.states, now + pto),
+            };
+            let wrong_key_phase = matches!(payload, Err(Error::WrongKeyPhase));
+            let pn_overlap = matches!(payload, Err(Error::PacketNumberOverlap));
             slc = remainder;
+            if let State::Closing { .. } = self.state {
+                match payload {
+                    Ok(payload) => self.process_packet_while_closing(&payload),
+                    Err(_) => {
+                        self.state_signaling.send_close();
+                        self.packet_ignored_while_closing();
+                    }
+                }
+                continue;
+            }
             if let Ok(payload) = payload {
                 // TODO(ekr@rtfm.com): Have the server blow away the initial
                 // crypto state if this fails? Otherwise, we will get a panic
                 // on the assert for doesn't exist.
                 // OK, we have a valid packet.
+                if self.role == Role::Server && payload.packet_type() == PacketType::Handshake {
+                    // Only a client that got our Handshake keys can send this.
+                    if let Some(p) = self.path.as_mut() {
+                        p.set_valid();
+                    }
+                }
                 self.idle_timeout.on_packet_received(now);
+                self.last_received = Some(now);
+                if !counted && !new_path && !self.is_duplicate(&payload) {
+                    // This is before processing, which might complete the handshake.
+                    self.count_handshake_datagram(d.len(), false, now);
+                    counted = true;
+                }
                 dump_packet(
                     self,
                     "-> RX",
@@ -1044,8 +2129,17 @@ impl Connection {
                     payload.pn(),
                     &payload[..],
                 );
+                #[cfg(feature = "qlog")]
                 qlog::packet_received(&mut self.qlog, &payload)?;
+                self.reach_milestone(|m| &mut m.first_received, "first_received", now)?;
+                if new_path && !on_probe {
+                    self.start_probe(&payload, &d);
+                }
+                self.rx_probe = new_path;
+                self.rx_cid_seqno = self.local_cids.seqno(&packet.dcid()[..]);
                 let res = self.process_packet(&payload, now);
+                self.rx_probe = false;
+                self.rx_cid_seqno = None;
                 if res.is_err() && self.path.is_none() {
                     self.initialize_path(&packet, &d);
                 }
@@ -1053,12 +2147,22 @@ impl Connection {
                 if self.state == State::WaitInitial {
                     self.start_handshake(&packet, &d)?;
                 }
-                self.process_migrations(&d)?;
+            } else if self.is_stateless_reset(&packet, &d) {
+                self.stateless_reset();
+                return Ok(frames);
             } else {
                 // Decryption failure, or not having keys is not fatal.
                 // If the state isn't available, or we can't decrypt the packet, drop
                 // the rest of the datagram on the floor, but don't generate an error.
-                self.stats.dropped_rx += 1;
+                self.packet_dropped(if wrong_key_phase {
+                    DropReason::KeyPhase
+                } else if pn_overlap {
+                    DropReason::Duplicate
+                } else if have_keys {
+                    DropReason::DecryptionFailure
+                } else {
+                    DropReason::NoKeys
+                });
             }
         }
         Ok(frames)
@@ -1075,9 +2179,9 @@ impl Connection {
         // OK, we have a valid packet.
 
         let space = PNSpace::from(packet.packet_type());
-        if self.acks.get_mut(space).unwrap().is_duplicate(packet.pn()) {
+        if self.is_duplicate(packet) {
             qdebug!([self], "Duplicate packet from {} pn={}", space, packet.pn());
-            self.stats.dups_rx += 1;
+            self.packet_dropped(DropReason::Duplicate);
             return Ok(vec![]);
         }
 
@@ -1115,32 +2219,146 @@ impl Connection {
             .get_mut(space)
             .unwrap()
             .set_received(now, packet.pn(), ack_eliciting);
+        self.packet_processed();
 
         Ok(frames)
     }
 
+    fn is_duplicate(&mut self, packet: &DecryptedPacket) -> bool {
+        let space = PNSpace::from(packet.packet_type());
+        self.acks
+            .get_mut(space)
+            .map_or(false, |recvd| recvd.is_duplicate(packet.pn()))
+    }
+
+    /// While closing, nothing in a packet matters except a CONNECTION_CLOSE from the
+    /// peer, which means that it is closing too.  That moves to draining, so the two
+    /// endpoints don't keep answering each other's CONNECTION_CLOSE.  Any other packet
+    /// gets our CONNECTION_CLOSE again.
+    fn process_packet_while_closing(&mut self, packet: &DecryptedPacket) {
+        let mut d = Decoder::from(&packet[..]);
+        while d.remaining() > 0 {
+            match Frame::decode(&mut d) {
+                Ok(Frame::ConnectionClose {
+                    error_code,
+                    reason_phrase,
+                    ..
+                }) => {
+                    qinfo!(
+                        [self],
+                        "ConnectionClose received while closing. Error code: {:?} reason {}",
+                        error_code,
+                        printable_reason_phrase(&reason_phrase)
+                    );
+                    if let State::Closing { timeout, .. } = self.state {
+                        let (error, _) = Self::peer_close_error(error_code);
+                        self.state_signaling.drain_closing();
+                        self.set_state(State::Draining { error, timeout });
+                    }
+                    self.packet_processed();
+                    return;
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        self.state_signaling.send_close();
+        self.packet_ignored_while_closing();
+    }
+
+    /// The error for a CONNECTION_CLOSE from the peer, and the frame type for ours.
+    fn peer_close_error(error_code: CloseError) -> (ConnectionError, FrameType) {
+        let (detail, frame_type) = if let CloseError::Application(_) = error_code {
+            // Use a transport error here because we want to send
+            // NO_ERROR in this case.
+            (
+                Error::PeerApplicationError(error_code.code()),
+                FRAME_TYPE_CONNECTION_CLOSE_APPLICATION,
+            )
+        } else {
+            (
+                Error::PeerError(error_code.code()),
+                FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT,
+            )
+        };
+        (ConnectionError::Transport(detail), frame_type)
+    }
+
+    /// The peer has sent a packet from a new address.  Validate the path to that
+    /// address, unless the packet is a copy of one that was already received,
+    /// which anyone could have sent from anywhere.
+    fn start_probe(&mut self, packet: &DecryptedPacket, d: &Datagram) {
+        if self.is_duplicate(packet) {
+            return;
+        }
+        let id = self.next_path_id();
+        if let Some(path) = self.path.as_ref() {
+            let mut probe = path.probe_from_datagram(id, d);
+            if let Some(cid) = self.remote_cids.next_unused(&[&path.remote_cid()[..]]) {
+                probe.set_remote_cid(&cid.as_ref());
+            }
+            probe.challenge();
+            qinfo!([self], "Validating path to {}", d.source());
+            self.abandon_probe();
+            self.probe = Some(probe);
+        }
+    }
+
     fn initialize_path(&mut self, packet: &PublicPacket, d: &Datagram) {
         debug_assert!(self.path.is_none());
-        let mut p = Path::from_datagram(&d, ConnectionId::from(packet.scid()));
-        p.add_local_cid(self.cid_manager.borrow_mut().generate_cid());
+        let mut p = Path::from_datagram(0, &d, ConnectionId::from(packet.scid()));
+        let cid = self.cid_manager.borrow_mut().generate_cid();
+        let token = self.cid_manager.borrow().stateless_reset_token(&cid);
+        if let Some(token) = token {
+            self.tps
+                .borrow_mut()
+                .local
+                .set_bytes(tparams::STATELESS_RESET_TOKEN, token.to_vec());
+        }
+        self.local_cids.add(cid.clone(), token);
+        p.add_local_cid(cid);
+        if self.token_validated {
+            p.set_valid();
+        }
         self.path = Some(p);
     }
 
+    /// Whether `packet`, which couldn't be processed, is a stateless reset from the peer.
+    /// A stateless reset looks like a short header packet, so it can't be followed by
+    /// another packet and it ends with the token.  These are ignored until the
+    /// handshake is complete.
+    fn is_stateless_reset(&self, packet: &PublicPacket, d: &Datagram) -> bool {
+        if packet.packet_type() != PacketType::Short || !self.state.connected() {
+            return false;
+        }
+        let tps = self.tps.borrow();
+        let token = tps
+            .remote
+            .as_ref()
+            .and_then(|r| r.get_bytes(tparams::STATELESS_RESET_TOKEN));
+        token
+            .iter()
+            .map(|t| &t[..])
+            .chain(self.remote_cids.reset_tokens().map(|t| &t[..]))
+            .any(|t| stateless_reset::is_stateless_reset(&d[..], t))
+    }
+
+    fn stateless_reset(&mut self) {
+        qinfo!([self], "Stateless reset from the peer");
+        self.packet_processed();
+        self.set_state(State::Closed(ConnectionError::Transport(
+            Error::StatelessReset,
+        )));
+    }
+
     fn start_handshake(&mut self, packet: &PublicPacket, d: &Datagram) -> Res<()> {
         if self.role == Role::Server {
             assert_eq!(packet.packet_type(), PacketType::Initial);
             // A server needs to accept the client's selected CID during the handshake.
+            // The path was installed when the first Initial arrived.
             self.valid_cids.push(ConnectionId::from(packet.dcid()));
-            // Install a path.
-            self.initialize_path(packet, d);
-
-            self.zero_rtt_state = match self.crypto.enable_0rtt(self.role) {
-                Ok(true) => {
-                    qdebug!([self], "Accepted 0-RTT");
-                    ZeroRttState::AcceptedServer
-                }
-                _ => ZeroRttState::Rejected,
-            };
+            // Whether 0-RTT is accepted isn't known until all of the ClientHello
+            // arrives, which might take more than this packet.
         } else {
             qdebug!([self], "Changing to use Server CID={}", packet.scid());
             let p = self
@@ -1154,18 +2372,18 @@ impl Connection {
         Ok(())
     }
 
-    fn process_migrations(&self, d: &Datagram) -> Res<()> {
-        if self.path.iter().any(|p| p.received_on(&d)) {
-            Ok(())
-        } else {
-            // Right now, we don't support any form of migration.
-            // So generate an error if a packet is received on a new path.
-            Err(Error::InvalidMigration)
-        }
-    }
-
     fn output(&mut self, now: Instant) -> SendOption {
         qtrace!([self], "output {:?}", now);
+        self.open_pending_streams();
+        if self.state == State::Confirmed {
+            if let Some(mut probe) = self.probe.take() {
+                let res = self.output_probe(&mut probe, now);
+                self.probe = Some(probe);
+                if let Some(SendOption::Yes(dgram)) = self.absorb_error(now, res) {
+                    return SendOption::Yes(dgram);
+                }
+            }
+        }
         if let Some(mut path) = self.path.take() {
             let res = match &self.state {
                 State::Init
@@ -1175,7 +2393,7 @@ impl Connection {
                 | State::Confirmed => self.output_path(&mut path, now),
                 State::Closing { .. } | State::Draining { .. } | State::Closed(_) => {
                     if let Some(frame) = self.state_signaling.close_frame() {
-                        self.output_close(&path, &frame)
+                        self.output_close(&mut path, &frame)
                     } else {
                         Ok(SendOption::default())
                     }
@@ -1233,8 +2451,12 @@ impl Connection {
         (pt, pn, builder)
     }
 
-    fn output_close(&mut self, path: &Path, frame: &Frame) -> Res<SendOption> {
+    /// Build a datagram containing a CONNECTION_CLOSE for every packet number space
+    /// that we have keys for, because we can't know which keys the peer has.
+    fn output_close(&mut self, path: &mut Path, frame: &Frame) -> Res<SendOption> {
         let mut encoder = Encoder::with_capacity(path.mtu());
+        let mut needs_padding = false;
+        let sent_bytes = self.stats.sent_bytes;
         for space in PNSpace::iter() {
             let tx = if let Some(tx_state) = self.crypto.states.tx(*space) {
                 tx_state
@@ -1243,22 +2465,94 @@ impl Connection {
             };
 
             // ConnectionClose frame not allowed for 0RTT.
+            // A client that only has 0-RTT keys for this space relies on the Initial.
             if tx.is_0rtt() {
                 continue;
             }
 
+            let header_start = encoder.len();
             let (_, _, mut builder) = Self::build_packet_header(path, *space, encoder, tx, &None);
+            let payload_start = builder.len();
             // ConnectionError::Application is only allowed at 1RTT.
-            if *space == PNSpace::ApplicationData {
-                frame.marshal(&mut builder);
+            let close = if *space == PNSpace::ApplicationData {
+                frame
+            } else {
+                frame.sanitize_close()
+            };
+            // Each packet gets a CONNECTION_CLOSE, which might not fit all of the reason.
+            let space_left = path.mtu().saturating_sub(builder.len() + tx.expansion());
+            if let Some(close) = close.close_that_fits(space_left) {
+                close.marshal(&mut builder);
             } else {
-                frame.sanitize_close().marshal(&mut builder);
+                encoder = builder.abort();
+                continue;
             }
+            let payload = builder.len() - payload_start;
 
             encoder = builder.build(tx)?;
+            self.stats.sent_bytes.packet(
+                payload_start - header_start,
+                payload,
+                encoder.len() - header_start,
+                sent_bytes.stream(),
+            );
+            // A server drops Initial packets from a client in small datagrams.
+            needs_padding |= self.role == Role::Client && *space == PNSpace::Initial;
         }
 
-        Ok(SendOption::Yes(path.datagram(encoder)))
+        let mut packets: Vec<u8> = encoder.into();
+        if needs_padding && packets.len() < path.mtu() {
+            self.stats.sent_bytes.padding += (path.mtu() - packets.len()) as u64;
+            packets.resize(path.mtu(), 0);
+        }
+        if packets.len() > path.amplification_limit() {
+            qdebug!([self], "CONNECTION_CLOSE blocked by amplification limit");
+            self.stats.sent_bytes = sent_bytes;
+            return Ok(SendOption::default());
+        }
+        Ok(SendOption::Yes(path.datagram(packets)))
+    }
+
+    /// Build a datagram for a path that is being validated.  This only carries
+    /// PATH_CHALLENGE and PATH_RESPONSE; everything else waits until the
+    /// connection moves to the path.
+    fn output_probe(&mut self, probe: &mut Path, now: Instant) -> Res<SendOption> {
+        if !probe.has_probe_frames() {
+            return Ok(SendOption::default());
+        }
+        let space = PNSpace::ApplicationData;
+        let tx = if let Some(tx_state) = self.crypto.states.tx(space) {
+            tx_state
+        } else {
+            return Ok(SendOption::default());
+        };
+        let encoder = Encoder::with_capacity(probe.mtu());
+        let (pt, pn, mut builder) = Self::build_packet_header(probe, space, encoder, tx, &None);
+        let payload_start = builder.len();
+        let limit = min(probe.mtu(), probe.amplification_limit());
+        if builder.len() + 2 * PATH_PROBE_FRAME_SIZE + tx.expansion() > limit {
+            qdebug!([self], "output_probe blocked by amplification limit");
+            return Ok(SendOption::default());
+        }
+        probe.write_probe_frames(&mut builder, now + self.loss_recovery.pto());
+
+        dump_packet(self, "TX ->", pt, pn, &builder[payload_start..]);
+        #[cfg(feature = "qlog")]
+        qlog::packet_sent(&mut self.qlog, pt, pn, &builder[payload_start..])?;
+
+        self.stats.packets_tx += 1;
+        let payload = builder.len() - payload_start;
+        let encoder = builder.build(self.crypto.states.tx(space).unwrap())?;
+        let stream = self.stats.sent_bytes.stream();
+        self.stats
+            .sent_bytes
+            .packet(payload_start, payload, encoder.len(), stream);
+        // The packet is acknowledged like any other, but the congestion controller
+        // is for the current path, so it isn't counted as in flight.
+        let sent = SentPacket::new(now, true, Vec::new(), encoder.len(), false);
+        self.loss_recovery.on_packet_sent(space, pn, sent);
+        let packets: Vec<u8> = encoder.into();
+        Ok(SendOption::Yes(probe.datagram(packets)))
     }
 
     /// Add frames to the provided builder and
@@ -1280,12 +2574,22 @@ impl Connection {
             builder.encode_varint(Frame::Ping.get_type());
             ack_eliciting = true;
         }
+        if self.ping_pending && space == PNSpace::ApplicationData && !profile.ack_only(space) {
+            if !ack_eliciting {
+                builder.encode_varint(Frame::Ping.get_type());
+                ack_eliciting = true;
+            }
+            self.ping_pending = false;
+        }
 
         // All useful frames are at least 2 bytes.
         while builder.len() + 2 < limit {
             let remaining = limit - builder.len();
             // Try to get a frame from frame sources
-            let mut frame = self.acks.get_frame(now, space);
+            let mut frame = self.acks.get_frame(now, space, remaining);
+            if frame.is_some() {
+                self.stats.acks_tx += 1;
+            }
             // If we are CC limited we can only send acks!
             if !profile.ack_only(space) {
                 if frame.is_none() && space == PNSpace::ApplicationData && self.role == Role::Server
@@ -1296,14 +2600,34 @@ impl Connection {
                     frame = self.crypto.streams.get_frame(space, remaining)
                 }
                 if frame.is_none() {
-                    frame = self.flow_mgr.borrow_mut().get_frame(space, remaining);
+                    // Stream credit goes in packets that are being sent anyway,
+                    // unless it can't wait.
+                    let stream_credit = !builder.is_empty()
+                        || self.stream_credit_deadline.map_or(false, |t| t <= now);
+                    frame = self
+                        .flow_mgr
+                        .borrow_mut()
+                        .get_frame(space, remaining, stream_credit);
                 }
                 if frame.is_none() {
-                    frame = self.send_streams.get_frame(space, remaining);
+                    frame = self
+                        .send_streams
+                        .get_frame(space, remaining, &mut self.stats);
+                }
+                // Connection IDs aren't worth a packet of their own, so they only
+                // go in packets that are being sent anyway.
+                if frame.is_none() && ack_eliciting {
+                    frame = self.local_cids.get_frame(space, remaining);
+                }
+                if frame.is_none() && ack_eliciting {
+                    frame = self.remote_cids.get_frame(space, remaining);
                 }
             }
 
             if let Some((frame, token)) = frame {
+                if let Frame::MaxStreamData { .. } = frame {
+                    self.stats.max_stream_data_tx += 1;
+                }
                 ack_eliciting |= frame.ack_eliciting();
                 debug_assert_ne!(frame, Frame::Padding);
                 frame.marshal(builder);
@@ -1319,17 +2643,46 @@ impl Connection {
 
     /// Build a datagram, possibly from multiple packets (for different PN
     /// spaces) and each containing 1+ frames.
+    /// Stream credit that isn't urgent waits up to `ACK_DELAY` for another packet.
+    fn update_stream_credit_deadline(&mut self, now: Instant) {
+        match self.flow_mgr.borrow().stream_credit_pending() {
+            None => self.stream_credit_deadline = None,
+            Some(true) => self.stream_credit_deadline = Some(now),
+            Some(false) => {
+                self.stream_credit_deadline.get_or_insert(now + ACK_DELAY);
+            }
+        }
+    }
+
     fn output_path(&mut self, path: &mut Path, now: Instant) -> Res<SendOption> {
         let mut initial_sent = None;
         let mut needs_padding = false;
+        self.update_stream_credit_deadline(now);
+
+        // After an idle period, the window is reduced before it decides how much
+        // can be sent.
+        if self.loss_recovery.restart_after_idle(now) {
+            self.stats.idle_restarts += 1;
+        }
 
         // Determine how we are sending packets (PTO, etc..).
         let profile = self.loss_recovery.send_profile(now, path.mtu());
         qdebug!([self], "output_path send_profile {:?}", profile);
+        // Until the client's address is validated, the server can only send a
+        // multiple of what it received.
+        let amplification_limit = path.amplification_limit();
+        let limit = min(profile.limit(), amplification_limit);
+        self.amplification_blocked = amplification_limit < ACK_ONLY_SIZE_LIMIT;
+        if self.amplification_blocked {
+            qdebug!([self], "output_path blocked by amplification limit");
+            if !self.state.connected() {
+                self.stats.handshake.amplification_blocked = true;
+            }
+        }
 
         // Frames for different epochs must go in different packets, but then these
         // packets can go in a single datagram
-        let mut encoder = Encoder::with_capacity(profile.limit());
+        let mut encoder = Encoder::with_capacity(limit);
         for space in PNSpace::iter() {
             // Ensure we have tx crypto state for this epoch, or skip it.
             let tx = if let Some(tx_state) = self.crypto.states.tx(*space) {
@@ -1344,33 +2697,46 @@ impl Connection {
             let payload_start = builder.len();
 
             // Work out if we have space left.
-            if builder.len() + tx.expansion() > profile.limit() {
+            if builder.len() + tx.expansion() > limit {
                 // No space for a packet of this type.
                 encoder = builder.abort();
                 continue;
             }
 
             // Add frames to the packet.
-            let limit = profile.limit() - tx.expansion();
+            let stream_sent = self.stats.sent_bytes.stream();
             let (tokens, ack_eliciting) =
-                self.add_frames(&mut builder, *space, limit, &profile, now);
+                self.add_frames(&mut builder, *space, limit - tx.expansion(), &profile, now);
             if builder.is_empty() {
                 // Nothing to include in this packet.
                 encoder = builder.abort();
                 continue;
             }
+            let payload = builder.len() - payload_start;
 
             dump_packet(self, "TX ->", pt, pn, &builder[payload_start..]);
+            #[cfg(feature = "qlog")]
             qlog::packet_sent(&mut self.qlog, pt, pn, &builder[payload_start..])?;
 
             self.stats.packets_tx += 1;
             encoder = builder.build(self.crypto.states.tx(*space).unwrap())?;
             debug_assert!(encoder.len() <= path.mtu());
+            self.stats.sent_bytes.packet(
+                payload_start - header_start,
+                payload,
+                encoder.len() - header_start,
+                stream_sent,
+            );
+            if self.stats.sent_bytes.stream() > stream_sent {
+                self.reach_milestone(|m| &mut m.first_stream_data, "first_stream_data", now)?;
+            }
 
             // Normal packets are in flight if they include PADDING frames,
-            // but we don't send those.
+            // but we don't send those.  A padded client Initial is marked as
+            // in flight when the padding is added below.
             let in_flight = !profile.pto() && ack_eliciting;
-            if in_flight {
+            if ack_eliciting {
+                // Probes restart the idle timer too.
                 self.idle_timeout.on_packet_sent(now);
             }
             let sent = SentPacket::new(
@@ -1402,6 +2768,7 @@ impl Connection {
                 }
             }
         }
+        self.update_stream_credit_deadline(now);
 
         if encoder.is_empty() {
             Ok(SendOption::No(profile.paced()))
@@ -1410,13 +2777,16 @@ impl Connection {
             let mut packets: Vec<u8> = encoder.into();
             if let Some((initial_pn, mut initial)) = initial_sent.take() {
                 if needs_padding {
+                    // A client path is always valid, so this isn't limited.
                     qdebug!([self], "pad Initial to path MTU {}", path.mtu());
-                    initial.size += path.mtu() - packets.len();
+                    self.stats.sent_bytes.padding += (path.mtu() - packets.len()) as u64;
+                    initial.add_padding(path.mtu() - packets.len(), !profile.pto());
                     packets.resize(path.mtu(), 0);
                 }
                 self.loss_recovery
                     .on_packet_sent(PNSpace::Initial, initial_pn, initial);
             }
+            self.count_handshake_datagram(packets.len(), true, now);
             Ok(SendOption::Yes(path.datagram(packets)))
         }
     }
@@ -1441,6 +2811,7 @@ impl Connection {
     fn client_start(&mut self, now: Instant) -> Res<()> {
         qinfo!([self], "client_start");
         debug_assert_eq!(self.role, Role::Client);
+        #[cfg(feature = "qlog")]
         qlog::client_connection_started(&mut self.qlog, self.path.as_ref().unwrap())?;
         self.loss_recovery.start_pacer(now);
 
@@ -1463,6 +2834,18 @@ impl Connection {
     /// Close the connection.
     pub fn close(&mut self, now: Instant, app_error: AppError, msg: impl AsRef<str>) {
         let error = ConnectionError::Application(app_error);
+        if self.state == State::Init {
+            // Nothing has been sent, so there is nobody to tell.
+            qinfo!([self], "Closed before starting");
+            self.set_state(State::Closed(error));
+            return;
+        }
+        if self.state.closed() {
+            // A new CONNECTION_CLOSE would be sent while draining, or would restart
+            // the closing period.
+            qinfo!([self], "Close while already closing");
+            return;
+        }
         let timeout = self.get_closing_period_time(now);
         self.state_signaling.close(error.clone(), 0, msg);
         self.set_state(State::Closing { error, timeout });
@@ -1484,6 +2867,28 @@ impl Connection {
             self.idle_timeout
                 .set_peer_timeout(Duration::from_millis(peer_timeout));
         }
+
+        self.loss_recovery
+            .set_peer_max_ack_delay(Duration::from_millis(
+                remote.get_integer(tparams::MAX_ACK_DELAY),
+            ));
+    }
+
+    /// The ACK delay in a frame from the peer, which is in units of
+    /// 2^`ack_delay_exponent` microseconds.  Delays in Initial and Handshake
+    /// packets aren't used, so those can be read before the peer's transport
+    /// parameters arrive.
+    fn decode_ack_delay(&self, space: PNSpace, ack_delay: u64) -> Duration {
+        let exponent = if space == PNSpace::ApplicationData {
+            self.tps
+                .borrow()
+                .remote()
+                .get_integer(tparams::ACK_DELAY_EXPONENT)
+        } else {
+            3
+        };
+        // The exponent is no more than 20.
+        Duration::from_micros(ack_delay.saturating_mul(1 << exponent))
     }
 
     fn validate_odcid(&mut self) -> Res<()> {
@@ -1516,9 +2921,16 @@ impl Connection {
         let try_update = data.is_some();
         match self.crypto.handshake(now, space, data)? {
             HandshakeState::Authenticated(_) | HandshakeState::InProgress => (),
-            HandshakeState::AuthenticationPending => self.events.authentication_needed(),
+            HandshakeState::AuthenticationPending => {
+                self.check_remote_tparams()?;
+                if self.authentication_deadline.is_none() {
+                    self.authentication_deadline = self.authentication_timeout.map(|t| now + t);
+                }
+                self.events.authentication_needed();
+            }
             HandshakeState::Complete(_) => {
                 if !self.state.connected() {
+                    self.check_remote_tparams()?;
                     self.set_connected(now)?;
                 }
             }
@@ -1526,17 +2938,38 @@ impl Connection {
                 unreachable!("Crypto state should not be new or failed after successful handshake")
             }
         }
+        self.set_max_packet_size();
 
         // There is a chance that this could be called less often, but getting the
         // conditions right is a little tricky, so call it on every  CRYPTO frame.
         if try_update {
             self.crypto.install_keys(self.role);
+            if self.crypto.states.has_keys(PNSpace::Handshake).1 {
+                self.reach_milestone(|m| &mut m.handshake_keys, "handshake_keys", now)?;
+            }
+            if self.role == Role::Server && self.crypto.states.has_keys(PNSpace::Handshake).1 {
+                self.check_remote_tparams()?;
+            }
+            // A server has handshake keys once it has all of the ClientHello.
+            if self.role == Role::Server
+                && self.zero_rtt_state == ZeroRttState::Init
+                && self.crypto.states.has_keys(PNSpace::Handshake).1
+            {
+                self.zero_rtt_state = match self.crypto.enable_0rtt(self.role) {
+                    Ok(true) => {
+                        qdebug!([self], "Accepted 0-RTT");
+                        ZeroRttState::AcceptedServer
+                    }
+                    _ => ZeroRttState::Rejected,
+                };
+            }
         }
 
         Ok(())
     }
 
     fn handle_max_data(&mut self, maximum_data: u64) {
+        self.stall_detector.limit_raised(None, maximum_data);
         let conn_was_blocked = self.flow_mgr.borrow().conn_credit_avail() == 0;
         let conn_credit_increased = self
             .flow_mgr
@@ -1584,11 +3017,10 @@ impl Connection {
             Frame::ResetStream {
                 stream_id,
                 application_error_code,
-                ..
+                final_size,
             } => {
-                // TODO(agrover@mozilla.com): use final_size for connection MaxData calc
                 if let (_, Some(rs)) = self.obtain_stream(stream_id)? {
-                    rs.reset(application_error_code);
+                    rs.reset(application_error_code, final_size)?;
                 }
             }
             Frame::StopSending {
@@ -1598,7 +3030,7 @@ impl Connection {
                 self.events
                     .send_stream_stop_sending(stream_id, application_error_code);
                 if let (Some(ss), _) = self.obtain_stream(stream_id)? {
-                    ss.reset(application_error_code);
+                    ss.stop_sending(application_error_code);
                 }
             }
             Frame::Crypto { offset, data } => {
@@ -1638,6 +3070,8 @@ impl Connection {
                 if let (Some(ss), _) = self.obtain_stream(stream_id)? {
                     ss.set_max_stream_data(maximum_stream_data);
                 }
+                self.stall_detector
+                    .limit_raised(Some(stream_id), maximum_stream_data);
             }
             Frame::MaxStreams {
                 stream_type,
@@ -1654,14 +3088,13 @@ impl Connection {
                 }
             }
             Frame::DataBlocked { data_limit } => {
-                // Should never happen since we set data limit to max
-                qwarn!(
+                qdebug!(
                     [self],
                     "Received DataBlocked with data limit {}",
                     data_limit
                 );
-                // But if it does, open it up all the way
-                self.flow_mgr.borrow_mut().max_data(LOCAL_MAX_DATA);
+                // The last MAX_DATA might have been lost, so send the current limit.
+                self.flow_mgr.borrow_mut().conn_rx_update(true);
             }
             Frame::StreamDataBlocked { stream_id, .. } => {
                 // TODO(agrover@mozilla.com): how should we be using
@@ -1674,7 +3107,7 @@ impl Connection {
                 }
 
                 if let (_, Some(rs)) = self.obtain_stream(stream_id)? {
-                    rs.maybe_send_flowc_update();
+                    rs.send_flowc_update();
                 }
             }
             Frame::StreamsBlocked { stream_type, .. } => {
@@ -1689,28 +3122,46 @@ impl Connection {
             }
             Frame::NewConnectionId {
                 sequence_number,
+                retire_prior,
                 connection_id,
                 stateless_reset_token,
-                ..
             } => {
-                self.connection_ids
-                    .insert(sequence_number, (connection_id, stateless_reset_token));
+                self.stats.new_cids_rx += 1;
+                self.handle_new_connection_id(
+                    sequence_number,
+                    retire_prior,
+                    connection_id,
+                    stateless_reset_token,
+                )?;
             }
             Frame::RetireConnectionId { sequence_number } => {
-                self.connection_ids.remove(&sequence_number);
+                self.stats.retire_cids_rx += 1;
+                self.handle_retire_connection_id(sequence_number, now)?;
+            }
+            Frame::PathChallenge { data } => {
+                // The answer goes on the path that the challenge came on.
+                match self.probe.as_mut() {
+                    Some(probe) if self.rx_probe => probe.respond(data),
+                    _ => self.flow_mgr.borrow_mut().path_response(data),
+                }
             }
-            Frame::PathChallenge { data } => self.flow_mgr.borrow_mut().path_response(data),
-            Frame::PathResponse { .. } => {
-                // Should never see this, we don't support migration atm and
-                // do not send path challenges
-                qwarn!([self], "Received Path Response");
+            Frame::PathResponse { data } => {
+                if self.rx_probe && self.probe.as_ref().map_or(false, |p| p.is_response(&data)) {
+                    self.move_to_probe()?;
+                } else {
+                    // This is unsolicited, or a replay, or it arrived on another
+                    // path.  It is ignored, as it has to be: acting on a response
+                    // that doesn't match an outstanding challenge would let anyone
+                    // who has seen one validate a path.
+                    qwarn!([self], "Received Path Response");
+                }
             }
             Frame::ConnectionClose {
                 error_code,
                 frame_type,
                 reason_phrase,
             } => {
-                let reason_phrase = String::from_utf8_lossy(&reason_phrase);
+                let reason_phrase = printable_reason_phrase(&reason_phrase);
                 qinfo!(
                     [self],
                     "ConnectionClose received. Error code: {:?} frame type {:x} reason {}",
@@ -1718,20 +3169,7 @@ impl Connection {
                     frame_type,
                     reason_phrase
                 );
-                let (detail, frame_type) = if let CloseError::Application(_) = error_code {
-                    // Use a transport error here because we want to send
-                    // NO_ERROR in this case.
-                    (
-                        Error::PeerApplicationError(error_code.code()),
-                        FRAME_TYPE_CONNECTION_CLOSE_APPLICATION,
-                    )
-                } else {
-                    (
-                        Error::PeerError(error_code.code()),
-                        FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT,
-                    )
-                };
-                let error = ConnectionError::Transport(detail);
+                let (error, frame_type) = Self::peer_close_error(error_code);
                 self.state_signaling.drain(error.clone(), frame_type, "");
                 self.set_state(State::Draining {
                     error,
@@ -1743,6 +3181,7 @@ impl Connection {
                     return Err(Error::ProtocolViolation);
                 }
                 self.set_state(State::Confirmed);
+                self.reach_milestone(|m| &mut m.handshake_confirmed, "handshake_confirmed", now)?;
                 self.discard_keys(PNSpace::Handshake);
             }
         };
@@ -1750,6 +3189,159 @@ impl Connection {
         Ok(())
     }
 
+    fn handle_new_connection_id(
+        &mut self,
+        sequence_number: u64,
+        retire_prior: u64,
+        connection_id: Vec<u8>,
+        stateless_reset_token: [u8; 16],
+    ) -> Res<()> {
+        if self
+            .path
+            .as_ref()
+            .map_or(true, |p| p.remote_cid().is_empty())
+        {
+            qwarn!(
+                [self],
+                "NEW_CONNECTION_ID from a peer using a zero-length CID"
+            );
+            return Err(Error::ProtocolViolation);
+        }
+        self.remote_cids.add(
+            sequence_number,
+            retire_prior,
+            &connection_id,
+            stateless_reset_token,
+            LOCAL_ACTIVE_CID_LIMIT,
+        )?;
+        self.replace_retired_remote_cids();
+        Ok(())
+    }
+
+    /// Move any path that uses a connection ID that the peer has retired to one
+    /// that it hasn't.  The frame that retires connection IDs always adds one, so
+    /// this only fails for a path that is being validated, which then keeps the
+    /// old connection ID.
+    fn replace_retired_remote_cids(&mut self) {
+        let remote_cids = &self.remote_cids;
+        let mut in_use: Vec<ConnectionId> = self
+            .path
+            .iter()
+            .chain(self.probe.iter())
+            .map(Path::remote_cid)
+            .filter(|cid| remote_cids.is_active(cid))
+            .cloned()
+            .collect();
+        for p in self.path.iter_mut().chain(self.probe.iter_mut()) {
+            if remote_cids.is_active(p.remote_cid()) {
+                continue;
+            }
+            let used: Vec<&[u8]> = in_use.iter().map(|cid| &cid[..]).collect();
+            if let Some(cid) = remote_cids.next_unused(&used) {
+                qinfo!("Peer retired {}, switching to {}", p.remote_cid(), cid);
+                p.set_remote_cid(&cid.as_ref());
+                in_use.push(cid);
+            }
+        }
+    }
+
+    fn handle_retire_connection_id(&mut self, sequence_number: u64, now: Instant) -> Res<()> {
+        if self
+            .path
+            .as_ref()
+            .map_or(true, |p| p.local_cid().is_empty())
+        {
+            qwarn!([self], "RETIRE_CONNECTION_ID for a zero-length CID");
+            return Err(Error::ProtocolViolation);
+        }
+        if self.rx_cid_seqno == Some(sequence_number) {
+            qwarn!(
+                [self],
+                "Peer retired connection ID {} in a packet sent to it",
+                sequence_number
+            );
+            return Err(Error::ProtocolViolation);
+        }
+        match self.local_cids.retire(sequence_number) {
+            Ok(Some(cid)) => {
+                qinfo!([self], "Peer retired connection ID {}", cid);
+                self.cid_manager.borrow_mut().retire_cid(&cid);
+                self.replace_cids(now);
+                if self.local_cids.len() < self.local_cid_limit() {
+                    qinfo!([self], "Not replacing retired connection IDs yet");
+                    self.stats.cids_throttled += 1;
+                }
+                Ok(())
+            }
+            Ok(None) => Ok(()),
+            Err(e) => {
+                qwarn!(
+                    [self],
+                    "Peer retired connection ID {} that was never issued",
+                    sequence_number
+                );
+                Err(e)
+            }
+        }
+    }
+
+    /// How many connection IDs the peer can have from us, or 0 if it can't have any.
+    fn local_cid_limit(&self) -> usize {
+        if self
+            .path
+            .as_ref()
+            .map_or(true, |p| p.local_cid().is_empty())
+        {
+            return 0;
+        }
+        let limit = self
+            .tps
+            .borrow()
+            .remote
+            .as_ref()
+            .map_or(0, |r| r.get_integer(tparams::ACTIVE_CONNECTION_ID_LIMIT));
+        usize::try_from(min(limit, MAX_ISSUED_CIDS)).unwrap()
+    }
+
+    fn issue_cid(&mut self) {
+        let cid = self.cid_manager.borrow_mut().generate_cid();
+        let token = self.cid_manager.borrow().stateless_reset_token(&cid);
+        qdebug!([self], "Issuing connection ID {}", cid);
+        self.local_cids.add(cid, token);
+        self.stats.new_cids_tx += 1;
+    }
+
+    /// Give the peer connection IDs, up to the number that it can hold, so that
+    /// it has one to use when it moves to a new path.  Nothing is given to a peer
+    /// if the connection ID it sends to is zero-length, as that never changes.
+    /// These are sent along with the next ack-eliciting packet, so a peer that
+    /// never sends anything but ACKs might not get them.
+    fn issue_cids(&mut self) {
+        let limit = self.local_cid_limit();
+        while self.local_cids.len() < limit {
+            self.issue_cid();
+        }
+    }
+
+    /// Replace connection IDs that the peer retired, as fast as `cid_issue` allows.
+    /// Those that can't be replaced now are replaced when the `ConnectionIds` timer
+    /// fires, or when there is output before that.
+    fn replace_cids(&mut self, now: Instant) {
+        let limit = self.local_cid_limit();
+        while self.local_cids.len() < limit && self.cid_issue.take(now) {
+            self.issue_cid();
+        }
+    }
+
+    /// Copy what loss recovery knows into the stats.
+    fn update_recovery_stats(&mut self) {
+        self.stats.lost = self.loss_recovery.packets_lost();
+        self.stats.pto_count = self.loss_recovery.pto_count();
+        self.stats.rtt = self.loss_recovery.rtt();
+        self.stats.rttvar = self.loss_recovery.rttvar();
+        self.stats.min_rtt = self.loss_recovery.min_rtt().unwrap_or_default();
+    }
+
     /// Given a set of `SentPacket` instances, ensure that the source of the packet
     /// is told that they are lost.  This gives the frame generation code a chance
     /// to retransmit the frame as needed.
@@ -1768,6 +3360,8 @@ impl Connection {
                         &mut self.indexes,
                     ),
                     RecoveryToken::HandshakeDone => self.state_signaling.handshake_done(),
+                    RecoveryToken::NewConnectionId(entry) => self.local_cids.lost(entry),
+                    RecoveryToken::RetireConnectionId(seqno) => self.remote_cids.lost(*seqno),
                 }
             }
         }
@@ -1797,9 +3391,10 @@ impl Connection {
             space,
             largest_acknowledged,
             acked_ranges,
-            Duration::from_millis(ack_delay),
+            self.decode_ack_delay(space, ack_delay),
             now,
         );
+        self.update_recovery_stats();
         for acked in acked_packets {
             for token in acked.tokens {
                 match token {
@@ -1807,9 +3402,12 @@ impl Connection {
                     RecoveryToken::Stream(st) => self.send_streams.acked(&st),
                     RecoveryToken::Crypto(ct) => self.crypto.acked(ct),
                     RecoveryToken::Flow(ft) => {
+                        self.stall_detector.frame_acked(&ft, now);
                         self.flow_mgr.borrow_mut().acked(ft, &mut self.send_streams)
                     }
-                    RecoveryToken::HandshakeDone => (),
+                    RecoveryToken::HandshakeDone
+                    | RecoveryToken::NewConnectionId(_)
+                    | RecoveryToken::RetireConnectionId(_) => (),
                 }
             }
         }
@@ -1828,15 +3426,139 @@ impl Connection {
         let dropped = self.loss_recovery.drop_0rtt();
         self.handle_lost_packets(&dropped);
 
-        self.send_streams.clear();
+        if self.zero_rtt_requeue && self.requeue_0rtt_streams() {
+            // The lost data goes again in 1-RTT, so the application sees nothing.
+            qdebug!([self], "0-RTT stream data requeued");
+            self.crypto.states.discard_0rtt_keys();
+            return;
+        }
+
+        self.send_streams.clear();
         self.recv_streams.clear();
-        self.indexes = StreamIndexes::new();
+        self.pending_streams.clear();
+        let tps = self.tps.borrow();
+        self.indexes = StreamIndexes::new(
+            tps.local.get_integer(tparams::INITIAL_MAX_STREAMS_BIDI),
+            tps.local.get_integer(tparams::INITIAL_MAX_STREAMS_UNI),
+        );
+        drop(tps);
         self.crypto.states.discard_0rtt_keys();
         self.events.client_0rtt_rejected();
     }
 
+    /// Keep the streams that were used in 0-RTT, if the limits that the server
+    /// has now sent are no smaller than the remembered ones that they were
+    /// opened under.  Data from the 0-RTT packets has been marked as lost, so
+    /// it is sent once more, in 1-RTT.  Returns false if the streams don't fit.
+    fn requeue_0rtt_streams(&mut self) -> bool {
+        let tps = self.tps.borrow();
+        let remote = tps.remote();
+        let max_data = remote.get_integer(tparams::INITIAL_MAX_DATA);
+        let max_streams_bidi = remote.get_integer(tparams::INITIAL_MAX_STREAMS_BIDI);
+        let max_streams_uni = remote.get_integer(tparams::INITIAL_MAX_STREAMS_UNI);
+        let max_stream_data_bidi = remote.get_integer(tparams::INITIAL_MAX_STREAM_DATA_BIDI_REMOTE);
+        let max_stream_data_uni = remote.get_integer(tparams::INITIAL_MAX_STREAM_DATA_UNI);
+        drop(tps);
+
+        let stream_limit = |id: StreamId| {
+            if id.is_bidi() {
+                max_stream_data_bidi
+            } else {
+                max_stream_data_uni
+            }
+        };
+        if max_data < self.flow_mgr.borrow().conn_max_credit()
+            || max_streams_bidi < self.indexes.local_next_stream_bidi.as_u64()
+            || max_streams_uni < self.indexes.local_next_stream_uni.as_u64()
+            || (&mut self.send_streams)
+                .into_iter()
+                .any(|(id, ss)| ss.max_stream_data() > stream_limit(*id))
+        {
+            return false;
+        }
+
+        let limit_reached = self.zero_rtt_buffered >= self.zero_rtt_limit;
+        for (id, ss) in &mut self.send_streams {
+            ss.set_max_stream_data(stream_limit(*id));
+            if limit_reached {
+                // As with acceptance, the 0-RTT limit no longer applies.
+                self.events.send_stream_writable(*id);
+            }
+        }
+        true
+    }
+
+    /// Add a datagram on the connection's path to the size of the handshake flights,
+    /// until the handshake completes.
+    fn count_handshake_datagram(&mut self, len: usize, sent: bool, now: Instant) {
+        if self.state.connected() || self.state.closed() {
+            return;
+        }
+        if self.handshake_start.is_none() {
+            self.handshake_start = Some(now);
+        }
+        if sent == (self.role == Role::Client) {
+            self.stats.handshake.client_flight += len;
+        } else {
+            self.stats.handshake.server_flight += len;
+        }
+    }
+
+    fn handshake_complete(&mut self, now: Instant) -> Res<()> {
+        let rtt = max(self.loss_recovery.rtt(), GRANULARITY);
+        let hs = &mut self.stats.handshake;
+        if let Some(start) = self.handshake_start {
+            let duration = now.saturating_duration_since(start);
+            hs.duration = Some(duration);
+            let round_trips = (duration.as_nanos() + rtt.as_nanos() / 2) / rtt.as_nanos();
+            hs.round_trips = max(1, usize::try_from(round_trips).unwrap_or(usize::MAX));
+        }
+        qinfo!([self], "Handshake {}", self.stats.handshake);
+        if self.stats.handshake.amplification_limited() {
+            qwarn!(
+                [self],
+                "Server flight {} is more than {} times client flight {}, costing a round trip",
+                self.stats.handshake.server_flight,
+                AMPLIFICATION_FACTOR,
+                self.stats.handshake.client_flight
+            );
+        }
+        #[cfg(feature = "qlog")]
+        qlog::handshake_flights(&mut self.qlog, &self.stats.handshake)?;
+        Ok(())
+    }
+
+    /// Check that the peer sent transport parameters.  They come in the ClientHello,
+    /// which a server has all of once it has Handshake keys, or in EncryptedExtensions,
+    /// which a client has before it can authenticate the server.
+    fn check_remote_tparams(&self) -> Res<()> {
+        if self.tps.borrow().remote.is_none() {
+            qwarn!([self], "No transport parameters from the peer");
+            // 109 = missing_extension
+            return Err(Error::CryptoAlert(109));
+        }
+        Ok(())
+    }
+
+    /// Once the peer's transport parameters arrive, only send datagrams that it
+    /// accepts, on the current path and on any path that is being validated.
+    fn set_max_packet_size(&mut self) {
+        let tps = self.tps.borrow();
+        if let Some(remote) = &tps.remote {
+            let max_packet_size =
+                usize::try_from(remote.get_integer(tparams::MAX_PACKET_SIZE)).unwrap_or(usize::MAX);
+            for path in self.path.iter_mut().chain(self.probe.iter_mut()) {
+                path.set_max_packet_size(max_packet_size);
+            }
+        }
+    }
+
     fn set_connected(&mut self, now: Instant) -> Res<()> {
         qinfo!([self], "TLS connection complete");
+        if self.crypto.tls.info().map(SecretAgentInfo::version) != Some(TLS_VERSION_1_3) {
+            qwarn!([self], "Negotiated something other than TLS 1.3");
+            return Err(Error::ProtocolViolation);
+        }
         if self.crypto.tls.info().map(SecretAgentInfo::alpn).is_none() {
             qwarn!([self], "No ALPN. Closing connection.");
             // 120 = no_application_protocol
@@ -1847,9 +3569,16 @@ impl Connection {
             assert_eq!(1, self.valid_cids.len());
             self.valid_cids.clear();
             // Generate a qlog event that the server connection started.
+            #[cfg(feature = "qlog")]
             qlog::server_connection_started(&mut self.qlog, self.path.as_ref().unwrap())?;
         } else {
             self.zero_rtt_state = if self.crypto.tls.info().unwrap().early_data_accepted() {
+                if self.zero_rtt_buffered >= self.zero_rtt_limit {
+                    // Streams might be blocked on the limit, which no longer applies.
+                    for (id, _) in &mut self.send_streams {
+                        self.events.send_stream_writable(*id);
+                    }
+                }
                 ZeroRttState::AcceptedClient
             } else {
                 self.client_0rtt_rejected();
@@ -1862,12 +3591,18 @@ impl Connection {
         self.crypto.install_application_keys(now + pto)?;
         self.validate_odcid()?;
         self.set_initial_limits();
+        self.issue_cids();
+        self.loss_recovery.start_rate_limit(now);
+        self.handshake_complete(now)?;
+        self.reach_milestone(|m| &mut m.handshake_complete, "handshake_complete", now)?;
         self.set_state(State::Connected);
         if self.role == Role::Server {
             self.state_signaling.handshake_done();
             self.set_state(State::Confirmed);
+            self.reach_milestone(|m| &mut m.handshake_confirmed, "handshake_confirmed", now)?;
         }
         qinfo!([self], "Connection established");
+        #[cfg(feature = "qlog")]
         qlog::connection_tparams_set(&mut self.qlog, &*self.tps.borrow())?;
         Ok(())
     }
@@ -1879,6 +3614,7 @@ impl Connection {
             if self.state.closed() {
                 self.send_streams.clear();
                 self.recv_streams.clear();
+                self.pending_streams.clear();
             }
             self.events.connection_state_change(state);
         } else if mem::discriminant(&state) != mem::discriminant(&self.state) {
@@ -1990,15 +3726,14 @@ impl Connection {
                 loop {
                     let next_stream_id =
                         next_stream_idx.to_stream_id(stream_id.stream_type(), stream_id.role());
-                    self.recv_streams.insert(
+                    let mut rs = RecvStream::new(
                         next_stream_id,
-                        RecvStream::new(
-                            next_stream_id,
-                            recv_initial_max_stream_data,
-                            self.flow_mgr.clone(),
-                            self.events.clone(),
-                        ),
+                        recv_initial_max_stream_data,
+                        self.flow_mgr.clone(),
+                        self.events.clone(),
                     );
+                    rs.set_credit_divisor(self.stream_credit_divisor);
+                    self.recv_streams.insert(next_stream_id, rs);
 
                     if next_stream_id.is_uni() {
                         self.events.new_stream(next_stream_id);
@@ -2039,9 +3774,8 @@ impl Connection {
         ))
     }
 
-    /// Create a stream.
-    // Returns new stream id
-    pub fn stream_create(&mut self, st: StreamType) -> Res<u64> {
+    /// Whether the connection state allows streams to be opened.
+    fn stream_create_state(&self) -> Res<()> {
         // Can't make streams while closing, otherwise rely on the stream limits.
         match self.state {
             State::Closing { .. } | State::Closed { .. } => return Err(Error::ConnectionState),
@@ -2055,8 +3789,16 @@ impl Connection {
         if self.tps.borrow().remote.is_none() && self.tps.borrow().remote_0rtt.is_none() {
             return Err(Error::ConnectionState);
         }
+        Ok(())
+    }
+
+    /// Create a stream.
+    // Returns new stream id
+    pub fn stream_create(&mut self, st: StreamType) -> Res<u64> {
+        self.stream_create_state()?;
+        self.check_stream_allowance(st)?;
 
-        Ok(match st {
+        let new_id = match st {
             StreamType::UniDi => {
                 if self.indexes.remote_next_stream_uni >= self.indexes.remote_max_stream_uni {
                     self.flow_mgr
@@ -2075,22 +3817,7 @@ impl Connection {
                     .remote_next_stream_uni
                     .to_stream_id(StreamType::UniDi, self.role);
                 self.indexes.remote_next_stream_uni += 1;
-                let initial_max_stream_data = self
-                    .tps
-                    .borrow()
-                    .remote()
-                    .get_integer(tparams::INITIAL_MAX_STREAM_DATA_UNI);
-
-                self.send_streams.insert(
-                    new_id,
-                    SendStream::new(
-                        new_id,
-                        initial_max_stream_data,
-                        self.flow_mgr.clone(),
-                        self.events.clone(),
-                    ),
-                );
-                new_id.as_u64()
+                new_id
             }
             StreamType::BiDi => {
                 if self.indexes.remote_next_stream_bidi >= self.indexes.remote_max_stream_bidi {
@@ -2110,6 +3837,130 @@ impl Connection {
                     .remote_next_stream_bidi
                     .to_stream_id(StreamType::BiDi, self.role);
                 self.indexes.remote_next_stream_bidi += 1;
+                new_id
+            }
+        };
+        self.open_stream(new_id);
+        Ok(new_id.as_u64())
+    }
+
+    /// Create a stream, as `stream_create` does, except that if the stream can't be
+    /// opened yet it is given the next stream ID anyway.  It is opened as soon as the
+    /// connection state and the peer's stream limits allow, in order with any other
+    /// pending streams.  Until then, it can be written to, as much as any stream
+    /// buffers, and closed or reset.  If 0-RTT is rejected, these streams are lost
+    /// along with any others.
+    /// # Errors
+    /// `ConnectionState` if the connection is closing or closed.
+    /// `StreamLimitError` if `MAX_PENDING_STREAMS` streams of this type are already
+    /// waiting to be opened.
+    pub fn stream_create_pending(&mut self, st: StreamType) -> Res<StreamId> {
+        if matches!(self.state, State::Closing { .. } | State::Closed { .. }) {
+            return Err(Error::ConnectionState);
+        }
+        self.check_stream_allowance(st)?;
+        let state_ok = self.stream_create_state().is_ok();
+        let waiting = self
+            .pending_streams
+            .iter()
+            .filter(|(id, p)| !p.opened && id.stream_type() == st)
+            .count();
+        if waiting >= MAX_PENDING_STREAMS {
+            qwarn!([self], "Too many pending {:?} streams", st);
+            return Err(Error::StreamLimitError);
+        }
+        let (next, max) = match st {
+            StreamType::BiDi => (
+                &mut self.indexes.remote_next_stream_bidi,
+                self.indexes.remote_max_stream_bidi,
+            ),
+            StreamType::UniDi => (
+                &mut self.indexes.remote_next_stream_uni,
+                self.indexes.remote_max_stream_uni,
+            ),
+        };
+        if state_ok && *next < max {
+            return self.stream_create(st).map(StreamId::from);
+        }
+
+        let new_id = next.to_stream_id(st, self.role);
+        *next += 1;
+        if state_ok {
+            self.flow_mgr.borrow_mut().streams_blocked(max, st);
+        }
+        qdebug!([self], "Stream {} is pending", new_id.as_u64());
+        self.pending_streams
+            .insert(new_id, PendingSendStream::default());
+        Ok(new_id)
+    }
+
+    /// Limit the number of streams of type `st` that this endpoint creates over the
+    /// life of the connection to `total`.  Once that many have been created,
+    /// `stream_create` fails with `StreamsExhausted`.  Unlike `StreamLimitError`,
+    /// that doesn't change when the peer allows more streams, so the only way to
+    /// get more streams is to use a new connection.  QUIC doesn't allow more than
+    /// 2^60 streams of each type, so that is the most that this can be.
+    pub fn set_stream_allowance(&mut self, st: StreamType, total: u64) {
+        let allowance = StreamIndex::new(min(total, MAX_STREAMS));
+        match st {
+            StreamType::BiDi => self.indexes.allowance_bidi = allowance,
+            StreamType::UniDi => self.indexes.allowance_uni = allowance,
+        }
+    }
+
+    /// The ID that the next stream of type `st` that this endpoint creates will have.
+    pub fn next_stream_id(&self, st: StreamType) -> u64 {
+        let next = match st {
+            StreamType::BiDi => self.indexes.remote_next_stream_bidi,
+            StreamType::UniDi => self.indexes.remote_next_stream_uni,
+        };
+        next.to_stream_id(st, self.role).as_u64()
+    }
+
+    fn check_stream_allowance(&self, st: StreamType) -> Res<()> {
+        let (next, allowance) = match st {
+            StreamType::BiDi => (
+                self.indexes.remote_next_stream_bidi,
+                self.indexes.allowance_bidi,
+            ),
+            StreamType::UniDi => (
+                self.indexes.remote_next_stream_uni,
+                self.indexes.allowance_uni,
+            ),
+        };
+        if next >= allowance {
+            qinfo!(
+                [self],
+                "No more {:?} streams after {}",
+                st,
+                allowance.as_u64()
+            );
+            return Err(Error::StreamsExhausted);
+        }
+        Ok(())
+    }
+
+    /// Add the state for a new locally-initiated stream.
+    fn open_stream(&mut self, new_id: StreamId) {
+        match new_id.stream_type() {
+            StreamType::UniDi => {
+                let initial_max_stream_data = self
+                    .tps
+                    .borrow()
+                    .remote()
+                    .get_integer(tparams::INITIAL_MAX_STREAM_DATA_UNI);
+
+                self.send_streams.insert(
+                    new_id,
+                    SendStream::new(
+                        new_id,
+                        initial_max_stream_data,
+                        self.flow_mgr.clone(),
+                        self.events.clone(),
+                    ),
+                );
+            }
+            StreamType::BiDi => {
                 // From the local perspective, this is a local- originated BiDi stream. From the
                 // remote perspective, this is a remote-originated BiDi stream. Therefore, look at
                 // the remote transport parameters for the INITIAL_MAX_STREAM_DATA_BIDI_REMOTE value
@@ -2139,18 +3990,57 @@ impl Connection {
                     .local
                     .get_integer(tparams::INITIAL_MAX_STREAM_DATA_BIDI_LOCAL);
 
-                self.recv_streams.insert(
+                let mut rs = RecvStream::new(
                     new_id,
-                    RecvStream::new(
-                        new_id,
-                        recv_initial_max_stream_data,
-                        self.flow_mgr.clone(),
-                        self.events.clone(),
-                    ),
+                    recv_initial_max_stream_data,
+                    self.flow_mgr.clone(),
+                    self.events.clone(),
                 );
-                new_id.as_u64()
+                rs.set_credit_divisor(self.stream_credit_divisor);
+                self.recv_streams.insert(new_id, rs);
             }
-        })
+        }
+    }
+
+    /// Open the pending streams that are now allowed and pass on their data.
+    fn open_pending_streams(&mut self) {
+        if self.pending_streams.is_empty() || self.stream_create_state().is_err() {
+            return;
+        }
+        let ids = self.pending_streams.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            if !self.pending_streams[&id].opened {
+                let max = match id.stream_type() {
+                    StreamType::BiDi => self.indexes.remote_max_stream_bidi,
+                    StreamType::UniDi => self.indexes.remote_max_stream_uni,
+                };
+                if StreamIndex::from(id) >= max {
+                    continue;
+                }
+                qdebug!([self], "Opening pending stream {}", id.as_u64());
+                self.open_stream(id);
+                let pending = self.pending_streams.get_mut(&id).unwrap();
+                pending.opened = true;
+                let _ = self.send_streams.set_priority(id, pending.priority);
+            }
+
+            let allowance = self.zero_rtt_allowance();
+            let done = if let Ok(stream) = self.send_streams.get_mut(id) {
+                let (sent, done) = self
+                    .pending_streams
+                    .get_mut(&id)
+                    .unwrap()
+                    .drain(stream, allowance);
+                self.zero_rtt_spent(sent);
+                done
+            } else {
+                // The stream is gone, so there is nowhere for the data to go.
+                true
+            };
+            if done {
+                self.pending_streams.remove(&id);
+            }
+        }
     }
 
     /// Send data on a stream.
@@ -2159,9 +4049,24 @@ impl Connection {
     /// # Errors
     /// `InvalidStreamId` the stream does not exist,
     /// `InvalidInput` if length of `data` is zero,
-    /// `FinalSizeError` if the stream has already been closed.
+    /// `FinalSizeError` if the stream has already been closed,
+    /// `StreamReset` if the stream was reset locally,
+    /// `StreamStopped` with the peer's error code if the peer sent STOP_SENDING.
+    /// Nothing is buffered or counted against flow control when there is an error.
     pub fn stream_send(&mut self, stream_id: u64, data: &[u8]) -> Res<usize> {
-        self.send_streams.get_mut(stream_id.into())?.send(data)
+        if let Some(pending) = self.pending_streams.get_mut(&stream_id.into()) {
+            return pending.send(data);
+        }
+        let allowance = self.zero_rtt_allowance();
+        let stream = self.send_streams.get_mut(stream_id.into())?;
+        stream.check_can_send()?;
+        if allowance == 0 && !data.is_empty() {
+            qdebug!("0-RTT send limit reached");
+            return Ok(0);
+        }
+        let sent = stream.send(&data[..min(data.len(), allowance)])?;
+        self.zero_rtt_spent(sent);
+        Ok(sent)
     }
 
     /// Send all data or nothing on a stream. May cause DATA_BLOCKED or
@@ -2170,13 +4075,27 @@ impl Connection {
     /// # Errors
     /// `InvalidStreamId` the stream does not exist,
     /// `InvalidInput` if length of `data` is zero,
-    /// `FinalSizeError` if the stream has already been closed.
+    /// `FinalSizeError` if the stream has already been closed,
+    /// `StreamReset` if the stream was reset locally,
+    /// `StreamStopped` with the peer's error code if the peer sent STOP_SENDING.
+    /// Nothing is buffered or counted against flow control when there is an error.
     pub fn stream_send_atomic(&mut self, stream_id: u64, data: &[u8]) -> Res<bool> {
-        let val = self
-            .send_streams
-            .get_mut(stream_id.into())?
-            .send_atomic(data);
+        if let Some(pending) = self.pending_streams.get_mut(&stream_id.into()) {
+            if data.len() > pending.avail() {
+                return Ok(false);
+            }
+            return pending.send(data).map(|_| true);
+        }
+        let allowance = self.zero_rtt_allowance();
+        let stream = self.send_streams.get_mut(stream_id.into())?;
+        stream.check_can_send()?;
+        if data.len() > allowance {
+            qdebug!("0-RTT send limit prevents atomic send");
+            return Ok(false);
+        }
+        let val = stream.send_atomic(data);
         if let Ok(val) = val {
+            self.zero_rtt_spent(val);
             debug_assert!(
                 val == 0 || val == data.len(),
                 "Unexpected value {} when trying to send {} bytes atomically",
@@ -2187,21 +4106,133 @@ impl Connection {
         val.map(|v| v == data.len())
     }
 
+    /// The limits the peer currently places on streams that this endpoint opens.
+    /// Until the peer's transport parameters are known (or remembered for 0-RTT),
+    /// everything is zero.
+    pub fn remote_stream_limits(&self) -> StreamLimits {
+        let tps = self.tps.borrow();
+        let (stream_data_bidi, stream_data_uni) =
+            if tps.remote.is_some() || tps.remote_0rtt.is_some() {
+                let remote = tps.remote();
+                (
+                    remote.get_integer(tparams::INITIAL_MAX_STREAM_DATA_BIDI_REMOTE),
+                    remote.get_integer(tparams::INITIAL_MAX_STREAM_DATA_UNI),
+                )
+            } else {
+                (0, 0)
+            };
+        StreamLimits {
+            max_streams_bidi: self.indexes.remote_max_stream_bidi.as_u64(),
+            max_streams_uni: self.indexes.remote_max_stream_uni.as_u64(),
+            initial_max_stream_data_bidi_remote: stream_data_bidi,
+            initial_max_stream_data_uni: stream_data_uni,
+            max_data: self.flow_mgr.borrow().conn_max_credit(),
+        }
+    }
+
+    /// How many streams of `stream_type` the peer can open in total.  This grows as
+    /// streams close and more are allowed with MAX_STREAMS.
+    pub fn local_stream_limit(&self, stream_type: StreamType) -> u64 {
+        match stream_type {
+            StreamType::BiDi => self.indexes.local_max_stream_bidi.as_u64(),
+            StreamType::UniDi => self.indexes.local_max_stream_uni.as_u64(),
+        }
+    }
+
     /// Bytes that stream_send() is guaranteed to accept for sending.
     /// i.e. that will not be blocked by flow credits or send buffer max
     /// capacity.
     pub fn stream_avail_send_space(&self, stream_id: u64) -> Res<usize> {
-        Ok(self.send_streams.get(stream_id.into())?.avail())
+        if let Some(pending) = self.pending_streams.get(&stream_id.into()) {
+            return Ok(pending.avail());
+        }
+        let avail = self.send_streams.get(stream_id.into())?.avail();
+        Ok(min(avail, self.zero_rtt_allowance()))
+    }
+
+    /// Set the amount of stream data that a client accepts for sending in 0-RTT.
+    /// Beyond this, `stream_send` accepts no more data until the server accepts
+    /// or rejects 0-RTT.  This avoids wasting memory and bandwidth on data that
+    /// the server might reject.  The default is `DEFAULT_ZERO_RTT_SEND_LIMIT`.
+    pub fn set_zero_rtt_send_limit(&mut self, limit: usize) {
+        self.zero_rtt_limit = limit;
+    }
+
+    /// Have a client send stream data again in 1-RTT if the server rejects 0-RTT,
+    /// rather than dropping its streams.  This only happens if the streams fit
+    /// in the limits that the server sends in the handshake; if they don't, the
+    /// streams are dropped as usual.  When the data is requeued, there is no
+    /// `ZeroRttRejected` event.  This is off by default, because the application
+    /// has to be able to resend the same data on the same streams.
+    pub fn set_zero_rtt_requeue(&mut self, requeue: bool) {
+        self.zero_rtt_requeue = requeue;
+    }
+
+    /// Whether this is a client that doesn't yet know if the server accepts 0-RTT.
+    /// A client can only create streams before the handshake completes if it
+    /// is going to attempt 0-RTT.
+    fn zero_rtt_unresolved(&self) -> bool {
+        self.role == Role::Client
+            && matches!(self.zero_rtt_state, ZeroRttState::Init | ZeroRttState::Sending)
+    }
+
+    /// The amount of stream data that can be accepted for sending.  This is only
+    /// limited while a client is sending 0-RTT.
+    fn zero_rtt_allowance(&self) -> usize {
+        if self.zero_rtt_unresolved() {
+            self.zero_rtt_limit.saturating_sub(self.zero_rtt_buffered)
+        } else {
+            usize::max_value()
+        }
+    }
+
+    fn zero_rtt_spent(&mut self, amount: usize) {
+        if self.zero_rtt_unresolved() {
+            self.zero_rtt_buffered += amount;
+        }
+    }
+
+    /// Keep the last `reserve` bytes of the connection flow control credit that the
+    /// peer gives for streams with `TransmissionPriority::Critical`.  Those
+    /// streams can then still send when other streams have used up the rest.
+    pub fn set_critical_reserve(&mut self, reserve: u64) {
+        self.flow_mgr.borrow_mut().set_critical_reserve(reserve);
+    }
+
+    /// Set when data on a stream is sent relative to other streams, see
+    /// `TransmissionPriority`.  This applies to data that is already buffered.
+    /// # Errors
+    /// `InvalidStreamId` if the stream does not exist or can't send.
+    pub fn stream_priority(&mut self, stream_id: u64, priority: TransmissionPriority) -> Res<()> {
+        if let Some(pending) = self.pending_streams.get_mut(&stream_id.into()) {
+            pending.priority = priority;
+            if !pending.opened {
+                return Ok(());
+            }
+        }
+        self.send_streams.set_priority(stream_id.into(), priority)
     }
 
     /// Close the stream. Enqueued data will be sent.
     pub fn stream_close_send(&mut self, stream_id: u64) -> Res<()> {
+        if let Some(pending) = self.pending_streams.get_mut(&stream_id.into()) {
+            pending.close();
+            return Ok(());
+        }
         self.send_streams.get_mut(stream_id.into())?.close();
         Ok(())
     }
 
     /// Abandon transmission of in-flight and future stream data.
     pub fn stream_reset_send(&mut self, stream_id: u64, err: AppError) -> Res<()> {
+        if let Some(pending) = self.pending_streams.get_mut(&stream_id.into()) {
+            if !pending.opened {
+                pending.reset(err);
+                return Ok(());
+            }
+            // Any data that is still pending is abandoned with the rest.
+            self.pending_streams.remove(&stream_id.into());
+        }
         self.send_streams.get_mut(stream_id.into())?.reset(err);
         Ok(())
     }
@@ -2246,6 +4277,14 @@ impl Connection {
     pub fn next_event(&mut self) -> Option<ConnectionEvent> {
         self.events.next_event()
     }
+
+    /// Only generate events of the kinds in `filter`; by default, all events are generated.
+    /// See `EventMask` for the events that are always generated.
+    /// An HTTP/3 connection relies on all events, so don't use this on the connection
+    /// that `Http3Client` uses; use `Http3Client::set_event_filter` instead.
+    pub fn set_event_filter(&mut self, filter: EventMask) {
+        self.events.set_filter(filter);
+    }
 }
 
 impl ::std::fmt::Display for Connection {
@@ -2257,17 +4296,21 @@ impl ::std::fmt::Display for Connection {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::cc::PACING_BURST_SIZE;
     use crate::cc::{INITIAL_CWND_PKTS, MIN_CONG_WINDOW};
+    use crate::cid::{CID_ISSUE_BURST, CID_ISSUE_INTERVAL};
     use crate::frame::{CloseError, StreamType};
-    use crate::path::PATH_MTU_V6;
-    use crate::recovery::ACK_ONLY_SIZE_LIMIT;
     use crate::recovery::PTO_PACKET_COUNT;
+    use crate::recv_stream::RX_STREAM_DATA_WINDOW;
+    use crate::rng;
+    use crate::sender::PACING_BURST_SIZE;
     use crate::tracking::{ACK_DELAY, MAX_UNACKED_PKTS};
     use std::convert::TryInto;
 
+    use crate::path::{normalize_address, MAX_PATH_CHALLENGES, PATH_MTU_V4};
     use neqo_common::matches;
+    use neqo_crypto::{Version, TLS_GRP_EC_SECP384R1, TLS_GRP_EC_X25519, TLS_VERSION_1_2};
     use std::mem;
+    use std::net::{IpAddr, Ipv4Addr};
     use test_fixture::{self, assertions, fixture_init, loopback, now};
 
     const AT_LEAST_PTO: Duration = Duration::from_secs(1);
@@ -2280,6 +4323,7 @@ mod tests {
     // These are a direct copy of those functions.
     pub fn default_client() -> Connection {
         fixture_init();
+        rng::set_default_seed(test_fixture::RNG_SEED);
         Connection::new_client(
             test_fixture::DEFAULT_SERVER_NAME,
             test_fixture::DEFAULT_ALPN,
@@ -2291,6 +4335,7 @@ mod tests {
     }
     pub fn default_server() -> Connection {
         fixture_init();
+        rng::set_default_seed(test_fixture::RNG_SEED);
         Connection::new_server(
             test_fixture::DEFAULT_KEYS,
             test_fixture::DEFAULT_ALPN,
@@ -2461,6 +4506,148 @@ mod tests {
         assert_error(&server, ConnectionError::Transport(Error::PeerError(300)));
     }
 
+    /// Check that the handshake milestones that were reached happened in order.
+    /// A server receives a packet before it sends one.
+    fn assert_milestones_ordered(m: &Milestones, role: Role) {
+        let (first, second) = match role {
+            Role::Client => (m.first_sent, m.first_received),
+            Role::Server => (m.first_received, m.first_sent),
+        };
+        let handshake = [
+            first,
+            second,
+            m.handshake_keys,
+            m.handshake_complete,
+            m.handshake_confirmed,
+        ];
+        let reached: Vec<_> = handshake.iter().filter_map(|t| *t).collect();
+        assert!(reached.windows(2).all(|w| w[0] <= w[1]), "{:?}", m);
+        // A milestone can't be reached without those before it.
+        let unreached = &handshake[reached.len()..];
+        assert!(unreached.iter().all(Option::is_none), "{:?}", m);
+    }
+
+    #[test]
+    fn milestones() {
+        const RTT: Duration = Duration::from_millis(100);
+        let mut client = default_client();
+        let mut server = default_server();
+        let start = now();
+        let now = connect_with_rtt(&mut client, &mut server, start, RTT);
+
+        for c in &[&client, &server] {
+            let m = c.milestones();
+            assert_milestones_ordered(&m, c.role());
+            assert!(m.handshake_confirmed.is_some());
+            assert!(m.first_stream_data.is_none());
+        }
+        let m = client.milestones();
+        assert_eq!(m.first_sent, Some(start));
+        assert_eq!(m.first_received, Some(start + RTT));
+        assert!(m.handshake_confirmed.unwrap() > m.handshake_complete.unwrap());
+        // The server confirms the handshake as soon as it completes.
+        let m = server.milestones();
+        assert_eq!(m.handshake_confirmed, m.handshake_complete);
+
+        let later = now + RTT;
+        let _ = send_something(&mut client, later);
+        let m = client.milestones();
+        assert_eq!(m.first_stream_data, Some(later));
+        assert_eq!(m.reached().len(), 6);
+        assert_eq!(m.reached()[5], ("first_stream_data", later));
+
+        // Only the first time counts.
+        let _ = send_something(&mut client, later + RTT);
+        assert_eq!(client.milestones(), m);
+    }
+
+    #[test]
+    fn milestones_failed_authentication() {
+        let mut client = default_client();
+        let mut server = default_server();
+        handshake_until_authentication(&mut client, &mut server);
+        client.authenticated(AuthenticationStatus::CertRevoked, now());
+        let _ = client.process(None, now());
+        assert_error(&client, ConnectionError::Transport(Error::CryptoAlert(44)));
+
+        // The client got as far as having Handshake keys before it failed.
+        let m = client.milestones();
+        assert_milestones_ordered(&m, Role::Client);
+        assert!(m.handshake_keys.is_some());
+        assert!(m.handshake_complete.is_none());
+        assert!(m.first_stream_data.is_none());
+    }
+
+    #[test]
+    fn local_drops() {
+        let mut client = default_client();
+        assert_eq!(client.record_local_drops(0), Ok(()));
+        assert_eq!(client.stats().local_drops_rx, 0);
+        assert_eq!(client.record_local_drops(3), Ok(()));
+        assert_eq!(client.record_local_drops(2), Ok(()));
+        assert_eq!(client.stats().local_drops_rx, 5);
+        // Local drops are not drops by the connection.
+        assert_eq!(client.stats().dropped_rx, 0);
+        assert!(client.stats().to_string().contains("local drops 5"));
+    }
+
+    /// Run the handshake up to the point where the client needs to authenticate
+    /// the server.
+    fn handshake_until_authentication(client: &mut Connection, server: &mut Connection) {
+        let out = client.process(None, now());
+        let out = server.process(out.dgram(), now());
+        let out = client.process(out.dgram(), now());
+        assert!(out.as_dgram_ref().is_some());
+        // The client acknowledges the server's flight, so the server has nothing to send.
+        let out = server.process(out.dgram(), now());
+        assert!(out.as_dgram_ref().is_none());
+
+        let authentication_needed = |e| matches!(e, ConnectionEvent::AuthenticationNeeded);
+        assert!(client.events().any(authentication_needed));
+    }
+
+    #[test]
+    fn authentication_timeout() {
+        const TIMEOUT: Duration = Duration::from_secs(5);
+        let mut client = default_client();
+        let mut server = default_server();
+        client.set_authentication_timeout(Some(TIMEOUT));
+        handshake_until_authentication(&mut client, &mut server);
+        assert!(client.process(None, now()).callback() <= TIMEOUT);
+
+        // While the client waits, neither side sends anything.
+        assert!(server.process(None, now() + TIMEOUT / 2).dgram().is_none());
+        assert!(client.process(None, now() + TIMEOUT / 2).dgram().is_none());
+
+        let out = client.process(None, now() + TIMEOUT);
+        assert!(out.as_dgram_ref().is_some());
+        assert_error(
+            &client,
+            ConnectionError::Transport(Error::AuthenticationTimeout),
+        );
+        let _ = server.process(out.dgram(), now() + TIMEOUT);
+        assert_error(&server, ConnectionError::Transport(Error::PeerError(1)));
+    }
+
+    #[test]
+    fn authentication_before_timeout() {
+        const TIMEOUT: Duration = Duration::from_secs(5);
+        let mut client = default_client();
+        let mut server = default_server();
+        client.set_authentication_timeout(Some(TIMEOUT));
+        handshake_until_authentication(&mut client, &mut server);
+
+        let later = now() + TIMEOUT / 2;
+        client.authenticated(AuthenticationStatus::Ok, later);
+        let out = client.process(None, later);
+        let out = server.process(out.dgram(), later);
+        let _ = client.process(out.dgram(), later);
+        assert_eq!(*client.state(), State::Confirmed);
+
+        let _ = client.process(None, now() + TIMEOUT * 2);
+        assert_eq!(*client.state(), State::Confirmed);
+    }
+
     #[test]
     #[allow(clippy::cognitive_complexity)]
     // tests stream send/recv after connection is established.
@@ -2618,6 +4805,47 @@ mod tests {
         }
     }
 
+    #[test]
+    fn handshake_flights() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let rtt = Duration::from_millis(100);
+        connect_with_rtt(&mut client, &mut server, now(), rtt);
+
+        let c = client.stats().handshake;
+        let s = server.stats().handshake;
+        // The client's padded Initial is in both flights.
+        assert!(c.client_flight >= PATH_MTU_V6);
+        assert!(s.client_flight > c.client_flight);
+        assert!(c.server_flight > 0);
+        assert_eq!(c.server_flight, s.server_flight);
+        assert!(!c.amplification_limited());
+        assert!(c.duration.unwrap() >= rtt);
+        assert!(c.round_trips >= 1);
+        assert!(s.duration.is_some());
+        assert!(s.round_trips >= 1);
+
+        // Nothing more is counted after the handshake.
+        send_something(&mut client, now());
+        assert_eq!(client.stats().handshake, c);
+    }
+
+    #[test]
+    fn handshake_flights_processed() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let initial = client.process(None, now()).dgram().unwrap();
+        let _ = server.process(Some(initial.clone()), now());
+        assert_eq!(server.stats().handshake.client_flight, initial.len());
+
+        // A copy of the same datagram doesn't let the server send any more, and
+        // neither does one that can't be processed.
+        server.process_input(initial.clone(), now());
+        let garbage = Datagram::new(initial.source(), initial.destination(), vec![0; 100]);
+        server.process_input(garbage, now());
+        assert_eq!(server.stats().handshake.client_flight, initial.len());
+    }
+
     #[test]
     fn test_no_alpn() {
         fixture_init();
@@ -2638,25 +4866,126 @@ mod tests {
         assert_error(&server, ConnectionError::Transport(Error::CryptoAlert(120)));
     }
 
-    #[test]
-    fn test_dup_server_flight1() {
-        qdebug!("---- client: generate CH");
-        let mut client = default_client();
-        let out = client.process(None, now());
-        assert!(out.as_dgram_ref().is_some());
-        assert_eq!(out.as_dgram_ref().unwrap().len(), PATH_MTU_V6);
-        qdebug!("Output={:0x?}", out.as_dgram_ref());
+    /// A TLS client that is set up like the one in a connection, except that it
+    /// doesn't send transport parameters and it only supports `version`.  What
+    /// it writes can be given to a server as CRYPTO frames.
+    fn fake_tls_client(version: Version) -> Client {
+        fixture_init();
+        let mut c = Client::new(test_fixture::DEFAULT_SERVER_NAME).unwrap();
+        c.set_version_range(version, version).unwrap();
+        c.set_alpn(test_fixture::DEFAULT_ALPN).unwrap();
+        c
+    }
 
-        qdebug!("---- server: CH -> SH, EE, CERT, CV, FIN");
-        let mut server = default_server();
-        let out_to_rep = server.process(out.dgram(), now());
-        assert!(out_to_rep.as_dgram_ref().is_some());
-        qdebug!("Output={:0x?}", out_to_rep.as_dgram_ref());
+    /// The CRYPTO data in the first flight from `client`.
+    fn client_hello(client: &mut Client) -> Vec<u8> {
+        let records = client.handshake_raw(now(), None).unwrap();
+        records.into_iter().flat_map(|r| r.data).collect()
+    }
 
-        qdebug!("---- client: cert verification");
-        let out = client.process(Some(out_to_rep.as_dgram_ref().unwrap().clone()), now());
-        assert!(out.as_dgram_ref().is_some());
-        qdebug!("Output={:0x?}", out.as_dgram_ref());
+    /// Pass `data` to `c` as though it arrived in a CRYPTO frame in `space`.
+    fn inject_crypto(c: &mut Connection, space: PNSpace, offset: u64, data: &[u8]) -> Res<()> {
+        let ptype = match space {
+            PNSpace::Initial => PacketType::Initial,
+            PNSpace::Handshake => PacketType::Handshake,
+            PNSpace::ApplicationData => PacketType::Short,
+        };
+        let frame = Frame::Crypto {
+            offset,
+            data: data.to_vec(),
+        };
+        c.input_frame(ptype, frame, now())
+    }
+
+    /// TLS only negotiates 1.3, so a client that only offers 1.2 gets an alert
+    /// before the version is checked again.
+    #[test]
+    fn tls_1_2_rejected() {
+        let mut client = fake_tls_client(TLS_VERSION_1_2);
+        let ch = client_hello(&mut client);
+        let mut server = default_server();
+        assert_eq!(
+            inject_crypto(&mut server, PNSpace::Initial, 0, &ch),
+            Err(Error::CryptoAlert(70)) // protocol_version
+        );
+    }
+
+    #[test]
+    fn missing_transport_parameters() {
+        let mut client = fake_tls_client(TLS_VERSION_1_3);
+        let ch = client_hello(&mut client);
+        let mut server = default_server();
+        assert_eq!(
+            inject_crypto(&mut server, PNSpace::Initial, 0, &ch),
+            Err(Error::CryptoAlert(109)) // missing_extension
+        );
+    }
+
+    #[test]
+    fn crypto_change_cipher_spec() {
+        let mut client = default_client();
+        let _ = client.process(None, now());
+        let ccs = [0x14, 0x03, 0x03, 0x00, 0x01, 0x01];
+        assert_eq!(
+            inject_crypto(&mut client, PNSpace::Initial, 0, &ccs),
+            Err(Error::ProtocolViolation)
+        );
+    }
+
+    #[test]
+    fn crypto_hello_request() {
+        let mut client = default_client();
+        let _ = client.process(None, now());
+        assert_eq!(
+            inject_crypto(&mut client, PNSpace::Initial, 0, &[0, 0, 0, 0]),
+            Err(Error::ProtocolViolation)
+        );
+    }
+
+    /// A KeyUpdate message is caught even when its header is split across frames.
+    #[test]
+    fn crypto_key_update() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        assert!(inject_crypto(&mut client, PNSpace::ApplicationData, 0, &[24, 0]).is_ok());
+        assert_eq!(
+            inject_crypto(&mut client, PNSpace::ApplicationData, 2, &[0, 1, 0]),
+            Err(Error::ProtocolViolation)
+        );
+    }
+
+    /// Only a server sends NewSessionTicket.
+    #[test]
+    fn crypto_session_ticket_to_server() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        assert_eq!(
+            inject_crypto(&mut server, PNSpace::ApplicationData, 0, &[4, 0, 0, 0]),
+            Err(Error::ProtocolViolation)
+        );
+    }
+
+    #[test]
+    fn test_dup_server_flight1() {
+        qdebug!("---- client: generate CH");
+        let mut client = default_client();
+        let out = client.process(None, now());
+        assert!(out.as_dgram_ref().is_some());
+        assert_eq!(out.as_dgram_ref().unwrap().len(), PATH_MTU_V6);
+        qdebug!("Output={:0x?}", out.as_dgram_ref());
+
+        qdebug!("---- server: CH -> SH, EE, CERT, CV, FIN");
+        let mut server = default_server();
+        let out_to_rep = server.process(out.dgram(), now());
+        assert!(out_to_rep.as_dgram_ref().is_some());
+        qdebug!("Output={:0x?}", out_to_rep.as_dgram_ref());
+
+        qdebug!("---- client: cert verification");
+        let out = client.process(Some(out_to_rep.as_dgram_ref().unwrap().clone()), now());
+        assert!(out.as_dgram_ref().is_some());
+        qdebug!("Output={:0x?}", out.as_dgram_ref());
 
         let out = server.process(out.dgram(), now());
         assert!(out.as_dgram_ref().is_none());
@@ -2683,6 +5012,103 @@ mod tests {
         assert_eq!(1, client.stats().dropped_rx);
     }
 
+    /// A client that only sends a key share for X25519, and a server that only
+    /// accepts P-384, so the server has to send a HelloRetryRequest.
+    fn hrr_pair() -> (Connection, Connection) {
+        let mut client = default_client();
+        client
+            .set_groups(&[TLS_GRP_EC_X25519, TLS_GRP_EC_SECP384R1])
+            .unwrap();
+        let mut server = default_server();
+        server.set_groups(&[TLS_GRP_EC_SECP384R1]).unwrap();
+        (client, server)
+    }
+
+    /// Exchange the first flights, checking that the server sent a HelloRetryRequest.
+    /// This returns the client's second Initial.
+    fn start_hrr(client: &mut Connection, server: &mut Connection, now: Instant) -> Datagram {
+        let ch1 = client.process(None, now).dgram();
+        assert!(ch1.is_some());
+        let ch1_end = client
+            .handshake_state_summary(now)
+            .initial
+            .unwrap()
+            .crypto_sent;
+        let hrr = server.process(ch1, now).dgram();
+        assert!(hrr.is_some());
+        assert_eq!(*server.state(), State::Handshaking);
+        assert_hrr_crypto_offsets(client, server, now);
+
+        let ch2 = client.process(hrr, now).dgram().unwrap();
+        // There is no ServerHello yet, so no Handshake keys.
+        let summary = client.handshake_state_summary(now);
+        assert!(!summary.handshake.map_or(false, |h| h.read_keys));
+        let initial = summary.initial.unwrap();
+        assert!(initial.crypto_received > 0);
+        // The second ClientHello follows the first in the crypto stream.
+        assert!(initial.crypto_sent > ch1_end);
+        assert_eq!(ch2.len(), PATH_MTU_V6);
+        ch2
+    }
+
+    /// The server has all of the client's Initial crypto data in order: the
+    /// second ClientHello starts where the first ended, rather than at zero.
+    fn assert_hrr_crypto_offsets(client: &mut Connection, server: &mut Connection, now: Instant) {
+        let sent = client.handshake_state_summary(now).initial.unwrap();
+        let received = server.handshake_state_summary(now).initial.unwrap();
+        assert_eq!(received.crypto_received, sent.crypto_sent);
+        assert_eq!(received.crypto_recv_buffered, 0);
+    }
+
+    fn assert_hrr_connected(client: &mut Connection, server: &mut Connection, now: Instant) {
+        assert_eq!(*client.state(), State::Confirmed);
+        assert_eq!(*server.state(), State::Confirmed);
+        assert_eq!(
+            client.tls_info().unwrap().key_exchange(),
+            TLS_GRP_EC_SECP384R1
+        );
+        assert_eq!(
+            server.tls_info().unwrap().key_exchange(),
+            TLS_GRP_EC_SECP384R1
+        );
+
+        // 1-RTT data works.
+        let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+        client.stream_send(stream_id, b"request").unwrap();
+        let out = client.process(None, now);
+        let _ = server.process(out.dgram(), now);
+        let mut buf = [0; 16];
+        let (len, fin) = server.stream_recv(stream_id, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"request");
+        assert!(!fin);
+    }
+
+    #[test]
+    fn hello_retry_request() {
+        let (mut client, mut server) = hrr_pair();
+        let ch2 = start_hrr(&mut client, &mut server, now());
+        server.process_input(ch2, now());
+        assert_hrr_crypto_offsets(&mut client, &mut server, now());
+        let now = handshake(&mut client, &mut server, now(), Duration::new(0, 0));
+        assert_hrr_connected(&mut client, &mut server, now);
+    }
+
+    #[test]
+    fn hello_retry_request_ch2_lost() {
+        let (mut client, mut server) = hrr_pair();
+        let now = now();
+        let _ = start_hrr(&mut client, &mut server, now);
+
+        // The second ClientHello is retransmitted from the same crypto offset.
+        let now = now + client.process(None, now).callback();
+        let ch2 = client.process(None, now).dgram();
+        assert!(ch2.is_some());
+        server.process_input(ch2.unwrap(), now);
+        assert_hrr_crypto_offsets(&mut client, &mut server, now);
+        let now = handshake(&mut client, &mut server, now, Duration::new(0, 0));
+        assert_hrr_connected(&mut client, &mut server, now);
+    }
+
     fn exchange_ticket(client: &mut Connection, server: &mut Connection, now: Instant) -> Vec<u8> {
         server.send_ticket(now, &[]).expect("can send ticket");
         let ticket = server.process_output(now).dgram();
@@ -2724,7 +5150,14 @@ mod tests {
         let mut server = default_server();
         connect(&mut client, &mut server);
 
+        assert!(client.resumption_token_expiration().is_none());
         let token = exchange_ticket(&mut client, &mut server, now());
+        let expiration = client
+            .resumption_token_expiration()
+            .expect("the token expires");
+        assert!(expiration > now());
+        assert!(server.resumption_token_expiration().is_none());
+
         let mut client = default_client();
         client
             .set_resumption_token(now(), &token[..])
@@ -2735,6 +5168,60 @@ mod tests {
         assert!(server.crypto.tls.info().unwrap().resumed());
     }
 
+    #[test]
+    fn resume_other_server() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let token = exchange_ticket(&mut client, &mut server, now());
+
+        let mut client = Connection::new_client(
+            "other.example",
+            test_fixture::DEFAULT_ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+            loopback(),
+            loopback(),
+        )
+        .unwrap();
+        assert_eq!(
+            client.set_resumption_token(now(), &token[..]),
+            Err(Error::InvalidResumptionToken)
+        );
+        assert_eq!(*client.state(), State::Init);
+    }
+
+    #[test]
+    fn resume_other_alpn() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let token = exchange_ticket(&mut client, &mut server, now());
+
+        let mut client = default_client();
+        client.set_alpn(&["other"]).unwrap();
+        assert_eq!(
+            client.set_resumption_token(now(), &token[..]),
+            Err(Error::InvalidResumptionToken)
+        );
+        assert_eq!(*client.state(), State::Init);
+    }
+
+    #[test]
+    fn resume_expired() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let token = exchange_ticket(&mut client, &mut server, now());
+
+        // The ticket is no good, so the client starts a full handshake.
+        let later = now() + Duration::from_secs(3 * 24 * 60 * 60);
+        let mut client = default_client();
+        client.set_resumption_token(later, &token[..]).unwrap();
+        assert_ne!(*client.zero_rtt_state(), ZeroRttState::Sending);
+        assert!(client.tps.borrow().remote_0rtt.is_none());
+        assert!(client.process_output(later).dgram().is_some());
+    }
+
     #[test]
     fn remember_smoothed_rtt() {
         let mut client = default_client();
@@ -2782,6 +5269,89 @@ mod tests {
         assert!(server.crypto.tls.info().unwrap().early_data_accepted());
     }
 
+    #[test]
+    fn export_keying_material() {
+        let mut client = default_client();
+        let mut server = default_server();
+        assert_eq!(
+            client.export_keying_material("EXPORTER-test", None, 16),
+            Err(Error::NotConnected)
+        );
+        connect(&mut client, &mut server);
+
+        let value = client
+            .export_keying_material("EXPORTER-test", Some(b"context"), 16)
+            .unwrap();
+        assert_eq!(value.len(), 16);
+        assert_eq!(
+            value,
+            server
+                .export_keying_material("EXPORTER-test", Some(b"context"), 16)
+                .unwrap()
+        );
+        assert_ne!(
+            value,
+            client
+                .export_keying_material("EXPORTER-test", Some(b"other"), 16)
+                .unwrap()
+        );
+        assert_ne!(
+            value,
+            client
+                .export_keying_material("EXPORTER-other", Some(b"context"), 16)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn export_early_keying_material() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        client.enable_early_exporter();
+        // There was no 0-RTT on this connection.
+        assert_eq!(
+            client.export_early_keying_material("EXPORTER-early", b"context", 16),
+            Err(Error::ConnectionState)
+        );
+
+        let token = exchange_ticket(&mut client, &mut server, now());
+        let mut client = default_client();
+        client
+            .set_resumption_token(now(), &token[..])
+            .expect("should set token");
+        let mut server = default_server();
+
+        // This has to be enabled explicitly.
+        assert_eq!(
+            client.export_early_keying_material("EXPORTER-early", b"context", 16),
+            Err(Error::ConnectionState)
+        );
+        client.enable_early_exporter();
+        server.enable_early_exporter();
+
+        let client_hs = client.process(None, now());
+        let value = client
+            .export_early_keying_material("EXPORTER-early", b"context", 16)
+            .unwrap();
+        let server_hs = server.process(client_hs.dgram(), now());
+        assert_eq!(
+            value,
+            server
+                .export_early_keying_material("EXPORTER-early", b"context", 16)
+                .unwrap()
+        );
+        // The early exporter is different from the exporter.
+        client.process_input(server_hs.dgram().unwrap(), now());
+        connect(&mut client, &mut server);
+        assert_ne!(
+            value,
+            client
+                .export_keying_material("EXPORTER-early", Some(b"context"), 16)
+                .unwrap()
+        );
+    }
+
     #[test]
     fn zero_rtt_send_recv() {
         let mut client = default_client();
@@ -2937,77 +5507,298 @@ mod tests {
         assert!(server.events().any(recvd_stream_evt));
     }
 
+    /// Create a client that has a resumption token, so that it can send 0-RTT.
+    fn resumed_client() -> Connection {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let token = exchange_ticket(&mut client, &mut server, now());
+        let mut client = default_client();
+        client
+            .set_resumption_token(now(), &token[..])
+            .expect("should set token");
+        client
+    }
+
+    /// Fill the 0-RTT send limit on a new stream, returning the stream ID.
+    fn fill_zero_rtt(client: &mut Connection) -> u64 {
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        let data = vec![0; DEFAULT_ZERO_RTT_SEND_LIMIT * 2];
+        assert_eq!(
+            client.stream_send(stream_id, &data).unwrap(),
+            DEFAULT_ZERO_RTT_SEND_LIMIT
+        );
+        assert_eq!(client.stream_send(stream_id, &data).unwrap(), 0);
+        assert_eq!(client.stream_avail_send_space(stream_id).unwrap(), 0);
+        assert!(!client.stream_send_atomic(stream_id, &[0]).unwrap());
+        stream_id
+    }
+
     #[test]
-    // Send fin even if a peer closes a reomte bidi send stream before sending any data.
-    fn report_fin_when_stream_closed_wo_data() {
-        // Note that the two servers in this test will get different anti-replay filters.
-        // That's OK because we aren't testing anti-replay.
+    fn event_filter() {
         let mut client = default_client();
         let mut server = default_server();
         connect(&mut client, &mut server);
+        server.set_event_filter(EventMask::ALL.without(EventMask::NEW_STREAM));
 
-        // create a stream
-        let stream_id = client.stream_create(StreamType::BiDi).unwrap();
-        client.stream_send(stream_id, &[0x00]).unwrap();
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[0; 10]).unwrap();
         let out = client.process(None, now());
         server.process(out.dgram(), now());
 
-        assert_eq!(Ok(()), server.stream_close_send(stream_id));
-        let out = server.process(None, now());
-        client.process(out.dgram(), now());
-        let stream_readable = |e| matches!(e, ConnectionEvent::RecvStreamReadable {..});
-        assert!(client.events().any(stream_readable));
+        let events = server.events().collect::<Vec<_>>();
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, ConnectionEvent::NewStream { .. })));
+        assert!(events
+            .iter()
+            .any(|e| *e == ConnectionEvent::RecvStreamReadable { stream_id }));
     }
 
-    /// Connect with an RTT and then force both peers to be idle.
-    /// Getting the client and server to reach an idle state is surprisingly hard.
-    /// The server sends HANDSHAKE_DONE at the end of the handshake, and the client
-    /// doesn't immediately acknowledge it.  Reordering packets does the trick.
-    fn connect_rtt_idle(
-        client: &mut Connection,
-        server: &mut Connection,
-        rtt: Duration,
-    ) -> Instant {
-        let mut now = connect_with_rtt(client, server, now(), rtt);
-        let p1 = send_something(server, now);
-        let p2 = send_something(server, now);
-        now += rtt / 2;
-        // Delivering p2 first at the client causes it to want to ACK.
-        client.process_input(p2, now);
-        // Delivering p1 should not have the client change its mind about the ACK.
-        let ack = client.process(Some(p1), now).dgram();
-        assert!(ack.is_some());
+    #[test]
+    fn pending_stream() {
+        let mut client = default_client();
+        let mut server = default_server();
+        // Until the handshake is done there are no limits for the server.
         assert_eq!(
-            server.process(ack, now),
-            Output::Callback(LOCAL_IDLE_TIMEOUT)
+            client.stream_create(StreamType::UniDi),
+            Err(Error::ConnectionState)
         );
+        let stream_id = client
+            .stream_create_pending(StreamType::UniDi)
+            .unwrap()
+            .as_u64();
+        assert_eq!(stream_id, 2);
+        assert_eq!(client.stream_send(stream_id, &[0x61; 10]), Ok(10));
+        client.stream_close_send(stream_id).unwrap();
         assert_eq!(
-            client.process_output(now),
-            Output::Callback(LOCAL_IDLE_TIMEOUT)
+            client.stream_send(stream_id, &[0x61; 10]),
+            Err(Error::FinalSizeError)
         );
-        now
-    }
 
-    fn connect_force_idle(client: &mut Connection, server: &mut Connection) {
-        connect_rtt_idle(client, server, Duration::new(0, 0));
+        // Writes are limited to what a stream would buffer.
+        let full_id = client
+            .stream_create_pending(StreamType::UniDi)
+            .unwrap()
+            .as_u64();
+        assert_eq!(full_id, 6);
+        assert_eq!(client.stream_send(full_id, &[0; 0x10000]), Ok(0xffff));
+        assert_eq!(client.stream_avail_send_space(full_id), Ok(0));
+
+        connect(&mut client, &mut server);
+        let out = client.process(None, now());
+        let _ = server.process(out.dgram(), now());
+
+        // The streams were opened in order.
+        assert_eq!(client.stream_create(StreamType::UniDi), Ok(10));
+        let mut buf = [0; 16];
+        assert_eq!(server.stream_recv(stream_id, &mut buf), Ok((10, true)));
+        assert_eq!(&buf[..10], &[0x61; 10]);
     }
 
     #[test]
-    fn idle_timeout() {
+    fn pending_stream_limit() {
         let mut client = default_client();
         let mut server = default_server();
-        connect_force_idle(&mut client, &mut server);
-
-        let now = now();
+        for i in 0..MAX_PENDING_STREAMS {
+            let id = client.stream_create_pending(StreamType::BiDi).unwrap();
+            assert_eq!(id.as_u64(), u64::try_from(i).unwrap() * 4);
+        }
+        assert_eq!(
+            client.stream_create_pending(StreamType::BiDi),
+            Err(Error::StreamLimitError)
+        );
+        // The cap applies to each type separately.
+        assert!(client.stream_create_pending(StreamType::UniDi).is_ok());
 
-        let res = client.process(None, now);
-        assert_eq!(res, Output::Callback(LOCAL_IDLE_TIMEOUT));
+        // Once the pending streams are opened, there is room for more.
+        connect(&mut client, &mut server);
+        let _ = client.process(None, now());
+        assert!(client.stream_create_pending(StreamType::BiDi).is_ok());
+    }
 
-        // Still connected after 29 seconds. Idle timer not reset
-        client.process(None, now + LOCAL_IDLE_TIMEOUT - Duration::from_secs(1));
-        assert!(matches!(client.state(), State::Confirmed));
+    #[test]
+    fn zero_rtt_send_limit_accept() {
+        let mut client = resumed_client();
+        let mut server = default_server();
+        let stream_id = fill_zero_rtt(&mut client);
 
-        client.process(None, now + LOCAL_IDLE_TIMEOUT);
+        connect(&mut client, &mut server);
+        assert!(client.crypto.tls.info().unwrap().early_data_accepted());
+        let writable = |e| {
+            matches!(e, ConnectionEvent::SendStreamWritable { stream_id: id } if id == stream_id)
+        };
+        assert!(client.events().any(writable));
+        assert_eq!(client.stream_send(stream_id, &[0; 100]).unwrap(), 100);
+    }
+
+    /// A server that rejects 0-RTT, because it has a fresh anti-replay context.
+    fn rejecting_server() -> Connection {
+        let ar = AntiReplay::new(now(), test_fixture::ANTI_REPLAY_WINDOW, 1, 3)
+            .expect("setup anti-replay");
+        Connection::new_server(
+            test_fixture::DEFAULT_KEYS,
+            test_fixture::DEFAULT_ALPN,
+            &ar,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn zero_rtt_send_limit_reject() {
+        let mut client = resumed_client();
+        let mut server = rejecting_server();
+        let stream_id = fill_zero_rtt(&mut client);
+
+        connect(&mut client, &mut server);
+        let recvd_0rtt_reject = |e| e == ConnectionEvent::ZeroRttRejected;
+        assert!(client.events().any(recvd_0rtt_reject));
+        assert_eq!(
+            client.stream_send(stream_id, &[0]).unwrap_err(),
+            Error::InvalidStreamId
+        );
+
+        // Once 0-RTT is resolved, there is no limit.
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        let data = vec![0; DEFAULT_ZERO_RTT_SEND_LIMIT * 2];
+        assert_eq!(client.stream_send(stream_id, &data).unwrap(), data.len());
+    }
+
+    #[test]
+    fn zero_rtt_requeue() {
+        let mut client = resumed_client();
+        client.set_zero_rtt_requeue(true);
+        let mut server = rejecting_server();
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        let msg = &[0x61; 100];
+        assert_eq!(client.stream_send(stream_id, msg).unwrap(), msg.len());
+        client.stream_close_send(stream_id).unwrap();
+
+        connect(&mut client, &mut server);
+        let recvd_0rtt_reject = |e| e == ConnectionEvent::ZeroRttRejected;
+        assert!(!client.events().any(recvd_0rtt_reject));
+
+        // The data arrives in 1-RTT, once.
+        let mut dgram = client.process(None, now()).dgram();
+        for _ in 0..3 {
+            dgram = server.process(dgram, now()).dgram();
+            dgram = client.process(dgram, now()).dgram();
+        }
+        let mut buf = [0; 200];
+        assert_eq!(
+            server.stream_recv(stream_id, &mut buf).unwrap(),
+            (msg.len(), true)
+        );
+        assert_eq!(&buf[..msg.len()], &msg[..]);
+    }
+
+    #[test]
+    fn zero_rtt_requeue_no_room() {
+        let mut client = resumed_client();
+        client.set_zero_rtt_requeue(true);
+        let mut server = rejecting_server();
+        // The server now allows fewer streams than were remembered.
+        server
+            .set_local_tparam(
+                tparams::INITIAL_MAX_STREAMS_UNI,
+                TransportParameter::Integer(0),
+            )
+            .unwrap();
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[0x61; 100]).unwrap();
+
+        connect(&mut client, &mut server);
+        let recvd_0rtt_reject = |e| e == ConnectionEvent::ZeroRttRejected;
+        assert!(client.events().any(recvd_0rtt_reject));
+        assert_eq!(
+            client.stream_send(stream_id, &[0]).unwrap_err(),
+            Error::InvalidStreamId
+        );
+    }
+
+    #[test]
+    fn zero_rtt_send_limit_configured() {
+        let mut client = resumed_client();
+        client.set_zero_rtt_send_limit(10);
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(client.stream_send(stream_id, &[0; 6]).unwrap(), 6);
+        assert_eq!(client.stream_send(stream_id, &[0; 6]).unwrap(), 4);
+        assert_eq!(client.stream_send(stream_id, &[0; 6]).unwrap(), 0);
+    }
+
+    #[test]
+    // Send fin even if a peer closes a reomte bidi send stream before sending any data.
+    fn report_fin_when_stream_closed_wo_data() {
+        // Note that the two servers in this test will get different anti-replay filters.
+        // That's OK because we aren't testing anti-replay.
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        // create a stream
+        let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+        client.stream_send(stream_id, &[0x00]).unwrap();
+        let out = client.process(None, now());
+        server.process(out.dgram(), now());
+
+        assert_eq!(Ok(()), server.stream_close_send(stream_id));
+        let out = server.process(None, now());
+        client.process(out.dgram(), now());
+        let stream_readable = |e| matches!(e, ConnectionEvent::RecvStreamReadable {..});
+        assert!(client.events().any(stream_readable));
+    }
+
+    /// Connect with an RTT and then force both peers to be idle.
+    /// Getting the client and server to reach an idle state is surprisingly hard.
+    /// The server sends HANDSHAKE_DONE at the end of the handshake, and the client
+    /// doesn't immediately acknowledge it.  Reordering packets does the trick.
+    fn connect_rtt_idle(
+        client: &mut Connection,
+        server: &mut Connection,
+        rtt: Duration,
+    ) -> Instant {
+        let mut now = connect_with_rtt(client, server, now(), rtt);
+        let p1 = send_something(server, now);
+        let p2 = send_something(server, now);
+        now += rtt / 2;
+        // Delivering p2 first at the client causes it to want to ACK.
+        client.process_input(p2, now);
+        // Delivering p1 should not have the client change its mind about the ACK.
+        let ack = client.process(Some(p1), now).dgram();
+        assert!(ack.is_some());
+        assert_eq!(
+            server.process(ack, now),
+            Output::Callback(LOCAL_IDLE_TIMEOUT)
+        );
+        assert_eq!(
+            client.process_output(now),
+            Output::Callback(LOCAL_IDLE_TIMEOUT)
+        );
+        now
+    }
+
+    fn connect_force_idle(client: &mut Connection, server: &mut Connection) {
+        connect_rtt_idle(client, server, Duration::new(0, 0));
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        let now = now();
+
+        let res = client.process(None, now);
+        assert_eq!(res, Output::Callback(LOCAL_IDLE_TIMEOUT));
+
+        // Still connected after 29 seconds. Idle timer not reset
+        client.process(None, now + LOCAL_IDLE_TIMEOUT - Duration::from_secs(1));
+        assert!(matches!(client.state(), State::Confirmed));
+
+        client.process(None, now + LOCAL_IDLE_TIMEOUT);
 
         // Not connected after LOCAL_IDLE_TIMEOUT seconds.
         assert!(matches!(client.state(), State::Closed(_)));
@@ -3043,6 +5834,43 @@ mod tests {
         assert_eq!(client.process(None, now()), Output::Callback(LOWER_TIMEOUT));
     }
 
+    #[test]
+    fn set_idle_timeout() {
+        const LOWER_TIMEOUT: Duration = Duration::from_secs(5);
+
+        let mut client = default_client();
+        let mut server = default_server();
+        assert_eq!(client.idle_timeout(), LOCAL_IDLE_TIMEOUT);
+        assert_eq!(
+            client.set_idle_timeout(Duration::from_secs(0)),
+            Err(Error::InvalidInput)
+        );
+        client.set_idle_timeout(LOWER_TIMEOUT).unwrap();
+        assert_eq!(client.idle_timeout(), LOWER_TIMEOUT);
+
+        // The server learns the lower value from the client.
+        connect(&mut client, &mut server);
+        assert_eq!(server.idle_timeout(), LOWER_TIMEOUT);
+        assert_eq!(
+            client.set_idle_timeout(LOCAL_IDLE_TIMEOUT),
+            Err(Error::ConnectionState)
+        );
+
+        let p1 = send_something(&mut server, now());
+        let p2 = send_something(&mut server, now());
+        client.process_input(p2, now());
+        let ack = client.process(Some(p1), now()).dgram();
+        assert!(ack.is_some());
+        assert_eq!(server.process(ack, now()), Output::Callback(LOWER_TIMEOUT));
+        assert_eq!(client.process(None, now()), Output::Callback(LOWER_TIMEOUT));
+
+        client.process(None, now() + LOWER_TIMEOUT);
+        assert_eq!(
+            *client.state(),
+            State::Closed(ConnectionError::Transport(Error::IdleTimeout))
+        );
+    }
+
     #[test]
     fn tiny_idle_timeout() {
         const RTT: Duration = Duration::from_millis(500);
@@ -3183,1904 +6011,4418 @@ mod tests {
         assert!(matches!(client.state(), State::Closed(_)));
     }
 
+    /// Times that don't advance, or that go backwards, don't cause problems.
     #[test]
-    fn max_data() {
+    fn time_does_not_advance() {
         let mut client = default_client();
         let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        let now = now() + Duration::from_secs(1);
 
-        const SMALL_MAX_DATA: usize = 16383;
-
-        server
-            .set_local_tparam(
-                tparams::INITIAL_MAX_DATA,
-                TransportParameter::Integer(SMALL_MAX_DATA.try_into().unwrap()),
-            )
-            .unwrap();
+        let dgram = send_something(&mut client, now);
+        assert!(matches!(client.process(None, now), Output::Callback(_)));
+        assert!(matches!(client.process(None, now), Output::Callback(_)));
+        // The ACK for the packet is delayed, so the server has a timer now.
+        assert!(matches!(
+            server.process(Some(dgram), now),
+            Output::Callback(_)
+        ));
 
-        connect(&mut client, &mut server);
+        // Go back to the time that the connection was established.
+        assert!(matches!(
+            client.process(None, now - Duration::from_secs(1)),
+            Output::Callback(_)
+        ));
+        assert!(matches!(
+            server.process(None, now - Duration::from_secs(1)),
+            Output::Callback(_)
+        ));
+        assert_eq!(*client.state(), State::Confirmed);
+        assert_eq!(*server.state(), State::Confirmed);
+    }
 
-        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
-        assert_eq!(stream_id, 2);
-        assert_eq!(
-            client.stream_avail_send_space(stream_id).unwrap(),
-            SMALL_MAX_DATA
-        );
-        assert_eq!(
-            client
-                .stream_send(stream_id, &[b'a'; RX_STREAM_DATA_WINDOW as usize])
-                .unwrap(),
-            SMALL_MAX_DATA
-        );
-        let evts = client.events().collect::<Vec<_>>();
-        assert_eq!(evts.len(), 2); // SendStreamWritable, StateChange(connected)
-        assert_eq!(client.stream_send(stream_id, b"hello").unwrap(), 0);
-        let ss = client.send_streams.get_mut(stream_id.into()).unwrap();
-        ss.mark_as_sent(0, 4096, false);
-        ss.mark_as_acked(0, 4096, false);
+    const SUSPEND: Duration = Duration::from_secs(20);
 
-        // no event because still limited by conn max data
-        let evts = client.events().collect::<Vec<_>>();
-        assert_eq!(evts.len(), 0);
+    /// If the clock jumps forward while there is data outstanding, as it does when
+    /// a laptop wakes up, the PTO timer fires once and probes are sent.
+    #[test]
+    fn suspend_pto() {
+        const RTT: Duration = Duration::from_millis(100);
+        let mut client = default_client();
+        let mut server = default_server();
+        let now = connect_rtt_idle(&mut client, &mut server, RTT);
+        let _ = send_something(&mut client, now);
 
-        // increase max data
-        client.handle_max_data(100_000);
-        assert_eq!(client.stream_avail_send_space(stream_id).unwrap(), 49152);
-        let evts = client.events().collect::<Vec<_>>();
-        assert_eq!(evts.len(), 1);
-        assert!(matches!(evts[0], ConnectionEvent::SendStreamWritable{..}));
+        let wake = now + SUSPEND;
+        assert!(client.process(None, wake).dgram().is_some());
+        let delay = loop {
+            match client.process(None, wake) {
+                Output::Datagram(_) => (),
+                Output::Callback(t) => break t,
+                Output::None => panic!("the connection should still be open"),
+            }
+        };
+        // The PTO timer is running again, not firing immediately.
+        assert!(delay >= RTT);
+        assert_eq!(*client.state(), State::Confirmed);
     }
 
-    // Test that we split crypto data if they cannot fit into one packet.
-    // To test this we will use a long server certificate.
+    /// An ACK that is only read after a suspend doesn't count toward the RTT.
     #[test]
-    fn test_crypto_frame_split() {
+    fn suspend_rtt() {
+        const RTT: Duration = Duration::from_millis(100);
         let mut client = default_client();
-        let mut server = Connection::new_server(
-            test_fixture::LONG_CERT_KEYS,
-            test_fixture::DEFAULT_ALPN,
-            &test_fixture::anti_replay(),
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(6))),
-        )
-        .expect("create a server");
+        let mut server = default_server();
+        let mut now = connect_rtt_idle(&mut client, &mut server, RTT);
+        let rtt = client.loss_recovery.rtt();
 
-        let client1 = client.process(None, now());
-        assert!(client1.as_dgram_ref().is_some());
+        // Delivering out of order causes the server to acknowledge immediately.
+        let p1 = send_something(&mut client, now);
+        let p2 = send_something(&mut client, now);
+        now += RTT / 2;
+        server.process_input(p2, now);
+        let ack = server.process(Some(p1), now).dgram();
+        assert!(ack.is_some());
 
-        // The entire server flight doesn't fit in a single packet because the
-        // certificate is large, therefore the server will produce 2 packets.
-        let server1 = server.process(client1.dgram(), now());
-        assert!(server1.as_dgram_ref().is_some());
-        let server2 = server.process(None, now());
-        assert!(server2.as_dgram_ref().is_some());
+        now += SUSPEND;
+        client.process_input(ack.unwrap(), now);
+        assert_eq!(client.loss_recovery.rtt(), rtt);
+        assert_eq!(*client.state(), State::Confirmed);
+    }
 
-        let client2 = client.process(server1.dgram(), now());
-        // This is an ack.
-        assert!(client2.as_dgram_ref().is_some());
-        // The client might have the certificate now, so we can't guarantee that
-        // this will work.
-        let auth1 = maybe_authenticate(&mut client);
-        assert_eq!(*client.state(), State::Handshaking);
+    /// A delayed ACK is sent after a suspend, once.
+    #[test]
+    fn suspend_ack_delay() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        let dgram = send_something(&mut client, now());
+        assert!(matches!(
+            server.process(Some(dgram), now()),
+            Output::Callback(_)
+        ));
 
-        // let server process the ack for the first packet.
-        let server3 = server.process(client2.dgram(), now());
-        assert!(server3.as_dgram_ref().is_none());
+        let wake = now() + SUSPEND;
+        assert!(server.process(None, wake).dgram().is_some());
+        assert!(matches!(server.process(None, wake), Output::Callback(_)));
+    }
 
-        // Consume the second packet from the server.
-        let client3 = client.process(server2.dgram(), now());
+    /// The idle timeout still applies when the clock jumps past it.
+    #[test]
+    fn suspend_idle() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        let _ = send_something(&mut client, now());
 
-        // Check authentication.
-        let auth2 = maybe_authenticate(&mut client);
-        assert!(auth1 ^ auth2);
-        // Now client has all data to finish handshake.
-        assert_eq!(*client.state(), State::Connected);
-
-        let client4 = client.process(server3.dgram(), now());
-        // One of these will contain data depending on whether Authentication was completed
-        // after the first or second server packet.
-        assert!(client3.as_dgram_ref().is_some() ^ client4.as_dgram_ref().is_some());
+        let wake = now() + Duration::from_secs(600);
+        assert_eq!(client.process(None, wake), Output::None);
+        assert!(matches!(
+            client.state(),
+            State::Closed(ConnectionError::Transport(Error::IdleTimeout))
+        ));
+    }
 
-        let _ = server.process(client3.dgram(), now());
-        let _ = server.process(client4.dgram(), now());
+    /// A connection that is closing finishes closing when the clock jumps.
+    #[test]
+    fn suspend_closing() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        client.close(now(), 0, "");
+        assert!(client.process(None, now()).dgram().is_some());
 
-        assert_eq!(*client.state(), State::Connected);
-        assert_eq!(*server.state(), State::Confirmed);
+        let wake = now() + Duration::from_secs(600);
+        assert_eq!(client.process(None, wake), Output::None);
+        assert!(matches!(client.state(), State::Closed(_)));
     }
 
-    #[test]
-    fn set_local_tparam() {
-        let client = default_client();
+    const RESET_TOKEN: [u8; 16] = [0xab; 16];
 
-        client
-            .set_local_tparam(tparams::INITIAL_MAX_DATA, TransportParameter::Integer(55))
-            .unwrap()
+    /// A server that gives the client `RESET_TOKEN`.
+    fn server_with_reset_token() -> Connection {
+        let server = default_server();
+        server
+            .set_local_tparam(
+                tparams::STATELESS_RESET_TOKEN,
+                TransportParameter::Bytes(RESET_TOKEN.to_vec()),
+            )
+            .unwrap();
+        server
+    }
+
+    /// Something that looks like a short header packet and ends in `token`.
+    fn stateless_reset(token: &[u8]) -> Datagram {
+        let mut reset = vec![0x40; 30];
+        reset.extend_from_slice(token);
+        Datagram::new(loopback(), loopback(), reset)
     }
 
     #[test]
-    // If we send a stop_sending to the peer, we should not accept more data from the peer.
-    fn do_not_accept_data_after_stop_sending() {
-        // Note that the two servers in this test will get different anti-replay filters.
-        // That's OK because we aren't testing anti-replay.
+    fn stateless_reset_client() {
         let mut client = default_client();
-        let mut server = default_server();
-        connect(&mut client, &mut server);
-
-        // create a stream
-        let stream_id = client.stream_create(StreamType::BiDi).unwrap();
-        client.stream_send(stream_id, &[0x00]).unwrap();
-        let out = client.process(None, now());
-        server.process(out.dgram(), now());
-
-        let stream_readable = |e| matches!(e, ConnectionEvent::RecvStreamReadable {..});
-        assert!(server.events().any(stream_readable));
+        let mut server = server_with_reset_token();
+        connect_force_idle(&mut client, &mut server);
 
-        // Send one more packet from client. The packet should arrive after the server
-        // has already requested stop_sending.
-        client.stream_send(stream_id, &[0x00]).unwrap();
-        let out_second_data_frame = client.process(None, now());
-        // Call stop sending.
+        client.process_input(stateless_reset(&RESET_TOKEN), now());
         assert_eq!(
-            Ok(()),
-            server.stream_stop_sending(stream_id, Error::NoError.code())
+            *client.state(),
+            State::Closed(ConnectionError::Transport(Error::StatelessReset))
         );
+        assert_eq!(client.process_output(now()), Output::None);
+    }
 
-        // Receive the second data frame. The frame should be ignored and now
-        // DataReadable events should be posted.
-        let out = server.process(out_second_data_frame.dgram(), now());
-        assert!(!server.events().any(stream_readable));
+    /// A stateless reset can use the connection ID that the client expects, in which
+    /// case it is only recognized after it fails to decrypt.
+    #[test]
+    fn stateless_reset_valid_cid() {
+        let mut client = default_client();
+        let mut server = server_with_reset_token();
+        connect_force_idle(&mut client, &mut server);
 
-        client.process(out.dgram(), now());
+        let mut reset = send_something(&mut server, now()).to_vec();
+        let token_start = reset.len() - RESET_TOKEN.len();
+        reset[token_start..].copy_from_slice(&RESET_TOKEN);
+        client.process_input(Datagram::new(loopback(), loopback(), reset), now());
         assert_eq!(
-            Err(Error::FinalSizeError),
-            client.stream_send(stream_id, &[0x00])
+            *client.state(),
+            State::Closed(ConnectionError::Transport(Error::StatelessReset))
         );
     }
 
     #[test]
-    // Server sends stop_sending, the client simultaneous sends reset.
-    fn simultaneous_stop_sending_and_reset() {
-        // Note that the two servers in this test will get different anti-replay filters.
-        // That's OK because we aren't testing anti-replay.
+    fn stateless_reset_wrong_token() {
         let mut client = default_client();
-        let mut server = default_server();
-        connect(&mut client, &mut server);
+        let mut server = server_with_reset_token();
+        connect_force_idle(&mut client, &mut server);
 
-        // create a stream
-        let stream_id = client.stream_create(StreamType::BiDi).unwrap();
-        client.stream_send(stream_id, &[0x00]).unwrap();
-        let out = client.process(None, now());
-        server.process(out.dgram(), now());
+        let dropped = client.stats().dropped_rx;
+        client.process_input(stateless_reset(&[0xcd; 16]), now());
+        assert_eq!(*client.state(), State::Confirmed);
+        assert_eq!(client.stats().dropped_rx, dropped + 1);
+    }
 
-        let stream_readable = |e| matches!(e, ConnectionEvent::RecvStreamReadable {..});
-        assert!(server.events().any(stream_readable));
+    /// Until the handshake is complete, a stateless reset is ignored.
+    #[test]
+    fn stateless_reset_during_handshake() {
+        let mut client = default_client();
+        let mut server = server_with_reset_token();
+        let c1 = client.process(None, now()).dgram();
+        let s1 = server.process(c1, now()).dgram();
+        client.process_input(s1.unwrap(), now());
+        // The client has the server's transport parameters, but it has yet to
+        // authenticate the server.
+        assert_eq!(*client.state(), State::Handshaking);
 
-        // The client resets the stream. The packet with reset should arrive after the server
-        // has already requested stop_sending.
-        client
-            .stream_reset_send(stream_id, Error::NoError.code())
-            .unwrap();
-        let out_reset_frame = client.process(None, now());
-        // Call stop sending.
-        assert_eq!(
-            Ok(()),
-            server.stream_stop_sending(stream_id, Error::NoError.code())
-        );
+        client.process_input(stateless_reset(&RESET_TOKEN), now());
+        assert_eq!(*client.state(), State::Handshaking);
+    }
 
-        // Receive the second data frame. The frame should be ignored and now
-        // DataReadable events should be posted.
-        let out = server.process(out_reset_frame.dgram(), now());
-        assert!(!server.events().any(stream_readable));
+    #[test]
+    fn migrate() {
+        const RTT: Duration = Duration::from_millis(100);
+        let fresh = default_client();
+        let new_local = SocketAddr::new(loopback().ip(), loopback().port() + 1);
 
-        // The client gets the STOP_SENDING frame.
-        client.process(out.dgram(), now());
+        let mut client = default_client();
+        let mut server = default_server();
+        assert_eq!(client.migrate(new_local), Err(Error::ConnectionState));
+        assert_eq!(server.migrate(new_local), Err(Error::WrongRole));
+        server.set_allow_migration(true).unwrap();
+        let now = connect_rtt_idle(&mut client, &mut server, RTT);
+        assert_eq!(client.loss_recovery.rtt(), RTT);
         assert_eq!(
-            Err(Error::InvalidStreamId),
-            client.stream_send(stream_id, &[0x00])
+            server.set_allow_migration(false),
+            Err(Error::ConnectionState)
         );
-    }
 
+        // The client sends a PATH_CHALLENGE from the new address, but it doesn't
+        // move until the server answers.
+        let client_cid = client.path().unwrap().remote_cid().clone();
+        let before = client.paths().next().unwrap();
+        assert_eq!(before.id, 0);
+        client.migrate(new_local).unwrap();
+        let challenge = client.process(None, now).dgram().unwrap();
+        let challenge_len = challenge.len();
+        assert_eq!(challenge.source(), new_local);
+        assert_eq!(challenge.destination(), loopback());
+        // Until it moves, the client reports the old path, unchanged.
+        assert_eq!(client.paths().collect::<Vec<_>>(), vec![before.clone()]);
+        assert_eq!(client.loss_recovery.rtt(), RTT);
+
+        // The server answers on the new path, with a challenge of its own.
+        let response = server.process(Some(challenge), now).dgram().unwrap();
+        assert_eq!(response.destination(), new_local);
+        assert_eq!(server.paths().next().unwrap().remote, loopback());
+
+        // That moves the client, which starts again on congestion control and RTT.
+        let response_len = response.len() as u64;
+        let response = client.process(Some(response), now).dgram().unwrap();
+        // The new path has its own id and only counts what was sent and received on it.
+        let after = client.paths().collect::<Vec<_>>();
+        assert_eq!(after.len(), 2);
+        assert_eq!(after[0].id, 1);
+        assert!(after[0].current);
+        assert_eq!(after[0].local, new_local);
+        assert_eq!(after[0].remote, before.remote);
+        assert_eq!(after[0].bytes_sent, (challenge_len + response.len()) as u64);
+        assert_eq!(after[0].bytes_received, response_len);
+        // The old path is still reported, as it was when the client moved.
+        assert_eq!(
+            after[1],
+            PathStats {
+                current: false,
+                ..before
+            }
+        );
+        // The client uses a connection ID on the new path that it didn't use before.
+        assert_ne!(client.path().unwrap().remote_cid(), &client_cid);
+        assert_eq!(client.loss_recovery.rtt(), fresh.loss_recovery.rtt());
+        assert_eq!(client.loss_recovery.cwnd(), fresh.loss_recovery.cwnd());
+
+        // The client answers the server's challenge from the new address, which
+        // moves the server too.
+        assert_eq!(response.source(), new_local);
+        server.process_input(response, now);
+        assert_eq!(server.paths().next().unwrap().remote, new_local);
+        assert_eq!(server.loss_recovery.rtt(), fresh.loss_recovery.rtt());
+        let d = send_something(&mut server, now);
+        assert_eq!(d.destination(), new_local);
+    }
+
+    /// Each path has its own RTT: the path that the client moved away from keeps
+    /// what was measured on it, and the new path measures its own.
     #[test]
-    fn test_client_fin_reorder() {
+    fn migrate_path_recovery() {
+        const RTT: Duration = Duration::from_millis(100);
+        const NEW_RTT: Duration = Duration::from_millis(20);
+        let new_local = SocketAddr::new(loopback().ip(), loopback().port() + 1);
+
         let mut client = default_client();
         let mut server = default_server();
+        server.set_allow_migration(true).unwrap();
+        let mut now = connect_rtt_idle(&mut client, &mut server, RTT);
+        let before = client.paths().next().unwrap();
+        assert_eq!(before.recovery.rtt, RTT);
+
+        client.migrate(new_local).unwrap();
+        let challenge = client.process(None, now).dgram().unwrap();
+        let response = server.process(Some(challenge), now).dgram().unwrap();
+        let response = client.process(Some(response), now).dgram().unwrap();
+        server.process_input(response, now);
+
+        // Delivering these out of order makes the server acknowledge them straight
+        // away, which gives the client an RTT sample on the new path.
+        let p1 = send_something(&mut client, now);
+        let p2 = send_something(&mut client, now);
+        now += NEW_RTT / 2;
+        server.process_input(p2, now);
+        let ack = server.process(Some(p1), now).dgram().unwrap();
+        now += NEW_RTT / 2;
+        client.process_input(ack, now);
+
+        let paths = client.paths().collect::<Vec<_>>();
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].current);
+        assert_eq!(paths[0].recovery.rtt, NEW_RTT);
+        assert_eq!(paths[0].recovery.min_rtt, NEW_RTT);
+        assert!(!paths[1].current);
+        assert_eq!(paths[1].id, before.id);
+        assert_eq!(paths[1].recovery, before.recovery);
+        assert_ne!(paths[0].recovery.cwnd, 0);
+
+        // The server keeps the client's old address too.
+        let server_paths = server.paths().collect::<Vec<_>>();
+        assert_eq!(server_paths.len(), 2);
+        assert_eq!(server_paths[0].remote, new_local);
+        assert_eq!(server_paths[1].remote, loopback());
+        assert!(!server_paths[1].current);
+    }
+
+    /// A download keeps going while the client moves: some of it arrives at the
+    /// old address, the rest at the new one, and none of it is lost.
+    #[test]
+    fn migrate_during_transfer() {
+        const LEN: usize = 200_000;
+        let new_local = SocketAddr::new(loopback().ip(), loopback().port() + 1);
 
-        // Send ClientHello.
-        let client_hs = client.process(None, now());
-        assert!(client_hs.as_dgram_ref().is_some());
+        let mut client = default_client();
+        let mut server = default_server();
+        server.set_allow_migration(true).unwrap();
+        connect_force_idle(&mut client, &mut server);
+        let mut now = now();
 
-        let server_hs = server.process(client_hs.dgram(), now());
-        assert!(server_hs.as_dgram_ref().is_some()); // ServerHello, etc...
+        let stream_id = server.stream_create(StreamType::UniDi).unwrap();
+        let data = (0..LEN)
+            .map(|i| u8::try_from(i % 251).unwrap())
+            .collect::<Vec<_>>();
+        let mut sent = 0;
+        let mut received = Vec::new();
+        let mut to_old = 0;
+        let mut to_new = 0;
+        let mut to_server = Vec::new();
+        for _ in 0..1000 {
+            if sent < LEN {
+                sent += server.stream_send(stream_id, &data[sent..]).unwrap();
+                if sent == LEN {
+                    server.stream_close_send(stream_id).unwrap();
+                }
+            }
+            for d in to_server.drain(..) {
+                server.process_input(d, now);
+            }
+            while let Output::Datagram(d) = server.process_output(now) {
+                if d.destination() == new_local {
+                    to_new += d.len();
+                } else {
+                    to_old += d.len();
+                }
+                client.process_input(d, now);
+            }
+            while let Output::Datagram(d) = client.process_output(now) {
+                to_server.push(d);
+            }
 
-        let client_ack = client.process(server_hs.dgram(), now());
-        assert!(client_ack.as_dgram_ref().is_some());
+            let mut buf = [0; 4096];
+            if let Ok((n, fin)) = client.stream_recv(stream_id, &mut buf) {
+                received.extend_from_slice(&buf[..n]);
+                if fin {
+                    break;
+                }
+            }
+            // Move once a good part of the data has arrived.
+            if client.probe.is_none()
+                && client.paths().next().unwrap().local != new_local
+                && received.len() > LEN / 4
+            {
+                client.migrate(new_local).unwrap();
+            }
+            now += Duration::from_millis(10);
+        }
 
-        let server_out = server.process(client_ack.dgram(), now());
-        assert!(server_out.as_dgram_ref().is_none());
+        assert_eq!(received, data);
+        assert_eq!(client.paths().next().unwrap().local, new_local);
+        assert_eq!(server.paths().next().unwrap().remote, new_local);
+        assert!(to_old > LEN / 4);
+        assert!(to_new > LEN / 4);
+        assert!(client.state().connected());
+    }
 
-        assert!(maybe_authenticate(&mut client));
-        assert_eq!(*client.state(), State::Connected);
+    /// A client that has its address changed by a NAT doesn't know that it has
+    /// moved, but the server sees packets from a new address.  It keeps using the
+    /// old path until the client answers a challenge on the new one.
+    #[test]
+    fn migrate_nat_rebinding() {
+        let new_remote = SocketAddr::new(loopback().ip(), loopback().port() + 1);
+        let nat = |d: Datagram| Datagram::new(new_remote, d.destination(), &d[..]);
+        let unnat = |d: Datagram| Datagram::new(d.source(), loopback(), &d[..]);
 
-        let client_fin = client.process(None, now());
-        assert!(client_fin.as_dgram_ref().is_some());
+        let mut client = default_client();
+        let mut server = default_server();
+        server.set_allow_migration(true).unwrap();
+        connect_force_idle(&mut client, &mut server);
+        let now = now();
 
-        let client_stream_id = client.stream_create(StreamType::UniDi).unwrap();
-        client.stream_send(client_stream_id, &[1, 2, 3]).unwrap();
-        let client_stream_data = client.process(None, now());
-        assert!(client_stream_data.as_dgram_ref().is_some());
+        // What arrives on the new path is used straight away.
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[6; 300]).unwrap();
+        let d = client.process(None, now).dgram().unwrap();
+        let challenge = server.process(Some(nat(d)), now).dgram().unwrap();
+        let mut buf = [0; 300];
+        assert_eq!(
+            server.stream_recv(stream_id, &mut buf).unwrap(),
+            (300, false)
+        );
+        assert_eq!(challenge.destination(), new_remote);
+        assert_eq!(server.paths().next().unwrap().remote, loopback());
 
-        // Now stream data gets before client_fin
-        let server_out = server.process(client_stream_data.dgram(), now());
-        assert!(server_out.as_dgram_ref().is_none()); // the packet will be discarded
+        // Anything else still goes to the old address.
+        let d = send_something(&mut server, now);
+        assert_eq!(d.destination(), loopback());
 
-        assert_eq!(*server.state(), State::Handshaking);
-        let server_out = server.process(client_fin.dgram(), now());
-        assert!(server_out.as_dgram_ref().is_some());
+        let response = client.process(Some(unnat(challenge)), now).dgram().unwrap();
+        server.process_input(nat(response), now);
+        assert_eq!(server.paths().next().unwrap().remote, new_remote);
+        let d = send_something(&mut server, now);
+        assert_eq!(d.destination(), new_remote);
     }
 
+    /// If nothing answers the challenges on a new path, the server gives up on it.
     #[test]
-    fn pto_works_basic() {
+    fn migrate_validation_fails() {
+        let other = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 443);
+
         let mut client = default_client();
         let mut server = default_server();
+        server.set_allow_migration(true).unwrap();
         connect_force_idle(&mut client, &mut server);
+        let start = now();
+        let mut now = start;
 
-        let mut now = now();
-
-        let res = client.process(None, now);
-        assert_eq!(res, Output::Callback(LOCAL_IDLE_TIMEOUT));
-
-        // Send data on two streams
-        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
-        assert_eq!(client.stream_send(2, b"hello").unwrap(), 5);
-        assert_eq!(client.stream_send(2, b" world").unwrap(), 6);
-
-        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 6);
-        assert_eq!(client.stream_send(6, b"there!").unwrap(), 6);
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[6; 300]).unwrap();
+        let d = client.process(None, now).dgram().unwrap();
+        server.process_input(Datagram::new(other, d.destination(), &d[..]), now);
+
+        let mut challenges = 0;
+        while now < start + LOCAL_IDLE_TIMEOUT / 2 {
+            match server.process_output(now) {
+                Output::Datagram(d) => {
+                    if d.destination() == other {
+                        challenges += 1;
+                    }
+                }
+                Output::Callback(t) => now += t,
+                Output::None => break,
+            }
+        }
+        assert_eq!(challenges, MAX_PATH_CHALLENGES);
+        assert!(server.probe.is_none());
+        assert_eq!(server.paths().next().unwrap().remote, loopback());
+        assert_eq!(*server.state(), State::Confirmed);
+    }
 
-        // Send a packet after some time.
-        now += Duration::from_secs(10);
-        let out = client.process(None, now);
-        assert!(out.dgram().is_some());
+    /// Without a connection ID that hasn't been used, the client stays put.
+    #[test]
+    fn migrate_no_unused_cid() {
+        let mut client = default_client();
+        let mut server = default_server();
+        server.set_allow_migration(true).unwrap();
+        connect(&mut client, &mut server);
+        client.remote_cids = RemoteConnectionIds::default();
+        let new_local = SocketAddr::new(loopback().ip(), loopback().port() + 1);
+        assert_eq!(client.migrate(new_local), Err(Error::InvalidMigration));
+        assert!(client.probe.is_none());
+        assert_eq!(client.paths().next().unwrap().local, loopback());
+    }
 
-        // Nothing to do, should return callback
-        let out = client.process(None, now);
-        assert!(matches!(out, Output::Callback(_)));
+    #[test]
+    fn migrate_disabled() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let new_local = SocketAddr::new(loopback().ip(), loopback().port() + 1);
+        assert_eq!(client.migrate(new_local), Err(Error::InvalidMigration));
+        assert_eq!(client.paths().next().unwrap().local, loopback());
+    }
 
-        // One second later, it should want to send PTO packet
-        now += AT_LEAST_PTO;
-        let out = client.process(None, now);
+    #[test]
+    fn send_ping_keeps_alive() {
+        let mut client = default_client();
+        let mut server = default_server();
+        assert_eq!(client.send_ping(), Err(Error::ConnectionState));
+        connect(&mut client, &mut server);
+        let now = now();
 
-        let frames = server.test_process_input(out.dgram().unwrap(), now);
+        // Nothing to send when idle, until a PING is requested.
+        assert!(client.process(None, now).dgram().is_none());
+        client.send_ping().unwrap();
+        let ping = client.process(None, now).dgram();
+        assert!(ping.is_some());
+        assert!(client.process(None, now).dgram().is_none());
 
-        assert!(frames.iter().all(|(_, sp)| *sp == PNSpace::ApplicationData));
-        assert!(frames.iter().any(|(f, _)| *f == Frame::Ping));
-        assert!(frames
-            .iter()
-            .any(|(f, _)| matches!(f, Frame::Stream { stream_id, .. } if stream_id.as_u64() == 2)));
-        assert!(frames
-            .iter()
-            .any(|(f, _)| matches!(f, Frame::Stream { stream_id, .. } if stream_id.as_u64() == 6)));
+        // The server acknowledges it, which resets the client's idle timer.
+        let later = now + LOCAL_IDLE_TIMEOUT / 2;
+        server.process_input(ping.unwrap(), later);
+        let ack = server.process(None, later + ACK_DELAY).dgram();
+        assert!(ack.is_some());
+        client.process_input(ack.unwrap(), later + ACK_DELAY);
+        let _ = client.process(None, now + LOCAL_IDLE_TIMEOUT + Duration::from_millis(1));
+        assert!(client.state().connected());
     }
 
     #[test]
-    fn pto_works_full_cwnd() {
+    fn max_data() {
         let mut client = default_client();
         let mut server = default_server();
-        connect_force_idle(&mut client, &mut server);
 
-        let res = client.process(None, now());
-        assert_eq!(res, Output::Callback(LOCAL_IDLE_TIMEOUT));
+        const SMALL_MAX_DATA: usize = 16383;
 
-        // Send lots of data.
-        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
-        let (dgrams, now) = fill_cwnd(&mut client, 2, now());
-        assert_full_cwnd(&dgrams, POST_HANDSHAKE_CWND);
+        server
+            .set_local_tparam(
+                tparams::INITIAL_MAX_DATA,
+                TransportParameter::Integer(SMALL_MAX_DATA.try_into().unwrap()),
+            )
+            .unwrap();
 
-        // Fill the CWND after waiting for a PTO.
-        let (dgrams, now) = fill_cwnd(&mut client, 2, now + AT_LEAST_PTO);
-        assert_eq!(dgrams.len(), 2); // Two packets in the PTO.
+        connect(&mut client, &mut server);
 
-        // All (2) datagrams contain one PING frame and at least one STREAM frame.
-        for d in dgrams {
-            assert_eq!(d.len(), PATH_MTU_V6);
-            let frames = server.test_process_input(d, now);
-            assert_eq!(
-                frames
-                    .iter()
-                    .filter(|i| matches!(i, (Frame::Ping, PNSpace::ApplicationData)))
-                    .count(),
-                1
-            );
-            assert!(
-                frames
-                    .iter()
-                    .filter(|i| matches!(i, (Frame::Stream { .. }, PNSpace::ApplicationData)))
-                    .count()
-                    >= 1
-            );
-        }
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(stream_id, 2);
+        assert_eq!(
+            client.stream_avail_send_space(stream_id).unwrap(),
+            SMALL_MAX_DATA
+        );
+        assert_eq!(
+            client
+                .stream_send(stream_id, &[b'a'; RX_STREAM_DATA_WINDOW as usize])
+                .unwrap(),
+            SMALL_MAX_DATA
+        );
+        let evts = client.events().collect::<Vec<_>>();
+        assert_eq!(evts.len(), 2); // SendStreamWritable, StateChange(connected)
+        assert_eq!(client.stream_send(stream_id, b"hello").unwrap(), 0);
+        let ss = client.send_streams.get_mut(stream_id.into()).unwrap();
+        ss.mark_as_sent(0, 4096, false);
+        ss.mark_as_acked(0, 4096, false);
+
+        // no event because still limited by conn max data
+        let evts = client.events().collect::<Vec<_>>();
+        assert_eq!(evts.len(), 0);
+
+        // increase max data
+        client.handle_max_data(100_000);
+        assert_eq!(client.stream_avail_send_space(stream_id).unwrap(), 49152);
+        let evts = client.events().collect::<Vec<_>>();
+        assert_eq!(evts.len(), 1);
+        assert!(matches!(evts[0], ConnectionEvent::SendStreamWritable{..}));
     }
 
     #[test]
-    #[allow(clippy::cognitive_complexity)]
-    fn pto_works_ping() {
+    fn remote_stream_limits() {
         let mut client = default_client();
         let mut server = default_server();
-        connect_force_idle(&mut client, &mut server);
-
-        let now = now();
-
-        let res = client.process(None, now);
-        assert_eq!(res, Output::Callback(LOCAL_IDLE_TIMEOUT));
-
-        // Send "zero" pkt
-        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
-        assert_eq!(client.stream_send(2, b"zero").unwrap(), 4);
-        let pkt0 = client.process(None, now + Duration::from_secs(10));
-        assert!(matches!(pkt0, Output::Datagram(_)));
-
-        // Send "one" pkt
-        assert_eq!(client.stream_send(2, b"one").unwrap(), 3);
-        let pkt1 = client.process(None, now + Duration::from_secs(10));
-
-        // Send "two" pkt
-        assert_eq!(client.stream_send(2, b"two").unwrap(), 3);
-        let pkt2 = client.process(None, now + Duration::from_secs(10));
-
-        // Send "three" pkt
-        assert_eq!(client.stream_send(2, b"three").unwrap(), 5);
-        let pkt3 = client.process(None, now + Duration::from_secs(10));
-
-        // Nothing to do, should return callback
-        let out = client.process(None, now + Duration::from_secs(10));
-        // Check callback delay is what we expect
-        assert!(matches!(out, Output::Callback(x) if x == Duration::from_millis(45)));
+        assert_eq!(client.remote_stream_limits(), StreamLimits::default());
 
-        // Process these by server, skipping pkt0
-        let srv0_pkt1 = server.process(pkt1.dgram(), now + Duration::from_secs(10));
-        // ooo, ack client pkt 1
-        assert!(matches!(srv0_pkt1, Output::Datagram(_)));
+        server
+            .set_local_tparam(
+                tparams::INITIAL_MAX_STREAMS_UNI,
+                TransportParameter::Integer(3),
+            )
+            .unwrap();
+        server
+            .set_local_tparam(
+                tparams::INITIAL_MAX_STREAM_DATA_UNI,
+                TransportParameter::Integer(1000),
+            )
+            .unwrap();
+        server
+            .set_local_tparam(tparams::INITIAL_MAX_DATA, TransportParameter::Integer(5000))
+            .unwrap();
+        connect(&mut client, &mut server);
 
-        // process pkt2 (no ack yet)
-        let srv2 = server.process(
-            pkt2.dgram(),
-            now + Duration::from_secs(10) + Duration::from_millis(20),
+        let limits = client.remote_stream_limits();
+        assert_eq!(
+            limits,
+            StreamLimits {
+                max_streams_bidi: LOCAL_STREAM_LIMIT_BIDI,
+                max_streams_uni: 3,
+                initial_max_stream_data_bidi_remote: RX_STREAM_DATA_WINDOW,
+                initial_max_stream_data_uni: 1000,
+                max_data: 5000,
+            }
         );
-        assert!(matches!(srv2, Output::Callback(_)));
 
-        // process pkt3 (acked)
-        let srv2 = server.process(
-            pkt3.dgram(),
-            now + Duration::from_secs(10) + Duration::from_millis(20),
-        );
-        // ack client pkt 2 & 3
-        assert!(matches!(srv2, Output::Datagram(_)));
+        // Updates from the peer are reflected.
+        client.handle_max_data(8000);
+        assert_eq!(client.remote_stream_limits().max_data, 8000);
+        let frame = Frame::MaxStreams {
+            stream_type: StreamType::UniDi,
+            maximum_streams: StreamIndex::new(10),
+        };
+        assert!(client.input_frame(PacketType::Short, frame, now()).is_ok());
+        assert_eq!(client.remote_stream_limits().max_streams_uni, 10);
+    }
 
-        // client processes ack
-        let pkt4 = client.process(
-            srv2.dgram(),
-            now + Duration::from_secs(10) + Duration::from_millis(40),
-        );
-        // client resends data from pkt0
-        assert!(matches!(pkt4, Output::Datagram(_)));
+    #[test]
+    fn stream_allowance() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
 
-        // server sees ooo pkt0 and generates ack
-        let srv_pkt2 = server.process(
-            pkt0.dgram(),
-            now + Duration::from_secs(10) + Duration::from_millis(40),
+        client.set_stream_allowance(StreamType::BiDi, 2);
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 4);
+        assert_eq!(
+            client.stream_create(StreamType::BiDi),
+            Err(Error::StreamsExhausted)
         );
-        assert!(matches!(srv_pkt2, Output::Datagram(_)));
-
-        // Orig data is acked
-        let pkt5 = client.process(
-            srv_pkt2.dgram(),
-            now + Duration::from_secs(10) + Duration::from_millis(40),
+        assert_eq!(
+            client.stream_create_pending(StreamType::BiDi),
+            Err(Error::StreamsExhausted)
         );
-        assert!(matches!(pkt5, Output::Callback(_)));
 
-        // PTO expires. No unacked data. Only send PING.
-        let pkt6 = client.process(
-            None,
-            now + Duration::from_secs(10) + Duration::from_millis(110),
+        // More credit from the server doesn't help.
+        let frame = Frame::MaxStreams {
+            stream_type: StreamType::BiDi,
+            maximum_streams: StreamIndex::new(1000),
+        };
+        assert!(client.input_frame(PacketType::Short, frame, now()).is_ok());
+        assert_eq!(
+            client.stream_create(StreamType::BiDi),
+            Err(Error::StreamsExhausted)
         );
 
-        let frames = server.test_process_input(
-            pkt6.dgram().unwrap(),
-            now + Duration::from_secs(10) + Duration::from_millis(110),
-        );
+        assert_eq!(client.next_stream_id(StreamType::BiDi), 8);
 
-        assert_eq!(frames[0], (Frame::Ping, PNSpace::ApplicationData));
+        // Other stream types are not affected.
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
     }
 
     #[test]
-    fn pto_initial() {
-        let mut now = now();
-
-        qdebug!("---- client: generate CH");
+    fn flow_control_stall() {
+        const STALL_TIMEOUT: Duration = Duration::from_secs(5);
         let mut client = default_client();
-        let pkt1 = client.process(None, now).dgram();
-        assert!(pkt1.is_some());
-        assert_eq!(pkt1.clone().unwrap().len(), PATH_MTU_V6);
-
-        let out = client.process(None, now);
-        assert_eq!(out, Output::Callback(Duration::from_millis(120)));
-
-        // Resend initial after PTO.
-        now += Duration::from_millis(120);
-        let pkt2 = client.process(None, now).dgram();
-        assert!(pkt2.is_some());
-        assert_eq!(pkt2.unwrap().len(), PATH_MTU_V6);
-
-        let pkt3 = client.process(None, now).dgram();
-        assert!(pkt3.is_some());
-        assert_eq!(pkt3.unwrap().len(), PATH_MTU_V6);
+        let mut server = default_server();
+        server
+            .set_local_tparam(
+                tparams::INITIAL_MAX_STREAM_DATA_UNI,
+                TransportParameter::Integer(1000),
+            )
+            .unwrap();
+        connect(&mut client, &mut server);
+        client.set_flow_control_stall_timeout(Some(STALL_TIMEOUT));
 
-        let out = client.process(None, now);
-        // PTO has doubled.
-        assert_eq!(out, Output::Callback(Duration::from_millis(240)));
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(client.stream_send(stream_id, &[0; 2000]).unwrap(), 1000);
+        let mut acks = Vec::new();
+        let mut out = client.process(None, now()).dgram();
+        while let Some(d) = out {
+            acks.extend(server.process(Some(d), now()).dgram());
+            out = client.process(None, now()).dgram();
+        }
 
-        // Server process the first initial pkt.
-        let mut server = default_server();
-        let out = server.process(pkt1, now).dgram();
-        assert!(out.is_some());
+        // The stall is timed from when STREAM_DATA_BLOCKED is acknowledged.
+        let start = now() + ACK_DELAY;
+        acks.extend(server.process(None, start).dgram());
+        assert!(!acks.is_empty());
+        for ack in acks {
+            client.process_input(ack, start);
+        }
 
-        // Client receives ack for the first initial packet as well a Handshake packet.
-        // After the handshake packet the initial keys and the crypto stream for the initial
-        // packet number space will be discarded.
-        // Here only an ack for the Handshake packet will be sent.
-        now += Duration::from_millis(10);
-        let out = client.process(out, now).dgram();
-        assert!(out.is_some());
+        // The server never reads, so it never increases the limit.
+        let _ = client.process(None, start + STALL_TIMEOUT / 2);
+        assert_eq!(client.stats().flow_control_stalls, 0);
+        let _ = client.process(None, start + STALL_TIMEOUT);
+        let stall = crate::stats::FlowControlStall {
+            stream_id: Some(stream_id),
+            limit: 1000,
+            duration: STALL_TIMEOUT,
+        };
+        assert_eq!(client.stats().flow_control_stalls, 1);
+        let stalled = ConnectionEvent::FlowControlStalled { stall };
+        assert!(client.events().any(|e| e == stalled));
 
-        // We do not have PTO for the resent initial packet any more, because keys are discarded.
-        // The timeout will be an idle time out of LOCAL_IDLE_TIMEOUT seconds.
-        let out = client.process(None, now);
-        assert_eq!(out, Output::Callback(LOCAL_IDLE_TIMEOUT));
+        // That is only reported once.
+        let _ = client.process(None, start + STALL_TIMEOUT * 2);
+        assert_eq!(client.stats().flow_control_stalls, 1);
+        assert!(client.state().connected());
     }
 
+    // Test that we split crypto data if they cannot fit into one packet.
+    // To test this we will use a long server certificate.
     #[test]
-    fn pto_handshake() {
-        let mut now = now();
-        // start handshake
+    fn test_crypto_frame_split() {
         let mut client = default_client();
-        let mut server = default_server();
+        let mut server = Connection::new_server(
+            test_fixture::LONG_CERT_KEYS,
+            test_fixture::DEFAULT_ALPN,
+            &test_fixture::anti_replay(),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(6))),
+        )
+        .expect("create a server");
 
-        let pkt = client.process(None, now).dgram();
-        let cb = client.process(None, now).callback();
-        assert_eq!(cb, Duration::from_millis(120));
+        let client1 = client.process(None, now());
+        assert!(client1.as_dgram_ref().is_some());
 
-        now += Duration::from_millis(10);
-        let pkt = server.process(pkt, now).dgram();
+        // The entire server flight doesn't fit in a single packet because the
+        // certificate is large, therefore the server will produce 2 packets.
+        let server1 = server.process(client1.dgram(), now());
+        assert!(server1.as_dgram_ref().is_some());
+        let server2 = server.process(None, now());
+        assert!(server2.as_dgram_ref().is_some());
 
-        now += Duration::from_millis(10);
-        let pkt = client.process(pkt, now).dgram();
+        let client2 = client.process(server1.dgram(), now());
+        // This is an ack.
+        assert!(client2.as_dgram_ref().is_some());
+        // The client might have the certificate now, so we can't guarantee that
+        // this will work.
+        let auth1 = maybe_authenticate(&mut client);
+        assert_eq!(*client.state(), State::Handshaking);
 
-        let cb = client.process(None, now).callback();
-        assert_eq!(cb, LOCAL_IDLE_TIMEOUT);
+        // let server process the ack for the first packet.
+        let server3 = server.process(client2.dgram(), now());
+        assert!(server3.as_dgram_ref().is_none());
 
-        now += Duration::from_millis(10);
-        let pkt = server.process(pkt, now).dgram();
-        assert!(pkt.is_none());
+        // Consume the second packet from the server.
+        let client3 = client.process(server2.dgram(), now());
 
-        now += Duration::from_millis(10);
-        client.authenticated(AuthenticationStatus::Ok, now);
+        // Check authentication.
+        let auth2 = maybe_authenticate(&mut client);
+        assert!(auth1 ^ auth2);
+        // Now client has all data to finish handshake.
+        assert_eq!(*client.state(), State::Connected);
 
-        qdebug!("---- client: SH..FIN -> FIN");
-        let pkt1 = client.process(None, now).dgram();
-        assert!(pkt1.is_some());
+        let client4 = client.process(server3.dgram(), now());
+        // One of these will contain data depending on whether Authentication was completed
+        // after the first or second server packet.
+        assert!(client3.as_dgram_ref().is_some() ^ client4.as_dgram_ref().is_some());
 
-        let cb = client.process(None, now).callback();
-        assert_eq!(cb, Duration::from_millis(60));
+        let _ = server.process(client3.dgram(), now());
+        let _ = server.process(client4.dgram(), now());
 
-        // Wait for PTO to expire and resend a handshake packet
-        now += Duration::from_millis(60);
-        let pkt2 = client.process(None, now).dgram();
-        assert!(pkt2.is_some());
-
-        // Get a second PTO packet.
-        let pkt3 = client.process(None, now).dgram();
-        assert!(pkt3.is_some());
-
-        // PTO has been doubled.
-        let cb = client.process(None, now).callback();
-        assert_eq!(cb, Duration::from_millis(120));
-
-        now += Duration::from_millis(10);
-        // Server receives the first packet.
-        // The output will be a Handshake packet with an ack and a app pn space packet with
-        // HANDSHAKE_DONE.
-        let pkt = server.process(pkt1, now).dgram();
-        assert!(pkt.is_some());
-
-        // Check that the PTO packets (pkt2, pkt3) have a Handshake and an app pn space packet.
-        // The server has discarded the Handshake keys already, therefore the handshake packet
-        // will be dropped.
-        let dropped_before = server.stats().dropped_rx;
-        let frames = server.test_process_input(pkt2.unwrap(), now);
-        assert_eq!(1, server.stats().dropped_rx - dropped_before);
-        assert_eq!(frames[0], (Frame::Ping, PNSpace::ApplicationData));
+        assert_eq!(*client.state(), State::Connected);
+        assert_eq!(*server.state(), State::Confirmed);
+    }
 
-        let dropped_before = server.stats().dropped_rx;
-        let frames = server.test_process_input(pkt3.unwrap(), now);
-        assert_eq!(1, server.stats().dropped_rx - dropped_before);
-        assert_eq!(frames[0], (Frame::Ping, PNSpace::ApplicationData));
+    /// Enough padding to need several packets, like a ClientHello with a
+    /// post-quantum key share.
+    const HANDSHAKE_PADDING: usize = 6000;
 
-        now += Duration::from_millis(10);
-        // Client receive ack for the first packet
-        let cb = client.process(pkt, now).callback();
-        // Ack delay timer for the packet carrying HANDSHAKE_DONE.
-        assert_eq!(cb, ACK_DELAY);
+    /// Get everything that `c` has to send, waiting for the pacer as needed.
+    /// This stops when `c` has nothing to do but wait for a timer.
+    fn send_flight(c: &mut Connection, mut now: Instant) -> (Vec<Datagram>, Instant) {
+        let mut dgrams = Vec::new();
+        loop {
+            match c.process_output(now) {
+                Output::Datagram(d) => dgrams.push(d),
+                Output::Callback(t) if t < c.loss_recovery.rtt() => now += t,
+                _ => return (dgrams, now),
+            }
+        }
+    }
 
-        // Let the ack timer expire.
-        now += cb;
-        let out = client.process(None, now).dgram();
-        assert!(out.is_some());
-        let cb = client.process(None, now).callback();
-        // The handshake keys are discarded, but now we're back to the idle timeout.
-        // We don't send another PING because the handshake space is done and there
-        // is nothing to probe for.
-        assert_eq!(cb, LOCAL_IDLE_TIMEOUT - ACK_DELAY);
+    /// Deliver everything in `dgrams` to `c`.
+    fn receive_flight(c: &mut Connection, dgrams: Vec<Datagram>, now: Instant) {
+        for d in dgrams {
+            c.process_input(d, now);
+        }
     }
 
     #[test]
-    fn test_pto_handshake_and_app_data() {
-        let mut now = now();
-        qdebug!("---- client: generate CH");
+    fn large_client_hello() {
         let mut client = default_client();
-        let pkt = client.process(None, now);
-
-        now += Duration::from_millis(10);
-        qdebug!("---- server: CH -> SH, EE, CERT, CV, FIN");
+        client.set_handshake_padding(HANDSHAKE_PADDING).unwrap();
         let mut server = default_server();
-        let pkt = server.process(pkt.dgram(), now);
-
-        now += Duration::from_millis(10);
-        qdebug!("---- client: cert verification");
-        let pkt = client.process(pkt.dgram(), now);
-
-        now += Duration::from_millis(10);
-        let _pkt = server.process(pkt.dgram(), now);
-
-        now += Duration::from_millis(10);
-        client.authenticated(AuthenticationStatus::Ok, now);
-
-        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
-        assert_eq!(client.stream_send(2, b"zero").unwrap(), 4);
-        qdebug!("---- client: SH..FIN -> FIN and 1RTT packet");
-        let pkt1 = client.process(None, now).dgram();
-        assert!(pkt1.is_some());
-
-        // Get PTO timer.
-        let out = client.process(None, now);
-        assert_eq!(out, Output::Callback(Duration::from_millis(60)));
 
-        // Wait for PTO to expire and resend a handshake and 1rtt packet
-        now += Duration::from_millis(60);
-        let pkt2 = client.process(None, now).dgram();
-        assert!(pkt2.is_some());
+        let (initials, now) = send_flight(&mut client, now());
+        assert!(initials.len() * PATH_MTU_V6 > HANDSHAKE_PADDING);
+        assert!(initials.iter().all(|d| d.len() == PATH_MTU_V6));
 
-        now += Duration::from_millis(10);
-        let frames = server.test_process_input(pkt2.unwrap(), now);
+        // Until the server has all of the ClientHello, it can only acknowledge it.
+        let (last, rest) = initials.split_last().unwrap();
+        for d in rest {
+            let ack = server.process(Some(d.clone()), now).dgram();
+            assert!(ack.map_or(true, |d| d.len() < PATH_MTU_V6));
+            assert!(!server.crypto.states.has_keys(PNSpace::Handshake).1);
+        }
+        server.process_input(last.clone(), now);
+        assert!(server.crypto.states.has_keys(PNSpace::Handshake).1);
 
-        assert!(matches!(frames[0], (Frame::Ping, PNSpace::Handshake)));
-        assert!(matches!(
-            frames[1],
-            (Frame::Crypto { .. }, PNSpace::Handshake)
-        ));
-        assert!(matches!(frames[2], (Frame::Ping, PNSpace::ApplicationData)));
-        assert!(matches!(
-            frames[3],
-            (Frame::Stream { .. }, PNSpace::ApplicationData)
-        ));
+        let (flight, now) = send_flight(&mut server, now);
+        assert!(!flight.is_empty());
+        assert!(!server.stats().handshake.amplification_limited());
+        receive_flight(&mut client, flight, now);
+        assert!(maybe_authenticate(&mut client));
+        let (flight, now) = send_flight(&mut client, now);
+        receive_flight(&mut server, flight, now);
+        assert_eq!(*client.state(), State::Connected);
+        assert_eq!(*server.state(), State::Confirmed);
     }
 
+    /// A server can't decide whether to accept 0-RTT until it has all of the
+    /// ClientHello.
     #[test]
-    fn pto_count_increase_across_spaces() {
-        let mut now = now();
-        qdebug!("---- client: generate CH");
+    fn large_client_hello_0rtt() {
         let mut client = default_client();
-        let pkt = client.process(None, now).dgram();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let token = exchange_ticket(&mut client, &mut server, now());
 
-        now += Duration::from_millis(10);
-        qdebug!("---- server: CH -> SH, EE, CERT, CV, FIN");
+        let mut client = default_client();
+        client
+            .set_resumption_token(now(), &token[..])
+            .expect("should set token");
+        client.set_handshake_padding(HANDSHAKE_PADDING).unwrap();
         let mut server = default_server();
-        let pkt = server.process(pkt, now).dgram();
 
-        now += Duration::from_millis(10);
-        qdebug!("---- client: cert verification");
-        let pkt = client.process(pkt, now).dgram();
+        let (hs, now) = send_flight(&mut client, now());
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[1, 2, 3]).unwrap();
+        let (zero_rtt, now) = send_flight(&mut client, now);
+        receive_flight(&mut server, hs, now);
+        receive_flight(&mut server, zero_rtt, now);
+        assert_eq!(server.zero_rtt_state, ZeroRttState::AcceptedServer);
+        assert!(server
+            .events()
+            .any(|e| matches!(e, ConnectionEvent::NewStream { .. })));
+    }
 
-        now += Duration::from_millis(10);
-        let _pkt = server.process(pkt, now);
+    /// Until the client's address is validated, the server can only send a multiple
+    /// of what it received, so a large server flight takes more than one round trip.
+    #[test]
+    fn large_server_flight() {
+        let mut client = default_client();
+        let mut server = default_server();
+        server.set_handshake_padding(HANDSHAKE_PADDING).unwrap();
 
-        now += Duration::from_millis(10);
-        client.authenticated(AuthenticationStatus::Ok, now);
+        let initial = client.process(None, now()).dgram().unwrap();
+        let limit = initial.len() * AMPLIFICATION_FACTOR;
+        server.process_input(initial, now());
+        let (flight, now) = send_flight(&mut server, now());
+        let sent: usize = flight.iter().map(|d| d.len()).sum();
+        assert!(sent <= limit);
+        assert!(sent + PATH_MTU_V6 > limit);
 
-        qdebug!("---- client: SH..FIN -> FIN");
-        let pkt1 = client.process(None, now).dgram();
-        assert!(pkt1.is_some());
-        // Get PTO timer.
-        let out = client.process(None, now);
-        assert_eq!(out, Output::Callback(Duration::from_millis(60)));
+        // The server is blocked, and it doesn't set a timer to probe.
+        assert!(server.process_output(now).callback() > server.loss_recovery.pto());
 
-        now += Duration::from_millis(10);
-        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
-        assert_eq!(client.stream_send(2, b"zero").unwrap(), 4);
-        qdebug!("---- client: 1RTT packet");
-        let pkt2 = client.process(None, now).dgram();
-        assert!(pkt2.is_some());
+        // What the client sends in response lets the server send the rest.
+        receive_flight(&mut client, flight, now);
+        assert_eq!(*client.state(), State::Handshaking);
+        let (flight, now) = send_flight(&mut client, now);
+        assert!(!flight.is_empty());
+        receive_flight(&mut server, flight, now);
+        let (flight, now) = send_flight(&mut server, now);
+        assert!(!flight.is_empty());
 
-        // Get PTO timer. It is the timer for pkt1(handshake pn space).
-        let out = client.process(None, now);
-        assert_eq!(out, Output::Callback(Duration::from_millis(50)));
+        receive_flight(&mut client, flight, now);
+        assert!(maybe_authenticate(&mut client));
+        assert_eq!(*client.state(), State::Connected);
+        let (flight, now) = send_flight(&mut client, now);
+        receive_flight(&mut server, flight, now);
+        assert_eq!(*server.state(), State::Confirmed);
+    }
 
-        // Wait for PTO to expire and resend a handshake and 1rtt packet
-        now += Duration::from_millis(50);
-        let pkt3 = client.process(None, now).dgram();
-        assert!(pkt3.is_some());
-        let pkt4 = client.process(None, now).dgram();
-        assert!(pkt4.is_some());
+    #[test]
+    fn set_local_tparam() {
+        let client = default_client();
 
-        // Get PTO timer. It is the timer for pkt2(app pn space). PTO has been doubled.
-        // pkt2 has been sent 50ms ago (50 + 120 = 170 == 2*85)
-        let out = client.process(None, now);
-        assert_eq!(out, Output::Callback(Duration::from_millis(120)));
+        client
+            .set_local_tparam(tparams::INITIAL_MAX_DATA, TransportParameter::Integer(55))
+            .unwrap()
+    }
 
-        // Wait for PTO to expire and resend a handshake and 1rtt packet
-        now += Duration::from_millis(120);
-        let pkt5 = client.process(None, now).dgram();
-        assert!(pkt5.is_some());
+    fn client_with_params(params: ConnectionParameters) -> Res<Connection> {
+        fixture_init();
+        rng::set_default_seed(test_fixture::RNG_SEED);
+        Connection::new_client_with_params(
+            test_fixture::DEFAULT_SERVER_NAME,
+            test_fixture::DEFAULT_ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+            loopback(),
+            loopback(),
+            params,
+        )
+    }
 
-        // Now check what the server receives.
-        let assert_hs_and_app_pto = |frames: &[(Frame, PNSpace)]| {
-            assert!(matches!(frames[0], (Frame::Ping, PNSpace::Handshake)));
-            assert!(matches!(
-                frames[1],
-                (Frame::Crypto { .. }, PNSpace::Handshake)
-            ));
-            assert!(matches!(frames[2], (Frame::Ping, PNSpace::ApplicationData)));
-            assert!(matches!(
-                frames[3],
-                (Frame::Stream { .. }, PNSpace::ApplicationData)
-            ));
-        };
+    /// The server can use every stream that the client's parameters allow.
+    #[test]
+    fn connection_parameters() {
+        const STREAMS: u64 = 100;
+        let params = ConnectionParameters::default()
+            .max_streams_bidi(STREAMS)
+            .idle_timeout(Duration::from_secs(5));
+        let mut client = client_with_params(params).unwrap();
+        assert_eq!(client.idle_timeout(), Duration::from_secs(5));
+        let mut server = default_server();
+        connect(&mut client, &mut server);
 
-        now += Duration::from_millis(10);
-        let frames = server.test_process_input(pkt3.unwrap(), now);
-        assert_hs_and_app_pto(&frames);
+        let mut last = 0;
+        for _ in 0..STREAMS {
+            last = server.stream_create(StreamType::BiDi).unwrap();
+        }
+        assert_eq!(
+            server.stream_create(StreamType::BiDi),
+            Err(Error::StreamLimitError)
+        );
+        server.stream_send(last, &[1]).unwrap();
+        let dgram = server.process(None, now()).dgram();
+        client.process_input(dgram.unwrap(), now());
+        assert_eq!(*client.state(), State::Confirmed);
+        assert!(client.events().any(
+            |e| matches!(e, ConnectionEvent::NewStream { stream_id, .. } if stream_id == last)
+        ));
+    }
 
-        now += Duration::from_millis(10);
-        let frames = server.test_process_input(pkt5.unwrap(), now);
-        assert_hs_and_app_pto(&frames);
+    #[test]
+    fn connection_parameters_invalid() {
+        let params = ConnectionParameters::default().max_packet_size(1199);
+        assert_eq!(
+            client_with_params(params).unwrap_err(),
+            Error::InvalidParameters("max_packet_size is less than 1200")
+        );
     }
 
     #[test]
-    // Absent path PTU discovery, max v6 packet size should be PATH_MTU_V6.
-    fn verify_pkt_honors_mtu() {
+    fn stream_send_after_stop_sending() {
         let mut client = default_client();
         let mut server = default_server();
-        connect_force_idle(&mut client, &mut server);
-
-        let now = now();
+        connect(&mut client, &mut server);
 
-        let res = client.process(None, now);
-        assert_eq!(res, Output::Callback(LOCAL_IDLE_TIMEOUT));
+        let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+        client.stream_send(stream_id, &[0x00]).unwrap();
+        let out = client.process(None, now());
+        let _ = server.process(out.dgram(), now());
+        server.stream_stop_sending(stream_id, 0x55).unwrap();
+        let out = server.process(None, now());
 
-        // Try to send a large stream and verify first packet is correctly sized
-        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
-        assert_eq!(client.stream_send(2, &[0xbb; 2000]).unwrap(), 2000);
-        let pkt0 = client.process(None, now);
-        assert!(matches!(pkt0, Output::Datagram(_)));
-        assert_eq!(pkt0.as_dgram_ref().unwrap().len(), PATH_MTU_V6);
+        // The application checks for space, but STOP_SENDING arrives before it sends.
+        assert!(client.stream_avail_send_space(stream_id).unwrap() > 0);
+        let _ = client.process(out.dgram(), now());
+        assert_eq!(
+            client.stream_send(stream_id, &[0x00]),
+            Err(Error::StreamStopped(0x55))
+        );
+        assert_eq!(
+            client.stream_send_atomic(stream_id, &[0x00]),
+            Err(Error::StreamStopped(0x55))
+        );
+        assert_eq!(client.stream_avail_send_space(stream_id), Ok(0));
     }
 
-    /// This fills the congestion window from a single source.
-    /// As the pacer will interfere with this, this moves time forward
-    /// as `Output::Callback` is received.  Because it is hard to tell
-    /// from the return value whether a timeout is an ACK delay, PTO, or
-    /// pacing, this looks at the congestion window to tell when to stop.
-    /// Returns a list of datagrams and the new time.
-    fn fill_cwnd(src: &mut Connection, stream: u64, mut now: Instant) -> (Vec<Datagram>, Instant) {
-        const BLOCK_SIZE: usize = 4_096;
-        let mut total_dgrams = Vec::new();
+    #[test]
+    fn stream_send_after_reset() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
 
-        qtrace!(
-            "fill_cwnd starting cwnd: {}",
-            src.loss_recovery.cwnd_avail()
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[0x00; 10]).unwrap();
+        client.stream_reset_send(stream_id, 0x55).unwrap();
+        assert_eq!(
+            client.stream_send(stream_id, &[0x00]),
+            Err(Error::StreamReset)
         );
 
-        loop {
-            let bytes_sent = src.stream_send(stream, &[0x42; BLOCK_SIZE]).unwrap();
-            qtrace!("fill_cwnd wrote {} bytes", bytes_sent);
-            if bytes_sent < BLOCK_SIZE {
-                break;
-            }
-        }
-
-        loop {
-            let pkt = src.process_output(now);
-            qtrace!(
-                "fill_cwnd cwnd remaining={}, output: {:?}",
-                src.loss_recovery.cwnd_avail(),
-                pkt
-            );
-            match pkt {
-                Output::Datagram(dgram) => {
-                    total_dgrams.push(dgram);
-                }
-                Output::Callback(t) => {
-                    if src.loss_recovery.cwnd_avail() < ACK_ONLY_SIZE_LIMIT {
-                        break;
-                    }
-                    now += t;
-                }
-                _ => panic!(),
-            }
-        }
-
-        (total_dgrams, now)
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_close_send(stream_id).unwrap();
+        assert_eq!(
+            client.stream_send(stream_id, &[0x00]),
+            Err(Error::FinalSizeError)
+        );
     }
 
-    // Receive multiple packets and generate an ack-only packet.
-    fn ack_bytes(
-        dest: &mut Connection,
-        stream: u64,
-        in_dgrams: Vec<Datagram>,
-        now: Instant,
-    ) -> (Vec<Datagram>, Vec<Frame>) {
-        let mut srv_buf = [0; 4_096];
-        let mut recvd_frames = Vec::new();
-
-        for dgram in in_dgrams {
-            recvd_frames.extend(dest.test_process_input(dgram, now));
-        }
-
-        loop {
-            let (bytes_read, _fin) = dest.stream_recv(stream, &mut srv_buf).unwrap();
-            if bytes_read == 0 {
-                break;
-            }
-        }
-
-        let mut tx_dgrams = Vec::new();
-        while let Output::Datagram(dg) = dest.process_output(now) {
-            tx_dgrams.push(dg);
-        }
-
-        assert!((tx_dgrams.len() == 1) || (tx_dgrams.len() == 2));
+    #[test]
+    // If we send a stop_sending to the peer, we should not accept more data from the peer.
+    fn do_not_accept_data_after_stop_sending() {
+        // Note that the two servers in this test will get different anti-replay filters.
+        // That's OK because we aren't testing anti-replay.
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
 
-        (
-            tx_dgrams,
-            recvd_frames.into_iter().map(|(f, _e)| f).collect(),
-        )
-    }
+        // create a stream
+        let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+        client.stream_send(stream_id, &[0x00]).unwrap();
+        let out = client.process(None, now());
+        server.process(out.dgram(), now());
 
-    /// This magic number is the size of the client's CWND after the handshake completes.
-    /// This includes the initial congestion window, as increased as a result
-    /// receiving acknowledgments for Initial and Handshake packets, which is
-    /// at least one full packet (the first Initial) and a little extra.
-    ///
-    /// As we change how we build packets, or even as NSS changes,
-    /// this number might be different.  The tests that depend on this
-    /// value could fail as a result of variations, so it's OK to just
-    /// change this value, but it is good to first understand where the
-    /// change came from.
-    const POST_HANDSHAKE_CWND: usize = PATH_MTU_V6 * (INITIAL_CWND_PKTS + 1) + 75;
+        let stream_readable = |e| matches!(e, ConnectionEvent::RecvStreamReadable {..});
+        assert!(server.events().any(stream_readable));
 
-    /// Determine the number of packets required to fill the CWND.
-    const fn cwnd_packets(data: usize) -> usize {
-        (data + ACK_ONLY_SIZE_LIMIT - 1) / PATH_MTU_V6
-    }
+        // Send one more packet from client. The packet should arrive after the server
+        // has already requested stop_sending.
+        client.stream_send(stream_id, &[0x00]).unwrap();
+        let out_second_data_frame = client.process(None, now());
+        // Call stop sending.
+        assert_eq!(
+            Ok(()),
+            server.stream_stop_sending(stream_id, Error::NoError.code())
+        );
 
-    /// Determine the size of the last packet.
-    /// The minimal size of a packet is `ACK_ONLY_SIZE_LIMIT`.
-    fn last_packet(cwnd: usize) -> usize {
-        if (cwnd % PATH_MTU_V6) > ACK_ONLY_SIZE_LIMIT {
-            cwnd % PATH_MTU_V6
-        } else {
-            PATH_MTU_V6
-        }
-    }
+        // Receive the second data frame. The frame should be ignored and now
+        // DataReadable events should be posted.
+        let out = server.process(out_second_data_frame.dgram(), now());
+        assert!(!server.events().any(stream_readable));
 
-    /// Assert that the set of packets fill the CWND.
-    fn assert_full_cwnd(packets: &[Datagram], cwnd: usize) {
-        assert_eq!(packets.len(), cwnd_packets(cwnd));
-        let (last, rest) = packets.split_last().unwrap();
-        assert!(rest.iter().all(|d| d.len() == PATH_MTU_V6));
-        assert_eq!(last.len(), last_packet(cwnd));
+        client.process(out.dgram(), now());
+        assert_eq!(
+            Err(Error::StreamStopped(Error::NoError.code())),
+            client.stream_send(stream_id, &[0x00])
+        );
     }
 
     #[test]
-    /// Verify initial CWND is honored.
-    fn cc_slow_start() {
+    // Server sends stop_sending, the client simultaneous sends reset.
+    fn simultaneous_stop_sending_and_reset() {
+        // Note that the two servers in this test will get different anti-replay filters.
+        // That's OK because we aren't testing anti-replay.
         let mut client = default_client();
         let mut server = default_server();
+        connect(&mut client, &mut server);
 
-        server
-            .set_local_tparam(
-                tparams::INITIAL_MAX_DATA,
-                TransportParameter::Integer(65536),
-            )
+        // create a stream
+        let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+        client.stream_send(stream_id, &[0x00]).unwrap();
+        let out = client.process(None, now());
+        server.process(out.dgram(), now());
+
+        let stream_readable = |e| matches!(e, ConnectionEvent::RecvStreamReadable {..});
+        assert!(server.events().any(stream_readable));
+
+        // The client resets the stream. The packet with reset should arrive after the server
+        // has already requested stop_sending.
+        client
+            .stream_reset_send(stream_id, Error::NoError.code())
             .unwrap();
-        connect_force_idle(&mut client, &mut server);
+        let out_reset_frame = client.process(None, now());
+        // Call stop sending.
+        assert_eq!(
+            Ok(()),
+            server.stream_stop_sending(stream_id, Error::NoError.code())
+        );
 
-        let now = now();
+        // Receive the second data frame. The frame should be ignored and now
+        // DataReadable events should be posted.
+        let out = server.process(out_reset_frame.dgram(), now());
+        assert!(!server.events().any(stream_readable));
 
-        // Try to send a lot of data
-        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
-        let (c_tx_dgrams, _) = fill_cwnd(&mut client, 2, now);
-        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND);
-        assert!(client.loss_recovery.cwnd_avail() < ACK_ONLY_SIZE_LIMIT);
+        // The client gets the STOP_SENDING frame.
+        client.process(out.dgram(), now());
+        assert_eq!(
+            Err(Error::InvalidStreamId),
+            client.stream_send(stream_id, &[0x00])
+        );
     }
 
     #[test]
-    /// Verify that CC moves to cong avoidance when a packet is marked lost.
-    fn cc_slow_start_to_cong_avoidance_recovery_period() {
+    fn test_client_fin_reorder() {
         let mut client = default_client();
         let mut server = default_server();
-        connect_force_idle(&mut client, &mut server);
-
-        // Create stream 0
-        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
 
-        // Buffer up lot of data and generate packets
-        let (c_tx_dgrams, now) = fill_cwnd(&mut client, 0, now());
-        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND);
-        // Predict the packet number of the last packet sent.
-        // We have already sent one packet in `connect_force_idle` (an ACK),
-        // so this will be equal to the number of packets in this flight.
-        let flight1_largest = PacketNumber::try_from(c_tx_dgrams.len()).unwrap();
+        // Send ClientHello.
+        let client_hs = client.process(None, now());
+        assert!(client_hs.as_dgram_ref().is_some());
 
-        // Server: Receive and generate ack
-        let (s_tx_dgram, _recvd_frames) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        let server_hs = server.process(client_hs.dgram(), now());
+        assert!(server_hs.as_dgram_ref().is_some()); // ServerHello, etc...
 
-        // Client: Process ack
-        for dgram in s_tx_dgram {
-            let recvd_frames = client.test_process_input(dgram, now);
+        let client_ack = client.process(server_hs.dgram(), now());
+        assert!(client_ack.as_dgram_ref().is_some());
 
-            // Verify that server-sent frame was what we thought.
-            if let (
-                Frame::Ack {
-                    largest_acknowledged,
-                    ..
-                },
-                PNSpace::ApplicationData,
-            ) = recvd_frames[0]
-            {
-                assert_eq!(largest_acknowledged, flight1_largest);
-            } else {
-                panic!("Expected an application ACK");
-            }
-        }
+        let server_out = server.process(client_ack.dgram(), now());
+        assert!(server_out.as_dgram_ref().is_none());
 
-        // Client: send more
-        let (mut c_tx_dgrams, now) = fill_cwnd(&mut client, 0, now);
-        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND * 2);
-        let flight2_largest = flight1_largest + u64::try_from(c_tx_dgrams.len()).unwrap();
+        assert!(maybe_authenticate(&mut client));
+        assert_eq!(*client.state(), State::Connected);
 
-        // Server: Receive and generate ack again, but drop first packet
-        c_tx_dgrams.remove(0);
-        let (s_tx_dgram, _recvd_frames) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        let client_fin = client.process(None, now());
+        assert!(client_fin.as_dgram_ref().is_some());
 
-        // Client: Process ack
-        for dgram in s_tx_dgram {
-            let recvd_frames = client.test_process_input(dgram, now);
+        let client_stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(client_stream_id, &[1, 2, 3]).unwrap();
+        let client_stream_data = client.process(None, now());
+        assert!(client_stream_data.as_dgram_ref().is_some());
 
-            // Verify that server-sent frame was what we thought.
-            if let (
-                Frame::Ack {
-                    largest_acknowledged,
-                    ..
-                },
-                PNSpace::ApplicationData,
-            ) = recvd_frames[0]
-            {
-                assert_eq!(largest_acknowledged, flight2_largest);
-            } else {
-                panic!("Expected an application ACK");
-            }
-        }
+        // Now stream data gets before client_fin
+        let server_out = server.process(client_stream_data.dgram(), now());
+        assert!(server_out.as_dgram_ref().is_none()); // the packet will be discarded
 
-        // If we just triggered cong avoidance, these should be equal
-        assert_eq!(client.loss_recovery.cwnd(), client.loss_recovery.ssthresh());
+        assert_eq!(*server.state(), State::Handshaking);
+        let server_out = server.process(client_fin.dgram(), now());
+        assert!(server_out.as_dgram_ref().is_some());
     }
 
     #[test]
-    /// Verify that CC stays in recovery period when packet sent before start of
-    /// recovery period is acked.
-    fn cc_cong_avoidance_recovery_period_unchanged() {
+    fn pto_works_basic() {
         let mut client = default_client();
         let mut server = default_server();
         connect_force_idle(&mut client, &mut server);
 
-        // Create stream 0
-        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+        let mut now = now();
 
-        // Buffer up lot of data and generate packets
-        let (mut c_tx_dgrams, now) = fill_cwnd(&mut client, 0, now());
-        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND);
+        let res = client.process(None, now);
+        assert_eq!(res, Output::Callback(LOCAL_IDLE_TIMEOUT));
 
-        // Drop 0th packet. When acked, this should put client into CARP.
-        c_tx_dgrams.remove(0);
+        // Send data on two streams
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
+        assert_eq!(client.stream_send(2, b"hello").unwrap(), 5);
+        assert_eq!(client.stream_send(2, b" world").unwrap(), 6);
 
-        let c_tx_dgrams2 = c_tx_dgrams.split_off(5);
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 6);
+        assert_eq!(client.stream_send(6, b"there!").unwrap(), 6);
 
-        // Server: Receive and generate ack
-        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
-        for dgram in s_tx_dgram {
-            client.test_process_input(dgram, now);
-        }
+        // Send a packet after some time.
+        now += Duration::from_secs(10);
+        let out = client.process(None, now);
+        assert!(out.dgram().is_some());
 
-        // If we just triggered cong avoidance, these should be equal
-        let cwnd1 = client.loss_recovery.cwnd();
-        assert_eq!(cwnd1, client.loss_recovery.ssthresh());
+        // Nothing to do, should return callback
+        let out = client.process(None, now);
+        assert!(matches!(out, Output::Callback(_)));
 
-        // Generate ACK for more received packets
-        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams2, now);
+        // One second later, it should want to send PTO packet
+        now += AT_LEAST_PTO;
+        let out = client.process(None, now);
 
-        // ACK more packets but they were sent before end of recovery period
-        for dgram in s_tx_dgram {
-            client.test_process_input(dgram, now);
-        }
+        let frames = server.test_process_input(out.dgram().unwrap(), now);
 
-        // cwnd should not have changed since ACKed packets were sent before
-        // recovery period expired
-        let cwnd2 = client.loss_recovery.cwnd();
-        assert_eq!(cwnd1, cwnd2);
+        assert!(frames.iter().all(|(_, sp)| *sp == PNSpace::ApplicationData));
+        assert!(frames.iter().any(|(f, _)| *f == Frame::Ping));
+        assert!(frames
+            .iter()
+            .any(|(f, _)| matches!(f, Frame::Stream { stream_id, .. } if stream_id.as_u64() == 2)));
+        assert!(frames
+            .iter()
+            .any(|(f, _)| matches!(f, Frame::Stream { stream_id, .. } if stream_id.as_u64() == 6)));
     }
 
     #[test]
-    /// Verify that CC moves out of recovery period when packet sent after start
-    /// of recovery period is acked.
-    fn cc_cong_avoidance_recovery_period_to_cong_avoidance() {
+    fn pto_works_full_cwnd() {
         let mut client = default_client();
         let mut server = default_server();
-        connect(&mut client, &mut server);
-
-        // Create stream 0
-        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+        connect_force_idle(&mut client, &mut server);
 
-        // Buffer up lot of data and generate packets
-        let (mut c_tx_dgrams, mut now) = fill_cwnd(&mut client, 0, now());
+        let res = client.process(None, now());
+        assert_eq!(res, Output::Callback(LOCAL_IDLE_TIMEOUT));
 
-        // Drop 0th packet. When acked, this should put client into CARP.
-        c_tx_dgrams.remove(0);
+        // Send lots of data.
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
+        let (dgrams, now) = fill_cwnd(&mut client, 2, now());
+        assert_full_cwnd(&dgrams, POST_HANDSHAKE_CWND);
 
-        // Server: Receive and generate ack
-        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        // Fill the CWND after waiting for a PTO.
+        let (dgrams, now) = fill_cwnd(&mut client, 2, now + AT_LEAST_PTO);
+        assert_eq!(dgrams.len(), 2); // Two packets in the PTO.
 
-        // Client: Process ack
-        for dgram in s_tx_dgram {
-            client.test_process_input(dgram, now);
+        // All (2) datagrams contain one PING frame and at least one STREAM frame.
+        for d in dgrams {
+            assert_eq!(d.len(), PATH_MTU_V6);
+            let frames = server.test_process_input(d, now);
+            assert_eq!(
+                frames
+                    .iter()
+                    .filter(|i| matches!(i, (Frame::Ping, PNSpace::ApplicationData)))
+                    .count(),
+                1
+            );
+            assert!(
+                frames
+                    .iter()
+                    .filter(|i| matches!(i, (Frame::Stream { .. }, PNSpace::ApplicationData)))
+                    .count()
+                    >= 1
+            );
         }
+    }
 
-        // Should be in CARP now.
-        let cwnd1 = client.loss_recovery.cwnd();
+    #[test]
+    #[allow(clippy::cognitive_complexity)]
+    fn pto_works_ping() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
 
-        now += Duration::from_millis(10); // Time passes. CARP -> CA
+        let now = now();
 
-        // Client: Send more data
-        let (mut c_tx_dgrams, next_now) = fill_cwnd(&mut client, 0, now);
-        now = next_now;
+        let res = client.process(None, now);
+        assert_eq!(res, Output::Callback(LOCAL_IDLE_TIMEOUT));
 
-        // Only sent 2 packets, to generate an ack but also keep cwnd increase
-        // small
-        c_tx_dgrams.truncate(2);
+        // Send "zero" pkt
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
+        assert_eq!(client.stream_send(2, b"zero").unwrap(), 4);
+        let pkt0 = client.process(None, now + Duration::from_secs(10));
+        assert!(matches!(pkt0, Output::Datagram(_)));
 
-        // Generate ACK
-        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        // Send "one" pkt
+        assert_eq!(client.stream_send(2, b"one").unwrap(), 3);
+        let pkt1 = client.process(None, now + Duration::from_secs(10));
 
-        for dgram in s_tx_dgram {
-            client.test_process_input(dgram, now);
-        }
+        // Send "two" pkt
+        assert_eq!(client.stream_send(2, b"two").unwrap(), 3);
+        let pkt2 = client.process(None, now + Duration::from_secs(10));
 
-        // ACK of pkts sent after start of recovery period should have caused
-        // exit from recovery period to just regular congestion avoidance. cwnd
-        // should now be a little higher but not as high as acked pkts during
-        // slow-start would cause it to be.
-        let cwnd2 = client.loss_recovery.cwnd();
+        // Send "three" pkt
+        assert_eq!(client.stream_send(2, b"three").unwrap(), 5);
+        let pkt3 = client.process(None, now + Duration::from_secs(10));
 
-        assert!(cwnd2 > cwnd1);
-        assert!(cwnd2 < cwnd1 + 500);
-    }
+        // Nothing to do, should return callback
+        let out = client.process(None, now + Duration::from_secs(10));
+        // Check callback delay is what we expect
+        assert!(matches!(out, Output::Callback(x) if x == Duration::from_millis(45)));
 
-    fn induce_persistent_congestion(
-        client: &mut Connection,
-        server: &mut Connection,
-        mut now: Instant,
-    ) -> Instant {
-        // Note: wait some arbitrary time that should be longer than pto
-        // timer. This is rather brittle.
-        now += AT_LEAST_PTO;
+        // Process these by server, skipping pkt0
+        let srv0_pkt1 = server.process(pkt1.dgram(), now + Duration::from_secs(10));
+        // ooo, ack client pkt 1
+        assert!(matches!(srv0_pkt1, Output::Datagram(_)));
 
-        let (c_tx_dgrams, next_now) = fill_cwnd(client, 0, now);
-        now = next_now;
-        assert_eq!(c_tx_dgrams.len(), 2); // Two PTO packets
+        // process pkt2 (no ack yet)
+        let srv2 = server.process(
+            pkt2.dgram(),
+            now + Duration::from_secs(10) + Duration::from_millis(20),
+        );
+        assert!(matches!(srv2, Output::Callback(_)));
 
-        now += Duration::from_secs(2);
-        let (c_tx_dgrams, next_now) = fill_cwnd(client, 0, now);
-        now = next_now;
-        assert_eq!(c_tx_dgrams.len(), 2); // Two PTO packets
+        // process pkt3 (acked)
+        let srv2 = server.process(
+            pkt3.dgram(),
+            now + Duration::from_secs(10) + Duration::from_millis(20),
+        );
+        // ack client pkt 2 & 3
+        assert!(matches!(srv2, Output::Datagram(_)));
 
-        now += Duration::from_secs(4);
-        let (c_tx_dgrams, next_now) = fill_cwnd(client, 0, now);
-        now = next_now;
-        assert_eq!(c_tx_dgrams.len(), 2); // Two PTO packets
+        // client processes ack
+        let pkt4 = client.process(
+            srv2.dgram(),
+            now + Duration::from_secs(10) + Duration::from_millis(40),
+        );
+        // client resends data from pkt0
+        assert!(matches!(pkt4, Output::Datagram(_)));
 
-        // Generate ACK
-        let (s_tx_dgram, _) = ack_bytes(server, 0, c_tx_dgrams, now);
+        // server sees ooo pkt0 and generates ack
+        let srv_pkt2 = server.process(
+            pkt0.dgram(),
+            now + Duration::from_secs(10) + Duration::from_millis(40),
+        );
+        assert!(matches!(srv_pkt2, Output::Datagram(_)));
 
-        // In PC now.
-        for dgram in s_tx_dgram {
-            client.test_process_input(dgram, now);
-        }
+        // Orig data is acked
+        let pkt5 = client.process(
+            srv_pkt2.dgram(),
+            now + Duration::from_secs(10) + Duration::from_millis(40),
+        );
+        assert!(matches!(pkt5, Output::Callback(_)));
 
-        assert_eq!(client.loss_recovery.cwnd(), MIN_CONG_WINDOW);
-        now
+        // PTO expires. No unacked data. Only send PING.
+        let pkt6 = client.process(
+            None,
+            now + Duration::from_secs(10) + Duration::from_millis(110),
+        );
+
+        let frames = server.test_process_input(
+            pkt6.dgram().unwrap(),
+            now + Duration::from_secs(10) + Duration::from_millis(110),
+        );
+
+        assert_eq!(frames[0], (Frame::Ping, PNSpace::ApplicationData));
     }
 
     #[test]
-    /// Verify transition to persistent congestion state if conditions are met.
-    fn cc_slow_start_to_persistent_congestion_no_acks() {
+    fn pto_initial() {
+        let mut now = now();
+
+        qdebug!("---- client: generate CH");
         let mut client = default_client();
-        let mut server = default_server();
-        connect_force_idle(&mut client, &mut server);
+        let pkt1 = client.process(None, now).dgram();
+        assert!(pkt1.is_some());
+        assert_eq!(pkt1.clone().unwrap().len(), PATH_MTU_V6);
 
-        // Create stream 0
-        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+        let out = client.process(None, now);
+        assert_eq!(out, Output::Callback(Duration::from_millis(120)));
 
-        // Buffer up lot of data and generate packets
-        let (c_tx_dgrams, mut now) = fill_cwnd(&mut client, 0, now());
-        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND);
+        // Resend initial after PTO.
+        now += Duration::from_millis(120);
+        let pkt2 = client.process(None, now).dgram();
+        assert!(pkt2.is_some());
+        assert_eq!(pkt2.unwrap().len(), PATH_MTU_V6);
 
-        // Server: Receive and generate ack
-        now += Duration::from_millis(100);
-        let (_s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        let pkt3 = client.process(None, now).dgram();
+        assert!(pkt3.is_some());
+        assert_eq!(pkt3.unwrap().len(), PATH_MTU_V6);
 
-        // ACK lost.
+        let out = client.process(None, now);
+        // PTO has doubled.
+        assert_eq!(out, Output::Callback(Duration::from_millis(240)));
 
-        induce_persistent_congestion(&mut client, &mut server, now);
-    }
-
-    #[test]
-    /// Verify transition to persistent congestion state if conditions are met.
-    fn cc_slow_start_to_persistent_congestion_some_acks() {
-        let mut client = default_client();
+        // Server process the first initial pkt.
         let mut server = default_server();
-        connect_force_idle(&mut client, &mut server);
-
-        // Create stream 0
-        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+        let out = server.process(pkt1, now).dgram();
+        assert!(out.is_some());
 
-        // Buffer up lot of data and generate packets
-        let (c_tx_dgrams, mut now) = fill_cwnd(&mut client, 0, now());
-        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND);
+        // Client receives ack for the first initial packet as well a Handshake packet.
+        // After the handshake packet the initial keys and the crypto stream for the initial
+        // packet number space will be discarded.
+        // Here only an ack for the Handshake packet will be sent.
+        now += Duration::from_millis(10);
+        let out = client.process(out, now).dgram();
+        assert!(out.is_some());
 
-        // Server: Receive and generate ack
-        now += Duration::from_millis(100);
-        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        // We do not have PTO for the resent initial packet any more, because keys are discarded.
+        // The timeout will be an idle time out of LOCAL_IDLE_TIMEOUT seconds.
+        let out = client.process(None, now);
+        assert_eq!(out, Output::Callback(LOCAL_IDLE_TIMEOUT));
+    }
 
-        now += Duration::from_millis(100);
-        for dgram in s_tx_dgram {
-            client.test_process_input(dgram, now);
+    /// Collect everything that `c` has to send, and how long it wants to wait after that.
+    fn drain(c: &mut Connection, now: Instant) -> (Vec<Datagram>, Duration) {
+        let mut dgrams = Vec::new();
+        loop {
+            match c.process_output(now) {
+                Output::Datagram(d) => dgrams.push(d),
+                out => return (dgrams, out.callback()),
+            }
         }
+    }
 
-        // send bytes that will be lost
-        let (_c_tx_dgrams, next_now) = fill_cwnd(&mut client, 0, now);
-        now = next_now + Duration::from_millis(100);
+    #[test]
+    fn lost_server_flight() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let mut now = now();
 
-        induce_persistent_congestion(&mut client, &mut server, now);
+        let c1 = client.process(None, now).dgram();
+        assert!(c1.is_some());
+
+        // Lose everything that the server sends in response.
+        server.process_input(c1.unwrap(), now);
+        let (lost, pto) = drain(&mut server, now);
+        assert!(!lost.is_empty());
+
+        // Only timers can move things along now.  The client probes too, so
+        // deliver everything until the handshake completes, waiting for the
+        // next timer whenever neither side has anything to send.
+        now += pto;
+        let mut to_client = Vec::new();
+        let mut to_server = Vec::new();
+        let mut waits = 0;
+        while *client.state() != State::Confirmed {
+            for d in to_client.drain(..) {
+                client.process_input(d, now);
+            }
+            let _ = maybe_authenticate(&mut client);
+            let (c_out, c_wait) = drain(&mut client, now);
+            for d in to_server.drain(..).chain(c_out) {
+                server.process_input(d, now);
+            }
+            let (s_out, s_wait) = drain(&mut server, now);
+            if s_out.is_empty() {
+                waits += 1;
+                assert!(waits < 5, "the handshake should not need many timers");
+                now += min(c_wait, s_wait);
+            }
+            to_client = s_out;
+        }
+        assert_eq!(*server.state(), State::Confirmed);
+        assert!(server.stats().packets_tx > lost.len());
     }
 
     #[test]
-    /// Verify persistent congestion moves to slow start after recovery period
-    /// ends.
-    fn cc_persistent_congestion_to_slow_start() {
+    fn pto_handshake() {
+        let mut now = now();
+        // start handshake
         let mut client = default_client();
         let mut server = default_server();
-        connect_force_idle(&mut client, &mut server);
 
-        // Create stream 0
-        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
-
-        // Buffer up lot of data and generate packets
-        let (c_tx_dgrams, mut now) = fill_cwnd(&mut client, 0, now());
-        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND);
+        let pkt = client.process(None, now).dgram();
+        let cb = client.process(None, now).callback();
+        assert_eq!(cb, Duration::from_millis(120));
 
-        // Server: Receive and generate ack
         now += Duration::from_millis(10);
-        let (_s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        let pkt = server.process(pkt, now).dgram();
 
-        // ACK lost.
+        now += Duration::from_millis(10);
+        let pkt = client.process(pkt, now).dgram();
 
-        now = induce_persistent_congestion(&mut client, &mut server, now);
+        let cb = client.process(None, now).callback();
+        assert_eq!(cb, LOCAL_IDLE_TIMEOUT);
 
-        // New part of test starts here
+        now += Duration::from_millis(10);
+        let pkt = server.process(pkt, now).dgram();
+        assert!(pkt.is_none());
 
         now += Duration::from_millis(10);
+        client.authenticated(AuthenticationStatus::Ok, now);
 
-        // Send packets from after start of CARP
-        let (c_tx_dgrams, next_now) = fill_cwnd(&mut client, 0, now);
-        assert_eq!(c_tx_dgrams.len(), 2);
+        qdebug!("---- client: SH..FIN -> FIN");
+        let pkt1 = client.process(None, now).dgram();
+        assert!(pkt1.is_some());
 
-        // Server: Receive and generate ack
-        now = next_now + Duration::from_millis(100);
-        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        let cb = client.process(None, now).callback();
+        assert_eq!(cb, Duration::from_millis(60));
 
-        // No longer in CARP. (pkts acked from after start of CARP)
-        // Should be in slow start now.
-        for dgram in s_tx_dgram {
-            client.test_process_input(dgram, now);
-        }
+        // Wait for PTO to expire and resend a handshake packet
+        now += Duration::from_millis(60);
+        let pkt2 = client.process(None, now).dgram();
+        assert!(pkt2.is_some());
 
-        // ACKing 2 packets should let client send 4.
-        let (c_tx_dgrams, _) = fill_cwnd(&mut client, 0, now);
-        assert_eq!(c_tx_dgrams.len(), 4);
-    }
+        // Get a second PTO packet.
+        let pkt3 = client.process(None, now).dgram();
+        assert!(pkt3.is_some());
 
-    fn check_discarded(peer: &mut Connection, pkt: Datagram, dropped: usize, dups: usize) {
-        let dropped_before = peer.stats.dropped_rx;
-        let dups_before = peer.stats.dups_rx;
-        let out = peer.process(Some(pkt), now());
-        assert!(out.as_dgram_ref().is_none());
-        assert_eq!(dropped, peer.stats.dropped_rx - dropped_before);
-        assert_eq!(dups, peer.stats.dups_rx - dups_before);
+        // PTO has been doubled.
+        let cb = client.process(None, now).callback();
+        assert_eq!(cb, Duration::from_millis(120));
+
+        now += Duration::from_millis(10);
+        // Server receives the first packet.
+        // The output will be a Handshake packet with an ack and a app pn space packet with
+        // HANDSHAKE_DONE.
+        let pkt = server.process(pkt1, now).dgram();
+        assert!(pkt.is_some());
+
+        // Check that the PTO packets (pkt2, pkt3) have a Handshake and an app pn space packet.
+        // The server has discarded the Handshake keys already, therefore the handshake packet
+        // will be dropped.
+        let dropped_before = server.stats().dropped_rx;
+        let frames = server.test_process_input(pkt2.unwrap(), now);
+        assert_eq!(1, server.stats().dropped_rx - dropped_before);
+        assert_eq!(frames[0], (Frame::Ping, PNSpace::ApplicationData));
+
+        let dropped_before = server.stats().dropped_rx;
+        let frames = server.test_process_input(pkt3.unwrap(), now);
+        assert_eq!(1, server.stats().dropped_rx - dropped_before);
+        assert_eq!(frames[0], (Frame::Ping, PNSpace::ApplicationData));
+
+        now += Duration::from_millis(10);
+        // Client receive ack for the first packet
+        let cb = client.process(pkt, now).callback();
+        // Ack delay timer for the packet carrying HANDSHAKE_DONE.
+        assert_eq!(cb, ACK_DELAY);
+
+        // Let the ack timer expire.
+        now += cb;
+        let out = client.process(None, now).dgram();
+        assert!(out.is_some());
+        let cb = client.process(None, now).callback();
+        // The handshake keys are discarded, but now we're back to the idle timeout.
+        // We don't send another PING because the handshake space is done and there
+        // is nothing to probe for.
+        assert_eq!(cb, LOCAL_IDLE_TIMEOUT - ACK_DELAY);
     }
 
     #[test]
-    fn discarded_initial_keys() {
+    fn test_pto_handshake_and_app_data() {
+        let mut now = now();
         qdebug!("---- client: generate CH");
         let mut client = default_client();
-        let init_pkt_c = client.process(None, now()).dgram();
-        assert!(init_pkt_c.is_some());
-        assert_eq!(init_pkt_c.as_ref().unwrap().len(), PATH_MTU_V6);
+        let pkt = client.process(None, now);
 
+        now += Duration::from_millis(10);
         qdebug!("---- server: CH -> SH, EE, CERT, CV, FIN");
         let mut server = default_server();
-        let init_pkt_s = server.process(init_pkt_c.clone(), now()).dgram();
-        assert!(init_pkt_s.is_some());
+        let pkt = server.process(pkt.dgram(), now);
 
+        now += Duration::from_millis(10);
         qdebug!("---- client: cert verification");
-        let out = client.process(init_pkt_s.clone(), now()).dgram();
-        assert!(out.is_some());
+        let pkt = client.process(pkt.dgram(), now);
 
-        // The client has received handshake packet. It will remove the Initial keys.
-        // We will check this by processing init_pkt_s a second time.
-        // The initial packet should be dropped. The packet contains a Handshake packet as well, which
-        // will be marked as dup.
-        check_discarded(&mut client, init_pkt_s.unwrap(), 1, 1);
+        now += Duration::from_millis(10);
+        let _pkt = server.process(pkt.dgram(), now);
 
-        assert!(maybe_authenticate(&mut client));
+        now += Duration::from_millis(10);
+        client.authenticated(AuthenticationStatus::Ok, now);
 
-        // The server has not removed the Initial keys yet, because it has not yet received a Handshake
-        // packet from the client.
-        // We will check this by processing init_pkt_c a second time.
-        // The dropped packet is padding. The Initial packet has been mark dup.
-        check_discarded(&mut server, init_pkt_c.clone().unwrap(), 1, 1);
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
+        assert_eq!(client.stream_send(2, b"zero").unwrap(), 4);
+        qdebug!("---- client: SH..FIN -> FIN and 1RTT packet");
+        let pkt1 = client.process(None, now).dgram();
+        assert!(pkt1.is_some());
 
-        qdebug!("---- client: SH..FIN -> FIN");
-        let out = client.process(None, now()).dgram();
-        assert!(out.is_some());
+        // Get PTO timer.
+        let out = client.process(None, now);
+        assert_eq!(out, Output::Callback(Duration::from_millis(60)));
 
-        // The server will process the first Handshake packet.
-        // After this the Initial keys will be dropped.
-        let out = server.process(out, now()).dgram();
-        assert!(out.is_some());
+        // Wait for PTO to expire and resend a handshake and 1rtt packet
+        now += Duration::from_millis(60);
+        let pkt2 = client.process(None, now).dgram();
+        assert!(pkt2.is_some());
 
-        // Check that the Initial keys are dropped at the server
-        // We will check this by processing init_pkt_c a third time.
-        // The Initial packet has been dropped and padding that follows it.
-        // There is no dups, everything has been dropped.
-        check_discarded(&mut server, init_pkt_c.unwrap(), 1, 0);
+        now += Duration::from_millis(10);
+        let frames = server.test_process_input(pkt2.unwrap(), now);
+
+        assert!(matches!(frames[0], (Frame::Ping, PNSpace::Handshake)));
+        assert!(matches!(
+            frames[1],
+            (Frame::Crypto { .. }, PNSpace::Handshake)
+        ));
+        assert!(matches!(frames[2], (Frame::Ping, PNSpace::ApplicationData)));
+        assert!(matches!(
+            frames[3],
+            (Frame::Stream { .. }, PNSpace::ApplicationData)
+        ));
     }
 
-    /// Send something on a stream from `sender` to `receiver`.
-    /// Return the resulting datagram.
-    #[must_use]
-    fn send_something(sender: &mut Connection, now: Instant) -> Datagram {
-        let stream_id = sender.stream_create(StreamType::UniDi).unwrap();
-        assert!(sender.stream_send(stream_id, b"data").is_ok());
-        assert!(sender.stream_close_send(stream_id).is_ok());
-        let dgram = sender.process(None, now).dgram();
-        dgram.expect("should have something to send")
+    #[test]
+    fn pto_count_increase_across_spaces() {
+        let mut now = now();
+        qdebug!("---- client: generate CH");
+        let mut client = default_client();
+        let pkt = client.process(None, now).dgram();
+
+        now += Duration::from_millis(10);
+        qdebug!("---- server: CH -> SH, EE, CERT, CV, FIN");
+        let mut server = default_server();
+        let pkt = server.process(pkt, now).dgram();
+
+        now += Duration::from_millis(10);
+        qdebug!("---- client: cert verification");
+        let pkt = client.process(pkt, now).dgram();
+
+        now += Duration::from_millis(10);
+        let _pkt = server.process(pkt, now);
+
+        now += Duration::from_millis(10);
+        client.authenticated(AuthenticationStatus::Ok, now);
+
+        qdebug!("---- client: SH..FIN -> FIN");
+        let pkt1 = client.process(None, now).dgram();
+        assert!(pkt1.is_some());
+        // Get PTO timer.
+        let out = client.process(None, now);
+        assert_eq!(out, Output::Callback(Duration::from_millis(60)));
+
+        now += Duration::from_millis(10);
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
+        assert_eq!(client.stream_send(2, b"zero").unwrap(), 4);
+        qdebug!("---- client: 1RTT packet");
+        let pkt2 = client.process(None, now).dgram();
+        assert!(pkt2.is_some());
+
+        // Get PTO timer. It is the timer for pkt1(handshake pn space).
+        let out = client.process(None, now);
+        assert_eq!(out, Output::Callback(Duration::from_millis(50)));
+
+        // Wait for PTO to expire and resend a handshake and 1rtt packet
+        now += Duration::from_millis(50);
+        let pkt3 = client.process(None, now).dgram();
+        assert!(pkt3.is_some());
+        let pkt4 = client.process(None, now).dgram();
+        assert!(pkt4.is_some());
+
+        // Get PTO timer. It is the timer for pkt2(app pn space). PTO has been doubled.
+        // pkt2 has been sent 50ms ago (50 + 120 = 170 == 2*85)
+        let out = client.process(None, now);
+        assert_eq!(out, Output::Callback(Duration::from_millis(120)));
+
+        // Wait for PTO to expire and resend a handshake and 1rtt packet
+        now += Duration::from_millis(120);
+        let pkt5 = client.process(None, now).dgram();
+        assert!(pkt5.is_some());
+
+        // Now check what the server receives.
+        let assert_hs_and_app_pto = |frames: &[(Frame, PNSpace)]| {
+            assert!(matches!(frames[0], (Frame::Ping, PNSpace::Handshake)));
+            assert!(matches!(
+                frames[1],
+                (Frame::Crypto { .. }, PNSpace::Handshake)
+            ));
+            assert!(matches!(frames[2], (Frame::Ping, PNSpace::ApplicationData)));
+            assert!(matches!(
+                frames[3],
+                (Frame::Stream { .. }, PNSpace::ApplicationData)
+            ));
+        };
+
+        now += Duration::from_millis(10);
+        let frames = server.test_process_input(pkt3.unwrap(), now);
+        assert_hs_and_app_pto(&frames);
+
+        now += Duration::from_millis(10);
+        let frames = server.test_process_input(pkt5.unwrap(), now);
+        assert_hs_and_app_pto(&frames);
+    }
+
+    #[test]
+    // Absent path PTU discovery, max v6 packet size should be PATH_MTU_V6.
+    fn verify_pkt_honors_mtu() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        let now = now();
+
+        let res = client.process(None, now);
+        assert_eq!(res, Output::Callback(LOCAL_IDLE_TIMEOUT));
+
+        // Try to send a large stream and verify first packet is correctly sized
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
+        assert_eq!(client.stream_send(2, &[0xbb; 2000]).unwrap(), 2000);
+        let pkt0 = client.process(None, now);
+        assert!(matches!(pkt0, Output::Datagram(_)));
+        assert_eq!(pkt0.as_dgram_ref().unwrap().len(), PATH_MTU_V6);
+    }
+
+    #[test]
+    fn small_max_packet_size() {
+        const SMALL: usize = 1200;
+        let mut client = default_client();
+        let mut server = default_server();
+        for c in &[&client, &server] {
+            c.set_local_tparam(
+                tparams::MAX_PACKET_SIZE,
+                TransportParameter::Integer(SMALL as u64),
+            )
+            .unwrap();
+        }
+        // Every datagram is also checked against the limit as it is sent.
+        connect(&mut client, &mut server);
+        assert_eq!(client.path().unwrap().mtu(), SMALL);
+        assert_eq!(server.path().unwrap().mtu(), SMALL);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        let sent = client.stream_send(stream_id, &[0x42; 20_000]).unwrap();
+        assert_eq!(sent, 20_000);
+        client.stream_close_send(stream_id).unwrap();
+
+        let mut now = now();
+        let mut buf = vec![0; 20_000];
+        let mut received = 0;
+        let mut fin = false;
+        while !fin {
+            match client.process_output(now) {
+                Output::Datagram(d) => {
+                    assert!(d.len() <= SMALL);
+                    server.process_input(d, now);
+                }
+                Output::Callback(t) => now += t,
+                Output::None => panic!("client stopped sending"),
+            }
+            if let Some(ack) = server.process_output(now).dgram() {
+                assert!(ack.len() <= SMALL);
+                client.process_input(ack, now);
+            }
+            if let Ok((amount, f)) = server.stream_recv(stream_id, &mut buf[received..]) {
+                received += amount;
+                fin = f;
+            }
+        }
+        assert_eq!(received, 20_000);
+    }
+
+    /// This fills the congestion window from a single source.
+    /// As the pacer will interfere with this, this moves time forward
+    /// as `Output::Callback` is received.  Because it is hard to tell
+    /// from the return value whether a timeout is an ACK delay, PTO, or
+    /// pacing, this looks at the congestion window to tell when to stop.
+    /// Returns a list of datagrams and the new time.
+    fn fill_cwnd(src: &mut Connection, stream: u64, mut now: Instant) -> (Vec<Datagram>, Instant) {
+        const BLOCK_SIZE: usize = 4_096;
+        let mut total_dgrams = Vec::new();
+
+        qtrace!(
+            "fill_cwnd starting cwnd: {}",
+            src.loss_recovery.cwnd_avail()
+        );
+
+        loop {
+            let bytes_sent = src.stream_send(stream, &[0x42; BLOCK_SIZE]).unwrap();
+            qtrace!("fill_cwnd wrote {} bytes", bytes_sent);
+            if bytes_sent < BLOCK_SIZE {
+                break;
+            }
+        }
+
+        loop {
+            let pkt = src.process_output(now);
+            qtrace!(
+                "fill_cwnd cwnd remaining={}, output: {:?}",
+                src.loss_recovery.cwnd_avail(),
+                pkt
+            );
+            match pkt {
+                Output::Datagram(dgram) => {
+                    total_dgrams.push(dgram);
+                }
+                Output::Callback(t) => {
+                    if src.loss_recovery.cwnd_avail() < ACK_ONLY_SIZE_LIMIT {
+                        break;
+                    }
+                    now += t;
+                }
+                _ => panic!(),
+            }
+        }
+
+        (total_dgrams, now)
+    }
+
+    // Receive multiple packets and generate an ack-only packet.
+    fn ack_bytes(
+        dest: &mut Connection,
+        stream: u64,
+        in_dgrams: Vec<Datagram>,
+        now: Instant,
+    ) -> (Vec<Datagram>, Vec<Frame>) {
+        let mut srv_buf = [0; 4_096];
+        let mut recvd_frames = Vec::new();
+
+        for dgram in in_dgrams {
+            recvd_frames.extend(dest.test_process_input(dgram, now));
+        }
+
+        loop {
+            let (bytes_read, _fin) = dest.stream_recv(stream, &mut srv_buf).unwrap();
+            if bytes_read == 0 {
+                break;
+            }
+        }
+
+        let mut tx_dgrams = Vec::new();
+        while let Output::Datagram(dg) = dest.process_output(now) {
+            tx_dgrams.push(dg);
+        }
+
+        assert!((tx_dgrams.len() == 1) || (tx_dgrams.len() == 2));
+
+        (
+            tx_dgrams,
+            recvd_frames.into_iter().map(|(f, _e)| f).collect(),
+        )
+    }
+
+    /// This magic number is the size of the client's CWND after the handshake completes.
+    /// This includes the initial congestion window, as increased as a result
+    /// receiving acknowledgments for Initial and Handshake packets, which is
+    /// at least one full packet (the first Initial) and a little extra.
+    ///
+    /// As we change how we build packets, or even as NSS changes,
+    /// this number might be different.  The tests that depend on this
+    /// value could fail as a result of variations, so it's OK to just
+    /// change this value, but it is good to first understand where the
+    /// change came from.
+    const POST_HANDSHAKE_CWND: usize = PATH_MTU_V6 * (INITIAL_CWND_PKTS + 1) + 75;
+
+    /// Determine the number of packets required to fill the CWND.
+    const fn cwnd_packets(data: usize) -> usize {
+        (data + ACK_ONLY_SIZE_LIMIT - 1) / PATH_MTU_V6
+    }
+
+    /// Determine the size of the last packet.
+    /// The minimal size of a packet is `ACK_ONLY_SIZE_LIMIT`.
+    fn last_packet(cwnd: usize) -> usize {
+        if (cwnd % PATH_MTU_V6) > ACK_ONLY_SIZE_LIMIT {
+            cwnd % PATH_MTU_V6
+        } else {
+            PATH_MTU_V6
+        }
+    }
+
+    /// Assert that the set of packets fill the CWND.
+    fn assert_full_cwnd(packets: &[Datagram], cwnd: usize) {
+        assert_eq!(packets.len(), cwnd_packets(cwnd));
+        let (last, rest) = packets.split_last().unwrap();
+        assert!(rest.iter().all(|d| d.len() == PATH_MTU_V6));
+        assert_eq!(last.len(), last_packet(cwnd));
+    }
+
+    #[test]
+    /// Verify initial CWND is honored.
+    fn cc_slow_start() {
+        let mut client = default_client();
+        let mut server = default_server();
+
+        server
+            .set_local_tparam(
+                tparams::INITIAL_MAX_DATA,
+                TransportParameter::Integer(65536),
+            )
+            .unwrap();
+        connect_force_idle(&mut client, &mut server);
+
+        let now = now();
+
+        // Try to send a lot of data
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
+        let (c_tx_dgrams, _) = fill_cwnd(&mut client, 2, now);
+        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND);
+        assert!(client.loss_recovery.cwnd_avail() < ACK_ONLY_SIZE_LIMIT);
+    }
+
+    #[test]
+    /// Verify that CC moves to cong avoidance when a packet is marked lost.
+    fn cc_slow_start_to_cong_avoidance_recovery_period() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        // Create stream 0
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+
+        // Buffer up lot of data and generate packets
+        let (c_tx_dgrams, now) = fill_cwnd(&mut client, 0, now());
+        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND);
+        // Predict the packet number of the last packet sent.
+        // We have already sent one packet in `connect_force_idle` (an ACK),
+        // so this will be equal to the number of packets in this flight.
+        let flight1_largest = PacketNumber::try_from(c_tx_dgrams.len()).unwrap();
+
+        // Server: Receive and generate ack
+        let (s_tx_dgram, _recvd_frames) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+
+        // Client: Process ack
+        for dgram in s_tx_dgram {
+            let recvd_frames = client.test_process_input(dgram, now);
+
+            // Verify that server-sent frame was what we thought.
+            if let (
+                Frame::Ack {
+                    largest_acknowledged,
+                    ..
+                },
+                PNSpace::ApplicationData,
+            ) = recvd_frames[0]
+            {
+                assert_eq!(largest_acknowledged, flight1_largest);
+            } else {
+                panic!("Expected an application ACK");
+            }
+        }
+
+        // Client: send more
+        let (mut c_tx_dgrams, now) = fill_cwnd(&mut client, 0, now);
+        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND * 2);
+        let flight2_largest = flight1_largest + u64::try_from(c_tx_dgrams.len()).unwrap();
+
+        // Server: Receive and generate ack again, but drop first packet
+        c_tx_dgrams.remove(0);
+        let (s_tx_dgram, _recvd_frames) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+
+        // Client: Process ack
+        for dgram in s_tx_dgram {
+            let recvd_frames = client.test_process_input(dgram, now);
+
+            // Verify that server-sent frame was what we thought.
+            if let (
+                Frame::Ack {
+                    largest_acknowledged,
+                    ..
+                },
+                PNSpace::ApplicationData,
+            ) = recvd_frames[0]
+            {
+                assert_eq!(largest_acknowledged, flight2_largest);
+            } else {
+                panic!("Expected an application ACK");
+            }
+        }
+
+        // If we just triggered cong avoidance, these should be equal
+        assert_eq!(client.loss_recovery.cwnd(), client.loss_recovery.ssthresh());
+    }
+
+    #[test]
+    /// Verify that CC stays in recovery period when packet sent before start of
+    /// recovery period is acked.
+    fn cc_cong_avoidance_recovery_period_unchanged() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        // Create stream 0
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+
+        // Buffer up lot of data and generate packets
+        let (mut c_tx_dgrams, now) = fill_cwnd(&mut client, 0, now());
+        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND);
+
+        // Drop 0th packet. When acked, this should put client into CARP.
+        c_tx_dgrams.remove(0);
+
+        let c_tx_dgrams2 = c_tx_dgrams.split_off(5);
+
+        // Server: Receive and generate ack
+        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        for dgram in s_tx_dgram {
+            client.test_process_input(dgram, now);
+        }
+
+        // If we just triggered cong avoidance, these should be equal
+        let cwnd1 = client.loss_recovery.cwnd();
+        assert_eq!(cwnd1, client.loss_recovery.ssthresh());
+
+        // Generate ACK for more received packets
+        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams2, now);
+
+        // ACK more packets but they were sent before end of recovery period
+        for dgram in s_tx_dgram {
+            client.test_process_input(dgram, now);
+        }
+
+        // cwnd should not have changed since ACKed packets were sent before
+        // recovery period expired
+        let cwnd2 = client.loss_recovery.cwnd();
+        assert_eq!(cwnd1, cwnd2);
+    }
+
+    #[test]
+    /// Verify that CC moves out of recovery period when packet sent after start
+    /// of recovery period is acked.
+    fn cc_cong_avoidance_recovery_period_to_cong_avoidance() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        // Create stream 0
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+
+        // Buffer up lot of data and generate packets
+        let (mut c_tx_dgrams, mut now) = fill_cwnd(&mut client, 0, now());
+
+        // Drop 0th packet. When acked, this should put client into CARP.
+        c_tx_dgrams.remove(0);
+
+        // Server: Receive and generate ack
+        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+
+        // Client: Process ack
+        for dgram in s_tx_dgram {
+            client.test_process_input(dgram, now);
+        }
+
+        // Should be in CARP now.
+        let cwnd1 = client.loss_recovery.cwnd();
+
+        now += Duration::from_millis(10); // Time passes. CARP -> CA
+
+        // Client: Send more data
+        let (mut c_tx_dgrams, next_now) = fill_cwnd(&mut client, 0, now);
+        now = next_now;
+
+        // Only sent 2 packets, to generate an ack but also keep cwnd increase
+        // small
+        c_tx_dgrams.truncate(2);
+
+        // Generate ACK
+        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+
+        for dgram in s_tx_dgram {
+            client.test_process_input(dgram, now);
+        }
+
+        // ACK of pkts sent after start of recovery period should have caused
+        // exit from recovery period to just regular congestion avoidance. cwnd
+        // should now be a little higher but not as high as acked pkts during
+        // slow-start would cause it to be.
+        let cwnd2 = client.loss_recovery.cwnd();
+
+        assert!(cwnd2 > cwnd1);
+        assert!(cwnd2 < cwnd1 + 500);
+    }
+
+    #[test]
+    /// The congestion window halves when a packet is lost, and grows again once
+    /// packets that were sent after the loss are acknowledged.
+    fn congestion_stats_loss() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        let start = client.congestion_stats();
+        assert_eq!(start.cwnd, POST_HANDSHAKE_CWND);
+        assert_eq!(start.ssthresh, usize::MAX);
+
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+        let (mut c_tx_dgrams, mut now) = fill_cwnd(&mut client, 0, now());
+        let sent: usize = c_tx_dgrams.iter().map(|d| d.len()).sum();
+        assert_eq!(
+            client.congestion_stats().bytes_in_flight,
+            start.bytes_in_flight + sent
+        );
+
+        // Drop the first packet; acknowledging the rest declares it lost.
+        let lost = c_tx_dgrams.remove(0).len();
+        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        for dgram in s_tx_dgram {
+            client.test_process_input(dgram, now);
+        }
+        assert_eq!(client.stats().lost, 1);
+        let recovery = client.congestion_stats();
+        assert_eq!(recovery.bytes_in_flight, start.bytes_in_flight);
+        assert_eq!(recovery.ssthresh, recovery.cwnd);
+        // Acknowledgments that arrive before the loss is detected still count
+        // toward slow start, so the window is at most half of what it became.
+        assert!(recovery.cwnd <= (POST_HANDSHAKE_CWND + sent - lost) / 2);
+
+        // Packets sent after recovery started end it when they are acknowledged.
+        now += Duration::from_millis(10);
+        let (mut c_tx_dgrams, next_now) = fill_cwnd(&mut client, 0, now);
+        now = next_now;
+        c_tx_dgrams.truncate(2);
+        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        for dgram in s_tx_dgram {
+            client.test_process_input(dgram, now);
+        }
+        let recovered = client.congestion_stats();
+        assert!(recovered.cwnd > recovery.cwnd);
+        assert_eq!(recovered.ssthresh, recovery.ssthresh);
+    }
+
+    /// Send a full congestion window twice, acknowledging both, so that the
+    /// congestion window grows past the initial window.
+    fn grow_cwnd(client: &mut Connection, server: &mut Connection) -> Instant {
+        connect_force_idle(client, server);
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+        let mut now = now();
+        for _ in 0..2 {
+            let (c_tx_dgrams, next_now) = fill_cwnd(client, 0, now);
+            now = next_now;
+            let (s_tx_dgrams, _) = ack_bytes(server, 0, c_tx_dgrams, now);
+            for dgram in s_tx_dgrams {
+                client.process_input(dgram, now);
+            }
+        }
+        assert!(client.loss_recovery.cwnd() > POST_HANDSHAKE_CWND);
+        now
+    }
+
+    #[test]
+    /// A pause in the middle of a transfer doesn't produce a burst that is larger
+    /// than the initial window when the transfer resumes.
+    fn cc_idle_restart() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let now = grow_cwnd(&mut client, &mut server);
+
+        let (c_tx_dgrams, _) = fill_cwnd(&mut client, 0, now + Duration::from_secs(10));
+        let burst: usize = c_tx_dgrams.iter().map(|d| d.len()).sum();
+        assert!(burst <= INITIAL_CWND_PKTS * PATH_MTU_V6);
+        assert_eq!(client.stats().idle_restarts, 1);
+    }
+
+    #[test]
+    /// The window is reduced before the first packet after a pause is built, so
+    /// that packet is counted against the reduced window.
+    fn cc_idle_restart_first_packet() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let now = grow_cwnd(&mut client, &mut server) + Duration::from_secs(10);
+        // grow_cwnd leaves data waiting to be sent.
+        let dgram = client.process_output(now).dgram();
+        assert!(dgram.is_some());
+        assert_eq!(client.stats().idle_restarts, 1);
+        assert!(client.loss_recovery.cwnd() <= INITIAL_CWND_PKTS * PATH_MTU_V6);
+        assert_eq!(
+            client.loss_recovery.cwnd_avail(),
+            client.loss_recovery.cwnd() - dgram.unwrap().len()
+        );
+    }
+
+    #[test]
+    /// Without idle restart, the whole congestion window is used after a pause.
+    fn cc_idle_restart_disabled() {
+        let mut client = default_client();
+        let mut server = default_server();
+        client.set_idle_restart(false);
+        let now = grow_cwnd(&mut client, &mut server);
+
+        let (c_tx_dgrams, _) = fill_cwnd(&mut client, 0, now + Duration::from_secs(10));
+        let burst: usize = c_tx_dgrams.iter().map(|d| d.len()).sum();
+        assert!(burst > INITIAL_CWND_PKTS * PATH_MTU_V6);
+        assert_eq!(client.stats().idle_restarts, 0);
+    }
+
+    fn induce_persistent_congestion(
+        client: &mut Connection,
+        server: &mut Connection,
+        mut now: Instant,
+    ) -> Instant {
+        // Note: wait some arbitrary time that should be longer than pto
+        // timer. This is rather brittle.
+        now += AT_LEAST_PTO;
+
+        let (c_tx_dgrams, next_now) = fill_cwnd(client, 0, now);
+        now = next_now;
+        assert_eq!(c_tx_dgrams.len(), 2); // Two PTO packets
+
+        now += Duration::from_secs(2);
+        let (c_tx_dgrams, next_now) = fill_cwnd(client, 0, now);
+        now = next_now;
+        assert_eq!(c_tx_dgrams.len(), 2); // Two PTO packets
+
+        now += Duration::from_secs(4);
+        let (c_tx_dgrams, next_now) = fill_cwnd(client, 0, now);
+        now = next_now;
+        assert_eq!(c_tx_dgrams.len(), 2); // Two PTO packets
+
+        // Generate ACK
+        let (s_tx_dgram, _) = ack_bytes(server, 0, c_tx_dgrams, now);
+
+        // In PC now.
+        for dgram in s_tx_dgram {
+            client.test_process_input(dgram, now);
+        }
+
+        assert_eq!(client.loss_recovery.cwnd(), MIN_CONG_WINDOW);
+        now
+    }
+
+    #[test]
+    /// Verify transition to persistent congestion state if conditions are met.
+    fn cc_slow_start_to_persistent_congestion_no_acks() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        // Create stream 0
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+
+        // Buffer up lot of data and generate packets
+        let (c_tx_dgrams, mut now) = fill_cwnd(&mut client, 0, now());
+        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND);
+
+        // Server: Receive and generate ack
+        now += Duration::from_millis(100);
+        let (_s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+
+        // ACK lost.
+
+        induce_persistent_congestion(&mut client, &mut server, now);
+    }
+
+    #[test]
+    /// Verify transition to persistent congestion state if conditions are met.
+    fn cc_slow_start_to_persistent_congestion_some_acks() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        // Create stream 0
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+
+        // Buffer up lot of data and generate packets
+        let (c_tx_dgrams, mut now) = fill_cwnd(&mut client, 0, now());
+        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND);
+
+        // Server: Receive and generate ack
+        now += Duration::from_millis(100);
+        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+
+        now += Duration::from_millis(100);
+        for dgram in s_tx_dgram {
+            client.test_process_input(dgram, now);
+        }
+
+        // send bytes that will be lost
+        let (_c_tx_dgrams, next_now) = fill_cwnd(&mut client, 0, now);
+        now = next_now + Duration::from_millis(100);
+
+        induce_persistent_congestion(&mut client, &mut server, now);
+    }
+
+    #[test]
+    /// Verify persistent congestion moves to slow start after recovery period
+    /// ends.
+    fn cc_persistent_congestion_to_slow_start() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        // Create stream 0
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+
+        // Buffer up lot of data and generate packets
+        let (c_tx_dgrams, mut now) = fill_cwnd(&mut client, 0, now());
+        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND);
+
+        // Server: Receive and generate ack
+        now += Duration::from_millis(10);
+        let (_s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+
+        // ACK lost.
+
+        now = induce_persistent_congestion(&mut client, &mut server, now);
+
+        // New part of test starts here
+
+        now += Duration::from_millis(10);
+
+        // Send packets from after start of CARP
+        let (c_tx_dgrams, next_now) = fill_cwnd(&mut client, 0, now);
+        assert_eq!(c_tx_dgrams.len(), 2);
+
+        // Server: Receive and generate ack
+        now = next_now + Duration::from_millis(100);
+        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+
+        // No longer in CARP. (pkts acked from after start of CARP)
+        // Should be in slow start now.
+        for dgram in s_tx_dgram {
+            client.test_process_input(dgram, now);
+        }
+
+        // ACKing 2 packets should let client send 4.
+        let (c_tx_dgrams, _) = fill_cwnd(&mut client, 0, now);
+        assert_eq!(c_tx_dgrams.len(), 4);
+    }
+
+    fn congestion_events(c: &mut Connection) -> Vec<CongestionState> {
+        c.events()
+            .filter_map(|e| match e {
+                ConnectionEvent::CongestionStateChanged { state } => Some(state),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn congestion_state_recovery() {
+        const RTT: Duration = Duration::from_millis(100);
+        let mut client = default_client();
+        let mut server = default_server();
+        let now = connect_with_rtt(&mut client, &mut server, now(), RTT);
+        let before = client.current_bandwidth_estimate();
+        let cwnd = u64::try_from(client.loss_recovery.cwnd()).unwrap();
+        assert_eq!(before, cwnd * 10);
+
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+        let (mut c_tx_dgrams, now) = fill_cwnd(&mut client, 0, now);
+
+        // Drop the first packet; acknowledging the rest starts recovery.
+        c_tx_dgrams.remove(0);
+        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now + RTT / 2);
+        let now = now + RTT;
+        for dgram in s_tx_dgram {
+            client.test_process_input(dgram, now);
+        }
+        let _ = client.process_output(now);
+        let events = congestion_events(&mut client);
+        assert_eq!(events, vec![CongestionState::Recovery]);
+        assert!(client.current_bandwidth_estimate() < before);
+
+        // Acknowledging packets sent after that ends recovery, but that
+        // isn't reported until a round trip after the last report.
+        let mut later = now + Duration::from_millis(10);
+        let mut c_tx_dgrams = Vec::new();
+        while c_tx_dgrams.len() < 2 {
+            match client.process_output(later) {
+                Output::Datagram(d) => c_tx_dgrams.push(d),
+                Output::Callback(t) => later += t,
+                Output::None => panic!("the client should be able to send"),
+            }
+        }
+        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, later);
+        for dgram in s_tx_dgram {
+            client.test_process_input(dgram, later);
+        }
+        let _ = client.process_output(later);
+        assert!(congestion_events(&mut client).is_empty());
+
+        // A timer is set for when the change can be reported, so it is reported
+        // without anything else happening on the connection.
+        let report = now + client.loss_recovery.rtt();
+        assert!(client
+            .timers(later)
+            .contains(&(report, TimerKind::CongestionState)));
+        let delay = loop {
+            match client.process_output(later) {
+                Output::Datagram(_) => {}
+                Output::Callback(t) => break t,
+                Output::None => panic!("the client should have timers"),
+            }
+        };
+        assert!(delay <= report - later);
+        let _ = client.process(None, report);
+        assert_eq!(
+            congestion_events(&mut client),
+            vec![CongestionState::NoCongestion]
+        );
+        assert!(client
+            .timers(report)
+            .iter()
+            .all(|(_, k)| *k != TimerKind::CongestionState));
+    }
+
+    #[test]
+    fn congestion_state_persistent() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+        let (c_tx_dgrams, mut now) = fill_cwnd(&mut client, 0, now());
+        now += Duration::from_millis(10);
+        let (_s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        now = induce_persistent_congestion(&mut client, &mut server, now);
+
+        // Persistent congestion is reported the next time the client sends.
+        now += Duration::from_millis(10);
+        let (c_tx_dgrams, next_now) = fill_cwnd(&mut client, 0, now);
+        assert_eq!(
+            congestion_events(&mut client),
+            vec![CongestionState::PersistentCongestion]
+        );
+        assert!(client.current_bandwidth_estimate() > 0);
+
+        now = next_now + Duration::from_millis(100);
+        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        for dgram in s_tx_dgram {
+            client.test_process_input(dgram, now);
+        }
+        let _ = fill_cwnd(&mut client, 0, now);
+        assert_eq!(
+            congestion_events(&mut client),
+            vec![CongestionState::NoCongestion]
+        );
+    }
+
+    fn check_discarded(peer: &mut Connection, pkt: Datagram, dropped: usize, dups: usize) {
+        let dropped_before = peer.stats.dropped_rx;
+        let dups_before = peer.stats.dups_rx;
+        let out = peer.process(Some(pkt), now());
+        assert!(out.as_dgram_ref().is_none());
+        assert_eq!(dropped, peer.stats.dropped_rx - dropped_before);
+        assert_eq!(dups, peer.stats.dups_rx - dups_before);
+    }
+
+    #[test]
+    fn discarded_initial_keys() {
+        qdebug!("---- client: generate CH");
+        let mut client = default_client();
+        let init_pkt_c = client.process(None, now()).dgram();
+        assert!(init_pkt_c.is_some());
+        assert_eq!(init_pkt_c.as_ref().unwrap().len(), PATH_MTU_V6);
+
+        qdebug!("---- server: CH -> SH, EE, CERT, CV, FIN");
+        let mut server = default_server();
+        let init_pkt_s = server.process(init_pkt_c.clone(), now()).dgram();
+        assert!(init_pkt_s.is_some());
+
+        qdebug!("---- client: cert verification");
+        let out = client.process(init_pkt_s.clone(), now()).dgram();
+        assert!(out.is_some());
+
+        // The client has received handshake packet. It will remove the Initial keys.
+        // We will check this by processing init_pkt_s a second time.
+        // The initial packet should be dropped. The packet contains a Handshake packet as well, which
+        // will be marked as dup.
+        check_discarded(&mut client, init_pkt_s.unwrap(), 1, 1);
+
+        assert!(maybe_authenticate(&mut client));
+
+        // The server has not removed the Initial keys yet, because it has not yet received a Handshake
+        // packet from the client.
+        // We will check this by processing init_pkt_c a second time.
+        // The dropped packet is padding. The Initial packet has been mark dup.
+        check_discarded(&mut server, init_pkt_c.clone().unwrap(), 1, 1);
+
+        qdebug!("---- client: SH..FIN -> FIN");
+        let out = client.process(None, now()).dgram();
+        assert!(out.is_some());
+
+        // The server will process the first Handshake packet.
+        // After this the Initial keys will be dropped.
+        let out = server.process(out, now()).dgram();
+        assert!(out.is_some());
+
+        // Check that the Initial keys are dropped at the server
+        // We will check this by processing init_pkt_c a third time.
+        // The Initial packet has been dropped and padding that follows it.
+        // There is no dups, everything has been dropped.
+        check_discarded(&mut server, init_pkt_c.unwrap(), 1, 0);
+    }
+
+    /// Send something on a stream from `sender` to `receiver`.
+    /// Return the resulting datagram.
+    #[must_use]
+    fn send_something(sender: &mut Connection, now: Instant) -> Datagram {
+        let stream_id = sender.stream_create(StreamType::UniDi).unwrap();
+        assert!(sender.stream_send(stream_id, b"data").is_ok());
+        assert!(sender.stream_close_send(stream_id).is_ok());
+        let dgram = sender.process(None, now).dgram();
+        dgram.expect("should have something to send")
+    }
+
+    /// Send something on a stream from `sender` to `receiver`.
+    /// Return any ACK that might result.
+    fn send_and_receive(
+        sender: &mut Connection,
+        receiver: &mut Connection,
+        now: Instant,
+    ) -> Option<Datagram> {
+        let dgram = send_something(sender, now);
+        receiver.process(Some(dgram), now).dgram()
+    }
+
+    #[test]
+    fn key_update_client() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        let mut now = now();
+
+        assert_eq!(client.get_epochs(), (Some(3), Some(3))); // (write, read)
+        assert_eq!(server.get_epochs(), (Some(3), Some(3)));
+
+        // TODO(mt) this needs to wait for handshake confirmation,
+        // but for now, we can do this immediately.
+        assert!(client.initiate_key_update().is_ok());
+        assert!(client.initiate_key_update().is_err());
+
+        // Initiating an update should only increase the write epoch.
+        assert_eq!(
+            Output::Callback(LOCAL_IDLE_TIMEOUT),
+            client.process(None, now)
+        );
+        assert_eq!(client.get_epochs(), (Some(4), Some(3)));
+
+        // Send something to propagate the update.
+        assert!(send_and_receive(&mut client, &mut server, now).is_none());
+
+        // The server should now be waiting to discharge read keys.
+        assert_eq!(server.get_epochs(), (Some(4), Some(3)));
+        let res = server.process(None, now);
+        if let Output::Callback(t) = res {
+            assert!(t < LOCAL_IDLE_TIMEOUT);
+        } else {
+            panic!("server should now be waiting to clear keys");
+        }
+
+        // Without having had time to purge old keys, more updates are blocked.
+        // The spec would permits it at this point, but we are more conservative.
+        assert!(client.initiate_key_update().is_err());
+        // The server can't update until it receives an ACK for a packet.
+        assert!(server.initiate_key_update().is_err());
+
+        // Waiting now for at least a PTO should cause the server to drop old keys.
+        // But at this point the client hasn't received a key update from the server.
+        // It will be stuck with old keys.
+        now += AT_LEAST_PTO;
+        let dgram = client.process(None, now).dgram();
+        assert!(dgram.is_some()); // Drop this packet.
+        assert_eq!(client.get_epochs(), (Some(4), Some(3)));
+        server.process(None, now);
+        assert_eq!(server.get_epochs(), (Some(4), Some(4)));
+
+        // Even though the server has updated, it hasn't received an ACK yet.
+        assert!(server.initiate_key_update().is_err());
+
+        // Now get an ACK from the server.
+        // The previous PTO packet (see above) was dropped, so we should get an ACK here.
+        let dgram = send_and_receive(&mut client, &mut server, now);
+        assert!(dgram.is_some());
+        let res = client.process(dgram, now);
+        // This is the first packet that the client has received from the server
+        // with new keys, so its read timer just started.
+        if let Output::Callback(t) = res {
+            assert!(t < LOCAL_IDLE_TIMEOUT);
+        } else {
+            panic!("client should now be waiting to clear keys");
+        }
+
+        assert!(client.initiate_key_update().is_err());
+        assert_eq!(client.get_epochs(), (Some(4), Some(3)));
+        // The server can't update until it gets something from the client.
+        assert!(server.initiate_key_update().is_err());
+
+        now += AT_LEAST_PTO;
+        client.process(None, now);
+        assert_eq!(client.get_epochs(), (Some(4), Some(4)));
+    }
+
+    #[test]
+    fn key_update_consecutive() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let now = now();
+
+        assert!(server.initiate_key_update().is_ok());
+        assert_eq!(server.get_epochs(), (Some(4), Some(3)));
+
+        // Server sends something.
+        // Send twice and drop the first to induce an ACK from the client.
+        let _ = send_something(&mut server, now); // Drop this.
+
+        // Another packet from the server will cause the client to ACK and update keys.
+        let dgram = send_and_receive(&mut server, &mut client, now);
+        assert!(dgram.is_some());
+        assert_eq!(client.get_epochs(), (Some(4), Some(3)));
+
+        // Have the server process the ACK.
+        if let Output::Callback(_) = server.process(dgram, now) {
+            assert_eq!(server.get_epochs(), (Some(4), Some(3)));
+            // Now move the server temporarily into the future so that it
+            // rotates the keys.  The client stays in the present.
+            server.process(None, now + AT_LEAST_PTO);
+            assert_eq!(server.get_epochs(), (Some(4), Some(4)));
+        } else {
+            panic!("server should have a timer set");
+        }
+
+        // Now update keys on the server again.
+        assert!(server.initiate_key_update().is_ok());
+        assert_eq!(server.get_epochs(), (Some(5), Some(4)));
+
+        let dgram = send_something(&mut server, now + AT_LEAST_PTO);
+
+        // However, as the server didn't wait long enough to update again, the
+        // client hasn't rotated its keys, so the packet gets dropped.
+        check_discarded(&mut client, dgram, 1, 0);
+    }
+
+    // Packets from before a key update can still be read for a while after it.
+    #[test]
+    fn key_update_reordered() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        let mut now = now();
+
+        let old1 = send_something(&mut client, now);
+        let old2 = send_something(&mut client, now);
+        assert!(client.initiate_key_update().is_ok());
+        let new = send_something(&mut client, now);
+
+        // The server sees the new key phase first.
+        server.process_input(new, now);
+        assert_eq!(server.get_epochs(), (Some(4), Some(3)));
+
+        // The old keys are kept for now.
+        let dropped_before = server.stats.dropped_rx;
+        server.process_input(old1, now);
+        assert_eq!(server.stats.dropped_rx, dropped_before);
+
+        // Once they are gone, packets that use them are dropped.
+        now += AT_LEAST_PTO;
+        let _ = server.process(None, now);
+        assert_eq!(server.get_epochs(), (Some(4), Some(4)));
+        server.process_input(old2, now);
+        assert_eq!(server.stats.dropped_rx, dropped_before + 1);
+        assert_eq!(server.stats.dropped_by_reason[&DropReason::KeyPhase], 1);
+    }
+
+    /// Have `sender` update keys between sending two pairs of packets, and have
+    /// `receiver` see them out of order.  The acknowledgments that `receiver`
+    /// sends from either side of the update also arrive out of order.
+    fn key_update_ack_reordered(sender: &mut Connection, receiver: &mut Connection) {
+        let mut now = now();
+
+        let old1 = send_something(sender, now);
+        let old2 = send_something(sender, now);
+        assert!(sender.initiate_key_update().is_ok());
+        let new1 = send_something(sender, now);
+        let new2 = send_something(sender, now);
+
+        // This acknowledgment uses the old keys.
+        receiver.process_input(old1, now);
+        now += ACK_DELAY;
+        let ack_old = receiver.process(None, now).dgram();
+        assert!(ack_old.is_some());
+
+        // The packet that was sent before the update arrives after the first
+        // packet that uses the new keys.
+        receiver.process_input(new1, now);
+        receiver.process_input(old2, now);
+        receiver.process_input(new2, now);
+        now += ACK_DELAY;
+        let ack_new = receiver.process(None, now).dgram();
+        assert!(ack_new.is_some());
+
+        let cwnd_before = sender.loss_recovery.cwnd();
+        sender.process_input(ack_new.unwrap(), now);
+        let largest = sender
+            .loss_recovery
+            .largest_acknowledged_pn(PNSpace::ApplicationData);
+        assert!(largest.is_some());
+        assert_eq!(
+            sender
+                .loss_recovery
+                .unacked_packets(PNSpace::ApplicationData),
+            Some(0)
+        );
+
+        // The acknowledgment from before the update changes nothing.
+        sender.process_input(ack_old.unwrap(), now);
+        assert_eq!(
+            sender
+                .loss_recovery
+                .largest_acknowledged_pn(PNSpace::ApplicationData),
+            largest
+        );
+        assert_eq!(
+            sender.loss_recovery.congestion_state(),
+            CongestionState::NoCongestion
+        );
+        assert!(sender.loss_recovery.cwnd() >= cwnd_before);
+        assert!(sender.loss_recovery.rtt() <= ACK_DELAY * 2);
+
+        // Nothing was dropped, because each packet was read with the right keys.
+        for c in &[sender, receiver] {
+            let dropped = &c.stats.dropped_by_reason;
+            assert!(!dropped.contains_key(&DropReason::KeyPhase));
+            assert!(!dropped.contains_key(&DropReason::DecryptionFailure));
+        }
+    }
+
+    #[test]
+    fn key_update_ack_reordered_client() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        key_update_ack_reordered(&mut client, &mut server);
+    }
+
+    #[test]
+    fn key_update_ack_reordered_server() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        key_update_ack_reordered(&mut server, &mut client);
+    }
+
+    // Key updates can't be initiated too early.
+    #[test]
+    fn key_update_before_confirmed() {
+        let mut client = default_client();
+        assert!(client.initiate_key_update().is_err());
+        let mut server = default_server();
+        assert!(server.initiate_key_update().is_err());
+
+        // Client Initial
+        let dgram = client.process(None, now()).dgram();
+        assert!(dgram.is_some());
+        assert!(client.initiate_key_update().is_err());
+
+        // Server Initial + Handshake
+        let dgram = server.process(dgram, now()).dgram();
+        assert!(dgram.is_some());
+        assert!(server.initiate_key_update().is_err());
+
+        // Client Handshake
+        client.process_input(dgram.unwrap(), now());
+        assert!(client.initiate_key_update().is_err());
+
+        assert!(maybe_authenticate(&mut client));
+        assert!(client.initiate_key_update().is_err());
+
+        let dgram = client.process(None, now()).dgram();
+        assert!(dgram.is_some());
+        assert!(client.initiate_key_update().is_err());
+
+        // Server HANDSHAKE_DONE
+        let dgram = server.process(dgram, now()).dgram();
+        assert!(dgram.is_some());
+        assert!(server.initiate_key_update().is_ok());
+
+        // Client receives HANDSHAKE_DONE
+        let dgram = client.process(dgram, now()).dgram();
+        assert!(dgram.is_none());
+        assert!(client.initiate_key_update().is_ok());
+    }
+
+    #[test]
+    fn ack_are_not_cc() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        // Create a stream
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+
+        // Buffer up lot of data and generate packets, so that cc window is filled.
+        let (c_tx_dgrams, now) = fill_cwnd(&mut client, 0, now());
+        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND);
+
+        // The server hasn't received any of these packets yet, the server
+        // won't ACK, but if it sends an ack-eliciting packet instead.
+        qdebug!([server], "Sending ack-eliciting");
+        assert_eq!(server.stream_create(StreamType::BiDi).unwrap(), 1);
+        server.stream_send(1, b"dropped").unwrap();
+        let dropped_packet = server.process(None, now).dgram();
+        assert!(dropped_packet.is_some()); // Now drop this one.
+
+        // Now the server sends a packet that will force an ACK,
+        // because the client will detect a gap.
+        server.stream_send(1, b"sent").unwrap();
+        let ack_eliciting_packet = server.process(None, now).dgram();
+        assert!(ack_eliciting_packet.is_some());
+
+        // The client can ack the server packet even if cc windows is full.
+        qdebug!([client], "Process ack-eliciting");
+        let ack_pkt = client.process(ack_eliciting_packet, now).dgram();
+        assert!(ack_pkt.is_some());
+        qdebug!([server], "Handle ACK");
+        let frames = server.test_process_input(ack_pkt.unwrap(), now);
+        assert_eq!(frames.len(), 1);
+        assert!(matches!(
+            frames[0],
+            (Frame::Ack { .. }, PNSpace::ApplicationData)
+        ));
+    }
+
+    #[test]
+    fn after_fin_is_read_conn_events_for_stream_should_be_removed() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let id = server.stream_create(StreamType::BiDi).unwrap();
+        server.stream_send(id, &[6; 10]).unwrap();
+        server.stream_close_send(id).unwrap();
+        let out = server.process(None, now()).dgram();
+        assert!(out.is_some());
+
+        let _ = client.process(out, now());
+
+        // read from the stream before checking connection events.
+        let mut buf = vec![0; 4000];
+        let (_, fin) = client.stream_recv(id, &mut buf).unwrap();
+        assert_eq!(fin, true);
+
+        // Make sure we do not have RecvStreamReadable events for the stream when fin has been read.
+        let readable_stream_evt =
+            |e| matches!(e, ConnectionEvent::RecvStreamReadable { stream_id } if stream_id == id);
+        assert!(!client.events().any(readable_stream_evt));
+    }
+
+    #[test]
+    fn after_stream_stop_sending_is_called_conn_events_for_stream_should_be_removed() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let id = server.stream_create(StreamType::BiDi).unwrap();
+        server.stream_send(id, &[6; 10]).unwrap();
+        server.stream_close_send(id).unwrap();
+        let out = server.process(None, now()).dgram();
+        assert!(out.is_some());
+
+        let _ = client.process(out, now());
+
+        // send stop seending.
+        client
+            .stream_stop_sending(id, Error::NoError.code())
+            .unwrap();
+
+        // Make sure we do not have RecvStreamReadable events for the stream after stream_stop_sending
+        // has been called.
+        let readable_stream_evt =
+            |e| matches!(e, ConnectionEvent::RecvStreamReadable { stream_id } if stream_id == id);
+        assert!(!client.events().any(readable_stream_evt));
+    }
+
+    // During the handshake, an application close should be sanitized.
+    #[test]
+    fn early_application_close() {
+        let mut client = default_client();
+        let mut server = default_server();
+
+        // One flight each.
+        let dgram = client.process(None, now()).dgram();
+        assert!(dgram.is_some());
+        let dgram = server.process(dgram, now()).dgram();
+        assert!(dgram.is_some());
+
+        server.close(now(), 77, String::from(""));
+        assert!(server.state().closed());
+        let dgram = server.process(None, now()).dgram();
+        assert!(dgram.is_some());
+
+        let frames = client.test_process_input(dgram.unwrap(), now());
+        assert!(matches!(
+            frames[0],
+            (
+                Frame::ConnectionClose {
+                    error_code: CloseError::Transport(code),
+                    ..
+                },
+                PNSpace::Initial,
+            ) if code == Error::ApplicationError.code()
+        ));
+        assert!(client.state().closed());
+    }
+
+    #[test]
+    fn bad_tls_version() {
+        let mut client = default_client();
+        // Do a bad, bad thing.
+        client
+            .crypto
+            .tls
+            .set_option(neqo_crypto::Opt::Tls13CompatMode, true)
+            .unwrap();
+        let mut server = default_server();
+        let dgram = client.process(None, now()).dgram();
+        assert!(dgram.is_some());
+        let dgram = server.process(dgram, now()).dgram();
+        assert_eq!(
+            *server.state(),
+            State::Closed(ConnectionError::Transport(Error::ProtocolViolation))
+        );
+        assert!(dgram.is_some());
+        let frames = client.test_process_input(dgram.unwrap(), now());
+        assert!(matches!(
+            frames[0],
+            (
+                Frame::ConnectionClose {
+                    error_code: CloseError::Transport(_),
+                    ..
+                },
+                PNSpace::Initial,
+            )
+        ));
+    }
+
+    #[test]
+    fn pace() {
+        const RTT: Duration = Duration::from_millis(1000);
+        const DATA: &[u8] = &[0xcc; 4_096];
+        let mut client = default_client();
+        let mut server = default_server();
+        let mut now = connect_rtt_idle(&mut client, &mut server, RTT);
+
+        // Now fill up the pipe and watch it trickle out.
+        let stream = client.stream_create(StreamType::BiDi).unwrap();
+        loop {
+            let written = client.stream_send(stream, DATA).unwrap();
+            if written < DATA.len() {
+                break;
+            }
+        }
+        let mut count = 0;
+        // We should get a burst at first.
+        for _ in 0..PACING_BURST_SIZE {
+            let dgram = client.process_output(now).dgram();
+            assert!(dgram.is_some());
+            count += 1;
+        }
+        let gap = client.process_output(now).callback();
+        assert_ne!(gap, Duration::new(0, 0));
+        for _ in PACING_BURST_SIZE..cwnd_packets(POST_HANDSHAKE_CWND) {
+            assert_eq!(client.process_output(now).callback(), gap);
+            now += gap;
+            let dgram = client.process_output(now).dgram();
+            assert!(dgram.is_some());
+            count += 1;
+        }
+        assert_eq!(count, cwnd_packets(POST_HANDSHAKE_CWND));
+        let fin = client.process_output(now).callback();
+        assert_ne!(fin, Duration::new(0, 0));
+        assert_ne!(fin, gap);
+    }
+
+    #[test]
+    fn output_batch() {
+        const RTT: Duration = Duration::from_millis(1000);
+        let mut client = default_client();
+        let mut server = default_server();
+        let mut now = connect_rtt_idle(&mut client, &mut server, RTT);
+
+        let stream = client.stream_create(StreamType::BiDi).unwrap();
+        client.stream_send(stream, &[0xcc; 10_000]).unwrap();
+        // The batch stops where the pacer does, with the timer that
+        // `process_output` gives.
+        let (dgrams, out) = client.process_output_batch(now, 10);
+        assert_eq!(dgrams.len(), PACING_BURST_SIZE);
+        let gap = out.callback();
+        assert_ne!(gap, Duration::new(0, 0));
+        assert_eq!(client.process_output(now).callback(), gap);
+
+        // A full batch asks to be called again straight away.
+        now += gap;
+        let (dgrams, out) = client.process_output_batch(now, 1);
+        assert_eq!(dgrams.len(), 1);
+        assert_eq!(out, Output::Callback(Duration::new(0, 0)));
+    }
+
+    #[test]
+    fn pace_granularity() {
+        const RTT: Duration = Duration::from_millis(1000);
+        const DATA: &[u8] = &[0xcc; 4_096];
+        let mut client = default_client();
+        let mut server = default_server();
+        let now = connect_rtt_idle(&mut client, &mut server, RTT);
+        // Everything the pacer would release in the next RTT is sent now.
+        client.set_pacing_granularity(RTT);
+
+        let stream = client.stream_create(StreamType::BiDi).unwrap();
+        loop {
+            let written = client.stream_send(stream, DATA).unwrap();
+            if written < DATA.len() {
+                break;
+            }
+        }
+        for _ in 0..cwnd_packets(POST_HANDSHAKE_CWND) {
+            assert!(client.process_output(now).dgram().is_some());
+        }
+        // The congestion window is full.
+        assert!(client.process_output(now).dgram().is_none());
+    }
+
+    #[test]
+    fn handshake_not_paced() {
+        let mut client = default_client();
+        client.set_handshake_padding(HANDSHAKE_PADDING).unwrap();
+
+        // All of the Initial packets are sent at once.
+        let now = now();
+        let mut initials = 0;
+        while client.process_output(now).dgram().is_some() {
+            initials += 1;
+        }
+        assert!(initials > PACING_BURST_SIZE);
+        assert!(initials * PATH_MTU_V6 > HANDSHAKE_PADDING);
+    }
+
+    #[test]
+    fn loss_recovery_crash() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let now = now();
+
+        // The server sends something, but we will drop this.
+        let _ = send_something(&mut server, now);
+
+        // Then send something again, but let it through.
+        let ack = send_and_receive(&mut server, &mut client, now);
+        assert!(ack.is_some());
+
+        // Have the server process the ACK.
+        let cb = server.process(ack, now).callback();
+        assert!(cb > Duration::from_secs(0));
+
+        // Now we leap into the future.  The server should regard the first
+        // packet as lost based on time alone.
+        let dgram = server.process(None, now + AT_LEAST_PTO).dgram();
+        assert!(dgram.is_some());
+
+        // This crashes.
+        let _ = send_something(&mut server, now + AT_LEAST_PTO);
+    }
+
+    // If we receive packets after the PTO timer has fired, we won't clear
+    // the PTO state, but we might need to acknowledge those packets.
+    // This shouldn't happen, but we found that some implementations do this.
+    #[test]
+    fn ack_after_pto() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        let mut now = now();
+
+        // The client sends and is forced into a PTO.
+        let _ = send_something(&mut client, now);
+
+        // Jump forward to the PTO and drain the PTO packets.
+        now += AT_LEAST_PTO;
+        for _ in 0..PTO_PACKET_COUNT {
+            let dgram = client.process(None, now).dgram();
+            assert!(dgram.is_some());
+        }
+        assert!(client.process(None, now).dgram().is_none());
+
+        // The server now needs to send something that will cause the
+        // client to want to acknowledge it.  A little out of order
+        // delivery is just the thing.
+        // Note: The server can't ACK anything here, but none of what
+        // the client has sent so far has been transferred.
+        let _ = send_something(&mut server, now);
+        let dgram = send_something(&mut server, now);
+
+        // The client is now after a PTO, but if it receives something
+        // that demands acknowledgment, it will send just the ACK.
+        let ack = client.process(Some(dgram), now).dgram();
+        assert!(ack.is_some());
+
+        // Make sure that the packet only contained ACK frames.
+        let frames = server.test_process_input(ack.unwrap(), now);
+        assert_eq!(frames.len(), 1);
+        for (frame, space) in frames {
+            assert_eq!(space, PNSpace::ApplicationData);
+            assert!(matches!(frame, Frame::Ack { .. }));
+        }
+    }
+
+    /// Test the interaction between the loss recovery timer
+    /// and the closing timer.
+    #[test]
+    fn closing_timers_interation() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let mut now = now();
+
+        // We're going to induce time-based loss recovery so that timer is set.
+        let _p1 = send_something(&mut client, now);
+        let p2 = send_something(&mut client, now);
+        let ack = server.process(Some(p2), now).dgram();
+        assert!(ack.is_some()); // This is an ACK.
+
+        // After processing the ACK, we should be on the loss recovery timer.
+        let cb = client.process(ack, now).callback();
+        assert_ne!(cb, Duration::from_secs(0));
+        now += cb;
+
+        // Rather than let the timer pop, close the connection.
+        client.close(now, 0, "");
+        let client_close = client.process(None, now).dgram();
+        assert!(client_close.is_some());
+        // This should now report the end of the closing period, not a
+        // zero-duration wait driven by the (now defunct) loss recovery timer.
+        let client_close_timer = client.process(None, now).callback();
+        assert_ne!(client_close_timer, Duration::from_secs(0));
+    }
+
+    #[test]
+    fn closing_and_draining() {
+        const APP_ERROR: AppError = 7;
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        // Save a packet from the client for later.
+        let p1 = send_something(&mut client, now());
+
+        // Close the connection.
+        client.close(now(), APP_ERROR, "");
+        let client_close = client.process(None, now()).dgram();
+        assert!(client_close.is_some());
+        let client_close_timer = client.process(None, now()).callback();
+        assert_ne!(client_close_timer, Duration::from_secs(0));
+
+        // The client will spit out the same packet in response to anything it receives.
+        // That packet isn't counted as dropped, but the reason is recorded.
+        let p3 = send_something(&mut server, now());
+        let dropped = client.stats().dropped_rx;
+        let _ = client.take_datagram_dispositions();
+        let client_close2 = client.process(Some(p3), now()).dgram();
+        assert_eq!(
+            client_close.as_ref().unwrap().len(),
+            client_close2.as_ref().unwrap().len()
+        );
+        assert_eq!(client.stats().dropped_rx, dropped);
+        assert_eq!(
+            client.take_datagram_dispositions()[0].dropped,
+            vec![DropReason::Closed]
+        );
+
+        // After this time, the client should transition to closed.
+        let end = client.process(None, now() + client_close_timer);
+        assert_eq!(end, Output::None);
+        assert_eq!(
+            *client.state(),
+            State::Closed(ConnectionError::Application(APP_ERROR))
+        );
+
+        // When the server receives the close, it too should generate CONNECTION_CLOSE.
+        let server_close = server.process(client_close, now()).dgram();
+        assert!(server.state().closed());
+        assert!(server_close.is_some());
+        // .. but it ignores any further close packets.
+        let server_close_timer = server.process(client_close2, now()).callback();
+        assert_ne!(server_close_timer, Duration::from_secs(0));
+        // Even a legitimate packet without a close in it.
+        let server_close_timer2 = server.process(Some(p1), now()).callback();
+        assert_eq!(server_close_timer, server_close_timer2);
+
+        let end = server.process(None, now() + server_close_timer);
+        assert_eq!(end, Output::None);
+        assert_eq!(
+            *server.state(),
+            State::Closed(ConnectionError::Transport(Error::PeerApplicationError(
+                APP_ERROR
+            )))
+        );
+    }
+
+    /// When we declare a packet as lost, we keep it around for a while for another loss period.
+    /// Those packets should not affect how we report the loss recovery timer.
+    /// As the loss recovery timer based on RTT we use that to drive the state.
+    #[test]
+    fn lost_but_kept_and_lr_timer() {
+        const RTT: Duration = Duration::from_secs(1);
+        let mut client = default_client();
+        let mut server = default_server();
+        let mut now = connect_with_rtt(&mut client, &mut server, now(), RTT);
+
+        // Two packets (p1, p2) are sent at around t=0.  The first is lost.
+        let _p1 = send_something(&mut client, now);
+        let p2 = send_something(&mut client, now);
+
+        // At t=RTT/2 the server receives the packet and ACKs it.
+        now += RTT / 2;
+        let ack = server.process(Some(p2), now).dgram();
+        assert!(ack.is_some());
+        // The client also sends another two packets (p3, p4), again losing the first.
+        let _p3 = send_something(&mut client, now);
+        let p4 = send_something(&mut client, now);
+
+        // At t=RTT the client receives the ACK and goes into timed loss recovery.
+        // The client doesn't call p1 lost at this stage, but it will soon.
+        now += RTT / 2;
+        let res = client.process(ack, now);
+        // The client should be on a loss recovery timer as p1 is missing.
+        let lr_timer = res.callback();
+        // Loss recovery timer should be RTT/8, but only check for 0 or >=RTT/2.
+        assert_ne!(lr_timer, Duration::from_secs(0));
+        assert!(lr_timer < (RTT / 2));
+        // The server also receives and acknowledges p4, again sending an ACK.
+        let ack = server.process(Some(p4), now).dgram();
+        assert!(ack.is_some());
+
+        // At t=RTT*3/2 the client should declare p1 to be lost.
+        now += RTT / 2;
+        // So the client will send the data from p1 again.
+        let res = client.process(None, now);
+        assert!(res.dgram().is_some());
+        // When the client processes the ACK, it should engage the
+        // loss recovery timer for p3, not p1 (even though it still tracks p1).
+        let res = client.process(ack, now);
+        let lr_timer2 = res.callback();
+        assert_eq!(lr_timer, lr_timer2);
+    }
+
+    /// Split the first packet off a coalesced packet.
+    fn split_packet(buf: &[u8]) -> (&[u8], Option<&[u8]>) {
+        if buf[0] & 0x80 == 0 {
+            // Short header: easy.
+            return (buf, None);
+        }
+        let mut dec = Decoder::from(buf);
+        let first = dec.decode_byte().unwrap();
+        dec.skip(4); // Version.
+        dec.skip_vec(1); // DCID
+        dec.skip_vec(1); // SCID
+        if first & 0x30 == 0 {
+            // Initial
+            dec.skip_vvec();
+        }
+        dec.skip_vvec(); // The rest of the packet.
+        let p1 = &buf[..dec.offset()];
+        let p2 = if dec.remaining() > 0 {
+            Some(dec.decode_remainder())
+        } else {
+            None
+        };
+        (p1, p2)
+    }
+
+    /// Split the first datagram off a coalesced datagram.
+    fn split_datagram(d: Datagram) -> (Datagram, Option<Datagram>) {
+        let (a, b) = split_packet(&d[..]);
+        (
+            Datagram::new(d.source(), d.destination(), a),
+            b.map(|b| Datagram::new(d.source(), d.destination(), b)),
+        )
+    }
+
+    /// We should not be setting the loss recovery timer based on packets
+    /// that are sent prior to the largest acknowledged.
+    /// Testing this requires that we construct a case where one packet
+    /// number space causes the loss recovery timer to be engaged.  At the same time,
+    /// there is a packet in another space that hasn't been acknowledged AND
+    /// that packet number space has not received acknowledgments for later packets.
+    #[test]
+    fn loss_time_past_largest_acked() {
+        const RTT: Duration = Duration::from_secs(10);
+        const INCR: Duration = Duration::from_millis(1);
+        let mut client = default_client();
+        let mut server = default_server();
+
+        let mut now = now();
+
+        // Start the handshake.
+        let c_in = client.process(None, now).dgram();
+        now += RTT / 2;
+        let s_hs1 = server.process(c_in, now).dgram();
+
+        // Get some spare server handshake packets for the client to ACK.
+        // This involves a time machine, so be a little cautious.
+        // This test uses an RTT of 10s, but our server starts
+        // with a much lower RTT estimate, so the PTO at this point should
+        // be much smaller than an RTT and so the server shouldn't see
+        // time go backwards.
+        let s_pto = server.process(None, now).callback();
+        assert_ne!(s_pto, Duration::from_secs(0));
+        assert!(s_pto < RTT);
+        let s_hs2 = server.process(None, now + s_pto).dgram();
+        assert!(s_hs2.is_some());
+        let s_hs3 = server.process(None, now + s_pto).dgram();
+        assert!(s_hs3.is_some());
+
+        // Get some Handshake packets from the client.
+        // We need one to be left unacknowledged before one that is acknowledged.
+        // So that the client engages the loss recovery timer.
+        // This is complicated by the fact that it is hard to cause the client
+        // to generate an ack-eliciting packet.  For that, we use the Finished message.
+        // Reordering delivery ensures that the later packet is also acknowledged.
+        now += RTT / 2;
+        let c_hs1 = client.process(s_hs1, now).dgram();
+        assert!(c_hs1.is_some()); // This comes first, so it's useless.
+        maybe_authenticate(&mut client);
+        let c_hs2 = client.process(None, now).dgram();
+        assert!(c_hs2.is_some()); // This one will elicit an ACK.
+
+        // The we need the outstanding packet to be sent after the
+        // application data packet, so space these out a tiny bit.
+        let _p1 = send_something(&mut client, now + INCR);
+        let c_hs3 = client.process(s_hs2, now + (INCR * 2)).dgram();
+        assert!(c_hs3.is_some()); // This will be left outstanding.
+        let c_hs4 = client.process(s_hs3, now + (INCR * 3)).dgram();
+        assert!(c_hs4.is_some()); // This will be acknowledged.
+
+        // Get an ACK for the client.
+        now += RTT / 2;
+        // Deliver the last one first, so that gets acknowledged.
+        // This won't generate an ACK, because it only contains an ACK.
+        let s_ack1 = server.process(c_hs4, now).dgram();
+        assert!(s_ack1.is_none());
+        // This includes an ACK, but it also includes HANDSHAKE_DONE,
+        // which we need to remove because that will cause the Handshake loss recovery
+        // state to be dropped.
+        let s_ack2 = server.process(c_hs2, now).dgram();
+        assert!(s_ack2.is_some());
+        let (s_hs_ack, _s_ap_ack) = split_datagram(s_ack2.unwrap());
+
+        // Now the client should start its loss recovery timer based on the ACK.
+        now += RTT / 2;
+        let c_ack = client.process(Some(s_hs_ack), now).dgram();
+        assert!(c_ack.is_none());
+        // The client should now have the loss recovery timer active.
+        let lr_time = client.process(None, now).callback();
+        assert_ne!(lr_time, Duration::from_secs(0));
+        assert!(lr_time < (RTT / 2));
+
+        // Skipping forward by the loss recovery timer should cause the client to
+        // mark packets as lost and retransmit, after which we should be on the PTO
+        // timer.
+        now += lr_time;
+        let delay = client.process(None, now).callback();
+        assert_ne!(delay, Duration::from_secs(0));
+        assert!(delay > lr_time);
+    }
+
+    #[test]
+    fn unknown_version() {
+        let mut client = default_client();
+        // Start the handshake.
+        let _ = client.process(None, now()).dgram();
+
+        let mut unknown_version_packet = vec![0x80, 0x1a, 0x1a, 0x1a, 0x1a];
+        unknown_version_packet.resize(1200, 0x0);
+        client.process(
+            Some(Datagram::new(
+                loopback(),
+                loopback(),
+                unknown_version_packet,
+            )),
+            now(),
+        );
+        assert_eq!(1, client.stats().dropped_rx);
+    }
+
+    #[test]
+    fn stats_counters() {
+        const RTT: Duration = Duration::from_millis(100);
+        let mut client = default_client();
+        let mut server = default_server();
+        let now = connect_with_rtt(&mut client, &mut server, now(), RTT);
+
+        let before = (client.stats().bytes_tx, client.stats().packets_tx);
+        let dgram = send_something(&mut client, now);
+        let stats = client.stats();
+        assert_eq!(stats.bytes_tx, before.0 + dgram.len());
+        assert_eq!(stats.packets_tx, before.1 + 1);
+        assert_eq!(stats.rtt, RTT);
+        assert_eq!(stats.lost, 0);
+        assert_eq!(stats.pto_count, 0);
+
+        let received = server.stats().bytes_rx;
+        let ack = server.process(Some(dgram.clone()), now).dgram();
+        assert_eq!(server.stats().bytes_rx, received + dgram.len());
+        assert!(ack.is_some());
+        assert!(server.stats().acks_tx > 0);
+
+        // The client doesn't get an acknowledgment, so the PTO timer fires.
+        let now = now + client.process(None, now).callback();
+        let _ = client.process(None, now);
+        assert_eq!(client.stats().pto_count, 1);
+    }
+
+    /// The delay that the peer reports in an ACK is taken out of the RTT sample.
+    #[test]
+    fn rtt_ack_delay() {
+        const RTT: Duration = Duration::from_millis(100);
+        let mut client = default_client();
+        let mut server = default_server();
+        let mut now = connect_with_rtt(&mut client, &mut server, now(), RTT);
+
+        let dgram = send_something(&mut client, now);
+        now += RTT / 2;
+        let delay = server.process(Some(dgram), now).callback();
+        assert_eq!(delay, ACK_DELAY);
+        now += delay;
+        let ack = server.process(None, now).dgram();
+        assert!(ack.is_some());
+        now += RTT / 2;
+        client.process_input(ack.unwrap(), now);
+
+        let stats = client.stats();
+        assert_eq!(stats.rtt, RTT);
+        assert_eq!(stats.min_rtt, RTT);
+    }
+
+    #[test]
+    fn stream_credit_coalesced() {
+        const DATA_LEN: usize = 1 << 20;
+        const RTT: Duration = Duration::from_millis(10);
+        let mut client = default_client();
+        let mut server = default_server();
+        let mut now = connect_with_rtt(&mut client, &mut server, now(), RTT);
+
+        let stream_id = server.stream_create(StreamType::UniDi).unwrap();
+        let data = vec![0; DATA_LEN];
+        let mut buf = vec![0; 4096];
+        let mut sent = 0;
+        let mut received = 0;
+        let mut fin = false;
+        while !fin {
+            if sent < DATA_LEN {
+                sent += server.stream_send(stream_id, &data[sent..]).unwrap();
+                if sent == DATA_LEN {
+                    server.stream_close_send(stream_id).unwrap();
+                }
+            }
+            while let Some(d) = server.process_output(now).dgram() {
+                client.process_input(d, now);
+            }
+            // Read a little at a time, as an application that is fast would.
+            while !fin {
+                let (amount, end) = client.stream_recv(stream_id, &mut buf).unwrap();
+                received += amount;
+                fin = end;
+                if amount == 0 {
+                    break;
+                }
+            }
+            while let Some(d) = client.process_output(now).dgram() {
+                server.process_input(d, now);
+            }
+            now += Duration::from_millis(5);
+        }
+        assert_eq!(received, DATA_LEN);
+
+        // Each update carries at least a quarter of the 64k window.
+        let updates = client.stats().max_stream_data_tx;
+        assert!(updates > 0);
+        assert!(updates <= DATA_LEN / usize::try_from(RX_STREAM_DATA_WINDOW / 4).unwrap());
+    }
+
+    #[test]
+    fn stream_credit_divisor_bad() {
+        let mut client = default_client();
+        assert_eq!(
+            client.set_stream_credit_divisor(0),
+            Err(Error::InvalidInput)
+        );
+        assert_eq!(client.stream_credit_divisor, STREAM_CREDIT_DIVISOR);
+        assert_eq!(client.set_stream_credit_divisor(2), Ok(()));
+        assert_eq!(client.stream_credit_divisor, 2);
     }
 
-    /// Send something on a stream from `sender` to `receiver`.
-    /// Return any ACK that might result.
-    fn send_and_receive(
-        sender: &mut Connection,
-        receiver: &mut Connection,
-        now: Instant,
-    ) -> Option<Datagram> {
-        let dgram = send_something(sender, now);
-        receiver.process(Some(dgram), now).dgram()
+    /// A download several times larger than the connection window completes,
+    /// because MAX_DATA follows what the application reads.
+    #[test]
+    fn conn_credit_updated() {
+        const WINDOW: u64 = 16384;
+        const DATA_LEN: usize = 10 * WINDOW as usize;
+        let mut client =
+            client_with_params(ConnectionParameters::default().max_data(WINDOW)).unwrap();
+        let mut server = default_server();
+        let mut now = connect_with_rtt(&mut client, &mut server, now(), Duration::from_millis(10));
+        assert_eq!(server.remote_stream_limits().max_data, WINDOW);
+
+        let stream_id = server.stream_create(StreamType::UniDi).unwrap();
+        let data = vec![0; DATA_LEN];
+        let mut buf = vec![0; 4096];
+        let mut sent = 0;
+        let mut received = 0;
+        let mut fin = false;
+        while !fin {
+            if sent < DATA_LEN {
+                sent += server.stream_send(stream_id, &data[sent..]).unwrap();
+                if sent == DATA_LEN {
+                    server.stream_close_send(stream_id).unwrap();
+                }
+            }
+            while let Some(d) = server.process_output(now).dgram() {
+                client.process_input(d, now);
+            }
+            while !fin {
+                let (amount, end) = client.stream_recv(stream_id, &mut buf).unwrap();
+                received += amount;
+                fin = end;
+                if amount == 0 {
+                    break;
+                }
+            }
+            while let Some(d) = client.process_output(now).dgram() {
+                server.process_input(d, now);
+            }
+            now += Duration::from_millis(5);
+        }
+        assert_eq!(received, DATA_LEN);
+
+        // The peer is never given more than a window beyond what was read.
+        let max_data = server.remote_stream_limits().max_data;
+        assert!(max_data >= u64::try_from(DATA_LEN).unwrap());
+        assert!(max_data <= u64::try_from(DATA_LEN).unwrap() + WINDOW);
     }
 
+    /// Data that arrives on a stream which is then reset is never read, but it
+    /// still gives credit back to the peer.
     #[test]
-    fn key_update_client() {
-        let mut client = default_client();
+    fn reset_returns_conn_credit() {
+        const WINDOW: u64 = 4096;
+        let mut client =
+            client_with_params(ConnectionParameters::default().max_data(WINDOW)).unwrap();
         let mut server = default_server();
-        connect_force_idle(&mut client, &mut server);
-        let mut now = now();
+        connect(&mut client, &mut server);
 
-        assert_eq!(client.get_epochs(), (Some(3), Some(3))); // (write, read)
-        assert_eq!(server.get_epochs(), (Some(3), Some(3)));
+        let stream_id = server.stream_create(StreamType::UniDi).unwrap();
+        let len = usize::try_from(WINDOW).unwrap();
+        assert_eq!(server.stream_send(stream_id, &vec![0; len]).unwrap(), len);
+        // Give the pacer time to release all of it.
+        let mut now = now();
+        for _ in 0..20 {
+            if let Some(d) = server.process_output(now).dgram() {
+                client.process_input(d, now);
+            }
+            now += Duration::from_millis(10);
+        }
+        server.stream_reset_send(stream_id, 7).unwrap();
+        let dgram = server.process_output(now).dgram();
+        client.process_input(dgram.unwrap(), now);
+        assert!(client.events().any(|e| e
+            == ConnectionEvent::RecvStreamReset {
+                stream_id,
+                app_error: 7,
+            }));
 
-        // TODO(mt) this needs to wait for handshake confirmation,
-        // but for now, we can do this immediately.
-        assert!(client.initiate_key_update().is_ok());
-        assert!(client.initiate_key_update().is_err());
+        let dgram = client.process_output(now).dgram();
+        server.process_input(dgram.unwrap(), now);
+        assert_eq!(server.remote_stream_limits().max_data, 2 * WINDOW);
+    }
 
-        // Initiating an update should only increase the write epoch.
+    #[test]
+    fn datagram_dispositions() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let _ = client.take_datagram_dispositions();
+
+        let dgram = send_something(&mut server, now());
+        client.process_input(dgram.clone(), now());
+        client.process_input(dgram, now());
+        // A short header packet for a connection ID that the client doesn't use.
+        client.process_input(Datagram::new(loopback(), loopback(), vec![0x40; 64]), now());
+        // A short header packet without the fixed bit.
+        client.process_input(Datagram::new(loopback(), loopback(), vec![0; 64]), now());
+
+        let dispositions = client.take_datagram_dispositions();
+        assert_eq!(dispositions.len(), 4);
+        assert!(dispositions[0].processed > 0);
+        assert!(dispositions[0].dropped.is_empty());
+        assert_eq!(dispositions[1].processed, 0);
+        assert!(dispositions[1].dropped.contains(&DropReason::Duplicate));
         assert_eq!(
-            Output::Callback(LOCAL_IDLE_TIMEOUT),
-            client.process(None, now)
+            dispositions[2].dropped,
+            vec![DropReason::UnknownConnectionId]
         );
-        assert_eq!(client.get_epochs(), (Some(4), Some(3)));
+        assert_eq!(dispositions[3].dropped, vec![DropReason::MalformedHeader]);
+        assert!(client.take_datagram_dispositions().is_empty());
 
-        // Send something to propagate the update.
-        assert!(send_and_receive(&mut client, &mut server, now).is_none());
+        let by_reason = &client.stats().dropped_by_reason;
+        assert_eq!(by_reason.get(&DropReason::Duplicate), Some(&1));
+        assert_eq!(by_reason.get(&DropReason::UnknownConnectionId), Some(&1));
+        assert_eq!(by_reason.get(&DropReason::MalformedHeader), Some(&1));
+    }
 
-        // The server should now be waiting to discharge read keys.
-        assert_eq!(server.get_epochs(), (Some(4), Some(3)));
-        let res = server.process(None, now);
-        if let Output::Callback(t) = res {
-            assert!(t < LOCAL_IDLE_TIMEOUT);
-        } else {
-            panic!("server should now be waiting to clear keys");
-        }
+    #[test]
+    fn small_initial_dropped() {
+        let mut client = default_client();
+        let mut server = default_server();
+        client
+            .path
+            .as_mut()
+            .unwrap()
+            .set_max_packet_size(MIN_INITIAL_PACKET_SIZE - 100);
+        let small = client.process(None, now()).dgram().unwrap();
+        assert!(small.len() < MIN_INITIAL_PACKET_SIZE);
+        assert!(server.process(Some(small), now()).dgram().is_none());
+        assert_eq!(
+            server.take_datagram_dispositions()[0].dropped,
+            vec![DropReason::AmplificationLimit]
+        );
+        assert_eq!(*server.state(), State::Init);
+        assert_eq!(
+            server
+                .stats()
+                .dropped_by_reason
+                .get(&DropReason::AmplificationLimit),
+            Some(&1)
+        );
+    }
 
-        // Without having had time to purge old keys, more updates are blocked.
-        // The spec would permits it at this point, but we are more conservative.
-        assert!(client.initiate_key_update().is_err());
-        // The server can't update until it receives an ACK for a packet.
-        assert!(server.initiate_key_update().is_err());
+    /// Change the source connection ID of the long header packet at the start of `d`.
+    fn change_scid(d: &Datagram) -> Datagram {
+        let mut data = d[..].to_vec();
+        let dcid_len = usize::from(data[5]);
+        let scid_len = usize::from(data[6 + dcid_len]);
+        assert!(scid_len > 0);
+        data[7 + dcid_len] ^= 0xff;
+        Datagram::new(d.source(), d.destination(), data)
+    }
 
-        // Waiting now for at least a PTO should cause the server to drop old keys.
-        // But at this point the client hasn't received a key update from the server.
-        // It will be stuck with old keys.
-        now += AT_LEAST_PTO;
-        let dgram = client.process(None, now).dgram();
-        assert!(dgram.is_some()); // Drop this packet.
-        assert_eq!(client.get_epochs(), (Some(4), Some(3)));
-        server.process(None, now);
-        assert_eq!(server.get_epochs(), (Some(4), Some(4)));
+    /// A server that changes its source connection ID on its second flight
+    /// has its packets dropped without changing the connection ID the client uses.
+    #[test]
+    fn server_scid_changed_mid_handshake() {
+        let mut client = default_client();
+        let mut server = default_server();
 
-        // Even though the server has updated, it hasn't received an ACK yet.
-        assert!(server.initiate_key_update().is_err());
+        let c1 = client.process(None, now()).dgram();
+        let s1 = server.process(c1, now()).dgram();
+        let (s_init, s_hs) = split_datagram(s1.unwrap());
+        let s_hs = s_hs.expect("server should send a Handshake packet");
 
-        // Now get an ACK from the server.
-        // The previous PTO packet (see above) was dropped, so we should get an ACK here.
-        let dgram = send_and_receive(&mut client, &mut server, now);
-        assert!(dgram.is_some());
-        let res = client.process(dgram, now);
-        // This is the first packet that the client has received from the server
-        // with new keys, so its read timer just started.
-        if let Output::Callback(t) = res {
-            assert!(t < LOCAL_IDLE_TIMEOUT);
-        } else {
-            panic!("client should now be waiting to clear keys");
-        }
+        client.process_input(s_init, now());
+        assert_eq!(*client.state(), State::Handshaking);
+        let server_cid = client.path().unwrap().remote_cid().clone();
 
-        assert!(client.initiate_key_update().is_err());
-        assert_eq!(client.get_epochs(), (Some(4), Some(3)));
-        // The server can't update until it gets something from the client.
-        assert!(server.initiate_key_update().is_err());
+        let dropped_before = client.stats().dropped_rx;
+        client.process_input(change_scid(&s_hs), now());
+        assert_eq!(client.stats().dropped_rx - dropped_before, 1);
+        assert_eq!(
+            client
+                .stats()
+                .dropped_by_reason
+                .get(&DropReason::SourceConnectionIdChanged),
+            Some(&1)
+        );
+        assert_eq!(*client.path().unwrap().remote_cid(), server_cid);
 
-        now += AT_LEAST_PTO;
-        client.process(None, now);
-        assert_eq!(client.get_epochs(), (Some(4), Some(4)));
+        // The genuine packet is still accepted and the handshake completes.
+        client.process_input(s_hs, now());
+        assert!(maybe_authenticate(&mut client));
+        let c2 = client.process(None, now()).dgram();
+        assert_eq!(*client.path().unwrap().remote_cid(), server_cid);
+        let s2 = server.process(c2, now()).dgram();
+        client.process_input(s2.unwrap(), now());
+        assert!(client.state().connected());
     }
 
     #[test]
-    fn key_update_consecutive() {
+    fn path_hints_seed_rtt() {
+        const RTT: Duration = Duration::from_millis(300);
         let mut client = default_client();
         let mut server = default_server();
-        connect(&mut client, &mut server);
-        let now = now();
+        let now = connect_with_rtt(&mut client, &mut server, now(), RTT);
+        let info = client.path_info().expect("client has a path");
+        assert_eq!(info.rtt, RTT);
 
-        assert!(server.initiate_key_update().is_ok());
-        assert_eq!(server.get_epochs(), (Some(4), Some(3)));
+        let mut enc = Encoder::default();
+        info.encode(&mut enc);
+        let decoded = PathInfo::decode(&mut Decoder::from(&enc[..])).unwrap();
+        assert_eq!(decoded, info);
 
-        // Server sends something.
-        // Send twice and drop the first to induce an ACK from the client.
-        let _ = send_something(&mut server, now); // Drop this.
+        // The first PTO on a new connection uses the seeded RTT.
+        let mut client = default_client();
+        client.set_path_hints(&decoded, false).unwrap();
+        let _ = client
+            .process(None, now)
+            .dgram()
+            .expect("should send an Initial");
+        let delay = client.process(None, now).callback();
+        assert_eq!(delay, RTT + GRANULARITY);
 
-        // Another packet from the server will cause the client to ACK and update keys.
-        let dgram = send_and_receive(&mut server, &mut client, now);
-        assert!(dgram.is_some());
-        assert_eq!(client.get_epochs(), (Some(4), Some(3)));
+        // Without hints, the default RTT is used.
+        let mut client = default_client();
+        let _ = client
+            .process(None, now)
+            .dgram()
+            .expect("should send an Initial");
+        let delay = client.process(None, now).callback();
+        assert_eq!(delay, Duration::from_millis(120));
 
-        // Have the server process the ACK.
-        if let Output::Callback(_) = server.process(dgram, now) {
-            assert_eq!(server.get_epochs(), (Some(4), Some(3)));
-            // Now move the server temporarily into the future so that it
-            // rotates the keys.  The client stays in the present.
-            server.process(None, now + AT_LEAST_PTO);
-            assert_eq!(server.get_epochs(), (Some(4), Some(4)));
-        } else {
-            panic!("server should have a timer set");
-        }
+        // Hints can't be set once the connection has started.
+        assert_eq!(
+            client.set_path_hints(&decoded, false),
+            Err(Error::ConnectionState)
+        );
+    }
 
-        // Now update keys on the server again.
-        assert!(server.initiate_key_update().is_ok());
-        assert_eq!(server.get_epochs(), (Some(5), Some(4)));
+    #[test]
+    fn path_info_encode_clamps() {
+        let info = PathInfo {
+            rtt: Duration::from_secs(u64::max_value()),
+            mtu: usize::max_value(),
+            cwnd: usize::max_value(),
+        };
+        let mut enc = Encoder::default();
+        info.encode(&mut enc);
+        let decoded = PathInfo::decode(&mut Decoder::from(&enc[..])).unwrap();
+        let max = (1 << 62) - 1;
+        assert_eq!(decoded.rtt, Duration::from_micros(max));
+        assert_eq!(decoded.mtu as u64, min(max, usize::max_value() as u64));
+        assert_eq!(decoded.cwnd as u64, min(max, usize::max_value() as u64));
+    }
 
-        let dgram = send_something(&mut server, now + AT_LEAST_PTO);
+    #[test]
+    fn path_hints_seed_mtu() {
+        const MTU: usize = 1250;
+        let mut client = default_client();
+        let hints = PathInfo {
+            rtt: Duration::from_millis(50),
+            mtu: MTU,
+            cwnd: client.loss_recovery.cwnd(),
+        };
+        client.set_path_hints(&hints, false).unwrap();
+        assert_eq!(client.path().unwrap().mtu(), MTU);
+        assert_eq!(client.path_info().unwrap().mtu, MTU);
 
-        // However, as the server didn't wait long enough to update again, the
-        // client hasn't rotated its keys, so the packet gets dropped.
-        check_discarded(&mut client, dgram, 1, 0);
+        // The Initial is padded to the seeded MTU, and the connection works.
+        let initial = client.process(None, now()).dgram().unwrap();
+        assert_eq!(initial.len(), MTU);
+        let mut server = default_server();
+        let mut dgram = Some(initial);
+        for _ in 0..10 {
+            dgram = server.process(dgram, now()).dgram();
+            let _ = maybe_authenticate(&mut client);
+            dgram = client.process(dgram, now()).dgram();
+        }
+        assert!(client.state().connected());
+        assert!(server.state().connected());
     }
 
-    // Key updates can't be initiated too early.
     #[test]
-    fn key_update_before_confirmed() {
+    fn path_hints_jump_start() {
         let mut client = default_client();
-        assert!(client.initiate_key_update().is_err());
-        let mut server = default_server();
-        assert!(server.initiate_key_update().is_err());
+        let initial_cwnd = client.loss_recovery.cwnd();
+        let hints = PathInfo {
+            rtt: Duration::from_millis(50),
+            mtu: PATH_MTU_V6,
+            cwnd: initial_cwnd * 3,
+        };
+        client.set_path_hints(&hints, true).unwrap();
+        assert_eq!(client.loss_recovery.cwnd(), initial_cwnd * 3 / 2);
 
-        // Client Initial
-        let dgram = client.process(None, now()).dgram();
-        assert!(dgram.is_some());
-        assert!(client.initiate_key_update().is_err());
+        // A huge window is capped.
+        let mut client = default_client();
+        let hints = PathInfo {
+            cwnd: usize::max_value(),
+            ..hints
+        };
+        client.set_path_hints(&hints, true).unwrap();
+        assert_eq!(client.loss_recovery.cwnd(), initial_cwnd * 4);
 
-        // Server Initial + Handshake
-        let dgram = server.process(dgram, now()).dgram();
-        assert!(dgram.is_some());
-        assert!(server.initiate_key_update().is_err());
+        // Without jump start the window is unchanged.
+        let mut client = default_client();
+        client.set_path_hints(&hints, false).unwrap();
+        assert_eq!(client.loss_recovery.cwnd(), initial_cwnd);
+    }
 
-        // Client Handshake
-        client.process_input(dgram.unwrap(), now());
-        assert!(client.initiate_key_update().is_err());
+    fn v4_mapped(addr: SocketAddr) -> SocketAddr {
+        match addr {
+            SocketAddr::V4(v4) => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+            SocketAddr::V6(_) => addr,
+        }
+    }
 
-        assert!(maybe_authenticate(&mut client));
-        assert!(client.initiate_key_update().is_err());
+    fn rewrite(d: Datagram, f: fn(SocketAddr) -> SocketAddr) -> Datagram {
+        Datagram::new(f(d.source()), f(d.destination()), &d[..])
+    }
 
-        let dgram = client.process(None, now()).dgram();
-        assert!(dgram.is_some());
-        assert!(client.initiate_key_update().is_err());
+    // Run the handshake and exchange some stream data, changing the form of the addresses
+    // on the datagrams that each side receives.  This is what happens when one side has a
+    // dual-stack socket and the other has an IPv4 socket.
+    fn dual_stack_exchange(
+        client: &mut Connection,
+        to_client: fn(SocketAddr) -> SocketAddr,
+        to_server: fn(SocketAddr) -> SocketAddr,
+    ) {
+        let mut server = default_server();
+        let mut dgram = client.process(None, now()).dgram();
+        for _ in 0..10 {
+            dgram = server
+                .process(dgram.map(|d| rewrite(d, to_server)), now())
+                .dgram();
+            let _ = maybe_authenticate(client);
+            dgram = client
+                .process(dgram.map(|d| rewrite(d, to_client)), now())
+                .dgram();
+        }
+        assert_eq!(*client.state(), State::Confirmed);
+        assert_eq!(*server.state(), State::Confirmed);
 
-        // Server HANDSHAKE_DONE
-        let dgram = server.process(dgram, now()).dgram();
-        assert!(dgram.is_some());
-        assert!(server.initiate_key_update().is_ok());
+        let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+        client.stream_send(stream_id, &[6; 100]).unwrap();
+        let d = client.process(None, now()).dgram().unwrap();
+        let _ = server.process(Some(rewrite(d, to_server)), now());
+        let mut buf = [0; 128];
+        assert_eq!(server.stream_recv(stream_id, &mut buf), Ok((100, false)));
+        server.stream_send(stream_id, &[7; 100]).unwrap();
+        let d = server.process(None, now()).dgram().unwrap();
+        let _ = client.process(Some(rewrite(d, to_client)), now());
+        assert_eq!(client.stream_recv(stream_id, &mut buf), Ok((100, false)));
+
+        // Neither side saw a new path.
+        assert_eq!(*client.state(), State::Confirmed);
+        assert_eq!(*server.state(), State::Confirmed);
+    }
 
-        // Client receives HANDSHAKE_DONE
-        let dgram = client.process(dgram, now()).dgram();
-        assert!(dgram.is_none());
-        assert!(client.initiate_key_update().is_ok());
+    fn v4_client(addr: SocketAddr) -> Connection {
+        fixture_init();
+        Connection::new_client(
+            test_fixture::DEFAULT_SERVER_NAME,
+            test_fixture::DEFAULT_ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+            addr,
+            addr,
+        )
+        .unwrap()
     }
 
+    /// The client has a dual-stack socket and the server an IPv4 socket.
     #[test]
-    fn ack_are_not_cc() {
-        let mut client = default_client();
-        let mut server = default_server();
-        connect_force_idle(&mut client, &mut server);
+    fn dual_stack_client() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 443);
+        let mut client = v4_client(v4_mapped(addr));
+        assert_eq!(client.path().unwrap().mtu(), PATH_MTU_V4);
+        dual_stack_exchange(&mut client, v4_mapped, normalize_address);
+    }
 
-        // Create a stream
-        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+    /// The client has an IPv4 socket and the server a dual-stack socket.
+    #[test]
+    fn dual_stack_server() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 443);
+        let mut client = v4_client(addr);
+        dual_stack_exchange(&mut client, normalize_address, v4_mapped);
+    }
 
-        // Buffer up lot of data and generate packets, so that cc window is filled.
-        let (c_tx_dgrams, now) = fill_cwnd(&mut client, 0, now());
-        assert_full_cwnd(&c_tx_dgrams, POST_HANDSHAKE_CWND);
+    /// The same path can be reported in either form, one datagram to the next.
+    #[test]
+    fn dual_stack_mixed() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 443);
+        let mut client = v4_client(addr);
+        assert_eq!(client.path().unwrap().mtu(), PATH_MTU_V4);
+        let mut server = default_server();
 
-        // The server hasn't received any of these packets yet, the server
-        // won't ACK, but if it sends an ack-eliciting packet instead.
-        qdebug!([server], "Sending ack-eliciting");
-        assert_eq!(server.stream_create(StreamType::BiDi).unwrap(), 1);
-        server.stream_send(1, b"dropped").unwrap();
-        let dropped_packet = server.process(None, now).dgram();
-        assert!(dropped_packet.is_some()); // Now drop this one.
+        let mut dgram = client.process(None, now()).dgram();
+        for i in 0..10 {
+            let form: fn(SocketAddr) -> SocketAddr = if i % 2 == 0 {
+                v4_mapped
+            } else {
+                normalize_address
+            };
+            dgram = server
+                .process(dgram.map(|d| rewrite(d, form)), now())
+                .dgram();
+            let _ = maybe_authenticate(&mut client);
+            dgram = client
+                .process(dgram.map(|d| rewrite(d, form)), now())
+                .dgram();
+        }
+        assert_eq!(*client.state(), State::Confirmed);
+        assert_eq!(*server.state(), State::Confirmed);
+    }
 
-        // Now the server sends a packet that will force an ACK,
-        // because the client will detect a gap.
-        server.stream_send(1, b"sent").unwrap();
-        let ack_eliciting_packet = server.process(None, now).dgram();
-        assert!(ack_eliciting_packet.is_some());
+    /// A different address is still a new path, so the datagram is dropped.  The
+    /// packet in it isn't recorded as received, so it counts when it arrives on
+    /// the right path.
+    #[test]
+    fn dual_stack_other_address() {
+        let mut client = v4_client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 443));
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let _ = server.take_datagram_dispositions();
 
-        // The client can ack the server packet even if cc windows is full.
-        qdebug!([client], "Process ack-eliciting");
-        let ack_pkt = client.process(ack_eliciting_packet, now).dgram();
-        assert!(ack_pkt.is_some());
-        qdebug!([server], "Handle ACK");
-        let frames = server.test_process_input(ack_pkt.unwrap(), now);
-        assert_eq!(frames.len(), 1);
-        assert!(matches!(
-            frames[0],
-            (Frame::Ack { .. }, PNSpace::ApplicationData)
-        ));
+        let other = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), 443);
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[1]).unwrap();
+        let d = client.process(None, now()).dgram().unwrap();
+        let moved = Datagram::new(v4_mapped(other), d.destination(), &d[..]);
+        server.process_input(moved, now());
+        assert_eq!(*server.state(), State::Confirmed);
+        assert_eq!(
+            server.take_datagram_dispositions()[0].dropped,
+            vec![DropReason::UnknownPath]
+        );
+        assert!(!server
+            .events()
+            .any(|e| matches!(e, ConnectionEvent::NewStream { .. })));
+
+        server.process_input(d, now());
+        let mut buf = [0; 1];
+        assert_eq!(server.stream_recv(stream_id, &mut buf).unwrap(), (1, false));
     }
 
     #[test]
-    fn after_fin_is_read_conn_events_for_stream_should_be_removed() {
+    fn path_stats() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let rtt = Duration::from_millis(100);
+        let now = connect_with_rtt(&mut client, &mut server, now(), rtt);
+
+        let paths = client.paths().collect::<Vec<_>>();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].id, 0);
+        assert_eq!(paths[0].remote, loopback());
+        assert_eq!(client.path_info().unwrap().rtt, rtt);
+        let path = |c: &Connection| c.paths().next().unwrap();
+        let sent = path(&client).bytes_sent;
+        let received = path(&server).bytes_received;
+
+        let d = send_something(&mut client, now);
+        let len = d.len() as u64;
+        assert_eq!(path(&client).bytes_sent, sent + len);
+        // A datagram from another address doesn't count.
+        let other = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 443);
+        server.process_input(Datagram::new(other, d.destination(), &d[..]), now);
+        assert_eq!(path(&server).bytes_received, received);
+        server.process_input(d, now);
+        assert_eq!(path(&server).bytes_received, received + len);
+    }
+
+    /// A PATH_RESPONSE that doesn't answer a challenge is ignored, however many
+    /// times it arrives.
+    #[test]
+    fn path_response_unsolicited() {
         let mut client = default_client();
         let mut server = default_server();
         connect(&mut client, &mut server);
 
-        let id = server.stream_create(StreamType::BiDi).unwrap();
-        server.stream_send(id, &[6; 10]).unwrap();
-        server.stream_close_send(id).unwrap();
-        let out = server.process(None, now()).dgram();
-        assert!(out.is_some());
-
-        let _ = client.process(out, now());
-
-        // read from the stream before checking connection events.
-        let mut buf = vec![0; 4000];
-        let (_, fin) = client.stream_recv(id, &mut buf).unwrap();
-        assert_eq!(fin, true);
+        for _ in 0..2 {
+            let frame = Frame::PathResponse { data: [0xc4; 8] };
+            assert!(client.input_frame(PacketType::Short, frame, now()).is_ok());
+        }
+        assert_eq!(*client.state(), State::Confirmed);
 
-        // Make sure we do not have RecvStreamReadable events for the stream when fin has been read.
-        let readable_stream_evt =
-            |e| matches!(e, ConnectionEvent::RecvStreamReadable { stream_id } if stream_id == id);
-        assert!(!client.events().any(readable_stream_evt));
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[1]).unwrap();
+        let d = client.process(None, now()).dgram().unwrap();
+        server.process_input(d, now());
+        let mut buf = [0; 1];
+        assert_eq!(server.stream_recv(stream_id, &mut buf).unwrap(), (1, false));
     }
 
+    /// A packet carrying a PATH_RESPONSE is dropped when it arrives from another
+    /// address, and when it is replayed, even much later.
     #[test]
-    fn after_stream_stop_sending_is_called_conn_events_for_stream_should_be_removed() {
+    fn path_response_other_address_and_replay() {
         let mut client = default_client();
         let mut server = default_server();
         connect(&mut client, &mut server);
 
-        let id = server.stream_create(StreamType::BiDi).unwrap();
-        server.stream_send(id, &[6; 10]).unwrap();
-        server.stream_close_send(id).unwrap();
-        let out = server.process(None, now()).dgram();
-        assert!(out.is_some());
-
-        let _ = client.process(out, now());
-
-        // send stop seending.
-        client
-            .stream_stop_sending(id, Error::NoError.code())
-            .unwrap();
-
-        // Make sure we do not have RecvStreamReadable events for the stream after stream_stop_sending
-        // has been called.
-        let readable_stream_evt =
-            |e| matches!(e, ConnectionEvent::RecvStreamReadable { stream_id } if stream_id == id);
-        assert!(!client.events().any(readable_stream_evt));
+        let frame = Frame::PathChallenge { data: [0x5a; 8] };
+        assert!(server.input_frame(PacketType::Short, frame, now()).is_ok());
+        let d = server.process(None, now()).dgram().unwrap();
+        let _ = client.take_datagram_dispositions();
+
+        let other = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 443);
+        client.process_input(Datagram::new(other, d.destination(), &d[..]), now());
+        client.process_input(d.clone(), now());
+        client.process_input(d, now() + Duration::from_secs(20));
+
+        let dispositions = client.take_datagram_dispositions();
+        assert_eq!(dispositions[0].dropped, vec![DropReason::UnknownPath]);
+        assert!(dispositions[1].processed > 0);
+        assert_eq!(dispositions[2].processed, 0);
+        assert!(dispositions[2].dropped.contains(&DropReason::Duplicate));
+        assert_eq!(*client.state(), State::Confirmed);
     }
 
-    // During the handshake, an application close should be sanitized.
     #[test]
-    fn early_application_close() {
+    fn max_send_rate() {
+        const RATE: u64 = 100_000;
+        const DATA: usize = 1_000_000;
+
         let mut client = default_client();
         let mut server = default_server();
+        client.set_max_send_rate(RATE, false).unwrap();
+        let mut now = connect_with_rtt(&mut client, &mut server, now(), Duration::new(0, 0));
+        let start = now;
 
-        // One flight each.
-        let dgram = client.process(None, now()).dgram();
-        assert!(dgram.is_some());
-        let dgram = server.process(dgram, now()).dgram();
-        assert!(dgram.is_some());
-
-        server.close(now(), 77, String::from(""));
-        assert!(server.state().closed());
-        let dgram = server.process(None, now()).dgram();
-        assert!(dgram.is_some());
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        let mut sent = 0;
+        let mut received = 0;
+        let mut buf = vec![0; 4096];
+        let mut dgram = None;
+        while received < DATA {
+            if sent < DATA {
+                sent += client
+                    .stream_send(stream_id, &[0x42; 4096][..min(4096, DATA - sent)])
+                    .unwrap();
+            }
+            let client_out = client.process(dgram.take(), now);
+            let client_sent = client_out.as_dgram_ref().is_some();
+            let client_delay = client_out.callback();
+            let server_out = server.process(client_out.dgram(), now);
+            let server_delay = server_out.callback();
+            dgram = server_out.dgram();
+            while let Ok((amount, _)) = server.stream_recv(stream_id, &mut buf) {
+                if amount == 0 {
+                    break;
+                }
+                received += amount;
+            }
+            if !client_sent && dgram.is_none() {
+                now += min(client_delay, server_delay);
+            }
+        }
 
-        let frames = client.test_process_input(dgram.unwrap(), now());
-        assert!(matches!(
-            frames[0],
-            (
-                Frame::ConnectionClose {
-                    error_code: CloseError::Transport(code),
-                    ..
-                },
-                PNSpace::Initial,
-            ) if code == Error::ApplicationError.code()
-        ));
-        assert!(client.state().closed());
+        // The transfer takes about 10 seconds, a little more with packet overheads.
+        let elapsed = now - start;
+        assert!(elapsed > Duration::from_millis(9_500), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(11), "{:?}", elapsed);
     }
 
     #[test]
-    fn bad_tls_version() {
+    fn max_send_rate_bad() {
         let mut client = default_client();
-        // Do a bad, bad thing.
-        client
-            .crypto
-            .tls
-            .set_option(neqo_crypto::Opt::Tls13CompatMode, true)
-            .unwrap();
+        assert_eq!(client.set_max_send_rate(0, true), Err(Error::InvalidInput));
+
         let mut server = default_server();
-        let dgram = client.process(None, now()).dgram();
-        assert!(dgram.is_some());
-        let dgram = server.process(dgram, now()).dgram();
+        connect(&mut client, &mut server);
         assert_eq!(
-            *server.state(),
-            State::Closed(ConnectionError::Transport(Error::ProtocolViolation))
+            client.set_max_send_rate(100_000, true),
+            Err(Error::ConnectionState)
         );
-        assert!(dgram.is_some());
-        let frames = client.test_process_input(dgram.unwrap(), now());
-        assert!(matches!(
-            frames[0],
-            (
-                Frame::ConnectionClose {
-                    error_code: CloseError::Transport(_),
-                    ..
-                },
-                PNSpace::Initial,
-            )
-        ));
     }
 
     #[test]
-    fn pace() {
-        const RTT: Duration = Duration::from_millis(1000);
-        const DATA: &[u8] = &[0xcc; 4_096];
+    fn hostile_close_reason() {
+        const CODE: u64 = 0x3fff_ffff_ffff_ffff;
         let mut client = default_client();
         let mut server = default_server();
-        let mut now = connect_rtt_idle(&mut client, &mut server, RTT);
+        connect(&mut client, &mut server);
 
-        // Now fill up the pipe and watch it trickle out.
-        let stream = client.stream_create(StreamType::BiDi).unwrap();
-        loop {
-            let written = client.stream_send(stream, DATA).unwrap();
-            if written < DATA.len() {
-                break;
-            }
-        }
-        let mut count = 0;
-        // We should get a burst at first.
-        for _ in 0..PACING_BURST_SIZE {
-            let dgram = client.process_output(now).dgram();
-            assert!(dgram.is_some());
-            count += 1;
-        }
-        let gap = client.process_output(now).callback();
-        assert_ne!(gap, Duration::new(0, 0));
-        for _ in PACING_BURST_SIZE..cwnd_packets(POST_HANDSHAKE_CWND) {
-            assert_eq!(client.process_output(now).callback(), gap);
-            now += gap;
-            let dgram = client.process_output(now).dgram();
-            assert!(dgram.is_some());
-            count += 1;
+        let mut reason_phrase = b"\0\x1b]0;pwned\x07\x1b[2J".to_vec();
+        reason_phrase.resize(4000, 0xff);
+        let mut enc = Encoder::default();
+        Frame::ConnectionClose {
+            error_code: CloseError::Application(CODE),
+            frame_type: 0,
+            reason_phrase,
         }
-        assert_eq!(count, cwnd_packets(POST_HANDSHAKE_CWND));
-        let fin = client.process_output(now).callback();
-        assert_ne!(fin, Duration::new(0, 0));
-        assert_ne!(fin, gap);
+        .marshal(&mut enc);
+        let frame = Frame::decode(&mut enc.as_decoder()).unwrap();
+        assert!(client.input_frame(PacketType::Short, frame, now()).is_ok());
+        assert_draining(&client);
+        assert_error(
+            &client,
+            ConnectionError::Transport(Error::PeerApplicationError(CODE)),
+        );
     }
 
-    #[test]
-    fn loss_recovery_crash() {
+    /// The client closes, then the server closes `offset` later.  The server only sends
+    /// its own CONNECTION_CLOSE if it closes before the client's arrives.
+    fn simultaneous_close(offset: Duration) {
+        const RTT: Duration = Duration::from_millis(100);
         let mut client = default_client();
         let mut server = default_server();
-        connect(&mut client, &mut server);
-        let now = now();
+        let start = connect_with_rtt(&mut client, &mut server, now(), RTT);
 
-        // The server sends something, but we will drop this.
-        let _ = send_something(&mut server, now);
+        client.close(start, 1, "client");
+        let client_close = client.process(None, start).dgram();
+        assert!(client_close.is_some());
 
-        // Then send something again, but let it through.
-        let ack = send_and_receive(&mut server, &mut client, now);
-        assert!(ack.is_some());
+        let arrival = start + RTT / 2;
+        let server_close = if offset <= RTT / 2 {
+            server.close(start + offset, 2, "server");
+            let server_close = server.process(None, start + offset).dgram();
+            assert!(server_close.is_some());
+            // Having sent its own, the server doesn't answer the client's.
+            let out = server.process(client_close.clone(), arrival);
+            assert!(out.dgram().is_none());
+            server_close
+        } else {
+            // The server answers once, and then closing does nothing.
+            let server_close = server.process(client_close.clone(), arrival).dgram();
+            assert!(server_close.is_some());
+            server.close(start + offset, 2, "server");
+            assert!(server.process(None, start + offset).dgram().is_none());
+            server_close
+        };
+        assert_draining(&server);
+        assert_error(
+            &server,
+            ConnectionError::Transport(Error::PeerApplicationError(1)),
+        );
 
-        // Have the server process the ACK.
-        let cb = server.process(ack, now).callback();
-        assert!(cb > Duration::from_secs(0));
+        // The client doesn't answer either, and it sees the server's error.
+        let now = start + min(offset, RTT / 2) + RTT / 2;
+        assert!(client.process(server_close.clone(), now).dgram().is_none());
+        assert_draining(&client);
+        let client_error = if offset <= RTT / 2 {
+            ConnectionError::Transport(Error::PeerApplicationError(2))
+        } else {
+            ConnectionError::Transport(Error::PeerError(0))
+        };
+        assert_error(&client, client_error.clone());
+
+        // Nothing else is sent while draining, even if more arrives.
+        assert!(server.process(client_close, now).dgram().is_none());
+        assert!(client.process(server_close, now).dgram().is_none());
+        for c in &mut [&mut client, &mut server] {
+            let delay = match c.process(None, now) {
+                Output::Callback(d) => d,
+                o => panic!("unexpected {:?}", o),
+            };
+            assert_eq!(c.process(None, now + delay), Output::None);
+            assert!(matches!(c.state(), State::Closed(_)));
+        }
+        assert_error(&client, client_error);
+        assert_error(
+            &server,
+            ConnectionError::Transport(Error::PeerApplicationError(1)),
+        );
+    }
 
-        // Now we leap into the future.  The server should regard the first
-        // packet as lost based on time alone.
-        let dgram = server.process(None, now + AT_LEAST_PTO).dgram();
-        assert!(dgram.is_some());
+    #[test]
+    fn simultaneous_close_same_time() {
+        simultaneous_close(Duration::from_millis(0));
+    }
 
-        // This crashes.
-        let _ = send_something(&mut server, now + AT_LEAST_PTO);
+    #[test]
+    fn simultaneous_close_half_rtt() {
+        simultaneous_close(Duration::from_millis(50));
     }
 
-    // If we receive packets after the PTO timer has fired, we won't clear
-    // the PTO state, but we might need to acknowledge those packets.
-    // This shouldn't happen, but we found that some implementations do this.
     #[test]
-    fn ack_after_pto() {
+    fn simultaneous_close_one_rtt() {
+        simultaneous_close(Duration::from_millis(100));
+    }
+
+    fn assert_draining(c: &Connection) {
+        assert!(
+            matches!(c.state(), State::Draining { .. }),
+            "should be draining: {:?}",
+            c.state()
+        );
+    }
+
+    #[test]
+    fn close_before_start() {
+        let mut client = default_client();
+        client.close(now(), 0, "");
+        assert_eq!(
+            *client.state(),
+            State::Closed(ConnectionError::Application(0))
+        );
+        assert_eq!(client.process(None, now()), Output::None);
+    }
+
+    #[test]
+    fn close_zero_rtt_client() {
         let mut client = default_client();
         let mut server = default_server();
-        connect_force_idle(&mut client, &mut server);
+        connect(&mut client, &mut server);
 
-        let mut now = now();
+        let token = exchange_ticket(&mut client, &mut server, now());
+        let mut client = default_client();
+        client
+            .set_resumption_token(now(), &token[..])
+            .expect("should set token");
+        let mut server = default_server();
 
-        // The client sends and is forced into a PTO.
-        let _ = send_something(&mut client, now);
+        let c1 = client.process(None, now()).dgram();
+        let client_stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(client_stream_id, &[1, 2, 3]).unwrap();
+        let _c2 = client.process(None, now()).dgram();
+        assert_eq!(*client.zero_rtt_state(), ZeroRttState::Sending);
 
-        // Jump forward to the PTO and drain the PTO packets.
-        now += AT_LEAST_PTO;
-        for _ in 0..PTO_PACKET_COUNT {
-            let dgram = client.process(None, now).dgram();
-            assert!(dgram.is_some());
-        }
-        assert!(client.process(None, now).dgram().is_none());
+        client.close(now(), 0, "");
+        // Unsent 0-RTT data is gone immediately.
+        assert!(client.stream_send(client_stream_id, &[4]).is_err());
+        let close = client.process(None, now()).dgram();
+        assert_eq!(close.as_ref().unwrap().len(), PATH_MTU_V6);
 
-        // The server now needs to send something that will cause the
-        // client to want to acknowledge it.  A little out of order
-        // delivery is just the thing.
-        // Note: The server can't ACK anything here, but none of what
-        // the client has sent so far has been transferred.
-        let _ = send_something(&mut server, now);
-        let dgram = send_something(&mut server, now);
+        let _ = server.process(c1, now());
+        let _ = server.process(close, now());
+        assert_draining(&server);
+    }
 
-        // The client is now after a PTO, but if it receives something
-        // that demands acknowledgment, it will send just the ACK.
-        let ack = client.process(Some(dgram), now).dgram();
-        assert!(ack.is_some());
+    #[test]
+    fn close_wait_initial_client() {
+        let mut client = default_client();
+        let mut server = default_server();
 
-        // Make sure that the packet only contained ACK frames.
-        let frames = server.test_process_input(ack.unwrap(), now);
-        assert_eq!(frames.len(), 1);
-        for (frame, space) in frames {
-            assert_eq!(space, PNSpace::ApplicationData);
-            assert!(matches!(frame, Frame::Ack { .. }));
-        }
+        let c1 = client.process(None, now()).dgram();
+        assert_eq!(*client.state(), State::WaitInitial);
+        client.close(now(), 0, "");
+        let close = client.process(None, now()).dgram();
+        assert_eq!(close.as_ref().unwrap().len(), PATH_MTU_V6);
+
+        let _ = server.process(c1, now());
+        let _ = server.process(close, now());
+        assert_draining(&server);
     }
 
-    /// Test the interaction between the loss recovery timer
-    /// and the closing timer.
     #[test]
-    fn closing_timers_interation() {
+    fn close_handshaking_client() {
         let mut client = default_client();
         let mut server = default_server();
-        connect(&mut client, &mut server);
-
-        let mut now = now();
 
-        // We're going to induce time-based loss recovery so that timer is set.
-        let _p1 = send_something(&mut client, now);
-        let p2 = send_something(&mut client, now);
-        let ack = server.process(Some(p2), now).dgram();
-        assert!(ack.is_some()); // This is an ACK.
+        let c1 = client.process(None, now()).dgram();
+        let s1 = server.process(c1, now()).dgram();
+        client.process_input(s1.unwrap(), now());
+        assert_eq!(*client.state(), State::Handshaking);
 
-        // After processing the ACK, we should be on the loss recovery timer.
-        let cb = client.process(ack, now).callback();
-        assert_ne!(cb, Duration::from_secs(0));
-        now += cb;
+        client.close(now(), 0, "");
+        let close = client.process(None, now()).dgram();
+        let _ = server.process(close, now());
+        assert_draining(&server);
+    }
 
-        // Rather than let the timer pop, close the connection.
-        client.close(now, 0, "");
-        let client_close = client.process(None, now).dgram();
-        assert!(client_close.is_some());
-        // This should now report the end of the closing period, not a
-        // zero-duration wait driven by the (now defunct) loss recovery timer.
-        let client_close_timer = client.process(None, now).callback();
-        assert_ne!(client_close_timer, Duration::from_secs(0));
+    #[test]
+    fn close_handshaking_server() {
+        let mut client = default_client();
+        let mut server = default_server();
+
+        let c1 = client.process(None, now()).dgram();
+        let _s1 = server.process(c1, now()).dgram();
+        assert_eq!(*server.state(), State::Handshaking);
+
+        // The client never sees the server's first flight, so it only has Initial keys.
+        server.close(now(), 0, "");
+        let close = server.process(None, now()).dgram();
+        let _ = client.process(close, now());
+        assert_draining(&client);
+    }
+
+    fn new_cid_frame(sequence_number: u64, retire_prior: u64, cid: u8) -> Frame {
+        Frame::NewConnectionId {
+            sequence_number,
+            retire_prior,
+            connection_id: vec![cid; 8],
+            stateless_reset_token: [cid; 16],
+        }
     }
 
     #[test]
-    fn closing_and_draining() {
-        const APP_ERROR: AppError = 7;
+    fn new_connection_id_issued() {
         let mut client = default_client();
         let mut server = default_server();
         connect(&mut client, &mut server);
 
-        // Save a packet from the client for later.
-        let p1 = send_something(&mut client, now());
+        // Each side gives the other one more connection ID, which is what the
+        // default limit allows.
+        assert_eq!(client.local_cids().count(), 2);
+        assert_eq!(server.local_cids().count(), 2);
+        // The server's goes with HANDSHAKE_DONE; the client's waits until it
+        // sends something.
+        assert_eq!(client.stats().new_cids_rx, 1);
+        assert_eq!(server.stats().new_cids_rx, 0);
+        let d = send_something(&mut client, now());
+        server.process_input(d, now());
+        assert_eq!(server.stats().new_cids_rx, 1);
+        let issued = server.local_cids().nth(1).unwrap().clone();
+        assert_eq!(
+            client
+                .remote_cids
+                .next_unused(&[&client.path().unwrap().remote_cid()[..]]),
+            Some(issued)
+        );
+    }
 
-        // Close the connection.
-        client.close(now(), APP_ERROR, "");
-        let client_close = client.process(None, now()).dgram();
-        assert!(client_close.is_some());
-        let client_close_timer = client.process(None, now()).callback();
-        assert_ne!(client_close_timer, Duration::from_secs(0));
+    #[test]
+    fn new_connection_id_limit() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let handshake_cid = client.path().unwrap().remote_cid().clone();
 
-        // The client will spit out the same packet in response to anything it receives.
-        let p3 = send_something(&mut server, now());
-        let client_close2 = client.process(Some(p3), now()).dgram();
+        // The server has used sequence numbers 0 and 1, so the client has as many
+        // connection IDs as it can hold.
+        let mut input = |f| client.input_frame(PacketType::Short, f, now());
         assert_eq!(
-            client_close.as_ref().unwrap().len(),
-            client_close2.as_ref().unwrap().len()
+            input(new_cid_frame(2, 0, 2)),
+            Err(Error::ConnectionIdLimitExceeded)
         );
-
-        // After this time, the client should transition to closed.
-        let end = client.process(None, now() + client_close_timer);
-        assert_eq!(end, Output::None);
+        // A replacement that retires the previous one stays inside the limit.
+        assert_eq!(input(new_cid_frame(3, 1, 3)), Ok(()));
+        // Repeating a frame is OK.
+        assert_eq!(input(new_cid_frame(3, 1, 3)), Ok(()));
+        // Changing the connection ID for a sequence number is not.
+        assert_eq!(input(new_cid_frame(3, 1, 4)), Err(Error::ProtocolViolation));
         assert_eq!(
-            *client.state(),
-            State::Closed(ConnectionError::Application(APP_ERROR))
+            input(new_cid_frame(4, 5, 4)),
+            Err(Error::FrameEncodingError)
         );
-
-        // When the server receives the close, it too should generate CONNECTION_CLOSE.
-        let server_close = server.process(client_close, now()).dgram();
-        assert!(server.state().closed());
-        assert!(server_close.is_some());
-        // .. but it ignores any further close packets.
-        let server_close_timer = server.process(client_close2, now()).callback();
-        assert_ne!(server_close_timer, Duration::from_secs(0));
-        // Even a legitimate packet without a close in it.
-        let server_close_timer2 = server.process(Some(p1), now()).callback();
-        assert_eq!(server_close_timer, server_close_timer2);
-
-        let end = server.process(None, now() + server_close_timer);
-        assert_eq!(end, Output::None);
+        // Another connection ID is one too many.
         assert_eq!(
-            *server.state(),
-            State::Closed(ConnectionError::Transport(Error::PeerApplicationError(
-                APP_ERROR
-            )))
+            input(new_cid_frame(4, 1, 4)),
+            Err(Error::ConnectionIdLimitExceeded)
         );
+        assert_eq!(client.stats().new_cids_rx, 7);
+
+        // The connection ID from the handshake was retired, so the client moved.
+        assert_ne!(client.path().unwrap().remote_cid(), &handshake_cid);
     }
 
-    /// When we declare a packet as lost, we keep it around for a while for another loss period.
-    /// Those packets should not affect how we report the loss recovery timer.
-    /// As the loss recovery timer based on RTT we use that to drive the state.
+    /// When the peer retires the connection ID that is in use, the connection
+    /// moves to another, and tells the peer that the old one is retired.
     #[test]
-    fn lost_but_kept_and_lr_timer() {
-        const RTT: Duration = Duration::from_secs(1);
+    fn new_connection_id_retire_prior() {
         let mut client = default_client();
         let mut server = default_server();
-        let mut now = connect_with_rtt(&mut client, &mut server, now(), RTT);
-
-        // Two packets (p1, p2) are sent at around t=0.  The first is lost.
-        let _p1 = send_something(&mut client, now);
-        let p2 = send_something(&mut client, now);
-
-        // At t=RTT/2 the server receives the packet and ACKs it.
-        now += RTT / 2;
-        let ack = server.process(Some(p2), now).dgram();
-        assert!(ack.is_some());
-        // The client also sends another two packets (p3, p4), again losing the first.
-        let _p3 = send_something(&mut client, now);
-        let p4 = send_something(&mut client, now);
-
-        // At t=RTT the client receives the ACK and goes into timed loss recovery.
-        // The client doesn't call p1 lost at this stage, but it will soon.
-        now += RTT / 2;
-        let res = client.process(ack, now);
-        // The client should be on a loss recovery timer as p1 is missing.
-        let lr_timer = res.callback();
-        // Loss recovery timer should be RTT/8, but only check for 0 or >=RTT/2.
-        assert_ne!(lr_timer, Duration::from_secs(0));
-        assert!(lr_timer < (RTT / 2));
-        // The server also receives and acknowledges p4, again sending an ACK.
-        let ack = server.process(Some(p4), now).dgram();
-        assert!(ack.is_some());
-
-        // At t=RTT*3/2 the client should declare p1 to be lost.
-        now += RTT / 2;
-        // So the client will send the data from p1 again.
-        let res = client.process(None, now);
-        assert!(res.dgram().is_some());
-        // When the client processes the ACK, it should engage the
-        // loss recovery timer for p3, not p1 (even though it still tracks p1).
-        let res = client.process(ack, now);
-        let lr_timer2 = res.callback();
-        assert_eq!(lr_timer, lr_timer2);
-    }
-
-    /// Split the first packet off a coalesced packet.
-    fn split_packet(buf: &[u8]) -> (&[u8], Option<&[u8]>) {
-        if buf[0] & 0x80 == 0 {
-            // Short header: easy.
-            return (buf, None);
-        }
-        let mut dec = Decoder::from(buf);
-        let first = dec.decode_byte().unwrap();
-        dec.skip(4); // Version.
-        dec.skip_vec(1); // DCID
-        dec.skip_vec(1); // SCID
-        if first & 0x30 == 0 {
-            // Initial
-            dec.skip_vvec();
-        }
-        dec.skip_vvec(); // The rest of the packet.
-        let p1 = &buf[..dec.offset()];
-        let p2 = if dec.remaining() > 0 {
-            Some(dec.decode_remainder())
-        } else {
-            None
+        connect_force_idle(&mut client, &mut server);
+        let handshake_cid = client.path().unwrap().remote_cid().clone();
+
+        // Make the server give out a connection ID that retires the others.
+        let seqno = server.local_cids.len() as u64;
+        let cid = server.cid_manager.borrow_mut().generate_cid();
+        server.local_cids.add(cid.clone(), Some([7; 16]));
+        let frame = Frame::NewConnectionId {
+            sequence_number: seqno,
+            retire_prior: seqno,
+            connection_id: cid.to_vec(),
+            stateless_reset_token: [7; 16],
         };
-        (p1, p2)
+        assert_eq!(client.input_frame(PacketType::Short, frame, now()), Ok(()));
+        assert_eq!(client.path().unwrap().remote_cid(), &cid);
+        assert_ne!(client.path().unwrap().remote_cid(), &handshake_cid);
+
+        // The server sees both of its earlier connection IDs retired, and
+        // replaces them, but the client stays inside its limit.
+        let d = send_something(&mut client, now());
+        server.process_input(d, now());
+        assert_eq!(server.stats().retire_cids_rx, 2);
+        assert_eq!(*server.state(), State::Confirmed);
+        let d = send_something(&mut server, now());
+        client.process_input(d, now());
+        assert_eq!(*client.state(), State::Confirmed);
     }
 
-    /// Split the first datagram off a coalesced datagram.
-    fn split_datagram(d: Datagram) -> (Datagram, Option<Datagram>) {
-        let (a, b) = split_packet(&d[..]);
-        (
-            Datagram::new(d.source(), d.destination(), a),
-            b.map(|b| Datagram::new(d.source(), d.destination(), b)),
-        )
+    #[test]
+    fn retire_connection_id() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        let issued = client.local_cids().nth(1).unwrap().clone();
+        let d = send_something(&mut client, now());
+        server.process_input(d, now());
+
+        server.remote_cids.retire(&issued);
+        let d = send_something(&mut server, now());
+        client.process_input(d, now());
+        assert_eq!(client.stats().retire_cids_rx, 1);
+        // Retiring it again has no effect.
+        let frame = Frame::RetireConnectionId { sequence_number: 1 };
+        assert_eq!(client.input_frame(PacketType::Short, frame, now()), Ok(()));
+
+        // The client replaces what was retired.
+        assert_eq!(client.local_cids().count(), 2);
+        assert!(client.local_cids().all(|cid| *cid != issued));
+        let d = send_something(&mut client, now());
+        server.process_input(d, now());
+        assert_eq!(server.stats().new_cids_rx, 2);
+        assert_eq!(*server.state(), State::Confirmed);
     }
 
-    /// We should not be setting the loss recovery timer based on packets
-    /// that are sent prior to the largest acknowledged.
-    /// Testing this requires that we construct a case where one packet
-    /// number space causes the loss recovery timer to be engaged.  At the same time,
-    /// there is a packet in another space that hasn't been acknowledged AND
-    /// that packet number space has not received acknowledgments for later packets.
+    /// A peer that retires every connection ID that it gets can't make us issue
+    /// more than a burst of new ones at a time.
     #[test]
-    fn loss_time_past_largest_acked() {
-        const RTT: Duration = Duration::from_secs(10);
-        const INCR: Duration = Duration::from_millis(1);
+    fn retire_connection_id_churn() {
         let mut client = default_client();
         let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        let issued = client.stats().new_cids_tx;
 
-        let mut now = now();
-
-        // Start the handshake.
-        let c_in = client.process(None, now).dgram();
-        now += RTT / 2;
-        let s_hs1 = server.process(c_in, now).dgram();
+        let retire_newest = |client: &mut Connection, now: Instant| {
+            let cid = client.local_cids().last().unwrap().clone();
+            let sequence_number = client.local_cids.seqno(&cid).unwrap();
+            let frame = Frame::RetireConnectionId { sequence_number };
+            assert_eq!(client.input_frame(PacketType::Short, frame, now), Ok(()));
+        };
+        for _ in 0..CID_ISSUE_BURST {
+            retire_newest(&mut client, now());
+            assert_eq!(client.local_cids().count(), 2);
+        }
+        assert_eq!(client.stats().new_cids_tx, issued + CID_ISSUE_BURST);
+        assert_eq!(client.stats().cids_throttled, 0);
+
+        retire_newest(&mut client, now());
+        assert_eq!(client.local_cids().count(), 1);
+        assert_eq!(client.stats().cids_throttled, 1);
+        let _ = client.process(None, now() + CID_ISSUE_INTERVAL / 2);
+        assert_eq!(client.local_cids().count(), 1);
+
+        // A timer is set for the replacement, so that it doesn't wait for something
+        // else to be sent.
+        let replace = now() + CID_ISSUE_INTERVAL;
+        assert!(client
+            .timers(now())
+            .contains(&(replace, TimerKind::ConnectionIds)));
+        let _ = client.process(None, replace);
+        assert_eq!(client.local_cids().count(), 2);
+        assert!(client
+            .timers(replace)
+            .iter()
+            .all(|(_, k)| *k != TimerKind::ConnectionIds));
+        assert_eq!(client.stats().new_cids_tx, issued + CID_ISSUE_BURST + 1);
+        assert_eq!(*client.state(), State::Confirmed);
+    }
 
-        // Get some spare server handshake packets for the client to ACK.
-        // This involves a time machine, so be a little cautious.
-        // This test uses an RTT of 10s, but our server starts
-        // with a much lower RTT estimate, so the PTO at this point should
-        // be much smaller than an RTT and so the server shouldn't see
-        // time go backwards.
-        let s_pto = server.process(None, now).callback();
-        assert_ne!(s_pto, Duration::from_secs(0));
-        assert!(s_pto < RTT);
-        let s_hs2 = server.process(None, now + s_pto).dgram();
-        assert!(s_hs2.is_some());
-        let s_hs3 = server.process(None, now + s_pto).dgram();
-        assert!(s_hs3.is_some());
+    #[test]
+    fn retire_connection_id_not_issued() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
 
-        // Get some Handshake packets from the client.
-        // We need one to be left unacknowledged before one that is acknowledged.
-        // So that the client engages the loss recovery timer.
-        // This is complicated by the fact that it is hard to cause the client
-        // to generate an ack-eliciting packet.  For that, we use the Finished message.
-        // Reordering delivery ensures that the later packet is also acknowledged.
-        now += RTT / 2;
-        let c_hs1 = client.process(s_hs1, now).dgram();
-        assert!(c_hs1.is_some()); // This comes first, so it's useless.
-        maybe_authenticate(&mut client);
-        let c_hs2 = client.process(None, now).dgram();
-        assert!(c_hs2.is_some()); // This one will elicit an ACK.
+        for seq in &[2, 1000] {
+            assert_eq!(
+                client.input_frame(
+                    PacketType::Short,
+                    Frame::RetireConnectionId {
+                        sequence_number: *seq,
+                    },
+                    now()
+                ),
+                Err(Error::ProtocolViolation)
+            );
+        }
+        assert_eq!(client.stats().retire_cids_rx, 2);
+    }
 
-        // The we need the outstanding packet to be sent after the
-        // application data packet, so space these out a tiny bit.
-        let _p1 = send_something(&mut client, now + INCR);
-        let c_hs3 = client.process(s_hs2, now + (INCR * 2)).dgram();
-        assert!(c_hs3.is_some()); // This will be left outstanding.
-        let c_hs4 = client.process(s_hs3, now + (INCR * 3)).dgram();
-        assert!(c_hs4.is_some()); // This will be acknowledged.
+    #[test]
+    fn retire_connection_id_closes() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
 
-        // Get an ACK for the client.
-        now += RTT / 2;
-        // Deliver the last one first, so that gets acknowledged.
-        // This won't generate an ACK, because it only contains an ACK.
-        let s_ack1 = server.process(c_hs4, now).dgram();
-        assert!(s_ack1.is_none());
-        // This includes an ACK, but it also includes HANDSHAKE_DONE,
-        // which we need to remove because that will cause the Handshake loss recovery
-        // state to be dropped.
-        let s_ack2 = server.process(c_hs2, now).dgram();
-        assert!(s_ack2.is_some());
-        let (s_hs_ack, _s_ap_ack) = split_datagram(s_ack2.unwrap());
+        let frame = Frame::RetireConnectionId {
+            sequence_number: 1000,
+        };
+        let res = client.input_frame(PacketType::Short, frame, now());
+        client.absorb_error(now(), res);
+        assert_error(
+            &client,
+            ConnectionError::Transport(Error::ProtocolViolation),
+        );
+    }
 
-        // Now the client should start its loss recovery timer based on the ACK.
-        now += RTT / 2;
-        let c_ack = client.process(Some(s_hs_ack), now).dgram();
-        assert!(c_ack.is_none());
-        // The client should now have the loss recovery timer active.
-        let lr_time = client.process(None, now).callback();
-        assert_ne!(lr_time, Duration::from_secs(0));
-        assert!(lr_time < (RTT / 2));
+    /// A connection ID can't be retired in a packet that is sent to it.
+    #[test]
+    fn retire_connection_id_in_use() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
 
-        // Skipping forward by the loss recovery timer should cause the client to
-        // mark packets as lost and retransmit, after which we should be on the PTO
-        // timer.
-        now += lr_time;
-        let delay = client.process(None, now).callback();
-        assert_ne!(delay, Duration::from_secs(0));
-        assert!(delay > lr_time);
+        let cid = server.path().unwrap().remote_cid().clone();
+        server.remote_cids.retire(&cid);
+        let d = send_something(&mut server, now());
+        client.process_input(d, now());
+        assert_error(
+            &client,
+            ConnectionError::Transport(Error::ProtocolViolation),
+        );
     }
 
     #[test]
-    fn unknown_version() {
+    fn handshake_summary() {
         let mut client = default_client();
-        // Start the handshake.
-        let _ = client.process(None, now()).dgram();
-
-        let mut unknown_version_packet = vec![0x80, 0x1a, 0x1a, 0x1a, 0x1a];
-        unknown_version_packet.resize(1200, 0x0);
-        client.process(
-            Some(Datagram::new(
-                loopback(),
-                loopback(),
-                unknown_version_packet,
-            )),
-            now(),
+        let now = now();
+        let _ = client.process(None, now).dgram().expect("should send an Initial");
+        let summary = client.handshake_state_summary(now);
+        assert_eq!(summary.state, State::WaitInitial);
+        let initial = summary.initial.unwrap();
+        assert!(initial.read_keys && initial.write_keys);
+        assert!(initial.crypto_sent > 0);
+        assert_eq!(initial.crypto_received, 0);
+        assert_eq!(initial.unacked_packets, 1);
+        let handshake = summary.handshake.unwrap();
+        assert!(!handshake.read_keys && !handshake.write_keys);
+        assert_eq!(summary.since_last_received, None);
+        assert_eq!(
+            summary.next_timer.map(|(kind, _)| kind),
+            Some(TimerKind::LossRecovery)
         );
-        assert_eq!(1, client.stats().dropped_rx);
+        // This shouldn't panic.
+        let _ = summary.to_string();
+
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let summary = client.handshake_state_summary(now);
+        assert!(summary.state.connected());
+        assert!(summary.initial.is_none());
+        let application = summary.application.unwrap();
+        assert!(application.read_keys && application.write_keys);
+        assert!(summary.since_last_received.is_some());
     }
 }
@@ -5,13 +5,13 @@
 // except according to those terms.
 
 use std::cell::RefCell;
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::mem;
 use std::ops::{Index, IndexMut, Range};
 use std::rc::Rc;
 use std::time::Instant;
 
-use neqo_common::{hex, matches, qdebug, qerror, qinfo, qtrace, Role};
+use neqo_common::{hex, matches, qdebug, qerror, qinfo, qtrace, qwarn, Role};
 use neqo_crypto::aead::Aead;
 use neqo_crypto::hp::HpKey;
 use neqo_crypto::{
@@ -31,11 +31,83 @@ use crate::{Error, Res};
 
 const MAX_AUTH_TAG: usize = 32;
 
+/// The TLS handshake message types that are checked before they are passed to TLS.
+const TLS_HS_HELLO_REQUEST: u8 = 0;
+const TLS_HS_NEW_SESSION_TICKET: u8 = 4;
+const TLS_HS_KEY_UPDATE: u8 = 24;
+/// The start of the ChangeCipherSpec record that TLS sends for middlebox compatibility:
+/// the content type, the legacy version, and the first byte of the length.  Read as a
+/// handshake message header, this is a Finished message that is far too long.
+const TLS_CCS_RECORD_START: &[u8] = &[0x14, 0x03, 0x03, 0x00];
+
 #[derive(Debug)]
 pub struct Crypto {
     pub(crate) tls: Agent,
     pub(crate) streams: CryptoStreams,
     pub(crate) states: CryptoStates,
+    /// Where each handshake message from the peer starts, for each packet number space.
+    inbound: [InboundMessages; 3],
+}
+
+/// Tracks the boundaries of the handshake messages in the CRYPTO stream for one
+/// packet number space, so that the type of each can be checked.
+#[derive(Debug, Default)]
+struct InboundMessages {
+    /// The header of the next message, while it is incomplete.
+    header: Vec<u8>,
+    /// How much of the current message remains.
+    remaining: usize,
+}
+
+impl InboundMessages {
+    /// Check the messages that start in `data`, which continues on from the data that
+    /// was last passed to this function.
+    fn check(&mut self, role: Role, space: PNSpace, mut data: &[u8]) -> Res<()> {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let n = min(self.remaining, data.len());
+                self.remaining -= n;
+                data = &data[n..];
+                continue;
+            }
+            let n = min(4 - self.header.len(), data.len());
+            self.header.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.header.len() < 4 {
+                break;
+            }
+            Self::check_header(role, space, &self.header)?;
+            self.remaining = self.header[1..]
+                .iter()
+                .fold(0, |len, b| (len << 8) | usize::from(*b));
+            self.header.clear();
+        }
+        Ok(())
+    }
+
+    /// TLS 1.3 in QUIC has no ChangeCipherSpec, no renegotiation, and no KeyUpdate.
+    /// After the handshake, the only message is NewSessionTicket from the server.
+    fn check_header(role: Role, space: PNSpace, header: &[u8]) -> Res<()> {
+        if header == TLS_CCS_RECORD_START {
+            qwarn!("ChangeCipherSpec in CRYPTO frames");
+            return Err(Error::ProtocolViolation);
+        }
+        let ok = match header[0] {
+            TLS_HS_HELLO_REQUEST | TLS_HS_KEY_UPDATE => false,
+            TLS_HS_NEW_SESSION_TICKET => role == Role::Client && space == PNSpace::ApplicationData,
+            _ => space != PNSpace::ApplicationData,
+        };
+        if ok {
+            Ok(())
+        } else {
+            qwarn!(
+                "Handshake message {} not allowed in {} space",
+                header[0],
+                space
+            );
+            Err(Error::ProtocolViolation)
+        }
+    }
 }
 
 impl Crypto {
@@ -62,6 +134,7 @@ impl Crypto {
             tls: agent,
             streams: Default::default(),
             states: Default::default(),
+            inbound: Default::default(),
         })
     }
 
@@ -71,6 +144,14 @@ impl Crypto {
         space: PNSpace,
         data: Option<&[u8]>,
     ) -> Res<&HandshakeState> {
+        if let Some(d) = data {
+            let role = match self.tls {
+                Agent::Client(_) => Role::Client,
+                Agent::Server(_) => Role::Server,
+            };
+            self.inbound[space as usize].check(role, space, d)?;
+        }
+        let was_connected = self.tls.state().is_connected();
         let input = data.map(|d| {
             qtrace!("Handshake record received {:0x?} ", d);
             let epoch = match space {
@@ -88,6 +169,11 @@ impl Crypto {
 
         match self.tls.handshake_raw(now, input) {
             Ok(output) => {
+                if was_connected && !self.tls.state().is_connected() {
+                    // Something like renegotiation, which TLS 1.3 doesn't have.
+                    qerror!("TLS handshake restarted after it completed");
+                    return Err(Error::ProtocolViolation);
+                }
                 self.buffer_records(output)?;
                 Ok(self.tls.state())
             }
@@ -592,6 +678,29 @@ impl CryptoStates {
         }
     }
 
+    /// The packet number that the next packet in `space` is expected to have, which
+    /// is used to recover the full packet number from what is sent.  The packet
+    /// number space carries on across key updates, so this is the higher of the
+    /// values for the current read keys and the keys for the next key phase.
+    pub fn rx_next_pn(&mut self, space: PNSpace) -> Option<PacketNumber> {
+        let next = if space == PNSpace::ApplicationData {
+            self.app_read_next
+                .as_ref()
+                .map_or(0, |arn| arn.dx.next_pn())
+        } else {
+            0
+        };
+        self.rx_hp(space).map(|rx| max(rx.next_pn(), next))
+    }
+
+    /// Create a standalone set of crypto states that only has Initial keys.
+    /// A server uses this to examine an Initial packet before it creates a connection.
+    pub fn new_initial(role: Role, dcid: &[u8]) -> Self {
+        let mut states = Self::default();
+        states.init(role, dcid);
+        states
+    }
+
     /// Create the initial crypto state.
     pub fn init(&mut self, role: Role, dcid: &[u8]) {
         const CLIENT_INITIAL_LABEL: &str = "client in";
@@ -789,6 +898,19 @@ impl CryptoStates {
         Ok(())
     }
 
+    /// Whether there are keys for `space`.  This returns (read, write).
+    pub fn has_keys(&self, space: PNSpace) -> (bool, bool) {
+        let zero_rtt = |dir| self.zero_rtt.as_ref().map_or(false, |z| z.direction == dir);
+        match space {
+            PNSpace::Initial => (self.initial.is_some(), self.initial.is_some()),
+            PNSpace::Handshake => (self.handshake.is_some(), self.handshake.is_some()),
+            PNSpace::ApplicationData => (
+                self.app_read.is_some() || zero_rtt(CryptoDxDirection::Read),
+                self.app_write.is_some() || zero_rtt(CryptoDxDirection::Write),
+            ),
+        }
+    }
+
     /// Get the current/highest epoch.  This returns (write, read) epochs.
     #[cfg(test)]
     pub fn get_epochs(&self) -> (Option<usize>, Option<usize>) {
@@ -910,6 +1032,19 @@ impl CryptoStreams {
         self.get(space).map_or(false, |cs| cs.rx.data_ready())
     }
 
+    /// Progress on the crypto stream for `space`, if it hasn't been discarded.
+    /// This returns (sent, send buffered, received, receive buffered).
+    pub fn progress(&self, space: PNSpace) -> Option<(u64, usize, u64, u64)> {
+        self.get(space).map(|cs| {
+            (
+                cs.tx.highest_sent(),
+                cs.tx.buffered(),
+                cs.rx.retired(),
+                cs.rx.buffered(),
+            )
+        })
+    }
+
     pub fn read_to_end(&mut self, space: PNSpace, buf: &mut Vec<u8>) -> usize {
         self.get_mut(space).unwrap().rx.read_to_end(buf)
     }
@@ -974,27 +1109,25 @@ impl CryptoStreams {
         remaining: usize,
     ) -> Option<(Frame, Option<RecoveryToken>)> {
         let cs = self.get_mut(space).unwrap();
-        if let Some((offset, data)) = cs.tx.next_bytes() {
-            let (frame, length) = Frame::new_crypto(offset, data, remaining);
-            cs.tx.mark_as_sent(offset, length);
-
-            qdebug!(
-                "Emitting crypto frame space={}, offset={}, len={}",
+        let (offset, data) = cs.tx.next_bytes()?;
+        // This is none if there isn't space for any data.
+        let (frame, length) = Frame::new_crypto(offset, data, remaining)?;
+        cs.tx.mark_as_sent(offset, length);
+
+        qdebug!(
+            "Emitting crypto frame space={}, offset={}, len={}",
+            space,
+            offset,
+            length
+        );
+        Some((
+            frame,
+            Some(RecoveryToken::Crypto(CryptoRecoveryToken {
                 space,
                 offset,
-                length
-            );
-            Some((
-                frame,
-                Some(RecoveryToken::Crypto(CryptoRecoveryToken {
-                    space,
-                    offset,
-                    length,
-                })),
-            ))
-        } else {
-            None
-        }
+                length,
+            })),
+        ))
     }
 }
 
@@ -6,14 +6,17 @@
 
 // Collecting a list of events relevant to whoever is using the Connection.
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
+use std::ops::BitOr;
 use std::rc::Rc;
 
 use neqo_common::matches;
 
+use crate::cc::CongestionState;
 use crate::connection::State;
 use crate::frame::StreamType;
+use crate::stats::FlowControlStall;
 use crate::stream_id::StreamId;
 use crate::AppError;
 
@@ -44,15 +47,96 @@ pub enum ConnectionEvent {
     /// This event invalidates all state in streams that has been created.
     /// Any data written to streams needs to be written again.
     ZeroRttRejected,
+    /// The congestion controller entered or left a recovery period.  This is
+    /// advisory: it is reported at most once per round trip, so short-lived
+    /// changes might not be seen, and it says nothing about whether any
+    /// particular data was lost.
+    CongestionStateChanged { state: CongestionState },
+    /// Sending has been blocked on a flow control limit from the peer for the stall
+    /// timeout.  The peer might never increase the limit, so the application might
+    /// want to give up and try elsewhere.  This is reported once per limit.
+    FlowControlStalled { stall: FlowControlStall },
+}
+
+impl ConnectionEvent {
+    /// The kind of event, for filtering.  Events that can't be filtered return `None`.
+    fn kind(&self) -> Option<EventMask> {
+        match self {
+            Self::NewStream { .. } => Some(EventMask::NEW_STREAM),
+            Self::SendStreamWritable { .. } => Some(EventMask::SEND_STREAM_WRITABLE),
+            Self::RecvStreamReadable { .. } => Some(EventMask::RECV_STREAM_READABLE),
+            Self::SendStreamComplete { .. } => Some(EventMask::SEND_STREAM_COMPLETE),
+            Self::SendStreamCreatable { .. } => Some(EventMask::SEND_STREAM_CREATABLE),
+            Self::AuthenticationNeeded
+            | Self::RecvStreamReset { .. }
+            | Self::SendStreamStopSending { .. }
+            | Self::StateChange(_)
+            | Self::ZeroRttRejected
+            | Self::CongestionStateChanged { .. }
+            | Self::FlowControlStalled { .. } => None,
+        }
+    }
+}
+
+/// A set of event kinds, see `Connection::set_event_filter`.
+///
+/// Only events that an application can recover by polling can be filtered.
+/// `AuthenticationNeeded`, `RecvStreamReset`, `SendStreamStopSending`,
+/// `StateChange`, `ZeroRttRejected`, `CongestionStateChanged`, and `FlowControlStalled`
+/// are always delivered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventMask(u8);
+
+impl EventMask {
+    pub const NONE: Self = Self(0);
+    pub const NEW_STREAM: Self = Self(1);
+    pub const SEND_STREAM_WRITABLE: Self = Self(1 << 1);
+    pub const RECV_STREAM_READABLE: Self = Self(1 << 2);
+    pub const SEND_STREAM_COMPLETE: Self = Self(1 << 3);
+    pub const SEND_STREAM_CREATABLE: Self = Self(1 << 4);
+    pub const ALL: Self = Self((1 << 5) - 1);
+
+    /// Whether all of the kinds in `other` are in this set.
+    #[must_use]
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// This set, less the kinds in `other`.
+    #[must_use]
+    pub fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl Default for EventMask {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl BitOr for EventMask {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
 }
 
 #[derive(Debug, Default, Clone)]
 #[allow(clippy::module_name_repetitions)]
 pub struct ConnectionEvents {
     events: Rc<RefCell<VecDeque<ConnectionEvent>>>,
+    filter: Rc<Cell<EventMask>>,
 }
 
 impl ConnectionEvents {
+    /// Only queue events of the kinds in `filter`.  Queued events that
+    /// no longer pass are dropped.
+    pub fn set_filter(&self, filter: EventMask) {
+        self.filter.set(filter);
+        self.remove(|evt| evt.kind().map_or(false, |k| !filter.contains(k)));
+    }
+
     pub fn authentication_needed(&self) {
         self.insert(ConnectionEvent::AuthenticationNeeded);
     }
@@ -126,6 +210,14 @@ impl ConnectionEvents {
         self.insert(ConnectionEvent::ZeroRttRejected);
     }
 
+    pub fn congestion_state_changed(&self, state: CongestionState) {
+        self.insert(ConnectionEvent::CongestionStateChanged { state });
+    }
+
+    pub fn flow_control_stalled(&self, stall: FlowControlStall) {
+        self.insert(ConnectionEvent::FlowControlStalled { stall });
+    }
+
     pub fn recv_stream_complete(&self, stream_id: StreamId) {
         // If stopped, no longer readable.
         self.remove(|evt| matches!(evt, ConnectionEvent::RecvStreamReadable { stream_id: x } if *x == stream_id.as_u64()));
@@ -145,6 +237,11 @@ impl ConnectionEvents {
 
     #[allow(clippy::block_in_if_condition_stmt)]
     fn insert(&self, event: ConnectionEvent) {
+        if let Some(kind) = event.kind() {
+            if !self.filter.get().contains(kind) {
+                return;
+            }
+        }
         let mut q = self.events.borrow_mut();
 
         // Special-case two enums that are not strictly PartialEq equal but that
@@ -243,4 +340,39 @@ mod tests {
         )));
         assert_eq!(evts.events().count(), 1);
     }
+
+    #[test]
+    fn event_filter() {
+        let evts = ConnectionEvents::default();
+        evts.send_stream_writable(4.into());
+        evts.recv_stream_readable(4.into());
+        evts.set_filter(EventMask::ALL.without(EventMask::SEND_STREAM_WRITABLE));
+        // The queued event is dropped.
+        assert_eq!(
+            evts.events().collect::<Vec<_>>(),
+            vec![ConnectionEvent::RecvStreamReadable { stream_id: 4 }]
+        );
+
+        for i in 0..1000_u64 {
+            evts.send_stream_writable((i * 4).into());
+        }
+        assert!(!evts.has_events());
+
+        // Events that change state can't be filtered.
+        evts.set_filter(EventMask::NONE);
+        evts.authentication_needed();
+        evts.send_stream_stop_sending(8.into(), 55);
+        evts.recv_stream_reset(8.into(), 66);
+        evts.client_0rtt_rejected();
+        evts.connection_state_change(State::Connected);
+        assert_eq!(evts.events().count(), 2);
+
+        evts.set_filter(EventMask::NEW_STREAM | EventMask::SEND_STREAM_WRITABLE);
+        evts.new_stream(4.into());
+        evts.send_stream_writable(4.into());
+        evts.recv_stream_readable(4.into());
+        evts.send_stream_complete(8.into());
+        evts.send_stream_creatable(StreamType::BiDi);
+        assert_eq!(evts.events().count(), 2);
+    }
 }
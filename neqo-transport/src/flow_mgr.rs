@@ -7,21 +7,48 @@
 // Tracks possibly-redundant flow control signals from other code and converts
 // into flow control frames needing to be sent to the remote.
 
+use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::mem;
 
 use neqo_common::{qinfo, qtrace, qwarn, Encoder};
 
+use crate::connection::LOCAL_MAX_DATA;
 use crate::frame::{Frame, StreamType};
 use crate::recovery::RecoveryToken;
 use crate::recv_stream::RecvStreams;
-use crate::send_stream::SendStreams;
+use crate::send_stream::{SendStreams, TransmissionPriority};
 use crate::stream_id::{StreamId, StreamIndex, StreamIndexes};
 use crate::tracking::PNSpace;
-use crate::AppError;
+use crate::{AppError, Error, Res};
 
 pub type FlowControlRecoveryToken = Frame;
 
+/// The receive side of connection flow control.
+#[derive(Debug)]
+struct RxCredit {
+    /// The initial_max_data transport parameter.  More credit is sent as the
+    /// application reads, so that the peer can have this much outstanding.
+    window: u64,
+    /// The limit that was last sent, in the transport parameter or MAX_DATA.
+    max_data: u64,
+    /// What the peer's streams count against the limit.
+    received: u64,
+    /// How much of `received` has been read or discarded.
+    retired: u64,
+}
+
+impl Default for RxCredit {
+    fn default() -> Self {
+        Self {
+            window: LOCAL_MAX_DATA,
+            max_data: LOCAL_MAX_DATA,
+            received: 0,
+            retired: 0,
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct FlowMgr {
     // Discriminant as key ensures only 1 of every frame type will be queued.
@@ -35,8 +62,19 @@ pub struct FlowMgr {
     // per stream type will be queued.
     from_stream_types: HashMap<(StreamType, mem::Discriminant<Frame>), Frame>,
 
+    // MAX_STREAM_DATA frames, which are kept apart so that they can wait for
+    // a packet that is being sent anyway.
+    stream_credit: HashMap<StreamId, Frame>,
+    /// Whether a peer might soon be blocked on one of the streams in `stream_credit`.
+    stream_credit_urgent: bool,
+
+    rx: RxCredit,
+
     used_data: u64,
     max_data: u64,
+    critical_reserve: u64,
+    /// The connection limit at the last DATA_BLOCKED.
+    blocked_limit: Option<u64>,
 }
 
 impl FlowMgr {
@@ -44,11 +82,49 @@ impl FlowMgr {
         self.max_data - self.used_data
     }
 
+    /// Connection credit that a stream with `priority` can use.  Only critical
+    /// streams get the last `critical_reserve` bytes.
+    pub fn conn_credit_avail_for(&self, priority: TransmissionPriority) -> u64 {
+        if priority == TransmissionPriority::Critical {
+            self.conn_credit_avail()
+        } else {
+            self.conn_credit_avail()
+                .saturating_sub(self.critical_reserve)
+        }
+    }
+
+    pub fn set_critical_reserve(&mut self, reserve: u64) {
+        self.critical_reserve = reserve;
+    }
+
+    /// The connection limit, if sending is blocked on it.  This is only the case
+    /// after DATA_BLOCKED was queued for the current limit, and while streams that
+    /// aren't critical don't have any credit.
+    pub fn conn_blocked(&self) -> Option<u64> {
+        if self.blocked_limit == Some(self.max_data)
+            && self.conn_credit_avail_for(TransmissionPriority::Normal) == 0
+        {
+            self.blocked_limit
+        } else {
+            None
+        }
+    }
+
+    pub fn conn_max_credit(&self) -> u64 {
+        self.max_data
+    }
+
     pub fn conn_increase_credit_used(&mut self, amount: u64) {
         self.used_data += amount;
         assert!(self.used_data <= self.max_data)
     }
 
+    /// Return credit for data that was buffered but will never be sent.
+    pub fn conn_decrease_credit_used(&mut self, amount: u64) {
+        assert!(amount <= self.used_data);
+        self.used_data -= amount;
+    }
+
     // Dummy DataBlocked frame for discriminant use below
 
     /// Returns whether max credit was actually increased.
@@ -65,6 +141,47 @@ impl FlowMgr {
         }
     }
 
+    /// Set the receive window, which is the initial_max_data transport parameter.
+    /// This can only change before the peer has sent anything.
+    pub fn set_rx_window(&mut self, window: u64) {
+        debug_assert_eq!(self.rx.received, 0);
+        self.rx.window = window;
+        self.rx.max_data = window;
+    }
+
+    /// Count more data from the peer against the connection flow control limit.
+    pub fn conn_rx_received(&mut self, amount: u64) -> Res<()> {
+        if self.rx.received + amount > self.rx.max_data {
+            qwarn!(
+                "Connection RX window {} exceeded: {}",
+                self.rx.max_data,
+                self.rx.received + amount
+            );
+            return Err(Error::FlowControlError);
+        }
+        self.rx.received += amount;
+        Ok(())
+    }
+
+    /// Return credit for data that the application has read or that was discarded.
+    pub fn conn_rx_retired(&mut self, amount: u64) {
+        self.rx.retired += amount;
+        debug_assert!(self.rx.retired <= self.rx.received);
+        self.conn_rx_update(false);
+    }
+
+    /// Tell the peer how much they can send.  Unless `force` is set, as when the
+    /// peer says it is blocked or the last MAX_DATA was lost, this only happens once
+    /// the application has read half the window, so that updates aren't sent for
+    /// every read.
+    pub fn conn_rx_update(&mut self, force: bool) {
+        let new_max = min(self.rx.retired + self.rx.window, LOCAL_MAX_DATA);
+        if force || new_max >= self.rx.max_data + self.rx.window / 2 {
+            self.rx.max_data = max(self.rx.max_data, new_max);
+            self.max_data(self.rx.max_data);
+        }
+    }
+
     // -- frames scoped on connection --
 
     pub fn data_blocked(&mut self) {
@@ -72,6 +189,7 @@ impl FlowMgr {
             data_limit: self.max_data,
         };
         self.from_conn.insert(mem::discriminant(&frame), frame);
+        self.blocked_limit = Some(self.max_data);
     }
 
     pub fn path_response(&mut self, data: [u8; 8]) {
@@ -79,7 +197,7 @@ impl FlowMgr {
         self.from_conn.insert(mem::discriminant(&frame), frame);
     }
 
-    pub fn max_data(&mut self, maximum_data: u64) {
+    fn max_data(&mut self, maximum_data: u64) {
         let frame = Frame::MaxData { maximum_data };
         self.from_conn.insert(mem::discriminant(&frame), frame);
     }
@@ -118,18 +236,31 @@ impl FlowMgr {
             stream_id,
             maximum_stream_data,
         };
-        self.from_streams
-            .insert((stream_id, mem::discriminant(&frame)), frame);
+        self.stream_credit.insert(stream_id, frame);
+    }
+
+    /// Send any stream data updates straight away, rather than waiting for
+    /// another packet to put them in.
+    pub fn stream_credit_urgent(&mut self) {
+        self.stream_credit_urgent = true;
+    }
+
+    /// Whether stream data updates are waiting to be sent, and if so, whether
+    /// they are urgent.
+    pub fn stream_credit_pending(&self) -> Option<bool> {
+        if self.stream_credit.is_empty() {
+            None
+        } else {
+            Some(self.stream_credit_urgent)
+        }
     }
 
     /// Don't send stream data updates if no more data is coming
     pub fn clear_max_stream_data(&mut self, stream_id: StreamId) {
-        let frame = Frame::MaxStreamData {
-            stream_id,
-            maximum_stream_data: 0,
-        };
-        self.from_streams
-            .remove(&(stream_id, mem::discriminant(&frame)));
+        self.stream_credit.remove(&stream_id);
+        if self.stream_credit.is_empty() {
+            self.stream_credit_urgent = false;
+        }
     }
 
     /// Indicate to receiving remote we need more credits
@@ -163,12 +294,19 @@ impl FlowMgr {
     }
 
     pub fn peek(&self) -> Option<&Frame> {
+        self.peek_with(true)
+    }
+
+    /// Look at the next frame, leaving out stream data updates unless `stream_credit`.
+    fn peek_with(&self, stream_credit: bool) -> Option<&Frame> {
         if let Some(key) = self.from_conn.keys().next() {
             self.from_conn.get(key)
         } else if let Some(key) = self.from_streams.keys().next() {
             self.from_streams.get(key)
         } else if let Some(key) = self.from_stream_types.keys().next() {
             self.from_stream_types.get(key)
+        } else if stream_credit {
+            self.stream_credit.values().next()
         } else {
             None
         }
@@ -247,16 +385,20 @@ impl FlowMgr {
 
                 self.max_streams(*local_max, stream_type)
             }
-            // Only resend "*Blocked" frames if still blocked
-            Frame::DataBlocked { .. } => {
-                if self.conn_credit_avail() == 0 {
+            // Only resend "*Blocked" frames if still blocked at the same limit.
+            // The frame has to arrive, so that the peer knows.
+            Frame::DataBlocked { data_limit } => {
+                if data_limit == self.max_data && self.conn_credit_avail() == 0 {
                     self.data_blocked()
                 }
             }
-            Frame::StreamDataBlocked { stream_id, .. } => {
+            Frame::StreamDataBlocked {
+                stream_id,
+                stream_data_limit,
+            } => {
                 if let Ok(ss) = send_streams.get(stream_id) {
-                    if ss.credit_avail() == 0 {
-                        self.stream_data_blocked(stream_id, ss.max_stream_data())
+                    if ss.max_stream_data() == stream_data_limit && ss.credit_avail() == 0 {
+                        self.stream_data_blocked(stream_id, stream_data_limit)
                     }
                 }
             }
@@ -278,27 +420,32 @@ impl FlowMgr {
                 application_error_code,
             } => self.stop_sending(stream_id, application_error_code),
             // Resend MaxStreamData if not SizeKnown
-            // (maybe_send_flowc_update() checks this.)
+            // (send_flowc_update() checks this.)
             Frame::MaxStreamData { stream_id, .. } => {
                 if let Some(rs) = recv_streams.get_mut(&stream_id) {
-                    rs.maybe_send_flowc_update()
+                    rs.send_flowc_update()
                 }
             }
+            // Resend MaxData with the current limit.
+            Frame::MaxData { .. } => self.conn_rx_update(true),
             Frame::PathResponse { .. } => qinfo!("Path Response lost, not re-sent"),
             _ => qwarn!("Unexpected Flow frame {:?} lost, not re-sent", token),
         }
     }
 
+    /// Get a frame to send.  Stream data updates are only included if `stream_credit`
+    /// is set, so that they can wait for a packet that would be sent without them.
     pub(crate) fn get_frame(
         &mut self,
         space: PNSpace,
         remaining: usize,
+        stream_credit: bool,
     ) -> Option<(Frame, Option<RecoveryToken>)> {
         if space != PNSpace::ApplicationData {
             return None;
         }
 
-        if let Some(frame) = self.peek() {
+        if let Some(frame) = self.peek_with(stream_credit) {
             // A suboptimal way to figure out if the frame fits within remaining
             // space.
             let mut d = Encoder::default();
@@ -311,15 +458,12 @@ impl FlowMgr {
             return None;
         }
         // There is enough space we can add this frame to the packet.
-        let frame = self.next().expect("just peeked this");
+        let frame = self.next_with(stream_credit).expect("just peeked this");
         Some((frame.clone(), Some(RecoveryToken::Flow(frame))))
     }
-}
 
-impl Iterator for FlowMgr {
-    type Item = Frame;
-    /// Used by generator to get a flow control frame.
-    fn next(&mut self) -> Option<Frame> {
+    /// Take the frame that `peek_with` returns.
+    fn next_with(&mut self, stream_credit: bool) -> Option<Frame> {
         let first_key = self.from_conn.keys().next();
         if let Some(&first_key) = first_key {
             return self.from_conn.remove(&first_key);
@@ -335,6 +479,25 @@ impl Iterator for FlowMgr {
             return self.from_stream_types.remove(&first_key);
         }
 
+        if stream_credit {
+            let first_key = self.stream_credit.keys().next();
+            if let Some(&first_key) = first_key {
+                let frame = self.stream_credit.remove(&first_key);
+                if self.stream_credit.is_empty() {
+                    self.stream_credit_urgent = false;
+                }
+                return frame;
+            }
+        }
+
         None
     }
 }
+
+impl Iterator for FlowMgr {
+    type Item = Frame;
+    /// Used by generator to get a flow control frame.
+    fn next(&mut self) -> Option<Frame> {
+        self.next_with(true)
+    }
+}
@@ -10,7 +10,7 @@ use neqo_common::{matches, qdebug, qtrace, Decoder, Encoder};
 
 use crate::cid::MAX_CONNECTION_ID_LEN;
 use crate::packet::PacketType;
-use crate::stream_id::{StreamId, StreamIndex};
+use crate::stream_id::{StreamId, StreamIndex, MAX_STREAMS};
 use crate::{AppError, ConnectionError, Error, Res, TransportError, ERROR_APPLICATION_CLOSE};
 
 use std::cmp::{min, Ordering};
@@ -45,6 +45,10 @@ pub const FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT: FrameType = 0x1c;
 pub const FRAME_TYPE_CONNECTION_CLOSE_APPLICATION: FrameType = 0x1d;
 const FRAME_TYPE_HANDSHAKE_DONE: FrameType = 0x1e;
 
+/// The longest reason phrase that is kept from a received CONNECTION_CLOSE frame.
+/// Longer reason phrases are truncated.
+pub const MAX_REASON_PHRASE_LEN: usize = 256;
+
 const STREAM_FRAME_BIT_FIN: u64 = 0x01;
 const STREAM_FRAME_BIT_LEN: u64 = 0x02;
 const STREAM_FRAME_BIT_OFF: u64 = 0x04;
@@ -250,20 +254,24 @@ impl Frame {
     }
 
     /// Create a CRYPTO frame that fits the available space and its length.
-    pub fn new_crypto(offset: u64, data: &[u8], space: usize) -> (Self, usize) {
+    /// This is `None` if none of the data fits.
+    pub fn new_crypto(offset: u64, data: &[u8], space: usize) -> Option<(Self, usize)> {
         // Subtract the frame type and offset from available space.
-        let mut remaining = space - 1 - Encoder::varint_len(offset);
+        let mut remaining = space.checked_sub(1 + Encoder::varint_len(offset))?;
         // Then subtract space for the length field.
-        let data_len = min(remaining - 1, data.len());
-        remaining -= Encoder::varint_len(u64::try_from(data_len).unwrap());
+        let data_len = min(remaining.saturating_sub(1), data.len());
+        remaining = remaining.checked_sub(Encoder::varint_len(u64::try_from(data_len).unwrap()))?;
         remaining = min(data.len(), remaining);
-        (
+        if remaining == 0 {
+            return None;
+        }
+        Some((
             Self::Crypto {
                 offset,
                 data: data[..remaining].to_vec(),
             },
             remaining,
-        )
+        ))
     }
 
     /// Create a STREAM frame that fits the available space.
@@ -467,6 +475,32 @@ impl Frame {
         }
     }
 
+    /// Shorten the reason phrase of a CONNECTION_CLOSE so that the frame fits in `space`.
+    /// This is `None` if the frame doesn't fit even without a reason phrase.
+    pub fn close_that_fits(&self, space: usize) -> Option<Self> {
+        if let Self::ConnectionClose {
+            error_code,
+            frame_type,
+            reason_phrase,
+        } = &self
+        {
+            let mut enc = Encoder::default();
+            self.marshal(&mut enc);
+            let excess = enc.len().saturating_sub(space);
+            if excess > reason_phrase.len() {
+                return None;
+            }
+            // The length of the reason phrase can only get shorter.
+            Some(Self::ConnectionClose {
+                error_code: *error_code,
+                frame_type: *frame_type,
+                reason_phrase: reason_phrase[..reason_phrase.len() - excess].to_vec(),
+            })
+        } else {
+            panic!("Attempted to shorten a non-close frame");
+        }
+    }
+
     pub fn ack_eliciting(&self) -> bool {
         !matches!(self, Self::Ack { .. } | Self::Padding | Self::ConnectionClose { .. })
     }
@@ -664,7 +698,7 @@ impl Frame {
             }),
             FRAME_TYPE_MAX_STREAMS_BIDI | FRAME_TYPE_MAX_STREAMS_UNIDI => {
                 let m = dv!(dec);
-                if m > (1 << 60) {
+                if m > MAX_STREAMS {
                     return Err(Error::StreamLimitError);
                 }
                 Ok(Self::MaxStreams {
@@ -719,10 +753,15 @@ impl Frame {
                 Ok(Self::PathResponse { data: datav })
             }
             FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT | FRAME_TYPE_CONNECTION_CLOSE_APPLICATION => {
+                let error_code = CloseError::from_type_bit(t, d!(dec.decode_varint()));
+                let frame_type = dv!(dec);
+                // The whole reason phrase is consumed, but only the start of it is kept.
+                let reason = d!(dec.decode_vvec());
+                let reason_phrase = reason[..min(reason.len(), MAX_REASON_PHRASE_LEN)].to_vec();
                 Ok(Self::ConnectionClose {
-                    error_code: CloseError::from_type_bit(t, d!(dec.decode_varint())),
-                    frame_type: dv!(dec),
-                    reason_phrase: d!(dec.decode_vvec()).to_vec(), // TODO(mt) unnecessary copy
+                    error_code,
+                    frame_type,
+                    reason_phrase,
                 })
             }
             FRAME_TYPE_HANDSHAKE_DONE => Ok(Self::HandshakeDone),
@@ -731,6 +770,15 @@ impl Frame {
     }
 }
 
+/// Make a reason phrase from a CONNECTION_CLOSE frame safe to log.  Invalid UTF-8
+/// is replaced and control characters are escaped, so that a peer can't send
+/// terminal escape sequences.
+pub fn printable_reason_phrase(reason_phrase: &[u8]) -> String {
+    String::from_utf8_lossy(reason_phrase)
+        .escape_debug()
+        .to_string()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -991,6 +1039,40 @@ mod tests {
         enc_dec(&f, "1d80005678523403010203");
     }
 
+    #[test]
+    fn connection_close_long_reason() {
+        let f = Frame::ConnectionClose {
+            error_code: CloseError::Application(0x3fff_ffff_ffff_ffff),
+            frame_type: 0x1234,
+            reason_phrase: vec![0x41; 1000],
+        };
+        let mut enc = Encoder::default();
+        f.marshal(&mut enc);
+        Frame::Ping.marshal(&mut enc);
+
+        let mut dec = enc.as_decoder();
+        let f2 = Frame::decode(&mut dec).unwrap();
+        assert_eq!(
+            f2,
+            Frame::ConnectionClose {
+                error_code: CloseError::Application(0x3fff_ffff_ffff_ffff),
+                frame_type: 0x1234,
+                reason_phrase: vec![0x41; MAX_REASON_PHRASE_LEN],
+            }
+        );
+        // The rest of the reason phrase is skipped.
+        assert_eq!(Frame::decode(&mut dec).unwrap(), Frame::Ping);
+        assert_eq!(dec.remaining(), 0);
+    }
+
+    #[test]
+    fn hostile_reason_phrase() {
+        assert_eq!(
+            printable_reason_phrase(b"a\0b\x1b[31mred\xff"),
+            "a\\0b\\u{1b}[31mred\u{fffd}"
+        );
+    }
+
     #[test]
     fn test_compare() {
         let f1 = Frame::Padding;
@@ -1198,4 +1280,72 @@ mod tests {
             panic!("Wrong frame type");
         }
     }
+
+    #[test]
+    fn new_crypto_small() {
+        // A large offset takes 4 bytes, so there is no space for data.
+        assert!(Frame::new_crypto(1 << 20, &[0x43; 10], 3).is_none());
+        assert!(Frame::new_crypto(1 << 20, &[0x43; 10], 6).is_none());
+
+        let (f, used) = Frame::new_crypto(1 << 20, &[0x43; 10], 7).expect("a frame");
+        assert_eq!(used, 1);
+        let mut enc = Encoder::default();
+        f.marshal(&mut enc);
+        assert_eq!(enc.len(), 7);
+    }
+
+    #[test]
+    fn close_that_fits() {
+        let f = Frame::ConnectionClose {
+            error_code: CloseError::Transport(1),
+            frame_type: 0,
+            reason_phrase: vec![0x41; 100],
+        };
+        // This takes 105 bytes, with 2 for the length of the reason.  Taking 55 bytes
+        // off the reason means that its length only needs 1.
+        let short = f.close_that_fits(50).unwrap();
+        let mut enc = Encoder::default();
+        short.marshal(&mut enc);
+        assert_eq!(enc.len(), 49);
+        if let Frame::ConnectionClose { reason_phrase, .. } = short {
+            assert_eq!(reason_phrase, vec![0x41; 45]);
+        } else {
+            panic!("Wrong frame type");
+        }
+
+        assert_eq!(f.close_that_fits(200), Some(f.clone()));
+        assert!(f.close_that_fits(3).is_none());
+    }
+
+    #[test]
+    fn ack_eliciting() {
+        // One frame of every type, and whether a packet that carries it needs an ACK.
+        let frames = [
+            ("00", false),                                            // PADDING
+            ("01", true),                                             // PING
+            ("025234523502523601020304", false),                      // ACK
+            ("04523440777456", true),                                 // RESET_STREAM
+            ("053F4077", true),                                       // STOP_SENDING
+            ("060103010203", true),                                   // CRYPTO
+            ("0703123456", true),                                     // NEW_TOKEN
+            ("0a0503010203", true),                                   // STREAM
+            ("105234", true),                                         // MAX_DATA
+            ("11055234", true),                                       // MAX_STREAM_DATA
+            ("125234", true),                                         // MAX_STREAMS
+            ("145234", true),                                         // DATA_BLOCKED
+            ("15055234", true),                                       // STREAM_DATA_BLOCKED
+            ("165234", true),                                         // STREAMS_BLOCKED
+            ("1852340002010209090909090909090909090909090909", true), // NEW_CONNECTION_ID
+            ("195234", true),                                         // RETIRE_CONNECTION_ID
+            ("1a0909090909090909", true),                             // PATH_CHALLENGE
+            ("1b0909090909090909", true),                             // PATH_RESPONSE
+            ("1c80005678523403010203", false),                        // CONNECTION_CLOSE
+            ("1d80005678523403010203", false),                        // CONNECTION_CLOSE
+            ("1e", true),                                             // HANDSHAKE_DONE
+        ];
+        for (enc, eliciting) in &frames {
+            let f = Frame::decode(&mut Encoder::from_hex(enc).as_decoder()).unwrap();
+            assert_eq!(f.ack_eliciting(), *eliciting, "{:?}", f);
+        }
+    }
 }
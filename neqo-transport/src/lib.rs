@@ -19,27 +19,51 @@ mod flow_mgr;
 mod frame;
 mod pace;
 mod packet;
+mod params;
 mod path;
+#[cfg(feature = "qlog")]
 mod qlog;
 mod recovery;
 mod recv_stream;
+pub mod rng;
 mod send_stream;
+mod sender;
 pub mod server;
+mod stall;
+mod stateless_reset;
 mod stats;
 mod stream_id;
+mod summary;
 pub mod tparams;
 mod tracking;
 
-pub use self::cid::{ConnectionId, ConnectionIdManager};
-pub use self::connection::{Connection, FixedConnectionIdManager, Output, State, ZeroRttState};
-pub use self::events::{ConnectionEvent, ConnectionEvents};
+pub use self::cc::CongestionState;
+pub use self::cid::{ConnectionId, ConnectionIdDecoder, ConnectionIdManager, ConnectionIdRef};
+pub use self::connection::{
+    Connection, FixedConnectionIdManager, Output, State, ZeroRttState,
+    DEFAULT_AUTHENTICATION_TIMEOUT, DEFAULT_ZERO_RTT_SEND_LIMIT,
+};
+pub use self::crypto::{CryptoDxDirection, CryptoStates};
+pub use self::events::{ConnectionEvent, ConnectionEvents, EventMask};
 pub use self::frame::CloseError;
-pub use self::frame::StreamType;
-pub use self::stream_id::StreamId;
+pub use self::frame::{AckRange, Frame, StreamType};
+pub use self::packet::{DecryptedPacket, PacketNumber, PacketType, PublicPacket};
+pub use self::params::ConnectionParameters;
+pub use self::path::{PathInfo, PathStats, RecoveryStats};
+pub use self::send_stream::TransmissionPriority;
+pub use self::stall::DEFAULT_FLOW_CONTROL_STALL_TIMEOUT;
+pub use self::stats::{
+    CongestionStats, DatagramDisposition, DropReason, FlowControlStall, HandshakeStats, Milestones,
+    SentBytes, Stats,
+};
+pub use self::stream_id::{StreamId, StreamLimits};
+pub use self::summary::{HandshakeSummary, SpaceSummary, TimerKind};
 
 /// The supported version of the QUIC protocol.
 pub type Version = u32;
 pub const QUIC_VERSION: Version = 0xff00_0000 + 27;
+/// The version of neqo-transport.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const LOCAL_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30); // 30 second
 
@@ -58,6 +82,7 @@ pub enum Error {
     FinalSizeError,
     FrameEncodingError,
     TransportParameterError,
+    ConnectionIdLimitExceeded,
     ProtocolViolation,
     InvalidToken,
     ApplicationError,
@@ -67,6 +92,9 @@ pub enum Error {
 
     // All internal errors from here.
     AckedUnsentPacket,
+    /// The application didn't call `authenticated` in time, see
+    /// `Connection::set_authentication_timeout`.
+    AuthenticationTimeout,
     ConnectionState,
     DecodingFrame,
     DecryptError,
@@ -76,6 +104,8 @@ pub enum Error {
     InvalidInput,
     InvalidMigration,
     InvalidPacket,
+    /// A `ConnectionParameters` value can't be sent, for the reason given.
+    InvalidParameters(&'static str),
     InvalidResumptionToken,
     InvalidRetry,
     InvalidStreamId,
@@ -84,11 +114,24 @@ pub enum Error {
     // An attempt to update keys can be blocked if
     // a packet sent with the current keys hasn't been acknowledged.
     KeyUpdateBlocked,
+    /// A packet couldn't be decrypted with the keys for its key phase, which are
+    /// not the current keys.
+    WrongKeyPhase,
     NoMoreData,
     NotConnected,
     PacketNumberOverlap,
     PeerApplicationError(AppError),
     PeerError(TransportError),
+    /// The peer sent a stateless reset, which means that it has lost the state
+    /// for the connection.
+    StatelessReset,
+    /// The stream was reset locally, so nothing more can be sent on it.
+    StreamReset,
+    /// The peer sent STOP_SENDING with this error code, so the stream was reset.
+    StreamStopped(AppError),
+    /// No more streams of the requested type can be created on this connection,
+    /// see `Connection::set_stream_allowance`.
+    StreamsExhausted,
     TooMuchData,
     UnexpectedMessage,
     UnknownFrameType,
@@ -111,6 +154,7 @@ impl Error {
             Self::FinalSizeError => 6,
             Self::FrameEncodingError => 7,
             Self::TransportParameterError => 8,
+            Self::ConnectionIdLimitExceeded => 9,
             Self::ProtocolViolation => 10,
             Self::InvalidToken => 11,
             Self::ApplicationError => ERROR_APPLICATION_CLOSE,
@@ -128,6 +172,7 @@ impl From<neqo_crypto::Error> for Error {
     }
 }
 
+#[cfg(feature = "qlog")]
 impl From<::qlog::Error> for Error {
     fn from(_err: ::qlog::Error) -> Self {
         Self::QlogError
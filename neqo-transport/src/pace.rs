@@ -9,7 +9,7 @@
 
 use neqo_common::qtrace;
 
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::convert::TryFrom;
 use std::fmt::{Debug, Display};
 use std::time::{Duration, Instant};
@@ -57,21 +57,29 @@ impl Pacer {
     /// This returns a time, which could be in the past (this object doesn't know what
     /// the current time is).
     pub fn next(&self, rtt: Duration, cwnd: usize) -> Instant {
+        self.next_at_rate(rtt, cwnd * PACER_SPEEDUP)
+    }
+
+    /// Determine when the next packet will be available if credit accrues at a
+    /// fixed rate of `bytes` in every `period`.  Like `next`, this doesn't update state.
+    pub fn next_at_rate(&self, period: Duration, bytes: usize) -> Instant {
         if self.c >= self.p {
-            qtrace!([self], "next {}/{:?} no wait: {:?}", cwnd, rtt, self.t);
+            qtrace!([self], "next {}/{:?} no wait: {:?}", bytes, period, self.t);
             self.t
         } else {
-            // This is the inverse of the function in `spend`:
-            // self.t + rtt * (self.p - self.c) / (PACER_SPEEDUP * cwnd)
-            let r = rtt.as_nanos();
+            // This is the inverse of the function in `spend_at_rate`:
+            // self.t + period * (self.p - self.c) / bytes
+            let r = period.as_nanos();
             let d = r.saturating_mul(u128::try_from(self.p - self.c).unwrap());
-            let add = d / u128::try_from(cwnd * PACER_SPEEDUP).unwrap();
-            let dt = u64::try_from(add).map(Duration::from_nanos).unwrap_or(rtt);
+            let add = d / u128::try_from(bytes).unwrap();
+            let dt = u64::try_from(add)
+                .map(Duration::from_nanos)
+                .unwrap_or(period);
             qtrace!(
                 [self],
                 "next {}/{:?} wait {:?}: {:?}",
-                cwnd,
-                rtt,
+                bytes,
+                period,
                 dt,
                 self.t + dt
             );
@@ -84,22 +92,27 @@ impl Pacer {
     /// an estimate of the round trip time (`rtt`), the estimated congestion
     /// window (`cwnd`), and the number of bytes that were sent (`count`).
     pub fn spend(&mut self, now: Instant, rtt: Duration, cwnd: usize, count: usize) {
-        qtrace!([self], "spend {} over {}, {:?}", count, cwnd, rtt);
+        self.spend_at_rate(now, rtt, cwnd * PACER_SPEEDUP, count);
+    }
+
+    /// Spend credit that accrues at a fixed rate of `bytes` in every `period`.
+    pub fn spend_at_rate(&mut self, now: Instant, period: Duration, bytes: usize, count: usize) {
+        qtrace!([self], "spend {} at {}/{:?}", count, bytes, period);
         // Increase the capacity by:
-        //    `(now - self.t) * PACER_SPEEDUP * cwnd / rtt`
-        // That is, the elapsed fraction of the RTT times rate that data is added.
+        //    `(now - self.t) * bytes / period`
+        // That is, the elapsed fraction of the period times rate that data is added.
         let incr = now
             .saturating_duration_since(self.t)
             .as_nanos()
-            .saturating_mul(u128::try_from(cwnd * PACER_SPEEDUP).unwrap())
-            .checked_div(rtt.as_nanos())
+            .saturating_mul(u128::try_from(bytes).unwrap())
+            .checked_div(period.as_nanos())
             .map(|i| usize::try_from(i).ok())
             .flatten()
             .unwrap_or(self.m);
 
         // Add the capacity up to a limit of `self.m`, then subtract `count`.
         self.c = min(self.m, (self.c + incr).saturating_sub(count));
-        self.t = now;
+        self.t = max(self.t, now);
     }
 }
 
@@ -7,11 +7,12 @@
 // Encoding and decoding packets off the wire.
 use crate::cid::{ConnectionId, ConnectionIdDecoder, ConnectionIdRef, MAX_CONNECTION_ID_LEN};
 use crate::crypto::{CryptoDxState, CryptoStates};
+use crate::rng::random;
 use crate::tracking::PNSpace;
 use crate::{Error, Res, Version, QUIC_VERSION};
 
 use neqo_common::{hex, hex_with_len, qerror, qtrace, Decoder, Encoder};
-use neqo_crypto::{aead::Aead, hkdf, random, TLS_AES_128_GCM_SHA256, TLS_VERSION_1_3};
+use neqo_crypto::{aead::Aead, hkdf, TLS_AES_128_GCM_SHA256, TLS_VERSION_1_3};
 
 use std::cell::RefCell;
 use std::convert::TryFrom;
@@ -33,6 +34,10 @@ const PACKET_BIT_FIXED_QUIC: u8 = 0x40;
 const PACKET_HP_MASK_LONG: u8 = 0x0f;
 const PACKET_HP_MASK_SHORT: u8 = 0x1f;
 
+/// MIN_INITIAL_PACKET_SIZE is the smallest packet that can be used to establish
+/// a new connection across all QUIC versions this server supports.
+pub(crate) const MIN_INITIAL_PACKET_SIZE: usize = 1200;
+
 const SAMPLE_SIZE: usize = 16;
 const SAMPLE_OFFSET: usize = 4;
 
@@ -509,6 +514,21 @@ impl<'a> PublicPacket<'a> {
         self.token
     }
 
+    /// The versions listed in a Version Negotiation packet.
+    pub fn supported_versions(&self) -> Res<Vec<Version>> {
+        assert_eq!(self.packet_type, PacketType::VersionNegotiation);
+        let mut decoder = Decoder::new(&self.data[self.header_len..]);
+        let mut res = Vec::new();
+        while decoder.remaining() > 0 {
+            let version = Self::opt(decoder.decode_uint(4))?;
+            res.push(Version::try_from(version).unwrap());
+        }
+        if res.is_empty() {
+            return Err(Error::InvalidPacket);
+        }
+        Ok(res)
+    }
+
     fn decode_pn(expected: PacketNumber, pn: u64, w: usize) -> PacketNumber {
         let window = 1_u64 << (w * 8);
         let candidate = (expected & !(window - 1)) | pn;
@@ -528,6 +548,7 @@ impl<'a> PublicPacket<'a> {
     fn decrypt_header(
         &self,
         crypto: &mut CryptoDxState,
+        expected_pn: PacketNumber,
     ) -> Res<(bool, PacketNumber, Vec<u8>, &'a [u8])> {
         assert_ne!(self.packet_type, PacketType::Retry);
         assert_ne!(self.packet_type, PacketType::VersionNegotiation);
@@ -574,7 +595,7 @@ impl<'a> PublicPacket<'a> {
 
         let key_phase = self.packet_type == PacketType::Short
             && (first_byte & PACKET_BIT_KEY_PHASE) == PACKET_BIT_KEY_PHASE;
-        let pn = Self::decode_pn(crypto.next_pn(), pn_encoded, pn_len);
+        let pn = Self::decode_pn(expected_pn, pn_encoded, pn_len);
         Ok((
             key_phase,
             pn,
@@ -587,21 +608,32 @@ impl<'a> PublicPacket<'a> {
         let space = PNSpace::from(self.packet_type);
         // This has to work in two stages because we need to remove header protection
         // before picking the keys to use.
-        if let Some(rx) = crypto.rx_hp(space) {
+        let expected_pn = crypto.rx_next_pn(space);
+        if let (Some(rx), Some(expected_pn)) = (crypto.rx_hp(space), expected_pn) {
             // Note that this will dump early, which creates a side-channel.
             // This is OK in this case because we the only reason this can
             // fail is if the cryptographic module is bad or the packet is
             // too small (which is public information).
-            let (key_phase, pn, header, body) = self.decrypt_header(rx)?;
+            let (key_phase, pn, header, body) = self.decrypt_header(rx, expected_pn)?;
             qtrace!([rx], "decoded header: {:?}", header);
+            let other_phase = self.packet_type == PacketType::Short && key_phase != rx.key_phase();
             if let Some(rx) = crypto.rx(space, key_phase) {
-                let d = rx.decrypt(pn, &header, body)?;
+                let d = match rx.decrypt(pn, &header, body) {
+                    Ok(d) => d,
+                    Err(_) if other_phase => return Err(Error::WrongKeyPhase),
+                    Err(e) => return Err(e),
+                };
                 // If this is the first packet ever successfully decrypted
                 // using `rx`, make sure to initiate a key update.
                 if rx.needs_update() {
                     crypto.key_update_received(release_at)?;
                 }
-                crypto.check_pn_overlap()?;
+                // A packet number that doesn't fit is only a key phase problem if the
+                // packet used the other key phase.
+                crypto.check_pn_overlap().map_err(|e| match e {
+                    Error::PacketNumberOverlap if other_phase => Error::WrongKeyPhase,
+                    _ => e,
+                })?;
                 Ok(DecryptedPacket {
                     pt: self.packet_type,
                     pn,
@@ -963,6 +995,29 @@ mod tests {
         assert_eq!(&packet.dcid[..], SERVER_CID);
         assert!(packet.scid.is_some());
         assert_eq!(&packet.scid.unwrap()[..], CLIENT_CID);
+        assert_eq!(
+            packet.supported_versions().unwrap(),
+            vec![QUIC_VERSION, 0x0a0a_0a0a]
+        );
+    }
+
+    #[test]
+    fn parse_vn_bad_versions() {
+        // Drop the last byte, so that the last version is cut short.
+        let (packet, _) = PublicPacket::decode(
+            &SAMPLE_VN[..SAMPLE_VN.len() - 1],
+            &FixedConnectionIdManager::new(5),
+        )
+        .unwrap();
+        assert_eq!(packet.supported_versions(), Err(Error::NoMoreData));
+
+        // No versions at all.
+        let (packet, _) = PublicPacket::decode(
+            &SAMPLE_VN[..SAMPLE_VN.len() - 8],
+            &FixedConnectionIdManager::new(5),
+        )
+        .unwrap();
+        assert_eq!(packet.supported_versions(), Err(Error::InvalidPacket));
     }
 
     /// A Version Negotiation packet can have a long connection ID.
@@ -0,0 +1,307 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The transport parameters that a connection sends, chosen when the connection
+// is made.
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use crate::connection::{LOCAL_MAX_DATA, LOCAL_STREAM_LIMIT_BIDI, LOCAL_STREAM_LIMIT_UNI};
+use crate::recv_stream::RX_STREAM_DATA_WINDOW;
+use crate::stream_id::MAX_STREAMS;
+use crate::tparams::{self, TransportParameters};
+use crate::{Error, Res, LOCAL_IDLE_TIMEOUT};
+
+/// The largest value that a transport parameter can have.
+const MAX_VARINT: u64 = (1 << 62) - 1;
+/// The smallest `max_packet_size` that a peer can be asked to respect.
+const MIN_MAX_PACKET_SIZE: u64 = 1200;
+
+/// The local transport parameters for `Connection::new_client_with_params` and
+/// `Connection::new_server_with_params`.  The defaults are what `new_client` and
+/// `new_server` use.  Values that can't be sent are only rejected when a
+/// connection is made with them.
+///
+/// ```
+/// # use neqo_transport::ConnectionParameters;
+/// # use std::time::Duration;
+/// let params = ConnectionParameters::default()
+///     .max_data(1 << 24)
+///     .max_streams_bidi(100)
+///     .idle_timeout(Duration::from_secs(10));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionParameters {
+    max_data: u64,
+    max_stream_data_bidi_local: u64,
+    max_stream_data_bidi_remote: u64,
+    max_stream_data_uni: u64,
+    max_streams_bidi: u64,
+    max_streams_uni: u64,
+    idle_timeout: Duration,
+    max_packet_size: Option<u64>,
+}
+
+impl Default for ConnectionParameters {
+    fn default() -> Self {
+        Self {
+            max_data: LOCAL_MAX_DATA,
+            max_stream_data_bidi_local: RX_STREAM_DATA_WINDOW,
+            max_stream_data_bidi_remote: RX_STREAM_DATA_WINDOW,
+            max_stream_data_uni: RX_STREAM_DATA_WINDOW,
+            max_streams_bidi: LOCAL_STREAM_LIMIT_BIDI,
+            max_streams_uni: LOCAL_STREAM_LIMIT_UNI,
+            idle_timeout: LOCAL_IDLE_TIMEOUT,
+            max_packet_size: None,
+        }
+    }
+}
+
+impl ConnectionParameters {
+    /// The initial_max_data transport parameter: what the peer can send on all
+    /// streams together.
+    #[must_use]
+    pub fn max_data(mut self, max_data: u64) -> Self {
+        self.max_data = max_data;
+        self
+    }
+
+    /// The initial_max_stream_data_bidi_local transport parameter: what the peer
+    /// can send on each bidirectional stream that this endpoint opens.
+    #[must_use]
+    pub fn max_stream_data_bidi_local(mut self, max_stream_data: u64) -> Self {
+        self.max_stream_data_bidi_local = max_stream_data;
+        self
+    }
+
+    /// The initial_max_stream_data_bidi_remote transport parameter: what the peer
+    /// can send on each bidirectional stream that it opens.
+    #[must_use]
+    pub fn max_stream_data_bidi_remote(mut self, max_stream_data: u64) -> Self {
+        self.max_stream_data_bidi_remote = max_stream_data;
+        self
+    }
+
+    /// The initial_max_stream_data_uni transport parameter: what the peer can
+    /// send on each unidirectional stream.
+    #[must_use]
+    pub fn max_stream_data_uni(mut self, max_stream_data: u64) -> Self {
+        self.max_stream_data_uni = max_stream_data;
+        self
+    }
+
+    /// The initial_max_streams_bidi transport parameter: how many bidirectional
+    /// streams the peer can open at once.
+    #[must_use]
+    pub fn max_streams_bidi(mut self, max_streams: u64) -> Self {
+        self.max_streams_bidi = max_streams;
+        self
+    }
+
+    /// The initial_max_streams_uni transport parameter: how many unidirectional
+    /// streams the peer can open at once.
+    #[must_use]
+    pub fn max_streams_uni(mut self, max_streams: u64) -> Self {
+        self.max_streams_uni = max_streams;
+        self
+    }
+
+    /// The idle_timeout transport parameter, in milliseconds when it is sent.  The
+    /// connection uses the smaller of this and the value the peer advertises,
+    /// see `Connection::set_idle_timeout`.  This can't be zero.
+    #[must_use]
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// The max_packet_size transport parameter, which limits the size of the UDP
+    /// payloads that the peer sends.  This is not sent by default, and it can't be
+    /// less than 1200.
+    #[must_use]
+    pub fn max_packet_size(mut self, max_packet_size: u64) -> Self {
+        self.max_packet_size = Some(max_packet_size);
+        self
+    }
+
+    #[must_use]
+    pub fn get_max_data(&self) -> u64 {
+        self.max_data
+    }
+
+    #[must_use]
+    pub fn get_max_stream_data_bidi_local(&self) -> u64 {
+        self.max_stream_data_bidi_local
+    }
+
+    #[must_use]
+    pub fn get_max_stream_data_bidi_remote(&self) -> u64 {
+        self.max_stream_data_bidi_remote
+    }
+
+    #[must_use]
+    pub fn get_max_stream_data_uni(&self) -> u64 {
+        self.max_stream_data_uni
+    }
+
+    pub(crate) fn get_max_streams_bidi(&self) -> u64 {
+        self.max_streams_bidi
+    }
+
+    pub(crate) fn get_max_streams_uni(&self) -> u64 {
+        self.max_streams_uni
+    }
+
+    /// The idle timeout in whole milliseconds, or `None` if that can't be sent.
+    fn idle_timeout_ms(&self) -> Option<u64> {
+        u64::try_from(self.idle_timeout.as_millis())
+            .ok()
+            .filter(|&ms| ms > 0 && ms <= MAX_VARINT)
+    }
+
+    pub(crate) fn get_idle_timeout(&self) -> Duration {
+        Duration::from_millis(self.idle_timeout_ms().unwrap())
+    }
+
+    /// Check that every value can be sent.
+    pub(crate) fn validate(&self) -> Res<()> {
+        let flow_control = [
+            (self.max_data, "max_data is larger than 2^62-1"),
+            (
+                self.max_stream_data_bidi_local,
+                "max_stream_data_bidi_local is larger than 2^62-1",
+            ),
+            (
+                self.max_stream_data_bidi_remote,
+                "max_stream_data_bidi_remote is larger than 2^62-1",
+            ),
+            (
+                self.max_stream_data_uni,
+                "max_stream_data_uni is larger than 2^62-1",
+            ),
+        ];
+        if let Some((_, msg)) = flow_control.iter().find(|(v, _)| *v > MAX_VARINT) {
+            return Err(Error::InvalidParameters(msg));
+        }
+        if self.max_streams_bidi > MAX_STREAMS {
+            return Err(Error::InvalidParameters(
+                "max_streams_bidi is larger than 2^60",
+            ));
+        }
+        if self.max_streams_uni > MAX_STREAMS {
+            return Err(Error::InvalidParameters(
+                "max_streams_uni is larger than 2^60",
+            ));
+        }
+        if self.idle_timeout_ms().is_none() {
+            return Err(Error::InvalidParameters(
+                "idle_timeout is zero or too large to send",
+            ));
+        }
+        if self
+            .max_packet_size
+            .map_or(false, |v| v < MIN_MAX_PACKET_SIZE)
+        {
+            return Err(Error::InvalidParameters(
+                "max_packet_size is less than 1200",
+            ));
+        }
+        if self.max_packet_size.map_or(false, |v| v > MAX_VARINT) {
+            return Err(Error::InvalidParameters(
+                "max_packet_size is larger than 2^62-1",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Set the transport parameters, which `validate` has checked.
+    pub(crate) fn set_transport_parameters(&self, tps: &mut TransportParameters) {
+        tps.set_integer(
+            tparams::INITIAL_MAX_STREAM_DATA_BIDI_LOCAL,
+            self.max_stream_data_bidi_local,
+        );
+        tps.set_integer(
+            tparams::INITIAL_MAX_STREAM_DATA_BIDI_REMOTE,
+            self.max_stream_data_bidi_remote,
+        );
+        tps.set_integer(
+            tparams::INITIAL_MAX_STREAM_DATA_UNI,
+            self.max_stream_data_uni,
+        );
+        tps.set_integer(tparams::INITIAL_MAX_STREAMS_BIDI, self.max_streams_bidi);
+        tps.set_integer(tparams::INITIAL_MAX_STREAMS_UNI, self.max_streams_uni);
+        tps.set_integer(tparams::INITIAL_MAX_DATA, self.max_data);
+        tps.set_integer(tparams::IDLE_TIMEOUT, self.idle_timeout_ms().unwrap());
+        if let Some(max_packet_size) = self.max_packet_size {
+            tps.set_integer(tparams::MAX_PACKET_SIZE, max_packet_size);
+        }
+        tps.set_empty(tparams::DISABLE_MIGRATION);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid(params: &ConnectionParameters) -> &'static str {
+        match params.validate() {
+            Err(Error::InvalidParameters(msg)) => msg,
+            res => panic!("{:?} should be invalid, not {:?}", params, res),
+        }
+    }
+
+    #[test]
+    fn default_valid() {
+        ConnectionParameters::default().validate().unwrap();
+    }
+
+    #[test]
+    fn flow_control_too_large() {
+        let params = ConnectionParameters::default().max_data(MAX_VARINT);
+        params.validate().unwrap();
+        assert_eq!(
+            invalid(&params.max_stream_data_uni(MAX_VARINT + 1)),
+            "max_stream_data_uni is larger than 2^62-1"
+        );
+    }
+
+    #[test]
+    fn streams_too_many() {
+        let params = ConnectionParameters::default().max_streams_bidi(MAX_STREAMS);
+        params.validate().unwrap();
+        assert_eq!(
+            invalid(&params.max_streams_uni(MAX_STREAMS + 1)),
+            "max_streams_uni is larger than 2^60"
+        );
+    }
+
+    #[test]
+    fn idle_timeout_zero() {
+        let params = ConnectionParameters::default().idle_timeout(Duration::from_micros(999));
+        assert_eq!(
+            invalid(&params),
+            "idle_timeout is zero or too large to send"
+        );
+    }
+
+    #[test]
+    fn max_packet_size_small() {
+        let params = ConnectionParameters::default().max_packet_size(MIN_MAX_PACKET_SIZE);
+        params.validate().unwrap();
+        let mut tps = TransportParameters::default();
+        params.set_transport_parameters(&mut tps);
+        assert_eq!(
+            tps.get_integer(tparams::MAX_PACKET_SIZE),
+            MIN_MAX_PACKET_SIZE
+        );
+
+        assert_eq!(
+            invalid(&params.max_packet_size(MIN_MAX_PACKET_SIZE - 1)),
+            "max_packet_size is less than 1200"
+        );
+    }
+}
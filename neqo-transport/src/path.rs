@@ -4,11 +4,18 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::net::SocketAddr;
+use std::cmp::min;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
 
 use crate::cid::{ConnectionId, ConnectionIdRef};
+use crate::frame::Frame;
+use crate::packet::PacketBuilder;
+use crate::stats::AMPLIFICATION_FACTOR;
 
-use neqo_common::Datagram;
+use neqo_common::{Datagram, Decoder, Encoder};
+use neqo_crypto::random;
 
 /// This is the MTU that we assume when using IPv6.
 /// We use this size for Initial packets, so we don't need to worry about probing for support.
@@ -18,51 +25,262 @@ use neqo_common::Datagram;
 pub const PATH_MTU_V6: usize = 1337;
 /// The path MTU for IPv4 can be 20 bytes larger than for v6.
 pub const PATH_MTU_V4: usize = PATH_MTU_V6 + 20;
+/// QUIC needs a path to carry datagrams of at least this size.
+const MIN_PATH_MTU: usize = 1200;
+/// The largest path MTU that is taken from a previous connection, which is what
+/// fits in an Ethernet frame after the IPv4 and UDP headers.
+const MAX_MTU_HINT_V4: usize = 1472;
+/// The same, after the IPv6 and UDP headers.
+const MAX_MTU_HINT_V6: usize = 1452;
+/// How many times PATH_CHALLENGE is sent on a path before validating it is abandoned.
+/// Each is sent one PTO after the last, which makes the three PTOs that factor
+/// into the time allowed for path validation.
+pub const MAX_PATH_CHALLENGES: usize = 3;
+/// The space that PATH_CHALLENGE and PATH_RESPONSE take in a packet.
+pub const PATH_PROBE_FRAME_SIZE: usize = 9;
+
+/// Convert an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) to IPv4.  A dual-stack socket
+/// reports IPv4 peers in this form, so this makes the addresses it uses compare equal to
+/// those used by an IPv4 socket.
+#[must_use]
+pub fn normalize_address(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(v6) = addr {
+        let ip = v6.ip();
+        if ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] {
+            let o = ip.octets();
+            let v4 = Ipv4Addr::new(o[12], o[13], o[14], o[15]);
+            return SocketAddr::new(IpAddr::V4(v4), v6.port());
+        }
+    }
+    addr
+}
+
+/// Information about a path that can be used to seed a new connection to the same
+/// peer, see `Connection::path_info` and `Connection::set_path_hints`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PathInfo {
+    /// The round trip time estimate.
+    pub rtt: Duration,
+    /// The path MTU.
+    pub mtu: usize,
+    /// The congestion window.
+    pub cwnd: usize,
+}
+
+impl PathInfo {
+    /// Encode as three varints.  Values that don't fit are clamped, which makes
+    /// the decoded value smaller, but still larger than anything a path uses.
+    pub fn encode(&self, enc: &mut Encoder) {
+        let clamp = |v: u64| min(v, (1 << 62) - 1);
+        enc.encode_varint(clamp(
+            u64::try_from(self.rtt.as_micros()).unwrap_or(u64::max_value()),
+        ));
+        enc.encode_varint(clamp(u64::try_from(self.mtu).unwrap_or(u64::max_value())));
+        enc.encode_varint(clamp(u64::try_from(self.cwnd).unwrap_or(u64::max_value())));
+    }
+
+    pub fn decode(dec: &mut Decoder) -> Option<Self> {
+        let rtt = Duration::from_micros(dec.decode_varint()?);
+        let mtu = usize::try_from(dec.decode_varint()?).ok()?;
+        let cwnd = usize::try_from(dec.decode_varint()?).ok()?;
+        Some(Self { rtt, mtu, cwnd })
+    }
+}
+
+/// What loss recovery measured on a path.  A connection has one RTT estimate and
+/// congestion controller, which start again when it moves, so for a path that the
+/// connection moved away from, these are the values from when it moved.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RecoveryStats {
+    /// The smoothed round trip time.
+    pub rtt: Duration,
+    /// The lowest round trip time seen, or zero if there hasn't been a sample.
+    pub min_rtt: Duration,
+    /// The congestion window.
+    pub cwnd: usize,
+    /// Packets declared lost while the connection was on the path.
+    pub lost: usize,
+    /// Times that the PTO timer fired while the connection was on the path.
+    pub pto_count: usize,
+}
+
+/// What a connection knows about one of its paths, see `Connection::paths`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathStats {
+    /// Identifies the path for as long as the connection lasts.
+    pub id: u64,
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    pub mtu: usize,
+    /// Bytes in datagrams sent on the path.
+    pub bytes_sent: u64,
+    /// Bytes in datagrams received on the path, including any that were dropped.
+    pub bytes_received: u64,
+    /// Whether the connection is on this path, rather than having moved away from it.
+    pub current: bool,
+    pub recovery: RecoveryStats,
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Path {
+    id: u64,
     local: SocketAddr,
     remote: SocketAddr,
     local_cids: Vec<ConnectionId>,
     remote_cid: ConnectionId,
+    bytes_sent: u64,
+    bytes_received: u64,
+    /// Whether the peer has shown that it receives what is sent on this path.
+    validated: bool,
+    /// The data in the PATH_CHALLENGE that was sent on this path, until the
+    /// peer answers it.
+    challenge: Option<[u8; 8]>,
+    /// Whether PATH_CHALLENGE needs to be sent.
+    challenge_pending: bool,
+    /// How many times PATH_CHALLENGE has been sent.
+    challenges_sent: usize,
+    /// When to send PATH_CHALLENGE again, or give up.
+    challenge_timeout: Option<Instant>,
+    /// The data from a PATH_CHALLENGE that arrived on this path, which has to
+    /// be answered on the same path.
+    response: Option<[u8; 8]>,
+    /// The largest datagram that the peer accepts, from its transport parameters.
+    max_packet_size: usize,
+    /// The path MTU from a previous connection to the peer, which replaces the
+    /// default for the address family.
+    mtu_hint: Option<usize>,
+    /// The connection's lost packet and PTO counts when it moved to this path,
+    /// which are subtracted to get the counts for the path.
+    recovery_base: (usize, usize),
 }
 
 impl Path {
-    /// Create a path from addresses and connection IDs.
+    /// Create a path from addresses and connection IDs.  We chose the remote
+    /// address, so the path is treated as valid.
     pub fn new(
+        id: u64,
         local: SocketAddr,
         remote: SocketAddr,
         local_cid: ConnectionId,
         remote_cid: ConnectionId,
     ) -> Self {
         Self {
+            id,
             local,
             remote,
             local_cids: vec![local_cid],
             remote_cid,
+            bytes_sent: 0,
+            bytes_received: 0,
+            validated: true,
+            challenge: None,
+            challenge_pending: false,
+            challenges_sent: 0,
+            challenge_timeout: None,
+            response: None,
+            max_packet_size: usize::MAX,
+            mtu_hint: None,
+            recovery_base: (0, 0),
         }
     }
 
-    /// Create a path based on a received packet.
-    pub fn from_datagram(d: &Datagram, remote_cid: ConnectionId) -> Self {
+    /// Create a path based on a received packet, which is counted as received on it.
+    /// The path isn't valid until the peer shows that it can receive packets on it.
+    pub fn from_datagram(id: u64, d: &Datagram, remote_cid: ConnectionId) -> Self {
         Self {
+            id,
             local: d.destination(),
             remote: d.source(),
             local_cids: Vec::new(),
             remote_cid,
+            bytes_sent: 0,
+            bytes_received: d.len() as u64,
+            validated: false,
+            challenge: None,
+            challenge_pending: false,
+            challenges_sent: 0,
+            challenge_timeout: None,
+            response: None,
+            max_packet_size: usize::MAX,
+            mtu_hint: None,
+            recovery_base: (0, 0),
+        }
+    }
+
+    /// Create a path to where `d` came from, which uses the same connection IDs as
+    /// this one.  This is how a server sees a client that has moved.
+    pub fn probe_from_datagram(&self, id: u64, d: &Datagram) -> Self {
+        Self {
+            local_cids: self.local_cids.clone(),
+            max_packet_size: self.max_packet_size,
+            ..Self::from_datagram(id, d, self.remote_cid.clone())
+        }
+    }
+
+    /// Create a path that sends from `local` to the same peer, using the same
+    /// connection IDs.  This is how a client moves.  The peer's address is known,
+    /// so sending on this path isn't limited, but the path still has to be
+    /// validated before the connection moves to it.
+    pub fn probe_from_local(&self, id: u64, local: SocketAddr) -> Self {
+        Self {
+            id,
+            local,
+            remote: self.remote,
+            local_cids: self.local_cids.clone(),
+            remote_cid: self.remote_cid.clone(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            validated: true,
+            challenge: None,
+            challenge_pending: false,
+            challenges_sent: 0,
+            challenge_timeout: None,
+            response: None,
+            max_packet_size: self.max_packet_size,
+            mtu_hint: None,
+            recovery_base: (0, 0),
         }
     }
 
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Whether the datagram was received on this path.  Addresses are compared after
+    /// normalization, so that a peer isn't seen to move to a new path when a dual-stack
+    /// socket is used at either end.
     pub fn received_on(&self, d: &Datagram) -> bool {
-        self.local == d.destination() && self.remote == d.source()
+        normalize_address(self.local) == normalize_address(d.destination())
+            && normalize_address(self.remote) == normalize_address(d.source())
     }
 
+    /// The largest datagram that can be sent on this path.
     pub fn mtu(&self) -> usize {
-        if self.local.is_ipv4() {
+        // The local address of a dual-stack socket is IPv6, even when it sends IPv4.
+        let mtu = if normalize_address(self.remote).is_ipv4() {
             PATH_MTU_V4
         } else {
             PATH_MTU_V6 // IPv6
-        }
+        };
+        min(self.mtu_hint.unwrap_or(mtu), self.max_packet_size)
+    }
+
+    /// Use the path MTU from a previous connection to the same peer.  This is
+    /// kept between the smallest MTU that QUIC allows and what fits in an Ethernet
+    /// frame, so that a bad hint can't make the path unusable.  The hint only
+    /// applies to this path: a new path starts with the default.
+    pub fn set_mtu_hint(&mut self, mtu: usize) {
+        let limit = if normalize_address(self.remote).is_ipv4() {
+            MAX_MTU_HINT_V4
+        } else {
+            MAX_MTU_HINT_V6
+        };
+        self.mtu_hint = Some(min(mtu, limit).max(MIN_PATH_MTU));
+    }
+
+    /// Limit datagrams to what the peer says it accepts.
+    pub fn set_max_packet_size(&mut self, max_packet_size: usize) {
+        self.max_packet_size = max_packet_size;
     }
 
     /// Add a connection ID to the local set.
@@ -70,11 +288,6 @@ impl Path {
         self.local_cids.push(cid);
     }
 
-    /// Determine if the given connection ID is valid.
-    pub fn valid_local_cid(&self, cid: &ConnectionIdRef) -> bool {
-        self.local_cids.iter().any(|c| c == cid)
-    }
-
     /// Get the first local connection ID.
     pub fn local_cid(&self) -> &ConnectionId {
         self.local_cids.first().as_ref().unwrap()
@@ -90,9 +303,62 @@ impl Path {
         &self.remote_cid
     }
 
-    /// Make a datagram.
-    pub fn datagram<V: Into<Vec<u8>>>(&self, payload: V) -> Datagram {
-        Datagram::new(self.local, self.remote, payload)
+    /// Make a datagram, and count it as sent on this path.
+    pub fn datagram<V: Into<Vec<u8>>>(&mut self, payload: V) -> Datagram {
+        let d = Datagram::new(self.local, self.remote, payload);
+        debug_assert!(d.len() <= self.mtu(), "datagram exceeds the path MTU");
+        self.bytes_sent += d.len() as u64;
+        d
+    }
+
+    /// Count a datagram that was received on this path.
+    pub fn received(&mut self, len: usize) {
+        self.bytes_received += len as u64;
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Count lost packets and PTOs on this path from the connection's totals,
+    /// which is done when the connection moves to it.
+    pub fn set_recovery_base(&mut self, lost: usize, pto_count: usize) {
+        self.recovery_base = (lost, pto_count);
+    }
+
+    /// The lost packet and PTO counts for this path, given the connection's totals.
+    pub fn recovery_counts(&self, lost: usize, pto_count: usize) -> (usize, usize) {
+        (
+            lost.saturating_sub(self.recovery_base.0),
+            pto_count.saturating_sub(self.recovery_base.1),
+        )
+    }
+
+    /// Mark the path as valid, so that sending on it isn't limited any more.
+    pub fn set_valid(&mut self) {
+        self.validated = true;
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.validated
+    }
+
+    /// How many more bytes can be sent on this path.  Until the path is valid,
+    /// only `AMPLIFICATION_FACTOR` times what was received on it can be sent, so
+    /// that we can't be used to send a lot to an address that didn't ask for it.
+    pub fn amplification_limit(&self) -> usize {
+        if self.validated {
+            return usize::MAX;
+        }
+        let limit = self
+            .bytes_received
+            .saturating_mul(AMPLIFICATION_FACTOR as u64)
+            .saturating_sub(self.bytes_sent);
+        usize::try_from(limit).unwrap_or(usize::MAX)
     }
 
     /// Get local address as `SocketAddr`
@@ -104,4 +370,166 @@ impl Path {
     pub fn remote_address(&self) -> &SocketAddr {
         &self.remote
     }
+
+    /// Start validating this path, which sends a PATH_CHALLENGE with new data.
+    pub fn challenge(&mut self) {
+        let mut data = [0; 8];
+        data.copy_from_slice(&random(8));
+        self.challenge = Some(data);
+        self.challenge_pending = true;
+        self.challenges_sent = 0;
+        self.challenge_timeout = None;
+    }
+
+    /// Whether `data` from a PATH_RESPONSE answers the challenge that was sent
+    /// on this path.
+    pub fn is_response(&self, data: &[u8; 8]) -> bool {
+        self.challenge.as_ref() == Some(data)
+    }
+
+    /// Answer a PATH_CHALLENGE that arrived on this path.  Only the latest is
+    /// answered.
+    pub fn respond(&mut self, data: [u8; 8]) {
+        self.response = Some(data);
+    }
+
+    /// Whether there is a PATH_CHALLENGE or PATH_RESPONSE to send on this path.
+    pub fn has_probe_frames(&self) -> bool {
+        self.challenge_pending || self.response.is_some()
+    }
+
+    /// Add PATH_RESPONSE and PATH_CHALLENGE to `builder`, as needed.  If a challenge
+    /// is sent, it is sent again at `retry` unless the path is validated first.
+    pub fn write_probe_frames(&mut self, builder: &mut PacketBuilder, retry: Instant) {
+        if let Some(data) = self.response.take() {
+            Frame::PathResponse { data }.marshal(builder);
+        }
+        if self.challenge_pending {
+            if let Some(data) = self.challenge {
+                Frame::PathChallenge { data }.marshal(builder);
+                self.challenges_sent += 1;
+                self.challenge_timeout = Some(retry);
+            }
+            self.challenge_pending = false;
+        }
+    }
+
+    /// When the PATH_CHALLENGE that was last sent is overdue.
+    pub fn challenge_timeout(&self) -> Option<Instant> {
+        self.challenge_timeout
+    }
+
+    /// Handle the challenge timeout expiring, which means sending PATH_CHALLENGE
+    /// again.  This returns false if enough have been sent that validation failed.
+    pub fn challenge_expired(&mut self) -> bool {
+        self.challenge_timeout = None;
+        if self.challenges_sent >= MAX_PATH_CHALLENGES {
+            return false;
+        }
+        self.challenge_pending = true;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_address, Path, PATH_MTU_V4, PATH_MTU_V6};
+    use crate::cid::ConnectionId;
+    use crate::stats::AMPLIFICATION_FACTOR;
+    use neqo_common::Datagram;
+    use std::net::SocketAddr;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(
+            normalize_address(addr("[::ffff:192.0.2.1]:443")),
+            addr("192.0.2.1:443")
+        );
+        for unchanged in &["192.0.2.1:443", "[2001:db8::1]:443", "[::192.0.2.1]:443"] {
+            assert_eq!(normalize_address(addr(unchanged)), addr(unchanged));
+        }
+    }
+
+    #[test]
+    fn mapped_path() {
+        let cid = ConnectionId::from(&[1, 2, 3][..]);
+        let path = Path::new(
+            0,
+            addr("[::]:0"),
+            addr("[::ffff:192.0.2.1]:443"),
+            cid.clone(),
+            cid,
+        );
+        assert_eq!(path.mtu(), PATH_MTU_V4);
+        let d = Datagram::new(addr("192.0.2.1:443"), addr("[::]:0"), &[0_u8][..]);
+        assert!(path.received_on(&d));
+        let d = Datagram::new(addr("192.0.2.2:443"), addr("[::]:0"), &[0_u8][..]);
+        assert!(!path.received_on(&d));
+    }
+
+    #[test]
+    fn v6_path() {
+        let cid = ConnectionId::from(&[1, 2, 3][..]);
+        let path = Path::new(
+            0,
+            addr("[::1]:1234"),
+            addr("[2001:db8::1]:443"),
+            cid.clone(),
+            cid,
+        );
+        assert_eq!(path.mtu(), PATH_MTU_V6);
+    }
+
+    #[test]
+    fn mtu_hint() {
+        let cid = ConnectionId::from(&[1, 2, 3][..]);
+        let mut path = Path::new(
+            0,
+            addr("[::1]:1234"),
+            addr("[2001:db8::1]:443"),
+            cid.clone(),
+            cid,
+        );
+        path.set_mtu_hint(1400);
+        assert_eq!(path.mtu(), 1400);
+        path.set_mtu_hint(9000);
+        assert_eq!(path.mtu(), 1452);
+        path.set_mtu_hint(500);
+        assert_eq!(path.mtu(), 1200);
+
+        // The peer's limit still applies, and a new path doesn't take the hint.
+        path.set_mtu_hint(1400);
+        path.set_max_packet_size(1300);
+        assert_eq!(path.mtu(), 1300);
+        let probe = path.probe_from_local(1, addr("[::1]:1235"));
+        assert_eq!(probe.mtu(), 1300);
+        path.set_max_packet_size(usize::MAX);
+        let probe = path.probe_from_local(1, addr("[::1]:1235"));
+        assert_eq!(probe.mtu(), PATH_MTU_V6);
+    }
+
+    #[test]
+    fn amplification_limit() {
+        let cid = ConnectionId::from(&[1, 2, 3][..]);
+        let d = Datagram::new(addr("192.0.2.1:443"), addr("[::]:0"), vec![0; 100]);
+        let mut path = Path::from_datagram(0, &d, cid);
+        assert!(!path.is_valid());
+        assert_eq!(path.amplification_limit(), 100 * AMPLIFICATION_FACTOR);
+
+        let _ = path.datagram(vec![0; 250]);
+        assert_eq!(path.amplification_limit(), 50);
+        let _ = path.datagram(vec![0; 100]);
+        assert_eq!(path.amplification_limit(), 0);
+
+        // Receiving more allows more to be sent.
+        path.received(100);
+        assert_eq!(path.amplification_limit(), 250);
+
+        path.set_valid();
+        assert_eq!(path.amplification_limit(), usize::MAX);
+    }
 }
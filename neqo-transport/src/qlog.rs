@@ -8,13 +8,17 @@
 
 use std::string::String;
 
-use qlog::{self, event::Event, PacketHeader, QuicFrame};
+use qlog::{
+    self, event::Event, EventCategory, EventData, EventType, GenericEventType, PacketHeader,
+    QuicFrame,
+};
 
 use neqo_common::{hex, qinfo, qlog::NeqoQlog, Decoder};
 
 use crate::frame::{self, Frame};
 use crate::packet::{DecryptedPacket, PacketNumber, PacketType};
-use crate::path::Path;
+use crate::path::{Path, RecoveryStats};
+use crate::stats::{FlowControlStall, HandshakeStats};
 use crate::tparams::{self, TransportParametersHandler};
 use crate::{Res, QUIC_VERSION};
 
@@ -52,8 +56,7 @@ pub fn connection_tparams_set(
             Some(remote.get_integer(tparams::MAX_PACKET_SIZE)),
             Some(remote.get_integer(tparams::ACK_DELAY_EXPONENT)),
             Some(remote.get_integer(tparams::MAX_ACK_DELAY)),
-            // TODO(hawkinsw@obs.cr): We do not yet handle ACTIVE_CONNECTION_ID_LIMIT in tparams yet.
-            None,
+            Some(remote.get_integer(tparams::ACTIVE_CONNECTION_ID_LIMIT)),
             Some(format!("{}", remote.get_integer(tparams::INITIAL_MAX_DATA))),
             Some(format!(
                 "{}",
@@ -150,6 +153,125 @@ pub fn packet_received(qlog: &mut Option<NeqoQlog>, payload: &DecryptedPacket) -
     Ok(())
 }
 
+/// There is no qlog event for this, so it is a warning with the details.
+pub fn flow_control_stalled(qlog: &mut Option<NeqoQlog>, stall: &FlowControlStall) -> Res<()> {
+    if let Some(qlog) = qlog {
+        qlog.stream().add_event(Event {
+            category: EventCategory::Warning,
+            ty: EventType::GenericEventType(GenericEventType::InternalWarning),
+            data: EventData::InternalWarning {
+                code: None,
+                description: Some(format!("flow control stalled: {}", stall)),
+            },
+        })?;
+    }
+    Ok(())
+}
+
+/// Datagrams that the local socket dropped are a warning, because they look
+/// like loss on the network but the fix is a larger receive buffer.
+pub fn local_drops(qlog: &mut Option<NeqoQlog>, count: usize, total: usize) -> Res<()> {
+    if let Some(qlog) = qlog {
+        qlog.stream().add_event(Event {
+            category: EventCategory::Warning,
+            ty: EventType::GenericEventType(GenericEventType::InternalWarning),
+            data: EventData::InternalWarning {
+                code: None,
+                description: Some(format!(
+                    "socket receive buffer dropped {} datagrams ({} in total)",
+                    count, total
+                )),
+            },
+        })?;
+    }
+    Ok(())
+}
+
+/// There is no qlog event for the size of the handshake flights, so it is a message,
+/// or a warning if the server's flight was held back by the amplification limit.
+pub fn handshake_flights(qlog: &mut Option<NeqoQlog>, hs: &HandshakeStats) -> Res<()> {
+    if let Some(qlog) = qlog {
+        let event = if hs.amplification_limited() {
+            Event {
+                category: EventCategory::Warning,
+                ty: EventType::GenericEventType(GenericEventType::InternalWarning),
+                data: EventData::InternalWarning {
+                    code: None,
+                    description: Some(format!("handshake {}", hs)),
+                },
+            }
+        } else {
+            Event {
+                category: EventCategory::Transport,
+                ty: EventType::GenericEventType(GenericEventType::Message),
+                data: EventData::Message {
+                    message: format!("handshake {}", hs),
+                },
+            }
+        };
+        qlog.stream().add_event(event)?;
+    }
+    Ok(())
+}
+
+/// There is no qlog event for reaching a milestone, so it is a message with the name.
+pub fn milestone(qlog: &mut Option<NeqoQlog>, name: &str) -> Res<()> {
+    if let Some(qlog) = qlog {
+        qlog.stream().add_event(Event {
+            category: EventCategory::Transport,
+            ty: EventType::GenericEventType(GenericEventType::Message),
+            data: EventData::Message {
+                message: format!("milestone {}", name),
+            },
+        })?;
+    }
+    Ok(())
+}
+
+/// There is no qlog event for moving to a new path, so it is a message that gives
+/// the id that `Connection::paths` uses for the path, and its addresses.
+pub fn path_moved(qlog: &mut Option<NeqoQlog>, path: &Path) -> Res<()> {
+    if let Some(qlog) = qlog {
+        qlog.stream().add_event(Event {
+            category: EventCategory::Connectivity,
+            ty: EventType::GenericEventType(GenericEventType::Message),
+            data: EventData::Message {
+                message: format!(
+                    "path {} {} -> {}",
+                    path.id(),
+                    path.local_address(),
+                    path.remote_address()
+                ),
+            },
+        })?;
+    }
+    Ok(())
+}
+
+/// What loss recovery measured on the path with id `path_id`, written when the
+/// connection moves away from it.  The events in this version of qlog have no
+/// field for a path, so this is also a message, like `path_moved`.
+pub fn path_metrics(qlog: &mut Option<NeqoQlog>, path_id: u64, stats: &RecoveryStats) -> Res<()> {
+    if let Some(qlog) = qlog {
+        qlog.stream().add_event(Event {
+            category: EventCategory::Recovery,
+            ty: EventType::GenericEventType(GenericEventType::Message),
+            data: EventData::Message {
+                message: format!(
+                    "path {} smoothed_rtt {} min_rtt {} congestion_window {} lost {} pto_count {}",
+                    path_id,
+                    stats.rtt.as_micros(),
+                    stats.min_rtt.as_micros(),
+                    stats.cwnd,
+                    stats.lost,
+                    stats.pto_count
+                ),
+            },
+        })?;
+    }
+    Ok(())
+}
+
 fn connection_started(qlog: &mut Option<NeqoQlog>, path: &Path) -> Res<()> {
     if let Some(qlog) = qlog {
         qlog.stream().add_event(Event::connection_started(
@@ -16,10 +16,13 @@ use smallvec::{smallvec, SmallVec};
 
 use neqo_common::{qdebug, qinfo, qtrace, qwarn};
 
-use crate::cc::CongestionControl;
+use crate::cc::CongestionState;
+use crate::cid::ConnectionIdEntry;
 use crate::crypto::CryptoRecoveryToken;
 use crate::flow_mgr::FlowControlRecoveryToken;
 use crate::send_stream::StreamRecoveryToken;
+use crate::sender::PacketSender;
+use crate::stats::CongestionStats;
 use crate::tracking::{AckToken, PNSpace, SentPacket};
 use crate::LOCAL_IDLE_TIMEOUT;
 
@@ -35,6 +38,10 @@ pub(crate) const ACK_ONLY_SIZE_LIMIT: usize = 256;
 /// The number of packets we send on a PTO.
 /// And the number to declare lost when the PTO timer is hit.
 pub const PTO_PACKET_COUNT: usize = 2;
+/// If a timer is this late, the host was probably suspended.  Rather than
+/// deciding that everything that was sent before that is lost, the times
+/// for packets are moved forward so that the timer only just fired.
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Clone)]
 #[allow(clippy::module_name_repetitions)]
@@ -44,6 +51,8 @@ pub enum RecoveryToken {
     Crypto(CryptoRecoveryToken),
     Flow(FlowControlRecoveryToken),
     HandshakeDone,
+    NewConnectionId(ConnectionIdEntry),
+    RetireConnectionId(u64),
 }
 
 #[derive(Debug, Default)]
@@ -120,10 +129,11 @@ impl SendProfile {
         }
     }
 
-    pub fn new_paced() -> Self {
-        // When pacing, we still allow ACK frames to be sent.
+    /// When pacing, we still allow ACK frames to be sent.  Handshake packets are
+    /// not paced, so they can use up to `limit`.
+    pub fn new_paced(limit: usize) -> Self {
         Self {
-            limit: ACK_ONLY_SIZE_LIMIT - 1,
+            limit: max(ACK_ONLY_SIZE_LIMIT - 1, limit),
             pto: None,
             paced: true,
         }
@@ -144,10 +154,13 @@ impl SendProfile {
 
     /// Determine whether an ACK-only packet should be sent for the given packet
     /// number space.
-    /// Send only ACKs either: when the space available is too small, or when a PTO
-    /// exists for a later packet number space (which could use extra space for data).
+    /// Send only ACKs either: when the space available is too small, when a PTO
+    /// exists for a later packet number space (which could use extra space for data),
+    /// or when application data is paced.
     pub fn ack_only(&self, space: PNSpace) -> bool {
-        self.limit < ACK_ONLY_SIZE_LIMIT || self.pto.map_or(false, |sp| space < sp)
+        self.limit < ACK_ONLY_SIZE_LIMIT
+            || self.pto.map_or(false, |sp| space < sp)
+            || (self.paced && space == PNSpace::ApplicationData)
     }
 
     pub fn paced(&self) -> bool {
@@ -209,6 +222,12 @@ impl LossRecoverySpace {
         self.in_flight_outstanding > 0
     }
 
+    /// The number of packets that are neither acknowledged nor declared lost.
+    #[must_use]
+    pub fn unacked(&self) -> usize {
+        self.sent_packets.len()
+    }
+
     pub fn pto_packets(&mut self, count: usize) -> impl Iterator<Item = &SentPacket> {
         self.sent_packets
             .iter_mut()
@@ -244,7 +263,7 @@ impl LossRecoverySpace {
 
     pub fn remove_packet(&mut self, pn: u64) -> Option<SentPacket> {
         if let Some(sent) = self.sent_packets.remove(&pn) {
-            if sent.cc_in_flight() {
+            if sent.ack_eliciting() && sent.cc_in_flight() {
                 debug_assert!(self.in_flight_outstanding > 0);
                 self.in_flight_outstanding -= 1;
             }
@@ -275,6 +294,21 @@ impl LossRecoverySpace {
         )
     }
 
+    /// Move all of the times that are tracked for this space `by` later.
+    fn suspended(&mut self, by: Duration) {
+        for t in self
+            .largest_acked_sent_time
+            .iter_mut()
+            .chain(self.pto_base_time.iter_mut())
+            .chain(self.first_ooo_time.iter_mut())
+        {
+            *t += by;
+        }
+        for p in self.sent_packets.values_mut() {
+            p.suspended(by);
+        }
+    }
+
     /// Remove all tracked packets from the space.
     /// This is called by a client when 0-RTT packets are dropped, when a Retry is received
     /// and when keys are dropped.
@@ -292,7 +326,9 @@ impl LossRecoverySpace {
         lost_packets: &mut Vec<SentPacket>,
     ) {
         // Packets sent before this time are deemed lost.
-        let lost_deadline = now - loss_delay;
+        // This is `None` if `loss_delay` is before any time that can be represented,
+        // in which case nothing was sent early enough.
+        let lost_deadline = now.checked_sub(loss_delay);
         qtrace!(
             "detect lost {}: now={:?} delay={:?} deadline={:?}",
             self.space,
@@ -316,7 +352,7 @@ impl LossRecoverySpace {
             // BTreeMap iterates in order of ascending PN
             .take_while(|(&k, _)| Some(k) < largest_acked)
         {
-            if packet.time_sent <= lost_deadline {
+            if lost_deadline.map_or(false, |d| packet.time_sent <= d) {
                 qdebug!(
                     "lost={}, time sent {:?} is before lost_deadline {:?}",
                     pn,
@@ -452,7 +488,11 @@ impl PtoState {
 pub(crate) struct LossRecovery {
     pto_state: Option<PtoState>,
     rtt_vals: RttVals,
-    cc: CongestionControl,
+    cc: PacketSender,
+    /// The number of packets that have been declared lost.
+    packets_lost: usize,
+    /// The number of times that the PTO timer has fired.
+    pto_count: usize,
 
     spaces: LossRecoverySpaces,
 }
@@ -467,30 +507,92 @@ impl LossRecovery {
                 ..RttVals::default()
             },
             pto_state: None,
-            cc: CongestionControl::default(),
+            cc: PacketSender::default(),
+            packets_lost: 0,
+            pto_count: 0,
             spaces: LossRecoverySpaces::new(),
         }
     }
 
-    #[cfg(test)]
+    #[must_use]
     pub fn cwnd(&self) -> usize {
         self.cc.cwnd()
     }
 
+    #[must_use]
+    pub fn congestion_state(&self) -> CongestionState {
+        self.cc.state()
+    }
+
+    pub fn jump_start(&mut self, cwnd: usize) {
+        self.cc.jump_start(cwnd);
+    }
+
     #[cfg(test)]
     pub fn ssthresh(&self) -> usize {
         self.cc.ssthresh()
     }
 
+    #[must_use]
+    pub fn congestion_stats(&self) -> CongestionStats {
+        self.cc.stats()
+    }
+
+    /// The number of packets that have been declared lost.  Packets that are sent
+    /// again because of a PTO or a Retry don't count.
+    #[must_use]
+    pub fn packets_lost(&self) -> usize {
+        self.packets_lost
+    }
+
     pub fn rtt(&self) -> Duration {
         self.rtt_vals.rtt()
     }
 
+    pub fn rttvar(&self) -> Duration {
+        self.rtt_vals.rttvar
+    }
+
+    /// The lowest RTT that has been seen, or `None` before the first sample.
+    #[must_use]
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.rtt_vals.smoothed_rtt.map(|_| self.rtt_vals.min_rtt)
+    }
+
+    /// Use the `max_ack_delay` transport parameter from the peer to limit the
+    /// ACK delay that is taken from each RTT sample and to work out the PTO.
+    pub fn set_peer_max_ack_delay(&mut self, max_ack_delay: Duration) {
+        self.rtt_vals.max_ack_delay = max_ack_delay;
+    }
+
+    /// The number of times that the PTO timer has fired.
+    #[must_use]
+    pub fn pto_count(&self) -> usize {
+        self.pto_count
+    }
+
+    /// Forget what is known about the path after a migration.
+    pub fn migrate(&mut self) {
+        self.rtt_vals = RttVals {
+            min_rtt: Duration::from_secs(u64::max_value()),
+            max_ack_delay: self.rtt_vals.max_ack_delay,
+            latest_rtt: INITIAL_RTT,
+            ..RttVals::default()
+        };
+        self.cc.restart();
+    }
+
     pub fn set_initial_rtt(&mut self, value: Duration) {
         debug_assert!(self.rtt_vals.smoothed_rtt.is_none());
         self.rtt_vals.latest_rtt = value
     }
 
+    /// The number of unacknowledged packets in `space`, or `None` if it has been discarded.
+    #[must_use]
+    pub fn unacked_packets(&self, space: PNSpace) -> Option<usize> {
+        self.spaces.get(space).map(LossRecoverySpace::unacked)
+    }
+
     pub fn cwnd_avail(&self) -> usize {
         self.cc.cwnd_avail()
     }
@@ -549,6 +651,7 @@ impl LossRecovery {
             pn_space,
             largest_acked
         );
+        let suspended = self.check_suspend(now);
 
         let space = self
             .spaces
@@ -569,9 +672,20 @@ impl LossRecovery {
             // packet was ack-eliciting, update the RTT. (-recovery 5.1)
             let largest_acked_pkt = acked_packets.last().expect("must be there");
             space.largest_acked_sent_time = Some(largest_acked_pkt.time_sent);
-            if any_ack_eliciting {
-                let latest_rtt = now - largest_acked_pkt.time_sent;
-                self.rtt_vals.update_rtt(latest_rtt, ack_delay);
+            // The time spent suspended says nothing about the path, so skip this sample
+            // if the packet was sent before the host was suspended.
+            // A time before the packet was sent says nothing either.
+            if any_ack_eliciting && !suspended {
+                if let Some(latest_rtt) = now.checked_duration_since(largest_acked_pkt.time_sent) {
+                    // The peer doesn't delay acknowledgments of Initial and Handshake
+                    // packets, so ignore any delay it reports for them. (-recovery 5.3)
+                    let ack_delay = if pn_space == PNSpace::ApplicationData {
+                        ack_delay
+                    } else {
+                        Duration::from_millis(0)
+                    };
+                    self.rtt_vals.update_rtt(latest_rtt, ack_delay);
+                }
             }
         }
         self.cc.on_packets_acked(&acked_packets);
@@ -583,6 +697,7 @@ impl LossRecovery {
             loss_delay,
             &mut lost_packets,
         );
+        self.packets_lost += lost_packets.len();
         // TODO Process ECN information if present.
         self.cc.on_packets_lost(
             now,
@@ -714,6 +829,7 @@ impl LossRecovery {
         // This has to happen outside the loop. Increasing the PTO count here causes the
         // pto_time to increase which might cause PTO for later packet number spaces to not fire.
         if let Some(pn_space) = pto_space {
+            self.pto_count += 1;
             if let Some(st) = &mut self.pto_state {
                 st.pto(pn_space);
             } else {
@@ -722,8 +838,30 @@ impl LossRecovery {
         }
     }
 
+    /// Check whether the loss recovery timer is `SUSPEND_THRESHOLD` late, which
+    /// happens when the host is suspended (or the caller's clock jumps forward).
+    /// If it is, move all of the times that are tracked forward by the amount the
+    /// timer is late, so that the timer fires just once when `now` is processed.
+    /// Returns `true` if that happened.
+    fn check_suspend(&mut self, now: Instant) -> bool {
+        let late = match self.next_timeout() {
+            Some(t) => now.saturating_duration_since(t),
+            None => return false,
+        };
+        if late < SUSPEND_THRESHOLD {
+            return false;
+        }
+        qinfo!([self], "timer is {:?} late, assuming suspend", late);
+        for space in self.spaces.iter_mut() {
+            space.suspended(late);
+        }
+        self.cc.suspended(late);
+        true
+    }
+
     pub fn timeout(&mut self, now: Instant) -> Vec<SentPacket> {
         qtrace!([self], "timeout {:?}", now);
+        self.check_suspend(now);
 
         let loss_delay = self.loss_delay();
         let mut lost_packets = Vec::new();
@@ -738,6 +876,8 @@ impl LossRecovery {
             )
         }
 
+        self.packets_lost += lost_packets.len();
+
         self.maybe_fire_pto(now, &mut lost_packets);
         lost_packets
     }
@@ -747,6 +887,28 @@ impl LossRecovery {
         self.cc.start_pacer(now);
     }
 
+    pub fn set_max_send_rate(&mut self, rate: usize, include_handshake: bool) {
+        self.cc.set_max_send_rate(rate, include_handshake);
+    }
+
+    pub fn start_rate_limit(&mut self, now: Instant) {
+        self.cc.start_rate_limit(now);
+    }
+
+    pub fn set_idle_restart(&mut self, idle_restart: bool) {
+        self.cc.set_idle_restart(idle_restart);
+    }
+
+    pub fn set_pacing_granularity(&mut self, granularity: Duration) {
+        self.cc.set_pacing_granularity(granularity);
+    }
+
+    /// Reduce the congestion window if nothing has been sent for a while.
+    /// See `CongestionControl::restart_after_idle`.
+    pub fn restart_after_idle(&mut self, now: Instant) -> bool {
+        self.cc.restart_after_idle(now, self.raw_pto())
+    }
+
     /// Get the next time that a paced packet might be sent.
     pub fn next_paced(&self) -> Option<Instant> {
         self.cc.next_paced(self.rtt())
@@ -754,16 +916,19 @@ impl LossRecovery {
 
     /// Check how packets should be sent, based on whether there is a PTO,
     /// what the current congestion window is, and what the pacer says.
+    /// Probes are not subject to any send rate limit.
     pub fn send_profile(&mut self, now: Instant, mtu: usize) -> SendProfile {
         qdebug!([self], "get send profile {:?}", now);
         if let Some(pto) = self.pto_state.as_mut() {
             pto.send_profile(mtu)
+        } else if self.cc.rate_limited(now) {
+            SendProfile::new_paced(0)
         } else {
             let cwnd = self.cwnd_avail();
             if cwnd > mtu {
                 // More than an MTU available; we might need to pace.
-                if self.next_paced().map_or(false, |t| t > now) {
-                    SendProfile::new_paced()
+                if self.cc.paced(now, self.rtt()) {
+                    SendProfile::new_paced(mtu)
                 } else {
                     SendProfile::new_limited(mtu)
                 }
@@ -953,6 +1118,59 @@ mod tests {
         assert_no_sent_times(&lr);
     }
 
+    // The ack delay is limited by the max_ack_delay of the peer.
+    #[test]
+    fn ack_delay_capped() {
+        let mut lr = setup_lr(2);
+        let max_ack_delay = ms!(10);
+        assert!(max_ack_delay < ACK_DELAY);
+        lr.set_peer_max_ack_delay(max_ack_delay);
+        ack(&mut lr, 1, INITIAL_RTT + ACK_DELAY);
+        let extra = ACK_DELAY - max_ack_delay;
+        let expected_rtt = INITIAL_RTT + (extra / 8);
+        let expected_rttvar = (INITIAL_RTTVAR * 3 + extra) / 4;
+        assert_rtts(
+            &lr,
+            INITIAL_RTT + extra,
+            expected_rtt,
+            expected_rttvar,
+            INITIAL_RTT,
+        );
+    }
+
+    // The ack delay is ignored for packets that aren't application data.
+    #[test]
+    fn handshake_ack_delay_ignored() {
+        let mut lr = LossRecovery::new();
+        lr.start_pacer(now());
+        for pn in 0..2 {
+            lr.on_packet_sent(
+                PNSpace::Handshake,
+                pn,
+                SentPacket::new(pn_time(pn), true, Vec::new(), ON_SENT_SIZE, true),
+            );
+        }
+        for (pn, rtt) in [(0, INITIAL_RTT), (1, INITIAL_RTT + ACK_DELAY)].iter() {
+            lr.on_ack_received(
+                PNSpace::Handshake,
+                *pn,
+                vec![(*pn, *pn)],
+                ACK_DELAY,
+                pn_time(*pn) + *rtt,
+            );
+        }
+        let expected_rtt = INITIAL_RTT + (ACK_DELAY / 8);
+        let expected_rttvar = (INITIAL_RTTVAR * 3 + ACK_DELAY) / 4;
+        assert_rtts(
+            &lr,
+            INITIAL_RTT + ACK_DELAY,
+            expected_rtt,
+            expected_rttvar,
+            INITIAL_RTT,
+        );
+        assert_eq!(lr.min_rtt(), Some(INITIAL_RTT));
+    }
+
     // Acknowledging something again has no effect.
     #[test]
     fn no_new_acks() {
@@ -1037,6 +1255,7 @@ mod tests {
         assert_eq!(callback_time, Some(pn1_loss_time));
         let packets = lr.timeout(pn1_loss_time);
         assert_eq!(packets.len(), 1);
+        assert_eq!(lr.packets_lost(), 1);
         // Checking for expiration with zero delay lets us check the loss time.
         assert!(packets[0].expired(pn1_loss_time, Duration::from_secs(0)));
         assert_no_sent_times(&lr);
@@ -1055,6 +1274,42 @@ mod tests {
             pn_time(4),
         );
         assert_eq!(lost.len(), 1);
+        assert_eq!(lr.packets_lost(), 1);
+    }
+
+    // Times before packets were sent aren't used for RTT and don't panic.
+    #[test]
+    fn time_travel() {
+        let mut lr = setup_lr(3);
+        assert!(lr.timeout(now()).is_empty());
+        let (acked, lost) =
+            lr.on_ack_received(PNSpace::ApplicationData, 2, vec![(2, 2)], ACK_DELAY, now());
+        assert_eq!(acked.len(), 1);
+        assert!(lost.is_empty());
+        assert_rtts(&lr, INITIAL_RTT, INITIAL_RTT, INITIAL_RTTVAR, INITIAL_RTT);
+    }
+
+    // After a suspend, the PTO timer fires once.  It isn't due again straight away.
+    #[test]
+    fn suspend_pto() {
+        let mut lr = setup_lr(2);
+        let wake = lr.next_timeout().unwrap() + super::SUSPEND_THRESHOLD * 10;
+        let lost = lr.timeout(wake);
+        assert_eq!(lost.len(), 1);
+        assert!(!lost[0].lost());
+        assert_eq!(lr.packets_lost(), 0);
+        assert_eq!(lr.pto_count(), 1);
+        assert!(lr.next_timeout().unwrap() > wake);
+        assert!(lr.timeout(wake).is_empty());
+    }
+
+    // An ACK that arrives after a suspend isn't used for RTT.
+    #[test]
+    fn suspend_rtt() {
+        let mut lr = setup_lr(2);
+        ack(&mut lr, 1, super::SUSPEND_THRESHOLD * 10);
+        assert_rtts(&lr, INITIAL_RTT, INITIAL_RTT, INITIAL_RTTVAR, INITIAL_RTT);
+        assert_no_sent_times(&lr);
     }
 
     #[test]
@@ -1134,4 +1389,40 @@ mod tests {
         );
         assert_sent_times(&lr, None, None, Some(pn_time(2)));
     }
+
+    // A padded packet that only carries an ACK is in flight, but it doesn't
+    // need to be acknowledged, so it doesn't keep the PTO timer running.
+    #[test]
+    fn padded_ack_in_flight() {
+        let mut sp = LossRecoverySpace::new(PNSpace::Initial);
+        let mut pkt = SentPacket::new(pn_time(0), false, Vec::new(), ON_SENT_SIZE, false);
+        pkt.add_padding(1000, true);
+        assert!(pkt.cc_in_flight());
+        assert_eq!(pkt.size, ON_SENT_SIZE + 1000);
+        sp.on_packet_sent(0, pkt);
+        assert!(!sp.in_flight_outstanding());
+        assert!(sp.pto_base_time().is_none());
+
+        sp.on_packet_sent(
+            1,
+            SentPacket::new(pn_time(1), true, Vec::new(), ON_SENT_SIZE, true),
+        );
+        assert!(sp.in_flight_outstanding());
+
+        // Acknowledging the padded packet leaves the other one outstanding.
+        assert!(sp.remove_packet(0).unwrap().cc_in_flight());
+        assert!(sp.in_flight_outstanding());
+        assert_eq!(sp.pto_base_time(), Some(pn_time(1)));
+        sp.remove_packet(1).unwrap();
+        assert!(!sp.in_flight_outstanding());
+    }
+
+    // Padding a probe doesn't put it in flight.
+    #[test]
+    fn padded_probe_not_in_flight() {
+        let mut pkt = SentPacket::new(pn_time(0), true, Vec::new(), ON_SENT_SIZE, false);
+        pkt.add_padding(1000, false);
+        assert!(!pkt.cc_in_flight());
+        assert_eq!(pkt.size, ON_SENT_SIZE + 1000);
+    }
 }
@@ -24,6 +24,8 @@ use crate::{AppError, Error, Res};
 use neqo_common::{matches, qtrace};
 
 pub const RX_STREAM_DATA_WINDOW: u64 = 0xFFFF; // 64 KiB
+/// By default, more credit is sent once the application reads a quarter of the window.
+pub const STREAM_CREDIT_DIVISOR: u64 = 4;
 
 pub(crate) type RecvStreams = BTreeMap<StreamId, RecvStream>;
 
@@ -43,7 +45,7 @@ impl RxStreamOrderer {
     /// Process an incoming stream frame off the wire. This may result in data
     /// being available to upper layers if frame is not out of order (ooo) or
     /// if the frame fills a gap.
-    pub fn inbound_frame(&mut self, new_start: u64, mut new_data: Vec<u8>) -> Res<()> {
+    pub fn inbound_frame(&mut self, mut new_start: u64, mut new_data: Vec<u8>) -> Res<()> {
         qtrace!("Inbound data offset={} len={}", new_start, new_data.len());
 
         // Get entry before where new entry would go, so we can see if we already
@@ -61,6 +63,13 @@ impl RxStreamOrderer {
             return Ok(());
         }
 
+        if new_start < self.retired {
+            // Drop what has already been read, so that no range is left that
+            // ends before the read offset.
+            let _ = new_data.drain(..usize::try_from(self.retired - new_start).unwrap());
+            new_start = self.retired;
+        }
+
         let (insert_new, remove_prev) = if let Some((&prev_start, prev_vec)) = self
             .data_ranges
             .range_mut((Unbounded, Included(new_start)))
@@ -76,7 +85,7 @@ impl RxStreamOrderer {
                     // (In-order frames will take this path, with no overlap)
                     let overlap = prev_end.saturating_sub(new_start);
                     if overlap != 0 {
-                        let truncate_to = new_data.len() - overlap as usize;
+                        let truncate_to = prev_vec.len() - overlap as usize;
                         prev_vec.truncate(truncate_to)
                     }
                     qtrace!(
@@ -205,13 +214,13 @@ impl RxStreamOrderer {
     }
 
     /// Bytes read by the application.
-    fn retired(&self) -> u64 {
+    pub fn retired(&self) -> u64 {
         self.retired
     }
 
     /// Data bytes buffered. Could be more than bytes_readable if there are
     /// ranges missing.
-    fn buffered(&self) -> u64 {
+    pub fn buffered(&self) -> u64 {
         self.data_ranges
             .iter()
             .map(|(&start, data)| data.len() as u64 - (self.retired.saturating_sub(start)))
@@ -263,15 +272,6 @@ impl RxStreamOrderer {
         buf.resize(orig_len + self.bytes_ready(), 0);
         self.read(&mut buf[orig_len..])
     }
-
-    fn highest_seen_offset(&self) -> u64 {
-        let maybe_ooo_last = self
-            .data_ranges
-            .iter()
-            .next_back()
-            .map(|(start, data)| *start + data.len() as u64);
-        maybe_ooo_last.unwrap_or(self.retired)
-    }
 }
 
 /// QUIC receiving states, based on -transport 3.2.
@@ -286,7 +286,6 @@ enum RecvStreamState {
     },
     SizeKnown {
         recv_buf: RxStreamOrderer,
-        final_size: u64,
     },
     DataRecvd {
         recv_buf: RxStreamOrderer,
@@ -324,9 +323,11 @@ impl RecvStreamState {
         }
     }
 
-    fn final_size(&self) -> Option<u64> {
+    fn max_stream_data(&self) -> Option<u64> {
         match self {
-            Self::SizeKnown { final_size, .. } => Some(*final_size),
+            Self::Recv {
+                max_stream_data, ..
+            } => Some(*max_stream_data),
             _ => None,
         }
     }
@@ -337,6 +338,16 @@ impl RecvStreamState {
 pub struct RecvStream {
     stream_id: StreamId,
     state: RecvStreamState,
+    /// The highest offset that has been received, including the final size from
+    /// a RESET_STREAM.  This is kept in every state so that frames can be checked
+    /// after the data is gone.
+    received: u64,
+    /// From the first FIN or RESET_STREAM, in any state.
+    final_size: Option<u64>,
+    /// How much of `received` has been returned to connection flow control.
+    conn_retired: u64,
+    /// More credit is sent once the application has read 1/`credit_divisor` of the window.
+    credit_divisor: u64,
     flow_mgr: Rc<RefCell<FlowMgr>>,
     conn_events: ConnectionEvents,
 }
@@ -351,11 +362,20 @@ impl RecvStream {
         Self {
             stream_id,
             state: RecvStreamState::new(max_stream_data),
+            received: 0,
+            final_size: None,
+            conn_retired: 0,
+            credit_divisor: STREAM_CREDIT_DIVISOR,
             flow_mgr,
             conn_events,
         }
     }
 
+    pub fn set_credit_divisor(&mut self, divisor: u64) {
+        debug_assert!(divisor > 0);
+        self.credit_divisor = divisor;
+    }
+
     fn set_state(&mut self, new_state: RecvStreamState) {
         debug_assert_ne!(
             mem::discriminant(&self.state),
@@ -381,53 +401,77 @@ impl RecvStream {
         self.state = new_state;
     }
 
-    pub fn inbound_stream_frame(&mut self, fin: bool, offset: u64, data: Vec<u8>) -> Res<()> {
-        let new_end = offset + data.len() as u64;
+    /// The amount of data that this stream counts against the connection flow
+    /// control limit.  Once the final size is known, that is the final size.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
 
-        // Send final size errors even if stream is closed
-        if let Some(final_size) = self.state.final_size() {
-            if new_end > final_size || (fin && new_end != final_size) {
+    /// Check an offset that a STREAM frame reaches, or the final size from a
+    /// RESET_STREAM (which is like a FIN), against the final size and the flow
+    /// control limit, then record it.
+    fn receive_offset(&mut self, fin: bool, end: u64) -> Res<()> {
+        if let Some(final_size) = self.final_size {
+            if end > final_size || (fin && end != final_size) {
+                qtrace!(
+                    "RecvStream {} final size is {}, received {} fin={}",
+                    self.stream_id.as_u64(),
+                    final_size,
+                    end,
+                    fin
+                );
                 return Err(Error::FinalSizeError);
             }
+        } else if fin && end < self.received {
+            qtrace!(
+                "RecvStream {} final size {} is less than {} already received",
+                self.stream_id.as_u64(),
+                end,
+                self.received
+            );
+            return Err(Error::FinalSizeError);
         }
 
-        match &mut self.state {
-            RecvStreamState::Recv {
-                recv_buf,
-                max_stream_data,
-                ..
-            } => {
-                if new_end > *max_stream_data {
-                    qtrace!("Stream RX window {} exceeded: {}", max_stream_data, new_end);
-                    return Err(Error::FlowControlError);
-                }
+        if let Some(max_stream_data) = self.state.max_stream_data() {
+            if end > max_stream_data {
+                qtrace!("Stream RX window {} exceeded: {}", max_stream_data, end);
+                return Err(Error::FlowControlError);
+            }
+        }
 
-                if fin {
-                    let final_size = offset + data.len() as u64;
-                    if final_size < recv_buf.highest_seen_offset() {
-                        return Err(Error::FinalSizeError);
-                    }
-                    recv_buf.inbound_frame(offset, data)?;
+        if end > self.received {
+            self.flow_mgr
+                .borrow_mut()
+                .conn_rx_received(end - self.received)?;
+            self.received = end;
+        }
+        if fin {
+            self.final_size = Some(end);
+        }
+        Ok(())
+    }
+
+    pub fn inbound_stream_frame(&mut self, fin: bool, offset: u64, data: Vec<u8>) -> Res<()> {
+        let new_end = offset + data.len() as u64;
 
+        // Send final size errors even if stream is closed
+        self.receive_offset(fin, new_end)?;
+
+        match &mut self.state {
+            RecvStreamState::Recv { recv_buf, .. } => {
+                recv_buf.inbound_frame(offset, data)?;
+                if fin {
                     let buf = mem::replace(recv_buf, RxStreamOrderer::new());
-                    if final_size == buf.retired() + buf.bytes_ready() as u64 {
+                    if new_end == buf.retired() + buf.bytes_ready() as u64 {
                         self.set_state(RecvStreamState::DataRecvd { recv_buf: buf });
                     } else {
-                        self.set_state(RecvStreamState::SizeKnown {
-                            recv_buf: buf,
-                            final_size,
-                        });
+                        self.set_state(RecvStreamState::SizeKnown { recv_buf: buf });
                     }
-                } else {
-                    recv_buf.inbound_frame(offset, data)?;
                 }
             }
-            RecvStreamState::SizeKnown {
-                recv_buf,
-                final_size,
-            } => {
+            RecvStreamState::SizeKnown { recv_buf } => {
                 recv_buf.inbound_frame(offset, data)?;
-                if *final_size == recv_buf.retired() + recv_buf.bytes_ready() as u64 {
+                if self.final_size == Some(recv_buf.retired() + recv_buf.bytes_ready() as u64) {
                     let buf = mem::replace(recv_buf, RxStreamOrderer::new());
                     self.set_state(RecvStreamState::DataRecvd { recv_buf: buf });
                 }
@@ -442,11 +486,15 @@ impl RecvStream {
         if self.data_ready() || self.needs_to_inform_app_about_fin() {
             self.conn_events.recv_stream_readable(self.stream_id)
         }
+        self.retire_conn_credit();
 
         Ok(())
     }
 
-    pub fn reset(&mut self, application_error_code: AppError) {
+    /// Handle RESET_STREAM.  The final size is checked in every state, even when
+    /// the reset itself is ignored.
+    pub fn reset(&mut self, application_error_code: AppError, final_size: u64) -> Res<()> {
+        self.receive_offset(true, final_size)?;
         match self.state {
             RecvStreamState::Recv { .. } | RecvStreamState::SizeKnown { .. } => {
                 self.conn_events
@@ -457,28 +505,57 @@ impl RecvStream {
                 // Ignore reset if in DataRecvd, DataRead, or ResetRecvd
             }
         }
+        self.retire_conn_credit();
+        Ok(())
     }
 
-    /// If we should tell the sender they have more credit, return an offset
+    /// Return connection credit for data that has been read, and for data that
+    /// won't be because the stream was reset or the application stopped reading.
+    fn retire_conn_credit(&mut self) {
+        let retired = self
+            .state
+            .recv_buf()
+            .map_or(self.received, RxStreamOrderer::retired);
+        if retired > self.conn_retired {
+            self.flow_mgr
+                .borrow_mut()
+                .conn_rx_retired(retired - self.conn_retired);
+            self.conn_retired = retired;
+        }
+    }
+
+    /// Tell the sender they have more credit, if the application has read enough.
     pub fn maybe_send_flowc_update(&mut self) {
+        self.flowc_update(false);
+    }
+
+    /// Tell the sender how much they can send, even if that hasn't changed much,
+    /// as when they say that they are blocked or when the last update was lost.
+    pub fn send_flowc_update(&mut self) {
+        self.flowc_update(true);
+    }
+
+    fn flowc_update(&mut self, force: bool) {
         if let RecvStreamState::Recv {
             max_bytes,
             max_stream_data,
             recv_buf,
         } = &mut self.state
         {
-            {
-                // Algo: send an update if app has consumed more than half
-                // the data in the current window
-                // TODO(agrover@mozilla.com): This algo is not great but
-                // should prevent Silly Window Syndrome. Spec refers to using
-                // highest seen offset somehow? RTT maybe?
-                let maybe_new_max = recv_buf.retired() + *max_bytes;
-                if maybe_new_max > (*max_bytes / 2) + *max_stream_data {
-                    *max_stream_data = maybe_new_max;
-                    self.flow_mgr
-                        .borrow_mut()
-                        .max_stream_data(self.stream_id, maybe_new_max)
+            // Algo: send an update if app has consumed a fraction of
+            // the data in the current window, so that updates aren't sent
+            // for every read.
+            let maybe_new_max = recv_buf.retired() + *max_bytes;
+            if force || maybe_new_max > (*max_bytes / self.credit_divisor) + *max_stream_data {
+                // A sender with less than half a window left might be blocked
+                // before another packet would carry the update.
+                let urgent =
+                    force || max_stream_data.saturating_sub(self.received) < *max_bytes / 2;
+                *max_stream_data = max(*max_stream_data, maybe_new_max);
+                let mut flow_mgr = self.flow_mgr.borrow_mut();
+                flow_mgr.max_stream_data(self.stream_id, *max_stream_data);
+                if urgent {
+                    flow_mgr.stream_credit_urgent();
                 }
             }
         }
@@ -517,6 +594,7 @@ impl RecvStream {
             RecvStreamState::DataRead | RecvStreamState::ResetRecvd => Err(Error::NoMoreData),
         };
         self.maybe_send_flowc_update();
+        self.retire_conn_credit();
         res
     }
 
@@ -532,6 +610,7 @@ impl RecvStream {
                 // Already in terminal state
             }
         }
+        self.retire_conn_credit();
     }
 }
 
@@ -539,6 +618,8 @@ impl RecvStream {
 mod tests {
     use super::*;
     use crate::frame::Frame;
+    use crate::rng::Rng;
+    use crate::tracking::PNSpace;
     use neqo_common::matches;
 
     #[test]
@@ -722,6 +803,45 @@ mod tests {
         assert_eq!(s.flow_mgr.borrow().peek(), None);
     }
 
+    #[test]
+    fn stream_credit_deferred() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        let mut s = RecvStream::new(
+            4.into(),
+            RX_STREAM_DATA_WINDOW,
+            Rc::clone(&flow_mgr),
+            ConnectionEvents::default(),
+        );
+
+        // Reading a little doesn't release any credit.
+        let mut buf = vec![0u8; RX_STREAM_DATA_WINDOW as usize];
+        s.inbound_stream_frame(false, 0, vec![0; 10]).unwrap();
+        assert_eq!(s.read(&mut buf).unwrap(), (10, false));
+        assert_eq!(flow_mgr.borrow().stream_credit_pending(), None);
+
+        // Reading more than a quarter of the window does, but the sender
+        // still has plenty of credit, so the update can wait.
+        let quarter = (RX_STREAM_DATA_WINDOW / STREAM_CREDIT_DIVISOR) as usize;
+        s.inbound_stream_frame(false, 10, vec![0; quarter]).unwrap();
+        assert_eq!(s.read(&mut buf).unwrap(), (quarter, false));
+        assert_eq!(flow_mgr.borrow().stream_credit_pending(), Some(false));
+        assert!(flow_mgr
+            .borrow_mut()
+            .get_frame(PNSpace::ApplicationData, 100, false)
+            .is_none());
+
+        // A blocked sender needs it now.
+        s.send_flowc_update();
+        assert_eq!(flow_mgr.borrow().stream_credit_pending(), Some(true));
+        assert!(matches!(
+            flow_mgr
+                .borrow_mut()
+                .get_frame(PNSpace::ApplicationData, 100, true),
+            Some((Frame::MaxStreamData { .. }, _))
+        ));
+        assert_eq!(flow_mgr.borrow().stream_credit_pending(), None);
+    }
+
     #[test]
     fn test_stream_max_stream_data() {
         let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
@@ -796,4 +916,403 @@ mod tests {
             .unwrap();
         assert!(matches!(s.flow_mgr.borrow().peek(), None));
     }
+
+    #[test]
+    fn conn_credit() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr.borrow_mut().set_rx_window(100);
+        let conn_events = ConnectionEvents::default();
+        let mut a = RecvStream::new(2.into(), 1024, Rc::clone(&flow_mgr), conn_events.clone());
+        let mut b = RecvStream::new(6.into(), 1024, Rc::clone(&flow_mgr), conn_events);
+
+        a.inbound_stream_frame(false, 0, vec![0; 60]).unwrap();
+        assert_eq!(
+            b.inbound_stream_frame(false, 0, vec![0; 41]),
+            Err(Error::FlowControlError)
+        );
+        b.inbound_stream_frame(false, 0, vec![0; 40]).unwrap();
+
+        // Reading less than half the window doesn't send MAX_DATA.
+        let mut buf = [0; 60];
+        assert_eq!(a.read(&mut buf[..49]).unwrap(), (49, false));
+        assert!(!matches!(
+            flow_mgr.borrow().peek(),
+            Some(Frame::MaxData { .. })
+        ));
+        assert_eq!(a.read(&mut buf).unwrap(), (11, false));
+        assert_eq!(
+            flow_mgr.borrow().peek(),
+            Some(&Frame::MaxData { maximum_data: 160 })
+        );
+
+        // Data that is discarded is credited too, as a forced update shows.
+        b.stop_sending(0);
+        flow_mgr.borrow_mut().conn_rx_update(true);
+        assert_eq!(
+            flow_mgr.borrow().peek(),
+            Some(&Frame::MaxData { maximum_data: 200 })
+        );
+    }
+
+    fn final_size_stream() -> RecvStream {
+        RecvStream::new(
+            4.into(),
+            1024,
+            Rc::new(RefCell::new(FlowMgr::default())),
+            ConnectionEvents::default(),
+        )
+    }
+
+    fn read_all(s: &mut RecvStream) {
+        let mut buf = vec![0; 100];
+        let (len, _) = s.read(&mut buf).unwrap();
+        assert_eq!(len, 10);
+    }
+
+    #[test]
+    fn final_size_data_then_fin() {
+        let mut s = final_size_stream();
+        s.inbound_stream_frame(false, 0, vec![1; 10]).unwrap();
+        read_all(&mut s);
+        assert_eq!(
+            s.inbound_stream_frame(true, 0, vec![1; 5]),
+            Err(Error::FinalSizeError)
+        );
+        assert_eq!(
+            s.inbound_stream_frame(true, 8, vec![]),
+            Err(Error::FinalSizeError)
+        );
+        s.inbound_stream_frame(true, 10, vec![]).unwrap();
+        assert_eq!(s.received(), 10);
+    }
+
+    #[test]
+    fn final_size_fin_then_data() {
+        let mut s = final_size_stream();
+        s.inbound_stream_frame(true, 0, vec![1; 10]).unwrap();
+        read_all(&mut s);
+        assert!(s.is_terminal());
+        assert_eq!(
+            s.inbound_stream_frame(false, 5, vec![1; 10]),
+            Err(Error::FinalSizeError)
+        );
+        assert_eq!(
+            s.inbound_stream_frame(true, 0, vec![1; 11]),
+            Err(Error::FinalSizeError)
+        );
+        // A retransmission is fine.
+        s.inbound_stream_frame(true, 0, vec![1; 10]).unwrap();
+    }
+
+    #[test]
+    fn final_size_data_then_reset() {
+        let mut s = final_size_stream();
+        s.inbound_stream_frame(false, 0, vec![1; 10]).unwrap();
+        read_all(&mut s);
+        assert_eq!(s.reset(0, 5), Err(Error::FinalSizeError));
+        assert!(!s.is_terminal());
+        s.reset(0, 12).unwrap();
+        assert!(s.is_terminal());
+        assert_eq!(s.received(), 12);
+    }
+
+    #[test]
+    fn final_size_reset_then_data() {
+        let mut s = final_size_stream();
+        s.reset(0, 10).unwrap();
+        assert_eq!(
+            s.inbound_stream_frame(false, 8, vec![1; 4]),
+            Err(Error::FinalSizeError)
+        );
+        s.inbound_stream_frame(false, 0, vec![1; 10]).unwrap();
+        assert_eq!(s.received(), 10);
+    }
+
+    #[test]
+    fn final_size_fin_then_reset() {
+        let mut s = final_size_stream();
+        // A FIN that leaves a gap, so the stream stays in SizeKnown.
+        s.inbound_stream_frame(true, 4, vec![1; 6]).unwrap();
+        assert_eq!(s.reset(0, 12), Err(Error::FinalSizeError));
+        assert_eq!(s.reset(0, 8), Err(Error::FinalSizeError));
+        s.reset(0, 10).unwrap();
+        assert!(s.is_terminal());
+    }
+
+    #[test]
+    fn final_size_reset_then_fin() {
+        let mut s = final_size_stream();
+        s.reset(0, 10).unwrap();
+        assert_eq!(
+            s.inbound_stream_frame(true, 0, vec![1; 12]),
+            Err(Error::FinalSizeError)
+        );
+        assert_eq!(
+            s.inbound_stream_frame(true, 0, vec![1; 8]),
+            Err(Error::FinalSizeError)
+        );
+        assert_eq!(s.reset(0, 11), Err(Error::FinalSizeError));
+        s.inbound_stream_frame(true, 0, vec![1; 10]).unwrap();
+        s.reset(0, 10).unwrap();
+    }
+
+    /// The data is discarded after STOP_SENDING, but a reset is still checked.
+    #[test]
+    fn final_size_stop_sending_then_reset() {
+        let mut s = final_size_stream();
+        s.inbound_stream_frame(false, 0, vec![1; 10]).unwrap();
+        s.stop_sending(0);
+        assert_eq!(s.reset(0, 9), Err(Error::FinalSizeError));
+        s.reset(0, 10).unwrap();
+    }
+
+    #[test]
+    fn reset_flow_control() {
+        let mut s = final_size_stream();
+        assert_eq!(s.reset(0, 1025), Err(Error::FlowControlError));
+        s.reset(0, 1024).unwrap();
+        assert_eq!(s.received(), 1024);
+    }
+
+    /// What `RecvStream` should do, kept as simple as possible: each byte is held
+    /// by its offset, and the rules for final size and flow control are applied
+    /// directly.
+    #[derive(Default)]
+    struct ModelStream {
+        window: u64,
+        max_stream_data: u64,
+        bytes: BTreeMap<u64, u8>,
+        read: u64,
+        received: u64,
+        final_size: Option<u64>,
+        /// Set by a reset, STOP_SENDING, or reading the FIN.
+        done: bool,
+    }
+
+    impl ModelStream {
+        fn new(window: u64) -> Self {
+            Self {
+                window,
+                max_stream_data: window,
+                ..Self::default()
+            }
+        }
+
+        /// Whether the flow control limit applies, which it only does until the
+        /// final size is known.
+        fn limited(&self) -> bool {
+            !self.done && self.final_size.is_none()
+        }
+
+        fn all_received(&self) -> bool {
+            !self.done
+                && self.final_size.map_or(false, |fs| {
+                    (self.read..fs).all(|o| self.bytes.contains_key(&o))
+                })
+        }
+
+        fn check_offset(&mut self, fin: bool, end: u64) -> Res<()> {
+            if let Some(fs) = self.final_size {
+                if end > fs || (fin && end != fs) {
+                    return Err(Error::FinalSizeError);
+                }
+            } else if fin && end < self.received {
+                return Err(Error::FinalSizeError);
+            }
+            if self.limited() && end > self.max_stream_data {
+                return Err(Error::FlowControlError);
+            }
+            self.received = max(self.received, end);
+            if fin {
+                self.final_size = Some(end);
+            }
+            Ok(())
+        }
+
+        fn frame(&mut self, fin: bool, offset: u64, data: &[u8]) -> Res<()> {
+            self.check_offset(fin, offset + data.len() as u64)?;
+            if !self.done {
+                for (o, &b) in (offset..).zip(data) {
+                    if o >= self.read {
+                        self.bytes.entry(o).or_insert(b);
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        /// A reset is ignored once everything has arrived.
+        fn reset(&mut self, final_size: u64) -> Res<()> {
+            // Only a FIN frame that completes the data makes a reset too late.
+            let complete = self.all_received();
+            self.check_offset(true, final_size)?;
+            if !complete {
+                self.stop();
+            }
+            Ok(())
+        }
+
+        fn stop(&mut self) {
+            self.done = true;
+            self.bytes.clear();
+        }
+
+        fn read(&mut self, len: usize) -> Res<(Vec<u8>, bool)> {
+            if self.done {
+                return Err(Error::NoMoreData);
+            }
+            let mut out = Vec::new();
+            while out.len() < len {
+                if let Some(b) = self.bytes.remove(&self.read) {
+                    out.push(b);
+                    self.read += 1;
+                } else {
+                    break;
+                }
+            }
+            let fin = self.final_size == Some(self.read);
+            if fin {
+                self.stop();
+            }
+            if self.limited() {
+                let new_max = self.read + self.window;
+                if new_max > self.window / STREAM_CREDIT_DIVISOR + self.max_stream_data {
+                    self.max_stream_data = new_max;
+                }
+            }
+            Ok((out, fin))
+        }
+    }
+
+    /// Random numbers from a seeded generator, so that a failing case can be run again.
+    struct Gen(Rng);
+
+    impl Gen {
+        fn new(case: u64) -> Self {
+            test_fixture::fixture_init();
+            let mut seed = [0; 32];
+            seed[..8].copy_from_slice(&case.to_le_bytes());
+            Self(Rng::from_seed(seed))
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            let mut b = [0; 8];
+            self.0.fill(&mut b);
+            u64::from_le_bytes(b) % n
+        }
+    }
+
+    /// Every sender sends the same byte at each offset.
+    fn stream_bytes(offset: u64, len: u64) -> Vec<u8> {
+        (offset..offset + len).map(|o| (o % 251) as u8).collect()
+    }
+
+    /// Run one random sequence of frames, resets, STOP_SENDING, and reads against
+    /// both `RecvStream` and the model, checking that they agree after every step.
+    fn model_case(case: u64) {
+        const WINDOWS: &[u64] = &[8, 64, 1000];
+        let mut g = Gen::new(case);
+        let window = WINDOWS[usize::try_from(g.below(WINDOWS.len() as u64)).unwrap()];
+        // The size that senders aim for, which can be more than the window.
+        let size = g.below(window * 2 + 1);
+        let mut s = RecvStream::new(
+            4.into(),
+            window,
+            Rc::new(RefCell::new(FlowMgr::default())),
+            ConnectionEvents::default(),
+        );
+        let mut m = ModelStream::new(window);
+
+        for step in 0..100 {
+            let what = g.below(100);
+            if what < 55 {
+                let offset = g.below(size + 8);
+                let len = g.below(33);
+                let fin = g.below(10) == 0;
+                let data = stream_bytes(offset, len);
+                let expected = m.frame(fin, offset, &data);
+                assert_eq!(
+                    s.inbound_stream_frame(fin, offset, data),
+                    expected,
+                    "case {} step {}: frame {}+{} fin={}",
+                    case,
+                    step,
+                    offset,
+                    len,
+                    fin
+                );
+            } else if what < 70 {
+                let len = g.below(min(size, 32) + 1);
+                let data = stream_bytes(size - len, len);
+                let expected = m.frame(true, size - len, &data);
+                assert_eq!(
+                    s.inbound_stream_frame(true, size - len, data),
+                    expected,
+                    "case {} step {}: fin frame {}+{}",
+                    case,
+                    step,
+                    size - len,
+                    len
+                );
+            } else if what < 95 {
+                let len = usize::try_from(g.below(48)).unwrap();
+                let mut buf = vec![0; len];
+                let res = s.read(&mut buf).map(|(n, fin)| (buf[..n].to_vec(), fin));
+                assert_eq!(
+                    res,
+                    m.read(len),
+                    "case {} step {}: read {}",
+                    case,
+                    step,
+                    len
+                );
+            } else if what < 98 {
+                let final_size = if g.below(2) == 0 {
+                    size
+                } else {
+                    g.below(size * 2 + 1)
+                };
+                assert_eq!(
+                    s.reset(0, final_size),
+                    m.reset(final_size),
+                    "case {} step {}: reset {}",
+                    case,
+                    step,
+                    final_size
+                );
+            } else {
+                s.stop_sending(0);
+                m.stop();
+            }
+
+            let max_stream_data = if m.limited() {
+                Some(m.max_stream_data)
+            } else {
+                None
+            };
+            assert_eq!(s.received(), m.received, "case {} step {}", case, step);
+            assert_eq!(
+                s.state.max_stream_data(),
+                max_stream_data,
+                "case {} step {}",
+                case,
+                step
+            );
+            assert_eq!(s.is_terminal(), m.done, "case {} step {}", case, step);
+            assert_eq!(
+                s.data_ready(),
+                m.bytes.contains_key(&m.read),
+                "case {} step {}",
+                case,
+                step
+            );
+        }
+    }
+
+    #[test]
+    fn model() {
+        for case in 0..3000 {
+            model_case(case);
+        }
+    }
 }
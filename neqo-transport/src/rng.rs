@@ -0,0 +1,154 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Randomness that isn't secret, but that goes on the wire: connection IDs and
+// greasing.  Nobody may predict it, so it comes from NSS.  Tests can seed a
+// generator instead, so that a run can be repeated exactly; that generator is
+// only built for tests, or with the `test-rng` feature.
+
+use neqo_crypto::random as nss_random;
+
+#[cfg(any(test, feature = "test-rng"))]
+pub use seeded::{seed, set_default_seed, set_seed, Rng, Seed};
+
+#[cfg(any(test, feature = "test-rng"))]
+mod seeded {
+    use neqo_crypto::constants::{TLS_AES_128_GCM_SHA256, TLS_VERSION_1_3};
+    use neqo_crypto::{hkdf, SymKey};
+    use std::cell::RefCell;
+
+    pub type Seed = [u8; 32];
+
+    /// A generator that makes the same bytes from the same seed.  Each block of
+    /// output is HKDF-Expand-Label of the seed with a counter as the context.
+    pub struct Rng {
+        key: SymKey,
+        counter: u64,
+        block: Vec<u8>,
+        used: usize,
+    }
+
+    impl Rng {
+        #[must_use]
+        pub fn from_seed(seed: Seed) -> Self {
+            let key = hkdf::import_key(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, &seed)
+                .expect("NSS imports the seed");
+            Self {
+                key,
+                counter: 0,
+                block: Vec::new(),
+                used: 0,
+            }
+        }
+
+        fn next_block(&mut self) {
+            let secret = hkdf::expand_label(
+                TLS_VERSION_1_3,
+                TLS_AES_128_GCM_SHA256,
+                &self.key,
+                &self.counter.to_be_bytes(),
+                "neqo rng",
+            )
+            .expect("NSS expands the seed");
+            self.block = secret.as_bytes().expect("the block has bytes").to_vec();
+            self.counter += 1;
+            self.used = 0;
+        }
+
+        pub fn fill(&mut self, buf: &mut [u8]) {
+            for b in buf {
+                if self.used == self.block.len() {
+                    self.next_block();
+                }
+                *b = self.block[self.used];
+                self.used += 1;
+            }
+        }
+    }
+
+    thread_local!(static RNG: RefCell<Option<(Seed, Rng)>> = RefCell::new(None));
+
+    /// Seed the generator used on this thread.  This starts the sequence again, so
+    /// calling this with the same seed repeats a run.
+    pub fn set_seed(seed: Seed) {
+        RNG.with(|r| *r.borrow_mut() = Some((seed, Rng::from_seed(seed))));
+    }
+
+    /// Seed the generator used on this thread, unless it has already been seeded.
+    pub fn set_default_seed(seed: Seed) {
+        let seeded = RNG.with(|r| r.borrow().is_some());
+        if !seeded {
+            set_seed(seed);
+        }
+    }
+
+    /// The seed of the generator used on this thread, if it has been seeded.
+    #[must_use]
+    pub fn seed() -> Option<Seed> {
+        RNG.with(|r| r.borrow().as_ref().map(|(seed, _)| *seed))
+    }
+
+    /// `len` bytes from the generator used on this thread, if it has been seeded.
+    pub(super) fn random(len: usize) -> Option<Vec<u8>> {
+        RNG.with(|r| {
+            r.borrow_mut().as_mut().map(|(_, rng)| {
+                let mut buf = vec![0; len];
+                rng.fill(&mut buf);
+                buf
+            })
+        })
+    }
+}
+
+/// Get `len` random bytes.
+#[must_use]
+pub fn random(len: usize) -> Vec<u8> {
+    #[cfg(any(test, feature = "test-rng"))]
+    {
+        if let Some(buf) = seeded::random(len) {
+            return buf;
+        }
+    }
+    nss_random(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{random, seed, set_default_seed, set_seed, Rng};
+    use test_fixture::fixture_init;
+
+    #[test]
+    fn blocks() {
+        fixture_init();
+        let mut whole = [0; 100];
+        Rng::from_seed([1; 32]).fill(&mut whole);
+        // Taking the output in uneven pieces crosses blocks the same way.
+        let mut rng = Rng::from_seed([1; 32]);
+        let mut pieces = [0; 100];
+        for piece in pieces.chunks_mut(30) {
+            rng.fill(piece);
+        }
+        assert_eq!(&whole[..], &pieces[..]);
+        assert_ne!(&whole[..32], &whole[32..64]);
+    }
+
+    #[test]
+    fn repeatable() {
+        fixture_init();
+        set_seed([7; 32]);
+        let first = random(40);
+        set_seed([7; 32]);
+        assert_eq!(random(40), first);
+        assert_eq!(seed(), Some([7; 32]));
+
+        // A default seed doesn't replace one that is already set.
+        set_default_seed([8; 32]);
+        assert_eq!(seed(), Some([7; 32]));
+
+        set_seed([8; 32]);
+        assert_ne!(random(40), first);
+    }
+}
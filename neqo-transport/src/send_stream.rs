@@ -21,10 +21,30 @@ use crate::events::ConnectionEvents;
 use crate::flow_mgr::FlowMgr;
 use crate::frame::Frame;
 use crate::recovery::RecoveryToken;
+use crate::stats::Stats;
 use crate::stream_id::StreamId;
 use crate::tracking::PNSpace;
 use crate::{AppError, Error, Res};
 
+/// The order in which streams get to send data.  Streams in a higher band
+/// always go before those in a lower one, and streams in the same band take
+/// turns.  Retransmissions are sent in the same order.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub enum TransmissionPriority {
+    /// For streams that other streams depend on, like the control and QPACK
+    /// streams of HTTP/3.  These should only carry small amounts of data.
+    Critical,
+    High,
+    Normal,
+    Low,
+}
+
+impl Default for TransmissionPriority {
+    fn default() -> Self {
+        Self::Normal
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 enum RangeState {
     Sent,
@@ -359,7 +379,7 @@ impl TxBuffer {
         self.buffered() as u64 + self.retired
     }
 
-    fn buffered(&self) -> usize {
+    pub fn buffered(&self) -> usize {
         self.send_buf.len()
     }
 
@@ -449,6 +469,13 @@ pub struct SendStream {
     state: SendStreamState,
     flow_mgr: Rc<RefCell<FlowMgr>>,
     conn_events: ConnectionEvents,
+    /// The error code from STOP_SENDING, if the peer sent one.
+    stopped: Option<AppError>,
+    priority: TransmissionPriority,
+    /// The stream limit at the last STREAM_DATA_BLOCKED.
+    blocked_limit: Option<u64>,
+    /// The end of the data that has been sent, which doesn't move back when data is lost.
+    highest_sent: u64,
 }
 
 impl SendStream {
@@ -464,6 +491,10 @@ impl SendStream {
             state: SendStreamState::Ready,
             flow_mgr,
             conn_events,
+            stopped: None,
+            priority: TransmissionPriority::default(),
+            blocked_limit: None,
+            highest_sent: 0,
         };
         if ss.avail() > 0 {
             ss.conn_events.send_stream_writable(stream_id);
@@ -471,6 +502,14 @@ impl SendStream {
         ss
     }
 
+    pub fn priority(&self) -> TransmissionPriority {
+        self.priority
+    }
+
+    pub fn set_priority(&mut self, priority: TransmissionPriority) {
+        self.priority = priority;
+    }
+
     /// Return the next range to be sent, if any.
     pub fn next_bytes(&mut self) -> Option<(u64, &[u8])> {
         match self.state {
@@ -498,13 +537,18 @@ impl SendStream {
         }
     }
 
-    pub fn mark_as_sent(&mut self, offset: u64, len: usize, fin: bool) {
+    /// Mark a range as sent.  This returns how much of it was sent for the first time.
+    pub fn mark_as_sent(&mut self, offset: u64, len: usize, fin: bool) -> usize {
+        let end = offset + len as u64;
+        let new = usize::try_from(end.saturating_sub(max(offset, self.highest_sent))).unwrap();
+        self.highest_sent = max(self.highest_sent, end);
         if let Some(buf) = self.state.tx_buf_mut() {
             buf.mark_as_sent(offset, len);
             if offset + len as u64 == self.max_stream_data {
                 self.flow_mgr
                     .borrow_mut()
                     .stream_data_blocked(self.stream_id, self.max_stream_data);
+                self.blocked_limit = Some(self.max_stream_data);
             }
             if self.flow_mgr.borrow().conn_credit_avail() == 0 {
                 self.flow_mgr.borrow_mut().data_blocked();
@@ -516,6 +560,7 @@ impl SendStream {
                 *fin_sent = true;
             }
         }
+        new
     }
 
     pub fn mark_as_acked(&mut self, offset: u64, len: usize, fin: bool) {
@@ -570,11 +615,13 @@ impl SendStream {
     }
 
     /// Bytes sendable on stream. Constrained by stream credit available,
-    /// connection credit available, and space in the tx buffer.
+    /// connection credit available, and space in the tx buffer.  Streams that
+    /// aren't critical can't use the connection credit that is reserved for
+    /// critical streams.
     pub fn avail(&self) -> usize {
         min(
             min(self.state.tx_avail(), self.credit_avail()),
-            self.flow_mgr.borrow().conn_credit_avail(),
+            self.flow_mgr.borrow().conn_credit_avail_for(self.priority),
         )
         .try_into()
         .unwrap()
@@ -584,6 +631,20 @@ impl SendStream {
         self.max_stream_data
     }
 
+    /// The stream limit, if sending is blocked on it.  This is only the case after
+    /// STREAM_DATA_BLOCKED was queued for the current limit, and while the stream
+    /// is open and has used all of its credit.
+    pub fn flow_control_blocked(&self) -> Option<u64> {
+        if matches!(self.state, SendStreamState::Send { .. })
+            && self.credit_avail() == 0
+            && self.blocked_limit == Some(self.max_stream_data)
+        {
+            self.blocked_limit
+        } else {
+            None
+        }
+    }
+
     pub fn set_max_stream_data(&mut self, value: u64) {
         let stream_was_blocked = self.avail() == 0;
         self.max_stream_data = max(self.max_stream_data, value);
@@ -609,6 +670,22 @@ impl SendStream {
         matches!(self.state, SendStreamState::DataRecvd { .. } | SendStreamState::ResetRecvd)
     }
 
+    /// Whether more data can be sent.  This fails with `FinalSizeError` once the stream
+    /// has been closed, `StreamStopped` if the peer sent STOP_SENDING, and `StreamReset`
+    /// if it was reset locally.
+    pub fn check_can_send(&self) -> Res<()> {
+        match self.state {
+            SendStreamState::Ready | SendStreamState::Send { .. } => Ok(()),
+            SendStreamState::DataSent { .. } | SendStreamState::DataRecvd { .. } => {
+                Err(Error::FinalSizeError)
+            }
+            SendStreamState::ResetSent | SendStreamState::ResetRecvd => match self.stopped {
+                Some(err) => Err(Error::StreamStopped(err)),
+                None => Err(Error::StreamReset),
+            },
+        }
+    }
+
     pub fn send(&mut self, buf: &[u8]) -> Res<usize> {
         self.send_internal(buf, false)
     }
@@ -622,9 +699,17 @@ impl SendStream {
             self.flow_mgr
                 .borrow_mut()
                 .stream_data_blocked(self.stream_id, self.max_stream_data);
+            self.blocked_limit = Some(self.max_stream_data);
         }
 
-        if self.flow_mgr.borrow().conn_credit_avail() < len {
+        // The connection is only blocked if this stream can use all of the credit
+        // that is left; credit that is reserved for critical streams isn't used.
+        let conn_blocked = {
+            let flow_mgr = self.flow_mgr.borrow();
+            let avail = flow_mgr.conn_credit_avail();
+            avail < len && flow_mgr.conn_credit_avail_for(self.priority) == avail
+        };
+        if conn_blocked {
             self.flow_mgr.borrow_mut().data_blocked();
         }
     }
@@ -635,16 +720,13 @@ impl SendStream {
             return Err(Error::InvalidInput);
         }
 
+        self.check_can_send()?;
         if let SendStreamState::Ready = self.state {
             self.state.transition(SendStreamState::Send {
                 send_buf: TxBuffer::new(),
             });
         }
 
-        if !matches!(self.state, SendStreamState::Send{..}) {
-            return Err(Error::FinalSizeError);
-        }
-
         let buf = if buf.is_empty() || (self.avail() == 0) {
             return Ok(0);
         } else if self.avail() < buf.len() {
@@ -706,6 +788,11 @@ impl SendStream {
                 self.state.transition(SendStreamState::ResetSent);
             }
             SendStreamState::Send { send_buf } => {
+                // Data that was buffered but not sent doesn't count toward the final
+                // size, so it stops counting against connection flow control.
+                self.flow_mgr
+                    .borrow_mut()
+                    .conn_decrease_credit_used(send_buf.data_limit() - send_buf.highest_sent());
                 self.flow_mgr.borrow_mut().stream_reset(
                     self.stream_id,
                     err,
@@ -726,62 +813,193 @@ impl SendStream {
             SendStreamState::ResetRecvd => qtrace!("already in ResetRecvd state"),
         };
     }
+
+    /// The peer sent STOP_SENDING.  Reset the stream and remember the error code,
+    /// which is reported for any later attempt to send.
+    pub fn stop_sending(&mut self, err: AppError) {
+        match self.state {
+            SendStreamState::ResetSent | SendStreamState::ResetRecvd => {}
+            _ => self.stopped = Some(err),
+        }
+        self.reset(err);
+    }
 }
 
+/// Data for a stream from `Connection::stream_create_pending`.  The stream has an ID,
+/// but it can't be opened until the peer's limits allow, or it has been opened and
+/// not all of the data has been passed to its `SendStream` yet.
 #[derive(Debug, Default)]
-pub(crate) struct SendStreams(HashMap<StreamId, SendStream>);
+pub(crate) struct PendingSendStream {
+    data: Vec<u8>,
+    fin: bool,
+    reset: Option<AppError>,
+    pub opened: bool,
+    pub priority: TransmissionPriority,
+}
+
+impl PendingSendStream {
+    /// Buffer as much of `buf` as a `SendStream` would.
+    pub fn send(&mut self, buf: &[u8]) -> Res<usize> {
+        if buf.is_empty() {
+            return Err(Error::InvalidInput);
+        }
+        if self.reset.is_some() {
+            return Err(Error::StreamReset);
+        }
+        if self.fin {
+            return Err(Error::FinalSizeError);
+        }
+        let sent = min(self.avail(), buf.len());
+        self.data.extend_from_slice(&buf[..sent]);
+        Ok(sent)
+    }
+
+    pub fn avail(&self) -> usize {
+        if self.fin || self.reset.is_some() {
+            0
+        } else {
+            TxBuffer::BUFFER_SIZE - self.data.len()
+        }
+    }
+
+    pub fn close(&mut self) {
+        self.fin = true;
+    }
+
+    pub fn reset(&mut self, err: AppError) {
+        self.data.clear();
+        self.reset = Some(err);
+    }
+
+    /// Pass up to `limit` bytes of buffered data, then any close or reset, to the
+    /// opened `stream`.  Returns the amount of data passed and whether everything has
+    /// been passed, in which case this can be dropped.
+    pub fn drain(&mut self, stream: &mut SendStream, limit: usize) -> (usize, bool) {
+        if let Some(err) = self.reset {
+            stream.reset(err);
+            return (0, true);
+        }
+        let mut sent = 0;
+        let amount = min(min(self.data.len(), limit), stream.avail());
+        if amount > 0 {
+            match stream.send(&self.data[..amount]) {
+                Ok(n) => sent = n,
+                // The stream was reset by the peer, so the data is of no use.
+                Err(_) => return (0, true),
+            }
+            self.data.drain(..sent);
+        }
+        if self.data.is_empty() && self.fin {
+            stream.close();
+        }
+        (sent, self.data.is_empty())
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SendStreams {
+    streams: HashMap<StreamId, SendStream>,
+    /// The streams in each band, in the order that they get to send.  A stream
+    /// that sends moves behind the others in its band, so that they take turns.
+    order: BTreeMap<TransmissionPriority, VecDeque<StreamId>>,
+}
 
 impl SendStreams {
     pub fn get(&self, id: StreamId) -> Res<&SendStream> {
-        self.0.get(&id).ok_or_else(|| Error::InvalidStreamId)
+        self.streams.get(&id).ok_or_else(|| Error::InvalidStreamId)
     }
 
     pub fn get_mut(&mut self, id: StreamId) -> Res<&mut SendStream> {
-        self.0.get_mut(&id).ok_or_else(|| Error::InvalidStreamId)
+        self.streams
+            .get_mut(&id)
+            .ok_or_else(|| Error::InvalidStreamId)
     }
 
     pub fn insert(&mut self, id: StreamId, stream: SendStream) {
-        self.0.insert(id, stream);
+        let priority = stream.priority();
+        if let Some(old) = self.streams.insert(id, stream) {
+            self.unlink(id, old.priority());
+        }
+        self.order.entry(priority).or_default().push_back(id);
+    }
+
+    /// Change the band of a stream.  It hasn't had a turn in the new band, so
+    /// it goes first there.
+    pub fn set_priority(&mut self, id: StreamId, priority: TransmissionPriority) -> Res<()> {
+        let stream = self
+            .streams
+            .get_mut(&id)
+            .ok_or_else(|| Error::InvalidStreamId)?;
+        let old = stream.priority();
+        if old != priority {
+            stream.set_priority(priority);
+            self.unlink(id, old);
+            self.order.entry(priority).or_default().push_front(id);
+        }
+        Ok(())
+    }
+
+    fn unlink(&mut self, id: StreamId, priority: TransmissionPriority) {
+        if let Some(ids) = self.order.get_mut(&priority) {
+            ids.retain(|x| *x != id);
+            if ids.is_empty() {
+                self.order.remove(&priority);
+            }
+        }
     }
 
     pub fn acked(&mut self, token: &StreamRecoveryToken) {
-        if let Some(ss) = self.0.get_mut(&token.id) {
+        if let Some(ss) = self.streams.get_mut(&token.id) {
             ss.mark_as_acked(token.offset, token.length, token.fin);
         }
     }
 
     pub fn reset_acked(&mut self, id: StreamId) {
-        if let Some(ss) = self.0.get_mut(&id) {
+        if let Some(ss) = self.streams.get_mut(&id) {
             ss.reset_acked()
         }
     }
 
     pub fn lost(&mut self, token: &StreamRecoveryToken) {
-        if let Some(ss) = self.0.get_mut(&token.id) {
+        if let Some(ss) = self.streams.get_mut(&token.id) {
             ss.mark_as_lost(token.offset, token.length, token.fin);
         }
     }
 
     pub fn clear(&mut self) {
-        self.0.clear()
+        self.streams.clear();
+        self.order.clear();
     }
 
     pub fn clear_terminal(&mut self) {
-        self.0.retain(|_, stream| !stream.is_terminal())
+        let streams = &mut self.streams;
+        streams.retain(|_, stream| !stream.is_terminal());
+        for ids in self.order.values_mut() {
+            ids.retain(|id| streams.contains_key(id));
+        }
+        self.order.retain(|_, ids| !ids.is_empty());
     }
 
+    /// Get a frame from the first stream that has data that fits.  `stats` counts
+    /// the stream data that is sent, and the times that a critical stream had data
+    /// but a stream in another band sent instead, which should not happen.
     pub(crate) fn get_frame(
         &mut self,
         space: PNSpace,
         remaining: usize,
+        stats: &mut Stats,
     ) -> Option<(Frame, Option<RecoveryToken>)> {
         if space != PNSpace::ApplicationData {
             return None;
         }
 
-        for (stream_id, stream) in self {
-            let final_size = stream.final_size();
-            if let Some((offset, data)) = stream.next_bytes() {
+        let mut critical_skipped = false;
+        for (&priority, ids) in &mut self.order {
+            let streams = &mut self.streams;
+            let found = ids.iter().enumerate().find_map(|(i, &stream_id)| {
+                let stream = streams.get_mut(&stream_id).unwrap();
+                let final_size = stream.final_size();
+                let (offset, data) = stream.next_bytes()?;
                 let data_len = u64::try_from(data.len()).unwrap();
                 let range_has_fin = final_size
                     .map(|fs| fs == offset + data_len)
@@ -798,18 +1016,30 @@ impl SendStreams {
                     );
                     let fin = range_has_fin && length == data.len();
                     debug_assert!(!fin || matches!(frame, Frame::Stream{fin: true, .. }));
-                    stream.mark_as_sent(offset, length, fin);
-
-                    return Some((
-                        frame,
-                        Some(RecoveryToken::Stream(StreamRecoveryToken {
-                            id: *stream_id,
-                            offset,
-                            length,
-                            fin,
-                        })),
-                    ));
+                    let new = stream.mark_as_sent(offset, length, fin);
+                    stats.sent_bytes.stream_data += new as u64;
+                    stats.sent_bytes.stream_retransmitted += (length - new) as u64;
+                    let token = StreamRecoveryToken {
+                        id: stream_id,
+                        offset,
+                        length,
+                        fin,
+                    };
+                    Some((i, frame, token))
+                } else {
+                    if priority == TransmissionPriority::Critical {
+                        critical_skipped = true;
+                    }
+                    None
+                }
+            });
+            if let Some((i, frame, token)) = found {
+                if critical_skipped && priority != TransmissionPriority::Critical {
+                    stats.critical_starved += 1;
                 }
+                // This stream goes last in its band next time.
+                ids.rotate_left(i + 1);
+                return Some((frame, Some(RecoveryToken::Stream(token))));
             }
         }
         None
@@ -821,7 +1051,7 @@ impl<'a> IntoIterator for &'a mut SendStreams {
     type IntoIter = IterMut<'a, StreamId, SendStream>;
 
     fn into_iter(self) -> IterMut<'a, StreamId, SendStream> {
-        self.0.iter_mut()
+        self.streams.iter_mut()
     }
 }
 
@@ -1168,15 +1398,20 @@ mod tests {
         s.close();
 
         let mut ss = SendStreams::default();
+        let mut stats = Stats::default();
         ss.insert(0.into(), s);
 
-        let (_f1, f1_token) = ss.get_frame(PNSpace::ApplicationData, 6).unwrap();
+        let (_f1, f1_token) = ss
+            .get_frame(PNSpace::ApplicationData, 6, &mut stats)
+            .unwrap();
         assert!(matches!(&f1_token, Some(RecoveryToken::Stream(x)) if !x.fin));
-        let (_f2, f2_token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        let (_f2, f2_token) = ss
+            .get_frame(PNSpace::ApplicationData, 100, &mut stats)
+            .unwrap();
         assert!(matches!(&f2_token, Some(RecoveryToken::Stream(x)) if x.fin));
 
         // Should be no more data to frame
-        let f3 = ss.get_frame(PNSpace::ApplicationData, 100);
+        let f3 = ss.get_frame(PNSpace::ApplicationData, 100, &mut stats);
         assert!(matches!(f3, None));
 
         // Mark frame 1 as lost
@@ -1188,7 +1423,9 @@ mod tests {
 
         // Next frame should not set fin even though stream has fin but frame
         // does not include end of stream
-        let (_f4, f4_token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        let (_f4, f4_token) = ss
+            .get_frame(PNSpace::ApplicationData, 100, &mut stats)
+            .unwrap();
         assert!(matches!(f4_token, Some(RecoveryToken::Stream(x)) if !x.fin));
 
         // Mark frame 2 as lost
@@ -1199,7 +1436,9 @@ mod tests {
         ss.lost(&f2_token);
 
         // Next frame should set fin because it includes end of stream
-        let (_f5, f5_token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        let (_f5, f5_token) = ss
+            .get_frame(PNSpace::ApplicationData, 100, &mut stats)
+            .unwrap();
         assert!(matches!(f5_token, Some(RecoveryToken::Stream(x)) if x.fin));
     }
 
@@ -1215,20 +1454,25 @@ mod tests {
         s.send(&[0; 10]).unwrap();
 
         let mut ss = SendStreams::default();
+        let mut stats = Stats::default();
         ss.insert(0.into(), s);
 
-        let (_f1, f1_token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        let (_f1, f1_token) = ss
+            .get_frame(PNSpace::ApplicationData, 100, &mut stats)
+            .unwrap();
         assert!(matches!(&f1_token, Some(RecoveryToken::Stream(x)) if x.offset == 0));
         assert!(matches!(&f1_token, Some(RecoveryToken::Stream(x)) if x.length == 10));
         assert!(matches!(&f1_token, Some(RecoveryToken::Stream(x)) if !x.fin));
 
         // Should be no more data to frame
-        let f2 = ss.get_frame(PNSpace::ApplicationData, 100);
+        let f2 = ss.get_frame(PNSpace::ApplicationData, 100, &mut stats);
         assert!(matches!(f2, None));
 
         ss.get_mut(0.into()).unwrap().close();
 
-        let (_f2, f2_token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        let (_f2, f2_token) = ss
+            .get_frame(PNSpace::ApplicationData, 100, &mut stats)
+            .unwrap();
         assert!(matches!(&f2_token, Some(RecoveryToken::Stream(x)) if x.offset == 10));
         assert!(matches!(&f2_token, Some(RecoveryToken::Stream(x)) if x.length == 0));
         assert!(matches!(&f2_token, Some(RecoveryToken::Stream(x)) if x.fin));
@@ -1241,7 +1485,9 @@ mod tests {
         ss.lost(&f2_token);
 
         // Next frame should set fin
-        let (_f3, f3_token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        let (_f3, f3_token) = ss
+            .get_frame(PNSpace::ApplicationData, 100, &mut stats)
+            .unwrap();
         assert!(matches!(&f3_token, Some(RecoveryToken::Stream(x)) if x.offset == 10));
         assert!(matches!(&f3_token, Some(RecoveryToken::Stream(x)) if x.length == 0));
         assert!(matches!(&f3_token, Some(RecoveryToken::Stream(x)) if x.fin));
@@ -1254,12 +1500,116 @@ mod tests {
         ss.lost(&f1_token);
 
         // Next frame should set fin and include all data
-        let (_f4, f4_token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        let (_f4, f4_token) = ss
+            .get_frame(PNSpace::ApplicationData, 100, &mut stats)
+            .unwrap();
         assert!(matches!(&f4_token, Some(RecoveryToken::Stream(x)) if x.offset == 0));
         assert!(matches!(&f4_token, Some(RecoveryToken::Stream(x)) if x.length == 10));
         assert!(matches!(&f4_token, Some(RecoveryToken::Stream(x)) if x.fin));
     }
 
+    fn priority_streams(priorities: &[TransmissionPriority]) -> SendStreams {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr.borrow_mut().conn_increase_max_credit(10_000);
+        let mut ss = SendStreams::default();
+        for (i, p) in priorities.iter().enumerate() {
+            let id = StreamId::from(u64::try_from(i).unwrap() * 4);
+            let mut s = SendStream::new(id, 1000, flow_mgr.clone(), ConnectionEvents::default());
+            s.set_priority(*p);
+            s.send(&[0; 1000]).unwrap();
+            ss.insert(id, s);
+        }
+        ss
+    }
+
+    fn next_sender(ss: &mut SendStreams, stats: &mut Stats) -> u64 {
+        match ss.get_frame(PNSpace::ApplicationData, 100, stats) {
+            Some((_, Some(RecoveryToken::Stream(t)))) => t.id.as_u64(),
+            f => panic!("unexpected frame {:?}", f),
+        }
+    }
+
+    #[test]
+    fn critical_first() {
+        let mut ss = priority_streams(&[
+            TransmissionPriority::Low,
+            TransmissionPriority::Normal,
+            TransmissionPriority::Critical,
+        ]);
+        let mut stats = Stats::default();
+        for _ in 0..3 {
+            assert_eq!(next_sender(&mut ss, &mut stats), 8);
+        }
+        ss.set_priority(4.into(), TransmissionPriority::Critical)
+            .unwrap();
+        assert_eq!(next_sender(&mut ss, &mut stats), 4);
+        assert_eq!(stats.critical_starved, 0);
+    }
+
+    #[test]
+    fn take_turns() {
+        let mut ss = priority_streams(&[
+            TransmissionPriority::Normal,
+            TransmissionPriority::Normal,
+            TransmissionPriority::Normal,
+            TransmissionPriority::Low,
+        ]);
+        let mut stats = Stats::default();
+        let order = (0..6)
+            .map(|_| next_sender(&mut ss, &mut stats))
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![0, 4, 8, 0, 4, 8]);
+    }
+
+    #[test]
+    fn change_band() {
+        let mut ss = priority_streams(&[
+            TransmissionPriority::Normal,
+            TransmissionPriority::Normal,
+            TransmissionPriority::Normal,
+        ]);
+        let mut stats = Stats::default();
+        ss.set_priority(0.into(), TransmissionPriority::Low)
+            .unwrap();
+        let order = (0..4)
+            .map(|_| next_sender(&mut ss, &mut stats))
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![4, 8, 4, 8]);
+
+        // Coming back, the stream goes first in its band.
+        ss.set_priority(0.into(), TransmissionPriority::Normal)
+            .unwrap();
+        let order = (0..3)
+            .map(|_| next_sender(&mut ss, &mut stats))
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![0, 4, 8]);
+        assert_eq!(
+            ss.set_priority(12.into(), TransmissionPriority::Low),
+            Err(Error::InvalidStreamId)
+        );
+    }
+
+    /// A critical stream that can't fit is counted when another stream sends instead.
+    #[test]
+    fn critical_starved() {
+        let mut ss = priority_streams(&[TransmissionPriority::Normal]);
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr.borrow_mut().conn_increase_max_credit(100_000);
+        let id = StreamId::from(1 << 20);
+        let mut s = SendStream::new(id, 100_000, flow_mgr, ConnectionEvents::default());
+        s.set_priority(TransmissionPriority::Critical);
+        s.send(&[0; 100]).unwrap();
+        ss.insert(id, s);
+
+        // The critical stream has a longer ID, so only the other one fits.
+        let mut stats = Stats::default();
+        match ss.get_frame(PNSpace::ApplicationData, 4, &mut stats) {
+            Some((_, Some(RecoveryToken::Stream(t)))) => assert_eq!(t.id.as_u64(), 0),
+            f => panic!("unexpected frame {:?}", f),
+        }
+        assert_eq!(stats.critical_starved, 1);
+    }
+
     #[test]
     fn send_atomic() {
         let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
@@ -1319,4 +1669,97 @@ mod tests {
         // assert that atomic writing 10 byte works
         assert_eq!(s.send_atomic(b"abcdefghij").unwrap(), 10);
     }
+
+    #[test]
+    fn critical_reserve() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr.borrow_mut().conn_increase_max_credit(10);
+        flow_mgr.borrow_mut().set_critical_reserve(4);
+        let conn_events = ConnectionEvents::default();
+        let mut s = SendStream::new(0.into(), 100, Rc::clone(&flow_mgr), conn_events.clone());
+        let mut critical = SendStream::new(2.into(), 100, Rc::clone(&flow_mgr), conn_events);
+        critical.set_priority(TransmissionPriority::Critical);
+
+        // The other stream can't use the reserve.  While the reserve is unused,
+        // the connection isn't blocked, so there is no DATA_BLOCKED.
+        assert_eq!(s.avail(), 6);
+        assert_eq!(s.send(b"abcdefghij"), Ok(6));
+        assert_eq!(s.avail(), 0);
+        assert!(flow_mgr.borrow_mut().next().is_none());
+
+        // Once the critical stream wants more than is left, it is.
+        assert_eq!(critical.avail(), 4);
+        assert_eq!(critical.send(b"abcdef"), Ok(4));
+        assert_eq!(flow_mgr.borrow().conn_credit_avail(), 0);
+        assert_eq!(
+            flow_mgr.borrow_mut().next().unwrap(),
+            Frame::DataBlocked { data_limit: 10 }
+        );
+
+        // More credit goes to the other stream only once the reserve is back.
+        flow_mgr.borrow_mut().conn_increase_max_credit(12);
+        assert_eq!(s.avail(), 0);
+        assert_eq!(critical.avail(), 2);
+        flow_mgr.borrow_mut().conn_increase_max_credit(20);
+        assert_eq!(s.avail(), 6);
+    }
+
+    #[test]
+    fn send_after_close() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr.borrow_mut().conn_increase_max_credit(100);
+        let conn_events = ConnectionEvents::default();
+        let mut s = SendStream::new(4.into(), 100, Rc::clone(&flow_mgr), conn_events);
+        assert_eq!(s.send(b"abc"), Ok(3));
+        s.close();
+        assert_eq!(s.send(b"abc"), Err(Error::FinalSizeError));
+        assert_eq!(s.send_atomic(b"abc"), Err(Error::FinalSizeError));
+        assert_eq!(s.final_size(), Some(3));
+        assert_eq!(flow_mgr.borrow().conn_credit_avail(), 97);
+    }
+
+    #[test]
+    fn send_after_reset() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr.borrow_mut().conn_increase_max_credit(100);
+        let conn_events = ConnectionEvents::default();
+        let mut s = SendStream::new(4.into(), 100, Rc::clone(&flow_mgr), conn_events);
+        assert_eq!(s.send(b"abcdef"), Ok(6));
+        // Send some of it.
+        let (offset, data) = s.next_bytes().unwrap();
+        assert_eq!(offset, 0);
+        assert_eq!(data.len(), 6);
+        s.mark_as_sent(0, 2, false);
+
+        s.reset(1);
+        assert_eq!(s.send(b"abc"), Err(Error::StreamReset));
+        // The four bytes that were never sent don't count against flow control.
+        assert_eq!(flow_mgr.borrow().conn_credit_avail(), 98);
+        assert_eq!(
+            flow_mgr.borrow_mut().next(),
+            Some(Frame::ResetStream {
+                stream_id: 4.into(),
+                application_error_code: 1,
+                final_size: 2,
+            })
+        );
+
+        // A later STOP_SENDING doesn't change the error.
+        s.stop_sending(2);
+        assert_eq!(s.send(b"abc"), Err(Error::StreamReset));
+    }
+
+    #[test]
+    fn send_after_stop_sending() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr.borrow_mut().conn_increase_max_credit(100);
+        let conn_events = ConnectionEvents::default();
+        let mut s = SendStream::new(4.into(), 100, Rc::clone(&flow_mgr), conn_events);
+        assert_eq!(s.send(b"abc"), Ok(3));
+        s.stop_sending(7);
+        assert_eq!(s.send(b"abc"), Err(Error::StreamStopped(7)));
+        assert_eq!(s.check_can_send(), Err(Error::StreamStopped(7)));
+        assert_eq!(s.avail(), 0);
+        assert_eq!(flow_mgr.borrow().conn_credit_avail(), 100);
+    }
 }
@@ -0,0 +1,242 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The sending side of loss recovery: a congestion controller, the pacer,
+// and any limit on the send rate.
+
+use std::cmp::max;
+use std::fmt::{self, Display};
+use std::time::{Duration, Instant};
+
+use crate::cc::{CongestionControl, CongestionState, NewReno, MAX_DATAGRAM_SIZE};
+use crate::pace::Pacer;
+use crate::stats::CongestionStats;
+use crate::tracking::SentPacket;
+use neqo_common::qdebug;
+
+/// The number of packets we allow to burst from the pacer.
+pub(crate) const PACING_BURST_SIZE: usize = 2;
+/// The period over which a fixed send rate is measured.
+const SEND_RATE_PERIOD: Duration = Duration::from_secs(1);
+
+/// A fixed limit on the rate of sending, which applies regardless of the
+/// congestion window.
+#[derive(Debug)]
+struct RateLimit {
+    /// The rate, in bytes per second.
+    rate: usize,
+    /// Whether the limit applies during the handshake.
+    include_handshake: bool,
+    /// The leaky bucket that enforces the limit, which is created when it starts.
+    limiter: Option<Pacer>,
+}
+
+#[derive(Debug)]
+pub struct PacketSender {
+    cc: Box<dyn CongestionControl>,
+    pacer: Option<Pacer>,
+    /// Packets that the pacer would release within this time are sent straight away.
+    pacing_granularity: Duration,
+    rate_limit: Option<RateLimit>,
+}
+
+impl Default for PacketSender {
+    fn default() -> Self {
+        Self::new(Box::new(NewReno::default()))
+    }
+}
+
+impl Display for PacketSender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.cc)?;
+        if let Some(p) = &self.pacer {
+            write!(f, " {}", p)?;
+        }
+        Ok(())
+    }
+}
+
+impl PacketSender {
+    #[must_use]
+    pub fn new(cc: Box<dyn CongestionControl>) -> Self {
+        Self {
+            cc,
+            pacer: None,
+            pacing_granularity: Duration::from_secs(0),
+            rate_limit: None,
+        }
+    }
+
+    #[must_use]
+    pub fn cwnd(&self) -> usize {
+        self.cc.cwnd()
+    }
+
+    #[must_use]
+    pub fn ssthresh(&self) -> usize {
+        self.cc.ssthresh()
+    }
+
+    #[must_use]
+    pub fn state(&self) -> CongestionState {
+        self.cc.state()
+    }
+
+    #[must_use]
+    pub fn cwnd_avail(&self) -> usize {
+        self.cc.cwnd_avail()
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> CongestionStats {
+        CongestionStats {
+            cwnd: self.cc.cwnd(),
+            ssthresh: self.cc.ssthresh(),
+            bytes_in_flight: self.cc.bytes_in_flight(),
+        }
+    }
+
+    pub fn jump_start(&mut self, cwnd: usize) {
+        self.cc.jump_start(cwnd);
+    }
+
+    /// Start again on a new path.  A rate limit applies to any path, so it stays.
+    pub fn restart(&mut self) {
+        self.cc.restart();
+    }
+
+    pub fn set_idle_restart(&mut self, idle_restart: bool) {
+        self.cc.set_idle_restart(idle_restart);
+    }
+
+    pub fn restart_after_idle(&mut self, now: Instant, pto: Duration) -> bool {
+        self.cc.restart_after_idle(now, pto)
+    }
+
+    pub fn on_packets_acked(&mut self, acked_pkts: &[SentPacket]) {
+        self.cc.on_packets_acked(acked_pkts);
+    }
+
+    pub fn on_packets_lost(
+        &mut self,
+        now: Instant,
+        prev_largest_acked_sent: Option<Instant>,
+        pto: Duration,
+        lost_packets: &[SentPacket],
+    ) {
+        self.cc
+            .on_packets_lost(now, prev_largest_acked_sent, pto, lost_packets);
+    }
+
+    pub fn discard(&mut self, pkt: &SentPacket) {
+        self.cc.discard(pkt);
+    }
+
+    pub fn on_packet_sent(&mut self, pkt: &SentPacket, rtt: Duration) {
+        self.pacer
+            .as_mut()
+            .unwrap()
+            .spend(pkt.time_sent, rtt, self.cc.cwnd(), pkt.size);
+        if let Some(RateLimit {
+            rate,
+            limiter: Some(limiter),
+            ..
+        }) = &mut self.rate_limit
+        {
+            limiter.spend_at_rate(pkt.time_sent, SEND_RATE_PERIOD, *rate, pkt.size);
+        }
+        self.cc.on_packet_sent(pkt);
+    }
+
+    pub fn suspended(&mut self, by: Duration) {
+        self.cc.suspended(by);
+    }
+
+    pub fn start_pacer(&mut self, now: Instant) {
+        // Start the pacer with a small burst size.
+        self.pacer = Some(Pacer::new(
+            now,
+            MAX_DATAGRAM_SIZE * PACING_BURST_SIZE,
+            MAX_DATAGRAM_SIZE,
+        ));
+        if self
+            .rate_limit
+            .as_ref()
+            .map_or(false, |rl| rl.include_handshake)
+        {
+            self.start_rate_limit(now);
+        }
+    }
+
+    /// Limit sending to `rate` bytes per second.  The limit takes effect when
+    /// the pacer starts if `include_handshake` is set, or when `start_rate_limit`
+    /// is called otherwise.
+    pub fn set_max_send_rate(&mut self, rate: usize, include_handshake: bool) {
+        debug_assert!(self.pacer.is_none());
+        self.rate_limit = Some(RateLimit {
+            rate,
+            include_handshake,
+            limiter: None,
+        });
+    }
+
+    /// Start enforcing any send rate limit.  This does nothing if there is no limit
+    /// or if it is already being enforced.
+    pub fn start_rate_limit(&mut self, now: Instant) {
+        if let Some(rl) = &mut self.rate_limit {
+            if rl.limiter.is_none() {
+                qdebug!("Start limiting send rate to {}", rl.rate);
+                rl.limiter = Some(Pacer::new(
+                    now,
+                    MAX_DATAGRAM_SIZE * PACING_BURST_SIZE,
+                    MAX_DATAGRAM_SIZE,
+                ));
+            }
+        }
+    }
+
+    /// The next time that the send rate limit allows a packet to be sent, if there is one.
+    fn next_rate_limited(&self) -> Option<Instant> {
+        self.rate_limit.as_ref().and_then(|rl| {
+            rl.limiter
+                .as_ref()
+                .map(|l| l.next_at_rate(SEND_RATE_PERIOD, rl.rate))
+        })
+    }
+
+    /// Determine whether the send rate limit prevents sending at `now`.
+    #[must_use]
+    pub fn rate_limited(&self, now: Instant) -> bool {
+        self.next_rate_limited().map_or(false, |t| t > now)
+    }
+
+    /// Pace no more finely than `granularity`, so that whoever drives the
+    /// connection isn't woken for every packet.
+    pub fn set_pacing_granularity(&mut self, granularity: Duration) {
+        self.pacing_granularity = granularity;
+    }
+
+    /// Determine whether the pacer or the send rate limit holds back packets at `now`.
+    #[must_use]
+    pub fn paced(&self, now: Instant, rtt: Duration) -> bool {
+        self.next_paced(rtt)
+            .map_or(false, |t| t > now + self.pacing_granularity)
+    }
+
+    pub fn next_paced(&self, rtt: Duration) -> Option<Instant> {
+        // Only pace if there are bytes in flight.
+        let paced = if self.cc.bytes_in_flight() > 0 {
+            Some(self.pacer.as_ref().unwrap().next(rtt, self.cc.cwnd()))
+        } else {
+            None
+        };
+        // A rate limit applies even when nothing is in flight.
+        match (paced, self.next_rate_limited()) {
+            (Some(p), Some(r)) => Some(max(p, r)),
+            (p, r) => p.or(r),
+        }
+    }
+}
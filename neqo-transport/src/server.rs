@@ -7,8 +7,8 @@
 // This file implements a server that can handle multiple connections.
 
 use neqo_common::{
-    self as common, hex, matches, qdebug, qerror, qinfo, qlog::NeqoQlog, qtrace, qwarn,
-    timer::Timer, Datagram, Decoder, Encoder, Role,
+    hex, matches, qdebug, qerror, qinfo, qlog::NeqoQlog, qtrace, qwarn, timer::Timer, Datagram,
+    Decoder, Encoder, Role,
 };
 use neqo_crypto::{
     constants::{TLS_AES_128_GCM_SHA256, TLS_VERSION_1_3},
@@ -18,12 +18,21 @@ use neqo_crypto::{
 
 use crate::cid::{ConnectionId, ConnectionIdDecoder, ConnectionIdManager, ConnectionIdRef};
 use crate::connection::{Connection, Output, State};
-use crate::packet::{PacketBuilder, PacketType, PublicPacket};
+use crate::crypto::CryptoStates;
+use crate::frame::Frame;
+use crate::packet::{
+    DecryptedPacket, PacketBuilder, PacketType, PublicPacket, MIN_INITIAL_PACKET_SIZE,
+};
+use crate::params::ConnectionParameters;
+use crate::path::normalize_address;
+use crate::stateless_reset::StatelessResetKey;
+use crate::tparams::{TransportParameter, TransportParameterId};
 use crate::Res;
 
 use std::cell::RefCell;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
+#[cfg(feature = "qlog")]
 use std::fs::OpenOptions;
 use std::mem;
 use std::net::{IpAddr, SocketAddr};
@@ -38,9 +47,6 @@ pub enum InitialResult {
     Retry(Vec<u8>),
 }
 
-/// MIN_INITIAL_PACKET_SIZE is the smallest packet that can be used to establish
-/// a new connection across all QUIC versions this server supports.
-const MIN_INITIAL_PACKET_SIZE: usize = 1200;
 const TIMER_GRANULARITY: Duration = Duration::from_millis(10);
 const TIMER_CAPACITY: usize = 16384;
 
@@ -68,6 +74,17 @@ impl DerefMut for ServerConnectionState {
     }
 }
 
+/// Whether the server checks the address of a client before it accepts a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidateAddress {
+    /// Accept connections without checking the address of the client.
+    Never,
+    /// Send a Retry in response to every Initial that doesn't carry a token.
+    /// The connection is only accepted when the client comes back with the
+    /// token from that Retry, from the same address, before the token expires.
+    Always,
+}
+
 enum RetryTokenResult {
     Pass,
     Valid(ConnectionId),
@@ -77,7 +94,7 @@ enum RetryTokenResult {
 
 struct RetryToken {
     /// Whether to send a Retry.
-    require_retry: bool,
+    validate: ValidateAddress,
     /// A self-encryption object used for protecting Retry tokens.
     self_encrypt: SelfEncrypt,
     /// When this object was created.
@@ -87,7 +104,7 @@ struct RetryToken {
 impl RetryToken {
     fn new(now: Instant) -> Res<Self> {
         Ok(Self {
-            require_retry: false,
+            validate: ValidateAddress::Never,
             self_encrypt: SelfEncrypt::new(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256)?,
             start_time: now,
         })
@@ -96,7 +113,9 @@ impl RetryToken {
     fn encode_peer_address(peer_address: SocketAddr) -> Vec<u8> {
         // Let's be "clever" by putting the peer's address in the AAD.
         // We don't need to encode these into the token as they should be
-        // available when we need to check the token.
+        // available when we need to check the token.  The address is
+        // normalized, so that it doesn't matter if a dual-stack socket is used.
+        let peer_address = normalize_address(peer_address);
         let mut encoded_address = Encoder::default();
         match peer_address.ip() {
             IpAddr::V4(a) => {
@@ -123,15 +142,15 @@ impl RetryToken {
         let mut token = Encoder::default();
         const EXPIRATION: Duration = Duration::from_secs(5);
         let end = now + EXPIRATION;
-        let end_millis = u32::try_from(end.duration_since(self.start_time).as_millis())?;
+        let end_millis = u32::try_from(end.saturating_duration_since(self.start_time).as_millis())?;
         token.encode_uint(4, end_millis);
         token.encode(dcid);
         let peer_addr = Self::encode_peer_address(peer_address);
         Ok(self.self_encrypt.seal(&peer_addr, &token)?)
     }
 
-    pub fn set_retry_required(&mut self, retry: bool) {
-        self.require_retry = retry;
+    pub fn set_validation(&mut self, validate: ValidateAddress) {
+        self.validate = validate;
     }
 
     /// Decrypts `token` and returns the connection Id it contains.
@@ -168,7 +187,7 @@ impl RetryToken {
         now: Instant,
     ) -> RetryTokenResult {
         if token.is_empty() {
-            if self.require_retry {
+            if self.validate == ValidateAddress::Always {
                 RetryTokenResult::Validate
             } else {
                 RetryTokenResult::Pass
@@ -216,6 +235,14 @@ pub struct Server {
     retry: RetryToken,
     /// Directory to create qlog traces in
     qlog_dir: Option<PathBuf>,
+    /// Transport parameters that override the defaults for new connections.
+    local_tparams: Vec<(TransportParameterId, TransportParameter)>,
+    /// The transport parameters that connections start with.
+    conn_params: ConnectionParameters,
+    /// Whether clients can move new connections to another address.
+    allow_migration: bool,
+    /// The key for stateless reset tokens.
+    reset_key: Rc<StatelessResetKey>,
 }
 
 impl Server {
@@ -245,16 +272,58 @@ impl Server {
             timers: Timer::new(now, TIMER_GRANULARITY, TIMER_CAPACITY),
             retry: RetryToken::new(now)?,
             qlog_dir: None,
+            local_tparams: Vec::new(),
+            conn_params: ConnectionParameters::default(),
+            allow_migration: false,
+            reset_key: Rc::new(StatelessResetKey::random()?),
         })
     }
 
+    /// Set the key that stateless reset tokens are derived from.  By default, a random
+    /// key is used, so a server that restarts can't reset the connections that it had
+    /// before.  A server that uses the same key after restarting can; the key needs to
+    /// be secret, and at least 32 bytes long.  This affects connections that are
+    /// accepted from now on.
+    pub fn set_stateless_reset_key(&mut self, key: &[u8]) -> Res<()> {
+        self.reset_key = Rc::new(StatelessResetKey::new(key)?);
+        Ok(())
+    }
+
     /// Set or clear directory to create logs of connection events in QLOG format.
+    /// Without the `qlog` feature, no logs are created.
     pub fn set_qlog_dir(&mut self, dir: Option<PathBuf>) {
         self.qlog_dir = dir;
     }
 
-    pub fn set_retry_required(&mut self, require_retry: bool) {
-        self.retry.set_retry_required(require_retry);
+    /// Send the transport parameters in `params` on connections that are accepted
+    /// from now on.  Parameters from `set_local_tparam` are set after these.
+    /// # Errors
+    /// `InvalidParameters` if one of `params` can't be sent.
+    pub fn set_connection_parameters(&mut self, params: ConnectionParameters) -> Res<()> {
+        params.validate()?;
+        self.conn_params = params;
+        Ok(())
+    }
+
+    /// Set a local transport parameter for connections that are accepted from now on,
+    /// see `Connection::set_local_tparam`.
+    pub fn set_local_tparam(&mut self, tp: TransportParameterId, value: TransportParameter) {
+        self.local_tparams.retain(|(t, _)| *t != tp);
+        self.local_tparams.push((tp, value));
+    }
+
+    /// Set whether clients can move connections that are accepted from now on to
+    /// another address, see `Connection::set_allow_migration`.
+    pub fn set_allow_migration(&mut self, allow: bool) {
+        self.allow_migration = allow;
+    }
+
+    /// Set whether the addresses of clients are validated with Retry before
+    /// connections are accepted.  This is `ValidateAddress::Never` by default.
+    /// Packets that carry a token that is expired or was not made by this
+    /// server for the address the packet comes from are dropped.
+    pub fn set_validation(&mut self, validate: ValidateAddress) {
+        self.retry.set_validation(validate);
     }
 
     fn remove_timer(&mut self, c: &StateRef) {
@@ -379,6 +448,7 @@ impl Server {
         }
     }
 
+    #[cfg(feature = "qlog")]
     fn create_qlog_trace(&self, attempt_key: &AttemptKey) -> Option<NeqoQlog> {
         if let Some(qlog_dir) = &self.qlog_dir {
             let mut qlog_path = qlog_dir.to_path_buf();
@@ -402,7 +472,7 @@ impl Server {
                         Some("Neqo server qlog".to_string()),
                         None,
                         std::time::Instant::now(),
-                        common::qlog::new_trace(Role::Server),
+                        neqo_common::qlog::new_trace(Role::Server),
                         Box::new(f),
                     );
                     let n_qlog = NeqoQlog::new(streamer, qlog_path);
@@ -429,6 +499,68 @@ impl Server {
         }
     }
 
+    #[cfg(not(feature = "qlog"))]
+    fn create_qlog_trace(&self, _attempt_key: &AttemptKey) -> Option<NeqoQlog> {
+        if self.qlog_dir.is_some() {
+            qwarn!("Not creating a qlog trace, qlog support is not built");
+        }
+        None
+    }
+
+    /// Check that the first packet in `dgram` is an Initial packet that starts a
+    /// handshake before committing to creating a connection, which is expensive.
+    /// The packet has to decrypt, it has to contain a CRYPTO frame, and it can only
+    /// contain frames that are allowed in Initial packets.
+    /// This returns the Initial keys and the decrypted packet, so that the
+    /// connection can use them.
+    fn check_initial(
+        &self,
+        dgram: &Datagram,
+        now: Instant,
+    ) -> Option<(CryptoStates, DecryptedPacket)> {
+        let res = PublicPacket::decode(&dgram[..], self.cid_manager.borrow().as_decoder());
+        let packet = match res {
+            Ok((packet, _)) if packet.packet_type() == PacketType::Initial => packet,
+            _ => return None,
+        };
+        if !packet.is_valid_initial() {
+            qdebug!([self], "Dropping invalid Initial");
+            return None;
+        }
+        let mut states = CryptoStates::new_initial(Role::Server, packet.dcid());
+        let payload = match packet.decrypt(&mut states, now) {
+            Ok(payload) => payload,
+            Err(e) => {
+                qdebug!([self], "Dropping Initial that didn't decrypt: {:?}", e);
+                return None;
+            }
+        };
+
+        let mut dec = Decoder::from(&payload[..]);
+        let mut crypto = false;
+        while dec.remaining() > 0 {
+            match Frame::decode(&mut dec) {
+                Ok(f) if f.is_allowed(PacketType::Initial) => {
+                    crypto |= matches!(f, Frame::Crypto { .. });
+                }
+                Ok(f) => {
+                    qdebug!([self], "Dropping Initial with {:?} frame", f.get_type());
+                    return None;
+                }
+                Err(e) => {
+                    qdebug!([self], "Dropping Initial with bad frame: {:?}", e);
+                    return None;
+                }
+            }
+        }
+        if crypto {
+            Some((states, payload))
+        } else {
+            qdebug!([self], "Dropping Initial without CRYPTO frames");
+            None
+        }
+    }
+
     fn accept_connection(
         &mut self,
         attempt_key: AttemptKey,
@@ -436,6 +568,7 @@ impl Server {
         dgram: Datagram,
         now: Instant,
     ) -> Option<Datagram> {
+        let initial = self.check_initial(&dgram, now)?;
         qinfo!([self], "Accept connection {:?}", attempt_key);
         // The internal connection ID manager that we use is not used directly.
         // Instead, wrap it so that we can save connection IDs.
@@ -443,19 +576,27 @@ impl Server {
             c: None,
             cid_manager: self.cid_manager.clone(),
             connections: self.connections.clone(),
+            reset_key: Rc::clone(&self.reset_key),
         }));
 
-        let sconn = Connection::new_server(
+        let sconn = Connection::new_server_with_initial(
             &self.certs,
             &self.protocols,
             &self.anti_replay,
             cid_mgr.clone(),
+            initial,
+            self.conn_params.clone(),
         );
 
         if let Ok(mut c) = sconn {
             if let Some(odcid) = orig_dcid {
                 c.original_connection_id(&odcid);
             }
+            for (tp, value) in &self.local_tparams {
+                // A new connection is always in the initial state.
+                c.set_local_tparam(*tp, value.clone()).unwrap();
+            }
+            c.set_allow_migration(self.allow_migration).unwrap();
             c.set_qlog(self.create_qlog_trace(&attempt_key));
             let c = Rc::new(RefCell::new(ServerConnectionState {
                 c,
@@ -492,9 +633,8 @@ impl Server {
         }
 
         if packet.packet_type() == PacketType::Short {
-            // TODO send a stateless reset here.
             qtrace!([self], "Short header packet for an unknown connection");
-            return None;
+            return self.stateless_reset(packet.dcid(), &dgram);
         }
 
         if dgram.len() < MIN_INITIAL_PACKET_SIZE {
@@ -520,6 +660,25 @@ impl Server {
         }
     }
 
+    /// Make a stateless reset in response to `dgram`, which was for a connection that
+    /// this server doesn't have.
+    fn stateless_reset(&self, cid: &ConnectionIdRef, dgram: &Datagram) -> Option<Datagram> {
+        match self.reset_key.make_reset(cid, dgram.len()) {
+            Ok(Some(reset)) => {
+                qdebug!([self], "Send stateless reset for {:?}", cid);
+                Some(Datagram::new(dgram.destination(), dgram.source(), reset))
+            }
+            Ok(None) => {
+                qtrace!([self], "Too short for a stateless reset");
+                None
+            }
+            Err(e) => {
+                qwarn!([self], "Unable to make a stateless reset: {:?}", e);
+                None
+            }
+        }
+    }
+
     /// Iterate through the pending connections looking for any that might want
     /// to send a datagram.  Stop at the first one that does.
     fn process_next_output(&mut self, now: Instant) -> Option<Datagram> {
@@ -540,7 +699,9 @@ impl Server {
 
     fn next_time(&mut self, now: Instant) -> Option<Duration> {
         if self.waiting.is_empty() {
-            self.timers.next_time().map(|x| x - now)
+            self.timers
+                .next_time()
+                .map(|x| x.saturating_duration_since(now))
         } else {
             Some(Duration::new(0, 0))
         }
@@ -620,6 +781,7 @@ struct ServerConnectionIdManager {
     c: Option<StateRef>,
     connections: ConnectionTableRef,
     cid_manager: CidMgr,
+    reset_key: Rc<StatelessResetKey>,
 }
 
 impl ConnectionIdDecoder for ServerConnectionIdManager {
@@ -643,6 +805,12 @@ impl ConnectionIdManager for ServerConnectionIdManager {
     fn as_decoder(&self) -> &dyn ConnectionIdDecoder {
         self
     }
+    fn stateless_reset_token(&self, cid: &[u8]) -> Option<[u8; 16]> {
+        self.reset_key.token(cid).ok()
+    }
+    fn retire_cid(&mut self, cid: &[u8]) {
+        self.connections.borrow_mut().remove(cid);
+    }
 }
 
 impl ::std::fmt::Display for Server {
@@ -0,0 +1,222 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Noticing when a peer stops increasing flow control limits.  Nothing in the
+// protocol makes a peer send MAX_DATA or MAX_STREAM_DATA, so a peer that stops
+// leaves a transfer hanging on a connection that is otherwise fine.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::frame::Frame;
+use crate::stats::FlowControlStall;
+use crate::stream_id::StreamId;
+
+/// How long sending can be blocked on one flow control limit before that is
+/// reported as a stall.
+pub const DEFAULT_FLOW_CONTROL_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct Blocked {
+    limit: u64,
+    since: Instant,
+    reported: bool,
+}
+
+/// Tracks which limits sending is blocked on.  The key is the stream, or `None`
+/// for the connection.  A limit is only tracked once the peer has acknowledged
+/// the DATA_BLOCKED or STREAM_DATA_BLOCKED frame for it, because until then the
+/// peer might not know.
+#[derive(Debug)]
+pub struct StallDetector {
+    timeout: Option<Duration>,
+    blocked: HashMap<Option<StreamId>, Blocked>,
+}
+
+impl Default for StallDetector {
+    fn default() -> Self {
+        Self {
+            timeout: Some(DEFAULT_FLOW_CONTROL_STALL_TIMEOUT),
+            blocked: HashMap::new(),
+        }
+    }
+}
+
+impl StallDetector {
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+        if timeout.is_none() {
+            self.blocked.clear();
+        }
+    }
+
+    /// The peer acknowledged a frame saying that sending is blocked at `limit`.
+    /// The time starts now, unless it already started for the same limit.
+    pub fn blocked(&mut self, id: Option<StreamId>, limit: u64, now: Instant) {
+        if self.timeout.is_none() {
+            return;
+        }
+        match self.blocked.get(&id) {
+            Some(b) if b.limit == limit => {}
+            _ => {
+                self.blocked.insert(
+                    id,
+                    Blocked {
+                        limit,
+                        since: now,
+                        reported: false,
+                    },
+                );
+            }
+        }
+    }
+
+    /// A frame that the peer acknowledged, which only matters if it said that
+    /// sending is blocked.
+    pub fn frame_acked(&mut self, frame: &Frame, now: Instant) {
+        match frame {
+            Frame::DataBlocked { data_limit } => self.blocked(None, *data_limit, now),
+            Frame::StreamDataBlocked {
+                stream_id,
+                stream_data_limit,
+            } => self.blocked(Some(*stream_id), *stream_data_limit, now),
+            _ => {}
+        }
+    }
+
+    /// The peer raised a limit to `limit`.  Anything blocked below that isn't
+    /// any more.
+    pub fn limit_raised(&mut self, id: Option<StreamId>, limit: u64) {
+        if self.blocked.get(&id).map_or(false, |b| b.limit < limit) {
+            self.blocked.remove(&id);
+        }
+    }
+
+    /// Return the stalls that have lasted for the timeout, each only once.
+    /// This does nothing until the timeout for something passes.  Then
+    /// `still_blocked` is asked whether sending is still blocked at each
+    /// limit, and anything that isn't is forgotten.
+    pub fn check(
+        &mut self,
+        now: Instant,
+        mut still_blocked: impl FnMut(Option<StreamId>, u64) -> bool,
+    ) -> Vec<FlowControlStall> {
+        let timeout = match self.timeout {
+            Some(t) if self.next_timeout().map_or(false, |next| now >= next) => t,
+            _ => return Vec::new(),
+        };
+        self.blocked.retain(|id, b| still_blocked(*id, b.limit));
+        let mut stalls = Vec::new();
+        for (id, b) in &mut self.blocked {
+            if !b.reported && now >= b.since + timeout {
+                b.reported = true;
+                stalls.push(FlowControlStall {
+                    stream_id: id.map(StreamId::as_u64),
+                    limit: b.limit,
+                    duration: now.saturating_duration_since(b.since),
+                });
+            }
+        }
+        stalls.sort();
+        stalls
+    }
+
+    /// When the next stall would be reported, if nothing changes.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        let timeout = self.timeout?;
+        self.blocked
+            .values()
+            .filter(|b| !b.reported)
+            .map(|b| b.since + timeout)
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StallDetector, DEFAULT_FLOW_CONTROL_STALL_TIMEOUT};
+    use crate::stats::FlowControlStall;
+    use crate::stream_id::StreamId;
+    use std::time::{Duration, Instant};
+    use test_fixture::now;
+
+    const TIMEOUT: Duration = DEFAULT_FLOW_CONTROL_STALL_TIMEOUT;
+
+    fn blocked(sd: &mut StallDetector, blocked: &[(Option<StreamId>, u64)], now: Instant) {
+        for (id, limit) in blocked {
+            sd.blocked(*id, *limit, now);
+        }
+    }
+
+    #[test]
+    fn stall_reported_once() {
+        let mut sd = StallDetector::default();
+        let start = now();
+        let b = [(Some(StreamId::new(0)), 100), (None, 1000)];
+        blocked(&mut sd, &b, start);
+        assert!(sd.check(start, |_, _| true).is_empty());
+        assert_eq!(sd.next_timeout(), Some(start + TIMEOUT));
+
+        // Hearing about the same limits again changes nothing.
+        blocked(&mut sd, &b, start + TIMEOUT / 2);
+        let late = start + TIMEOUT + Duration::from_secs(1);
+        assert_eq!(
+            sd.check(late, |_, _| true),
+            vec![
+                FlowControlStall {
+                    stream_id: None,
+                    limit: 1000,
+                    duration: TIMEOUT + Duration::from_secs(1),
+                },
+                FlowControlStall {
+                    stream_id: Some(0),
+                    limit: 100,
+                    duration: TIMEOUT + Duration::from_secs(1),
+                },
+            ]
+        );
+        assert_eq!(sd.next_timeout(), None);
+        assert!(sd.check(late + TIMEOUT, |_, _| true).is_empty());
+    }
+
+    #[test]
+    fn new_limit_restarts() {
+        let mut sd = StallDetector::default();
+        let start = now();
+        let mid = start + TIMEOUT / 2;
+        sd.blocked(None, 1000, start);
+        sd.limit_raised(None, 2000);
+        assert_eq!(sd.next_timeout(), None);
+        sd.blocked(None, 2000, mid);
+        // A limit that isn't higher doesn't count.
+        sd.limit_raised(None, 2000);
+        assert!(sd.check(start + TIMEOUT, |_, _| true).is_empty());
+        assert_eq!(sd.next_timeout(), Some(mid + TIMEOUT));
+        assert_eq!(sd.check(mid + TIMEOUT, |_, _| true).len(), 1);
+    }
+
+    #[test]
+    fn no_longer_blocked() {
+        let mut sd = StallDetector::default();
+        let start = now();
+        sd.blocked(Some(StreamId::new(4)), 100, start);
+        sd.blocked(None, 1000, start);
+        // The stream went away without the limit changing.
+        let stalls = sd.check(start + TIMEOUT, |id, _| id.is_none());
+        assert_eq!(stalls.len(), 1);
+        assert_eq!(stalls[0].stream_id, None);
+        assert_eq!(sd.next_timeout(), None);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut sd = StallDetector::default();
+        sd.set_timeout(None);
+        sd.blocked(None, 1000, now());
+        assert!(sd.check(now() + TIMEOUT * 2, |_, _| true).is_empty());
+        assert_eq!(sd.next_timeout(), None);
+    }
+}
@@ -0,0 +1,180 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Stateless reset.  A server that has lost the state for a connection sends
+// one of these when it gets a packet for that connection, so that the client
+// can stop without waiting for the idle timeout.  The token for a connection ID
+// is derived from a static key, so no state is needed to make one.
+
+use std::cmp::min;
+
+use neqo_crypto::{
+    constants::{TLS_AES_128_GCM_SHA256, TLS_VERSION_1_3},
+    hkdf, random, SymKey,
+};
+
+use crate::Res;
+
+/// The length of a stateless reset token.
+pub const STATELESS_RESET_TOKEN_LEN: usize = 16;
+/// The smallest stateless reset that is sent or recognized: a first byte, four
+/// bytes that stand in for a connection ID and packet number, and the token.
+pub const MIN_STATELESS_RESET_SIZE: usize = 5 + STATELESS_RESET_TOKEN_LEN;
+/// The size of a stateless reset sent in response to a larger packet.
+const STATELESS_RESET_SIZE: usize = 43;
+/// The label that tokens are expanded with.
+const TOKEN_LABEL: &str = "neqo reset";
+
+pub type StatelessResetToken = [u8; STATELESS_RESET_TOKEN_LEN];
+
+/// Makes the stateless reset tokens for a server.
+pub struct StatelessResetKey {
+    key: SymKey,
+}
+
+impl StatelessResetKey {
+    /// A key that is only good for as long as the server runs.
+    pub fn random() -> Res<Self> {
+        Ok(Self {
+            key: hkdf::generate_key(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256)?,
+        })
+    }
+
+    /// A key that is derived from `key`.  A server that uses the same value
+    /// after a restart can reset connections that it had before.
+    pub fn new(key: &[u8]) -> Res<Self> {
+        Ok(Self {
+            key: hkdf::import_key(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, key)?,
+        })
+    }
+
+    /// The token for `cid`.  This is HKDF-Expand-Label of the key with the
+    /// connection ID as context, so each token is the output of a PRF over its
+    /// connection ID and tells nothing about the token for any other.
+    pub fn token(&self, cid: &[u8]) -> Res<StatelessResetToken> {
+        let secret = hkdf::expand_label(
+            TLS_VERSION_1_3,
+            TLS_AES_128_GCM_SHA256,
+            &self.key,
+            cid,
+            TOKEN_LABEL,
+        )?;
+        let mut token = [0; STATELESS_RESET_TOKEN_LEN];
+        token.copy_from_slice(&secret.as_bytes()?[..STATELESS_RESET_TOKEN_LEN]);
+        Ok(token)
+    }
+
+    /// Make a stateless reset for a packet of `received` bytes that was sent to `cid`.
+    /// This is always smaller than the packet, so that two endpoints can't keep
+    /// sending these to each other.  Nothing is made if the packet is too small.
+    pub fn make_reset(&self, cid: &[u8], received: usize) -> Res<Option<Vec<u8>>> {
+        if received <= MIN_STATELESS_RESET_SIZE {
+            return Ok(None);
+        }
+        let size = min(received - 1, STATELESS_RESET_SIZE);
+        let mut reset = random(size - STATELESS_RESET_TOKEN_LEN);
+        // This has to look like a short header packet.
+        reset[0] = 0x40 | (reset[0] & 0x3f);
+        reset.extend_from_slice(&self.token(cid)?);
+        Ok(Some(reset))
+    }
+}
+
+impl ::std::fmt::Display for StatelessResetKey {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "StatelessResetKey")
+    }
+}
+
+/// Whether the datagram `d` ends with `token`.  Comparing every byte stops the
+/// time taken from showing how much of a guess was right.
+pub fn is_stateless_reset(d: &[u8], token: &[u8]) -> bool {
+    if d.len() < MIN_STATELESS_RESET_SIZE || token.len() != STATELESS_RESET_TOKEN_LEN {
+        return false;
+    }
+    d[d.len() - STATELESS_RESET_TOKEN_LEN..]
+        .iter()
+        .zip(token)
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_stateless_reset, StatelessResetKey, MIN_STATELESS_RESET_SIZE, STATELESS_RESET_SIZE,
+    };
+    use test_fixture::fixture_init;
+
+    #[test]
+    fn token_depends_on_cid() {
+        fixture_init();
+        let key = StatelessResetKey::random().unwrap();
+        assert_eq!(
+            key.token(&[1, 2, 3]).unwrap(),
+            key.token(&[1, 2, 3]).unwrap()
+        );
+        assert_ne!(
+            key.token(&[1, 2, 3]).unwrap(),
+            key.token(&[1, 2, 4]).unwrap()
+        );
+    }
+
+    // Tokens for connection IDs that differ in one bit have nothing in common
+    // beyond chance, and knowing some tokens doesn't give away any other.
+    #[test]
+    fn tokens_independent() {
+        fixture_init();
+        let key = StatelessResetKey::new(&[7; 32]).unwrap();
+        let a = key.token(&[0; 8]).unwrap();
+        let b = key.token(&[0, 0, 0, 0, 0, 0, 0, 1]).unwrap();
+        let c = key.token(&[0, 0, 0, 0, 0, 0, 0, 2]).unwrap();
+        let differ = |x: &[u8], y: &[u8]| -> u32 {
+            x.iter().zip(y).map(|(x, y)| (x ^ y).count_ones()).sum()
+        };
+        // 128 bits, of which about half should differ.
+        assert!(differ(&a, &b) > 32);
+        assert!(differ(&b, &c) > 32);
+        // With a fixed nonce, a ^ b ^ c would be the token of a ^ b ^ c for a
+        // polynomial MAC like GCM; a PRF has no such relation.
+        let xor = a
+            .iter()
+            .zip(&b)
+            .zip(&c)
+            .map(|((a, b), c)| a ^ b ^ c)
+            .collect::<Vec<_>>();
+        assert_ne!(xor, key.token(&[0, 0, 0, 0, 0, 0, 0, 3]).unwrap().to_vec());
+    }
+
+    #[test]
+    fn same_key_same_token() {
+        fixture_init();
+        let a = StatelessResetKey::new(&[7; 32]).unwrap();
+        let b = StatelessResetKey::new(&[7; 32]).unwrap();
+        let c = StatelessResetKey::new(&[8; 32]).unwrap();
+        assert_eq!(a.token(&[1]).unwrap(), b.token(&[1]).unwrap());
+        assert_ne!(a.token(&[1]).unwrap(), c.token(&[1]).unwrap());
+    }
+
+    #[test]
+    fn reset_size() {
+        fixture_init();
+        let key = StatelessResetKey::random().unwrap();
+        let token = key.token(&[1]).unwrap();
+        assert!(key
+            .make_reset(&[1], MIN_STATELESS_RESET_SIZE)
+            .unwrap()
+            .is_none());
+        for received in &[MIN_STATELESS_RESET_SIZE + 1, 30, 1200] {
+            let reset = key.make_reset(&[1], *received).unwrap().unwrap();
+            assert!(reset.len() < *received);
+            assert!(reset.len() <= STATELESS_RESET_SIZE);
+            assert_eq!(reset[0] & 0xc0, 0x40);
+            assert!(is_stateless_reset(&reset, &token));
+            assert!(!is_stateless_reset(&reset, &key.token(&[2]).unwrap()));
+        }
+    }
+}
@@ -6,6 +6,240 @@
 
 // Tracking of some useful statistics.
 
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// A server can send three times as much as it has received from a client
+/// before the client's address is validated.
+pub const AMPLIFICATION_FACTOR: usize = 3;
+
+/// The reason that a received packet, or the remainder of a datagram, was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// The packet header could not be parsed.
+    MalformedHeader,
+    /// The packet was addressed to a connection ID that we don't recognize.
+    UnknownConnectionId,
+    /// A long header packet carried a source connection ID other than the one
+    /// the peer chose when the handshake started.
+    SourceConnectionIdChanged,
+    /// There are no keys for the packet number space, either yet or any more.
+    NoKeys,
+    /// The packet failed header protection removal or AEAD authentication.
+    DecryptionFailure,
+    /// The packet has a key phase other than the current one, and it could not
+    /// be decrypted with the keys for that phase, or it has a packet number that
+    /// doesn't fit with the packets that were received in the current phase.
+    /// These are packets from before a key update that arrived after the old keys
+    /// were discarded, or packets from a key update that came too soon.
+    KeyPhase,
+    /// The packet number has already been received, or it is older than the
+    /// packets that the keys for its key phase were used for.
+    Duplicate,
+    /// The packet type is not acceptable in the current connection state.
+    UnexpectedPacket,
+    /// The connection is closing, draining, or closed.
+    Closed,
+    /// The datagram arrived on a path other than the one the connection uses.
+    UnknownPath,
+    /// A server got a datagram that would start the connection, but it was too
+    /// small.  Answering it could send more than the anti-amplification limit allows.
+    AmplificationLimit,
+}
+
+/// What happened to a single datagram passed to `process_input`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DatagramDisposition {
+    /// The number of packets in the datagram that were processed.
+    pub processed: usize,
+    /// The reason for each packet in the datagram that was dropped.
+    pub dropped: Vec<DropReason>,
+}
+
+/// The size of each side's handshake flight, which includes every datagram sent
+/// on the connection's path until the handshake completes.  Datagrams that are
+/// received only count if a packet in them was processed, as others don't add to
+/// what the server may send.  A client completes the handshake before it sends
+/// its Finished, so the client flight it reports doesn't include that, but the
+/// one a server reports does.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct HandshakeStats {
+    /// Bytes in datagrams sent by the client.
+    pub client_flight: usize,
+    /// Bytes in datagrams sent by the server.
+    pub server_flight: usize,
+    /// How long the handshake took, once it is complete.
+    pub duration: Option<Duration>,
+    /// The duration as a number of round trips, at least 1 once the handshake is complete.
+    pub round_trips: usize,
+    /// The server used all that the anti-amplification limit allowed on the path.
+    /// Only a server knows this.
+    pub amplification_blocked: bool,
+}
+
+impl HandshakeStats {
+    /// Whether the server flight is larger than the server can send before the
+    /// client's address is validated.  The rest of the flight has to wait for
+    /// the client to send more, which costs at least one extra round trip.
+    /// A client can only tell this from the size of the flights.
+    #[must_use]
+    pub fn amplification_limited(&self) -> bool {
+        self.amplification_blocked || self.server_flight > self.client_flight * AMPLIFICATION_FACTOR
+    }
+}
+
+impl Display for HandshakeStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.duration.is_some() {
+            write!(f, "{} RTT", self.round_trips)?;
+        } else {
+            write!(f, "incomplete")?;
+        }
+        write!(f, ", server flight {} bytes", self.server_flight)?;
+        if self.amplification_limited() {
+            write!(f, " (amplification-limited)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Sending that was blocked on one flow control limit for longer than the stall
+/// timeout, see `Connection::set_flow_control_stall_timeout`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FlowControlStall {
+    /// The stream that was blocked, or `None` for the connection limit.
+    pub stream_id: Option<u64>,
+    /// The limit that the peer set, which is MAX_STREAM_DATA for a stream or
+    /// MAX_DATA for the connection.
+    pub limit: u64,
+    /// How long sending had been blocked at that limit when this was reported.
+    pub duration: Duration,
+}
+
+impl Display for FlowControlStall {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let Some(id) = self.stream_id {
+            write!(f, "stream {}", id)?;
+        } else {
+            write!(f, "connection")?;
+        }
+        write!(
+            f,
+            " blocked at limit {} for {:?}",
+            self.limit, self.duration
+        )
+    }
+}
+
+/// What the bytes in the datagrams that a connection sent were used for.
+/// Stream data is counted when it is sent, so the fields always add up to the
+/// size of every datagram sent.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SentBytes {
+    /// Data in STREAM frames, the first time it was sent.
+    pub stream_data: u64,
+    /// Data in STREAM frames that had been sent before and was lost.
+    pub stream_retransmitted: u64,
+    /// The rest of the packet payloads: everything in STREAM frames other than
+    /// their data, and every other frame.
+    pub frames: u64,
+    /// Packet headers, including packet numbers.
+    pub packet_headers: u64,
+    /// The authentication tags that packet protection adds.
+    pub aead: u64,
+    /// Bytes that were added to make a datagram larger.
+    pub padding: u64,
+}
+
+impl SentBytes {
+    /// The size of every datagram that was sent.
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        self.stream_data
+            + self.stream_retransmitted
+            + self.frames
+            + self.packet_headers
+            + self.aead
+            + self.padding
+    }
+
+    pub(crate) fn stream(&self) -> u64 {
+        self.stream_data + self.stream_retransmitted
+    }
+
+    /// Count a packet that is `len` bytes once it is protected.  `header` and
+    /// `payload` are the sizes before protection.  `stream_before` is what
+    /// `stream()` was before the payload was written; the stream data that went
+    /// into the payload has been counted since.
+    pub(crate) fn packet(&mut self, header: usize, payload: usize, len: usize, stream_before: u64) {
+        self.packet_headers += header as u64;
+        self.frames += payload as u64 - (self.stream() - stream_before);
+        self.aead += (len - header - payload) as u64;
+    }
+}
+
+/// A snapshot of the congestion controller, see `Connection::congestion_stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CongestionStats {
+    /// The congestion window, in bytes.
+    pub cwnd: usize,
+    /// The slow start threshold, in bytes.  This is `usize::MAX` until a
+    /// packet is lost, which means that the connection is still in slow start.
+    pub ssthresh: usize,
+    /// Bytes in packets that have been sent but not yet acknowledged or lost.
+    pub bytes_in_flight: usize,
+}
+
+/// When each step in establishing a connection happened, see `Connection::milestones`.
+/// Each is recorded the first time that it happens, and they are kept if the
+/// connection fails, so they show how far a failed connection got.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Milestones {
+    /// The first datagram was sent.
+    pub first_sent: Option<Instant>,
+    /// The first packet was received and could be decrypted.
+    pub first_received: Option<Instant>,
+    /// Keys for sending Handshake packets were available.
+    pub handshake_keys: Option<Instant>,
+    /// The TLS handshake completed.
+    pub handshake_complete: Option<Instant>,
+    /// The handshake was confirmed.
+    pub handshake_confirmed: Option<Instant>,
+    /// Stream data was first sent.
+    pub first_stream_data: Option<Instant>,
+}
+
+impl Milestones {
+    /// Record `milestone` at `now` if it hasn't happened before.
+    /// This returns true if this is the first time.
+    pub(crate) fn reach(milestone: &mut Option<Instant>, now: Instant) -> bool {
+        if milestone.is_none() {
+            *milestone = Some(now);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The milestones that have been reached, with their names, in the order that
+    /// a client usually reaches them.
+    #[must_use]
+    pub fn reached(&self) -> Vec<(&'static str, Instant)> {
+        vec![
+            ("first_sent", self.first_sent),
+            ("first_received", self.first_received),
+            ("handshake_keys", self.handshake_keys),
+            ("handshake_complete", self.handshake_complete),
+            ("handshake_confirmed", self.handshake_confirmed),
+            ("first_stream_data", self.first_stream_data),
+        ]
+        .into_iter()
+        .filter_map(|(name, t)| t.map(|t| (name, t)))
+        .collect()
+    }
+}
+
 #[derive(Default, Debug)]
 /// Connection statistics
 pub struct Stats {
@@ -13,8 +247,138 @@ pub struct Stats {
     pub packets_rx: usize,
     /// Total packets sent
     pub packets_tx: usize,
+    /// Total bytes in datagrams received
+    pub bytes_rx: usize,
+    /// Total bytes in datagrams sent
+    pub bytes_tx: usize,
+    /// ACK frames sent
+    pub acks_tx: usize,
+    /// MAX_STREAM_DATA frames sent
+    pub max_stream_data_tx: usize,
     /// Duplicate packets received
     pub dups_rx: usize,
     /// Dropped datagrams, or parts thereof
     pub dropped_rx: usize,
+    /// Dropped packets by reason, including duplicates and the packets that are
+    /// answered with CONNECTION_CLOSE while closing, which aren't in `dropped_rx`
+    pub dropped_by_reason: HashMap<DropReason, usize>,
+    /// Datagrams that were dropped before they reached the connection because
+    /// the socket's receive buffer was full, see `Connection::record_local_drops`.
+    /// These look like loss on the network, but they happen on this host.
+    pub local_drops_rx: usize,
+    /// NEW_CONNECTION_ID frames received
+    pub new_cids_rx: usize,
+    /// RETIRE_CONNECTION_ID frames received
+    pub retire_cids_rx: usize,
+    /// Connection IDs issued to the peer in NEW_CONNECTION_ID
+    pub new_cids_tx: usize,
+    /// Times that connection IDs the peer retired weren't replaced straight away,
+    /// because the peer was retiring them too fast.
+    pub cids_throttled: usize,
+    /// The size of the handshake
+    pub handshake: HandshakeStats,
+    /// Times that stream data was sent while a critical stream had data that did not fit,
+    /// see `TransmissionPriority::Critical`.  This should stay at zero.
+    pub critical_starved: usize,
+    /// Times that sending stalled on flow control.  The details of each are in
+    /// a `FlowControlStalled` event.
+    pub flow_control_stalls: usize,
+    /// Packets that were declared lost, either because later packets were
+    /// acknowledged or because they were not acknowledged in time.
+    pub lost: usize,
+    /// Times that the PTO timer fired.
+    pub pto_count: usize,
+    /// The smoothed RTT, as of the last ACK or timer.
+    pub rtt: Duration,
+    /// The variation in the RTT, as of the last ACK or timer.
+    pub rttvar: Duration,
+    /// The lowest RTT that has been seen, or zero if there hasn't been a sample.
+    pub min_rtt: Duration,
+    /// Times that the congestion window was reduced because nothing was sent for
+    /// a while, see `Connection::set_idle_restart`.
+    pub idle_restarts: usize,
+    /// What the bytes that were sent were used for.
+    pub sent_bytes: SentBytes,
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "packets tx {} rx {} (dups {} dropped {} local drops {}), bytes tx {} rx {}, acks tx {}, max stream data tx {}, lost {}, pto {}, rtt {:?} rttvar {:?} min {:?}",
+            self.packets_tx,
+            self.packets_rx,
+            self.dups_rx,
+            self.dropped_rx,
+            self.local_drops_rx,
+            self.bytes_tx,
+            self.bytes_rx,
+            self.acks_tx,
+            self.max_stream_data_tx,
+            self.lost,
+            self.pto_count,
+            self.rtt,
+            self.rttvar,
+            self.min_rtt
+        )
+    }
+}
+
+impl Stats {
+    /// Count a dropped packet.  Duplicates are counted separately from other drops.
+    pub(crate) fn packet_dropped(&mut self, reason: DropReason) {
+        if reason == DropReason::Duplicate {
+            self.dups_rx += 1;
+        } else {
+            self.dropped_rx += 1;
+        }
+        *self.dropped_by_reason.entry(reason).or_insert(0) += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlowControlStall, HandshakeStats};
+    use std::time::Duration;
+
+    #[test]
+    fn handshake_display() {
+        let mut hs = HandshakeStats {
+            client_flight: 1200,
+            server_flight: 3600,
+            ..HandshakeStats::default()
+        };
+        assert!(!hs.amplification_limited());
+        assert_eq!(hs.to_string(), "incomplete, server flight 3600 bytes");
+
+        hs.server_flight = 9400;
+        hs.duration = Some(Duration::from_millis(60));
+        hs.round_trips = 2;
+        assert!(hs.amplification_limited());
+        assert_eq!(
+            hs.to_string(),
+            "2 RTT, server flight 9400 bytes (amplification-limited)"
+        );
+
+        // A server that was blocked knows, whatever the sizes.
+        hs.server_flight = 3600;
+        assert!(!hs.amplification_limited());
+        hs.amplification_blocked = true;
+        assert!(hs.amplification_limited());
+    }
+
+    #[test]
+    fn stall_display() {
+        let mut stall = FlowControlStall {
+            stream_id: Some(4),
+            limit: 1000,
+            duration: Duration::from_secs(30),
+        };
+        assert_eq!(stall.to_string(), "stream 4 blocked at limit 1000 for 30s");
+        stall.stream_id = None;
+        assert_eq!(
+            stall.to_string(),
+            "connection blocked at limit 1000 for 30s"
+        );
+    }
 }
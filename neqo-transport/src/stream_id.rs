@@ -10,9 +10,11 @@ use std::ops::AddAssign;
 
 use neqo_common::Role;
 
-use crate::connection::{LOCAL_STREAM_LIMIT_BIDI, LOCAL_STREAM_LIMIT_UNI};
 use crate::frame::StreamType;
 
+/// QUIC can't have more than this many streams of each type.
+pub const MAX_STREAMS: u64 = 1 << 60;
+
 pub struct StreamIndexes {
     pub local_max_stream_uni: StreamIndex,
     pub local_max_stream_bidi: StreamIndex,
@@ -22,23 +24,48 @@ pub struct StreamIndexes {
     pub remote_max_stream_bidi: StreamIndex,
     pub remote_next_stream_uni: StreamIndex,
     pub remote_next_stream_bidi: StreamIndex,
+    /// The most streams of each type that this endpoint creates over the life
+    /// of the connection.
+    pub allowance_uni: StreamIndex,
+    pub allowance_bidi: StreamIndex,
 }
 
 impl StreamIndexes {
-    pub fn new() -> Self {
+    /// Start with the stream limits that this endpoint sends to the peer.
+    pub fn new(local_max_stream_bidi: u64, local_max_stream_uni: u64) -> Self {
         Self {
-            local_max_stream_bidi: StreamIndex::new(LOCAL_STREAM_LIMIT_BIDI),
-            local_max_stream_uni: StreamIndex::new(LOCAL_STREAM_LIMIT_UNI),
+            local_max_stream_bidi: StreamIndex::new(local_max_stream_bidi),
+            local_max_stream_uni: StreamIndex::new(local_max_stream_uni),
             local_next_stream_uni: StreamIndex::new(0),
             local_next_stream_bidi: StreamIndex::new(0),
             remote_max_stream_bidi: StreamIndex::new(0),
             remote_max_stream_uni: StreamIndex::new(0),
             remote_next_stream_uni: StreamIndex::new(0),
             remote_next_stream_bidi: StreamIndex::new(0),
+            allowance_uni: StreamIndex::new(MAX_STREAMS),
+            allowance_bidi: StreamIndex::new(MAX_STREAMS),
         }
     }
 }
 
+/// The limits that the peer currently places on streams opened by this endpoint,
+/// see `Connection::remote_stream_limits`.  These start from the peer's transport
+/// parameters; the stream counts and `max_data` include later MAX_STREAMS and
+/// MAX_DATA frames, but the stream data limits only apply to new streams.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StreamLimits {
+    /// The number of bidirectional streams that can be opened in total.
+    pub max_streams_bidi: u64,
+    /// The number of unidirectional streams that can be opened in total.
+    pub max_streams_uni: u64,
+    /// The data that can be sent on a new bidirectional stream.
+    pub initial_max_stream_data_bidi_remote: u64,
+    /// The data that can be sent on a new unidirectional stream.
+    pub initial_max_stream_data_uni: u64,
+    /// The data that can be sent on all streams together.
+    pub max_data: u64,
+}
+
 #[derive(Debug, Eq, PartialEq, Clone, Copy, Ord, PartialOrd, Hash)]
 pub struct StreamId(u64);
 
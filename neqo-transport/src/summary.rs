@@ -0,0 +1,121 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A snapshot of handshake progress, for debugging connections that stall.
+
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+use crate::connection::State;
+
+/// What a timer is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerKind {
+    /// The closing or draining period.
+    Closing,
+    /// Sending a delayed acknowledgment.
+    Ack,
+    /// The idle timeout.
+    Idle,
+    /// Loss detection or a probe timeout.
+    LossRecovery,
+    /// Discarding old keys after a key update.
+    KeyUpdate,
+    /// Closing the connection if the application doesn't authenticate the peer.
+    Authentication,
+    /// Reporting that sending has been blocked on flow control for too long.
+    FlowControlStall,
+    /// Sending PATH_CHALLENGE again, or giving up on validating a path.
+    PathValidation,
+    /// Sending MAX_STREAM_DATA frames that were waiting for another packet.
+    StreamCredit,
+    /// Reporting a change in the congestion state that was held back.
+    CongestionState,
+    /// Replacing connection IDs that the peer retired, once the rate allows.
+    ConnectionIds,
+}
+
+/// The state of one packet number space.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpaceSummary {
+    /// Whether there are keys for reading packets.
+    pub read_keys: bool,
+    /// Whether there are keys for writing packets.
+    pub write_keys: bool,
+    /// The highest offset of crypto data that has been sent.
+    pub crypto_sent: u64,
+    /// The amount of crypto data that has been received in order.
+    pub crypto_received: u64,
+    /// Crypto data that is waiting to be sent or acknowledged.
+    pub crypto_send_buffered: usize,
+    /// Crypto data that was received out of order and can't be used yet.
+    pub crypto_recv_buffered: u64,
+    /// Packets that have been sent but not acknowledged or declared lost.
+    pub unacked_packets: usize,
+}
+
+impl Display for SpaceSummary {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "keys={}{} crypto tx={} (buffered {}) rx={} (buffered {}) unacked={}",
+            if self.read_keys { "r" } else { "-" },
+            if self.write_keys { "w" } else { "-" },
+            self.crypto_sent,
+            self.crypto_send_buffered,
+            self.crypto_received,
+            self.crypto_recv_buffered,
+            self.unacked_packets
+        )
+    }
+}
+
+/// A snapshot of a connection, see `Connection::handshake_state_summary`.
+/// Packet number spaces that have been discarded are `None`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandshakeSummary {
+    pub state: State,
+    pub initial: Option<SpaceSummary>,
+    pub handshake: Option<SpaceSummary>,
+    pub application: Option<SpaceSummary>,
+    /// Whether sending is blocked by the congestion window.
+    pub congestion_blocked: bool,
+    /// Whether sending stream data is blocked by the peer's MAX_DATA.
+    pub flow_control_blocked: bool,
+    /// How long ago the last packet was received, if any has been.
+    pub since_last_received: Option<Duration>,
+    /// The next timer and how long until it fires.
+    pub next_timer: Option<(TimerKind, Duration)>,
+}
+
+impl Display for HandshakeSummary {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "state={:?}", self.state)?;
+        for (name, space) in &[
+            ("initial", &self.initial),
+            ("handshake", &self.handshake),
+            ("application", &self.application),
+        ] {
+            if let Some(space) = space {
+                write!(f, "; {}: {}", name, space)?;
+            }
+        }
+        if self.congestion_blocked {
+            write!(f, "; congestion blocked")?;
+        }
+        if self.flow_control_blocked {
+            write!(f, "; flow control blocked")?;
+        }
+        match self.since_last_received {
+            Some(d) => write!(f, "; last received {:?} ago", d)?,
+            None => write!(f, "; nothing received")?,
+        }
+        if let Some((kind, d)) = self.next_timer {
+            write!(f, "; next timer {:?} in {:?}", kind, d)?;
+        }
+        Ok(())
+    }
+}
@@ -8,7 +8,7 @@
 
 #![allow(dead_code)]
 use crate::{Error, Res};
-use neqo_common::{hex, matches, qdebug, qinfo, qtrace, Decoder, Encoder};
+use neqo_common::{hex, matches, qdebug, qinfo, qtrace, qwarn, Decoder, Encoder};
 use neqo_crypto::constants::{TLS_HS_CLIENT_HELLO, TLS_HS_ENCRYPTED_EXTENSIONS};
 use neqo_crypto::ext::{ExtensionHandler, ExtensionHandlerResult, ExtensionWriterResult};
 use neqo_crypto::{HandshakeMessage, ZeroRttCheckResult, ZeroRttChecker};
@@ -41,6 +41,10 @@ tpids! {
     MAX_ACK_DELAY = 11,
     DISABLE_MIGRATION = 12,
     PREFERRED_ADDRESS = 13,
+    ACTIVE_CONNECTION_ID_LIMIT = 14,
+    // Some clients send this to test that servers can handle a ClientHello
+    // that is as large as one with post-quantum key shares.
+    QUANTUM_READINESS = 0x173e,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -92,7 +96,7 @@ impl TransportParameter {
             | INITIAL_MAX_STREAM_DATA_BIDI_LOCAL
             | INITIAL_MAX_STREAM_DATA_BIDI_REMOTE
             | INITIAL_MAX_STREAM_DATA_UNI
-            | MAX_ACK_DELAY => match d.decode_varint() {
+            | ACTIVE_CONNECTION_ID_LIMIT => match d.decode_varint() {
                 Some(v) => Self::Integer(v),
                 None => return Err(Error::TransportParameterError),
             },
@@ -112,7 +116,14 @@ impl TransportParameter {
                 _ => return Err(Error::TransportParameterError),
             },
 
+            MAX_ACK_DELAY => match d.decode_varint() {
+                Some(v) if v < (1 << 14) => Self::Integer(v),
+                _ => return Err(Error::TransportParameterError),
+            },
+
             DISABLE_MIGRATION => Self::Empty,
+            // This is only padding, so don't keep it.
+            QUANTUM_READINESS => return Ok(None),
             // Skip.
             _ => return Ok(None),
         };
@@ -177,6 +188,7 @@ impl TransportParameters {
             MAX_PACKET_SIZE => 65527,
             ACK_DELAY_EXPONENT => 3,
             MAX_ACK_DELAY => 25,
+            ACTIVE_CONNECTION_ID_LIMIT => 2,
             _ => panic!("Transport parameter not known or not an Integer"),
         };
         match self.params.get(&tp) {
@@ -198,7 +210,8 @@ impl TransportParameters {
             | INITIAL_MAX_STREAMS_UNI
             | MAX_PACKET_SIZE
             | ACK_DELAY_EXPONENT
-            | MAX_ACK_DELAY => {
+            | MAX_ACK_DELAY
+            | ACTIVE_CONNECTION_ID_LIMIT => {
                 self.set(tp, TransportParameter::Integer(value));
             }
             _ => panic!("Transport parameter not known"),
@@ -331,6 +344,14 @@ impl ExtensionHandler for TransportParametersHandler {
         let mut dec = Decoder::from(d);
         match TransportParameters::decode(&mut dec) {
             Ok(tp) => {
+                if let Some(remote) = &self.remote {
+                    // Only a ClientHello that follows a HelloRetryRequest carries
+                    // transport parameters again, and they can't change.
+                    if msg != TLS_HS_CLIENT_HELLO || *remote != tp {
+                        qwarn!("Second set of transport parameters, msg={:?}", msg);
+                        return ExtensionHandlerResult::Alert(47); // illegal_parameter
+                    }
+                }
                 self.remote = Some(tp);
                 ExtensionHandlerResult::Ok
             }
@@ -421,6 +442,38 @@ mod tests {
         let tps2 = TransportParameters::decode(&mut enc.as_decoder()).expect("Couldn't decode");
     }
 
+    #[test]
+    fn quantum_readiness_ignored() {
+        let mut tps = TransportParameters::default();
+        tps.set(QUANTUM_READINESS, TransportParameter::Bytes(vec![0; 6000]));
+        tps.set(INITIAL_MAX_DATA, TransportParameter::Integer(10));
+
+        let mut enc = Encoder::default();
+        tps.encode(&mut enc);
+        assert!(enc.len() > 6000);
+
+        let tps2 = TransportParameters::decode(&mut enc.as_decoder()).expect("Couldn't decode");
+        assert!(!tps2.was_sent(QUANTUM_READINESS));
+        assert_eq!(tps2.get_integer(INITIAL_MAX_DATA), 10);
+    }
+
+    #[test]
+    fn max_ack_delay_limit() {
+        let encode = |max_ack_delay| {
+            let mut tps = TransportParameters::default();
+            tps.set_integer(MAX_ACK_DELAY, max_ack_delay);
+            let mut enc = Encoder::default();
+            tps.encode(&mut enc);
+            enc
+        };
+        let tps = TransportParameters::decode(&mut encode((1 << 14) - 1).as_decoder()).unwrap();
+        assert_eq!(tps.get_integer(MAX_ACK_DELAY), (1 << 14) - 1);
+        assert_eq!(
+            TransportParameters::decode(&mut encode(1 << 14).as_decoder()).unwrap_err(),
+            Error::TransportParameterError
+        );
+    }
+
     #[test]
     fn compatible_0rtt_ignored_values() {
         let mut tps_a = TransportParameters::default();
@@ -485,4 +538,32 @@ mod tests {
             assert!(!tps_b.ok_for_0rtt(&tps_a));
         }
     }
+
+    #[test]
+    fn second_set_rejected() {
+        let encode = |max_data| {
+            let mut tps = TransportParameters::default();
+            tps.set_integer(INITIAL_MAX_DATA, max_data);
+            let mut enc = Encoder::default();
+            tps.encode(&mut enc);
+            enc
+        };
+        let first = encode(1);
+        let changed = encode(2);
+
+        let mut handler = TransportParametersHandler::default();
+        let res = handler.handle(TLS_HS_ENCRYPTED_EXTENSIONS, &first);
+        assert!(matches!(res, ExtensionHandlerResult::Ok));
+        let res = handler.handle(TLS_HS_ENCRYPTED_EXTENSIONS, &first);
+        assert!(matches!(res, ExtensionHandlerResult::Alert(47)));
+
+        // A ClientHello that follows HelloRetryRequest repeats them, unchanged.
+        let mut handler = TransportParametersHandler::default();
+        let res = handler.handle(TLS_HS_CLIENT_HELLO, &first);
+        assert!(matches!(res, ExtensionHandlerResult::Ok));
+        let res = handler.handle(TLS_HS_CLIENT_HELLO, &first);
+        assert!(matches!(res, ExtensionHandlerResult::Ok));
+        let res = handler.handle(TLS_HS_CLIENT_HELLO, &changed);
+        assert!(matches!(res, ExtensionHandlerResult::Alert(47)));
+    }
 }
@@ -12,7 +12,7 @@ use std::collections::VecDeque;
 use std::convert::TryInto;
 use std::time::{Duration, Instant};
 
-use neqo_common::{qdebug, qinfo, qtrace, qwarn};
+use neqo_common::{qdebug, qinfo, qtrace, qwarn, Encoder};
 use neqo_crypto::{Epoch, TLS_EPOCH_HANDSHAKE, TLS_EPOCH_INITIAL};
 
 use crate::frame::{AckRange, Frame};
@@ -106,6 +106,13 @@ impl SentPacket {
         self.in_flight
     }
 
+    /// Account for PADDING that was added to the datagram after the packet was built.
+    /// A packet that contains PADDING is in flight, unless it is a probe.
+    pub fn add_padding(&mut self, len: usize, in_flight: bool) {
+        self.size += len;
+        self.in_flight |= in_flight;
+    }
+
     /// Whether the packet has been declared lost.
     pub fn lost(&self) -> bool {
         self.time_declared_lost.is_some()
@@ -129,6 +136,15 @@ impl SentPacket {
         }
     }
 
+    /// Move the times recorded for this packet `by` later, so that time spent
+    /// while the host was suspended doesn't count against it.
+    pub fn suspended(&mut self, by: Duration) {
+        self.time_sent += by;
+        if let Some(t) = &mut self.time_declared_lost {
+            *t += by;
+        }
+    }
+
     /// Ask whether this tracked packet has been declared lost for long enough
     /// that it can be expired and no longer tracked.
     pub fn expired(&self, now: Instant, expiration_period: Duration) -> bool {
@@ -402,7 +418,13 @@ impl RecvdPackets {
     ///
     /// We don't send ranges that have been acknowledged, but they still need
     /// to be tracked so that duplicates can be detected.
-    fn get_frame(&mut self, now: Instant) -> Option<(Frame, Option<RecoveryToken>)> {
+    ///
+    /// The oldest ranges are left out if the frame would not fit in `remaining`.
+    fn get_frame(
+        &mut self,
+        now: Instant,
+        remaining: usize,
+    ) -> Option<(Frame, Option<RecoveryToken>)> {
         // Check that we aren't delaying ACKs.
         if !self.ack_now(now) {
             return None;
@@ -410,58 +432,70 @@ impl RecvdPackets {
 
         // Limit the number of ACK ranges we send so that we'll always
         // have space for data in packets.
-        let ranges: Vec<PacketRange> = self
+        let mut ranges: Vec<PacketRange> = self
             .ranges
             .iter()
             .filter(|r| r.ack_needed())
             .take(MAX_ACKS_PER_FRAME)
             .cloned()
             .collect();
-        let mut iter = ranges.iter();
+        let first = ranges.first()?.clone();
 
-        let first = match iter.next() {
-            Some(v) => v,
-            _ => return None, // Nothing to send.
+        let ack_delay = now.saturating_duration_since(self.largest_pn_time.unwrap());
+        // We use the default exponent so
+        // ack_delay is in multiples of 8 microseconds.
+        let delay = if let Ok(delay) = (ack_delay.as_micros() / 8).try_into() {
+            delay
+        } else {
+            qwarn!(
+                "ack_delay.as_micros() did not fit a u64 {:?}",
+                ack_delay.as_micros()
+            );
+            return None;
         };
+
+        // The frame type and the count of ranges, which is less than 64, take a byte each.
+        let mut len = 2
+            + Encoder::varint_len(first.largest)
+            + Encoder::varint_len(delay)
+            + Encoder::varint_len(first.len() - 1);
+        if len > remaining {
+            return None;
+        }
         let mut ack_ranges = Vec::new();
         let mut last = first.smallest;
-
-        for range in iter {
-            ack_ranges.push(AckRange {
+        for range in &ranges[1..] {
+            let ack_range = AckRange {
                 // the difference must be at least 2 because 0-length gaps,
                 // (difference 1) are illegal.
                 gap: last - range.largest - 2,
                 range: range.len() - 1,
-            });
+            };
+            len += Encoder::varint_len(ack_range.gap) + Encoder::varint_len(ack_range.range);
+            if len > remaining {
+                break;
+            }
+            ack_ranges.push(ack_range);
             last = range.smallest;
         }
+        // Only what is sent can be acknowledged.
+        ranges.truncate(ack_ranges.len() + 1);
 
         // We've sent an ACK, reset the timer.
         self.ack_time = None;
         self.pkts_since_last_ack = 0;
 
-        let ack_delay = now.duration_since(self.largest_pn_time.unwrap());
-        // We use the default exponent so
-        // ack_delay is in multiples of 8 microseconds.
-        if let Ok(delay) = (ack_delay.as_micros() / 8).try_into() {
-            let ack = Frame::Ack {
-                largest_acknowledged: first.largest,
-                ack_delay: delay,
-                first_ack_range: first.len() - 1,
-                ack_ranges,
-            };
-            let token = RecoveryToken::Ack(AckToken {
-                space: self.space,
-                ranges,
-            });
-            Some((ack, Some(token)))
-        } else {
-            qwarn!(
-                "ack_delay.as_micros() did not fit a u64 {:?}",
-                ack_delay.as_micros()
-            );
-            None
-        }
+        let ack = Frame::Ack {
+            largest_acknowledged: first.largest,
+            ack_delay: delay,
+            first_ack_range: first.len() - 1,
+            ack_ranges,
+        };
+        let token = RecoveryToken::Ack(AckToken {
+            space: self.space,
+            ranges,
+        });
+        Some((ack, Some(token)))
     }
 }
 
@@ -528,9 +562,10 @@ impl AckTracker {
         &mut self,
         now: Instant,
         pn_space: PNSpace,
+        remaining: usize,
     ) -> Option<(Frame, Option<RecoveryToken>)> {
         self.get_mut(pn_space)
-            .map(|space| space.get_frame(now))
+            .map(|space| space.get_frame(now, remaining))
             .flatten()
     }
 }
@@ -550,7 +585,7 @@ impl Default for AckTracker {
 #[cfg(test)]
 mod tests {
     use super::{
-        AckTracker, Duration, Instant, PNSpace, RecoveryToken, RecvdPackets, ACK_DELAY,
+        AckTracker, Duration, Frame, Instant, PNSpace, RecoveryToken, RecvdPackets, ACK_DELAY,
         MAX_TRACKED_RANGES, MAX_UNACKED_PKTS,
     };
     use lazy_static::lazy_static;
@@ -734,7 +769,9 @@ mod tests {
             .set_received(*NOW, 0, true);
         // The reference time for `ack_time` has to be in the past or we filter out the timer.
         assert!(tracker.ack_time(*NOW - Duration::from_millis(1)).is_some());
-        let (_ack, token) = tracker.get_frame(*NOW, PNSpace::Initial).unwrap();
+        let (_ack, token) = tracker
+            .get_frame(*NOW, PNSpace::Initial, usize::MAX)
+            .unwrap();
         assert!(token.is_some());
 
         // Mark another packet as received so we have cause to send another ACK in that space.
@@ -749,7 +786,9 @@ mod tests {
 
         assert!(tracker.get_mut(PNSpace::Initial).is_none());
         assert!(tracker.ack_time(*NOW - Duration::from_millis(1)).is_none());
-        assert!(tracker.get_frame(*NOW, PNSpace::Initial).is_none());
+        assert!(tracker
+            .get_frame(*NOW, PNSpace::Initial, usize::MAX)
+            .is_none());
         if let RecoveryToken::Ack(tok) = token.as_ref().unwrap() {
             tracker.acked(tok); // Should be a noop.
         } else {
@@ -757,6 +796,45 @@ mod tests {
         }
     }
 
+    #[test]
+    fn ack_truncated() {
+        let mut tracker = AckTracker::default();
+        let space = tracker.get_mut(PNSpace::Initial).unwrap();
+        // Every other packet, for 11 ranges.
+        for pn in (0..=20).step_by(2) {
+            space.set_received(*NOW, pn, true);
+        }
+
+        // The frame type, largest, delay, count and first range take 5 bytes,
+        // then each range adds 2, so only two of the ranges fit.
+        let (ack, token) = tracker.get_frame(*NOW, PNSpace::Initial, 10).unwrap();
+        if let Frame::Ack {
+            largest_acknowledged,
+            ack_ranges,
+            ..
+        } = ack
+        {
+            assert_eq!(largest_acknowledged, 20);
+            assert_eq!(ack_ranges.len(), 2);
+        } else {
+            panic!("not an ACK frame");
+        }
+        // Only the ranges that were sent are acknowledged by the token.
+        if let Some(RecoveryToken::Ack(tok)) = token {
+            assert_eq!(tok.ranges.len(), 3);
+        } else {
+            panic!("not an ACK token");
+        }
+
+        // Nothing is sent if the first range doesn't fit.
+        tracker
+            .get_mut(PNSpace::Initial)
+            .unwrap()
+            .set_received(*NOW, 30, true);
+        assert!(tracker.get_frame(*NOW, PNSpace::Initial, 4).is_none());
+        assert!(tracker.get_frame(*NOW, PNSpace::Initial, 5).is_some());
+    }
+
     #[test]
     fn ack_time_elapsed() {
         let mut tracker = AckTracker::default();
@@ -16,7 +16,8 @@ use neqo_crypto::{
     AuthenticationStatus,
 };
 use neqo_transport::{
-    server::{ActiveConnectionRef, Server},
+    server::{ActiveConnectionRef, Server, ValidateAddress},
+    tparams::{self, TransportParameter},
     Connection, ConnectionError, Error, FixedConnectionIdManager, Output, State, StreamType,
     QUIC_VERSION,
 };
@@ -55,7 +56,7 @@ fn connected_server(server: &mut Server) -> ActiveConnectionRef {
 
 /// Connect.  This returns a reference to the server connection.
 fn connect(client: &mut Connection, server: &mut Server) -> ActiveConnectionRef {
-    server.set_retry_required(false);
+    server.set_validation(ValidateAddress::Never);
 
     assert_eq!(*client.state(), State::Init);
     let dgram = client.process(None, now()).dgram(); // ClientHello
@@ -112,6 +113,24 @@ fn single_client() {
     connect(&mut client, &mut server);
 }
 
+#[test]
+fn local_tparams() {
+    let mut server = default_server();
+    server.set_local_tparam(
+        tparams::INITIAL_MAX_STREAM_DATA_UNI,
+        TransportParameter::Integer(1000),
+    );
+    // A later value replaces an earlier one.
+    server.set_local_tparam(
+        tparams::INITIAL_MAX_STREAM_DATA_UNI,
+        TransportParameter::Integer(2000),
+    );
+    let mut client = default_client();
+    connect(&mut client, &mut server);
+    let limits = client.remote_stream_limits();
+    assert_eq!(limits.initial_max_stream_data_uni, 2000);
+}
+
 #[test]
 fn duplicate_initial() {
     let mut server = default_server();
@@ -227,7 +246,7 @@ fn drop_non_initial() {
 #[test]
 fn retry() {
     let mut server = default_server();
-    server.set_retry_required(true);
+    server.set_validation(ValidateAddress::Always);
     let mut client = default_client();
 
     let dgram = client.process(None, now()).dgram(); // Initial
@@ -251,6 +270,29 @@ fn retry() {
     connected_server(&mut server);
 }
 
+#[test]
+fn retry_milestones() {
+    let mut server = default_server();
+    server.set_validation(ValidateAddress::Always);
+    let mut client = default_client();
+    let start = now();
+    let retried = start + Duration::from_millis(10);
+
+    let dgram = client.process(None, start).dgram(); // Initial
+    let dgram = server.process(dgram, start).dgram(); // Retry
+    let dgram = client.process(dgram, retried).dgram(); // Initial w/token
+    let dgram = server.process(dgram, retried).dgram(); // Initial, HS
+    let handshake = retried + Duration::from_millis(10);
+    let _ = client.process(dgram, handshake).dgram();
+
+    // The Retry doesn't count as the first packet received.
+    let m = client.milestones();
+    assert_eq!(m.first_sent, Some(start));
+    assert_eq!(m.first_received, Some(handshake));
+    assert_eq!(m.handshake_keys, Some(handshake));
+    assert!(m.handshake_complete.is_none());
+}
+
 // attempt a retry with 0-RTT, and have 0-RTT packets sent with the second ClientHello
 #[test]
 fn retry_0rtt() {
@@ -268,7 +310,7 @@ fn retry_0rtt() {
     // Calling active_connections clears the set of active connections.
     assert_eq!(server.active_connections().len(), 1);
 
-    server.set_retry_required(true);
+    server.set_validation(ValidateAddress::Always);
     let mut client = default_client();
     client
         .set_resumption_token(now(), &token)
@@ -305,7 +347,7 @@ fn retry_0rtt() {
 #[test]
 fn retry_different_ip() {
     let mut server = default_server();
-    server.set_retry_required(true);
+    server.set_validation(ValidateAddress::Always);
     let mut client = default_client();
 
     let dgram = client.process(None, now()).dgram(); // Initial
@@ -331,7 +373,7 @@ fn retry_different_ip() {
 fn retry_after_initial() {
     let mut server = default_server();
     let mut retry_server = default_server();
-    retry_server.set_retry_required(true);
+    retry_server.set_validation(ValidateAddress::Always);
     let mut client = default_client();
 
     let cinit = client.process(None, now()).dgram(); // Initial
@@ -369,10 +411,41 @@ fn retry_after_initial() {
     connected_server(&mut server);
 }
 
+#[test]
+fn retry_twice() {
+    let mut server = default_server();
+    server.set_validation(ValidateAddress::Always);
+    let mut other_server = default_server();
+    other_server.set_validation(ValidateAddress::Always);
+    let mut client = default_client();
+
+    let cinit = client.process(None, now()).dgram(); // Initial
+    assert!(cinit.is_some());
+    let retry = server.process(cinit.clone(), now()).dgram(); // Retry
+    assert!(retry.is_some());
+    assertions::assert_retry(&retry.as_ref().unwrap());
+    let other_retry = other_server.process(cinit, now()).dgram(); // Another Retry
+    assert!(other_retry.is_some());
+    assertions::assert_retry(&other_retry.as_ref().unwrap());
+
+    let dgram = client.process(retry, now()).dgram(); // Initial w/token
+    assert!(dgram.is_some());
+
+    // The client should ignore the second Retry.
+    let dropped = client.stats().dropped_rx;
+    let junk = client.process(other_retry, now()).dgram();
+    assert!(junk.is_none());
+    assert_eq!(client.stats().dropped_rx, dropped + 1);
+
+    let dgram = server.process(dgram, now()).dgram(); // Initial, HS
+    assert!(dgram.is_some());
+    complete_connection(&mut client, &mut server, dgram);
+}
+
 #[test]
 fn retry_bad_integrity() {
     let mut server = default_server();
-    server.set_retry_required(true);
+    server.set_validation(ValidateAddress::Always);
     let mut client = default_client();
 
     let dgram = client.process(None, now()).dgram(); // Initial
@@ -396,7 +469,7 @@ fn retry_bad_integrity() {
 fn retry_bad_token() {
     let mut client = default_client();
     let mut retry_server = default_server();
-    retry_server.set_retry_required(true);
+    retry_server.set_validation(ValidateAddress::Always);
     let mut server = default_server();
 
     // Send a retry to one server, then replay it to the other.
@@ -411,6 +484,54 @@ fn retry_bad_token() {
     assert!(dgram.is_none());
 }
 
+#[test]
+fn retry_expired() {
+    let mut server = default_server();
+    server.set_validation(ValidateAddress::Always);
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram(); // Initial
+    let dgram = server.process(dgram, now()).dgram(); // Retry
+    let dgram = client.process(dgram, now()).dgram(); // Initial w/token
+    assert!(dgram.is_some());
+
+    // The token is only good for a few seconds.
+    let later = now() + Duration::from_secs(10);
+    let dgram = server.process(dgram, later).dgram();
+    assert!(dgram.is_none());
+}
+
+#[test]
+fn retry_validates_address() {
+    let mut server = default_server();
+    server.set_validation(ValidateAddress::Always);
+    // Make the server flight a lot larger than what the client sends.
+    server.set_local_tparam(
+        tparams::QUANTUM_READINESS,
+        TransportParameter::Bytes(vec![0; 10000]),
+    );
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram(); // Initial
+    let dgram = server.process(dgram, now()).dgram(); // Retry
+    let dgram = client.process(dgram, now()).dgram(); // Initial w/token
+    let received = dgram.as_ref().unwrap().len();
+
+    // With the token, the server doesn't limit what it sends to the client.
+    let mut t = now();
+    let mut sent = 0;
+    let mut out = server.process(dgram, t);
+    loop {
+        match out {
+            Output::Datagram(d) => sent += d.len(),
+            Output::Callback(delay) if delay < Duration::from_millis(100) => t += delay,
+            _ => break,
+        }
+        out = server.process(None, t);
+    }
+    assert!(sent > received * 3);
+}
+
 // Generate an AEAD and header protection object for a client Initial.
 fn client_initial_aead_and_hp(dcid: &[u8]) -> (Aead, HpKey) {
     const INITIAL_SALT: &[u8] = &[
@@ -525,7 +646,7 @@ fn apply_header_protection(hp: &HpKey, packet: &mut [u8], pn_bytes: Range<usize>
 fn mitm_retry() {
     let mut client = default_client();
     let mut retry_server = default_server();
-    retry_server.set_retry_required(true);
+    retry_server.set_validation(ValidateAddress::Always);
     let mut server = default_server();
 
     // Trigger initial and a second client Initial.
@@ -607,13 +728,10 @@ fn mitm_retry() {
     ));
 }
 
-#[test]
-fn bad_client_initial() {
-    let mut client = default_client();
-    let mut server = default_server();
-
-    let dgram = client.process(None, now()).dgram().expect("a datagram");
-    let (header, dcid, scid, payload) = decode_initial_header(&dgram);
+// Decrypt a client Initial, replace the payload with the output of `f`, and
+// encrypt it again.
+fn rewrite_initial(dgram: &Datagram, f: impl FnOnce(&[u8]) -> Vec<u8>) -> Datagram {
+    let (header, dcid, scid, payload) = decode_initial_header(dgram);
     let (aead, hp) = client_initial_aead_and_hp(dcid);
     let (fixed_header, pn) = remove_header_protection(&hp, header, payload);
     let payload = &payload[(fixed_header.len() - header.len())..];
@@ -623,8 +741,7 @@ fn bad_client_initial() {
         .decrypt(pn, &fixed_header, payload, &mut plaintext_buf)
         .unwrap();
 
-    let mut payload_enc = Encoder::from(plaintext);
-    payload_enc.encode(&[0x08, 0x02, 0x00, 0x00]); // Add a stream frame.
+    let payload_enc = Encoder::from(&f(plaintext)[..]);
 
     // Make a new header with a 1 byte packet number length.
     let mut header_enc = Encoder::new();
@@ -656,35 +773,67 @@ fn bad_client_initial() {
         &mut ciphertext,
         (header_enc.len() - 1)..header_enc.len(),
     );
-    let bad_dgram = Datagram::new(dgram.source(), dgram.destination(), ciphertext);
+    Datagram::new(dgram.source(), dgram.destination(), ciphertext)
+}
 
-    // The server should reject this.
+/// Check that the server drops `bad_dgram` without creating a connection,
+/// then that it accepts the genuine Initial in `dgram`.
+fn drop_then_accept(
+    client: &mut Connection,
+    server: &mut Server,
+    dgram: Datagram,
+    bad_dgram: Datagram,
+) {
     let response = server.process(Some(bad_dgram), now());
-    let close_dgram = response.dgram().unwrap();
-    assert!(close_dgram.len() < 200); // Too small for anything real.
+    assert!(response.as_dgram_ref().is_none());
+    assert!(server.active_connections().is_empty());
 
-    // The client should accept this new and stop trying to connect.
-    // It will generate a CONNECTION_CLOSE first though.
-    let response = client.process(Some(close_dgram), now()).dgram();
+    let response = server.process(Some(dgram), now()).dgram();
     assert!(response.is_some());
-    // The client will now wait out its closing period.
-    let delay = client.process(None, now()).callback();
-    assert_ne!(delay, Duration::from_secs(0));
-    assert!(matches!(
-        *client.state(),
-        State::Draining { error: ConnectionError::Transport(Error::PeerError(code)), .. } if code == Error::ProtocolViolation.code()
-    ));
+    complete_connection(client, server, response);
+}
 
-    for server in server.active_connections() {
-        assert_eq!(
-            *server.borrow().state(),
-            State::Closed(ConnectionError::Transport(Error::ProtocolViolation))
-        );
-    }
+#[test]
+fn bad_client_initial() {
+    let mut client = default_client();
+    let mut server = default_server();
 
-    // After sending the CONNECTION_CLOSE, the server goes idle.
-    let res = server.process(None, now());
-    assert_eq!(res, Output::None);
+    let dgram = client.process(None, now()).dgram().expect("a datagram");
+    let bad_dgram = rewrite_initial(&dgram, |plaintext| {
+        let mut payload = plaintext.to_vec();
+        payload.extend_from_slice(&[0x08, 0x02, 0x00, 0x00]); // Add a stream frame.
+        payload
+    });
+    drop_then_accept(&mut client, &mut server, dgram, bad_dgram);
+}
+
+#[test]
+fn client_initial_without_crypto() {
+    let mut client = default_client();
+    let mut server = default_server();
+
+    let dgram = client.process(None, now()).dgram().expect("a datagram");
+    let bad_dgram = rewrite_initial(&dgram, |plaintext| {
+        // A PING frame followed by padding.
+        let mut payload = vec![0x01];
+        payload.resize(plaintext.len(), 0);
+        payload
+    });
+    drop_then_accept(&mut client, &mut server, dgram, bad_dgram);
+}
+
+#[test]
+fn client_initial_bad_aead() {
+    let mut client = default_client();
+    let mut server = default_server();
+
+    let dgram = client.process(None, now()).dgram().expect("a datagram");
+    let (header, _, _, payload) = decode_initial_header(&dgram);
+    let end = header.len() + payload.len();
+    let mut input = dgram.to_vec();
+    input[end - 1] ^= 0x01; // Break the authentication tag.
+    let bad_dgram = Datagram::new(dgram.source(), dgram.destination(), input);
+    drop_then_accept(&mut client, &mut server, dgram, bad_dgram);
 }
 
 #[test]
@@ -717,14 +866,86 @@ fn version_negotiation() {
     }
     assert!(found, "valid version not found");
 
+    // The server lists the version that the client used, so the client can't
+    // act on this: it might be an attempt to make the client change version.
+    let dropped = client.stats().dropped_rx;
+    let _ = client.process(Some(vn), now());
+    assert_eq!(*client.state(), State::WaitInitial);
+    assert_eq!(client.stats().dropped_rx, dropped + 1);
+}
+
+/// Make a Version Negotiation packet in response to `dgram` that lists `versions`.
+fn version_negotiation_for(dgram: &Datagram, versions: &[u32]) -> Datagram {
+    let mut dec = Decoder::from(&dgram[5..]); // Skip past version.
+    let dcid = dec.decode_vec(1).expect("client DCID");
+    let scid = dec.decode_vec(1).expect("client SCID");
+
+    let mut enc = Encoder::default();
+    enc.encode_byte(0x80);
+    enc.encode(&[0; 4]); // Zero version == VN.
+    enc.encode_vec(1, scid);
+    enc.encode_vec(1, dcid);
+    for v in versions {
+        enc.encode_uint(4, *v);
+    }
+    Datagram::new(dgram.destination(), dgram.source(), enc)
+}
+
+#[test]
+fn version_negotiation_no_common_version() {
+    let mut client = default_client();
+    let dgram = client.process(None, now()).dgram().expect("a datagram");
+
+    let vn = version_negotiation_for(&dgram, &[0xff00_0014, 0x1a2a_3a4a]);
     let res = client.process(Some(vn), now());
     assert_eq!(res, Output::None);
-    match client.state() {
-        State::Closed(err) => {
-            assert_eq!(*err, ConnectionError::Transport(Error::VersionNegotiation))
-        }
-        _ => panic!("Invalid client state"),
-    }
+    assert_eq!(
+        *client.state(),
+        State::Closed(ConnectionError::Transport(Error::VersionNegotiation))
+    );
+}
+
+#[test]
+fn version_negotiation_milestones() {
+    let mut client = default_client();
+    let dgram = client.process(None, now()).dgram().expect("a datagram");
+
+    let vn = version_negotiation_for(&dgram, &[0xff00_0014]);
+    let _ = client.process(Some(vn), now() + Duration::from_millis(10));
+    assert!(matches!(client.state(), State::Closed(_)));
+
+    // The failed connection keeps the one milestone that it reached.
+    let m = client.milestones();
+    assert_eq!(m.first_sent, Some(now()));
+    assert_eq!(m.reached().len(), 1);
+}
+
+#[test]
+fn version_negotiation_wrong_cid() {
+    let mut client = default_client();
+    let dgram = client.process(None, now()).dgram().expect("a datagram");
+
+    let mut vn = version_negotiation_for(&dgram, &[0xff00_0014]).to_vec();
+    vn[6] ^= 0xff; // The first byte of the echoed connection ID.
+    let vn = Datagram::new(dgram.destination(), dgram.source(), vn);
+    let dropped = client.stats().dropped_rx;
+    let _ = client.process(Some(vn), now());
+    assert_eq!(*client.state(), State::WaitInitial);
+    assert_eq!(client.stats().dropped_rx, dropped + 1);
+}
+
+#[test]
+fn version_negotiation_after_handshake() {
+    let mut server = default_server();
+    server.set_validation(ValidateAddress::Never);
+    let mut client = default_client();
+    let dgram = client.process(None, now()).dgram().expect("a datagram");
+    let vn = version_negotiation_for(&dgram, &[0xff00_0014]);
+
+    let dgram = server.process(Some(dgram), now()).dgram();
+    complete_connection(&mut client, &mut server, dgram);
+    let _ = client.process(Some(vn), now());
+    assert_eq!(*client.state(), State::Confirmed);
 }
 
 #[test]
@@ -745,3 +966,42 @@ fn closed() {
     let res = server.process(None, now() + Duration::from_secs(60));
     assert_eq!(res, Output::None);
 }
+
+const RESET_KEY: &[u8] = &[0x5e; 32];
+
+/// Connect, then send a packet from the client to a new server, which knows
+/// nothing about the connection.
+fn lose_server_state(new_server: &mut Server) -> (Connection, Option<Datagram>) {
+    let mut server = default_server();
+    server.set_stateless_reset_key(RESET_KEY).unwrap();
+    let mut client = default_client();
+    connect(&mut client, &mut server);
+
+    let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+    assert_eq!(client.stream_send(stream_id, &[1; 100]).unwrap(), 100);
+    let dgram = client.process_output(now()).dgram();
+    assert!(dgram.is_some());
+    let reset = new_server.process(dgram, now()).dgram();
+    (client, reset)
+}
+
+#[test]
+fn stateless_reset() {
+    let mut server = default_server();
+    server.set_stateless_reset_key(RESET_KEY).unwrap();
+    let (mut client, reset) = lose_server_state(&mut server);
+    client.process_input(reset.expect("should send a stateless reset"), now());
+    assert_eq!(
+        *client.state(),
+        State::Closed(ConnectionError::Transport(Error::StatelessReset))
+    );
+}
+
+#[test]
+fn stateless_reset_other_key() {
+    // A server with a different key still sends a stateless reset, but the client ignores it.
+    let mut server = default_server();
+    let (mut client, reset) = lose_server_state(&mut server);
+    client.process_input(reset.expect("should send a stateless reset"), now());
+    assert_eq!(*client.state(), State::Confirmed);
+}
@@ -0,0 +1,747 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Sending and receiving batches of datagrams, with errors normalized across
+// platforms, for the client and server binaries.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![warn(clippy::use_self)]
+
+use neqo_common::{matches, Datagram};
+
+use std::cmp::max;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+mod local_drops;
+
+pub use self::local_drops::{local_drops, LocalDrops, PROC_READ_INTERVAL};
+
+/// The most datagrams that are passed to the kernel in one call.
+pub const MAX_BATCH: usize = 32;
+/// The size of each receive buffer.
+pub const RECV_BUF_SIZE: usize = 2048;
+
+/// The shortest read timeout.  A zero timeout is rejected, and Windows counts in milliseconds.
+const MIN_READ_TIMEOUT: Duration = Duration::from_millis(1);
+
+// The standard library has no `ErrorKind` for a datagram that doesn't fit.
+#[cfg(target_os = "linux")]
+const EMSGSIZE: i32 = libc::EMSGSIZE;
+// macOS and the BSDs.
+#[cfg(all(unix, not(target_os = "linux")))]
+const EMSGSIZE: i32 = 40;
+// WSAEMSGSIZE
+#[cfg(windows)]
+const EMSGSIZE: i32 = 10040;
+
+// Nor for a network that has gone away.
+#[cfg(target_os = "linux")]
+const ENETDOWN: i32 = libc::ENETDOWN;
+#[cfg(target_os = "linux")]
+const ENETUNREACH: i32 = libc::ENETUNREACH;
+#[cfg(all(unix, not(target_os = "linux")))]
+const ENETDOWN: i32 = 50;
+#[cfg(all(unix, not(target_os = "linux")))]
+const ENETUNREACH: i32 = 51;
+#[cfg(windows)]
+const ENETDOWN: i32 = 10050;
+#[cfg(windows)]
+const ENETUNREACH: i32 = 10051;
+
+/// How to react to an error from the socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Nothing was sent or received this time, but the socket is fine.  This
+    /// includes a read timeout, which is `TimedOut` rather than `WouldBlock` on Windows.
+    Retry,
+    /// An ICMP error for an earlier datagram was reported on the connected socket:
+    /// ECONNREFUSED on Unix, WSAECONNRESET on Windows.  This is only a hint, and it
+    /// can be forged, so the connection is left to time out if the peer is really gone.
+    Unreachable,
+    /// The datagram was too large to send, or was truncated on receipt (Windows reports
+    /// truncation as an error).
+    TooLarge,
+    /// The local address can't be used any more, as happens when the network
+    /// changes.  A new socket might work.
+    AddressGone,
+    /// Anything else.
+    Fatal,
+}
+
+#[must_use]
+pub fn classify(err: &io::Error) -> ErrorClass {
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::Interrupted | ErrorKind::TimedOut => ErrorClass::Retry,
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset => ErrorClass::Unreachable,
+        _ if err.raw_os_error() == Some(EMSGSIZE) => ErrorClass::TooLarge,
+        ErrorKind::AddrNotAvailable => ErrorClass::AddressGone,
+        _ if matches!(err.raw_os_error(), Some(ENETDOWN) | Some(ENETUNREACH)) => {
+            ErrorClass::AddressGone
+        }
+        _ => ErrorClass::Fatal,
+    }
+}
+
+/// Set the read timeout, or wait forever for `None`.  Timeouts that are too short for
+/// the platform are raised to 1ms rather than being an error.
+pub fn set_read_timeout(socket: &UdpSocket, timeout: Option<Duration>) -> io::Result<()> {
+    socket.set_read_timeout(timeout.map(|t| max(t, MIN_READ_TIMEOUT)))
+}
+
+/// The form of `addr` that a dual-stack socket uses: IPv4 addresses are mapped
+/// into IPv6 (`::ffff:a.b.c.d`) and IPv6 addresses are unchanged.
+#[must_use]
+pub fn dual_stack_address(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        SocketAddr::V6(_) => addr,
+    }
+}
+
+/// Bind an IPv6 socket to the wildcard address and an ephemeral port, with IPV6_V6ONLY
+/// turned off on Linux so that it can also reach IPv4 peers.  Elsewhere the socket keeps
+/// the platform default, which is dual-stack except on Windows; connecting it to an IPv4
+/// peer fails if it isn't.
+pub fn bind_dual_stack() -> io::Result<UdpSocket> {
+    sys::bind_dual_stack()
+}
+
+/// The send and receive buffer sizes that the kernel has for `socket`.  Linux reports
+/// twice what was asked for, to allow for its own overhead.  This is only available
+/// on Linux.
+pub fn buffer_sizes(socket: &UdpSocket) -> io::Result<(usize, usize)> {
+    sys::buffer_sizes(socket)
+}
+
+/// Set the don't fragment bit on datagrams sent from `socket`, so that a datagram
+/// that is too large for the path is dropped rather than fragmented.  On a
+/// dual-stack socket, this covers IPv4 peers too.  This is only available on
+/// Linux, macOS and FreeBSD.
+pub fn set_dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+    sys::set_dont_fragment(socket)
+}
+
+/// Counts of system calls made and datagrams moved for a socket.  Each socket
+/// has its own, which is passed to `send_batch` and `recv_batch`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BatchStats {
+    pub send_calls: usize,
+    pub sent: usize,
+    pub recv_calls: usize,
+    pub received: usize,
+}
+
+impl ::std::fmt::Display for BatchStats {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(
+            f,
+            "sent {} datagrams in {} calls, received {} datagrams in {} calls",
+            self.sent, self.send_calls, self.received, self.recv_calls
+        )
+    }
+}
+
+/// Send up to `MAX_BATCH` datagrams from the start of `dgrams` in one call, on
+/// a connected socket.  This returns the number that were sent, which is never
+/// zero for a non-empty input; if the first datagram can't be sent the error is
+/// returned instead.
+pub fn send_batch(
+    socket: &UdpSocket,
+    dgrams: &[Datagram],
+    stats: &mut BatchStats,
+) -> io::Result<usize> {
+    send(socket, dgrams, false, stats)
+}
+
+/// As `send_batch`, for a socket that isn't connected, as a server's is.  Each
+/// datagram goes to its own destination.
+pub fn send_batch_to(
+    socket: &UdpSocket,
+    dgrams: &[Datagram],
+    stats: &mut BatchStats,
+) -> io::Result<usize> {
+    send(socket, dgrams, true, stats)
+}
+
+fn send(
+    socket: &UdpSocket,
+    dgrams: &[Datagram],
+    addressed: bool,
+    stats: &mut BatchStats,
+) -> io::Result<usize> {
+    if dgrams.is_empty() {
+        return Ok(0);
+    }
+    let n = dgrams.len().min(MAX_BATCH);
+    let res = sys::send(socket, &dgrams[..n], addressed, stats);
+    if let Ok(sent) = res {
+        stats.sent += sent;
+    }
+    res
+}
+
+/// Receive up to `bufs.len()` datagrams, waiting for the first one as a normal
+/// receive would.  This returns the size of each datagram that was received,
+/// in the order they were received.
+pub fn recv_batch(
+    socket: &UdpSocket,
+    bufs: &mut [[u8; RECV_BUF_SIZE]],
+    stats: &mut BatchStats,
+) -> io::Result<Vec<usize>> {
+    let n = bufs.len().min(MAX_BATCH);
+    let res = sys::recv(socket, &mut bufs[..n], stats);
+    if let Ok(sizes) = &res {
+        stats.received += sizes.len();
+    }
+    res
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::{BatchStats, Datagram, RECV_BUF_SIZE};
+    use std::convert::TryFrom;
+    use std::io;
+    use std::mem;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::ptr;
+
+    fn check(rv: libc::c_int) -> io::Result<usize> {
+        usize::try_from(rv).map_err(|_| io::Error::last_os_error())
+    }
+
+    pub fn bind_dual_stack() -> io::Result<UdpSocket> {
+        // The option has to be set before binding, which `std` can't do.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_INET6,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::IPPROTO_UDP,
+            )
+        };
+        check(fd)?;
+        // This owns the descriptor, so it is closed if anything fails.
+        let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+        let off: libc::c_int = 0;
+        check(unsafe {
+            libc::setsockopt(
+                fd,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_V6ONLY,
+                &off as *const libc::c_int as *const libc::c_void,
+                libc::socklen_t::try_from(mem::size_of_val(&off)).unwrap(),
+            )
+        })?;
+        // All zeros is the wildcard address and an ephemeral port.
+        let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+        addr.sin6_family = libc::sa_family_t::try_from(libc::AF_INET6).unwrap();
+        check(unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                libc::socklen_t::try_from(mem::size_of_val(&addr)).unwrap(),
+            )
+        })?;
+        Ok(socket)
+    }
+
+    pub(super) fn getsockopt_int(
+        socket: &UdpSocket,
+        level: libc::c_int,
+        name: libc::c_int,
+    ) -> io::Result<usize> {
+        let mut value: libc::c_int = 0;
+        let mut len = libc::socklen_t::try_from(mem::size_of_val(&value)).unwrap();
+        check(unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        })?;
+        Ok(usize::try_from(value).unwrap_or(0))
+    }
+
+    pub fn buffer_sizes(socket: &UdpSocket) -> io::Result<(usize, usize)> {
+        Ok((
+            getsockopt_int(socket, libc::SOL_SOCKET, libc::SO_SNDBUF)?,
+            getsockopt_int(socket, libc::SOL_SOCKET, libc::SO_RCVBUF)?,
+        ))
+    }
+
+    fn setsockopt_int(
+        socket: &UdpSocket,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> io::Result<()> {
+        check(unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                libc::socklen_t::try_from(mem::size_of_val(&value)).unwrap(),
+            )
+        })?;
+        Ok(())
+    }
+
+    pub fn set_dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+        if socket.local_addr()?.is_ipv6() {
+            setsockopt_int(
+                socket,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                libc::IPV6_PMTUDISC_DO,
+            )?;
+        }
+        // A dual-stack socket sends to IPv4 peers with IPv4, which has its own
+        // option, so this is set on IPv6 sockets too.
+        setsockopt_int(
+            socket,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        )
+    }
+
+    type SockAddr = (libc::sockaddr_storage, libc::socklen_t);
+
+    fn sockaddr(addr: &SocketAddr) -> SockAddr {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(a) => {
+                let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::sa_family_t::try_from(libc::AF_INET).unwrap();
+                sin.sin_port = a.port().to_be();
+                // The octets are already in network byte order.
+                sin.sin_addr.s_addr = u32::from_ne_bytes(a.ip().octets());
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(a) => {
+                let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::sa_family_t::try_from(libc::AF_INET6).unwrap();
+                sin6.sin6_port = a.port().to_be();
+                sin6.sin6_flowinfo = a.flowinfo();
+                sin6.sin6_addr.s6_addr = a.ip().octets();
+                sin6.sin6_scope_id = a.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, libc::socklen_t::try_from(len).unwrap())
+    }
+
+    /// One message for each buffer.  `names` has the address for each message, or
+    /// is empty if the socket is connected and there is no address to pass.
+    fn msgs(iovecs: &mut [libc::iovec], names: &mut [SockAddr]) -> Vec<libc::mmsghdr> {
+        iovecs
+            .iter_mut()
+            .enumerate()
+            .map(|(i, iov)| {
+                let mut m: libc::mmsghdr = unsafe { mem::zeroed() };
+                m.msg_hdr.msg_iov = iov;
+                m.msg_hdr.msg_iovlen = 1;
+                if let Some((name, len)) = names.get_mut(i) {
+                    m.msg_hdr.msg_name = name as *mut libc::sockaddr_storage as *mut libc::c_void;
+                    m.msg_hdr.msg_namelen = *len;
+                }
+                m
+            })
+            .collect()
+    }
+
+    pub fn send(
+        socket: &UdpSocket,
+        dgrams: &[Datagram],
+        addressed: bool,
+        stats: &mut BatchStats,
+    ) -> io::Result<usize> {
+        let mut iovecs = dgrams
+            .iter()
+            .map(|d| libc::iovec {
+                iov_base: d.as_ptr() as *mut libc::c_void,
+                iov_len: d.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut names = if addressed {
+            dgrams.iter().map(|d| sockaddr(&d.destination())).collect()
+        } else {
+            Vec::new()
+        };
+        let mut msgs = msgs(&mut iovecs, &mut names);
+        stats.send_calls += 1;
+        // The kernel only reads from the buffers for a send.
+        let rv = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                libc::c_uint::try_from(msgs.len()).unwrap(),
+                0,
+            )
+        };
+        check(rv)
+    }
+
+    pub fn recv(
+        socket: &UdpSocket,
+        bufs: &mut [[u8; RECV_BUF_SIZE]],
+        stats: &mut BatchStats,
+    ) -> io::Result<Vec<usize>> {
+        let mut iovecs = bufs
+            .iter_mut()
+            .map(|b| libc::iovec {
+                iov_base: b.as_mut_ptr() as *mut libc::c_void,
+                iov_len: b.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut msgs = msgs(&mut iovecs, &mut []);
+        stats.recv_calls += 1;
+        // MSG_WAITFORONE blocks (subject to the socket timeout) for the first
+        // datagram only, then takes whatever else is already queued.
+        let rv = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                libc::c_uint::try_from(msgs.len()).unwrap(),
+                libc::MSG_WAITFORONE,
+                ptr::null_mut(),
+            )
+        };
+        let count = check(rv)?;
+        Ok(msgs[..count]
+            .iter()
+            .map(|m| usize::try_from(m.msg_len).unwrap())
+            .collect())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use super::{BatchStats, Datagram, RECV_BUF_SIZE};
+    use std::io;
+    use std::net::{Ipv6Addr, UdpSocket};
+
+    pub fn bind_dual_stack() -> io::Result<UdpSocket> {
+        UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+    }
+
+    fn unavailable() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "not available on this platform")
+    }
+
+    pub fn buffer_sizes(_socket: &UdpSocket) -> io::Result<(usize, usize)> {
+        Err(unavailable())
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    pub fn set_dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+        use std::convert::TryFrom;
+        use std::os::unix::io::AsRawFd;
+
+        let set = |level, name| {
+            let on: libc::c_int = 1;
+            let rv = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    level,
+                    name,
+                    &on as *const libc::c_int as *const libc::c_void,
+                    libc::socklen_t::try_from(std::mem::size_of_val(&on)).unwrap(),
+                )
+            };
+            if rv == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        };
+        if socket.local_addr()?.is_ipv6() {
+            set(libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG)?;
+            // IPv4 peers of a dual-stack socket need the IPv4 option, which an
+            // IPv6-only socket doesn't have.
+            let _ = set(libc::IPPROTO_IP, libc::IP_DONTFRAG);
+            Ok(())
+        } else {
+            set(libc::IPPROTO_IP, libc::IP_DONTFRAG)
+        }
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+    pub fn set_dont_fragment(_socket: &UdpSocket) -> io::Result<()> {
+        Err(unavailable())
+    }
+
+    pub fn send(
+        socket: &UdpSocket,
+        dgrams: &[Datagram],
+        addressed: bool,
+        stats: &mut BatchStats,
+    ) -> io::Result<usize> {
+        for (i, d) in dgrams.iter().enumerate() {
+            stats.send_calls += 1;
+            let res = if addressed {
+                socket.send_to(&d[..], d.destination())
+            } else {
+                socket.send(&d[..])
+            };
+            if let Err(e) = res {
+                // Report the datagrams that were sent; the caller will retry
+                // from the one that failed.
+                return if i == 0 { Err(e) } else { Ok(i) };
+            }
+        }
+        Ok(dgrams.len())
+    }
+
+    pub fn recv(
+        socket: &UdpSocket,
+        bufs: &mut [[u8; RECV_BUF_SIZE]],
+        stats: &mut BatchStats,
+    ) -> io::Result<Vec<usize>> {
+        stats.recv_calls += 1;
+        let sz = socket.recv(&mut bufs[0][..])?;
+        Ok(vec![sz])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        classify, dual_stack_address, recv_batch, send_batch, send_batch_to, set_read_timeout,
+        BatchStats, ErrorClass, EMSGSIZE, ENETDOWN, ENETUNREACH, MAX_BATCH, RECV_BUF_SIZE,
+    };
+    use neqo_common::Datagram;
+    use std::io::{self, ErrorKind};
+    use std::net::{SocketAddr, UdpSocket};
+    use std::time::Duration;
+
+    #[test]
+    fn classify_kinds() {
+        for kind in &[
+            ErrorKind::WouldBlock,
+            ErrorKind::Interrupted,
+            ErrorKind::TimedOut,
+        ] {
+            assert_eq!(classify(&io::Error::from(*kind)), ErrorClass::Retry);
+        }
+        for kind in &[ErrorKind::ConnectionRefused, ErrorKind::ConnectionReset] {
+            assert_eq!(classify(&io::Error::from(*kind)), ErrorClass::Unreachable);
+        }
+        assert_eq!(
+            classify(&io::Error::from(ErrorKind::PermissionDenied)),
+            ErrorClass::Fatal
+        );
+    }
+
+    #[test]
+    fn classify_msgsize() {
+        assert_eq!(
+            classify(&io::Error::from_raw_os_error(EMSGSIZE)),
+            ErrorClass::TooLarge
+        );
+    }
+
+    #[test]
+    fn classify_address_gone() {
+        assert_eq!(
+            classify(&io::Error::from(ErrorKind::AddrNotAvailable)),
+            ErrorClass::AddressGone
+        );
+        for code in &[ENETDOWN, ENETUNREACH] {
+            assert_eq!(
+                classify(&io::Error::from_raw_os_error(*code)),
+                ErrorClass::AddressGone
+            );
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn classify_linux_codes() {
+        assert_eq!(
+            classify(&io::Error::from_raw_os_error(libc::ECONNREFUSED)),
+            ErrorClass::Unreachable
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn classify_windows_codes() {
+        // WSAECONNRESET and WSAETIMEDOUT
+        assert_eq!(
+            classify(&io::Error::from_raw_os_error(10054)),
+            ErrorClass::Unreachable
+        );
+        assert_eq!(
+            classify(&io::Error::from_raw_os_error(10060)),
+            ErrorClass::Retry
+        );
+    }
+
+    #[test]
+    fn dual_stack_addresses() {
+        let v4: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:443".parse().unwrap();
+        assert_eq!(dual_stack_address(v4), mapped);
+        assert_eq!(dual_stack_address(mapped), mapped);
+    }
+
+    // This is skipped if the host has no IPv6.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dual_stack_v4_peer() {
+        let socket = match super::bind_dual_stack() {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        assert!(socket.local_addr().unwrap().is_ipv6());
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .connect(dual_stack_address(peer.local_addr().unwrap()))
+            .unwrap();
+        socket.send(b"ping").unwrap();
+
+        // The peer sees an IPv4 address.
+        let mut buf = [0; 16];
+        let (sz, from) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..sz], b"ping");
+        assert!(from.is_ipv4());
+        peer.send_to(b"pong", from).unwrap();
+        set_read_timeout(&socket, Some(Duration::from_secs(5))).unwrap();
+        let sz = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..sz], b"pong");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn socket_options() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (send, recv) = super::buffer_sizes(&socket).unwrap();
+        assert!(send > 0 && recv > 0);
+        super::set_dont_fragment(&socket).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dont_fragment_dual_stack() {
+        use std::convert::TryFrom;
+
+        let socket = super::bind_dual_stack().unwrap();
+        super::set_dont_fragment(&socket).unwrap();
+        // IPv4 peers are covered as well as IPv6 ones.
+        let get = |level, name| super::sys::getsockopt_int(&socket, level, name).unwrap();
+        let on = |v| usize::try_from(v).unwrap();
+        assert_eq!(
+            get(libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER),
+            on(libc::IPV6_PMTUDISC_DO)
+        );
+        assert_eq!(
+            get(libc::IPPROTO_IP, libc::IP_MTU_DISCOVER),
+            on(libc::IP_PMTUDISC_DO)
+        );
+    }
+
+    // Two connected sockets on the loopback interface.
+    fn socket_pair() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        set_read_timeout(&b, Some(Duration::from_secs(5))).unwrap();
+        (a, b)
+    }
+
+    #[test]
+    fn batch() {
+        let (a, b) = socket_pair();
+        let (src, dst) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let dgrams = (0..5_u8)
+            .map(|i| Datagram::new(src, dst, vec![i; 100]))
+            .collect::<Vec<_>>();
+        let mut a_stats = BatchStats::default();
+        assert_eq!(send_batch(&a, &dgrams, &mut a_stats).unwrap(), dgrams.len());
+        assert_eq!(a_stats.sent, dgrams.len());
+        #[cfg(target_os = "linux")]
+        assert_eq!(a_stats.send_calls, 1);
+
+        let mut b_stats = BatchStats::default();
+        let mut bufs = vec![[0; RECV_BUF_SIZE]; MAX_BATCH];
+        let mut received = Vec::new();
+        while received.len() < dgrams.len() {
+            let sizes = recv_batch(&b, &mut bufs, &mut b_stats).unwrap();
+            received.extend(sizes.iter().zip(&bufs).map(|(&sz, buf)| buf[..sz].to_vec()));
+        }
+        assert_eq!(
+            received,
+            dgrams.iter().map(|d| d.to_vec()).collect::<Vec<_>>()
+        );
+        assert_eq!(b_stats.received, dgrams.len());
+        assert!(b_stats.recv_calls <= dgrams.len());
+        // Each socket only counts its own calls.
+        assert_eq!(a_stats.recv_calls, 0);
+        assert_eq!(b_stats.send_calls, 0);
+    }
+
+    // Only the datagrams that fit in a batch are sent.
+    #[test]
+    fn batch_limit() {
+        let (a, b) = socket_pair();
+        let (src, dst) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let dgrams = vec![Datagram::new(src, dst, vec![1; 10]); MAX_BATCH + 1];
+        let mut stats = BatchStats::default();
+        assert_eq!(send_batch(&a, &dgrams, &mut stats).unwrap(), MAX_BATCH);
+        assert_eq!(send_batch(&a, &[], &mut stats).unwrap(), 0);
+        assert_eq!(stats.sent, MAX_BATCH);
+    }
+
+    // A socket that isn't connected sends each datagram to its own destination.
+    #[test]
+    fn batch_to() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let src = socket.local_addr().unwrap();
+        let peers = [
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+        ];
+        let dgrams = (0..4_u8)
+            .map(|i| {
+                let dst = peers[usize::from(i % 2)].local_addr().unwrap();
+                Datagram::new(src, dst, vec![i; 10])
+            })
+            .collect::<Vec<_>>();
+        let mut stats = BatchStats::default();
+        assert_eq!(
+            send_batch_to(&socket, &dgrams, &mut stats).unwrap(),
+            dgrams.len()
+        );
+        #[cfg(target_os = "linux")]
+        assert_eq!(stats.send_calls, 1);
+
+        for (i, peer) in peers.iter().enumerate() {
+            set_read_timeout(peer, Some(Duration::from_secs(5))).unwrap();
+            let mut buf = [0; 16];
+            for d in dgrams.iter().skip(i).step_by(2) {
+                let (sz, from) = peer.recv_from(&mut buf).unwrap();
+                assert_eq!(from, src);
+                assert_eq!(&buf[..sz], &d[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn zero_timeout() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        set_read_timeout(&socket, Some(Duration::from_millis(0))).unwrap();
+        assert!(socket.read_timeout().unwrap().unwrap() >= Duration::from_millis(1));
+        set_read_timeout(&socket, None).unwrap();
+        assert_eq!(socket.read_timeout().unwrap(), None);
+    }
+}
@@ -0,0 +1,206 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Counting datagrams that the kernel drops because a socket's receive buffer is
+// full.  These never reach a connection, so they look like loss on the network;
+// `Connection::record_local_drops` is where the count goes.
+
+use std::io;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+/// The shortest time between reads of /proc.  Each read copies the whole table of
+/// UDP sockets, which is too much to do after every batch of datagrams.
+pub const PROC_READ_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many datagrams the kernel has dropped for `socket` because its receive
+/// buffer was full, since the socket was created.  This is only available on Linux.
+/// # Errors
+/// If the count isn't available.
+pub fn local_drops(socket: &UdpSocket) -> io::Result<usize> {
+    sys::meminfo_drops(socket).map_or_else(|| sys::proc_drops(socket), Ok)
+}
+
+/// Tracks the drop count for a socket, so that only new drops are reported.
+#[derive(Debug)]
+pub struct LocalDrops {
+    /// The count when last read, or `None` if it isn't available here.
+    last: Option<usize>,
+    /// When the count was last read from /proc, for kernels that have no cheaper
+    /// way to get it.
+    proc_read: Option<Instant>,
+}
+
+impl LocalDrops {
+    #[must_use]
+    pub fn new(socket: &UdpSocket) -> Self {
+        Self {
+            last: local_drops(socket).ok(),
+            proc_read: None,
+        }
+    }
+
+    /// The number of datagrams that `socket` has dropped since the last call.
+    /// Where the count has to come from /proc, it is read at most once every
+    /// `PROC_READ_INTERVAL` and this returns 0 in between.
+    pub fn check(&mut self, socket: &UdpSocket, now: Instant) -> usize {
+        let last = match self.last {
+            Some(n) => n,
+            None => return 0,
+        };
+        let drops = match sys::meminfo_drops(socket) {
+            Some(n) => n,
+            None => {
+                if self
+                    .proc_read
+                    .map_or(false, |t| now < t + PROC_READ_INTERVAL)
+                {
+                    return 0;
+                }
+                self.proc_read = Some(now);
+                match sys::proc_drops(socket) {
+                    Ok(n) => n,
+                    Err(_) => return 0,
+                }
+            }
+        };
+        self.last = Some(drops);
+        // The kernel counts in 32 bits, so this misses drops if it wraps.
+        drops.saturating_sub(last)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::io;
+    use std::mem;
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    // libc doesn't have this.  It is the value everywhere except SPARC and PA-RISC.
+    const SO_MEMINFO: libc::c_int = 55;
+    // How many values SO_MEMINFO has in a kernel that counts drops.
+    const SK_MEMINFO_VARS: usize = 9;
+
+    /// Kernels before 4.8 don't count drops in SO_MEMINFO, and those before 3.17
+    /// don't have it at all, but they all have /proc.
+    pub fn meminfo_drops(socket: &UdpSocket) -> Option<usize> {
+        let mut info = [0_u32; SK_MEMINFO_VARS];
+        let mut len = libc::socklen_t::try_from(mem::size_of_val(&info)).unwrap();
+        let rv = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                SO_MEMINFO,
+                info.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        let drops = usize::try_from(libc::SK_MEMINFO_DROPS).unwrap();
+        if rv == 0 && usize::try_from(len).unwrap() > drops * mem::size_of::<u32>() {
+            Some(usize::try_from(info[drops]).unwrap())
+        } else {
+            None
+        }
+    }
+
+    pub fn proc_drops(socket: &UdpSocket) -> io::Result<usize> {
+        let mut st: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat(socket.as_raw_fd(), &mut st) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let table = if socket.local_addr()?.is_ipv6() {
+            "/proc/net/udp6"
+        } else {
+            "/proc/net/udp"
+        };
+        parse_proc_drops(&fs::read_to_string(table)?, &st.st_ino.to_string()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("socket is not in {}", table),
+            )
+        })
+    }
+
+    /// Find the drops for the socket with `inode` in /proc/net/udp or udp6.  The
+    /// inode is the tenth column and drops are the last.
+    pub(super) fn parse_proc_drops(table: &str, inode: &str) -> Option<usize> {
+        table
+            .lines()
+            .skip(1)
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .find(|fields| fields.get(9) == Some(&inode))
+            .and_then(|fields| fields.last()?.parse().ok())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+    use std::net::UdpSocket;
+
+    pub fn meminfo_drops(_socket: &UdpSocket) -> Option<usize> {
+        None
+    }
+
+    pub fn proc_drops(_socket: &UdpSocket) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "not available on this platform",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LocalDrops, PROC_READ_INTERVAL};
+    use std::net::UdpSocket;
+    use std::time::Instant;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn local_drops() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert_eq!(super::local_drops(&socket).unwrap(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn proc_drops() {
+        let table = concat!(
+            "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops\n",
+            "  96: 0100007F:B7F3 00000000:0000 07 00000000:00034000 00:00000000 00000000  1000        0 123456 2 0000000000000000 17\n",
+            " 105: 00000000:14E9 00000000:0000 07 00000000:00000000 00:00000000 00000000   113        0 22334 2 0000000000000000 0\n",
+        );
+        assert_eq!(super::sys::parse_proc_drops(table, "123456"), Some(17));
+        assert_eq!(super::sys::parse_proc_drops(table, "22334"), Some(0));
+        assert_eq!(super::sys::parse_proc_drops(table, "1"), None);
+    }
+
+    #[test]
+    fn proc_rate_limit() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let now = Instant::now();
+        // A kernel that only has /proc, which was read just now.
+        let mut drops = LocalDrops {
+            last: Some(0),
+            proc_read: Some(now),
+        };
+        if super::sys::meminfo_drops(&socket).is_none() {
+            assert_eq!(drops.check(&socket, now + PROC_READ_INTERVAL / 2), 0);
+            assert_eq!(drops.proc_read, Some(now));
+            let later = now + PROC_READ_INTERVAL;
+            assert_eq!(drops.check(&socket, later), 0);
+            assert_eq!(drops.proc_read, Some(later));
+        } else {
+            // Where SO_MEMINFO has the count, /proc isn't needed.
+            assert_eq!(drops.check(&socket, now), 0);
+            assert_eq!(drops.proc_read, Some(now));
+        }
+    }
+}
@@ -9,9 +9,11 @@
 
 use neqo_common::matches;
 use neqo_crypto::{init_db, AntiReplay, AuthenticationStatus};
+#[cfg(feature = "http3")]
 use neqo_http3::{Http3Client, Http3Server};
+#[cfg(feature = "http3")]
 use neqo_qpack::QpackSettings;
-use neqo_transport::{Connection, ConnectionEvent, FixedConnectionIdManager, State};
+use neqo_transport::{rng, Connection, ConnectionEvent, FixedConnectionIdManager, State};
 
 use std::cell::RefCell;
 use std::mem;
@@ -22,15 +24,25 @@ use std::time::{Duration, Instant};
 use lazy_static::lazy_static;
 
 pub mod assertions;
+#[cfg(feature = "http3")]
+pub mod mock_http3;
+
+#[cfg(feature = "http3")]
+pub use mock_http3::MockHttp3;
 
 /// The path for the database used in tests.
 pub const NSS_DB_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/db");
 
+/// The seed for randomness in neqo-transport, so that tests behave the same way
+/// every time they run.
+pub const RNG_SEED: rng::Seed = [0x5e; 32];
+
 /// Initialize the test fixture.  Only call this if you aren't also calling a
 /// fixture function that depends on setup.  Other functions in the fixture
 /// that depend on this setup call the function for you.
 pub fn fixture_init() {
     init_db(NSS_DB_PATH);
+    rng::set_default_seed(RNG_SEED);
 }
 
 // This needs to be > 2ms to avoid it being rounded to zero.
@@ -135,6 +147,7 @@ pub fn connect() -> (Connection, Connection) {
 }
 
 /// Create a http3 client with default configuration.
+#[cfg(feature = "http3")]
 #[must_use]
 pub fn default_http3_client() -> Http3Client {
     fixture_init();
@@ -154,6 +167,7 @@ pub fn default_http3_client() -> Http3Client {
 }
 
 /// Create a http3 server with default configuration.
+#[cfg(feature = "http3")]
 #[must_use]
 pub fn default_http3_server() -> Http3Server {
     fixture_init();
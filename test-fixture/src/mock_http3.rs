@@ -0,0 +1,97 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A stand-in for `Http3Client` that replays a `Recording`.
+
+use neqo_http3::{Error, Http3ClientEvent, RecordedItem, Recording};
+
+use std::cmp::min;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Instant;
+
+/// This has the same event and reading methods as `Http3Client`, but the
+/// events come from a recording, and so does the data.  Data for each stream
+/// is returned in the order that it was recorded, regardless of where the
+/// reads were in the recording, and it can be read in larger or smaller pieces
+/// than it was recorded in.  Once a stream has been read to the end, it is
+/// gone, as it would be for `Http3Client`.  Server events in the recording are
+/// skipped.
+#[derive(Debug, Default)]
+pub struct MockHttp3 {
+    events: VecDeque<Http3ClientEvent>,
+    data: HashMap<u64, VecDeque<(Vec<u8>, bool)>>,
+    finished: HashSet<u64>,
+}
+
+impl MockHttp3 {
+    #[must_use]
+    pub fn new(recording: &Recording) -> Self {
+        let mut mock = Self::default();
+        for item in recording.items() {
+            match item {
+                RecordedItem::Event(e) => mock.events.push_back(e.clone()),
+                RecordedItem::Read {
+                    stream_id,
+                    data,
+                    fin,
+                } => mock
+                    .data
+                    .entry(*stream_id)
+                    .or_default()
+                    .push_back((data.clone(), *fin)),
+                RecordedItem::Server(_) => {}
+            }
+        }
+        mock
+    }
+
+    #[must_use]
+    pub fn has_events(&self) -> bool {
+        !self.events.is_empty()
+    }
+
+    pub fn next_event(&mut self) -> Option<Http3ClientEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn events(&mut self) -> impl Iterator<Item = Http3ClientEvent> {
+        std::mem::replace(&mut self.events, VecDeque::new()).into_iter()
+    }
+
+    /// Read recorded data.  Reading a stream that has no more recorded data
+    /// returns nothing, unless the end of the stream was read.
+    /// # Errors
+    /// `AlreadyRead` if the stream has been read to the end, and `UnknownStream` if
+    /// nothing was recorded for it.
+    pub fn read_response_data(
+        &mut self,
+        _now: Instant,
+        stream_id: u64,
+        buf: &mut [u8],
+    ) -> Result<(usize, bool), Error> {
+        if self.finished.contains(&stream_id) {
+            return Err(Error::AlreadyRead);
+        }
+        let reads = self.data.get_mut(&stream_id).ok_or(Error::UnknownStream)?;
+        let (data, fin) = match reads.front_mut() {
+            Some(read) => read,
+            None => return Ok((0, false)),
+        };
+        let amount = min(buf.len(), data.len());
+        buf[..amount].copy_from_slice(&data[..amount]);
+        if amount < data.len() {
+            let _ = data.drain(..amount);
+            return Ok((amount, false));
+        }
+        let fin = *fin;
+        let _ = reads.pop_front();
+        if fin {
+            self.data.remove(&stream_id);
+            self.finished.insert(stream_id);
+        }
+        Ok((amount, fin))
+    }
+}